@@ -0,0 +1,116 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Benchmarks header parsing and serialization, and the checksums computed along the way.
+
+#![feature(new_uninit)]
+#![feature(const_panic, const_alloc_layout)]
+#![feature(const_mut_refs, const_type_name)]
+#![feature(maybe_uninit_uninit_array, maybe_uninit_extra, maybe_uninit_ref)]
+
+use catnip::{
+    collections::bytes::{Bytes, BytesMut},
+    protocols::{
+        ethernet2::{EtherType2, Ethernet2Header},
+        ip,
+        ipv4::{Ipv4Header, Ipv4Protocol2},
+        tcp::segment::{TcpHeader, TcpOptions2, TcpSegment},
+    },
+    runtime::PacketBuf,
+    test_helpers,
+};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::{convert::TryFrom, num::Wrapping};
+
+//==============================================================================
+
+/// Payload sizes to benchmark checksums with, up to a jumbo frame.
+const PAYLOAD_SIZES: [usize; 4] = [0, 64, 1460, 8960];
+
+/// Builds a TCP segment from alice to bob carrying `size` bytes.
+fn cook_segment(size: usize, tx_checksum_offload: bool) -> TcpSegment<Bytes> {
+    let mut tcp_hdr = TcpHeader::new(
+        ip::Port::try_from(49152).unwrap(),
+        ip::Port::try_from(80).unwrap(),
+    );
+    tcp_hdr.ack = true;
+    tcp_hdr.seq_num = Wrapping(1);
+    tcp_hdr.ack_num = Wrapping(1);
+    tcp_hdr.window_size = 65535;
+    tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(1460));
+    tcp_hdr.push_option(TcpOptions2::WindowScale(7));
+    let mut data = BytesMut::zeroed(size).unwrap();
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    TcpSegment {
+        ethernet2_hdr: Ethernet2Header::new(
+            test_helpers::BOB_MAC,
+            test_helpers::ALICE_MAC,
+            EtherType2::Ipv4,
+        ),
+        ipv4_hdr: Ipv4Header::new(
+            test_helpers::ALICE_IPV4,
+            test_helpers::BOB_IPV4,
+            Ipv4Protocol2::Tcp,
+        ),
+        tcp_hdr,
+        data: data.freeze(),
+        tx_checksum_offload,
+        pseudo_header_sum: None,
+    }
+}
+
+/// Serializes `segment` into a frame, the way runtimes do on transmission.
+fn serialize(segment: TcpSegment<Bytes>) -> Bytes {
+    let header_size = segment.header_size();
+    let mut buf = BytesMut::zeroed(header_size + segment.body_size()).unwrap();
+    segment.write_header(&mut buf[..header_size]);
+    if let Some(body) = segment.take_body() {
+        buf[header_size..].copy_from_slice(&body[..]);
+    }
+    buf.freeze()
+}
+
+/// Parses all headers of `frame`, verifying the TCP checksum unless offloaded.
+fn parse(frame: Bytes, rx_checksum_offload: bool) -> (TcpHeader, Bytes) {
+    let (_, eth2_payload) = Ethernet2Header::parse(frame).unwrap();
+    let (ipv4_hdr, ipv4_payload) = Ipv4Header::parse(eth2_payload).unwrap();
+    TcpHeader::parse(&ipv4_hdr, ipv4_payload, rx_checksum_offload).unwrap()
+}
+
+//==============================================================================
+
+fn bench_headers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("headers");
+    let frame = serialize(cook_segment(0, false));
+    group.bench_function("parse", |b| {
+        b.iter(|| parse(black_box(frame.clone()), true))
+    });
+    let mut buf = vec![0u8; frame.len()];
+    group.bench_function("serialize", |b| {
+        let segment = cook_segment(0, true);
+        b.iter(|| black_box(&segment).write_header(&mut buf))
+    });
+    group.finish();
+}
+
+fn bench_checksum(c: &mut Criterion) {
+    let mut group = c.benchmark_group("checksum");
+    for &size in PAYLOAD_SIZES.iter() {
+        group.throughput(Throughput::Bytes(size as u64));
+        let frame = serialize(cook_segment(size, false));
+        group.bench_with_input(BenchmarkId::new("verify", size), &frame, |b, frame| {
+            b.iter(|| parse(black_box(frame.clone()), false))
+        });
+        let segment = cook_segment(size, false);
+        let mut buf = vec![0u8; segment.header_size()];
+        group.bench_with_input(BenchmarkId::new("compute", size), &segment, |b, segment| {
+            b.iter(|| black_box(segment).write_header(&mut buf))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_headers, bench_checksum);
+criterion_main!(benches);
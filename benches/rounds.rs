@@ -0,0 +1,202 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Benchmarks moving data between two engines on a [TestRuntime], from the receive path of an
+//! established TCP connection to full send/receive rounds.
+
+#![feature(new_uninit)]
+#![feature(const_panic, const_alloc_layout)]
+#![feature(const_mut_refs, const_type_name)]
+#![feature(maybe_uninit_uninit_array, maybe_uninit_extra, maybe_uninit_ref)]
+
+use catnip::{
+    collections::bytes::{Bytes, BytesMut},
+    engine::Engine,
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{ip, ipv4},
+    runtime::Runtime,
+    test_helpers::{self, TestRuntime},
+};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use futures::task::noop_waker_ref;
+use std::{
+    cell::RefCell,
+    convert::TryFrom,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+//==============================================================================
+
+/// Payload sizes to benchmark with, all within a single segment.
+const PAYLOAD_SIZES: [usize; 3] = [64, 512, 1024];
+
+/// How far clocks move between rounds, enough for delayed ACKs to go out.
+const TICK: Duration = Duration::from_millis(5);
+
+/// An established TCP connection from alice to bob.
+struct Connection {
+    now: Instant,
+    alice: Engine<TestRuntime>,
+    bob: Engine<TestRuntime>,
+    alice_fd: FileDescriptor,
+    bob_fd: FileDescriptor,
+}
+
+//==============================================================================
+
+/// Cooks a buffer.
+fn cook_buffer(size: usize) -> Bytes {
+    let mut buf = BytesMut::zeroed(size).unwrap();
+    for i in 0..size {
+        buf[i] = i as u8;
+    }
+    buf.freeze()
+}
+
+/// Polls `future` once, expecting it to complete successfully.
+fn must_complete<F: Future<Output = Result<T, Fail>> + Unpin, T>(mut future: F) -> T {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    match Future::poll(Pin::new(&mut future), &mut ctx) {
+        Poll::Ready(Ok(value)) => value,
+        Poll::Ready(Err(e)) => panic!("operation failed: {:?}", e),
+        Poll::Pending => panic!("operation did not complete"),
+    }
+}
+
+impl Connection {
+    /// Runs the 3-way handshake between a client alice and a server bob.
+    fn new() -> Self {
+        let now = Instant::now();
+        let mut alice = test_helpers::new_alice2(now);
+        let mut bob = test_helpers::new_bob2(now);
+
+        let port = ip::Port::try_from(80).unwrap();
+        let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port);
+        let listen_fd = bob.tcp_socket().unwrap();
+        bob.tcp_bind(listen_fd, listen_addr).unwrap();
+        bob.tcp_listen(listen_fd, 1).unwrap();
+        let accept_future = bob.tcp_accept(listen_fd);
+
+        let alice_fd = alice.tcp_socket().unwrap();
+        let connect_future = alice.tcp_connect(alice_fd, listen_addr);
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        bob.rt().poll_scheduler();
+        alice.receive(bob.rt().pop_frame()).unwrap();
+        alice.rt().poll_scheduler();
+        bob.receive(alice.rt().pop_frame()).unwrap();
+        bob.rt().poll_scheduler();
+
+        let (bob_fd, _) = must_complete(accept_future);
+        must_complete(connect_future);
+        Self {
+            now,
+            alice,
+            bob,
+            alice_fd,
+            bob_fd,
+        }
+    }
+
+    /// Pushes `buf` on alice and returns the segment put on the wire.
+    fn send(&mut self, buf: Bytes) -> Bytes {
+        let push_future = self.alice.tcp_push(self.alice_fd, buf);
+        let frame = self.alice.rt().pop_frame();
+        must_complete(push_future);
+        frame
+    }
+
+    /// Delivers `frame` to bob and pops the data it carries.
+    fn recv(&mut self, frame: Bytes) -> Bytes {
+        let pop_future = self.bob.tcp_pop(self.bob_fd);
+        self.bob.receive(frame).unwrap();
+        must_complete(pop_future)
+    }
+
+    /// Lets bob acknowledge what it received, so that alice's send window never fills up.
+    fn acknowledge(&mut self) {
+        self.now += TICK;
+        self.alice.rt().advance_clock(self.now);
+        self.bob.rt().advance_clock(self.now);
+        self.bob.rt().poll_scheduler();
+        if let Some(ack) = self.bob.rt().pop_frame_unchecked() {
+            self.alice.receive(ack).unwrap();
+        }
+        self.alice.rt().poll_scheduler();
+    }
+}
+
+//==============================================================================
+
+fn bench_tcp_receive(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tcp_receive");
+    for &size in PAYLOAD_SIZES.iter() {
+        group.throughput(Throughput::Bytes(size as u64));
+        let buf = cook_buffer(size);
+        let connection = RefCell::new(Connection::new());
+        group.bench_with_input(BenchmarkId::from_parameter(size), &buf, |b, buf| {
+            b.iter_batched(
+                || {
+                    let mut connection = connection.borrow_mut();
+                    connection.acknowledge();
+                    connection.send(buf.clone())
+                },
+                |frame| connection.borrow_mut().recv(frame),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_tcp_round(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tcp_round");
+    for &size in PAYLOAD_SIZES.iter() {
+        group.throughput(Throughput::Bytes(size as u64));
+        let buf = cook_buffer(size);
+        let mut connection = Connection::new();
+        group.bench_with_input(BenchmarkId::from_parameter(size), &buf, |b, buf| {
+            b.iter(|| {
+                let frame = connection.send(buf.clone());
+                let received = connection.recv(frame);
+                connection.acknowledge();
+                received
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_udp_round(c: &mut Criterion) {
+    let mut group = c.benchmark_group("udp_round");
+    for &size in PAYLOAD_SIZES.iter() {
+        group.throughput(Throughput::Bytes(size as u64));
+        let buf = cook_buffer(size);
+        let now = Instant::now();
+        let mut alice = test_helpers::new_alice2(now);
+        let mut bob = test_helpers::new_bob2(now);
+        let (alice_fd, alice_addr) = test_helpers::udp_socket_setup(&mut alice, 49152);
+        let (bob_fd, bob_addr) = test_helpers::udp_socket_setup(&mut bob, 80);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &buf, |b, buf| {
+            b.iter(|| {
+                test_helpers::udp_send_recv_round(
+                    &mut alice,
+                    alice_fd,
+                    alice_addr,
+                    &mut bob,
+                    bob_fd,
+                    bob_addr,
+                    buf.clone(),
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_tcp_receive, bench_tcp_round, bench_udp_round);
+criterion_main!(benches);
@@ -0,0 +1,15 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+#![no_main]
+
+use catnip::{collections::bytes::Bytes, runtime::RuntimeBuf, test_helpers};
+use libfuzzer_sys::fuzz_target;
+use std::time::Instant;
+
+// Feeds a frame through the whole receive path of an engine that knows its peer, so that
+// inputs addressed to it reach the IPv4, TCP, UDP and ICMP handlers.
+fuzz_target!(|data: &[u8]| {
+    let mut engine = test_helpers::new_bob2(Instant::now());
+    let _ = engine.receive(Bytes::from_slice(data));
+});
@@ -0,0 +1,13 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+#![no_main]
+
+use catnip::{
+    collections::bytes::Bytes, protocols::ethernet2::Ethernet2Header, runtime::RuntimeBuf,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Ethernet2Header::parse(Bytes::from_slice(data));
+});
@@ -0,0 +1,11 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+#![no_main]
+
+use catnip::{collections::bytes::Bytes, protocols::icmpv4::Icmpv4Header, runtime::RuntimeBuf};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Icmpv4Header::parse(Bytes::from_slice(data));
+});
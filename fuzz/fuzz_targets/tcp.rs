@@ -0,0 +1,28 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+#![no_main]
+
+use catnip::{
+    collections::bytes::Bytes,
+    protocols::{
+        ipv4::{Ipv4Header, Ipv4Protocol2},
+        tcp::segment::TcpHeader,
+    },
+    runtime::RuntimeBuf,
+};
+use libfuzzer_sys::fuzz_target;
+use std::net::Ipv4Addr;
+
+// The first byte selects whether the checksum is verified, so that inputs with a bad checksum
+// still reach the rest of the parser.
+fuzz_target!(|data: &[u8]| {
+    if let Some((&flags, data)) = data.split_first() {
+        let ipv4_hdr = Ipv4Header::new(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Protocol2::Tcp,
+        );
+        let _ = TcpHeader::parse(&ipv4_hdr, Bytes::from_slice(data), flags & 1 != 0);
+    }
+});
@@ -72,6 +72,12 @@ impl WakerPage {
         notified
     }
 
+    /// Returns how many futures in this page are waiting to be polled again, without taking them.
+    pub fn count_notified(&self) -> u32 {
+        let notified = self.notified.load() & !self.completed.load() & !self.dropped.load();
+        notified.count_ones()
+    }
+
     pub fn has_completed(&self, ix: usize) -> bool {
         debug_assert!(ix < 64);
         self.completed.load() & (1 << ix) != 0
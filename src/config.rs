@@ -0,0 +1,413 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Populates [Options] from a TOML document and from environment variables.
+//!
+//! Both sources share a flat key space. TOML tables nest with dots, so
+//!
+//! ```toml
+//! ipv4_addr = "10.0.0.2"
+//!
+//! [tcp]
+//! mss = 1460
+//! ```
+//!
+//! sets the keys `ipv4_addr` and `tcp.mss`. Environment variables carry the `CATNIP_` prefix and
+//! use underscores in place of dots, e.g. `CATNIP_TCP_MSS=1460`. Durations are given in
+//! milliseconds.
+
+use crate::{
+    fail::Fail,
+    options::Options,
+    protocols::{
+        ethernet2::MacAddress,
+        tcp::{
+            cc::{self, CongestionControl},
+            constants::{MAX_MSS, MAX_WINDOW_SCALE, MIN_MSS, MIN_MTU},
+        },
+        udp,
+    },
+    runtime::Runtime,
+};
+use std::{env, fs, ops::RangeBounds, path::Path, str::FromStr, time::Duration};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Prefix of the environment variables recognized by [apply_env].
+pub const ENV_PREFIX: &str = "CATNIP_";
+
+/// Shortest duration an option that must be positive takes, at the millisecond granularity of
+/// configured durations.
+const ONE_MS: Duration = Duration::from_millis(1);
+
+/// Options being loaded. Values that must agree with one another are held apart until every key
+/// has been applied and only then checked, so that the order of the keys does not matter.
+struct Staging<RT: Runtime> {
+    options: Options<RT>,
+    rto_lower_bound: Duration,
+    rto_upper_bound: Duration,
+}
+
+/// Recognized keys.
+const KEYS: &[&str] = &[
+    "ipv4_addr",
+    "link_addr",
+    "gateway_ipv4_addr",
+    "arp.cache_ttl",
+    "arp.request_timeout",
+    "arp.retry_count",
+    "arp.disable",
+    "tcp.mss",
+    "tcp.mtu",
+    "tcp.path_mtu_probe_interval",
+    "tcp.congestion_control",
+    "tcp.handshake_retries",
+    "tcp.handshake_timeout",
+    "tcp.receive_window_size",
+    "tcp.window_scale",
+    "tcp.receive_buffer_size",
+    "tcp.receive_window_autotuning",
+    "tcp.send_buffer_size",
+    "tcp.ack_delay_timeout",
+    "tcp.ack_frequency",
+    "tcp.rx_checksum_offload",
+    "tcp.tx_checksum_offload",
+    "tcp.rto_lower_bound",
+    "tcp.rto_upper_bound",
+    "tcp.max_retransmits",
+    "tcp.max_backlog",
+    "tcp.max_unsent_segments",
+    "tcp.max_out_of_order_segments",
+    "tcp.nodelay",
+    "tcp.pacing",
+    "tcp.frto",
+    "tcp.cwnd_validation",
+    "tcp.ecn",
+    "tcp.fast_open",
+    "udp.rx_checksum_offload",
+    "udp.tx_checksum_offload",
+];
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+impl<RT: Runtime> Staging<RT> {
+    fn new(options: Options<RT>) -> Self {
+        Self {
+            rto_lower_bound: options.tcp.rto_lower_bound(),
+            rto_upper_bound: options.tcp.rto_upper_bound(),
+            options,
+        }
+    }
+
+    /// Sets the option named `key` to `value`. Values out of the range an option takes are refused
+    /// here, rather than left to the assertions of its setter.
+    fn apply(&mut self, key: &str, value: &str) -> Result<(), Fail> {
+        let options = &mut self.options;
+        let tcp = options.tcp.clone();
+        match key {
+            "ipv4_addr" => options.my_ipv4_addr = parse(key, value)?,
+            "link_addr" => options.my_link_addr = MacAddress::parse_str(value)?,
+            "gateway_ipv4_addr" => options.gateway_ipv4_addr = Some(parse(key, value)?),
+            "arp.cache_ttl" => options.arp.cache_ttl = parse_duration(key, value)?,
+            "arp.request_timeout" => options.arp.request_timeout = parse_duration(key, value)?,
+            "arp.retry_count" => options.arp.retry_count = parse(key, value)?,
+            "arp.disable" => options.arp.disable_arp = parse(key, value)?,
+            "tcp.mss" => {
+                options.tcp = tcp.set_advertised_mss(parse_in(key, value, MIN_MSS..=MAX_MSS)?)
+            }
+            "tcp.mtu" => options.tcp = tcp.set_mtu(parse_in(key, value, MIN_MTU..)?),
+            "tcp.path_mtu_probe_interval" => {
+                let interval = parse_duration_in(key, value, ONE_MS..)?;
+                options.tcp = tcp.set_path_mtu_probe_interval(interval)
+            }
+            "tcp.congestion_control" => {
+                let constructor: cc::CongestionControlConstructor<RT> = match value {
+                    "bbr" => cc::Bbr::new,
+                    "cubic" => cc::Cubic::new,
+                    "newreno" => cc::NewReno::new,
+                    "none" => cc::None::new,
+                    _ => return Err(invalid(key, value)),
+                };
+                options.tcp = tcp.set_congestion_ctrl_type(constructor);
+            }
+            "tcp.handshake_retries" => {
+                options.tcp = tcp.set_handshake_retries(parse_in(key, value, 1..)?)
+            }
+            "tcp.handshake_timeout" => {
+                options.tcp = tcp.set_handshake_timeout(parse_duration_in(key, value, ONE_MS..)?)
+            }
+            "tcp.receive_window_size" => {
+                options.tcp = tcp.set_receive_window_size(parse_in(key, value, 1..)?)
+            }
+            "tcp.window_scale" => {
+                options.tcp = tcp.set_window_scale(parse_in(key, value, ..=MAX_WINDOW_SCALE)?)
+            }
+            "tcp.receive_buffer_size" => {
+                options.tcp = tcp.set_receive_buffer_size(parse_in(key, value, 1..)?)
+            }
+            "tcp.receive_window_autotuning" => {
+                options.tcp = tcp.set_receive_window_autotuning(parse_in(key, value, 1..)?)
+            }
+            "tcp.send_buffer_size" => {
+                options.tcp = tcp.set_send_buffer_size(parse_in(key, value, 1..)?)
+            }
+            "tcp.ack_delay_timeout" => {
+                let max = Duration::from_millis(500);
+                options.tcp = tcp.set_ack_delay_timeout(parse_duration_in(key, value, ..=max)?)
+            }
+            "tcp.ack_frequency" => options.tcp = tcp.set_ack_frequency(parse_in(key, value, 1..)?),
+            "tcp.rx_checksum_offload" => {
+                options.tcp = tcp.set_rx_checksum_offload(parse(key, value)?)
+            }
+            "tcp.tx_checksum_offload" => {
+                options.tcp = tcp.set_tx_checksum_offload(parse(key, value)?)
+            }
+            "tcp.rto_lower_bound" => {
+                self.rto_lower_bound = parse_duration_in(key, value, ONE_MS..)?
+            }
+            "tcp.rto_upper_bound" => {
+                self.rto_upper_bound = parse_duration_in(key, value, ONE_MS..)?
+            }
+            "tcp.max_retransmits" => {
+                options.tcp = tcp.set_max_retransmits(parse_in(key, value, 1..)?)
+            }
+            "tcp.max_backlog" => options.tcp = tcp.set_max_backlog(parse_in(key, value, 1..)?),
+            "tcp.max_unsent_segments" => {
+                options.tcp = tcp.set_max_unsent_segments(parse_in(key, value, 1..)?)
+            }
+            "tcp.max_out_of_order_segments" => {
+                options.tcp = tcp.set_max_out_of_order_segments(parse(key, value)?)
+            }
+            "tcp.nodelay" => options.tcp = tcp.set_nodelay(parse(key, value)?),
+            "tcp.pacing" => options.tcp = tcp.set_pacing(parse(key, value)?),
+            "tcp.frto" => options.tcp = tcp.set_frto(parse(key, value)?),
+            "tcp.cwnd_validation" => options.tcp = tcp.set_cwnd_validation(parse(key, value)?),
+            "tcp.ecn" => options.tcp = tcp.set_ecn(parse(key, value)?),
+            "tcp.fast_open" => options.tcp = tcp.set_fast_open(parse(key, value)?),
+            "udp.rx_checksum_offload" => {
+                options.udp = udp::Options::new(parse(key, value)?, options.udp.tx_checksum())
+            }
+            "udp.tx_checksum_offload" => {
+                options.udp = udp::Options::new(options.udp.rx_checksum(), parse(key, value)?)
+            }
+            _ => {
+                warn!("Unknown configuration key {:?}", key);
+                return Err(Fail::Invalid {
+                    details: "Unknown configuration key",
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the values that must agree with one another and sets them.
+    fn finish(mut self) -> Result<Options<RT>, Fail> {
+        if self.rto_lower_bound > self.rto_upper_bound {
+            warn!(
+                "RTO lower bound {:?} exceeds upper bound {:?}",
+                self.rto_lower_bound, self.rto_upper_bound
+            );
+            return Err(Fail::Invalid {
+                details: "RTO lower bound exceeds upper bound",
+            });
+        }
+        self.options.tcp = self
+            .options
+            .tcp
+            .set_rto_bounds(self.rto_lower_bound, self.rto_upper_bound);
+        Ok(self.options)
+    }
+}
+
+//==============================================================================
+// Standalone Functions
+//==============================================================================
+
+/// Loads options from the TOML file at `path`, on top of `options`.
+pub fn load_file<RT: Runtime>(options: Options<RT>, path: &Path) -> Result<Options<RT>, Fail> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| Fail::from(e).context("reading configuration file"))?;
+    load_str(options, &contents)
+}
+
+/// Loads options from a TOML document, on top of `options`.
+pub fn load_str<RT: Runtime>(options: Options<RT>, contents: &str) -> Result<Options<RT>, Fail> {
+    let document: toml::Value = contents.parse().map_err(|e| {
+        warn!("Failed to parse configuration: {}", e);
+        Fail::Malformed {
+            details: "Invalid TOML configuration",
+        }
+    })?;
+    let mut entries = vec![];
+    flatten(None, &document, &mut entries)?;
+    let mut staging = Staging::new(options);
+    for (key, value) in entries {
+        staging.apply(&key, &value)?;
+    }
+    staging.finish()
+}
+
+/// Overrides `options` with the `CATNIP_*` variables set in the environment.
+pub fn apply_env<RT: Runtime>(options: Options<RT>) -> Result<Options<RT>, Fail> {
+    let mut staging = Staging::new(options);
+    for key in KEYS {
+        let var = format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase());
+        if let Ok(value) = env::var(&var) {
+            staging.apply(key, &value)?;
+        }
+    }
+    staging.finish()
+}
+
+/// Collects the leaves of a TOML document as (dotted key, value) pairs.
+fn flatten(
+    prefix: Option<&str>,
+    value: &toml::Value,
+    out: &mut Vec<(String, String)>,
+) -> Result<(), Fail> {
+    match value {
+        toml::Value::Table(table) => {
+            for (k, v) in table {
+                let key = match prefix {
+                    Some(p) => format!("{}.{}", p, k),
+                    None => k.clone(),
+                };
+                flatten(Some(&key), v, out)?;
+            }
+        }
+        toml::Value::String(s) => out.push((prefix.unwrap().to_string(), s.clone())),
+        toml::Value::Integer(i) => out.push((prefix.unwrap().to_string(), i.to_string())),
+        toml::Value::Boolean(b) => out.push((prefix.unwrap().to_string(), b.to_string())),
+        _ => {
+            warn!("Unsupported value for configuration key {:?}", prefix);
+            return Err(Fail::Unsupported {
+                details: "Unsupported configuration value type",
+            });
+        }
+    }
+    Ok(())
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, Fail> {
+    value.parse().map_err(|_| invalid(key, value))
+}
+
+/// Parses `value` like [parse], refusing what falls out of `range`.
+fn parse_in<T: FromStr + PartialOrd, R: RangeBounds<T>>(
+    key: &str,
+    value: &str,
+    range: R,
+) -> Result<T, Fail> {
+    let parsed = parse(key, value)?;
+    if !range.contains(&parsed) {
+        return Err(invalid(key, value));
+    }
+    Ok(parsed)
+}
+
+fn parse_duration(key: &str, value: &str) -> Result<Duration, Fail> {
+    Ok(Duration::from_millis(parse(key, value)?))
+}
+
+/// Parses `value` like [parse_duration], refusing what falls out of `range`.
+fn parse_duration_in<R: RangeBounds<Duration>>(
+    key: &str,
+    value: &str,
+    range: R,
+) -> Result<Duration, Fail> {
+    let parsed = parse_duration(key, value)?;
+    if !range.contains(&parsed) {
+        return Err(invalid(key, value));
+    }
+    Ok(parsed)
+}
+
+fn invalid(key: &str, value: &str) -> Fail {
+    warn!("Invalid value {:?} for configuration key {:?}", value, key);
+    Fail::Invalid {
+        details: "Invalid configuration value",
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{load_str, Staging};
+    use crate::{fail::Fail, options::Options, test_helpers::TestRuntime};
+    use must_let::must_let;
+    use std::{net::Ipv4Addr, time::Duration};
+
+    #[test]
+    fn test_load_str() {
+        let config = r#"
+            ipv4_addr = "10.0.0.2"
+            link_addr = "12:23:45:67:89:ab"
+
+            [arp]
+            request_timeout = 250
+
+            [tcp]
+            mss = 1460
+            congestion_control = "cubic"
+            rto_lower_bound = 200
+        "#;
+        let options = load_str(Options::<TestRuntime>::default(), config).unwrap();
+        assert_eq!(options.my_ipv4_addr, Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(options.my_link_addr.to_canonical(), "12-23-45-67-89-ab");
+        assert_eq!(options.arp.request_timeout, Duration::from_millis(250));
+        assert_eq!(options.tcp.advertised_mss(), 1460);
+        assert_eq!(options.tcp.rto_lower_bound(), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_load_str_mtu() {
+        let options = load_str(Options::<TestRuntime>::default(), "[tcp]\nmtu = 9000").unwrap();
+        assert_eq!(options.tcp.advertised_mss(), 8960);
+    }
+
+    #[test]
+    fn test_load_str_unknown_key() {
+        assert!(load_str(Options::<TestRuntime>::default(), "bogus = 1").is_err());
+    }
+
+    #[test]
+    fn test_load_str_out_of_range() {
+        for config in &[
+            "[tcp]\nmss = 100",
+            "[tcp]\nmtu = 100",
+            "[tcp]\nack_frequency = 0",
+            "[tcp]\nmax_retransmits = 0",
+            "[tcp]\nwindow_scale = 15",
+            "[tcp]\nreceive_buffer_size = 0",
+            "[tcp]\nrto_lower_bound = 0",
+            "[tcp]\nrto_lower_bound = 90000",
+            "[tcp]\nrto_upper_bound = 50",
+        ] {
+            must_let!(let Err(Fail::Invalid { .. }) = load_str(Options::<TestRuntime>::default(), config));
+        }
+    }
+
+    #[test]
+    fn test_rto_bounds_any_order() {
+        for keys in &[
+            ["tcp.rto_lower_bound", "tcp.rto_upper_bound"],
+            ["tcp.rto_upper_bound", "tcp.rto_lower_bound"],
+        ] {
+            let mut staging = Staging::new(Options::<TestRuntime>::default());
+            for key in keys {
+                let value = if *key == "tcp.rto_lower_bound" { "70000" } else { "120000" };
+                staging.apply(key, value).unwrap();
+            }
+            let options = staging.finish().unwrap();
+            assert_eq!(options.tcp.rto_lower_bound(), Duration::from_secs(70));
+            assert_eq!(options.tcp.rto_upper_bound(), Duration::from_secs(120));
+        }
+    }
+}
@@ -4,22 +4,44 @@
 use crate::{
     fail::Fail,
     file_table::{File, FileDescriptor, FileTable},
+    filter::{PacketFilter, Verdict},
+    netstat::SocketInfo,
     operations::ResultFuture,
+    options::Options,
+    pcap::{CaptureHandle, CaptureOptions, RollingCapture},
+    prometheus,
     protocols::{
         arp,
-        ethernet2::frame::{EtherType2, Ethernet2Header},
-        ipv4,
-        tcp::operations::{AcceptFuture, ConnectFuture, PopFuture, PushFuture},
+        ethernet2::{
+            frame::{EtherType2, Ethernet2Header},
+            MacAddress,
+        },
+        ipv4, tcp,
+        tcp::{
+            cc::CongestionControlConstructor,
+            operations::{
+                AcceptFuture, ConnectFuture, IncomingStream, PeekFuture, PopExactFuture, PopFuture,
+                PopMaxFuture, PushFuture,
+            },
+        },
+        udp,
         udp::{UdpOperation, UdpPopFuture},
         Protocol,
     },
-    runtime::Runtime,
-    scheduler::Operation,
+    runtime::{Resource, Runtime},
+    scheduler::{Operation, SchedulerStats},
+    stats::{Counters, DropReason, Stats},
+    tap::{Direction, TappedFrame},
+    timer::TimerStats,
+    trace::{self, TraceFilter, Tracer},
+};
+use std::{
+    future::Future,
+    net::{Ipv4Addr, Shutdown},
+    task::{Context, Poll},
+    time::Duration,
 };
-use std::{future::Future, net::Ipv4Addr, time::Duration};
 
-#[cfg(test)]
-use crate::protocols::ethernet2::MacAddress;
 #[cfg(test)]
 use std::collections::HashMap;
 
@@ -29,37 +51,258 @@ pub struct Engine<RT: Runtime> {
     arp: arp::Peer<RT>,
     ipv4: ipv4::Peer<RT>,
     file_table: FileTable,
+    counters: Counters,
+    tracer: Tracer,
 }
 
-impl<RT: Runtime> Engine<RT> {
-    pub fn new(rt: RT) -> Result<Self, Fail> {
+/// Builds an [Engine], starting from the options provided by the runtime and overriding them as
+/// requested.
+pub struct EngineBuilder<RT: Runtime> {
+    rt: RT,
+    arp_options: arp::Options,
+    tcp_options: tcp::Options<RT>,
+    udp_options: udp::Options,
+}
+
+impl<RT: Runtime> EngineBuilder<RT> {
+    pub fn new(rt: RT) -> Self {
+        Self {
+            arp_options: rt.arp_options(),
+            tcp_options: rt.tcp_options(),
+            udp_options: rt.udp_options(),
+            rt,
+        }
+    }
+
+    /// Takes the ARP, TCP and UDP options from `options`. Addresses and the RNG seed are owned by
+    /// the runtime and are not consulted here.
+    pub fn options(self, options: Options<RT>) -> Self {
+        self.arp_options(options.arp)
+            .tcp_options(options.tcp)
+            .udp_options(options.udp)
+    }
+
+    pub fn arp_options(mut self, value: arp::Options) -> Self {
+        self.arp_options = value;
+        self
+    }
+
+    pub fn tcp_options(mut self, value: tcp::Options<RT>) -> Self {
+        self.tcp_options = value;
+        self
+    }
+
+    pub fn udp_options(mut self, value: udp::Options) -> Self {
+        self.udp_options = value;
+        self
+    }
+
+    pub fn build(self) -> Result<Engine<RT>, Fail> {
+        let rt = self.rt;
         let now = rt.now();
+        let offload = rt.checksum_offload();
         let file_table = FileTable::new();
-        let arp = arp::Peer::new(now, rt.clone(), rt.arp_options())?;
-        let ipv4 = ipv4::Peer::new(rt.clone(), arp.clone(), file_table.clone());
+        let counters = Counters::default();
+        let arp = arp::Peer::new(now, rt.clone(), self.arp_options)?;
+        let ipv4 = ipv4::Peer::new(
+            rt.clone(),
+            arp.clone(),
+            file_table.clone(),
+            counters.clone(),
+            self.tcp_options.with_checksum_offload(offload),
+            self.udp_options.with_checksum_offload(offload),
+        );
         Ok(Engine {
             rt,
             arp,
             ipv4,
             file_table,
+            counters,
+            tracer: Tracer::default(),
         })
     }
+}
+
+impl<RT: Runtime> Engine<RT> {
+    pub fn new(rt: RT) -> Result<Self, Fail> {
+        EngineBuilder::new(rt).build()
+    }
 
     pub fn rt(&self) -> &RT {
         &self.rt
     }
 
+    /// Returns the MIB counters of the IP, ICMP, TCP and UDP layers.
+    pub fn stats(&self) -> Stats {
+        self.counters.get()
+    }
+
+    /// Calls `f` with every frame transmitted and received from now on. Fails if the runtime has
+    /// no [crate::tap::PacketTap].
+    pub fn tap<F: Fn(&TappedFrame) + 'static>(&mut self, f: F) -> Result<(), Fail> {
+        let tap = self.rt.packet_tap().ok_or(Fail::Unsupported {
+            details: "runtime has no packet tap",
+        })?;
+        tap.subscribe(f);
+        Ok(())
+    }
+
+    /// Returns the packet filter evaluated on every frame of this engine, to configure its rules.
+    /// Fails if the runtime has no [PacketFilter].
+    pub fn packet_filter(&self) -> Result<PacketFilter, Fail> {
+        self.rt.packet_filter().ok_or(Fail::Unsupported {
+            details: "runtime has no packet filter",
+        })
+    }
+
+    /// Starts a rolling capture of the frames of this engine, which runs until stopped through
+    /// the returned handle. Fails if the runtime has no [crate::tap::PacketTap].
+    pub fn capture(&mut self, options: CaptureOptions) -> Result<CaptureHandle, Fail> {
+        let tap = self.rt.packet_tap().ok_or(Fail::Unsupported {
+            details: "runtime has no packet tap",
+        })?;
+        let handle = CaptureHandle::new(RollingCapture::new(options, self.rt.now()));
+        let h = handle.clone();
+        tap.subscribe(move |frame| h.write(frame));
+        Ok(handle)
+    }
+
+    /// Starts or stops logging a header summary and a hexdump of every frame of `fd`, which must be
+    /// bound or connected. Fails if the runtime has no [crate::tap::PacketTap].
+    pub fn trace(&mut self, fd: FileDescriptor, enabled: bool) -> Result<(), Fail> {
+        if !enabled {
+            self.tracer.untrace(fd);
+            return Ok(());
+        }
+        let tap = self.rt.packet_tap().ok_or(Fail::Unsupported {
+            details: "runtime has no packet tap",
+        })?;
+        let socket = self
+            .connections()
+            .into_iter()
+            .find(|s| s.fd == fd)
+            .ok_or(Fail::BadFileDescriptor {})?;
+        let local = socket.local.ok_or(Fail::Malformed {
+            details: "Socket not bound",
+        })?;
+        let filter = TraceFilter {
+            protocol: socket.protocol,
+            local,
+            remote: socket.remote,
+        };
+        if !self.tracer.set_subscribed() {
+            let tracer = self.tracer.clone();
+            tap.subscribe(move |frame| tracer.on_frame(frame));
+        }
+        self.tracer.trace(fd, filter);
+        Ok(())
+    }
+
+    /// Describes every open socket, ordered by file descriptor.
+    pub fn connections(&self) -> Vec<SocketInfo> {
+        let mut sockets = self.ipv4.tcp.sockets();
+        sockets.extend(self.ipv4.udp.sockets());
+        sockets.sort_by_key(|s| s.fd);
+        sockets
+    }
+
+    /// Returns how the scheduler of the runtime has been polled.
+    pub fn scheduler_stats(&self) -> SchedulerStats {
+        self.rt.scheduler().stats()
+    }
+
+    /// Returns the counters of the runtime timer, if it keeps them.
+    pub fn timer_stats(&self) -> Option<TimerStats> {
+        self.rt.timer_stats()
+    }
+
+    /// Renders statistics in the Prometheus text exposition format, for scraping.
+    pub fn prometheus_metrics(&self) -> String {
+        prometheus::render(
+            &self.counters.get(),
+            &self.connections(),
+            &self.scheduler_stats(),
+            self.timer_stats().as_ref(),
+        )
+    }
+
     /// New incoming data has arrived. Route it to the correct parse out the Ethernet header and
     /// allow the correct protocol to handle it. The underlying protocol will futher parse the data
     /// and inform the correct task that its data has arrived.
     pub fn receive(&mut self, bytes: RT::Buf) -> Result<(), Fail> {
-        let (header, payload) = Ethernet2Header::parse(bytes)?;
-        debug!("Engine received {:?}", header);
-        if self.rt.local_link_addr() != header.dst_addr && !header.dst_addr.is_broadcast() {
+        let local_link_addr = self.rt.local_link_addr();
+        self.receive_frame(bytes, local_link_addr)
+    }
+
+    /// Delivers a burst of inbound frames, such as the ones handed over by a poll-mode driver in a
+    /// single call, as [Engine::receive] would one after the other.
+    ///
+    /// Frames are processed in order. A frame that fails to be delivered is logged and dropped
+    /// without affecting the rest of the burst. The only work shared across the burst is that of
+    /// pure ACKs: a TCP connection receiving several in the burst only processes the latest, once
+    /// the whole burst has been delivered. Returns the number of frames delivered.
+    pub fn receive_batch<I: IntoIterator<Item = RT::Buf>>(&mut self, frames: I) -> usize {
+        let local_link_addr = self.rt.local_link_addr();
+        let mut delivered = 0;
+        self.ipv4.tcp.begin_batch();
+        for frame in frames {
+            match self.receive_frame(frame, local_link_addr) {
+                Ok(()) => delivered += 1,
+                Err(e) => warn!("Dropped packet: {:?}", e),
+            }
+        }
+        self.ipv4.tcp.end_batch();
+        delivered
+    }
+
+    fn receive_frame(&mut self, bytes: RT::Buf, local_link_addr: MacAddress) -> Result<(), Fail> {
+        self.rt.on_receive(&bytes);
+        if let Some(tap) = self.rt.packet_tap() {
+            tap.notify(Direction::Receive, self.rt.now(), &bytes);
+        }
+        if self.rt.exhausted(Resource::Buffer) {
+            self.counters.count_drop(DropReason::NoBuffer);
+            return Err(Fail::ResourceExhausted {
+                details: "no buffer for received frame",
+            });
+        }
+        let verdict = match self.rt.packet_filter() {
+            Some(filter) => filter.evaluate(Direction::Receive, &bytes),
+            None => Verdict::Accept,
+        };
+        // Keep what is needed to answer a rejected frame, as parsing consumes it.
+        let rejected = match verdict {
+            Verdict::Reject => trace::summarize(&bytes).map(|s| (s, icmp_quote(&bytes))),
+            _ => None,
+        };
+        let (header, payload) = match Ethernet2Header::parse(bytes) {
+            Ok(r) => r,
+            Err(e) => {
+                self.counters.count_drop(match e {
+                    Fail::Unsupported { .. } => DropReason::Unsupported,
+                    _ => DropReason::Malformed,
+                });
+                return Err(e);
+            }
+        };
+        datapath!(Debug, "Engine received {}", header);
+        if local_link_addr != header.dst_addr && !header.dst_addr.is_broadcast() {
+            self.counters.count_drop(DropReason::NotForUs);
             return Err(Fail::Ignored {
                 details: "Physical dst_addr mismatch",
             });
         }
+        if verdict != Verdict::Accept {
+            self.counters.count_drop(DropReason::Filtered);
+            if let Some((summary, quote)) = rejected {
+                if let Err(e) = self.ipv4.reject(&summary, &quote) {
+                    warn!("Failed to answer rejected frame: {:?}", e);
+                }
+            }
+            return Err(Fail::Ignored {
+                details: "Discarded by packet filter",
+            });
+        }
         match header.ether_type {
             EtherType2::Arp => self.arp.receive(payload),
             EtherType2::Ipv4 => self.ipv4.receive(payload),
@@ -74,10 +317,10 @@ impl<RT: Runtime> Engine<RT> {
         self.ipv4.ping(dest_ipv4_addr, timeout)
     }
 
-    pub fn socket(&mut self, protocol: Protocol) -> FileDescriptor {
+    pub fn socket(&mut self, protocol: Protocol) -> Result<FileDescriptor, Fail> {
         match protocol {
             Protocol::Tcp => self.ipv4.tcp.socket(),
-            Protocol::Udp => self.ipv4.udp.socket().unwrap(),
+            Protocol::Udp => self.ipv4.udp.socket(),
         }
     }
 
@@ -176,6 +419,7 @@ impl<RT: Runtime> Engine<RT> {
     }
 
     pub fn close(&mut self, fd: FileDescriptor) -> Result<(), Fail> {
+        self.tracer.untrace(fd);
         match self.file_table.get(fd) {
             Some(File::TcpSocket) => self.ipv4.tcp.close(fd),
             Some(File::UdpSocket) => self.ipv4.udp.close(fd),
@@ -195,7 +439,17 @@ impl<RT: Runtime> Engine<RT> {
         self.ipv4.udp.bind(socket_fd, endpoint)
     }
 
-    pub fn tcp_socket(&mut self) -> FileDescriptor {
+    /// Returns the local endpoint of a UDP socket, once bound.
+    pub fn udp_local_endpoint(&self, socket_fd: FileDescriptor) -> Result<ipv4::Endpoint, Fail> {
+        self.ipv4.udp.local_endpoint(socket_fd)
+    }
+
+    /// Returns the remote endpoint of a UDP socket, if it has a default destination.
+    pub fn udp_remote_endpoint(&self, socket_fd: FileDescriptor) -> Result<ipv4::Endpoint, Fail> {
+        self.ipv4.udp.remote_endpoint(socket_fd)
+    }
+
+    pub fn tcp_socket(&mut self) -> Result<FileDescriptor, Fail> {
         self.ipv4.tcp.socket()
     }
 
@@ -207,6 +461,32 @@ impl<RT: Runtime> Engine<RT> {
         self.ipv4.tcp.connect(socket_fd, remote_endpoint)
     }
 
+    /// Connects like [Engine::tcp_connect], sending `buf` as the first data of the connection.
+    /// With TCP Fast Open, the data goes on the SYN if the remote granted a cookie earlier.
+    pub fn tcp_connect_with_data(
+        &mut self,
+        socket_fd: FileDescriptor,
+        remote_endpoint: ipv4::Endpoint,
+        buf: RT::Buf,
+    ) -> ConnectFuture<RT> {
+        self.ipv4
+            .tcp
+            .connect_with_data(socket_fd, remote_endpoint, buf)
+    }
+
+    /// Connects from `local_endpoint` rather than from an ephemeral port, as
+    /// [Engine::tcp_bind] followed by [Engine::tcp_connect] would.
+    pub fn tcp_connect_from(
+        &mut self,
+        socket_fd: FileDescriptor,
+        local_endpoint: ipv4::Endpoint,
+        remote_endpoint: ipv4::Endpoint,
+    ) -> ConnectFuture<RT> {
+        self.ipv4
+            .tcp
+            .connect_from(socket_fd, local_endpoint, remote_endpoint)
+    }
+
     pub fn tcp_bind(
         &mut self,
         socket_fd: FileDescriptor,
@@ -215,26 +495,182 @@ impl<RT: Runtime> Engine<RT> {
         self.ipv4.tcp.bind(socket_fd, endpoint)
     }
 
+    pub fn tcp_set_reuse_port(
+        &mut self,
+        socket_fd: FileDescriptor,
+        enable: bool,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp.set_reuse_port(socket_fd, enable)
+    }
+
+    /// Makes the connections of the socket use `constructor` for their congestion control.
+    pub fn tcp_set_congestion_ctrl(
+        &mut self,
+        socket_fd: FileDescriptor,
+        constructor: CongestionControlConstructor<RT>,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp.set_congestion_ctrl(socket_fd, constructor)
+    }
+
+    /// Sets whether the connections of the socket send small segments at once rather than
+    /// coalesce them while data is unacknowledged (TCP_NODELAY).
+    pub fn tcp_set_nodelay(&mut self, socket_fd: FileDescriptor, enable: bool) -> Result<(), Fail> {
+        self.ipv4.tcp.set_nodelay(socket_fd, enable)
+    }
+
+    /// Sets the bytes the connections of the socket buffer for the application, which their
+    /// receive window advertises (SO_RCVBUF). See [tcp::Peer::set_receive_buffer_size].
+    pub fn tcp_set_receive_buffer_size(
+        &mut self,
+        socket_fd: FileDescriptor,
+        size: u32,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp.set_receive_buffer_size(socket_fd, size)
+    }
+
+    /// Sets the bytes the connections of the socket hold for sending before pushes fail
+    /// (SO_SNDBUF). See [tcp::Peer::set_send_buffer_size].
+    pub fn tcp_set_send_buffer_size(
+        &mut self,
+        socket_fd: FileDescriptor,
+        size: usize,
+    ) -> Result<(), Fail> {
+        self.ipv4.tcp.set_send_buffer_size(socket_fd, size)
+    }
+
+    /// Rate the congestion control of the connection paces its segments at, in bytes per
+    /// second, if it paces them at all.
+    pub fn tcp_pacing_rate(&self, handle: FileDescriptor) -> Result<Option<u64>, Fail> {
+        self.ipv4.tcp.pacing_rate(handle)
+    }
+
+    /// Returns the local endpoint of a TCP socket, once bound or connecting. See
+    /// [tcp::Peer::local_endpoint].
+    pub fn tcp_local_endpoint(&self, socket_fd: FileDescriptor) -> Result<ipv4::Endpoint, Fail> {
+        self.ipv4.tcp.local_endpoint(socket_fd)
+    }
+
+    /// Returns the remote endpoint of a TCP socket, once connecting or accepted. See
+    /// [tcp::Peer::remote_endpoint].
+    pub fn tcp_remote_endpoint(&self, socket_fd: FileDescriptor) -> Result<ipv4::Endpoint, Fail> {
+        self.ipv4.tcp.remote_endpoint(socket_fd)
+    }
+
+    /// Takes a snapshot of the internals of the connection, such as its congestion window and
+    /// round-trip time estimates, for tuning and debugging.
+    pub fn tcp_info(&self, handle: FileDescriptor) -> Result<tcp::TcpInfo, Fail> {
+        self.ipv4.tcp.info(handle)
+    }
+
+    /// Tells what the connection is ready for out of `interest`, or registers the waker of `ctx`
+    /// to be woken once it may be, so that one waker can watch many sockets. See
+    /// [tcp::Peer::poll_ready].
+    pub fn tcp_poll_ready(
+        &self,
+        handle: FileDescriptor,
+        interest: tcp::Readiness,
+        ctx: &mut Context,
+    ) -> Poll<Result<tcp::Readiness, Fail>> {
+        self.ipv4.tcp.poll_ready(handle, interest, ctx)
+    }
+
     pub fn tcp_accept(&mut self, handle: FileDescriptor) -> AcceptFuture<RT> {
         self.ipv4.tcp.accept(handle)
     }
 
+    pub fn tcp_incoming(&mut self, handle: FileDescriptor) -> IncomingStream<RT> {
+        self.ipv4.tcp.incoming(handle)
+    }
+
     pub fn tcp_push(&mut self, socket_fd: FileDescriptor, buf: RT::Buf) -> PushFuture<RT> {
         self.ipv4.tcp.push(socket_fd, buf)
     }
 
+    /// Pushes several buffers as one, without concatenating them first. See
+    /// [tcp::Peer::pushv].
+    pub fn tcp_pushv(&mut self, socket_fd: FileDescriptor, bufs: &[RT::Buf]) -> PushFuture<RT> {
+        self.ipv4.tcp.pushv(socket_fd, bufs)
+    }
+
     pub fn tcp_pop(&mut self, socket_fd: FileDescriptor) -> PopFuture<RT> {
         self.ipv4.tcp.pop(socket_fd)
     }
 
+    /// Pops exactly `len` bytes, which suits length-prefixed protocols. See
+    /// [tcp::Peer::pop_exact].
+    pub fn tcp_pop_exact(&mut self, socket_fd: FileDescriptor, len: usize) -> PopExactFuture<RT> {
+        self.ipv4.tcp.pop_exact(socket_fd, len)
+    }
+
+    /// Pops no more than `max_len` bytes, splitting the data buffered if needed. See
+    /// [tcp::Peer::pop_max].
+    pub fn tcp_pop_max(&mut self, socket_fd: FileDescriptor, max_len: usize) -> PopMaxFuture<RT> {
+        self.ipv4.tcp.pop_max(socket_fd, max_len)
+    }
+
+    /// Returns the received data without consuming it, so that a parser can look at a header
+    /// before popping the message. See [tcp::Peer::peek].
+    pub fn tcp_peek(&mut self, socket_fd: FileDescriptor) -> PeekFuture<RT> {
+        self.ipv4.tcp.peek(socket_fd)
+    }
+
     pub fn tcp_close(&mut self, socket_fd: FileDescriptor) -> Result<(), Fail> {
         self.ipv4.tcp.close(socket_fd)
     }
 
+    /// Resets a connection at once, dropping its buffered data. See [tcp::Peer::abort].
+    pub fn tcp_abort(&mut self, socket_fd: FileDescriptor) -> Result<(), Fail> {
+        self.ipv4.tcp.abort(socket_fd)
+    }
+
+    /// Shuts down reading, writing, or both, as for a half-close. See [tcp::Peer::shutdown].
+    pub fn tcp_shutdown(&mut self, socket_fd: FileDescriptor, how: Shutdown) -> Result<(), Fail> {
+        self.ipv4.tcp.shutdown(socket_fd, how)
+    }
+
     pub fn tcp_listen(&mut self, socket_fd: FileDescriptor, backlog: usize) -> Result<(), Fail> {
         self.ipv4.tcp.listen(socket_fd, backlog)
     }
 
+    pub fn tcp_export_connection(
+        &mut self,
+        socket_fd: FileDescriptor,
+    ) -> Result<tcp::ConnectionState, Fail> {
+        self.ipv4.tcp.export_connection(socket_fd)
+    }
+
+    pub fn tcp_import_connection(
+        &mut self,
+        state: tcp::ConnectionState,
+    ) -> Result<FileDescriptor, Fail> {
+        self.ipv4.tcp.import_connection(state)
+    }
+
+    pub fn tcp_options(&self) -> tcp::Options<RT> {
+        self.ipv4.tcp.options()
+    }
+
+    /// Changes the TCP options on a live engine. See [tcp::Peer::set_options].
+    pub fn set_tcp_options(&mut self, options: tcp::Options<RT>) {
+        self.ipv4.tcp.set_options(options)
+    }
+
+    /// Calls `f` on every state transition of a TCP connection. See [tcp::Peer::subscribe].
+    pub fn tcp_subscribe<F: Fn(&tcp::StateTransition) + 'static>(&mut self, f: F) {
+        self.ipv4.tcp.subscribe(f)
+    }
+
+    /// Calls `f` with a summary of every TCP connection torn down. See
+    /// [tcp::Peer::subscribe_close].
+    pub fn tcp_subscribe_close<F: Fn(&tcp::CloseSummary) + 'static>(&mut self, f: F) {
+        self.ipv4.tcp.subscribe_close(f)
+    }
+
+    /// Returns a handle to the TCP peer, for use with [tcp::TcpListener] and [tcp::TcpStream].
+    pub fn tcp_peer(&self) -> tcp::Peer<RT> {
+        self.ipv4.tcp.clone()
+    }
+
     #[cfg(test)]
     pub fn arp_query(&self, ipv4_addr: Ipv4Addr) -> impl Future<Output = Result<MacAddress, Fail>> {
         self.arp.query(ipv4_addr)
@@ -255,3 +691,11 @@ impl<RT: Runtime> Engine<RT> {
         self.arp.export_cache()
     }
 }
+
+/// Returns the IPv4 header and first 8 bytes of payload of `frame`, as quoted by ICMP errors
+/// about it (RFC 792).
+fn icmp_quote(frame: &[u8]) -> Vec<u8> {
+    let datagram = frame.get(14..).unwrap_or(&[]);
+    let ihl = datagram.first().map_or(0, |b| (b & 0xf) as usize * 4);
+    datagram[..datagram.len().min(ihl + 8)].to_vec()
+}
@@ -3,16 +3,25 @@
 
 use custom_error::custom_error;
 use float_duration;
-use std::{cell::BorrowMutError, io::Error as IoError, num::TryFromIntError};
+use std::{
+    cell::BorrowMutError,
+    error::Error,
+    fmt,
+    io::{Error as IoError, ErrorKind as IoErrorKind},
+    num::TryFromIntError,
+    sync::Arc,
+};
 
 // the following type alias is needed because the `custom_error!` macro doesn't
 // allow `&` in type specifications.
 type Str = &'static str;
+type BoxedFail = Box<Fail>;
 
 custom_error! {#[derive(Clone, PartialEq)] pub Fail
     ConnectionAborted{} = "connection aborted",
     ConnectionRefused{} = "connection refused",
-    IoError {} = "IO Error",
+    ConnectionReset{} = "connection reset",
+    IoError {source: SharedIoError} = "IO Error ({source})",
     BorrowMutError {} = "BorrowMut Error",
     Ignored{details: Str} = "operation had no effect ({details})",
     Malformed{details: Str} = "encountered a malformed datagram ({details})",
@@ -31,11 +40,49 @@ custom_error! {#[derive(Clone, PartialEq)] pub Fail
     AddressFamilySupport {} = "address family not supported",
     SocketTypeSupport {} = "socket type not supported",
     BadFileDescriptor {} = "bad file descriptor",
+    Context {operation: Str, source: BoxedFail} = "{operation} failed: {source}",
+}
+
+/// An [IoError] kept by a [Fail], shared by its clones. Two are equal if they are of the same
+/// kind and say the same.
+#[derive(Clone, Debug)]
+pub struct SharedIoError(Arc<IoError>);
+
+impl SharedIoError {
+    pub fn kind(&self) -> IoErrorKind {
+        self.0.kind()
+    }
+}
+
+impl PartialEq for SharedIoError {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind() == other.kind() && self.0.to_string() == other.0.to_string()
+    }
+}
+
+impl fmt::Display for SharedIoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for SharedIoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.0.source()
+    }
 }
 
 impl From<IoError> for Fail {
-    fn from(_: IoError) -> Self {
-        Fail::IoError {}
+    fn from(e: IoError) -> Self {
+        Fail::IoError {
+            source: SharedIoError(Arc::new(e)),
+        }
+    }
+}
+
+impl From<Fail> for IoError {
+    fn from(e: Fail) -> Self {
+        IoError::new(e.io_error_kind(), e)
     }
 }
 
@@ -69,10 +116,51 @@ impl From<eui48::ParseError> for Fail {
     }
 }
 impl Fail {
+    /// Records that this failure happened while carrying out `operation`.
+    pub fn context(self, operation: Str) -> Self {
+        Fail::Context {
+            operation,
+            source: Box::new(self),
+        }
+    }
+
+    /// Returns the underlying failure, stripped of any context.
+    pub fn root(&self) -> &Fail {
+        match self {
+            Fail::Context { source, .. } => source.root(),
+            e => e,
+        }
+    }
+
+    /// Returns the [IoErrorKind] closest to this failure.
+    pub fn io_error_kind(&self) -> IoErrorKind {
+        match self.root() {
+            Fail::ConnectionAborted {} => IoErrorKind::ConnectionAborted,
+            Fail::ConnectionRefused {} => IoErrorKind::ConnectionRefused,
+            Fail::ConnectionReset {} => IoErrorKind::ConnectionReset,
+            Fail::IoError { source } => source.kind(),
+            Fail::Malformed { .. } => IoErrorKind::InvalidData,
+            Fail::ResourceNotFound { .. } => IoErrorKind::NotFound,
+            Fail::Timeout {} => IoErrorKind::TimedOut,
+            Fail::TypeMismatch { .. } => IoErrorKind::PermissionDenied,
+            Fail::Unsupported { .. }
+            | Fail::AddressFamilySupport {}
+            | Fail::SocketTypeSupport {} => IoErrorKind::Unsupported,
+            Fail::Invalid { .. } | Fail::OutOfRange { .. } | Fail::BadFileDescriptor {} => {
+                IoErrorKind::InvalidInput
+            }
+            Fail::AddressInUse {} => IoErrorKind::AddrInUse,
+            Fail::AddressNotAvailable {} => IoErrorKind::AddrNotAvailable,
+            _ => IoErrorKind::Other,
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn errno(&self) -> libc::c_int {
         match self {
             Fail::ConnectionAborted {} => libc::ECONNABORTED,
             Fail::ConnectionRefused {} => libc::ECONNREFUSED,
+            Fail::ConnectionReset {} => libc::ECONNRESET,
             Fail::Ignored { .. } => 0,
             Fail::Malformed { .. } => libc::EILSEQ,
             Fail::Misdelivered {} => libc::EHOSTUNREACH,
@@ -83,7 +171,7 @@ impl Fail {
             Fail::Timeout {} => libc::ETIMEDOUT,
             Fail::TypeMismatch { .. } => libc::EPERM,
             Fail::Unsupported { .. } => libc::ENOTSUP,
-            Fail::IoError {} => libc::EIO,
+            Fail::IoError { .. } => libc::EIO,
             Fail::BorrowMutError {} => libc::EINVAL,
             Fail::Invalid { .. } => libc::EINVAL,
             Fail::TooManyOpenedFiles { .. } => libc::EMFILE,
@@ -92,6 +180,7 @@ impl Fail {
             Fail::AddressFamilySupport { .. } => libc::EAFNOSUPPORT,
             Fail::SocketTypeSupport { .. } => libc::ESOCKTNOSUPPORT,
             Fail::BadFileDescriptor { .. } => libc::EBADF,
+            Fail::Context { source, .. } => source.errno(),
         }
     }
 }
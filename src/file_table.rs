@@ -2,7 +2,7 @@
 // Licensed under the MIT license.
 
 use slab::Slab;
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, rc::Rc, time::Instant};
 
 //==============================================================================
 // Constants & Structures
@@ -11,9 +11,16 @@ use std::{cell::RefCell, rc::Rc};
 /// File Descriptor
 pub type FileDescriptor = u32;
 
+/// File Table Entry
+struct Entry {
+    file: File,
+    /// When the file was allocated.
+    opened: Instant,
+}
+
 /// File Table Data
 struct Inner {
-    table: Slab<File>,
+    table: Slab<Entry>,
 }
 
 /// File Table
@@ -43,10 +50,10 @@ impl FileTable {
         }
     }
 
-    /// Allocates a new entry in the target file descriptor table.
-    pub fn alloc(&self, file: File) -> FileDescriptor {
+    /// Allocates a new entry in the target file descriptor table, opened at `now`.
+    pub fn alloc(&self, file: File, now: Instant) -> FileDescriptor {
         let mut inner = self.inner.borrow_mut();
-        let ix = inner.table.insert(file);
+        let ix = inner.table.insert(Entry { file, opened: now });
         ix as FileDescriptor
     }
 
@@ -58,7 +65,13 @@ impl FileTable {
             return None;
         }
 
-        inner.table.get(fd as usize).cloned()
+        inner.table.get(fd as usize).map(|e| e.file)
+    }
+
+    /// Gets the time at which a file descriptor was allocated.
+    pub fn opened(&self, fd: FileDescriptor) -> Option<Instant> {
+        let inner = self.inner.borrow();
+        inner.table.get(fd as usize).map(|e| e.opened)
     }
 
     /// Releases an entry in the target file descriptor table.
@@ -69,7 +82,7 @@ impl FileTable {
             return None;
         }
 
-        Some(inner.table.remove(fd as usize))
+        Some(inner.table.remove(fd as usize).file)
     }
 }
 
@@ -0,0 +1,308 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! A chain of rules evaluated on every TCP and UDP frame an engine transmits and receives, so
+//! embedded services can enforce simple policies without an external firewall.
+//!
+//! Rules are tried in order and the first one that matches decides what happens to the frame.
+//! Frames that match no rule get the default verdict. Frames other than TCP and UDP over IPv4
+//! are always accepted.
+
+use crate::{
+    protocols::Protocol,
+    tap::Direction,
+    trace::{self, FrameSummary},
+};
+use std::{cell::RefCell, net::Ipv4Addr, ops::RangeInclusive, rc::Rc};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+pub const TCP_URG: u8 = 0x20;
+pub const TCP_ECE: u8 = 0x40;
+pub const TCP_CWR: u8 = 0x80;
+
+/// What happens to a frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    /// Let the frame through.
+    Accept,
+    /// Discard the frame silently.
+    Drop,
+    /// Discard the frame. A received TCP segment is answered with a RST and a received UDP
+    /// datagram with an ICMP destination unreachable, while the sender of a transmitted frame
+    /// gets an error.
+    Reject,
+}
+
+/// Addresses within a prefix.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Prefix {
+    addr: u32,
+    mask: u32,
+}
+
+/// Frames a rule applies to, and its verdict. Criteria left unset match any frame.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rule {
+    verdict: Verdict,
+    direction: Option<Direction>,
+    protocol: Option<Protocol>,
+    src: Option<Prefix>,
+    dst: Option<Prefix>,
+    src_port: Option<RangeInclusive<u16>>,
+    dst_port: Option<RangeInclusive<u16>>,
+    /// Mask of the TCP flags looked at, and the value they must have.
+    tcp_flags: Option<(u8, u8)>,
+}
+
+/// Rules shared between the engine, which evaluates them on received frames, the runtime, which
+/// evaluates them on transmitted frames, and the application that configures them.
+#[derive(Clone, Default)]
+pub struct PacketFilter {
+    inner: Rc<RefCell<Inner>>,
+}
+
+struct Inner {
+    rules: Vec<Rule>,
+    /// Frames matched by each rule.
+    hits: Vec<u64>,
+    default: Verdict,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [Prefix].
+impl Prefix {
+    fn new(addr: Ipv4Addr, prefix_len: u8) -> Self {
+        assert!(prefix_len <= 32, "invalid prefix length {}", prefix_len);
+        let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+        Self {
+            addr: u32::from(addr) & mask,
+            mask,
+        }
+    }
+
+    fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & self.mask == self.addr
+    }
+}
+
+/// Associate functions for [Rule].
+impl Rule {
+    /// Returns a rule matching every frame with `verdict`.
+    pub fn new(verdict: Verdict) -> Self {
+        Self {
+            verdict,
+            direction: None,
+            protocol: None,
+            src: None,
+            dst: None,
+            src_port: None,
+            dst_port: None,
+            tcp_flags: None,
+        }
+    }
+
+    /// Matches frames going in `direction` only.
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = Some(direction);
+        self
+    }
+
+    /// Matches frames of `protocol` only.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    /// Matches frames from the first `prefix_len` bits of `addr` only.
+    pub fn src(mut self, addr: Ipv4Addr, prefix_len: u8) -> Self {
+        self.src = Some(Prefix::new(addr, prefix_len));
+        self
+    }
+
+    /// Matches frames to the first `prefix_len` bits of `addr` only.
+    pub fn dst(mut self, addr: Ipv4Addr, prefix_len: u8) -> Self {
+        self.dst = Some(Prefix::new(addr, prefix_len));
+        self
+    }
+
+    /// Matches frames from a port in `ports` only.
+    pub fn src_port(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.src_port = Some(ports);
+        self
+    }
+
+    /// Matches frames to a port in `ports` only.
+    pub fn dst_port(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.dst_port = Some(ports);
+        self
+    }
+
+    /// Matches TCP segments whose flags in `mask` are set as in `value` only. For instance, a
+    /// mask of `TCP_SYN | TCP_ACK` and a value of `TCP_SYN` matches connection requests.
+    pub fn tcp_flags(mut self, mask: u8, value: u8) -> Self {
+        self.protocol = Some(Protocol::Tcp);
+        self.tcp_flags = Some((mask, value & mask));
+        self
+    }
+
+    pub fn verdict(&self) -> Verdict {
+        self.verdict
+    }
+
+    /// Returns whether the rule applies to a frame summarized by `summary`.
+    pub fn matches(&self, direction: Direction, summary: &FrameSummary) -> bool {
+        self.direction.map_or(true, |d| d == direction)
+            && self.protocol.map_or(true, |p| p == summary.protocol)
+            && self.src.map_or(true, |p| p.contains(summary.src.addr))
+            && self.dst.map_or(true, |p| p.contains(summary.dst.addr))
+            && self
+                .src_port
+                .as_ref()
+                .map_or(true, |r| r.contains(&summary.src.port.into()))
+            && self
+                .dst_port
+                .as_ref()
+                .map_or(true, |r| r.contains(&summary.dst.port.into()))
+            && self
+                .tcp_flags
+                .map_or(true, |(mask, value)| summary.tcp_flags & mask == value)
+    }
+}
+
+/// Associate functions for [PacketFilter].
+impl PacketFilter {
+    /// Appends `rule` to the chain.
+    pub fn push(&self, rule: Rule) {
+        let mut inner = self.inner.borrow_mut();
+        inner.rules.push(rule);
+        inner.hits.push(0);
+    }
+
+    /// Removes every rule.
+    pub fn clear(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.rules.clear();
+        inner.hits.clear();
+    }
+
+    /// Sets the verdict of frames that match no rule.
+    pub fn set_default(&self, verdict: Verdict) {
+        self.inner.borrow_mut().default = verdict;
+    }
+
+    /// Returns whether every frame is accepted, so that evaluating frames can be skipped.
+    pub fn accepts_all(&self) -> bool {
+        let inner = self.inner.borrow();
+        inner.rules.is_empty() && inner.default == Verdict::Accept
+    }
+
+    /// Returns the number of frames matched by each rule, in chain order.
+    pub fn hits(&self) -> Vec<u64> {
+        self.inner.borrow().hits.clone()
+    }
+
+    /// Returns the verdict for `data`, a frame starting with its Ethernet header. Only the
+    /// headers need to be present.
+    pub fn evaluate(&self, direction: Direction, data: &[u8]) -> Verdict {
+        if self.accepts_all() {
+            return Verdict::Accept;
+        }
+        let mut inner = self.inner.borrow_mut();
+        let summary = match trace::summarize(data) {
+            Some(s) => s,
+            None => return Verdict::Accept,
+        };
+        let inner = &mut *inner;
+        match inner
+            .rules
+            .iter()
+            .position(|r| r.matches(direction, &summary))
+        {
+            Some(i) => {
+                inner.hits[i] += 1;
+                inner.rules[i].verdict
+            }
+            None => inner.default,
+        }
+    }
+}
+
+//==============================================================================
+// Trait Implementations
+//==============================================================================
+
+impl Default for Inner {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            hits: Vec::new(),
+            default: Verdict::Accept,
+        }
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{Rule, Verdict, TCP_ACK, TCP_SYN};
+    use crate::{
+        protocols::{ip, ipv4, Protocol},
+        tap::Direction,
+        test_helpers,
+        trace::FrameSummary,
+    };
+    use std::{convert::TryFrom, net::Ipv4Addr};
+
+    #[test]
+    fn test_rule_matches() {
+        let port = |p| ip::Port::try_from(p).unwrap();
+        let syn = FrameSummary {
+            protocol: Protocol::Tcp,
+            src: ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(50000)),
+            dst: ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(22)),
+            tcp_flags: TCP_SYN,
+            detail: String::new(),
+        };
+
+        let rule = Rule::new(Verdict::Drop)
+            .direction(Direction::Receive)
+            .src(Ipv4Addr::new(192, 168, 1, 0), 24)
+            .dst_port(20..=23)
+            .tcp_flags(TCP_SYN | TCP_ACK, TCP_SYN);
+        assert!(rule.matches(Direction::Receive, &syn));
+        assert!(!rule.matches(Direction::Transmit, &syn));
+        let syn_ack = FrameSummary {
+            tcp_flags: TCP_SYN | TCP_ACK,
+            ..syn.clone()
+        };
+        assert!(!rule.matches(Direction::Receive, &syn_ack));
+        let udp = FrameSummary {
+            protocol: Protocol::Udp,
+            tcp_flags: 0,
+            ..syn.clone()
+        };
+        assert!(!rule.matches(Direction::Receive, &udp));
+
+        // Prefixes of zero and full length.
+        assert!(Rule::new(Verdict::Drop)
+            .src(Ipv4Addr::UNSPECIFIED, 0)
+            .matches(Direction::Receive, &syn));
+        assert!(!Rule::new(Verdict::Drop)
+            .dst(test_helpers::CARRIE_IPV4, 32)
+            .matches(Direction::Receive, &syn));
+    }
+}
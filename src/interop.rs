@@ -73,8 +73,10 @@ impl dmtr_qresult_t {
                 qr_qt: qt,
                 qr_value: unsafe { mem::zeroed() },
             },
-            OperationResult::Accept(new_qd) => {
-                let sin = unsafe { mem::zeroed() };
+            OperationResult::Accept(new_qd, addr) => {
+                let mut sin: sockaddr_in = unsafe { mem::zeroed() };
+                sin.sin_port = addr.port.into();
+                sin.sin_addr.s_addr = u32::from_le_bytes(addr.addr.octets());
                 let qr_value = dmtr_qr_value_t {
                     ares: dmtr_accept_result_t {
                         qd: new_qd as c_int,
@@ -9,6 +9,7 @@
 #![feature(new_uninit)]
 #![feature(maybe_uninit_uninit_array, maybe_uninit_extra, maybe_uninit_ref)]
 #![feature(never_type)]
+#![feature(once_cell)]
 #![feature(raw)]
 #![feature(try_blocks)]
 #![deny(clippy::all)]
@@ -25,19 +26,34 @@ extern crate log;
 #[macro_use]
 extern crate derive_more;
 
+// Declared first so its macros are visible to the modules below.
+#[macro_use]
+pub mod logging;
+
 pub mod collections;
+#[cfg(feature = "config")]
+pub mod config;
 pub mod engine;
 pub mod fail;
 pub mod file_table;
+pub mod filter;
 mod futures_utility;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod interop;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod libos;
-pub mod logging;
+pub mod netstat;
 pub mod operations;
 pub mod options;
+pub mod pcap;
+pub mod prometheus;
 pub mod protocols;
+pub mod record;
 pub mod runtime;
 pub mod scheduler;
+pub mod stats;
 pub mod sync;
+pub mod tap;
 pub mod test_helpers;
 pub mod timer;
+pub mod trace;
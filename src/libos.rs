@@ -12,7 +12,8 @@ use crate::{
     operations::OperationResult,
     protocols::ipv4::Endpoint,
     protocols::Protocol,
-    runtime::Runtime,
+    record::{Event, Trace},
+    runtime::{Resource, Runtime, RuntimeBuf},
     scheduler::{Operation, SchedulerHandle},
 };
 use libc::c_int;
@@ -32,6 +33,13 @@ pub struct LibOS<RT: Runtime> {
     engine: Engine<RT>,
     rt: RT,
     ts_iters: usize,
+    recorder: Option<Recorder>,
+}
+
+/// Trace being recorded, with the time at which recording started.
+struct Recorder {
+    start: Instant,
+    trace: Trace,
 }
 
 impl<RT: Runtime> LibOS<RT> {
@@ -41,6 +49,7 @@ impl<RT: Runtime> LibOS<RT> {
             engine,
             rt,
             ts_iters: 0,
+            recorder: None,
         })
     }
 
@@ -48,6 +57,99 @@ impl<RT: Runtime> LibOS<RT> {
         &self.rt
     }
 
+    /// Starts recording everything that drives the engine into a [Trace]. Clock advances are
+    /// recorded relative to the current time of the runtime.
+    pub fn start_recording(&mut self) {
+        self.recorder = Some(Recorder {
+            start: self.rt.now(),
+            trace: Trace::default(),
+        });
+    }
+
+    /// Stops recording and returns the recorded trace, if any.
+    pub fn stop_recording(&mut self) -> Option<Trace> {
+        self.recorder.take().map(|recorder| recorder.trace)
+    }
+
+    fn record<F: FnOnce() -> Event>(&mut self, event: F) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.trace.push(event());
+        }
+    }
+
+    /// Reproduces the execution recorded in `trace`, with recording started at `start`. Frames
+    /// come from the trace instead of the runtime, so this runtime must start in the same state
+    /// as the recording one.
+    pub fn replay(&mut self, trace: &Trace, start: Instant) -> Result<(), Fail> {
+        let recorder = self.recorder.take();
+        let result = trace
+            .events
+            .iter()
+            .try_for_each(|event| self.replay_event(event, start));
+        self.recorder = recorder;
+        result
+    }
+
+    fn replay_event(&mut self, event: &Event, start: Instant) -> Result<(), Fail> {
+        // API calls fail during replay exactly as they did while recording.
+        let result = match event {
+            Event::Poll(count) => {
+                for _ in 0..*count {
+                    self.rt.scheduler().poll();
+                }
+                Ok(())
+            }
+            Event::Receive(frames) => {
+                let frames = frames.iter().map(|frame| RT::Buf::from_slice(frame));
+                self.engine.receive_batch(frames);
+                Ok(())
+            }
+            Event::AdvanceClock(offset) => {
+                self.rt.advance_clock(start + *offset);
+                Ok(())
+            }
+            Event::Socket {
+                domain,
+                socket_type,
+            } => self.socket(*domain, *socket_type, 0).map(drop),
+            Event::Bind { fd, local } => self.bind(*fd, *local),
+            Event::Listen { fd, backlog } => self.listen(*fd, *backlog),
+            Event::Accept { fd } => self.accept(*fd).map(drop),
+            Event::Connect { fd, remote } => self.connect(*fd, *remote).map(drop),
+            Event::Close { fd } => self.close(*fd),
+            Event::Push { fd, data } => self.push2(*fd, RT::Buf::from_slice(data)).map(drop),
+            Event::PushTo { fd, data, to } => {
+                self.pushto2(*fd, RT::Buf::from_slice(data), *to).map(drop)
+            }
+            Event::Pop { fd } => self.pop(*fd).map(drop),
+            Event::DropQToken { qt } => {
+                self.drop_qtoken(*qt);
+                Ok(())
+            }
+            Event::Take { qt } => {
+                let handle = self
+                    .rt
+                    .scheduler()
+                    .from_raw_handle(*qt)
+                    .ok_or(Fail::Invalid {
+                        details: "replay diverged: unknown queue token",
+                    })?;
+                if !handle.has_completed() {
+                    handle.into_raw();
+                    return Err(Fail::Invalid {
+                        details: "replay diverged: operation not completed",
+                    });
+                }
+                self.take_operation(*qt, handle);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            debug!("Replayed {:?} failed: {:?}", event, e);
+        }
+        Ok(())
+    }
+
     ///
     /// **Brief**
     ///
@@ -81,6 +183,10 @@ impl<RT: Runtime> LibOS<RT> {
             socket_type,
             _protocol
         );
+        self.record(|| Event::Socket {
+            domain,
+            socket_type,
+        });
         if domain != libc::AF_INET {
             return Err(Fail::AddressFamilySupport {});
         }
@@ -89,7 +195,7 @@ impl<RT: Runtime> LibOS<RT> {
             libc::SOCK_DGRAM => Protocol::Udp,
             _ => return Err(Fail::SocketTypeSupport {}),
         };
-        Ok(self.engine.socket(engine_protocol))
+        self.engine.socket(engine_protocol)
     }
 
     ///
@@ -107,6 +213,7 @@ impl<RT: Runtime> LibOS<RT> {
         #[cfg(feature = "profiler")]
         timer!("catnip::bind");
         trace!("bind(): fd={:?} local={:?}", fd, local);
+        self.record(|| Event::Bind { fd, local });
         self.engine.bind(fd, local)
     }
 
@@ -130,6 +237,7 @@ impl<RT: Runtime> LibOS<RT> {
         #[cfg(feature = "profiler")]
         timer!("catnip::listen");
         trace!("listen(): fd={:?} backlog={:?}", fd, backlog);
+        self.record(|| Event::Listen { fd, backlog });
         if backlog == 0 {
             return Err(Fail::Invalid {
                 details: "backlog length",
@@ -154,6 +262,7 @@ impl<RT: Runtime> LibOS<RT> {
         #[cfg(feature = "profiler")]
         timer!("catnip::accept");
         trace!("accept(): {:?}", fd);
+        self.record(|| Event::Accept { fd });
         match self.engine.accept(fd) {
             Ok(future) => Ok(self.rt.scheduler().insert(future).into_raw()),
             Err(fail) => Err(fail),
@@ -176,6 +285,7 @@ impl<RT: Runtime> LibOS<RT> {
         #[cfg(feature = "profiler")]
         timer!("catnip::connect");
         trace!("connect(): fd={:?} remote={:?}", fd, remote);
+        self.record(|| Event::Connect { fd, remote });
         let future = self.engine.connect(fd, remote)?;
         Ok(self.rt.scheduler().insert(future).into_raw())
     }
@@ -194,6 +304,7 @@ impl<RT: Runtime> LibOS<RT> {
         #[cfg(feature = "profiler")]
         timer!("catnip::close");
         trace!("close(): fd={:?}", fd);
+        self.record(|| Event::Close { fd });
         self.engine.close(fd)
     }
 
@@ -204,7 +315,16 @@ impl<RT: Runtime> LibOS<RT> {
         #[cfg(feature = "profiler")]
         timer!("catnip::push");
         trace!("push(): fd={:?}", fd);
+        if self.rt.exhausted(Resource::Buffer) {
+            return Err(Fail::ResourceExhausted {
+                details: "no buffer for pushed data",
+            });
+        }
         let buf = self.rt.clone_sgarray(sga);
+        self.record(|| Event::Push {
+            fd,
+            data: buf.to_vec(),
+        });
         if buf.len() == 0 {
             return Err(Fail::Invalid {
                 details: "zero-length buffer",
@@ -220,6 +340,10 @@ impl<RT: Runtime> LibOS<RT> {
         #[cfg(feature = "profiler")]
         timer!("catnip::push2");
         trace!("push2(): fd={:?}", fd);
+        self.record(|| Event::Push {
+            fd,
+            data: buf.to_vec(),
+        });
         if buf.len() == 0 {
             return Err(Fail::Invalid {
                 details: "zero-length buffer",
@@ -237,7 +361,17 @@ impl<RT: Runtime> LibOS<RT> {
     ) -> Result<QToken, Fail> {
         #[cfg(feature = "profiler")]
         timer!("catnip::pushto");
+        if self.rt.exhausted(Resource::Buffer) {
+            return Err(Fail::ResourceExhausted {
+                details: "no buffer for pushed data",
+            });
+        }
         let buf = self.rt.clone_sgarray(sga);
+        self.record(|| Event::PushTo {
+            fd,
+            data: buf.to_vec(),
+            to,
+        });
         if buf.len() == 0 {
             return Err(Fail::Invalid {
                 details: "zero-length buffer",
@@ -255,6 +389,11 @@ impl<RT: Runtime> LibOS<RT> {
     ) -> Result<QToken, Fail> {
         #[cfg(feature = "profiler")]
         timer!("catnip::pushto2");
+        self.record(|| Event::PushTo {
+            fd,
+            data: buf.to_vec(),
+            to,
+        });
         if buf.len() == 0 {
             return Err(Fail::Invalid {
                 details: "zero-length buffer",
@@ -273,6 +412,7 @@ impl<RT: Runtime> LibOS<RT> {
     pub fn drop_qtoken(&mut self, qt: QToken) {
         #[cfg(feature = "profiler")]
         timer!("catnip::drop_qtoken");
+        self.record(|| Event::DropQToken { qt });
         drop(self.rt.scheduler().from_raw_handle(qt).unwrap());
     }
 
@@ -283,6 +423,7 @@ impl<RT: Runtime> LibOS<RT> {
         timer!("catnip::pop");
 
         trace!("pop(): fd={:?}", fd);
+        self.record(|| Event::Pop { fd });
 
         let future = self.engine.pop(fd)?;
 
@@ -305,7 +446,7 @@ impl<RT: Runtime> LibOS<RT> {
             handle.into_raw();
             return None;
         }
-        let (qd, r) = self.take_operation(handle);
+        let (qd, r) = self.take_operation(qt, handle);
         Some(dmtr_qresult_t::pack(&self.rt, r, qd, qt))
     }
 
@@ -331,7 +472,7 @@ impl<RT: Runtime> LibOS<RT> {
         loop {
             self.poll_bg_work();
             if handle.has_completed() {
-                return self.take_operation(handle);
+                return self.take_operation(qt, handle);
             }
         }
     }
@@ -346,7 +487,7 @@ impl<RT: Runtime> LibOS<RT> {
             // TODO I don't understand what guarantees that this task will be done by the time we
             // get here and make this assert true.
             assert!(handle.has_completed());
-            must_let!(let (_, OperationResult::Push) = self.take_operation(handle));
+            must_let!(let (_, OperationResult::Push) = self.take_operation(qt, handle));
         }
     }
 
@@ -361,7 +502,7 @@ impl<RT: Runtime> LibOS<RT> {
             for (i, &qt) in qts.iter().enumerate() {
                 let handle = self.rt.scheduler().from_raw_handle(qt).unwrap();
                 if handle.has_completed() {
-                    let (qd, r) = self.take_operation(handle);
+                    let (qd, r) = self.take_operation(qt, handle);
                     return (i, dmtr_qresult_t::pack(&self.rt, r, qd, qt));
                 }
                 handle.into_raw();
@@ -378,7 +519,7 @@ impl<RT: Runtime> LibOS<RT> {
             for (i, &qt) in qts.iter().enumerate() {
                 let handle = self.rt.scheduler().from_raw_handle(qt).unwrap();
                 if handle.has_completed() {
-                    let (qd, r) = self.take_operation(handle);
+                    let (qd, r) = self.take_operation(qt, handle);
                     return (i, qd, r);
                 }
                 handle.into_raw();
@@ -394,7 +535,12 @@ impl<RT: Runtime> LibOS<RT> {
     /// and the file descriptor for this connection.
     ///
     /// This function will panic if the specified future had not completed or is _background_ future.
-    fn take_operation(&mut self, handle: SchedulerHandle) -> (FileDescriptor, OperationResult<RT>) {
+    fn take_operation(
+        &mut self,
+        qt: QToken,
+        handle: SchedulerHandle,
+    ) -> (FileDescriptor, OperationResult<RT>) {
+        self.record(|| Event::Take { qt });
         match self.rt.scheduler().take(handle) {
             Operation::Tcp(f) => f.expect_result(),
             Operation::Udp(f) => f.expect_result(),
@@ -405,21 +551,24 @@ impl<RT: Runtime> LibOS<RT> {
     /// Scheduler will poll all futures that are ready to make progress.
     /// Then ask the runtime to receive new data which we will forward to the engine to parse and
     /// route to the correct protocol.
-    fn poll_bg_work(&mut self) {
+    pub fn poll_bg_work(&mut self) {
         self.rt.scheduler().poll();
+        self.record(|| Event::Poll(1));
         for _ in 0..MAX_RECV_ITERS {
             let batch = self.rt.receive();
             if batch.is_empty() {
                 break;
             }
-            for pkt in batch {
-                if let Err(e) = self.engine.receive(pkt) {
-                    warn!("Dropped packet: {:?}", e);
-                }
-            }
+            self.record(|| Event::Receive(batch.iter().map(|frame| frame.to_vec()).collect()));
+            self.engine.receive_batch(batch);
         }
         if self.ts_iters == 0 {
-            self.rt.advance_clock(Instant::now());
+            let now = Instant::now();
+            if let Some(recorder) = self.recorder.as_mut() {
+                let offset = now.saturating_duration_since(recorder.start);
+                recorder.trace.push(Event::AdvanceClock(offset));
+            }
+            self.rt.advance_clock(now);
         }
         self.ts_iters = (self.ts_iters + 1) % TIMER_RESOLUTION;
     }
@@ -1,13 +1,215 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use flexi_logger::Logger;
-use std::sync::Once;
+//! Logger setup.
+//!
+//! The logger is configured either from the `RUST_LOG` environment variable ([initialize]) or
+//! programmatically through a [LogConfig] ([initialize_with]). Log statements on the data path
+//! go through `datapath!` instead, which is compiled out unless the `datapath_logging` feature is
+//! enabled.
+//!
+//! On WebAssembly targets there is no terminal or file system to log to, so the functions here
+//! are no-ops and the embedder is expected to install its own `log` backend.
 
-static INIT_LOG: Once = Once::new();
+use crate::fail::Fail;
+#[cfg(not(target_arch = "wasm32"))]
+use flexi_logger::{DeferredNow, FileSpec, LogSpecBuilder, Logger, LoggerHandle};
+use log::LevelFilter;
+#[cfg(not(target_arch = "wasm32"))]
+use log::Record;
+use std::path::PathBuf;
+#[cfg(not(target_arch = "wasm32"))]
+use std::{io::Write, lazy::SyncOnceCell, sync::Mutex};
 
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Most verbose level logged on the data path.
+pub const DATAPATH_MAX_LEVEL: LevelFilter = if cfg!(feature = "datapath_logging") {
+    LevelFilter::Trace
+} else {
+    LevelFilter::Off
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+static LOGGER: SyncOnceCell<Mutex<LoggerHandle>> = SyncOnceCell::new();
+
+/// Where log records are written.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LogTarget {
+    Stderr,
+    Stdout,
+    /// A file in the given directory, named after the program.
+    File(PathBuf),
+}
+
+/// Programmatic logger configuration.
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+    level: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    target: LogTarget,
+    json: bool,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [LogConfig].
+impl LogConfig {
+    /// Sets the level of modules without a level of their own.
+    pub fn level(mut self, value: LevelFilter) -> Self {
+        self.level = value;
+        self
+    }
+
+    /// Sets the level of `module` (e.g. `"catnip::protocols::tcp"`) and its submodules.
+    pub fn module(mut self, module: &str, value: LevelFilter) -> Self {
+        self.modules.push((module.to_string(), value));
+        self
+    }
+
+    /// Sets where records are written.
+    pub fn target(mut self, value: LogTarget) -> Self {
+        self.target = value;
+        self
+    }
+
+    /// Writes each record as a JSON object on its own line.
+    pub fn json(mut self, value: bool) -> Self {
+        self.json = value;
+        self
+    }
+}
+
+//==============================================================================
+// Trait Implementations
+//==============================================================================
+
+/// Default trait implementation for [LogConfig].
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Warn,
+            modules: Vec::new(),
+            target: LogTarget::Stderr,
+            json: false,
+        }
+    }
+}
+
+//==============================================================================
+// Standalone Functions
+//==============================================================================
+
+/// Starts a logger configured from the `RUST_LOG` environment variable, unless one is running.
+#[cfg(not(target_arch = "wasm32"))]
 pub fn initialize() {
-    INIT_LOG.call_once(|| {
-        Logger::try_with_env().unwrap().start().unwrap();
+    LOGGER.get_or_init(|| Mutex::new(Logger::try_with_env().unwrap().start().unwrap()));
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn initialize() {}
+
+/// Starts a logger configured by `config`. Fails if a logger is already running.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn initialize_with(config: &LogConfig) -> Result<(), Fail> {
+    let mut spec = LogSpecBuilder::new();
+    spec.default(config.level);
+    for (module, level) in &config.modules {
+        spec.module(module, *level);
+    }
+    let mut logger = Logger::with(spec.build());
+    logger = match &config.target {
+        LogTarget::Stderr => logger.log_to_stderr(),
+        LogTarget::Stdout => logger.log_to_stdout(),
+        LogTarget::File(directory) => {
+            logger.log_to_file(FileSpec::default().directory(directory.clone()))
+        }
+    };
+    if config.json {
+        logger = logger.format(json_format);
+    }
+
+    let mut result = Err(Fail::Invalid {
+        details: "Logger already initialized",
     });
+    LOGGER.get_or_try_init(|| {
+        let handle = logger.start().map_err(|e| {
+            warn!("Failed to start logger: {}", e);
+            Fail::Invalid {
+                details: "Failed to start logger",
+            }
+        })?;
+        result = Ok(());
+        Ok(Mutex::new(handle))
+    })?;
+    result
+}
+
+/// Starts a logger configured by `config`.
+#[cfg(target_arch = "wasm32")]
+pub fn initialize_with(_config: &LogConfig) -> Result<(), Fail> {
+    Err(Fail::Unsupported {
+        details: "Loggers are not supported on this target",
+    })
+}
+
+/// Replaces the log specification (e.g. `"info, catnip::protocols::tcp=trace"`) of a running
+/// logger.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_log_spec(spec: &str) -> Result<(), Fail> {
+    initialize();
+    let mut logger = LOGGER.get().unwrap().lock().unwrap();
+    logger.parse_new_spec(spec).map_err(|e| {
+        warn!("Failed to parse log specification: {}", e);
+        Fail::Invalid {
+            details: "Invalid log specification",
+        }
+    })
+}
+
+/// Replaces the log specification of a running logger.
+#[cfg(target_arch = "wasm32")]
+pub fn set_log_spec(_spec: &str) -> Result<(), Fail> {
+    Err(Fail::Unsupported {
+        details: "Log specifications are not supported on this target",
+    })
+}
+
+/// Writes `record` as a single-line JSON object.
+#[cfg(not(target_arch = "wasm32"))]
+fn json_format(
+    w: &mut dyn Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    write!(
+        w,
+        "{{\"time\":\"{}\",\"level\":\"{}\",\"module\":\"{}\",\"message\":\"",
+        now.now().to_rfc3339(),
+        record.level(),
+        record.module_path().unwrap_or("<unknown>"),
+    )?;
+    for c in record.args().to_string().chars() {
+        match c {
+            '"' => write!(w, "\\\"")?,
+            '\\' => write!(w, "\\\\")?,
+            c if c.is_control() => write!(w, "\\u{:04x}", c as u32)?,
+            c => write!(w, "{}", c)?,
+        }
+    }
+    write!(w, "\"}}")
+}
+
+/// Logs on the data path at the given level (e.g. `datapath!(Debug, "received {}", hdr)`).
+/// Compiles to nothing unless the `datapath_logging` feature is enabled.
+macro_rules! datapath {
+    ($lvl:ident, $($arg:tt)+) => {
+        if ::log::Level::$lvl <= $crate::logging::DATAPATH_MAX_LEVEL {
+            log!(::log::Level::$lvl, $($arg)+);
+        }
+    };
 }
@@ -0,0 +1,49 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Snapshot of the sockets of an engine, as listed by `netstat`.
+
+use crate::{
+    file_table::FileDescriptor,
+    protocols::{ipv4, tcp, Protocol},
+};
+use std::time::Duration;
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// State of a socket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SocketState {
+    /// Neither bound nor connected yet.
+    Idle,
+    /// UDP socket bound to a local endpoint.
+    Bound,
+    /// TCP socket waiting for incoming connections.
+    Listening,
+    /// TCP socket waiting for the remote to accept the connection.
+    Connecting,
+    /// TCP connection, in the given state.
+    Established(tcp::State),
+}
+
+/// Description of a socket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SocketInfo {
+    pub fd: FileDescriptor,
+    pub protocol: Protocol,
+    pub local: Option<ipv4::Endpoint>,
+    pub remote: Option<ipv4::Endpoint>,
+    pub state: SocketState,
+    /// Bytes pushed but not yet acknowledged by the remote.
+    pub send_queue: usize,
+    /// Bytes received but not yet popped, or connections not yet accepted for listening sockets.
+    pub recv_queue: usize,
+    /// Time since the socket was opened.
+    pub age: Duration,
+    /// Retransmission timeout of TCP connections, as currently estimated.
+    pub rto: Option<Duration>,
+    /// Smoothed round-trip time of TCP connections, once a round trip got sampled.
+    pub srtt: Option<Duration>,
+}
@@ -42,7 +42,7 @@ where
 
 pub enum OperationResult<RT: Runtime> {
     Connect,
-    Accept(FileDescriptor),
+    Accept(FileDescriptor, ipv4::Endpoint),
     Push,
     Pop(Option<ipv4::Endpoint>, RT::Buf),
     Failed(Fail),
@@ -11,6 +11,7 @@ use std::net::Ipv4Addr;
 #[derive(Clone, Debug)]
 pub struct Options<RT: Runtime> {
     pub arp: arp::Options,
+    pub gateway_ipv4_addr: Option<Ipv4Addr>,
     pub my_ipv4_addr: Ipv4Addr,
     pub my_link_addr: MacAddress,
     pub rng_seed: [u8; 32],
@@ -24,6 +25,7 @@ impl<RT: Runtime> Default for Options<RT> {
         thread_rng().fill(rng_seed.as_mut());
         Options {
             arp: arp::Options::default(),
+            gateway_ipv4_addr: None,
             my_ipv4_addr: Ipv4Addr::new(0, 0, 0, 0),
             my_link_addr: MacAddress::nil(),
             rng_seed,
@@ -39,6 +41,13 @@ impl<RT: Runtime> Options<RT> {
         self
     }
 
+    pub fn gateway_ipv4_addr(mut self, value: Ipv4Addr) -> Self {
+        assert!(!value.is_unspecified());
+        assert!(!value.is_broadcast());
+        self.gateway_ipv4_addr = Some(value);
+        self
+    }
+
     pub fn my_ipv4_addr(mut self, value: Ipv4Addr) -> Self {
         assert!(!value.is_unspecified());
         assert!(!value.is_broadcast());
@@ -62,4 +71,9 @@ impl<RT: Runtime> Options<RT> {
         self.tcp = value;
         self
     }
+
+    pub fn udp(mut self, value: udp::Options) -> Self {
+        self.udp = value;
+        self
+    }
 }
@@ -0,0 +1,795 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Reading of packet captures in the pcap and pcapng formats, replay of their frames into an
+//! [Engine], writing of pcap files, and rolling captures of a running engine.
+//!
+//! Only Ethernet captures are supported.
+
+use crate::{
+    engine::Engine,
+    fail::Fail,
+    runtime::{Runtime, RuntimeBuf},
+    tap::TappedFrame,
+    trace,
+};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Link type of Ethernet captures.
+pub const LINKTYPE_ETHERNET: u32 = 1;
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAP_HEADER_SIZE: usize = 24;
+const PCAP_RECORD_HEADER_SIZE: usize = 16;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const PCAPNG_SIMPLE_PACKET: u32 = 0x0000_0003;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x0000_0006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_OPTION_END: u16 = 0;
+const PCAPNG_OPTION_IF_TSRESOL: u16 = 9;
+
+/// A captured frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PcapFrame {
+    /// Capture time, relative to the Unix epoch.
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+/// Writes frames to a pcap file with nanosecond timestamps.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+/// Limits and filter of a [RollingCapture].
+#[derive(Clone, Debug)]
+pub struct CaptureOptions {
+    directory: PathBuf,
+    prefix: String,
+    max_file_size: u64,
+    max_file_duration: Duration,
+    max_files: usize,
+    port: Option<u16>,
+    address: Option<Ipv4Addr>,
+}
+
+/// Captures frames into a rotating set of pcap files named `<prefix>-<n>.pcap`, starting a new
+/// file when the current one grows too large or too old and deleting the oldest ones.
+pub struct RollingCapture {
+    options: CaptureOptions,
+    writer: Option<PcapWriter<BufWriter<File>>>,
+    /// Bytes written to the current file.
+    file_size: u64,
+    /// Time of the first frame of the current file.
+    file_started: Instant,
+    /// Files written so far, oldest first.
+    files: VecDeque<PathBuf>,
+    next_index: usize,
+    /// Wall-clock time corresponding to `start`, to timestamp frames.
+    wall_start: Duration,
+    start: Instant,
+}
+
+/// Stops the rolling capture of an engine, see [Engine::capture].
+#[derive(Clone)]
+pub struct CaptureHandle {
+    capture: Rc<RefCell<Option<RollingCapture>>>,
+}
+
+/// Interface described by a pcapng section.
+struct Interface {
+    link_type: u32,
+    /// Duration of a timestamp unit, in nanoseconds.
+    resolution: u64,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the file header of an Ethernet capture to `writer`.
+    pub fn new(mut writer: W) -> Result<Self, Fail> {
+        let mut header = [0u8; PCAP_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header[0..4], PCAP_MAGIC_NANOS);
+        LittleEndian::write_u16(&mut header[4..6], 2);
+        LittleEndian::write_u16(&mut header[6..8], 4);
+        LittleEndian::write_u32(&mut header[16..20], u16::MAX as u32);
+        LittleEndian::write_u32(&mut header[20..24], LINKTYPE_ETHERNET);
+        writer
+            .write_all(&header)
+            .map_err(|e| Fail::from(e).context("writing capture header"))?;
+        Ok(Self { writer })
+    }
+
+    /// Appends a frame captured at `timestamp` (relative to the Unix epoch).
+    pub fn write_frame(&mut self, timestamp: Duration, data: &[u8]) -> Result<(), Fail> {
+        let mut record = Vec::with_capacity(PCAP_RECORD_HEADER_SIZE + data.len());
+        record.resize(PCAP_RECORD_HEADER_SIZE, 0);
+        LittleEndian::write_u32(&mut record[0..4], timestamp.as_secs() as u32);
+        LittleEndian::write_u32(&mut record[4..8], timestamp.subsec_nanos());
+        LittleEndian::write_u32(&mut record[8..12], data.len() as u32);
+        LittleEndian::write_u32(&mut record[12..16], data.len() as u32);
+        record.extend_from_slice(data);
+        self.writer
+            .write_all(&record)
+            .map_err(|e| Fail::from(e).context("writing capture record"))
+    }
+
+    pub fn flush(&mut self) -> Result<(), Fail> {
+        self.writer
+            .flush()
+            .map_err(|e| Fail::from(e).context("flushing capture"))
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Associate functions for [CaptureOptions].
+impl CaptureOptions {
+    /// Writes files named `<prefix>-<n>.pcap` to `directory`.
+    pub fn new(directory: &Path, prefix: &str) -> Self {
+        Self {
+            directory: directory.to_path_buf(),
+            prefix: prefix.to_string(),
+            max_file_size: 64 * 1024 * 1024,
+            max_file_duration: Duration::from_secs(3600),
+            max_files: 8,
+            port: None,
+            address: None,
+        }
+    }
+
+    /// Starts a new file before one grows past `value` bytes.
+    pub fn max_file_size(mut self, value: u64) -> Self {
+        self.max_file_size = value;
+        self
+    }
+
+    /// Starts a new file once the current one has been written to for `value`.
+    pub fn max_file_duration(mut self, value: Duration) -> Self {
+        self.max_file_duration = value;
+        self
+    }
+
+    /// Deletes the oldest files beyond the `value` most recent ones.
+    pub fn max_files(mut self, value: usize) -> Self {
+        assert!(value > 0);
+        self.max_files = value;
+        self
+    }
+
+    /// Only captures TCP and UDP frames from or to `value`.
+    pub fn port(mut self, value: u16) -> Self {
+        self.port = Some(value);
+        self
+    }
+
+    /// Only captures TCP and UDP frames from or to `value`.
+    pub fn address(mut self, value: Ipv4Addr) -> Self {
+        self.address = Some(value);
+        self
+    }
+
+    /// Returns whether a frame passes the port and address filters.
+    fn matches(&self, data: &[u8]) -> bool {
+        if self.port.is_none() && self.address.is_none() {
+            return true;
+        }
+        let summary = match trace::summarize(data) {
+            Some(s) => s,
+            None => return false,
+        };
+        let endpoints = [summary.src, summary.dst];
+        let port_matches = self.port.map_or(true, |p| {
+            endpoints.iter().any(|e| {
+                let port: u16 = e.port.into();
+                port == p
+            })
+        });
+        let address_matches = self
+            .address
+            .map_or(true, |a| endpoints.iter().any(|e| e.addr == a));
+        port_matches && address_matches
+    }
+}
+
+/// Associate functions for [RollingCapture].
+impl RollingCapture {
+    /// Creates a capture whose frames are timestamped relative to the wall-clock time at `now`.
+    pub fn new(options: CaptureOptions, now: Instant) -> Self {
+        let wall_start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            options,
+            writer: None,
+            file_size: 0,
+            file_started: now,
+            files: VecDeque::new(),
+            next_index: 0,
+            wall_start,
+            start: now,
+        }
+    }
+
+    /// Appends `frame` if it passes the filter, rotating files as needed.
+    pub fn write(&mut self, frame: &TappedFrame) -> Result<(), Fail> {
+        if !self.options.matches(frame.data) {
+            return Ok(());
+        }
+        let record_size = (PCAP_RECORD_HEADER_SIZE + frame.data.len()) as u64;
+        let too_large = self.file_size + record_size > self.options.max_file_size;
+        let too_old = frame.timestamp.saturating_duration_since(self.file_started)
+            >= self.options.max_file_duration;
+        if self.writer.is_none() || too_large || too_old {
+            self.rotate(frame.timestamp)?;
+        }
+        let timestamp = self.wall_start + frame.timestamp.saturating_duration_since(self.start);
+        self.writer
+            .as_mut()
+            .unwrap()
+            .write_frame(timestamp, frame.data)?;
+        self.file_size += record_size;
+        Ok(())
+    }
+
+    /// Returns the files written so far, oldest first. Older files have been deleted.
+    pub fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.files.iter()
+    }
+
+    pub fn flush(&mut self) -> Result<(), Fail> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Closes the current file and starts the next one.
+    fn rotate(&mut self, now: Instant) -> Result<(), Fail> {
+        self.flush()?;
+        let name = format!("{}-{}.pcap", self.options.prefix, self.next_index);
+        let path = self.options.directory.join(name);
+        let file =
+            File::create(&path).map_err(|e| Fail::from(e).context("creating capture file"))?;
+        self.writer = Some(PcapWriter::new(BufWriter::new(file))?);
+        self.file_size = PCAP_HEADER_SIZE as u64;
+        self.file_started = now;
+        self.next_index += 1;
+        self.files.push_back(path);
+        while self.files.len() > self.options.max_files {
+            let oldest = self.files.pop_front().unwrap();
+            fs::remove_file(&oldest)
+                .map_err(|e| Fail::from(e).context("removing old capture file"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Associate functions for [CaptureHandle].
+impl CaptureHandle {
+    /// Wraps `capture`, to be fed by a packet tap.
+    pub fn new(capture: RollingCapture) -> Self {
+        Self {
+            capture: Rc::new(RefCell::new(Some(capture))),
+        }
+    }
+
+    /// Writes `frame`, stopping the capture if that fails.
+    pub fn write(&self, frame: &TappedFrame) {
+        let mut capture = self.capture.borrow_mut();
+        if let Some(c) = capture.as_mut() {
+            if let Err(e) = c.write(frame) {
+                warn!("Stopping capture: {:?}", e);
+                *capture = None;
+            }
+        }
+    }
+
+    /// Returns whether frames are still being captured.
+    pub fn is_active(&self) -> bool {
+        self.capture.borrow().is_some()
+    }
+
+    /// Flushes the current file and stops capturing, returning the files that were kept.
+    pub fn stop(&self) -> Result<Vec<PathBuf>, Fail> {
+        match self.capture.borrow_mut().take() {
+            Some(mut c) => {
+                c.flush()?;
+                Ok(c.files().cloned().collect())
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+//==============================================================================
+// Standalone Functions
+//==============================================================================
+
+/// Reads the frames of the capture file at `path`.
+pub fn read_file(path: &Path) -> Result<Vec<PcapFrame>, Fail> {
+    let contents = fs::read(path).map_err(|e| Fail::from(e).context("reading capture file"))?;
+    read_frames(&contents)
+}
+
+/// Reads the frames of a capture, in either the pcap or the pcapng format.
+pub fn read_frames(buf: &[u8]) -> Result<Vec<PcapFrame>, Fail> {
+    if buf.len() < 4 {
+        return Err(malformed("Capture too short"));
+    }
+    if LittleEndian::read_u32(&buf[0..4]) == PCAPNG_SECTION_HEADER {
+        read_pcapng(buf)
+    } else {
+        read_pcap(buf)
+    }
+}
+
+/// Feeds `frames` into `engine`, advancing its clock so that the first frame arrives at `start`
+/// and the others keep their relative spacing. Returns the number of frames delivered.
+pub fn replay<RT: Runtime>(engine: &mut Engine<RT>, frames: &[PcapFrame], start: Instant) -> usize {
+    let first = match frames.first() {
+        Some(frame) => frame.timestamp,
+        None => return 0,
+    };
+    let mut delivered = 0;
+    for frame in frames {
+        let now = start + frame.timestamp.checked_sub(first).unwrap_or_default();
+        if now > engine.rt().now() {
+            engine.rt().advance_clock(now);
+            engine.rt().scheduler().poll();
+        }
+        match engine.receive(RT::Buf::from_slice(&frame.data)) {
+            Ok(()) => delivered += 1,
+            Err(e) => warn!("Dropped replayed frame: {:?}", e),
+        }
+    }
+    engine.rt().scheduler().poll();
+    delivered
+}
+
+fn read_pcap(buf: &[u8]) -> Result<Vec<PcapFrame>, Fail> {
+    if buf.len() < PCAP_HEADER_SIZE {
+        return Err(malformed("pcap header too short"));
+    }
+    let (big_endian, nanos) = match (
+        LittleEndian::read_u32(&buf[0..4]),
+        BigEndian::read_u32(&buf[0..4]),
+    ) {
+        (PCAP_MAGIC_MICROS, _) => (false, false),
+        (PCAP_MAGIC_NANOS, _) => (false, true),
+        (_, PCAP_MAGIC_MICROS) => (true, false),
+        (_, PCAP_MAGIC_NANOS) => (true, true),
+        _ => return Err(malformed("Unknown capture format")),
+    };
+    let link_type = read_u32(&buf[20..24], big_endian) & 0xffff;
+    if link_type != LINKTYPE_ETHERNET {
+        return Err(Fail::Unsupported {
+            details: "Only Ethernet captures are supported",
+        });
+    }
+
+    let mut frames = Vec::new();
+    let mut pos = PCAP_HEADER_SIZE;
+    while pos < buf.len() {
+        let record = buf
+            .get(pos..pos + PCAP_RECORD_HEADER_SIZE)
+            .ok_or_else(|| malformed("pcap record header truncated"))?;
+        let seconds = read_u32(&record[0..4], big_endian) as u64;
+        let fraction = read_u32(&record[4..8], big_endian);
+        let captured_len = read_u32(&record[8..12], big_endian) as usize;
+        pos += PCAP_RECORD_HEADER_SIZE;
+        let data = buf
+            .get(pos..pos + captured_len)
+            .ok_or_else(|| malformed("pcap record truncated"))?;
+        pos += captured_len;
+
+        let fraction = match nanos {
+            true => Duration::from_nanos(fraction as u64),
+            false => Duration::from_micros(fraction as u64),
+        };
+        frames.push(PcapFrame {
+            timestamp: Duration::from_secs(seconds) + fraction,
+            data: data.to_vec(),
+        });
+    }
+    Ok(frames)
+}
+
+fn read_pcapng(buf: &[u8]) -> Result<Vec<PcapFrame>, Fail> {
+    let mut frames = Vec::new();
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut big_endian = false;
+    let mut pos = 0;
+    while pos < buf.len() {
+        let header = buf
+            .get(pos..pos + 12)
+            .ok_or_else(|| malformed("pcapng block truncated"))?;
+        let block_type = LittleEndian::read_u32(&header[0..4]);
+        if block_type == PCAPNG_SECTION_HEADER {
+            // Each section may use its own byte order.
+            big_endian = match LittleEndian::read_u32(&header[8..12]) {
+                PCAPNG_BYTE_ORDER_MAGIC => false,
+                _ if BigEndian::read_u32(&header[8..12]) == PCAPNG_BYTE_ORDER_MAGIC => true,
+                _ => return Err(malformed("Invalid pcapng byte order magic")),
+            };
+            interfaces.clear();
+        }
+        let block_type = read_u32(&header[0..4], big_endian);
+        let block_len = read_u32(&header[4..8], big_endian) as usize;
+        if block_len < 12 || block_len % 4 != 0 {
+            return Err(malformed("Invalid pcapng block length"));
+        }
+        let body = buf
+            .get(pos + 8..pos + block_len - 4)
+            .ok_or_else(|| malformed("pcapng block truncated"))?;
+        pos += block_len;
+
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                if body.len() < 8 {
+                    return Err(malformed("pcapng interface description too short"));
+                }
+                let link_type = read_u16(&body[0..2], big_endian) as u32;
+                let resolution = read_if_tsresol(&body[8..], big_endian)?;
+                interfaces.push(Interface {
+                    link_type,
+                    resolution,
+                });
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                if body.len() < 20 {
+                    return Err(malformed("pcapng packet block too short"));
+                }
+                let interface = interfaces
+                    .get(read_u32(&body[0..4], big_endian) as usize)
+                    .ok_or_else(|| malformed("pcapng packet on unknown interface"))?;
+                check_link_type(interface)?;
+                let high = read_u32(&body[4..8], big_endian) as u64;
+                let low = read_u32(&body[8..12], big_endian) as u64;
+                let captured_len = read_u32(&body[12..16], big_endian) as usize;
+                let data = body
+                    .get(20..20 + captured_len)
+                    .ok_or_else(|| malformed("pcapng packet truncated"))?;
+                let ticks = (high << 32) | low;
+                frames.push(PcapFrame {
+                    timestamp: Duration::from_nanos(ticks.saturating_mul(interface.resolution)),
+                    data: data.to_vec(),
+                });
+            }
+            PCAPNG_SIMPLE_PACKET => {
+                let interface = interfaces
+                    .first()
+                    .ok_or_else(|| malformed("pcapng packet on unknown interface"))?;
+                check_link_type(interface)?;
+                if body.len() < 4 {
+                    return Err(malformed("pcapng packet block too short"));
+                }
+                let original_len = read_u32(&body[0..4], big_endian) as usize;
+                let captured_len = original_len.min(body.len() - 4);
+                // Simple packet blocks carry no timestamp.
+                frames.push(PcapFrame {
+                    timestamp: Duration::default(),
+                    data: body[4..4 + captured_len].to_vec(),
+                });
+            }
+            // Skip section headers, statistics, name resolution and custom blocks.
+            _ => (),
+        }
+    }
+    Ok(frames)
+}
+
+/// Returns the timestamp resolution (in nanoseconds) set by the options of an interface
+/// description.
+fn read_if_tsresol(mut options: &[u8], big_endian: bool) -> Result<u64, Fail> {
+    let mut resolution = 1_000;
+    while options.len() >= 4 {
+        let code = read_u16(&options[0..2], big_endian);
+        let len = read_u16(&options[2..4], big_endian) as usize;
+        if code == PCAPNG_OPTION_END {
+            break;
+        }
+        let padded_len = (len + 3) & !3;
+        let value = options
+            .get(4..4 + len)
+            .ok_or_else(|| malformed("pcapng option truncated"))?;
+        if code == PCAPNG_OPTION_IF_TSRESOL && len == 1 {
+            let exponent = (value[0] & 0x7f) as u32;
+            resolution = match value[0] & 0x80 {
+                // Negative power of 10.
+                0 if exponent <= 9 => 10u64.pow(9 - exponent),
+                // Negative power of 2.
+                0x80 if exponent <= 30 => ((1_000_000_000u64 << 30) >> exponent) >> 30,
+                _ => {
+                    return Err(Fail::Unsupported {
+                        details: "Unsupported pcapng timestamp resolution",
+                    })
+                }
+            }
+            .max(1);
+        }
+        options = options.get(4 + padded_len..).unwrap_or(&[]);
+    }
+    Ok(resolution)
+}
+
+fn check_link_type(interface: &Interface) -> Result<(), Fail> {
+    match interface.link_type {
+        LINKTYPE_ETHERNET => Ok(()),
+        _ => Err(Fail::Unsupported {
+            details: "Only Ethernet captures are supported",
+        }),
+    }
+}
+
+fn read_u16(buf: &[u8], big_endian: bool) -> u16 {
+    match big_endian {
+        true => BigEndian::read_u16(buf),
+        false => LittleEndian::read_u16(buf),
+    }
+}
+
+fn read_u32(buf: &[u8], big_endian: bool) -> u32 {
+    match big_endian {
+        true => BigEndian::read_u32(buf),
+        false => LittleEndian::read_u32(buf),
+    }
+}
+
+fn malformed(details: &'static str) -> Fail {
+    Fail::Malformed { details }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{read_file, read_frames, replay, CaptureOptions, PcapFrame, PcapWriter};
+    use crate::{
+        collections::bytes::BytesMut,
+        protocols::{ip, ipv4},
+        runtime::Runtime,
+        test_helpers,
+    };
+    use byteorder::{ByteOrder, LittleEndian};
+    use futures::{
+        task::{noop_waker_ref, Context},
+        FutureExt,
+    };
+    use std::{
+        convert::TryFrom,
+        env, fs,
+        future::Future,
+        time::{Duration, Instant},
+    };
+
+    /// Encodes `frames` in the (microsecond resolution) pcap format.
+    fn encode_pcap(frames: &[PcapFrame]) -> Vec<u8> {
+        let mut buf = vec![0u8; 24];
+        LittleEndian::write_u32(&mut buf[0..4], 0xa1b2_c3d4);
+        LittleEndian::write_u16(&mut buf[4..6], 2);
+        LittleEndian::write_u16(&mut buf[6..8], 4);
+        LittleEndian::write_u32(&mut buf[16..20], 65535);
+        LittleEndian::write_u32(&mut buf[20..24], 1);
+        for frame in frames {
+            let mut record = [0u8; 16];
+            LittleEndian::write_u32(&mut record[0..4], frame.timestamp.as_secs() as u32);
+            LittleEndian::write_u32(&mut record[4..8], frame.timestamp.subsec_micros());
+            LittleEndian::write_u32(&mut record[8..12], frame.data.len() as u32);
+            LittleEndian::write_u32(&mut record[12..16], frame.data.len() as u32);
+            buf.extend_from_slice(&record);
+            buf.extend_from_slice(&frame.data);
+        }
+        buf
+    }
+
+    /// Encodes `frames` as a little-endian pcapng section with a nanosecond resolution interface.
+    fn encode_pcapng(frames: &[PcapFrame]) -> Vec<u8> {
+        fn block(buf: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+            let len = 12 + ((body.len() + 3) & !3);
+            let mut header = [0u8; 8];
+            LittleEndian::write_u32(&mut header[0..4], block_type);
+            LittleEndian::write_u32(&mut header[4..8], len as u32);
+            buf.extend_from_slice(&header);
+            buf.extend_from_slice(body);
+            buf.resize(buf.len() + (len - 12 - body.len()), 0);
+            buf.extend_from_slice(&header[4..8]);
+        }
+        let mut buf = Vec::new();
+        let mut shb = [0u8; 16];
+        LittleEndian::write_u32(&mut shb[0..4], 0x1a2b_3c4d);
+        LittleEndian::write_u16(&mut shb[4..6], 1);
+        LittleEndian::write_u64(&mut shb[8..16], u64::MAX);
+        block(&mut buf, 0x0a0d_0d0a, &shb);
+        // Link type Ethernet, with an if_tsresol option of 10^-9 followed by the end of options.
+        let idb = [1, 0, 0, 0, 0, 0, 0, 0, 9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0];
+        block(&mut buf, 1, &idb);
+        for frame in frames {
+            let mut epb = vec![0u8; 20];
+            let ticks = frame.timestamp.as_nanos() as u64;
+            LittleEndian::write_u32(&mut epb[4..8], (ticks >> 32) as u32);
+            LittleEndian::write_u32(&mut epb[8..12], ticks as u32);
+            LittleEndian::write_u32(&mut epb[12..16], frame.data.len() as u32);
+            LittleEndian::write_u32(&mut epb[16..20], frame.data.len() as u32);
+            epb.extend_from_slice(&frame.data);
+            block(&mut buf, 6, &epb);
+        }
+        buf
+    }
+
+    fn sample_frames() -> Vec<PcapFrame> {
+        vec![
+            PcapFrame {
+                timestamp: Duration::new(1_600_000_000, 250_000),
+                data: vec![0xab; 60],
+            },
+            PcapFrame {
+                timestamp: Duration::new(1_600_000_001, 0),
+                data: vec![0xcd; 61],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_read_pcap() {
+        let frames = sample_frames();
+        assert_eq!(read_frames(&encode_pcap(&frames)).unwrap(), frames);
+    }
+
+    #[test]
+    fn test_read_pcapng() {
+        let frames = sample_frames();
+        assert_eq!(read_frames(&encode_pcapng(&frames)).unwrap(), frames);
+    }
+
+    #[test]
+    fn test_write_pcap() {
+        let frames = sample_frames();
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        for frame in &frames {
+            writer.write_frame(frame.timestamp, &frame.data).unwrap();
+        }
+        assert_eq!(read_frames(&writer.into_inner()).unwrap(), frames);
+    }
+
+    #[test]
+    fn test_read_garbage() {
+        assert!(read_frames(&[0; 32]).is_err());
+    }
+
+    /// Replays an ARP request captured from alice into carrie, who learns alice's address and
+    /// answers.
+    #[test]
+    fn test_replay() {
+        let now = Instant::now();
+        let mut alice = test_helpers::new_alice(now);
+        let mut carrie = test_helpers::new_carrie(now);
+
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let mut fut = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+        assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+        alice.rt().advance_clock(now + Duration::from_micros(1));
+        let request = alice.rt().pop_frame();
+
+        let capture = encode_pcap(&[PcapFrame {
+            timestamp: Duration::from_secs(1_600_000_000),
+            data: request.to_vec(),
+        }]);
+        let frames = read_frames(&capture).unwrap();
+        let start = now + Duration::from_secs(1);
+        assert_eq!(replay(&mut carrie, &frames, start), 1);
+        assert_eq!(carrie.rt().now(), start);
+        assert_eq!(
+            carrie.export_arp_cache().get(&test_helpers::ALICE_IPV4),
+            Some(&test_helpers::ALICE_MAC)
+        );
+        assert!(carrie.rt().pop_frame_unchecked().is_some());
+    }
+
+    /// Captures the traffic of carrie while she answers an ARP request.
+    #[test]
+    fn test_runtime_capture() {
+        let now = Instant::now();
+        let mut alice = test_helpers::new_alice(now);
+        let mut carrie = test_helpers::new_carrie(now);
+        let path = env::temp_dir().join(format!("catnip-capture-{}.pcap", std::process::id()));
+        carrie.rt().start_capture(&path).unwrap();
+
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let mut fut = alice.arp_query(test_helpers::CARRIE_IPV4).boxed_local();
+        assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+        alice.rt().advance_clock(now + Duration::from_micros(1));
+        let request = alice.rt().pop_frame();
+        carrie.rt().advance_clock(now + Duration::from_millis(1));
+        carrie.receive(request.clone()).unwrap();
+        let reply = carrie.rt().pop_frame();
+
+        let frames = read_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            frames,
+            vec![
+                PcapFrame {
+                    timestamp: Duration::from_millis(1),
+                    data: request.to_vec(),
+                },
+                PcapFrame {
+                    timestamp: Duration::from_millis(1),
+                    data: reply.to_vec(),
+                },
+            ]
+        );
+    }
+
+    /// Captures the datagrams alice sends to port 80 into files holding one frame each, keeping
+    /// the last two.
+    #[test]
+    fn test_rolling_capture() {
+        let now = Instant::now();
+        let mut alice = test_helpers::new_alice2(now);
+        let (alice_fd, alice_addr) = test_helpers::udp_socket_setup(&mut alice, 80);
+        let directory = env::temp_dir().join(format!("catnip-rolling-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        // A datagram with 32 bytes of payload makes a 90-byte record.
+        let options = CaptureOptions::new(&directory, "alice")
+            .max_file_size(150)
+            .max_files(2)
+            .port(80);
+        let handle = alice.capture(options).unwrap();
+
+        let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+        let mut sent = Vec::new();
+        for port in &[80, 81, 80, 80] {
+            let bob_addr =
+                ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(*port).unwrap());
+            let bytes = test_helpers::udp_send(
+                &mut alice,
+                alice_fd,
+                alice_addr,
+                bob_addr,
+                test_helpers::BOB_MAC,
+                buf.clone(),
+            );
+            if *port == 80 {
+                sent.push(bytes);
+            }
+        }
+
+        assert!(handle.is_active());
+        let files = handle.stop().unwrap();
+        assert!(!handle.is_active());
+        assert_eq!(files.len(), 2);
+        assert!(!directory.join("alice-0.pcap").exists());
+        for (path, bytes) in files.iter().zip(&sent[1..]) {
+            let frames = read_file(path).unwrap();
+            assert_eq!(frames.len(), 1);
+            assert_eq!(&frames[0].data[..], &bytes[..]);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+        alice.close(alice_fd).unwrap();
+    }
+}
@@ -0,0 +1,580 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Rendering of engine statistics in the Prometheus text exposition format.
+
+use crate::{
+    netstat::{SocketInfo, SocketState},
+    protocols::Protocol,
+    scheduler::SchedulerStats,
+    stats::{DropReason, Stats},
+    timer::TimerStats,
+};
+use std::{collections::BTreeMap, fmt::Write};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+const DROP_REASONS: [(DropReason, &str); 11] = [
+    (DropReason::Malformed, "malformed"),
+    (DropReason::BadChecksum, "bad_checksum"),
+    (DropReason::NotForUs, "not_for_us"),
+    (DropReason::Unsupported, "unsupported"),
+    (DropReason::NoListener, "no_listener"),
+    (DropReason::OutOfWindow, "out_of_window"),
+    (DropReason::UnresolvedArp, "unresolved_arp"),
+    (DropReason::Filtered, "filtered"),
+    (DropReason::QueueFull, "queue_full"),
+    (DropReason::NoBuffer, "no_buffer"),
+    (DropReason::SynFlood, "syn_flood"),
+];
+
+/// Text being rendered, one metric family at a time.
+struct Exposition {
+    out: String,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [Exposition].
+impl Exposition {
+    /// Starts the metric family `name`.
+    fn family(&mut self, name: &str, kind: &str, help: &str) -> &mut Self {
+        writeln!(self.out, "# HELP catnip_{} {}", name, help).unwrap();
+        writeln!(self.out, "# TYPE catnip_{} {}", name, kind).unwrap();
+        self
+    }
+
+    /// Adds a sample with `labels` to the current family.
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: u64) -> &mut Self {
+        write!(self.out, "catnip_{}", name).unwrap();
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, v))
+                .collect();
+            write!(self.out, "{{{}}}", labels.join(",")).unwrap();
+        }
+        writeln!(self.out, " {}", value).unwrap();
+        self
+    }
+
+    /// Adds an unlabeled counter family.
+    fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.family(name, "counter", help).sample(name, &[], value);
+    }
+}
+
+//==============================================================================
+// Standalone Functions
+//==============================================================================
+
+/// Renders MIB counters, drops, per-protocol socket aggregates, scheduler metrics and, if given,
+/// timer metrics.
+pub fn render(
+    stats: &Stats,
+    sockets: &[SocketInfo],
+    scheduler: &SchedulerStats,
+    timers: Option<&TimerStats>,
+) -> String {
+    let mut e = Exposition { out: String::new() };
+
+    let (ip, icmp, tcp, udp) = (&stats.ip, &stats.icmp, &stats.tcp, &stats.udp);
+    let counters = [
+        (
+            "ip_in_receives_total",
+            "IP datagrams received.",
+            ip.in_receives,
+        ),
+        (
+            "ip_in_hdr_errors_total",
+            "IP datagrams with header errors.",
+            ip.in_hdr_errors,
+        ),
+        (
+            "ip_in_addr_errors_total",
+            "IP datagrams not addressed to us.",
+            ip.in_addr_errors,
+        ),
+        (
+            "ip_in_unknown_protos_total",
+            "IP datagrams of an unsupported protocol.",
+            ip.in_unknown_protos,
+        ),
+        (
+            "ip_in_delivers_total",
+            "IP datagrams delivered upwards.",
+            ip.in_delivers,
+        ),
+        (
+            "ip_out_requests_total",
+            "IP datagrams transmitted.",
+            ip.out_requests,
+        ),
+        (
+            "ip_reasm_fails_total",
+            "IP fragments discarded.",
+            ip.reasm_fails,
+        ),
+        (
+            "icmp_in_msgs_total",
+            "ICMP messages received.",
+            icmp.in_msgs,
+        ),
+        (
+            "icmp_in_errors_total",
+            "ICMP messages received with errors.",
+            icmp.in_errors,
+        ),
+        (
+            "icmp_in_echos_total",
+            "ICMP echo requests received.",
+            icmp.in_echos,
+        ),
+        (
+            "icmp_in_echo_reps_total",
+            "ICMP echo replies received.",
+            icmp.in_echo_reps,
+        ),
+        (
+            "icmp_in_dest_unreachs_total",
+            "ICMP destination unreachable messages received.",
+            icmp.in_dest_unreachs,
+        ),
+        (
+            "icmp_out_msgs_total",
+            "ICMP messages transmitted.",
+            icmp.out_msgs,
+        ),
+        (
+            "icmp_out_errors_total",
+            "ICMP messages not sent.",
+            icmp.out_errors,
+        ),
+        (
+            "icmp_out_echos_total",
+            "ICMP echo requests transmitted.",
+            icmp.out_echos,
+        ),
+        (
+            "icmp_out_echo_reps_total",
+            "ICMP echo replies transmitted.",
+            icmp.out_echo_reps,
+        ),
+        (
+            "tcp_active_opens_total",
+            "TCP connections opened with connect.",
+            tcp.active_opens,
+        ),
+        (
+            "tcp_passive_opens_total",
+            "TCP connections opened by a SYN.",
+            tcp.passive_opens,
+        ),
+        (
+            "tcp_attempt_fails_total",
+            "TCP connection attempts failed.",
+            tcp.attempt_fails,
+        ),
+        (
+            "tcp_estab_resets_total",
+            "TCP connections reset by the remote.",
+            tcp.estab_resets,
+        ),
+        ("tcp_in_segs_total", "TCP segments received.", tcp.in_segs),
+        (
+            "tcp_out_segs_total",
+            "TCP segments transmitted.",
+            tcp.out_segs,
+        ),
+        (
+            "tcp_retrans_segs_total",
+            "TCP segments retransmitted.",
+            tcp.retrans_segs,
+        ),
+        (
+            "tcp_in_errs_total",
+            "TCP segments received with errors.",
+            tcp.in_errs,
+        ),
+        (
+            "tcp_out_rsts_total",
+            "TCP segments transmitted with RST.",
+            tcp.out_rsts,
+        ),
+        (
+            "tcp_in_csum_errors_total",
+            "TCP segments with a bad checksum.",
+            tcp.in_csum_errors,
+        ),
+        (
+            "tcp_syncookies_sent_total",
+            "TCP SYN cookies sent.",
+            tcp.syncookies_sent,
+        ),
+        (
+            "tcp_syncookies_recv_total",
+            "TCP connections opened by a SYN cookie.",
+            tcp.syncookies_recv,
+        ),
+        (
+            "tcp_syncookies_failed_total",
+            "TCP SYN cookies that did not validate.",
+            tcp.syncookies_failed,
+        ),
+        (
+            "tcp_limit_rejects_total",
+            "TCP SYNs rejected by a connection limit.",
+            tcp.limit_rejects,
+        ),
+        (
+            "tcp_seq_out_of_window_total",
+            "TCP segments outside of the receive window.",
+            tcp.seq_out_of_window,
+        ),
+        (
+            "tcp_rst_seq_violations_total",
+            "TCP RSTs not at the expected sequence number.",
+            tcp.rst_seq_violations,
+        ),
+        (
+            "tcp_ack_unsent_total",
+            "TCP ACKs of data not sent yet.",
+            tcp.ack_unsent,
+        ),
+        (
+            "tcp_ack_too_old_total",
+            "TCP ACKs older than the send window.",
+            tcp.ack_too_old,
+        ),
+        (
+            "tcp_error_segments_throttled_total",
+            "TCP RSTs and challenge ACKs not sent because of the rate limit.",
+            tcp.error_segments_throttled,
+        ),
+        (
+            "tcp_challenge_acks_total",
+            "TCP challenge ACKs sent in reply to RSTs and SYNs that may be blind injections.",
+            tcp.challenge_acks,
+        ),
+        (
+            "tcp_predicted_acks_total",
+            "TCP pure ACKs handled by header prediction.",
+            tcp.predicted_acks,
+        ),
+        (
+            "tcp_predicted_data_total",
+            "TCP in-order data segments handled by header prediction.",
+            tcp.predicted_data,
+        ),
+        (
+            "tcp_coalesced_acks_total",
+            "TCP pure ACKs superseded by a later one in a receive batch.",
+            tcp.coalesced_acks,
+        ),
+        (
+            "tcp_idle_reaped_total",
+            "TCP connections torn down by the idle reaping policy.",
+            tcp.idle_reaped,
+        ),
+        (
+            "tcp_time_wait_evicted_total",
+            "TCP connections dropped from TIME_WAIT early to make room.",
+            tcp.time_wait_evicted,
+        ),
+        (
+            "tcp_time_wait_recycled_total",
+            "TCP connections started on endpoints still in TIME_WAIT.",
+            tcp.time_wait_recycled,
+        ),
+        (
+            "tcp_sack_retrans_segs_total",
+            "TCP segments retransmitted to fill holes reported by SACKs.",
+            tcp.sack_retrans_segs,
+        ),
+        (
+            "tcp_paws_rejected_total",
+            "TCP segments dropped by PAWS for carrying an old timestamp.",
+            tcp.paws_rejected,
+        ),
+        (
+            "tcp_window_probes_total",
+            "TCP zero-window probes sent.",
+            tcp.window_probes,
+        ),
+        (
+            "tcp_dup_acks_total",
+            "TCP duplicate ACKs received.",
+            tcp.dup_acks,
+        ),
+        (
+            "tcp_fast_retransmits_total",
+            "TCP fast retransmissions.",
+            tcp.fast_retransmits,
+        ),
+        (
+            "tcp_timeout_aborts_total",
+            "TCP connections torn down by retransmission timeouts.",
+            tcp.timeout_aborts,
+        ),
+        (
+            "tcp_ecn_ce_marks_total",
+            "TCP segments received marked with ECN congestion experienced.",
+            tcp.ecn_ce_marks,
+        ),
+        (
+            "tcp_ecn_cwnd_reductions_total",
+            "TCP congestion window reductions for ECN echoes.",
+            tcp.ecn_cwnd_reductions,
+        ),
+        (
+            "tcp_fast_open_cookie_reqs_total",
+            "TCP connection attempts asking for a Fast Open cookie.",
+            tcp.fast_open_cookie_reqs,
+        ),
+        (
+            "tcp_fast_open_active_total",
+            "TCP connections whose data on the SYN was acknowledged.",
+            tcp.fast_open_active,
+        ),
+        (
+            "tcp_fast_open_active_fails_total",
+            "TCP connections whose data on the SYN was not acknowledged.",
+            tcp.fast_open_active_fails,
+        ),
+        (
+            "tcp_fast_open_passive_total",
+            "TCP SYNs whose data was taken for a valid Fast Open cookie.",
+            tcp.fast_open_passive,
+        ),
+        (
+            "tcp_fast_open_passive_fails_total",
+            "TCP SYNs carrying an invalid Fast Open cookie.",
+            tcp.fast_open_passive_fails,
+        ),
+        (
+            "tcp_fast_open_replays_total",
+            "TCP SYNs whose data was refused as a possible replay.",
+            tcp.fast_open_replays,
+        ),
+        (
+            "tcp_dsacks_sent_total",
+            "TCP duplicate SACKs sent.",
+            tcp.dsacks_sent,
+        ),
+        (
+            "tcp_dsacks_received_total",
+            "TCP duplicate SACKs received.",
+            tcp.dsacks_received,
+        ),
+        (
+            "tcp_dsack_undos_total",
+            "TCP congestion window reductions undone by duplicate SACKs.",
+            tcp.dsack_undos,
+        ),
+        (
+            "tcp_frto_spurious_total",
+            "TCP retransmission timeouts found spurious by F-RTO.",
+            tcp.frto_spurious,
+        ),
+        (
+            "tcp_receive_window_grows_total",
+            "TCP receive windows grown by auto-tuning.",
+            tcp.receive_window_grows,
+        ),
+        (
+            "udp_in_datagrams_total",
+            "UDP datagrams delivered.",
+            udp.in_datagrams,
+        ),
+        (
+            "udp_no_ports_total",
+            "UDP datagrams to an unbound port.",
+            udp.no_ports,
+        ),
+        (
+            "udp_in_errors_total",
+            "UDP datagrams received with errors.",
+            udp.in_errors,
+        ),
+        (
+            "udp_out_datagrams_total",
+            "UDP datagrams transmitted.",
+            udp.out_datagrams,
+        ),
+        (
+            "udp_in_csum_errors_total",
+            "UDP datagrams with a bad checksum.",
+            udp.in_csum_errors,
+        ),
+    ];
+    for (name, help, value) in counters.iter() {
+        e.counter(name, help, *value);
+    }
+
+    e.family("drops_total", "counter", "Packets discarded, by reason.");
+    for (reason, label) in DROP_REASONS.iter() {
+        e.sample(
+            "drops_total",
+            &[("reason", *label)],
+            stats.drops.get(*reason),
+        );
+    }
+
+    // Aggregate sockets by protocol and state, keeping the output in a stable order.
+    let mut counts: BTreeMap<(&str, String), u64> = BTreeMap::new();
+    let mut queues: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+    for socket in sockets {
+        let protocol = protocol_label(socket.protocol);
+        *counts
+            .entry((protocol, state_label(&socket.state)))
+            .or_default() += 1;
+        let (send, recv) = queues.entry(protocol).or_default();
+        *send += socket.send_queue as u64;
+        *recv += socket.recv_queue as u64;
+    }
+    e.family("sockets", "gauge", "Open sockets, by protocol and state.");
+    for ((protocol, state), count) in &counts {
+        e.sample(
+            "sockets",
+            &[("protocol", *protocol), ("state", state.as_str())],
+            *count,
+        );
+    }
+    e.family(
+        "socket_send_queue_bytes",
+        "gauge",
+        "Bytes pushed but not yet acknowledged, summed over sockets.",
+    );
+    for (protocol, (send, _)) in &queues {
+        e.sample("socket_send_queue_bytes", &[("protocol", *protocol)], *send);
+    }
+    e.family(
+        "socket_recv_queue_bytes",
+        "gauge",
+        "Bytes received but not yet popped, summed over sockets.",
+    );
+    for (protocol, (_, recv)) in &queues {
+        e.sample("socket_recv_queue_bytes", &[("protocol", *protocol)], *recv);
+    }
+
+    e.family("scheduler_tasks", "gauge", "Tasks held by the scheduler.")
+        .sample("scheduler_tasks", &[], scheduler.tasks as u64);
+    e.family(
+        "scheduler_runnable_tasks",
+        "gauge",
+        "Tasks waiting to be polled.",
+    )
+    .sample("scheduler_runnable_tasks", &[], scheduler.runnable as u64);
+    e.family(
+        "scheduler_last_tick_polls",
+        "gauge",
+        "Tasks polled on the last tick.",
+    )
+    .sample("scheduler_last_tick_polls", &[], scheduler.last_tick_polls);
+    e.counter("scheduler_ticks_total", "Scheduler ticks.", scheduler.ticks);
+    e.counter(
+        "scheduler_wasted_ticks_total",
+        "Scheduler ticks that found no task to poll.",
+        scheduler.wasted_ticks,
+    );
+    e.counter("scheduler_polls_total", "Tasks polled.", scheduler.polls);
+    e.counter(
+        "scheduler_pending_polls_total",
+        "Polls that left the task pending.",
+        scheduler.pending_polls,
+    );
+
+    if let Some(timers) = timers {
+        e.family("timers_pending", "gauge", "Timers waiting to expire.")
+            .sample("timers_pending", &[], timers.pending as u64);
+        e.counter("timers_expired_total", "Timers expired.", timers.expired);
+    }
+
+    e.out
+}
+
+fn protocol_label(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Tcp => "tcp",
+        Protocol::Udp => "udp",
+    }
+}
+
+fn state_label(state: &SocketState) -> String {
+    match state {
+        SocketState::Idle => "idle".to_string(),
+        SocketState::Bound => "bound".to_string(),
+        SocketState::Listening => "listening".to_string(),
+        SocketState::Connecting => "connecting".to_string(),
+        SocketState::Established(s) => format!("{:?}", s).to_lowercase(),
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use crate::{
+        file_table::FileDescriptor,
+        netstat::{SocketInfo, SocketState},
+        protocols::{tcp, Protocol},
+        scheduler::SchedulerStats,
+        stats::Stats,
+        timer::TimerStats,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_render() {
+        let mut stats = Stats::default();
+        stats.tcp.in_segs = 7;
+        stats.drops.no_listener = 2;
+        let socket = |fd: FileDescriptor, state, send_queue, recv_queue| SocketInfo {
+            fd,
+            protocol: Protocol::Tcp,
+            local: None,
+            remote: None,
+            state,
+            send_queue,
+            recv_queue,
+            age: Duration::from_secs(0),
+            rto: None,
+            srtt: None,
+        };
+        let sockets = [
+            socket(1, SocketState::Listening, 0, 1),
+            socket(2, SocketState::Established(tcp::State::Established), 10, 5),
+            socket(3, SocketState::Established(tcp::State::Established), 3, 0),
+        ];
+        let scheduler = SchedulerStats {
+            tasks: 4,
+            ticks: 3,
+            ..Default::default()
+        };
+        let timers = TimerStats {
+            pending: 2,
+            expired: 1,
+        };
+        let text = render(&stats, &sockets, &scheduler, Some(&timers));
+
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE catnip_tcp_in_segs_total counter"));
+        assert!(lines.contains(&"catnip_tcp_in_segs_total 7"));
+        assert!(lines.contains(&"catnip_drops_total{reason=\"no_listener\"} 2"));
+        assert!(lines.contains(&"catnip_drops_total{reason=\"bad_checksum\"} 0"));
+        assert!(lines.contains(&"catnip_sockets{protocol=\"tcp\",state=\"established\"} 2"));
+        assert!(lines.contains(&"catnip_sockets{protocol=\"tcp\",state=\"listening\"} 1"));
+        assert!(lines.contains(&"catnip_socket_send_queue_bytes{protocol=\"tcp\"} 13"));
+        assert!(lines.contains(&"catnip_socket_recv_queue_bytes{protocol=\"tcp\"} 6"));
+        assert!(lines.contains(&"catnip_scheduler_tasks 4"));
+        assert!(lines.contains(&"catnip_scheduler_ticks_total 3"));
+        assert!(lines.contains(&"catnip_timers_pending 2"));
+    }
+}
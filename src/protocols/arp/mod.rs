@@ -11,4 +11,5 @@ mod peer;
 mod tests;
 
 pub use options::ArpOptions as Options;
+pub use pdu::{ArpOperation, ArpPdu};
 pub use peer::ArpPeer as Peer;
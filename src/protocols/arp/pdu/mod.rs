@@ -4,7 +4,7 @@
 use crate::{fail::Fail, protocols::ethernet2::MacAddress, runtime::RuntimeBuf};
 use byteorder::{ByteOrder, NetworkEndian};
 use num_traits::FromPrimitive;
-use std::{convert::TryInto, net::Ipv4Addr};
+use std::{convert::TryInto, fmt, net::Ipv4Addr};
 
 const ARP_HTYPE_ETHER2: u16 = 1;
 const ARP_HLEN_ETHER2: u8 = 6;
@@ -22,7 +22,7 @@ pub enum ArpOperation {
 ///
 /// # Protocol Data Unit (PDU) for ARP
 ///
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ArpPdu {
     // We only support Ethernet/Ipv4, so omit these fields.
     // hardware_type: u16,
@@ -123,3 +123,21 @@ impl ArpPdu {
         buf[24..28].copy_from_slice(&self.target_protocol_addr.octets());
     }
 }
+
+/// Formats the PDU the way tcpdump does, e.g. `ARP, Request who-has 10.0.0.2 tell 10.0.0.1`.
+impl fmt::Display for ArpPdu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.operation {
+            ArpOperation::Request => write!(
+                f,
+                "ARP, Request who-has {} tell {}",
+                self.target_protocol_addr, self.sender_protocol_addr
+            ),
+            ArpOperation::Reply => write!(
+                f,
+                "ARP, Reply {} is-at {}",
+                self.sender_protocol_addr, self.sender_hardware_addr
+            ),
+        }
+    }
+}
@@ -110,7 +110,7 @@ impl<RT: Runtime> ArpPeer<RT> {
         // > ?Do I speak the protocol in ar$pro?
         // > [optionally check the protocol length ar$pln]
         let pdu = ArpPdu::parse(buf)?;
-        debug!("Received {:?}", pdu);
+        debug!("Received {}", pdu);
 
         // from RFC 826:
         // > Merge_flag := false
@@ -166,8 +166,7 @@ impl<RT: Runtime> ArpPeer<RT> {
                     ),
                 );
                 debug!("Responding {:?}", reply);
-                self.rt.transmit(reply);
-                Ok(())
+                self.rt.try_transmit(reply)
             }
             ArpOperation::Reply => {
                 debug!(
@@ -182,7 +181,12 @@ impl<RT: Runtime> ArpPeer<RT> {
         }
     }
 
+    /// Returns the link address of `ipv4_addr` if cached. Our own address always resolves to our
+    /// own link address, for traffic looped back to this host.
     pub fn try_query(&self, ipv4_addr: Ipv4Addr) -> Option<MacAddress> {
+        if ipv4_addr == self.rt.local_ipv4_addr() {
+            return Some(self.rt.local_link_addr());
+        }
         self.cache.borrow().get(ipv4_addr).cloned()
     }
 
@@ -192,6 +196,9 @@ impl<RT: Runtime> ArpPeer<RT> {
         let cache = self.cache.clone();
         let arp_options = self.options.clone();
         async move {
+            if ipv4_addr == rt.local_ipv4_addr() {
+                return Ok(rt.local_link_addr());
+            }
             if let Some(&link_addr) = cache.borrow().get(ipv4_addr) {
                 return Ok(link_addr);
             }
@@ -216,7 +223,9 @@ impl<RT: Runtime> ArpPeer<RT> {
             // > second, the maximum suggested by [RFC1122].
             let result = {
                 for i in 0..arp_options.retry_count + 1 {
-                    rt.transmit(msg.clone());
+                    if let Err(e) = rt.try_transmit(msg.clone()) {
+                        warn!("Failed to send ARP request: {:?}", e);
+                    }
                     let timer = rt.wait(arp_options.request_timeout);
 
                     match arp_response.with_timeout(timer).await {
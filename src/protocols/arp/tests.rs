@@ -149,3 +149,41 @@ fn no_reply() {
 
     must_let!(let Poll::Ready(Err(Fail::Timeout {})) = Future::poll(fut.as_mut(), &mut ctx));
 }
+
+/// Tests ARP resolution among several hosts attached to a switch.
+#[test]
+fn switched_reply() {
+    let mut now = Instant::now();
+    let mut switch = test_helpers::VirtualSwitch::new();
+    let alice = switch.attach(test_helpers::new_alice(now));
+    let bob = switch.attach(test_helpers::new_bob(now));
+    let carrie = switch.attach(test_helpers::new_carrie(now));
+
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut fut = switch
+        .engine(alice)
+        .arp_query(test_helpers::CARRIE_IPV4)
+        .boxed_local();
+    assert!(Future::poll(fut.as_mut(), &mut ctx).is_pending());
+
+    // The request is flooded to everyone but alice, and carrie's reply goes back to alice only.
+    now += Duration::from_micros(1);
+    switch.advance_clock(now);
+    assert_eq!(switch.forward(), 1);
+    assert_eq!(switch.lookup(test_helpers::ALICE_MAC), Some(alice));
+    assert!(switch
+        .engine(bob)
+        .export_arp_cache()
+        .get(&test_helpers::ALICE_IPV4)
+        .is_none());
+
+    now += Duration::from_micros(1);
+    switch.advance_clock(now);
+    assert_eq!(switch.forward(), 1);
+    assert_eq!(switch.lookup(test_helpers::CARRIE_MAC), Some(carrie));
+
+    now += Duration::from_micros(1);
+    switch.advance_clock(now);
+    must_let!(let Poll::Ready(Ok(link_addr)) = Future::poll(fut.as_mut(), &mut ctx));
+    assert_eq!(test_helpers::CARRIE_MAC, link_addr);
+}
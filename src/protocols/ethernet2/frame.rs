@@ -4,13 +4,17 @@
 use crate::{fail::Fail, protocols::ethernet2::MacAddress, runtime::RuntimeBuf};
 use byteorder::{ByteOrder, NetworkEndian};
 use num_traits::FromPrimitive;
-use std::convert::{TryFrom, TryInto};
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+};
 
 pub const MIN_PAYLOAD_SIZE: usize = 46;
 pub const ETHERNET2_HEADER_SIZE: usize = 14;
 
 #[repr(u16)]
 #[derive(FromPrimitive, Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum EtherType2 {
     Arp = 0x806,
     Ipv4 = 0x800,
@@ -29,7 +33,8 @@ impl TryFrom<u16> for EtherType2 {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Ethernet2Header {
     // Bytes 0..6
     pub dst_addr: MacAddress,
@@ -80,3 +85,23 @@ impl Ethernet2Header {
         NetworkEndian::write_u16(&mut buf[12..14], self.ether_type as u16);
     }
 }
+
+impl fmt::Display for EtherType2 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EtherType2::Arp => write!(f, "ARP (0x{:04x})", *self as u16),
+            EtherType2::Ipv4 => write!(f, "IPv4 (0x{:04x})", *self as u16),
+        }
+    }
+}
+
+/// Formats the header as `12-23-45-67-89-ab > ab-89-67-45-23-12, ethertype IPv4 (0x0800)`.
+impl fmt::Display for Ethernet2Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} > {}, ethertype {}",
+            self.src_addr, self.dst_addr, self.ether_type
+        )
+    }
+}
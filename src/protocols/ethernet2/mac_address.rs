@@ -69,3 +69,19 @@ impl fmt::Debug for MacAddress {
         write!(f, "MacAddress({})", &self.to_canonical())
     }
 }
+
+/// Serialized in canonical form, e.g. `"12-23-45-67-89-ab"`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for MacAddress {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_canonical())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for MacAddress {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        Self::parse_str(&s).map_err(|_| serde::de::Error::custom("invalid MAC address"))
+    }
+}
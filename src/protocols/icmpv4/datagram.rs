@@ -3,18 +3,37 @@
 
 use crate::{
     fail::Fail,
-    protocols::{ethernet2::frame::Ethernet2Header, ipv4::datagram::Ipv4Header},
+    protocols::{
+        ethernet2::frame::Ethernet2Header,
+        ipv4::datagram::{Ipv4Header, Ipv4Protocol2, IPV4_HEADER_SIZE},
+    },
     runtime::PacketBuf,
     runtime::RuntimeBuf,
 };
 
 use byteorder::{ByteOrder, NetworkEndian};
 
-use std::{convert::TryInto, marker::PhantomData};
+use std::{
+    convert::{TryFrom, TryInto},
+    marker::PhantomData,
+    net::Ipv4Addr,
+};
 
 #[allow(unused)]
 const MAX_ICMPV4_DATAGRAM_SIZE: usize = 576;
 
+/// Code of a destination unreachable message for a datagram discarded by a packet filter
+/// (RFC 1812).
+pub const UNREACHABLE_ADMIN_PROHIBITED: u8 = 13;
+
+/// Code of a destination unreachable message for a datagram that needed fragmenting on the way
+/// but had the don't fragment flag set (RFC 792).
+pub const UNREACHABLE_FRAGMENTATION_NEEDED: u8 = 4;
+
+/// Bytes of the payload of a datagram that error messages quote at least, past its IPv4 header
+/// (RFC 792).
+const QUOTED_PAYLOAD_SIZE: usize = 8;
+
 //==============================================================================
 // Icmpv4Type2
 //==============================================================================
@@ -22,7 +41,7 @@ const MAX_ICMPV4_DATAGRAM_SIZE: usize = 576;
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Icmpv4Type2 {
     EchoReply { id: u16, seq_num: u16 },
-    DestinationUnreachable,
+    DestinationUnreachable { next_hop_mtu: u16 },
     SourceQuench,
     RedirectMessage,
     EchoRequest { id: u16, seq_num: u16 },
@@ -43,7 +62,10 @@ impl Icmpv4Type2 {
                 let seq_num = NetworkEndian::read_u16(&rest_of_header[2..4]);
                 Ok(EchoReply { id, seq_num })
             }
-            3 => Ok(DestinationUnreachable),
+            3 => {
+                let next_hop_mtu = NetworkEndian::read_u16(&rest_of_header[2..4]);
+                Ok(DestinationUnreachable { next_hop_mtu })
+            }
             4 => Ok(SourceQuench),
             5 => Ok(RedirectMessage),
             8 => {
@@ -72,7 +94,10 @@ impl Icmpv4Type2 {
                 let [seq1, seq2] = seq_num.to_be_bytes();
                 (0, [id1, id2, seq1, seq2])
             }
-            DestinationUnreachable => (3, zero),
+            DestinationUnreachable { next_hop_mtu } => {
+                let [mtu1, mtu2] = next_hop_mtu.to_be_bytes();
+                (3, [0, 0, mtu1, mtu2])
+            }
             SourceQuench => (4, zero),
             RedirectMessage => (5, zero),
             EchoRequest { id, seq_num } => {
@@ -140,6 +165,11 @@ impl Icmpv4Header {
     }
 
     pub fn serialize(&self, buf: &mut [u8]) {
+        self.serialize_with_body(buf, &[]);
+    }
+
+    /// Serializes the target header, with a checksum that covers `body`.
+    pub fn serialize_with_body(&self, buf: &mut [u8], body: &[u8]) {
         let buf: &mut [u8; ICMPV4_HEADER_SIZE] =
             (&mut buf[..ICMPV4_HEADER_SIZE]).try_into().unwrap();
         let (type_byte, rest_of_header) = self.icmpv4_type.serialize();
@@ -147,7 +177,7 @@ impl Icmpv4Header {
         buf[1] = self.code;
         // Skip the checksum for now.
         buf[4..8].copy_from_slice(&rest_of_header[..]);
-        let checksum = Self::checksum(buf, &[]);
+        let checksum = Self::checksum(buf, body);
         NetworkEndian::write_u16(&mut buf[2..4], checksum);
     }
 
@@ -174,6 +204,51 @@ impl Icmpv4Header {
     }
 }
 
+//==============================================================================
+// FragmentationNeeded
+//==============================================================================
+
+/// Fragmentation needed message about a datagram of ours, which a router on the way dropped for
+/// not fitting in the MTU of the next hop (RFC 1191).
+#[derive(Copy, Clone, Debug)]
+pub struct FragmentationNeeded {
+    /// MTU of the next hop, or zero if the router predates RFC 1191.
+    pub next_hop_mtu: u16,
+    /// Protocol of the dropped datagram.
+    pub protocol: Ipv4Protocol2,
+    pub src_addr: Ipv4Addr,
+    pub dst_addr: Ipv4Addr,
+    /// First bytes of the payload of the dropped datagram, which hold the ports and, for TCP, the
+    /// sequence number.
+    pub quoted_payload: [u8; QUOTED_PAYLOAD_SIZE],
+}
+
+/// Associate Functions for FragmentationNeeded
+impl FragmentationNeeded {
+    /// Parses `data`, the datagram quoted by a fragmentation needed message carrying
+    /// `next_hop_mtu`. The quote is cut short, so only the fields of the IPv4 header that
+    /// identify the datagram are read, and options are skipped.
+    pub fn parse(next_hop_mtu: u16, data: &[u8]) -> Result<Self, Fail> {
+        let ihl = data.get(0).map_or(0, |b| (b & 0xF) as usize * 4);
+        if ihl < IPV4_HEADER_SIZE || data.len() < ihl + QUOTED_PAYLOAD_SIZE {
+            return Err(Fail::Malformed {
+                details: "ICMPv4 quote too small",
+            });
+        }
+        let protocol = Ipv4Protocol2::try_from(data[9])?;
+        let src_addr = Ipv4Addr::from(NetworkEndian::read_u32(&data[12..16]));
+        let dst_addr = Ipv4Addr::from(NetworkEndian::read_u32(&data[16..20]));
+        let quoted_payload = data[ihl..(ihl + QUOTED_PAYLOAD_SIZE)].try_into().unwrap();
+        Ok(Self {
+            next_hop_mtu,
+            protocol,
+            src_addr,
+            dst_addr,
+            quoted_payload,
+        })
+    }
+}
+
 //==============================================================================
 // Icmpv4Message
 //==============================================================================
@@ -183,6 +258,8 @@ pub struct Icmpv4Message<T> {
     ethernet2_hdr: Ethernet2Header,
     ipv4_hdr: Ipv4Header,
     icmpv4_hdr: Icmpv4Header,
+    /// Data following the header, such as the start of the datagram an error is about.
+    data: Vec<u8>,
     _body_marker: PhantomData<T>,
 }
 
@@ -193,11 +270,22 @@ impl<T> Icmpv4Message<T> {
         ethernet2_hdr: Ethernet2Header,
         ipv4_hdr: Ipv4Header,
         icmpv4_hdr: Icmpv4Header,
+    ) -> Self {
+        Self::with_data(ethernet2_hdr, ipv4_hdr, icmpv4_hdr, Vec::new())
+    }
+
+    /// Creates an ICMP message carrying `data` after its header.
+    pub fn with_data(
+        ethernet2_hdr: Ethernet2Header,
+        ipv4_hdr: Ipv4Header,
+        icmpv4_hdr: Icmpv4Header,
+        data: Vec<u8>,
     ) -> Self {
         Self {
             ethernet2_hdr,
             ipv4_hdr,
             icmpv4_hdr,
+            data,
             _body_marker: PhantomData,
         }
     }
@@ -206,7 +294,10 @@ impl<T> Icmpv4Message<T> {
 /// PacketBuf Trait Implementation for Icmpv4Message
 impl<T> PacketBuf<T> for Icmpv4Message<T> {
     fn header_size(&self) -> usize {
-        self.ethernet2_hdr.compute_size() + self.ipv4_hdr.compute_size() + self.icmpv4_hdr.size()
+        self.ethernet2_hdr.compute_size()
+            + self.ipv4_hdr.compute_size()
+            + self.icmpv4_hdr.size()
+            + self.data.len()
     }
 
     fn body_size(&self) -> usize {
@@ -223,15 +314,19 @@ impl<T> PacketBuf<T> for Icmpv4Message<T> {
             .serialize(&mut buf[cur_pos..(cur_pos + eth_hdr_size)]);
         cur_pos += eth_hdr_size;
 
-        let ipv4_payload_len = icmpv4_hdr_size;
+        let ipv4_payload_len = icmpv4_hdr_size + self.data.len();
         self.ipv4_hdr.serialize(
             &mut buf[cur_pos..(cur_pos + ipv4_hdr_size)],
             ipv4_payload_len,
+            false,
         );
         cur_pos += ipv4_hdr_size;
 
         self.icmpv4_hdr
-            .serialize(&mut buf[cur_pos..(cur_pos + icmpv4_hdr_size)]);
+            .serialize_with_body(&mut buf[cur_pos..(cur_pos + icmpv4_hdr_size)], &self.data);
+        cur_pos += icmpv4_hdr_size;
+
+        buf[cur_pos..(cur_pos + self.data.len())].copy_from_slice(&self.data);
     }
 
     fn take_body(self) -> Option<T> {
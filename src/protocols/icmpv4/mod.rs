@@ -4,4 +4,8 @@
 mod datagram;
 mod peer;
 
+pub use datagram::{
+    FragmentationNeeded, Icmpv4Header, Icmpv4Message, Icmpv4Type2, UNREACHABLE_ADMIN_PROHIBITED,
+    UNREACHABLE_FRAGMENTATION_NEEDED,
+};
 pub use peer::Icmpv4Peer as Peer;
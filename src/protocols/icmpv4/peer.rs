@@ -1,7 +1,9 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use super::datagram::{Icmpv4Header, Icmpv4Type2};
+use super::datagram::{
+    FragmentationNeeded, Icmpv4Header, Icmpv4Type2, UNREACHABLE_FRAGMENTATION_NEEDED,
+};
 use crate::{
     fail::Fail,
     protocols::{
@@ -12,6 +14,7 @@ use crate::{
     },
     runtime::Runtime,
     scheduler::SchedulerHandle,
+    stats::Counters,
 };
 
 use byteorder::{ByteOrder, NetworkEndian};
@@ -77,6 +80,9 @@ pub struct Icmpv4Peer<RT: Runtime> {
     /// Underlying ARP Peer
     arp: arp::Peer<RT>,
 
+    /// MIB Counters
+    counters: Counters,
+
     /// Transmitter
     tx: mpsc::UnboundedSender<(Ipv4Addr, u16, u16)>,
 
@@ -92,14 +98,15 @@ pub struct Icmpv4Peer<RT: Runtime> {
 
 impl<RT: Runtime> Icmpv4Peer<RT> {
     /// Creates a new peer for handling ICMP.
-    pub fn new(rt: RT, arp: arp::Peer<RT>) -> Icmpv4Peer<RT> {
+    pub fn new(rt: RT, arp: arp::Peer<RT>, counters: Counters) -> Icmpv4Peer<RT> {
         let (tx, rx) = mpsc::unbounded();
         let requests = ReqQueue::new();
-        let future = Self::background(rt.clone(), arp.clone(), rx);
+        let future = Self::background(rt.clone(), arp.clone(), counters.clone(), rx);
         let handle = rt.spawn(future);
         Icmpv4Peer {
             rt,
             arp,
+            counters,
             tx,
             requests: Rc::new(RefCell::new(requests)),
             seq: Wrapping(0),
@@ -111,6 +118,7 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
     async fn background(
         rt: RT,
         arp: arp::Peer<RT>,
+        counters: Counters,
         mut rx: mpsc::UnboundedReceiver<(Ipv4Addr, u16, u16)>,
     ) {
         // Reply requests.
@@ -122,18 +130,21 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                     "ARP query complete ({} -> {})",
                     dst_ipv4_addr, dst_link_addr
                 );
-                debug!(
-                    "reply ping ({}, {}, {})",
-                    dst_ipv4_addr, id, seq_num
-                );
+                debug!("reply ping ({}, {}, {})", dst_ipv4_addr, id, seq_num);
                 // Send reply message.
-                rt.transmit(Icmpv4Message::new(
-                        Ethernet2Header::new(dst_link_addr, rt.local_link_addr(), EtherType2::Ipv4),
-                        Ipv4Header::new(rt.local_ipv4_addr(), dst_ipv4_addr, Ipv4Protocol2::Icmpv4),
-                        Icmpv4Header::new(Icmpv4Type2::EchoReply { id, seq_num }, 0),
-                ));
+                rt.try_transmit(Icmpv4Message::new(
+                    Ethernet2Header::new(dst_link_addr, rt.local_link_addr(), EtherType2::Ipv4),
+                    Ipv4Header::new(rt.local_ipv4_addr(), dst_ipv4_addr, Ipv4Protocol2::Icmpv4),
+                    Icmpv4Header::new(Icmpv4Type2::EchoReply { id, seq_num }, 0),
+                ))?;
+                counters.update(|s| {
+                    s.ip.out_requests += 1;
+                    s.icmp.out_msgs += 1;
+                    s.icmp.out_echo_reps += 1;
+                });
             };
             if let Err(e) = r {
+                counters.update(|s| s.icmp.out_errors += 1);
                 warn!(
                     "reply_to_ping({}, {}, {}) failed: {:?}",
                     dst_ipv4_addr, id, seq_num, e
@@ -142,25 +153,85 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
         }
     }
 
-    /// Parses and handles a ICMP message.
-    pub fn receive(&mut self, ipv4_header: &Ipv4Header, buf: RT::Buf) -> Result<(), Fail> {
-        let (icmpv4_hdr, _) = Icmpv4Header::parse(buf)?;
+    /// Parses and handles a ICMP message, returning it if it's a fragmentation needed message for
+    /// the transport protocol of the datagram it quotes to act upon.
+    pub fn receive(
+        &mut self,
+        ipv4_header: &Ipv4Header,
+        buf: RT::Buf,
+    ) -> Result<Option<FragmentationNeeded>, Fail> {
+        self.counters.update(|s| s.icmp.in_msgs += 1);
+        let (icmpv4_hdr, data) = match Icmpv4Header::parse(buf) {
+            Ok(r) => r,
+            Err(e) => {
+                self.counters.update(|s| s.icmp.in_errors += 1);
+                self.counters.count_parse_drop(&e);
+                return Err(e);
+            }
+        };
         debug!("ICMPv4 received {:?}", icmpv4_hdr);
         match icmpv4_hdr.icmpv4_type {
             Icmpv4Type2::EchoRequest { id, seq_num } => {
+                self.counters.update(|s| s.icmp.in_echos += 1);
                 self.tx
                     .unbounded_send((ipv4_header.src_addr, id, seq_num))
                     .unwrap();
-                }
+            }
             Icmpv4Type2::EchoReply { id, seq_num } => {
+                self.counters.update(|s| s.icmp.in_echo_reps += 1);
                 if let Some(tx) = self.requests.borrow_mut().remove(&(id, seq_num)) {
                     let _ = tx.send(());
                 }
             }
+            Icmpv4Type2::DestinationUnreachable { next_hop_mtu } => {
+                self.counters.update(|s| s.icmp.in_dest_unreachs += 1);
+                if icmpv4_hdr.code == UNREACHABLE_FRAGMENTATION_NEEDED {
+                    return FragmentationNeeded::parse(next_hop_mtu, &data[..]).map(Some);
+                }
+            }
             _ => {
                 warn!("Unsupported ICMPv4 message: {:?}", icmpv4_hdr);
             }
         }
+        Ok(None)
+    }
+
+    /// Sends a destination unreachable message with `code` to `dst_ipv4_addr`, about `datagram`,
+    /// the IPv4 header and first bytes of the datagram that could not be delivered. Fails if the
+    /// link address of the destination is not cached, rather than waiting on ARP.
+    pub fn send_unreachable(
+        &self,
+        dst_ipv4_addr: Ipv4Addr,
+        code: u8,
+        datagram: &[u8],
+    ) -> Result<(), Fail> {
+        let dst_link_addr = self
+            .arp
+            .try_query(dst_ipv4_addr)
+            .ok_or(Fail::ResourceNotFound {
+                details: "ICMP destination not in ARP cache",
+            })?;
+        let msg = Icmpv4Message::with_data(
+            Ethernet2Header::new(dst_link_addr, self.rt.local_link_addr(), EtherType2::Ipv4),
+            Ipv4Header::new(
+                self.rt.local_ipv4_addr(),
+                dst_ipv4_addr,
+                Ipv4Protocol2::Icmpv4,
+            ),
+            Icmpv4Header::new(
+                Icmpv4Type2::DestinationUnreachable { next_hop_mtu: 0 },
+                code,
+            ),
+            datagram.to_vec(),
+        );
+        if let Err(e) = self.rt.try_transmit(msg) {
+            self.counters.update(|s| s.icmp.out_errors += 1);
+            return Err(e);
+        }
+        self.counters.update(|s| {
+            s.ip.out_requests += 1;
+            s.icmp.out_msgs += 1;
+        });
         Ok(())
     }
 
@@ -205,6 +276,7 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
         let arp = self.arp.clone();
         let rt = self.rt.clone();
         let requests = self.requests.clone();
+        let counters = self.counters.clone();
         async move {
             let t0 = rt.now();
             debug!("initiating ARP query");
@@ -219,7 +291,15 @@ impl<RT: Runtime> Icmpv4Peer<RT> {
                 Ipv4Header::new(rt.local_ipv4_addr(), dst_ipv4_addr, Ipv4Protocol2::Icmpv4),
                 Icmpv4Header::new(echo_request, 0),
             );
-            rt.transmit(msg);
+            if let Err(e) = rt.try_transmit(msg) {
+                counters.update(|s| s.icmp.out_errors += 1);
+                return Err(e);
+            }
+            counters.update(|s| {
+                s.ip.out_requests += 1;
+                s.icmp.out_msgs += 1;
+                s.icmp.out_echos += 1;
+            });
             let rx = {
                 let (tx, rx) = channel();
                 assert!(requests.borrow_mut().insert((id, seq_num), tx).is_none());
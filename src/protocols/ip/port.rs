@@ -1,12 +1,31 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use crate::{fail::Fail, runtime::Runtime};
-use std::{convert::TryFrom, num::NonZeroU16};
+use crate::{fail::Fail, protocols::ipv4, runtime::Runtime};
+use crc::{crc32, Hasher32};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    hash::Hasher,
+    net::Ipv4Addr,
+    num::NonZeroU16,
+    time::{Duration, Instant},
+};
 
 const FIRST_PRIVATE_PORT: u16 = 49152;
 
+/// Number of private ports.
+const NUM_PRIVATE_PORTS: u32 = 65536 - FIRST_PRIVATE_PORT as u32;
+
+/// Counters of the port selection algorithm, indexed by a hash of the connection.
+const PORT_TABLE_LENGTH: usize = 256;
+
+/// Time before a connection released to the same remote endpoint can get the same local port.
+const PORT_REUSE_DELAY: Duration = Duration::from_secs(60);
+
 #[derive(Eq, PartialEq, Hash, Copy, Clone, Debug, Display, Ord, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct Port(NonZeroU16);
 
 impl TryFrom<u16> for Port {
@@ -36,27 +55,106 @@ impl Port {
     }
 }
 
+/// Selection of the local port of outgoing connections with the double-hash algorithm of RFC 6056
+/// (section 3.3.4). Ports are spread over the private range according to a keyed hash of the
+/// connection, so that an off-path attacker cannot guess them, while consecutive connections to
+/// the same destination still walk through different ports.
 pub struct EphemeralPorts {
-    ports: Vec<Port>,
+    /// Key of the hash that picks where the search starts.
+    offset_key: u32,
+    /// Key of the hash that picks the counter of a connection.
+    table_key: u32,
+    table: Vec<u32>,
+    /// Connections released recently, and when.
+    released: HashMap<(Port, ipv4::Endpoint), Instant>,
 }
 
 impl EphemeralPorts {
     pub fn new<RT: Runtime>(rt: &RT) -> Self {
-        let mut ports = (FIRST_PRIVATE_PORT..=65535u16)
-            .map(|p| Port(NonZeroU16::new(p).unwrap()))
-            .collect::<Vec<_>>();
+        Self::with_keys(rt.rng_gen(), rt.rng_gen())
+    }
 
-        rt.rng_shuffle(&mut ports[..]);
-        Self { ports }
+    fn with_keys(offset_key: u32, table_key: u32) -> Self {
+        Self {
+            offset_key,
+            table_key,
+            table: vec![0; PORT_TABLE_LENGTH],
+            released: HashMap::new(),
+        }
     }
 
-    pub fn alloc(&mut self) -> Result<Port, Fail> {
-        self.ports.pop().ok_or(Fail::ResourceExhausted {
+    /// Picks a local port for a connection from `local` to `remote`. Ports for which `in_use`
+    /// holds and ports released to `remote` in the last minute are skipped.
+    pub fn alloc<F: Fn(Port) -> bool>(
+        &mut self,
+        local: Ipv4Addr,
+        remote: ipv4::Endpoint,
+        now: Instant,
+        in_use: F,
+    ) -> Result<Port, Fail> {
+        self.released
+            .retain(|_, t| now.saturating_duration_since(*t) < PORT_REUSE_DELAY);
+
+        let offset = Self::hash(self.offset_key, local, remote);
+        let index = Self::hash(self.table_key, local, remote) as usize % PORT_TABLE_LENGTH;
+        for _ in 0..NUM_PRIVATE_PORTS {
+            let counter = self.table[index];
+            self.table[index] = counter.wrapping_add(1);
+            let n = FIRST_PRIVATE_PORT as u32 + offset.wrapping_add(counter) % NUM_PRIVATE_PORTS;
+            let port = Port(NonZeroU16::new(n as u16).unwrap());
+            if !in_use(port) && !self.released.contains_key(&(port, remote)) {
+                return Ok(port);
+            }
+        }
+        Err(Fail::ResourceExhausted {
             details: "Out of private ports",
         })
     }
 
-    pub fn free(&mut self, port: Port) {
-        self.ports.push(port);
+    /// Releases the local port of a connection to `remote`.
+    pub fn free(&mut self, port: Port, remote: ipv4::Endpoint, now: Instant) {
+        self.released.insert((port, remote), now);
+    }
+
+    fn hash(key: u32, local: Ipv4Addr, remote: ipv4::Endpoint) -> u32 {
+        let mut hash = crc32::Digest::new(crc32::IEEE);
+        hash.write_u32(local.into());
+        hash.write_u32(remote.address().into());
+        hash.write_u16(remote.port().into());
+        hash.write_u32(key);
+        hash.sum32()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EphemeralPorts, Port, PORT_REUSE_DELAY};
+    use crate::{protocols::ipv4, test_helpers};
+    use std::{convert::TryFrom, time::Instant};
+
+    #[test]
+    fn test_ephemeral_ports() {
+        let now = Instant::now();
+        let mut ports = EphemeralPorts::with_keys(1, 2);
+        let local = test_helpers::ALICE_IPV4;
+        let remote = ipv4::Endpoint::new(test_helpers::BOB_IPV4, Port::try_from(80).unwrap());
+
+        let first = ports.alloc(local, remote, now, |_| false).unwrap();
+        assert!(first.is_private());
+        let second = ports.alloc(local, remote, now, |_| false).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(
+            ports.alloc(local, remote, now, |p| p != first).unwrap(),
+            first
+        );
+
+        // A released port is not handed out again for the same remote right away.
+        ports.free(first, remote, now);
+        assert!(ports.alloc(local, remote, now, |p| p != first).is_err());
+        let later = now + PORT_REUSE_DELAY;
+        assert_eq!(
+            ports.alloc(local, remote, later, |p| p != first).unwrap(),
+            first
+        );
     }
 }
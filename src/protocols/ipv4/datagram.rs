@@ -6,6 +6,7 @@ use byteorder::{ByteOrder, NetworkEndian};
 use num_traits::FromPrimitive;
 use std::{
     convert::{TryFrom, TryInto},
+    fmt,
     net::Ipv4Addr,
 };
 
@@ -16,8 +17,20 @@ pub const DEFAULT_IPV4_TTL: u8 = 255;
 pub const IPV4_IHL_NO_OPTIONS: u8 = 5;
 pub const IPV4_VERSION: u8 = 4;
 
+/// Flag asking routers to drop the datagram rather than fragment it, telling the sender with an
+/// ICMP fragmentation needed message instead (RFC 1191).
+pub const IPV4_DONT_FRAGMENT: u8 = 0b010;
+
+/// Codepoints of the ECN field (RFC 3168, section 5): not ECN-capable, ECN-capable transport, and
+/// congestion experienced, which routers mark ECN-capable datagrams with instead of dropping them.
+pub const IPV4_ECN_NOT_ECT: u8 = 0b00;
+pub const IPV4_ECN_ECT1: u8 = 0b01;
+pub const IPV4_ECN_ECT0: u8 = 0b10;
+pub const IPV4_ECN_CE: u8 = 0b11;
+
 #[repr(u8)]
 #[derive(FromPrimitive, Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Ipv4Protocol2 {
     Icmpv4 = 0x01,
     Tcp = 0x06,
@@ -37,7 +50,8 @@ impl TryFrom<u8> for Ipv4Protocol2 {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Ipv4Header {
     // [ version 4 bits ] [ IHL 4 bits ]
     // The user shouldn't be able to mutate the version, so we parse it out but don't include it
@@ -103,7 +117,16 @@ impl Ipv4Header {
         IPV4_HEADER_SIZE
     }
 
-    pub fn parse<T: RuntimeBuf>(mut buf: T) -> Result<(Self, T), Fail> {
+    pub fn parse<T: RuntimeBuf>(buf: T) -> Result<(Self, T), Fail> {
+        Self::parse_with_offload(buf, false)
+    }
+
+    /// Parses a header whose checksum was verified by the network device if
+    /// `rx_checksum_offload` is set.
+    pub fn parse_with_offload<T: RuntimeBuf>(
+        mut buf: T,
+        rx_checksum_offload: bool,
+    ) -> Result<(Self, T), Fail> {
         if buf.len() < IPV4_HEADER_SIZE {
             return Err(Fail::Malformed {
                 details: "Datagram too small",
@@ -160,16 +183,18 @@ impl Ipv4Header {
         let time_to_live = hdr_buf[8];
         let protocol = Ipv4Protocol2::try_from(hdr_buf[9])?;
 
-        let header_checksum = NetworkEndian::read_u16(&hdr_buf[10..12]);
-        if header_checksum == 0xffff {
-            return Err(Fail::Malformed {
-                details: "IPv4 checksum is 0xFFFF",
-            });
-        }
-        if header_checksum != ipv4_checksum(hdr_buf) {
-            return Err(Fail::Malformed {
-                details: "Invalid IPv4 checksum",
-            });
+        if !rx_checksum_offload {
+            let header_checksum = NetworkEndian::read_u16(&hdr_buf[10..12]);
+            if header_checksum == 0xffff {
+                return Err(Fail::Malformed {
+                    details: "IPv4 checksum is 0xFFFF",
+                });
+            }
+            if header_checksum != ipv4_checksum(hdr_buf) {
+                return Err(Fail::Malformed {
+                    details: "Invalid IPv4 checksum",
+                });
+            }
         }
 
         let src_addr = Ipv4Addr::from(NetworkEndian::read_u32(&hdr_buf[12..16]));
@@ -197,7 +222,9 @@ impl Ipv4Header {
         Ok((header, buf))
     }
 
-    pub fn serialize(&self, buf: &mut [u8], payload_len: usize) {
+    /// Serializes the header, leaving its checksum to the network device if `tx_checksum_offload`
+    /// is set.
+    pub fn serialize(&self, buf: &mut [u8], payload_len: usize, tx_checksum_offload: bool) {
         let buf: &mut [u8; IPV4_HEADER_SIZE] = buf.try_into().unwrap();
         buf[0] = (IPV4_VERSION << 4) | IPV4_IHL_NO_OPTIONS;
         buf[1] = (self.dscp << 2) | (self.ecn & 3);
@@ -214,7 +241,36 @@ impl Ipv4Header {
         buf[12..16].copy_from_slice(&self.src_addr.octets());
         buf[16..20].copy_from_slice(&self.dst_addr.octets());
 
-        let checksum = ipv4_checksum(buf);
+        let checksum = if tx_checksum_offload {
+            0
+        } else {
+            ipv4_checksum(buf)
+        };
         NetworkEndian::write_u16(&mut buf[10..12], checksum);
     }
 }
+
+impl fmt::Display for Ipv4Protocol2 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ipv4Protocol2::Icmpv4 => write!(f, "ICMP ({})", *self as u8),
+            Ipv4Protocol2::Tcp => write!(f, "TCP ({})", *self as u8),
+            Ipv4Protocol2::Udp => write!(f, "UDP ({})", *self as u8),
+        }
+    }
+}
+
+/// Formats the header as `IP 10.0.0.1 > 10.0.0.2: proto TCP (6), ttl 255, id 0`.
+impl fmt::Display for Ipv4Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "IP {} > {}: proto {}, ttl {}, id {}",
+            self.src_addr, self.dst_addr, self.protocol, self.time_to_live, self.identification
+        )?;
+        if self.fragment_offset != 0 {
+            write!(f, ", offset {}", self.fragment_offset)?;
+        }
+        Ok(())
+    }
+}
@@ -5,6 +5,7 @@ use crate::protocols::ip;
 use std::net::Ipv4Addr;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Ipv4Endpoint {
     pub addr: Ipv4Addr,
     pub port: ip::Port,
@@ -4,12 +4,15 @@
 use crate::{
     fail::Fail,
     file_table::FileTable,
+    filter::TCP_RST,
     protocols::{
         arp, icmpv4,
         ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
-        tcp, udp,
+        tcp, udp, Protocol,
     },
     runtime::Runtime,
+    stats::{Counters, DropReason},
+    trace::FrameSummary,
 };
 use std::{future::Future, net::Ipv4Addr, time::Duration};
 
@@ -18,18 +21,33 @@ use crate::file_table::FileDescriptor;
 
 pub struct Ipv4Peer<RT: Runtime> {
     rt: RT,
+    counters: Counters,
     icmpv4: icmpv4::Peer<RT>,
     pub tcp: tcp::Peer<RT>,
     pub udp: udp::Peer<RT>,
 }
 
 impl<RT: Runtime> Ipv4Peer<RT> {
-    pub fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable) -> Ipv4Peer<RT> {
-        let udp = udp::Peer::new(rt.clone(), arp.clone(), file_table.clone());
-        let icmpv4 = icmpv4::Peer::new(rt.clone(), arp.clone());
-        let tcp = tcp::Peer::new(rt.clone(), arp, file_table);
+    pub fn new(
+        rt: RT,
+        arp: arp::Peer<RT>,
+        file_table: FileTable,
+        counters: Counters,
+        tcp_options: tcp::Options<RT>,
+        udp_options: udp::Options,
+    ) -> Ipv4Peer<RT> {
+        let udp = udp::Peer::new(
+            rt.clone(),
+            arp.clone(),
+            file_table.clone(),
+            counters.clone(),
+            udp_options,
+        );
+        let icmpv4 = icmpv4::Peer::new(rt.clone(), arp.clone(), counters.clone());
+        let tcp = tcp::Peer::new(rt.clone(), arp, file_table, counters.clone(), tcp_options);
         Ipv4Peer {
             rt,
+            counters,
             icmpv4,
             tcp,
             udp,
@@ -37,18 +55,53 @@ impl<RT: Runtime> Ipv4Peer<RT> {
     }
 
     pub fn receive(&mut self, buf: RT::Buf) -> Result<(), Fail> {
-        let (header, payload) = Ipv4Header::parse(buf)?;
-        debug!("Ipv4 received {:?}", header);
+        self.counters.update(|s| s.ip.in_receives += 1);
+        let rx_checksum_offload = self.rt.checksum_offload().rx;
+        let (header, payload) = match Ipv4Header::parse_with_offload(buf, rx_checksum_offload) {
+            Ok(r) => r,
+            Err(e) => {
+                count_parse_error(&self.counters, &e);
+                return Err(e);
+            }
+        };
+        datapath!(Debug, "Ipv4 received {:?}", header);
         if header.dst_addr != self.rt.local_ipv4_addr() && !header.dst_addr.is_broadcast() {
+            self.counters.update(|s| s.ip.in_addr_errors += 1);
+            self.counters.count_drop(DropReason::NotForUs);
             return Err(Fail::Misdelivered {});
         }
+        self.counters.update(|s| s.ip.in_delivers += 1);
         match header.protocol {
-            Ipv4Protocol2::Icmpv4 => self.icmpv4.receive(&header, payload),
+            Ipv4Protocol2::Icmpv4 => match self.icmpv4.receive(&header, payload)? {
+                Some(msg) if msg.protocol == Ipv4Protocol2::Tcp => {
+                    self.tcp.receive_fragmentation_needed(&msg);
+                    Ok(())
+                }
+                _ => Ok(()),
+            },
             Ipv4Protocol2::Tcp => self.tcp.receive(&header, payload),
             Ipv4Protocol2::Udp => self.udp.receive(&header, payload),
         }
     }
 
+    /// Answers a datagram summarized by `summary` that the packet filter rejected: a TCP segment
+    /// with a RST, unless it is one, and a UDP datagram with an ICMP destination unreachable
+    /// quoting `quote`, its IPv4 header and first bytes.
+    pub fn reject(&mut self, summary: &FrameSummary, quote: &[u8]) -> Result<(), Fail> {
+        if summary.dst.addr != self.rt.local_ipv4_addr() {
+            return Ok(());
+        }
+        match summary.protocol {
+            Protocol::Tcp if summary.tcp_flags & TCP_RST != 0 => Ok(()),
+            Protocol::Tcp => self.tcp.reject(&summary.dst, &summary.src),
+            Protocol::Udp => self.icmpv4.send_unreachable(
+                summary.src.addr,
+                icmpv4::UNREACHABLE_ADMIN_PROHIBITED,
+                quote,
+            ),
+        }
+    }
+
     pub fn ping(
         &mut self,
         dest_ipv4_addr: Ipv4Addr,
@@ -58,6 +111,20 @@ impl<RT: Runtime> Ipv4Peer<RT> {
     }
 }
 
+/// Counts a datagram that failed to parse under the matching MIB object and drop reason.
+fn count_parse_error(counters: &Counters, e: &Fail) {
+    counters.update(|s| match e {
+        Fail::Unsupported {
+            details: "IPv4 fragmentation is unsupported",
+        } => s.ip.reasm_fails += 1,
+        Fail::Unsupported {
+            details: "Unsupported IPv4 protocol",
+        } => s.ip.in_unknown_protos += 1,
+        _ => s.ip.in_hdr_errors += 1,
+    });
+    counters.count_parse_drop(e);
+}
+
 #[cfg(test)]
 impl<RT: Runtime> Ipv4Peer<RT> {
     pub fn tcp_mss(&self, fd: FileDescriptor) -> Result<usize, Fail> {
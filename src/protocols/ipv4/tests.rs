@@ -79,3 +79,34 @@ fn ipv4_ping_loop() {
         assert_eq!(latency, Duration::from_secs(2));
     }
 }
+
+//==============================================================================
+// Serialization
+//==============================================================================
+
+#[cfg(feature = "serde")]
+#[test]
+fn ipv4_serde_round_trip() {
+    use crate::protocols::{
+        ip,
+        ipv4::{Endpoint, Ipv4Header, Ipv4Protocol2},
+    };
+    use std::convert::TryFrom;
+
+    let endpoint = Endpoint::new(test_helpers::ALICE_IPV4, ip::Port::try_from(80).unwrap());
+    let json = serde_json::to_string(&endpoint).unwrap();
+    assert_eq!(json, r#"{"addr":"192.168.1.1","port":80}"#);
+    assert_eq!(serde_json::from_str::<Endpoint>(&json).unwrap(), endpoint);
+
+    let header = Ipv4Header::new(
+        test_helpers::ALICE_IPV4,
+        test_helpers::BOB_IPV4,
+        Ipv4Protocol2::Tcp,
+    );
+    let json = serde_json::to_string(&header).unwrap();
+    let parsed: Ipv4Header = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed.src_addr, header.src_addr);
+    assert_eq!(parsed.dst_addr, header.dst_addr);
+    assert_eq!(parsed.protocol, header.protocol);
+    assert_eq!(parsed.time_to_live, header.time_to_live);
+}
@@ -9,6 +9,10 @@ pub mod ipv4;
 pub mod tcp;
 pub mod udp;
 
+#[cfg(test)]
+mod proptests;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Protocol {
     Tcp,
     Udp,
@@ -0,0 +1,259 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Property tests for the wire formats: every header survives a serialize/parse round trip and
+//! serialized checksums verify.
+
+use crate::{
+    collections::bytes::{Bytes, BytesMut},
+    protocols::{
+        arp::{ArpOperation, ArpPdu},
+        ethernet2::{EtherType2, Ethernet2Header, MacAddress},
+        ip,
+        ipv4::{Ipv4Header, Ipv4Protocol2},
+        tcp::segment::{
+            SelectiveAcknowlegement, TcpHeader, TcpOptions2, MAX_TCP_HEADER_SIZE, MAX_TCP_OPTIONS,
+            MIN_TCP_HEADER_SIZE,
+        },
+        udp::UdpHeader,
+    },
+    runtime::RuntimeBuf,
+};
+use proptest::{collection::vec, option, prelude::*};
+use std::{convert::TryFrom, net::Ipv4Addr, num::Wrapping};
+
+//==============================================================================
+// Strategies
+//==============================================================================
+
+pub fn mac_address() -> impl Strategy<Value = MacAddress> {
+    any::<[u8; 6]>().prop_map(MacAddress::new)
+}
+
+pub fn ipv4_addr() -> impl Strategy<Value = Ipv4Addr> {
+    any::<u32>().prop_map(Ipv4Addr::from)
+}
+
+pub fn port() -> impl Strategy<Value = ip::Port> {
+    (1..=u16::MAX).prop_map(|p| ip::Port::try_from(p).unwrap())
+}
+
+pub fn ethernet2_header() -> impl Strategy<Value = Ethernet2Header> {
+    let ether_type = prop_oneof![Just(EtherType2::Arp), Just(EtherType2::Ipv4)];
+    (mac_address(), mac_address(), ether_type)
+        .prop_map(|(dst, src, ether_type)| Ethernet2Header::new(dst, src, ether_type))
+}
+
+/// IPv4 headers we are able to parse, i.e. without fragmentation.
+pub fn ipv4_header() -> impl Strategy<Value = Ipv4Header> {
+    let protocol = prop_oneof![
+        Just(Ipv4Protocol2::Icmpv4),
+        Just(Ipv4Protocol2::Tcp),
+        Just(Ipv4Protocol2::Udp),
+    ];
+    (
+        ipv4_addr(),
+        ipv4_addr(),
+        protocol,
+        0..64u8,
+        0..4u8,
+        any::<u16>(),
+        0..8u8,
+        any::<u8>(),
+    )
+        .prop_map(
+            |(src, dst, protocol, dscp, ecn, identification, flags, time_to_live)| {
+                let mut header = Ipv4Header::new(src, dst, protocol);
+                header.dscp = dscp;
+                header.ecn = ecn;
+                header.identification = identification;
+                header.flags = flags;
+                header.time_to_live = time_to_live;
+                header
+            },
+        )
+}
+
+/// TCP options that are kept when parsing. No-ops are skipped by the parser, so they are left out.
+pub fn tcp_option() -> impl Strategy<Value = TcpOptions2> {
+    let sacks = vec((any::<u32>(), any::<u32>()), 1..=4).prop_map(|blocks| {
+        let mut sacks = [SelectiveAcknowlegement {
+            begin: Wrapping(0),
+            end: Wrapping(0),
+        }; 4];
+        for (sack, (begin, end)) in sacks.iter_mut().zip(&blocks) {
+            sack.begin = Wrapping(*begin);
+            sack.end = Wrapping(*end);
+        }
+        TcpOptions2::SelectiveAcknowlegement {
+            num_sacks: blocks.len(),
+            sacks,
+        }
+    });
+    prop_oneof![
+        any::<u16>().prop_map(TcpOptions2::MaximumSegmentSize),
+        any::<u8>().prop_map(TcpOptions2::WindowScale),
+        Just(TcpOptions2::SelectiveAcknowlegementPermitted),
+        sacks,
+        (any::<u32>(), any::<u32>()).prop_map(|(sender_timestamp, echo_timestamp)| {
+            TcpOptions2::Timestamp {
+                sender_timestamp,
+                echo_timestamp,
+            }
+        }),
+    ]
+}
+
+pub fn tcp_header() -> impl Strategy<Value = TcpHeader> {
+    (
+        port(),
+        port(),
+        any::<u32>(),
+        any::<u32>(),
+        0..(1u16 << 9),
+        any::<u16>(),
+        any::<u16>(),
+        vec(tcp_option(), 0..=MAX_TCP_OPTIONS),
+    )
+        .prop_map(
+            |(
+                src_port,
+                dst_port,
+                seq_num,
+                ack_num,
+                flags,
+                window_size,
+                urgent_pointer,
+                options,
+            )| {
+                let mut header = TcpHeader::new(src_port, dst_port);
+                header.seq_num = Wrapping(seq_num);
+                header.ack_num = Wrapping(ack_num);
+                header.ns = flags & (1 << 8) != 0;
+                header.cwr = flags & (1 << 7) != 0;
+                header.ece = flags & (1 << 6) != 0;
+                header.urg = flags & (1 << 5) != 0;
+                header.ack = flags & (1 << 4) != 0;
+                header.psh = flags & (1 << 3) != 0;
+                header.rst = flags & (1 << 2) != 0;
+                header.syn = flags & (1 << 1) != 0;
+                header.fin = flags & (1 << 0) != 0;
+                header.window_size = window_size;
+                header.urgent_pointer = urgent_pointer;
+                for option in options {
+                    header.push_option(option);
+                }
+                header
+            },
+        )
+        .prop_filter("TCP options must fit in the header", |header| {
+            header.compute_size() <= MAX_TCP_HEADER_SIZE
+        })
+}
+
+pub fn udp_header() -> impl Strategy<Value = UdpHeader> {
+    (option::of(port()), port()).prop_map(|(src_port, dst_port)| UdpHeader::new(src_port, dst_port))
+}
+
+pub fn arp_pdu() -> impl Strategy<Value = ArpPdu> {
+    let operation = prop_oneof![Just(ArpOperation::Request), Just(ArpOperation::Reply)];
+    (
+        operation,
+        mac_address(),
+        ipv4_addr(),
+        mac_address(),
+        ipv4_addr(),
+    )
+        .prop_map(|(op, sha, spa, tha, tpa)| ArpPdu::new(op, sha, spa, tha, tpa))
+}
+
+pub fn payload() -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..256)
+}
+
+//==============================================================================
+// Properties
+//==============================================================================
+
+/// Serializes a TCP segment (header followed by `data`) into a fresh buffer.
+fn serialize_tcp(header: &TcpHeader, ipv4_hdr: &Ipv4Header, data: &[u8]) -> Vec<u8> {
+    let header_size = header.compute_size();
+    let mut buf = vec![0u8; header_size + data.len()];
+    header.serialize(&mut buf[..header_size], ipv4_hdr, data, false);
+    buf[header_size..].copy_from_slice(data);
+    buf
+}
+
+proptest! {
+    #[test]
+    fn ethernet2_round_trip(header in ethernet2_header(), data in payload()) {
+        let mut buf = BytesMut::zeroed(header.compute_size() + data.len()).unwrap();
+        header.serialize(&mut buf[..header.compute_size()]);
+        buf[header.compute_size()..].copy_from_slice(&data);
+        let (parsed, body) = Ethernet2Header::parse(buf.freeze()).unwrap();
+        prop_assert_eq!(parsed, header);
+        prop_assert_eq!(&body[..], &data[..]);
+    }
+
+    #[test]
+    fn ipv4_round_trip(header in ipv4_header(), data in payload()) {
+        let mut buf = BytesMut::zeroed(header.compute_size() + data.len()).unwrap();
+        header.serialize(&mut buf[..header.compute_size()], data.len(), false);
+        buf[header.compute_size()..].copy_from_slice(&data);
+        let (parsed, body) = Ipv4Header::parse(buf.freeze()).unwrap();
+        prop_assert_eq!(parsed, header);
+        prop_assert_eq!(&body[..], &data[..]);
+    }
+
+    #[test]
+    fn tcp_round_trip(header in tcp_header(), ipv4_hdr in ipv4_header(), data in payload()) {
+        let buf = serialize_tcp(&header, &ipv4_hdr, &data);
+        let (parsed, body) = TcpHeader::parse(&ipv4_hdr, Bytes::from_slice(&buf), false).unwrap();
+        prop_assert_eq!(parsed, header);
+        prop_assert_eq!(&body[..], &data[..]);
+    }
+
+    #[test]
+    fn tcp_options_length(header in tcp_header()) {
+        let size = header.compute_size();
+        prop_assert_eq!(size % 4, 0);
+        prop_assert!(size >= MIN_TCP_HEADER_SIZE && size <= MAX_TCP_HEADER_SIZE);
+        let buf = serialize_tcp(&header, &Ipv4Header::new(
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Protocol2::Tcp,
+        ), &[]);
+        // The data offset covers exactly the header we computed.
+        prop_assert_eq!((buf[12] >> 4) as usize * 4, size);
+    }
+
+    #[test]
+    fn tcp_checksum_detects_corruption(
+        header in tcp_header(),
+        ipv4_hdr in ipv4_header(),
+        data in vec(any::<u8>(), 1..256),
+        bit in any::<prop::sample::Index>(),
+    ) {
+        let mut buf = serialize_tcp(&header, &ipv4_hdr, &data);
+        let bit = header.compute_size() * 8 + bit.index(data.len() * 8);
+        buf[bit / 8] ^= 1 << (bit % 8);
+        prop_assert!(TcpHeader::parse(&ipv4_hdr, Bytes::from_slice(&buf), false).is_err());
+    }
+
+    #[test]
+    fn udp_round_trip(header in udp_header(), ipv4_hdr in ipv4_header(), data in payload()) {
+        let mut buf = vec![0u8; header.size() + data.len()];
+        header.serialize(&mut buf[..header.size()], &ipv4_hdr, &data, false);
+        buf[header.size()..].copy_from_slice(&data);
+        let (parsed, body) = UdpHeader::parse(&ipv4_hdr, Bytes::from_slice(&buf), false).unwrap();
+        prop_assert_eq!(parsed, header);
+        prop_assert_eq!(&body[..], &data[..]);
+    }
+
+    #[test]
+    fn arp_round_trip(pdu in arp_pdu()) {
+        let mut buf = BytesMut::zeroed(pdu.compute_size()).unwrap();
+        pdu.serialize(&mut buf[..]);
+        prop_assert_eq!(ArpPdu::parse(buf.freeze()).unwrap(), pdu);
+    }
+}
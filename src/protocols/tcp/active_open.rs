@@ -1,7 +1,14 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use super::{constants::FALLBACK_MSS, established::ControlBlock};
+use super::{
+    constants::{FALLBACK_MSS, MAX_WINDOW_SCALE},
+    established::ControlBlock,
+    events::Subscribers,
+    fast_open::FastOpenCache,
+    passive_open::syn_ack_header,
+    timestamp::TimestampClock,
+};
 use crate::{
     fail::Fail,
     protocols::{
@@ -10,25 +17,92 @@ use crate::{
         ipv4,
         ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
         tcp::{
-            segment::{TcpHeader, TcpOptions2, TcpSegment},
-            SeqNumber,
+            loopback::Loopback,
+            segment::{
+                TcpHeader, TcpOptions2, TcpSegment, MAX_TCP_HEADER_SIZE, MIN_TCP_HEADER_SIZE,
+            },
+            Options as TcpOptions, SeqNumber,
         },
     },
     runtime::{Runtime, RuntimeBuf},
     scheduler::SchedulerHandle,
+    stats::{Counters, DropReason},
 };
 use std::{
     cell::RefCell,
-    convert::TryInto,
     future::Future,
     num::Wrapping,
     rc::Rc,
     task::{Context, Poll, Waker},
 };
 
+/// Data sent on a SYN with TCP Fast Open, at most. As the MSS of the remote is unknown until it
+/// answers, that is what it takes at the least, leaving room for the largest options.
+const MAX_SYN_DATA: usize = FALLBACK_MSS - (MAX_TCP_HEADER_SIZE - MIN_TCP_HEADER_SIZE);
+
 struct ConnectResult<RT: Runtime> {
     waker: Option<Waker>,
     result: Option<Result<ControlBlock<RT>, Fail>>,
+    /// SYN of a remote opening the connection at the same time, putting it in SYN_RCVD.
+    syn_received: Option<RemoteSyn>,
+}
+
+/// What the SYN of the remote offered.
+#[derive(Clone, Copy)]
+struct RemoteSyn {
+    remote_isn: SeqNumber,
+    window_scale: Option<u8>,
+    mss: usize,
+    /// Whether both sides offered selective acknowledgments.
+    selective_acks: bool,
+    /// Timestamp of the remote, if it offered timestamps.
+    ts_recent: Option<u32>,
+    /// Whether both sides offered explicit congestion notification.
+    ecn: bool,
+}
+
+impl RemoteSyn {
+    fn parse<RT: Runtime>(header: &TcpHeader, tcp_options: &TcpOptions<RT>) -> Self {
+        let mut window_scale = None;
+        let mut mss = FALLBACK_MSS;
+        let mut selective_acks = false;
+        let mut ts_recent = None;
+        for option in header.iter_options() {
+            match option {
+                TcpOptions2::WindowScale(w) => {
+                    info!("Received window scale: {}", w);
+                    // RFC 7323, section 2.3: a larger shift count is taken as the largest one.
+                    window_scale = Some((*w).min(MAX_WINDOW_SCALE));
+                }
+                TcpOptions2::MaximumSegmentSize(m) => {
+                    info!("Received advertised MSS: {}", m);
+                    mss = *m as usize;
+                }
+                TcpOptions2::SelectiveAcknowlegementPermitted => {
+                    info!("Received SACK permitted");
+                    selective_acks = tcp_options.selective_acks();
+                }
+                TcpOptions2::Timestamp {
+                    sender_timestamp, ..
+                } => {
+                    info!("Received timestamp: {}", sender_timestamp);
+                    ts_recent = Some(*sender_timestamp);
+                }
+                _ => continue,
+            }
+        }
+        // RFC 3168, section 6.1.1: an ECN-setup SYN has ECE and CWR set, an ECN-setup SYN+ACK
+        // only ECE.
+        let ecn = tcp_options.ecn() && header.ece && header.cwr != header.ack;
+        Self {
+            remote_isn: header.seq_num,
+            window_scale,
+            mss,
+            selective_acks,
+            ts_recent,
+            ecn,
+        }
+    }
 }
 
 pub struct ActiveOpenSocket<RT: Runtime> {
@@ -39,6 +113,16 @@ pub struct ActiveOpenSocket<RT: Runtime> {
 
     rt: RT,
     arp: arp::Peer<RT>,
+    loopback: Loopback<RT>,
+    counters: Counters,
+    subscribers: Subscribers,
+    tcp_options: TcpOptions<RT>,
+    ts_clock: TimestampClock,
+    fast_open_cookies: Rc<RefCell<FastOpenCache>>,
+
+    /// Data to send once connected, of which the first `syn_data_len` bytes went on the SYN.
+    data: RT::Buf,
+    syn_data_len: usize,
 
     #[allow(unused)]
     handle: SchedulerHandle,
@@ -52,10 +136,39 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         remote: ipv4::Endpoint,
         rt: RT,
         arp: arp::Peer<RT>,
+        loopback: Loopback<RT>,
+        counters: Counters,
+        subscribers: Subscribers,
+        tcp_options: TcpOptions<RT>,
+        ts_clock: TimestampClock,
+        fast_open_cookies: Rc<RefCell<FastOpenCache>>,
+        data: RT::Buf,
     ) -> Self {
+        // With TCP Fast Open, the SYN carries the cookie the remote granted earlier and the
+        // first data, or asks for a cookie if there is none yet.
+        let fast_open_cookie = if tcp_options.fast_open() {
+            let cookies = fast_open_cookies.borrow();
+            Some(cookies.get(remote.addr).unwrap_or(&[]).to_vec())
+        } else {
+            None
+        };
+        let mut syn_data = RT::Buf::empty();
+        match fast_open_cookie {
+            Some(ref cookie) if cookie.is_empty() => {
+                counters.update(|s| s.tcp.fast_open_cookie_reqs += 1)
+            }
+            Some(_) => {
+                syn_data = data.clone();
+                syn_data.trim(data.len().saturating_sub(MAX_SYN_DATA));
+            }
+            None => (),
+        }
+        let syn_data_len = syn_data.len();
+
         let result = ConnectResult {
             waker: None,
             result: None,
+            syn_received: None,
         };
         let result = Rc::new(RefCell::new(result));
 
@@ -65,7 +178,13 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
             remote,
             rt.clone(),
             arp.clone(),
+            loopback.clone(),
+            counters.clone(),
+            tcp_options.clone(),
+            ts_clock,
             result.clone(),
+            fast_open_cookie,
+            syn_data,
         );
         let handle = rt.spawn(future);
 
@@ -76,6 +195,15 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
             remote,
             rt,
             arp,
+            loopback,
+            counters,
+            subscribers,
+            tcp_options,
+            ts_clock,
+            fast_open_cookies,
+
+            data,
+            syn_data_len,
 
             handle,
             result,
@@ -101,91 +229,115 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         r.result.replace(result);
     }
 
+    /// Gives the handshake up on behalf of the application, failing the pending connect.
+    pub fn abort(&mut self) {
+        self.set_result(Err(Fail::ConnectionAborted {}));
+    }
+
     pub fn receive(&mut self, header: &TcpHeader) {
+        // A SYN without ACK means the remote is opening the connection at the same time
+        // (RFC 793, section 3.4): move to SYN_RCVD, answering with a SYN+ACK.
+        if header.syn && !header.ack && !header.rst {
+            debug!("Received SYN: {}", header);
+            let syn = RemoteSyn::parse(header, &self.tcp_options);
+            self.result.borrow_mut().syn_received = Some(syn);
+            let tcp_hdr = syn_ack_header(
+                &self.local,
+                &self.remote,
+                self.local_isn,
+                syn.remote_isn,
+                &self.tcp_options,
+                syn.window_scale.is_some(),
+                syn.selective_acks,
+                self.timestamp(&syn),
+                syn.ecn,
+            );
+            debug!("Sending SYN+ACK: {}", tcp_hdr);
+            self.transmit(tcp_hdr);
+            return;
+        }
+
         let expected_seq = self.local_isn + Wrapping(1);
 
-        // Bail if we didn't receive a ACK packet with the right sequence number.
-        if !(header.ack && header.ack_num == expected_seq) {
+        // Bail if we didn't receive a ACK packet with the right sequence number. The remote
+        // acknowledges the data of our SYN along with it, if it took the data.
+        let Wrapping(acked_data) = header.ack_num - expected_seq;
+        if !header.ack || acked_data as usize > self.syn_data_len {
             return;
         }
 
         // Check if our peer is refusing our connection request.
         if header.rst {
+            self.counters.update(|s| s.tcp.attempt_fails += 1);
             self.set_result(Err(Fail::ConnectionRefused {}));
             return;
         }
 
-        // Bail if we didn't receive a SYN packet.
-        if !header.syn {
-            return;
-        }
-
-        debug!("Received SYN+ACK: {:?}", header);
-
-        // Acknowledge the SYN+ACK segment.
-        let remote_link_addr = match self.arp.try_query(self.remote.address()) {
-            Some(r) => r,
-            None => panic!("TODO: Clean up ARP query control flow"),
-        };
-        let remote_seq_num = header.seq_num + Wrapping(1);
-
-        let tcp_options = self.rt.tcp_options();
-
-        let mut tcp_hdr = TcpHeader::new(self.local.port, self.remote.port);
-        tcp_hdr.ack = true;
-        tcp_hdr.ack_num = remote_seq_num;
-        tcp_hdr.window_size = tcp_options.receive_window_size();
-        tcp_hdr.seq_num = self.local_isn + Wrapping(1);
-        debug!("Sending ACK: {:?}", tcp_hdr);
-
-        let segment = TcpSegment {
-            ethernet2_hdr: Ethernet2Header {
-                dst_addr: remote_link_addr,
-                src_addr: self.rt.local_link_addr(),
-                ether_type: EtherType2::Ipv4,
-            },
-            ipv4_hdr: Ipv4Header::new(self.local.addr, self.remote.addr, Ipv4Protocol2::Tcp),
-            tcp_hdr,
-            data: RT::Buf::empty(),
-            tx_checksum_offload: tcp_options.tx_checksum_offload(),
-        };
-        self.rt.transmit(segment);
-
-        let mut remote_window_scale = None;
-        let mut mss = FALLBACK_MSS;
-        for option in header.iter_options() {
-            match option {
-                TcpOptions2::WindowScale(w) => {
-                    info!("Received window scale: {}", w);
-                    remote_window_scale = Some(*w);
-                }
-                TcpOptions2::MaximumSegmentSize(m) => {
-                    info!("Received advertised MSS: {}", m);
-                    mss = *m as usize;
-                }
-                _ => continue,
+        let syn = if header.syn {
+            debug!("Received SYN+ACK: {}", header);
+            let syn = RemoteSyn::parse(header, &self.tcp_options);
+
+            // Acknowledge the SYN+ACK segment.
+            let mut tcp_hdr = TcpHeader::new(self.local.port, self.remote.port);
+            tcp_hdr.ack = true;
+            tcp_hdr.ack_num = syn.remote_isn + Wrapping(1);
+            // Unlike that of the SYN, the window of this ACK is scaled.
+            let local_window_scale = syn
+                .window_scale
+                .map_or(0, |_| self.tcp_options.window_scale() as u32);
+            let window = self.tcp_options.receive_window(local_window_scale);
+            tcp_hdr.window_size = (window >> local_window_scale) as u16;
+            tcp_hdr.seq_num = header.ack_num;
+            if let Some((sender_timestamp, echo_timestamp)) = self.timestamp(&syn) {
+                tcp_hdr.push_option(TcpOptions2::Timestamp {
+                    sender_timestamp,
+                    echo_timestamp,
+                });
             }
-        }
+            debug!("Sending ACK: {}", tcp_hdr);
+            self.transmit(tcp_hdr);
+
+            // Keep the TCP Fast Open cookie granted for the next connections to the remote.
+            match header.fast_open_cookie() {
+                Some(cookie) if !cookie.is_empty() && self.tcp_options.fast_open() => self
+                    .fast_open_cookies
+                    .borrow_mut()
+                    .insert(self.remote.addr, cookie),
+                _ => (),
+            }
+            if self.syn_data_len > 0 {
+                let taken = acked_data as usize == self.syn_data_len;
+                self.counters.update(|s| {
+                    if taken {
+                        s.tcp.fast_open_active += 1
+                    } else {
+                        s.tcp.fast_open_active_fails += 1
+                    }
+                });
+            }
+            syn
+        } else {
+            // In SYN_RCVD, the ACK of our SYN completes a simultaneous open.
+            match self.result.borrow().syn_received {
+                Some(syn) => syn,
+                None => return,
+            }
+        };
+        let tcp_options = &self.tcp_options;
+        let timestamps = tcp_options.timestamps() && syn.ts_recent.is_some();
 
-        let (local_window_scale, remote_window_scale) = match remote_window_scale {
+        let (local_window_scale, remote_window_scale) = match syn.window_scale {
             Some(w) => (tcp_options.window_scale() as u32, w),
             None => (0, 0),
         };
 
-        // TODO(RFC1323): Clamp the scale to 14 instead of panicking.
-        assert!(local_window_scale <= 14 && remote_window_scale <= 14);
-
-        let rx_window_size: u32 = (tcp_options.receive_window_size())
-            .checked_shl(local_window_scale as u32)
-            .expect("TODO: Window size overflow")
-            .try_into()
-            .expect("TODO: Window size overflow");
-
-        let tx_window_size: u32 = (header.window_size)
-            .checked_shl(remote_window_scale as u32)
-            .expect("TODO: Window size overflow")
-            .try_into()
-            .expect("TODO: Window size overflow");
+        let rx_window_size: u32 = tcp_options.receive_window(local_window_scale);
+        // The window of a segment with SYN set is never scaled.
+        let tx_window_size: u32 = if header.syn {
+            header.window_size as u32
+        } else {
+            (header.window_size as u32) << remote_window_scale
+        };
 
         info!(
             "Window sizes: local {}, remote {}",
@@ -196,60 +348,141 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
             local_window_scale, remote_window_scale
         );
 
+        // An ACK completing a simultaneous open carries a newer timestamp than the SYN did.
+        let ts_recent = syn
+            .ts_recent
+            .map(|ts| header.sender_timestamp().unwrap_or(ts));
+
         let cb = ControlBlock::new(
             self.local,
             self.remote,
             self.rt.clone(),
             self.arp.clone(),
-            remote_seq_num,
-            self.rt.tcp_options().ack_delay_timeout(),
+            self.loopback.clone(),
+            self.counters.clone(),
+            self.subscribers.clone(),
+            tcp_options.clone(),
+            syn.remote_isn + Wrapping(1),
             rx_window_size,
             local_window_scale,
-            expected_seq,
+            header.ack_num,
             tx_window_size,
             remote_window_scale,
-            mss,
-            tcp_options.congestion_ctrl_type(),
-            tcp_options.congestion_ctrl_options(),
+            syn.mss,
+            syn.selective_acks,
+            Some(self.ts_clock).filter(|_| timestamps),
+            ts_recent,
+            syn.ecn,
         );
+        // Whatever the remote did not take from the SYN goes out as usual.
+        let mut data = self.data.clone();
+        data.adjust(acked_data as usize);
+        if !data.is_empty() {
+            if let Err(e) = cb.send(data) {
+                warn!("Failed to send data after the handshake: {:?}", e);
+            }
+        }
         self.set_result(Ok(cb));
     }
 
+    /// Timestamp option values to answer `syn` with, if both sides offered timestamps.
+    fn timestamp(&self, syn: &RemoteSyn) -> Option<(u32, u32)> {
+        syn.ts_recent
+            .filter(|_| self.tcp_options.timestamps())
+            .map(|ts| (self.ts_clock.value(self.rt.now()), ts))
+    }
+
+    fn transmit(&self, tcp_hdr: TcpHeader) {
+        let remote_link_addr = match self.arp.try_query(self.remote.address()) {
+            Some(r) => r,
+            None => panic!("TODO: Clean up ARP query control flow"),
+        };
+        let segment = TcpSegment {
+            ethernet2_hdr: Ethernet2Header {
+                dst_addr: remote_link_addr,
+                src_addr: self.rt.local_link_addr(),
+                ether_type: EtherType2::Ipv4,
+            },
+            ipv4_hdr: Ipv4Header::new(self.local.addr, self.remote.addr, Ipv4Protocol2::Tcp),
+            tcp_hdr,
+            data: RT::Buf::empty(),
+            tx_checksum_offload: self.tcp_options.tx_checksum_offload(),
+            pseudo_header_sum: None,
+        };
+        match self.loopback.transmit(segment) {
+            Ok(()) => self.counters.update(|s| {
+                s.ip.out_requests += 1;
+                s.tcp.out_segs += 1;
+            }),
+            Err(e) => {
+                warn!("Failed to send segment: {:?}", e);
+                self.counters.count_transmit_drop(&e);
+            }
+        }
+    }
+
     fn background(
         local_isn: SeqNumber,
         local: ipv4::Endpoint,
         remote: ipv4::Endpoint,
         rt: RT,
         arp: arp::Peer<RT>,
+        loopback: Loopback<RT>,
+        counters: Counters,
+        tcp_options: TcpOptions<RT>,
+        ts_clock: TimestampClock,
         result: Rc<RefCell<ConnectResult<RT>>>,
+        fast_open_cookie: Option<Vec<u8>>,
+        syn_data: RT::Buf,
     ) -> impl Future<Output = ()> {
-        let tcp_options = rt.tcp_options();
         let handshake_retries: usize = tcp_options.handshake_retries();
-        let handshake_timeout = tcp_options.handshake_timeout();
+        let mut handshake_timeout = tcp_options.handshake_timeout();
 
         async move {
-            for _ in 0..handshake_retries {
+            for attempt in 0..handshake_retries {
                 let remote_link_addr = match arp.query(remote.address()).await {
                     Ok(r) => r,
                     Err(e) => {
                         warn!("ARP query failed: {:?}", e);
+                        counters.count_drop(DropReason::UnresolvedArp);
                         continue;
                     }
                 };
 
-                let mut tcp_hdr = TcpHeader::new(local.port, remote.port);
-                tcp_hdr.syn = true;
-                tcp_hdr.seq_num = local_isn;
-                tcp_hdr.window_size = tcp_options.receive_window_size();
-
-                let mss = tcp_options.advertised_mss() as u16;
-                tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(mss));
-                info!("Advertising MSS: {}", mss);
-
-                tcp_hdr.push_option(TcpOptions2::WindowScale(tcp_options.window_scale()));
-                info!("Advertising window scale: {}", tcp_options.window_scale());
+                // Once the SYN of the remote showed up, our SYN goes out along with its ACK.
+                let syn_received = result.borrow().syn_received;
+                let tcp_hdr = match syn_received {
+                    Some(syn) => syn_ack_header(
+                        &local,
+                        &remote,
+                        local_isn,
+                        syn.remote_isn,
+                        &tcp_options,
+                        syn.window_scale.is_some(),
+                        syn.selective_acks,
+                        syn.ts_recent
+                            .filter(|_| tcp_options.timestamps())
+                            .map(|ts| (ts_clock.value(rt.now()), ts)),
+                        syn.ecn,
+                    ),
+                    None => syn_header(
+                        &local,
+                        &remote,
+                        local_isn,
+                        &tcp_options,
+                        ts_clock,
+                        &rt,
+                        fast_open_cookie.as_deref(),
+                    ),
+                };
+                // RFC 7413, section 4.1.3: retransmitted SYNs leave the data out, in case the
+                // first one got dropped for carrying any.
+                let data = match (attempt, syn_received) {
+                    (0, None) => syn_data.clone(),
+                    _ => RT::Buf::empty(),
+                };
 
-                debug!("Sending SYN {:?}", tcp_hdr);
+                debug!("Sending SYN {}", tcp_hdr);
                 let segment = TcpSegment {
                     ethernet2_hdr: Ethernet2Header {
                         dst_addr: remote_link_addr,
@@ -258,12 +491,28 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
                     },
                     ipv4_hdr: Ipv4Header::new(local.addr, remote.addr, Ipv4Protocol2::Tcp),
                     tcp_hdr,
-                    data: RT::Buf::empty(),
+                    data,
                     tx_checksum_offload: tcp_options.tx_checksum_offload(),
+                    pseudo_header_sum: None,
                 };
-                rt.transmit(segment);
+                match loopback.transmit(segment) {
+                    Ok(()) => counters.update(|s| {
+                        s.ip.out_requests += 1;
+                        s.tcp.out_segs += 1;
+                        if attempt > 0 {
+                            s.tcp.retrans_segs += 1;
+                        }
+                    }),
+                    Err(e) => {
+                        warn!("Failed to send SYN: {:?}", e);
+                        counters.count_transmit_drop(&e);
+                    }
+                }
                 rt.wait(handshake_timeout).await;
+                // RFC 6298, section 5.5: wait twice as long for every SYN left unanswered.
+                handshake_timeout = (handshake_timeout * 2).min(tcp_options.rto_upper_bound());
             }
+            counters.update(|s| s.tcp.attempt_fails += 1);
             let mut r = result.borrow_mut();
             if let Some(w) = r.waker.take() {
                 w.wake()
@@ -272,3 +521,50 @@ impl<RT: Runtime> ActiveOpenSocket<RT> {
         }
     }
 }
+
+/// Builds the SYN opening a connection, offering every option enabled in `tcp_options`, and
+/// carrying the TCP Fast Open option with `fast_open_cookie` if set.
+fn syn_header<RT: Runtime>(
+    local: &ipv4::Endpoint,
+    remote: &ipv4::Endpoint,
+    local_isn: SeqNumber,
+    tcp_options: &TcpOptions<RT>,
+    ts_clock: TimestampClock,
+    rt: &RT,
+    fast_open_cookie: Option<&[u8]>,
+) -> TcpHeader {
+    let mut tcp_hdr = TcpHeader::new(local.port, remote.port);
+    tcp_hdr.syn = true;
+    tcp_hdr.seq_num = local_isn;
+    tcp_hdr.window_size = tcp_options.receive_window(0) as u16;
+
+    let mss = tcp_options.advertised_mss() as u16;
+    tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(mss));
+    info!("Advertising MSS: {}", mss);
+
+    tcp_hdr.push_option(TcpOptions2::WindowScale(tcp_options.window_scale()));
+    info!("Advertising window scale: {}", tcp_options.window_scale());
+
+    if tcp_options.selective_acks() {
+        tcp_hdr.push_option(TcpOptions2::SelectiveAcknowlegementPermitted);
+        info!("Advertising SACK permitted");
+    }
+
+    if tcp_options.timestamps() {
+        tcp_hdr.push_option(TcpOptions2::Timestamp {
+            sender_timestamp: ts_clock.value(rt.now()),
+            echo_timestamp: 0,
+        });
+    }
+
+    if let Some(cookie) = fast_open_cookie {
+        tcp_hdr.push_option(TcpOptions2::fast_open(cookie));
+    }
+
+    // RFC 3168, section 6.1.1: ECE and CWR both set offer explicit congestion notification.
+    if tcp_options.ecn() {
+        tcp_hdr.ece = true;
+        tcp_hdr.cwr = true;
+    }
+    tcp_hdr
+}
@@ -8,5 +8,12 @@ pub const FALLBACK_MSS: usize = 536;
 pub const MIN_MSS: usize = 536;
 pub const MAX_MSS: usize = u16::max_value() as usize;
 
-// TODO: does this need to be determined through MTU discovery?
-pub const DEFAULT_MSS: usize = 1450;
+// RFC 791: every host takes datagrams of 576 bytes, which leave room for an MSS of MIN_MSS.
+pub const MIN_MTU: usize = 576;
+
+// MTU of Ethernet, which the MSS we advertise derives from unless set otherwise.
+pub const DEFAULT_MTU: usize = 1500;
+
+// RFC 7323, section 2.3: a larger shift count would let the window cover more than half of the
+// sequence space.
+pub const MAX_WINDOW_SCALE: u8 = 14;
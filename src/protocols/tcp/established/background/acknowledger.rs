@@ -14,12 +14,9 @@ use std::rc::Rc;
 
 pub async fn acknowledger<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
     loop {
-        // TODO: Implement TCP delayed ACKs, subject to restrictions from RFC 1122
-        // - TCP should implement a delayed ACK
-        // - The delay must be less than 500ms
-        // - For a stream of full-sized segments, there should be an ack for every other segment.
+        // The receiver sets the deadline of delayed ACKs (RFC 1122, section 4.2.3.2): the ack
+        // delay timeout after data arrives, or at once for every few full-sized segments.
 
-        // TODO: Implement SACKs
         let (ack_deadline, ack_deadline_changed) = cb.get_ack_deadline();
         futures::pin_mut!(ack_deadline_changed);
 
@@ -33,9 +30,9 @@ pub async fn acknowledger<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fa
             _ = ack_deadline_changed => continue,
             _ = ack_future => {
                 let (recv_seq_no, _) = cb.get_last_recv_seq_no();
-                let (ack_seq_no, _) = cb.get_last_ack_no();
                 let (sent_seq_no, _) = cb.get_sent_seq_no();
-                assert_ne!(ack_seq_no, recv_seq_no);
+                // Data already acknowledged may get acknowledged again, either to tell the remote
+                // about a gap or to answer a segment with an old timestamp.
 
                 let remote_link_addr = cb.arp().query(cb.get_remote().address()).await?;
 
@@ -3,7 +3,7 @@
 
 //! Defines functions to be called during the TCP connection termination process.
 
-use super::{ControlBlock, State};
+use super::{super::fsm::Event, ControlBlock, State};
 use crate::{
     fail::Fail,
     runtime::{Runtime, RuntimeBuf},
@@ -39,7 +39,7 @@ async fn active_send_fin<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fai
         header.fin = true;
         cb.emit(header, RT::Buf::empty(), remote_link_addr);
 
-        cb.set_state(State::FinWait1);
+        cb.apply(Event::FinSent)?;
     }
 }
 
@@ -72,11 +72,7 @@ async fn active_ack_fin<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail
         header.ack_num = recv_seq + Wrapping(1);
         cb.emit(header, RT::Buf::empty(), remote_link_addr);
 
-        if st == State::Closing1 {
-            cb.set_state(State::Closing2)
-        } else {
-            cb.set_state(State::TimeWait2);
-        }
+        cb.apply(Event::FinAcked)?;
     }
 }
 
@@ -92,7 +88,7 @@ async fn active_wait_2msl<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fa
             continue;
         }
 
-        // TODO: Wait for 2*MSL if active close.
+        // The peer keeps what remains of the connection in its TIME_WAIT table for 2MSL.
         return Err(Fail::ConnectionAborted {});
     }
 }
@@ -124,7 +120,7 @@ async fn passive_close<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail>
         header.ack_num = recv_seq + Wrapping(1);
         cb.emit(header, RT::Buf::empty(), remote_link_addr);
 
-        cb.set_state(State::CloseWait1);
+        cb.apply(Event::FinAcked)?;
     }
 }
 
@@ -153,7 +149,7 @@ async fn passive_send_fin<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fa
         header.fin = true;
         cb.emit(header, RT::Buf::empty(), remote_link_addr);
 
-        cb.set_state(State::LastAck);
+        cb.apply(Event::FinSent)?;
     }
 }
 
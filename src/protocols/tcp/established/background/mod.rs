@@ -3,12 +3,13 @@
 
 mod acknowledger;
 mod closer;
+mod path_mtu;
 mod retransmitter;
 mod sender;
 
 use self::{
-    acknowledger::acknowledger, closer::connection_terminated, retransmitter::retransmitter,
-    sender::sender,
+    acknowledger::acknowledger, closer::connection_terminated, path_mtu::path_mtu_prober,
+    retransmitter::retransmitter, sender::sender,
 };
 use super::{ControlBlock, State};
 use crate::{file_table::FileDescriptor, runtime::Runtime};
@@ -33,6 +34,9 @@ pub fn background<RT: Runtime>(
         let sender = sender(cb.clone()).fuse();
         futures::pin_mut!(sender);
 
+        let path_mtu_prober = path_mtu_prober(cb.clone()).fuse();
+        futures::pin_mut!(path_mtu_prober);
+
         let closer = connection_terminated(cb).fuse();
         futures::pin_mut!(closer);
 
@@ -40,6 +44,7 @@ pub fn background<RT: Runtime>(
             r = acknowledger => r,
             r = retransmitter => r,
             r = sender => r,
+            r = path_mtu_prober => r,
             r = closer => r,
         };
         error!("Connection (fd {}) terminated: {:?}", fd, r);
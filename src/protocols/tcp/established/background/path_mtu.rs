@@ -0,0 +1,30 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::ControlBlock;
+use crate::{fail::Fail, runtime::Runtime};
+use futures::{
+    future::{self, Either},
+    FutureExt,
+};
+use std::rc::Rc;
+
+pub async fn path_mtu_prober<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+    loop {
+        // Shrinking the segments to fit the path MTU sets the deadline, after which they grow
+        // back to find out whether the path changed.
+        let (probe_deadline, probe_deadline_changed) = cb.get_path_mtu_probe_deadline();
+        futures::pin_mut!(probe_deadline_changed);
+
+        let probe_future = match probe_deadline {
+            Some(t) => Either::Left(cb.rt().wait_until(t).fuse()),
+            None => Either::Right(future::pending()),
+        };
+        futures::pin_mut!(probe_future);
+
+        futures::select_biased! {
+            _ = probe_deadline_changed => continue,
+            _ = probe_future => cb.probe_path_mtu(),
+        }
+    }
+}
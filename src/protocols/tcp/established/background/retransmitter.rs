@@ -18,33 +18,43 @@ async fn retransmit<RT: Runtime>(
     cause: RetransmitCause,
     cb: &Rc<ControlBlock<RT>>,
 ) -> Result<(), Fail> {
-    // Pop unack'ed segment.
-    let mut segment = match cb.pop_unacked_segment() {
-        Some(s) => s,
-        None => {
-            warn!("Retransmission with empty unacknowledged queue");
-            return Ok(());
+    // After a timeout, take the oldest unack'ed data, up to one MSS. A fast retransmission also
+    // fills the holes reported through selective acknowledgments, up to the congestion window.
+    let max_bytes = match cause {
+        RetransmitCause::TimeOut => {
+            cb.clear_sacks();
+            cb.get_mss()
+        }
+        RetransmitCause::FastRetransmit => {
+            let (cwnd, _) = cb.congestion_ctrl_watch_cwnd();
+            (cwnd as usize).max(cb.get_mss())
         }
     };
-
-    // TODO: Repacketization
+    let segments = cb.retransmit_unacked(max_bytes);
+    if segments.is_empty() {
+        warn!("Retransmission with empty unacknowledged queue");
+        return Ok(());
+    }
 
     // NOTE: Congestion Control Don't think we record a failure on Fast Retransmit, but can't find a definitive source.
     match cause {
         RetransmitCause::TimeOut => cb.rto_record_failure(),
-        RetransmitCause::FastRetransmit => (),
+        RetransmitCause::FastRetransmit => cb.count_fast_retransmit(),
     };
 
     // Our retransmission timer fired, so we need to resend a packet.
     let remote_link_addr = cb.arp().query(cb.get_remote().address()).await?;
 
-    // Unset the initial timestamp so we don't use this for RTT estimation.
-    segment.initial_tx.take();
-
-    let (seq_no, _) = cb.get_base_seq_no();
-    let mut header = cb.tcp_header();
-    header.seq_num = seq_no;
-    cb.emit(header, segment.bytes.clone(), remote_link_addr);
+    let num_segments = segments.len();
+    for (seq_no, bytes) in segments {
+        let mut header = cb.tcp_header();
+        header.seq_num = seq_no;
+        cb.emit(header, bytes, remote_link_addr);
+        cb.count_retransmit();
+    }
+    if num_segments > 1 {
+        cb.count_sack_retransmits(num_segments - 1);
+    }
 
     // Set new retransmit deadline
     let rto: Duration = cb.rto_estimate();
@@ -78,6 +88,10 @@ pub async fn retransmitter<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, F
             _ = rtx_deadline_changed => continue,
             _ = rtx_fast_retransmit_changed => continue,
             _ = rtx_future => {
+                if cb.retransmits_exhausted() {
+                    cb.time_out();
+                    return Err(Fail::Timeout {});
+                }
                 let (base_seq_no, _) = cb.get_base_seq_no();
                 cb.congestion_ctrl_on_rto(base_seq_no);
                 retransmit(RetransmitCause::TimeOut, &cb).await?;
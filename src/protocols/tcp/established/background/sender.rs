@@ -1,12 +1,22 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use super::super::{ctrlblk::ControlBlock, sender::UnackedSegment};
-use crate::{fail::Fail, runtime::Runtime};
+use super::super::ctrlblk::ControlBlock;
+use crate::{fail::Fail, protocols::tcp::SeqNumber, runtime::Runtime};
 use futures::FutureExt;
-use std::{cmp, num::Wrapping, rc::Rc, time::Duration};
+use std::{
+    cmp,
+    num::Wrapping,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// Longest wait between zero-window probes, which back off exponentially up to it.
+const MAX_PERSIST_TIMEOUT: Duration = Duration::from_secs(60);
 
 pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
+    // Wait before the next zero-window probe, backed off for as long as the window stays shut.
+    let mut persist_timeout: Option<Duration> = None;
     'top: loop {
         // First, check to see if there's any unsent data.
         let (unsent_seq, unsent_seq_changed) = cb.get_unsent_seq_no();
@@ -27,47 +37,57 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
         // remote side has available window.
         let (win_sz, win_sz_changed) = cb.get_window_size();
         futures::pin_mut!(win_sz_changed);
+        let (base_seq, base_seq_changed) = cb.get_base_seq_no();
+        futures::pin_mut!(base_seq_changed);
 
-        // If we don't have any window size at all, we need to transition to PERSIST state and
-        // repeatedly send window probes until window opens up.
+        // If the remote shut its window, enter the persist state (RFC 1122, section 4.2.2.17):
+        // once the window stayed shut for the persist timeout, probe it with a byte of data,
+        // sent again with exponential backoff until the remote acknowledges it or opens its
+        // window. Data in flight is left to the retransmitter.
         if win_sz == 0 {
+            if sent_seq != base_seq {
+                futures::select_biased! {
+                    _ = base_seq_changed => continue 'top,
+                    _ = win_sz_changed => continue 'top,
+                }
+            }
+            let mut timeout = persist_timeout.unwrap_or_else(|| cb.rto_estimate());
+            if !persist(&cb, base_seq, cb.rt().now() + timeout).await {
+                continue 'top;
+            }
+
             let remote_link_addr = cb.arp().query(cb.get_remote().address()).await?;
             let buf = cb
                 .pop_one_unsent_byte()
                 .unwrap_or_else(|| panic!("No unsent data? {}, {}", sent_seq, unsent_seq));
-
             cb.modify_sent_seq_no(|s| s + Wrapping(1));
-            let unacked_segment = UnackedSegment {
-                bytes: buf.clone(),
-                initial_tx: Some(cb.rt().now()),
-            };
-            cb.push_unacked_segment(unacked_segment);
-
-            let mut header = cb.tcp_header();
-            header.seq_num = sent_seq;
-            cb.emit(header, buf.clone(), remote_link_addr);
-
-            // Note that we loop here *forever*, exponentially backing off.
-            // TODO: Use the correct PERSIST state timer here.
-            let mut timeout = Duration::from_secs(1);
+            cb.push_unacked(&buf);
             loop {
-                futures::select_biased! {
-                    _ = win_sz_changed => continue 'top,
-                    _ = cb.rt().wait(timeout).fuse() => {
-                        timeout *= 2;
-                    }
-                }
-                // Retransmit our window probe.
                 let mut header = cb.tcp_header();
                 header.seq_num = sent_seq;
                 cb.emit(header, buf.clone(), remote_link_addr);
+                cb.count_window_probe();
+
+                timeout = cmp::min(timeout * 2, MAX_PERSIST_TIMEOUT);
+                persist_timeout = Some(timeout);
+                if !persist(&cb, base_seq, cb.rt().now() + timeout).await {
+                    // The window may have opened without room for the probe, which then needs
+                    // retransmitting like any data.
+                    let (ack_seq, _) = cb.get_base_seq_no();
+                    let (retransmit_deadline, _) = cb.get_retransmit_deadline();
+                    if ack_seq == base_seq && retransmit_deadline.is_none() {
+                        let rto = cb.rto_estimate();
+                        cb.set_retransmit_deadline(Some(cb.rt().now() + rto));
+                    }
+                    continue 'top;
+                }
+                // The probe goes out again, so its ACK can't time the round trip.
+                cb.mark_retransmitted(sent_seq + Wrapping(1));
             }
         }
+        persist_timeout = None;
 
         // The remote window is nonzero, but there still may not be room.
-        let (base_seq, base_seq_changed) = cb.get_base_seq_no();
-        futures::pin_mut!(base_seq_changed);
-
         // Before we get cwnd for the check, we prompt it to shrink it if the connection has been idle
         cb.congestion_ctrl_on_cwnd_check_before_send();
         let (cwnd, cwnd_changed) = cb.congestion_ctrl_watch_cwnd();
@@ -77,7 +97,8 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
         let (ltci, ltci_changed) = cb.congestion_ctrl_watch_limited_transmit_cwnd_increase();
         futures::pin_mut!(ltci_changed);
 
-        let effective_cwnd = cwnd + ltci;
+        // Forward RTO-recovery may let new data out past a window shrunk by a timeout.
+        let effective_cwnd = cmp::max(cwnd + ltci, cb.frto_window());
         let next_buf_size = cb.unsent_top_size().expect("no buffer in unsent queue");
 
         let Wrapping(sent_data) = sent_seq - base_seq;
@@ -94,9 +115,26 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
             }
         }
 
+        // Nagle's algorithm: while data is in flight, wait until a full segment's worth is unsent.
+        let (nodelay, nodelay_changed) = cb.get_nodelay();
+        futures::pin_mut!(nodelay_changed);
+        let Wrapping(unsent_data) = unsent_seq - sent_seq;
+        if !nodelay && sent_data > 0 && (unsent_data as usize) < cb.get_mss() {
+            futures::select_biased! {
+                _ = base_seq_changed => continue 'top,
+                _ = unsent_seq_changed => continue 'top,
+                _ = nodelay_changed => continue 'top,
+            }
+        }
+
+        // Pacing: leave room since the previous segment rather than send the window at once.
+        if let Some(deadline) = cb.pacing_deadline() {
+            cb.rt().wait_until(deadline).await;
+            continue 'top;
+        }
+
         // Past this point we have data to send and it's valid to send it!
 
-        // TODO: Nagle's algorithm
         // TODO: Silly window syndrome
         let remote_link_addr = cb.arp().query(cb.get_remote().address()).await?;
 
@@ -116,14 +154,11 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
 
         let mut header = cb.tcp_header();
         header.seq_num = sent_seq;
-        cb.emit(header, segment_data.clone(), remote_link_addr);
+        cb.emit_new_data(header, segment_data.clone(), remote_link_addr);
 
         cb.modify_sent_seq_no(|s| s + Wrapping(segment_data_len as u32));
-        let unacked_segment = UnackedSegment {
-            bytes: segment_data,
-            initial_tx: Some(cb.rt().now()),
-        };
-        cb.push_unacked_segment(unacked_segment);
+        cb.push_unacked(&segment_data);
+        cb.on_paced_send(segment_data_len);
 
         let (retransmit_deadline, _) = cb.get_retransmit_deadline();
         if retransmit_deadline.is_none() {
@@ -132,3 +167,27 @@ pub async fn sender<RT: Runtime>(cb: Rc<ControlBlock<RT>>) -> Result<!, Fail> {
         }
     }
 }
+
+/// Waits until `deadline` while the window of the remote stays shut and nothing past `base_seq`
+/// gets acknowledged, returning whether it got there. ACKs that leave the window shut don't cut
+/// the wait short.
+async fn persist<RT: Runtime>(
+    cb: &ControlBlock<RT>,
+    base_seq: SeqNumber,
+    deadline: Instant,
+) -> bool {
+    loop {
+        let (win_sz, win_sz_changed) = cb.get_window_size();
+        futures::pin_mut!(win_sz_changed);
+        let (ack_seq, ack_seq_changed) = cb.get_base_seq_no();
+        futures::pin_mut!(ack_seq_changed);
+        if win_sz != 0 || ack_seq != base_seq {
+            return false;
+        }
+        futures::select_biased! {
+            _ = win_sz_changed => continue,
+            _ = ack_seq_changed => continue,
+            _ = cb.rt().wait_until(deadline).fuse() => return true,
+        }
+    }
+}
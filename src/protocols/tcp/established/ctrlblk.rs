@@ -2,10 +2,10 @@
 // Licensed under the MIT license.
 
 use super::{
+    super::events::{CloseReason, CloseSummary, Readiness, StateTransition, Subscribers},
+    fsm::{self, Event},
     receiver::Receiver,
-    sender::congestion_ctrl,
     sender::Sender,
-    sender::{congestion_ctrl::CongestionControlConstructor, UnackedSegment},
 };
 
 use crate::{
@@ -19,23 +19,42 @@ use crate::{
             MacAddress,
         },
         ipv4,
-        ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
+        ipv4::datagram::{
+            Ipv4Header, Ipv4Protocol2, IPV4_ECN_ECT0, IPV4_ECN_NOT_ECT, IPV4_HEADER_SIZE,
+        },
         tcp::{
-            segment::{TcpHeader, TcpSegment},
-            SeqNumber,
+            constants::MIN_MTU,
+            loopback::Loopback,
+            migration::ConnectionState,
+            segment::{
+                self, SelectiveAcknowlegement, TcpHeader, TcpOptions2, TcpSegment,
+                MIN_TCP_HEADER_SIZE,
+            },
+            timestamp::{self, TimestampClock, PAWS_IDLE_TIMEOUT, TIMESTAMP_OPTION_SIZE},
+            seq_gt, seq_lt, Options as TcpOptions, ReapAction, SeqNumber, TcpInfo, Validation,
         },
     },
-    runtime::Runtime,
+    runtime::{Runtime, RuntimeBuf},
+    stats::{Counters, DropReason},
 };
 use std::{
+    cell::{Cell, RefCell},
+    net::Shutdown,
     num::Wrapping,
     rc::Rc,
-    task::{Context, Poll},
+    task::{Context, Poll, Waker},
     time::Duration,
     time::Instant,
 };
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Most blocks a selective acknowledgment carries, which is as many as fit in the options of a
+/// header.
+const MAX_SACK_BLOCKS: usize = 4;
+
+/// Most blocks a selective acknowledgment carries next to the timestamp option.
+const MAX_SACK_BLOCKS_WITH_TIMESTAMPS: usize = 3;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum State {
     Established,
     ActiveClose,
@@ -55,19 +74,78 @@ pub enum State {
 }
 
 /// Transmission control block for representing our TCP connection.
+///
+/// Fields read or written for every segment come first and stay inline, so that the receive and
+/// transmit paths touch as few cache lines as possible. Configuration and bookkeeping used a few
+/// times over the life of the connection sit behind a pointer in [ColdState].
 pub struct ControlBlock<RT: Runtime> {
     local: ipv4::Endpoint,
     remote: ipv4::Endpoint,
 
-    rt: Rc<RT>,
-    arp: Rc<arp::Peer<RT>>,
+    state: WatchedValue<State>,
+    /// Strictness of the checks of incoming segments.
+    validation: Cell<Validation>,
+    /// Whether the device computes the checksums of transmitted segments, copied from the
+    /// options so that emitting a segment doesn't reach for them.
+    tx_checksum_offload: bool,
+    /// Partial checksum of the pseudo-header of every segment we send, which only depends on the
+    /// addresses of the connection.
+    pseudo_header_sum: u32,
+    /// Bytes accepted from the remote.
+    bytes_in: Cell<u64>,
+    /// Bytes pushed by the application.
+    bytes_out: Cell<u64>,
+    /// When a segment last arrived or the application last pushed data.
+    last_activity: Cell<Instant>,
+    /// Whether both sides agreed on selective acknowledgments during the handshake.
+    selective_acks: bool,
+    /// Clock behind the timestamps we send, if both sides agreed on timestamps during the
+    /// handshake.
+    ts_clock: Option<TimestampClock>,
+    /// Latest timestamp value received from the remote, if it sent any.
+    ts_recent: Cell<Option<u32>>,
+    /// When `ts_recent` was last updated.
+    ts_recent_age: Cell<Instant>,
+    /// Whether both sides agreed on explicit congestion notification during the handshake.
+    ecn: bool,
+    /// Whether data arrived marked with congestion experienced since the remote last told us with
+    /// CWR that it shrank its window, which our ACKs echo until it does (RFC 3168, section 6.1.3).
+    ecn_echo: Cell<bool>,
 
     /// The sender end of our connection.
     sender: Sender<RT>,
     /// The receiver end of our connection.
     receiver: Receiver<RT>,
 
-    state: WatchedValue<State>,
+    rt: Rc<RT>,
+    arp: Rc<arp::Peer<RT>>,
+    loopback: Loopback<RT>,
+    counters: Counters,
+
+    cold: Box<ColdState<RT>>,
+}
+
+/// State of a connection that the data path doesn't need.
+struct ColdState<RT: Runtime> {
+    subscribers: Subscribers,
+    tcp_options: TcpOptions<RT>,
+    /// When the connection was established, as told by the runtime.
+    opened_at: Instant,
+    /// Segments retransmitted.
+    retransmits: Cell<u64>,
+    /// Whether the idle reaping policy tore the connection down.
+    reaped: Cell<bool>,
+    /// Whether the application shut down reading.
+    read_shutdown: Cell<bool>,
+    /// Why the connection was torn down, which operations on it fail with.
+    failure: RefCell<Option<Fail>>,
+    /// When to try segments of the MSS agreed on again, if the path MTU shrank them.
+    path_mtu_probe_deadline: WatchedValue<Option<Instant>>,
+    /// Whether a RST or SYN that may be a blind injection arrived, for the engine to answer with
+    /// a challenge ACK if the rate limit allows (RFC 5961).
+    challenge_ack: Cell<bool>,
+    /// What the application waits for the connection to be ready for, and how to wake it.
+    ready_waker: RefCell<Option<(Readiness, Waker)>>,
 }
 
 impl<RT: Runtime> ControlBlock<RT> {
@@ -76,48 +154,250 @@ impl<RT: Runtime> ControlBlock<RT> {
         remote: ipv4::Endpoint,
         rt: RT,
         arp: arp::Peer<RT>,
+        loopback: Loopback<RT>,
+        counters: Counters,
+        subscribers: Subscribers,
+        tcp_options: TcpOptions<RT>,
         receiver_seq_no: SeqNumber,
-        ack_delay_timeout: Duration,
         receiver_window_size: u32,
         receiver_window_scale: u32,
         sender_seq_no: SeqNumber,
         sender_window_size: u32,
         sender_window_scale: u8,
         sender_mss: usize,
-        sender_cc_constructor: CongestionControlConstructor<RT>,
-        sender_congestion_control_options: Option<congestion_ctrl::Options>,
+        selective_acks: bool,
+        ts_clock: Option<TimestampClock>,
+        ts_recent: Option<u32>,
+        ecn: bool,
     ) -> Self {
         let receiver = Receiver::new(
             receiver_seq_no,
-            ack_delay_timeout,
+            tcp_options.ack_delay_timeout(),
+            tcp_options.ack_frequency(),
+            tcp_options.advertised_mss(),
             receiver_window_size,
             receiver_window_scale,
+            tcp_options.max_out_of_order_segments(),
         );
+        if let Some(max_window_size) = tcp_options.receive_window_autotuning() {
+            receiver.set_window_tuning(max_window_size);
+        }
+        let now = rt.now();
+        // Segments carry no more than either side takes, leaving room for the timestamp option
+        // once agreed on.
+        let mss = sender_mss
+            .min(tcp_options.advertised_mss())
+            .saturating_sub(options_size(ts_clock))
+            .max(1);
         let sender = Sender::new(
             sender_seq_no,
             sender_window_size,
             sender_window_scale,
+            mss,
             sender_mss,
-            sender_cc_constructor,
-            sender_congestion_control_options,
+            (tcp_options.rto_lower_bound(), tcp_options.rto_upper_bound()),
+            tcp_options.max_unsent_segments(),
+            tcp_options.send_buffer_size(),
+            tcp_options.congestion_ctrl_type(),
+            tcp_options.congestion_ctrl_options(),
+            tcp_options.nodelay(),
+            tcp_options.pacing(),
+            tcp_options.frto(),
+            tcp_options.cwnd_validation(),
+            now,
         );
+        let validation = tcp_options.validation();
+        let tx_checksum_offload = tcp_options.tx_checksum_offload();
+        let cold = Box::new(ColdState {
+            subscribers,
+            tcp_options,
+            opened_at: now,
+            retransmits: Cell::new(0),
+            reaped: Cell::new(false),
+            read_shutdown: Cell::new(false),
+            failure: RefCell::new(None),
+            path_mtu_probe_deadline: WatchedValue::new(None),
+            challenge_ack: Cell::new(false),
+            ready_waker: RefCell::new(None),
+        });
         Self {
             local,
             remote,
+            state: WatchedValue::new(State::Established),
+            validation: Cell::new(validation),
+            tx_checksum_offload,
+            pseudo_header_sum: segment::pseudo_header_sum(local.addr, remote.addr),
+            bytes_in: Cell::new(0),
+            bytes_out: Cell::new(0),
+            last_activity: Cell::new(now),
+            selective_acks,
+            ts_clock,
+            ts_recent: Cell::new(ts_recent),
+            ts_recent_age: Cell::new(now),
+            ecn,
+            ecn_echo: Cell::new(false),
+            sender,
+            receiver,
             rt: Rc::new(rt),
             arp: Rc::new(arp),
-            sender: sender,
-            receiver: receiver,
-            state: WatchedValue::new(State::Established),
+            loopback,
+            counters,
+            cold,
+        }
+    }
+
+    /// Recreates a connection from a snapshot taken with [ControlBlock::export_state].
+    pub fn import_state(
+        state: ConnectionState,
+        rt: RT,
+        arp: arp::Peer<RT>,
+        loopback: Loopback<RT>,
+        counters: Counters,
+        subscribers: Subscribers,
+        tcp_options: TcpOptions<RT>,
+    ) -> Self {
+        let now = rt.now();
+        let cb = Self::new(
+            state.local,
+            state.remote,
+            rt,
+            arp,
+            loopback,
+            counters,
+            subscribers,
+            tcp_options,
+            Wrapping(state.receiver_base_seq_no),
+            state.receiver_window_size,
+            state.receiver_window_scale,
+            Wrapping(state.sender_base_seq_no),
+            state.sender_window_size,
+            state.sender_window_scale,
+            state.sender_mss,
+            state.selective_acks,
+            state.ts_value.map(|v| TimestampClock::resume(v, now)),
+            state.ts_recent,
+            state.ecn,
+        );
+        let to_bufs = |v: Vec<Vec<u8>>| -> Vec<RT::Buf> {
+            v.iter().map(|b| RT::Buf::from_slice(b)).collect()
+        };
+        cb.sender.import_queues(
+            to_bufs(state.sender_unacked),
+            to_bufs(state.sender_unsent),
+            now,
+        );
+        cb.receiver.import_queue(
+            Wrapping(state.receiver_ack_seq_no),
+            to_bufs(state.receiver_queue),
+            now,
+        );
+        cb
+    }
+
+    /// Queues the data carried by the SYN of a TCP Fast Open, which the SYN+ACK acknowledged.
+    pub fn receive_syn_data(&self, data: RT::Buf) {
+        let len = data.len();
+        let (recv_seq_no, _) = self.receiver.get_recv_seq_no();
+        let ack_seq_no = recv_seq_no + Wrapping(len as u32);
+        self.receiver.import_queue(ack_seq_no, vec![data], self.rt.now());
+        self.bytes_in.set(self.bytes_in.get() + len as u64);
+    }
+
+    /// Takes a snapshot of this connection, which must still be established.
+    pub fn export_state(&self) -> Result<ConnectionState, Fail> {
+        if self.state.get() != State::Established {
+            return Err(Fail::Unsupported {
+                details: "Only established connections can be exported",
+            });
         }
+        let to_vecs = |v: Vec<RT::Buf>| -> Vec<Vec<u8>> { v.iter().map(|b| b.to_vec()).collect() };
+        let (sender_base_seq_no, _) = self.sender.get_base_seq_no();
+        let (sender_unacked, sender_unsent) = self.sender.export_queues();
+        let (sender_window_size, _) = self.sender.get_window_size();
+        let (receiver_base_seq_no, receiver_queue) = self.receiver.export_queue();
+        let (receiver_ack_seq_no, _) = self.receiver.get_ack_seq_no();
+        let now = self.rt.now();
+        Ok(ConnectionState {
+            local: self.local,
+            remote: self.remote,
+            sender_base_seq_no: sender_base_seq_no.0,
+            sender_unacked: to_vecs(sender_unacked),
+            sender_unsent: to_vecs(sender_unsent),
+            sender_window_size,
+            sender_window_scale: self.sender.get_window_scale(),
+            sender_mss: self.sender.remote_mss(),
+            receiver_base_seq_no: receiver_base_seq_no.0,
+            receiver_ack_seq_no: receiver_ack_seq_no.0,
+            receiver_queue: to_vecs(receiver_queue),
+            receiver_window_size: self.receiver.get_max_window_size(),
+            receiver_window_scale: self.receiver.get_window_scale(),
+            selective_acks: self.selective_acks,
+            ts_value: self.ts_clock.map(|c| c.value(now)),
+            ts_recent: self.ts_recent.get(),
+            ecn: self.ecn,
+        })
+    }
+
+    /// Applies the subset of `options` that can safely change on a live connection: buffer
+    /// limits and timers. The congestion control algorithm, window and MSS negotiated during the
+    /// handshake are left untouched.
+    pub fn update_options(&self, options: &TcpOptions<RT>) {
+        self.receiver
+            .set_ack_delay_timeout(options.ack_delay_timeout());
+        self.receiver.set_ack_frequency(options.ack_frequency());
+        self.receiver
+            .set_max_out_of_order(options.max_out_of_order_segments());
+        self.sender
+            .set_rto_bounds(options.rto_lower_bound(), options.rto_upper_bound());
+        self.sender
+            .set_max_unsent_segments(options.max_unsent_segments());
+        self.sender.set_pacing(options.pacing());
+        self.sender.set_frto(options.frto());
+        self.sender
+            .set_cwnd_validation(options.cwnd_validation());
+        self.validation.set(options.validation());
     }
 
     pub fn get_state(&self) -> (State, WatchFuture<State>) {
         self.state.watch()
     }
 
-    pub fn set_state(&self, new_value: State) {
-        self.state.set(new_value)
+    /// Returns the bytes not yet acknowledged by the remote and the bytes not yet consumed by the
+    /// application.
+    pub fn queue_depths(&self) -> (usize, usize) {
+        let (base_seq_no, _) = self.sender.get_base_seq_no();
+        let (unsent_seq_no, _) = self.sender.get_unsent_seq_no();
+        let send_queue = (unsent_seq_no - base_seq_no).0 as usize;
+        (send_queue, self.receiver.queued_bytes())
+    }
+
+    /// Takes a snapshot of the internals of the connection.
+    pub fn info(&self) -> TcpInfo {
+        let (base_seq_no, _) = self.sender.get_base_seq_no();
+        let (sent_seq_no, _) = self.sender.get_sent_seq_no();
+        let (unsent_seq_no, _) = self.sender.get_unsent_seq_no();
+        let (send_window, _) = self.sender.get_window_size();
+        TcpInfo {
+            local: self.local,
+            remote: self.remote,
+            state: self.state.get(),
+            mss: self.sender.get_mss(),
+            cwnd: self.sender.cwnd(),
+            ssthresh: self.sender.ssthresh(),
+            send_window,
+            receive_window: self.receiver.window_size(),
+            rto: self.sender.current_rto(),
+            srtt: self.sender.srtt(),
+            rttvar: self.sender.rttvar(),
+            retransmits: self.cold.retransmits.get(),
+            retransmit_timeouts: self.sender.retransmit_timeouts(),
+            bytes_in_flight: (sent_seq_no - base_seq_no).0 as usize,
+            unsent_bytes: (unsent_seq_no - sent_seq_no).0 as usize,
+            recv_queue: self.receiver.queued_bytes(),
+            bytes_in: self.bytes_in.get(),
+            bytes_out: self.bytes_out.get(),
+            pacing_rate: self.sender.pacing_rate(),
+        }
     }
 
     pub fn get_local(&self) -> ipv4::Endpoint {
@@ -137,13 +417,66 @@ impl<RT: Runtime> ControlBlock<RT> {
     }
 
     pub fn send(&self, buf: RT::Buf) -> Result<(), Fail> {
-        if self.state.get() != State::Established {
+        if let Some(e) = self.failure() {
+            return Err(e);
+        }
+        if !fsm::accepts_data(self.state.get()) {
+            return Err(Fail::Ignored {
+                details: "Sender closed",
+            });
+        }
+
+        let len = buf.len() as u64;
+        self.sender.send(buf, self)?;
+        self.bytes_out.set(self.bytes_out.get() + len);
+        self.last_activity.set(self.rt.now());
+        Ok(())
+    }
+
+    /// Returns how many bytes [ControlBlock::send] would take now.
+    pub fn send_room(&self) -> Result<usize, Fail> {
+        if let Some(e) = self.failure() {
+            return Err(e);
+        }
+        if !fsm::accepts_data(self.state.get()) {
+            return Err(Fail::Ignored {
+                details: "Sender closed",
+            });
+        }
+        Ok(self.sender.send_room())
+    }
+
+    /// Sends `bufs` one after the other, as if pushed as one buffer.
+    pub fn send_vectored(&self, bufs: &[RT::Buf]) -> Result<(), Fail> {
+        if let Some(e) = self.failure() {
+            return Err(e);
+        }
+        if !fsm::accepts_data(self.state.get()) {
             return Err(Fail::Ignored {
                 details: "Sender closed",
             });
         }
 
-        self.sender.send(buf, self)
+        let len: usize = bufs.iter().map(|b| b.len()).sum();
+        self.sender.send_vectored(bufs)?;
+        self.bytes_out.set(self.bytes_out.get() + len as u64);
+        self.last_activity.set(self.rt.now());
+        Ok(())
+    }
+
+    /// Returns the latest timestamp value received from the remote, if it sent any.
+    pub fn ts_recent(&self) -> Option<u32> {
+        self.ts_recent.get()
+    }
+
+    /// Returns the clock of our timestamps, if the connection uses them.
+    pub fn ts_clock(&self) -> Option<TimestampClock> {
+        self.ts_clock
+    }
+
+    /// Returns when a segment last arrived or the application last pushed data.
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity.get()
     }
 
     pub fn congestion_ctrl_watch_retransmit_now_flag(&self) -> (bool, WatchFuture<bool>) {
@@ -163,7 +496,8 @@ impl<RT: Runtime> ControlBlock<RT> {
     }
 
     pub fn congestion_ctrl_on_cwnd_check_before_send(&self) {
-        self.sender.congestion_ctrl_on_cwnd_check_before_send()
+        self.sender
+            .congestion_ctrl_on_cwnd_check_before_send(self.rt.now())
     }
 
     pub fn congestion_ctrl_watch_cwnd(&self) -> (u32, WatchFuture<u32>) {
@@ -219,12 +553,25 @@ impl<RT: Runtime> ControlBlock<RT> {
         self.sender.set_retransmit_deadline(when);
     }
 
-    pub fn pop_unacked_segment(&self) -> Option<UnackedSegment<RT>> {
-        self.sender.pop_unacked_segment()
+    /// Returns segments of unacknowledged data to retransmit, up to `max_bytes` in all, with
+    /// their sequence numbers.
+    pub fn retransmit_unacked(&self, max_bytes: usize) -> Vec<(SeqNumber, RT::Buf)> {
+        self.sender
+            .retransmit_unacked(self.sender.get_mss(), max_bytes)
+    }
+
+    /// Forgets the data the remote reported holding in selective acknowledgments.
+    pub fn clear_sacks(&self) {
+        self.sender.clear_sacks()
+    }
+
+    pub fn push_unacked(&self, bytes: &[u8]) {
+        self.sender.push_unacked(bytes, self.rt.now())
     }
 
-    pub fn push_unacked_segment(&self, segment: UnackedSegment<RT>) {
-        self.sender.push_unacked_segment(segment)
+    /// Records that the data up to `end` went out again, which rules out timing its ACKs.
+    pub fn mark_retransmitted(&self, end: SeqNumber) {
+        self.sender.mark_retransmitted(end)
     }
 
     pub fn rto_estimate(&self) -> Duration {
@@ -235,94 +582,701 @@ impl<RT: Runtime> ControlBlock<RT> {
         self.sender.rto_record_failure()
     }
 
+    /// Returns whether the remote left data unacknowledged through as many retransmission
+    /// timeouts as the options allow.
+    pub fn retransmits_exhausted(&self) -> bool {
+        self.sender.retransmit_timeouts() >= self.cold.tcp_options.max_retransmits()
+    }
+
     pub fn unsent_top_size(&self) -> Option<usize> {
         self.sender.top_size_unsent()
     }
 
+    /// Takes the next segment off the unsent queue, which may make room for the application to
+    /// push more.
     pub fn pop_unsent_segment(&self, max_bytes: usize) -> Option<RT::Buf> {
-        self.sender.pop_unsent(max_bytes)
+        let buf = self.sender.pop_unsent(max_bytes);
+        self.notify_ready();
+        buf
+    }
+
+    /// Returns how much data forward RTO-recovery lets be in flight beyond the congestion window.
+    pub fn frto_window(&self) -> u32 {
+        self.sender.frto_window()
+    }
+
+    /// Returns when the next segment of new data may go out, if pacing holds it back until then.
+    pub fn pacing_deadline(&self) -> Option<Instant> {
+        self.sender.pacing_deadline(self.rt.now())
+    }
+
+    /// Spaces the next segment of new data out from one of `len` bytes just sent.
+    pub fn on_paced_send(&self, len: usize) {
+        self.sender.on_paced_send(len, self.rt.now())
     }
 
     pub fn pop_one_unsent_byte(&self) -> Option<RT::Buf> {
-        self.sender.pop_one_unsent_byte()
+        let buf = self.sender.pop_one_unsent_byte();
+        self.notify_ready();
+        buf
     }
 
     pub fn poll_recv(&self, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
-        if self.state.get() != State::Established {
-            return Poll::Ready(Err(Fail::ResourceNotFound {
-                details: "Receiver closed",
+        self.poll_recv_max(ctx, usize::MAX)
+    }
+
+    /// Like [ControlBlock::poll_recv], but takes no more than `max_len` bytes off the receive
+    /// queue, splitting the segment at its head if longer.
+    pub fn poll_recv_max(&self, ctx: &mut Context, max_len: usize) -> Poll<Result<RT::Buf, Fail>> {
+        if max_len == 0 {
+            return Poll::Ready(Err(Fail::Invalid {
+                details: "Zero maximum length",
+            }));
+        }
+        if let Some(e) = self.failure() {
+            return Poll::Ready(Err(e));
+        }
+        if !self.receiving() {
+            return Poll::Ready(Err(self.receiver_closed()));
+        }
+        self.receiver.poll_recv_max(ctx, max_len)
+    }
+
+    /// Like [ControlBlock::poll_recv], but leaves the data on the receive queue.
+    pub fn poll_peek(&self, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
+        if let Some(e) = self.failure() {
+            return Poll::Ready(Err(e));
+        }
+        if !self.receiving() {
+            return Poll::Ready(Err(self.receiver_closed()));
+        }
+        self.receiver.poll_peek(ctx)
+    }
+
+    /// Completes once `len` bytes can be taken off the receive queue at once, or with what is
+    /// left once the connection is no longer established.
+    pub fn poll_recv_exact(&self, ctx: &mut Context, len: usize) -> Poll<Result<RT::Buf, Fail>> {
+        if len > self.receiver.get_max_window_size() as usize {
+            return Poll::Ready(Err(Fail::Invalid {
+                details: "Length beyond the receive window",
             }));
         }
-        self.receiver.poll_recv(ctx)
+        if let Some(e) = self.failure() {
+            return Poll::Ready(Err(e));
+        }
+        let eof = !self.receiving();
+        if eof && self.receiver.queued_bytes() == 0 {
+            return Poll::Ready(Err(self.receiver_closed()));
+        }
+        self.receiver.poll_recv_exact(ctx, len, eof)
     }
 
-    pub fn receive(&self, header: &TcpHeader, data: RT::Buf) {
-        debug!(
+    /// Processes a segment from the remote, which a router on the way marked with congestion
+    /// experienced if `ce` is set.
+    pub fn receive(&self, header: &TcpHeader, data: RT::Buf, ce: bool) {
+        datapath!(
+            Debug,
             "{:?} Connection Receiving {} bytes + {:?}",
             self.state.get(),
             data.len(),
             header
         );
         let now = self.rt.now();
-        if header.syn {
-            warn!("Ignoring duplicate SYN on established connection");
+        self.last_activity.set(now);
+        if !self.check_timestamp(header, now) {
+            warn!("Discarding segment with an old timestamp {:?}", header);
+            return;
         }
-        if header.rst {
-            self.state.set(State::Reset);
+        if !ce && self.is_predicted(header, data.len()) {
+            self.update_ts_recent(header, now);
+            self.receive_predicted(header, data, now);
+            return;
+        }
+        // RFC 5961, section 4.2: a SYN, whatever its sequence number, only gets a challenge ACK.
+        // A remote that lost its state answers it with a RST at the next expected sequence
+        // number.
+        if header.syn && !header.rst {
+            warn!("Challenging SYN on established connection {:?}", header);
+            self.cold.challenge_ack.set(true);
+            return;
         }
-        if header.fin && header.ack {
-            match self.state.get() {
-                State::FinWait1 => self.state.set(State::TimeWait1),
-                s => panic!("bad peer state {:?}", s),
+        if !self.validate(header, data.len()) {
+            warn!("Discarding invalid segment {:?}", header);
+            self.counters.count_drop(DropReason::OutOfWindow);
+            return;
+        }
+        self.update_ts_recent(header, now);
+        let state = self.state.get();
+        let (sent_seq_no, _) = self.sender.get_sent_seq_no();
+        // Once our FIN is out, the ACK of it takes up a sequence number the sender doesn't count.
+        if header.ack
+            && !header.rst
+            && fsm::awaits_acks(state)
+            && !seq_gt(header.ack_num, sent_seq_no)
+        {
+            let rtt = self.timestamp_rtt(header, now);
+            let duplicate = self.is_duplicate_ack(header, data.len());
+            if duplicate {
+                self.counters.update(|s| s.tcp.dup_acks += 1);
             }
-        } else {
-            if header.fin {
-                match self.state.get() {
-                    State::FinWait1 => self.state.set(State::Closing1),
-                    State::FinWait2 => self.state.set(State::FinWait3),
-                    State::Established => self.state.set(State::PassiveClose),
-                    s => panic!("bad peer state {:?}", s),
-                }
+            if self.sender.frto_on_ack(header.ack_num, duplicate) {
+                self.counters.update(|s| s.tcp.frto_spurious += 1);
+            }
+            if let Err(e) = self.sender.remote_ack(header.ack_num, duplicate, now, rtt) {
+                warn!("Ignoring remote ack for {:?}: {:?}", header, e);
             }
-            if header.ack {
-                match self.state.get() {
-                    State::FinWait1 => self.state.set(State::FinWait2),
-                    State::Closing2 => self.state.set(State::TimeWait2),
-                    State::Established => {
-                        if let Err(e) = self.sender.remote_ack(header.ack_num, now) {
-                            warn!("Ignoring remote ack for {:?}: {:?}", header, e);
-                        }
+            if self.ecn && header.ece && self.sender.remote_ecn_echo(header.ack_num) {
+                self.counters.update(|s| s.tcp.ecn_cwnd_reductions += 1);
+            }
+            if self.selective_acks {
+                // RFC 2883, section 4: a first block below the cumulative ACK, or within the
+                // second block, reports data received twice rather than held out of order.
+                let sacks = header.selective_acks();
+                let dsack = match sacks {
+                    [first, rest @ ..] => {
+                        !seq_gt(first.end, header.ack_num)
+                            || rest.first().map_or(false, |second| {
+                                !seq_lt(first.begin, second.begin)
+                                    && !seq_gt(first.end, second.end)
+                            })
+                    }
+                    [] => false,
+                };
+                if dsack {
+                    self.counters.update(|s| s.tcp.dsacks_received += 1);
+                    if self.sender.remote_dsack(sacks[0].begin, sacks[0].end) {
+                        self.counters.update(|s| s.tcp.dsack_undos += 1);
+                    }
+                }
+                for sack in &sacks[dsack as usize..] {
+                    if let Err(e) = self.sender.remote_sack(sack.begin, sack.end) {
+                        warn!("Ignoring SACK block {:?}: {:?}", sack, e);
                     }
-                    State::LastAck => self.state.set(State::Closed),
-                    s => panic!("bad peer state {:?}", s),
                 }
             }
         }
-        if self.state.get() == State::Established {
+        let event = Event::Segment {
+            rst: header.rst,
+            fin: header.fin,
+            ack: header.ack,
+        };
+        if let Err(e) = self.apply(event) {
+            warn!("Ignoring {:?} in state {:?}: {:?}", header, state, e);
+        }
+        if header.rst && !fsm::is_terminal(state) {
+            self.fail(Fail::ConnectionReset {});
+            return;
+        }
+        if self.ecn {
+            // A CE mark on the segment carrying CWR is of congestion after the window shrank.
+            if header.cwr {
+                self.ecn_echo.set(false);
+            }
+            if ce {
+                self.ecn_echo.set(true);
+                self.counters.update(|s| s.tcp.ecn_ce_marks += 1);
+            }
+        }
+        if fsm::awaits_acks(self.state.get()) {
             if let Err(e) = self.sender.update_remote_window(header.window_size as u16) {
                 warn!("Invalid window size update for {:?}: {:?}", header, e);
             }
         }
         if !data.is_empty() {
-            if self.state.get() != State::Established {
+            if !fsm::receives_data(self.state.get()) {
                 warn!("Receiver closed");
             }
-            if let Err(e) = self.receiver.receive_data(header.seq_num, data, now) {
-                warn!("Ignoring remote data for {:?}: {:?}", header, e);
+            let len = data.len() as u64;
+            match self.receiver.receive_data(header.seq_num, data, now) {
+                // Reordered segments are held until the gap before them fills.
+                Ok(())
+                | Err(Fail::Ignored {
+                    details: "Out of order segment (reordered)",
+                }) => {
+                    self.bytes_in.set(self.bytes_in.get() + len);
+                    self.tune_receive_window(header, now);
+                    if self.cold.read_shutdown.get() {
+                        self.receiver.discard_queued();
+                    }
+                }
+                Err(e) => {
+                    warn!("Ignoring remote data for {:?}: {:?}", header, e);
+                    self.counters.count_drop(DropReason::OutOfWindow);
+                }
             }
         }
     }
 
     pub fn close(&self) -> Result<(), Fail> {
-        match self.state.get() {
-            State::Established => self.state.set(State::ActiveClose),
-            State::CloseWait1 => self.state.set(State::CloseWait2),
-            s => panic!("bad state {:?}", s),
+        self.apply(Event::Close)
+    }
+
+    /// Shuts down reading, writing, or both. Shutting down writing sends a FIN once the data
+    /// pushed so far is out, while data keeps arriving until the remote sends its own. Shutting
+    /// down reading drops the data waiting for the application and what arrives later, which is
+    /// still acknowledged.
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Fail> {
+        if how != Shutdown::Read {
+            self.close()?;
+        }
+        if how != Shutdown::Write {
+            self.cold.read_shutdown.set(true);
+            self.receiver.discard_queued();
         }
+        Ok(())
+    }
+
+    /// Tears the connection down on `e`, dropping the data waiting for the application and
+    /// failing the operations pending or yet to come with `e`.
+    pub fn fail(&self, e: Fail) {
+        *self.cold.failure.borrow_mut() = Some(e);
+        self.receiver.discard_queued();
+        self.notify_ready();
+    }
+
+    /// Returns what the connection is ready for. A connection torn down is ready for anything,
+    /// as operations fail at once.
+    pub fn readiness(&self) -> Readiness {
+        let failed = self.failure().is_some();
+        Readiness {
+            readable: failed || self.receiver.queued_bytes() > 0 || !self.receiving(),
+            writable: failed
+                || !fsm::accepts_data(self.state.get())
+                || self.sender.has_send_room(),
+        }
+    }
+
+    /// Completes with what the connection is ready for out of `interest`, or else has the waker
+    /// of `ctx` woken once it may be. A connection keeps the latest such registration only,
+    /// which lets the application wait on many connections through one waker rather than an
+    /// operation pending on each.
+    pub fn poll_ready(&self, ctx: &mut Context, interest: Readiness) -> Poll<Readiness> {
+        let ready = self.readiness().intersection(interest);
+        if !ready.is_empty() {
+            self.cold.ready_waker.borrow_mut().take();
+            return Poll::Ready(ready);
+        }
+        *self.cold.ready_waker.borrow_mut() = Some((interest, ctx.waker().clone()));
+        Poll::Pending
+    }
+
+    /// Wakes the application waiting through [ControlBlock::poll_ready] if the connection became
+    /// ready for what it waits for.
+    pub fn notify_ready(&self) {
+        let interest = match *self.cold.ready_waker.borrow() {
+            Some((interest, _)) => interest,
+            None => return,
+        };
+        if self.readiness().intersection(interest).is_empty() {
+            return;
+        }
+        if let Some((_, w)) = self.cold.ready_waker.borrow_mut().take() {
+            w.wake()
+        }
+    }
 
+    /// Tears the connection down once the remote stopped acknowledging data, failing operations
+    /// with [Fail::Timeout]. No RST goes out, as the remote is unlikely to get it.
+    pub fn time_out(&self) {
+        self.fail(Fail::Timeout {});
+        if let Err(e) = self.apply(Event::Abort) {
+            warn!("Timing out in state {:?}: {:?}", self.state.get(), e);
+        }
+        self.counters.update(|s| s.tcp.timeout_aborts += 1);
+    }
+
+    /// Returns why the connection was torn down, if it was.
+    pub fn failure(&self) -> Option<Fail> {
+        self.cold.failure.borrow().clone()
+    }
+
+    /// Returns whether the application may still take data off the receive queue.
+    fn receiving(&self) -> bool {
+        !self.cold.read_shutdown.get() && fsm::receives_data(self.state.get())
+    }
+
+    /// Returns what reads fail with once the application may no longer take data off the receive
+    /// queue: [Fail::ResourceNotFound], the end of the stream, once the remote sent its FIN or
+    /// reading got shut down, or else [Fail::ConnectionAborted] for a connection torn down
+    /// without a FIN.
+    fn receiver_closed(&self) -> Fail {
+        if self.state.get() == State::Reset && !self.cold.read_shutdown.get() {
+            return Fail::ConnectionAborted {};
+        }
+        Fail::ResourceNotFound {
+            details: "Receiver closed",
+        }
+    }
+
+    /// Tears the connection down at once, sending a RST if the link address of the remote is
+    /// known.
+    pub fn abort(&self) -> Result<(), Fail> {
+        let (sent_seq_no, _) = self.sender.get_sent_seq_no();
+        self.apply(Event::Abort)?;
+        let remote_link_addr = match self.arp.try_query(self.remote.addr) {
+            Some(addr) => addr,
+            None => {
+                warn!("Not sending RST to {:?}, not in ARP cache", self.remote);
+                return Ok(());
+            }
+        };
+        let mut header = TcpHeader::new(self.local.port, self.remote.port);
+        header.seq_num = sent_seq_no;
+        header.rst = true;
+        self.emit(header, RT::Buf::empty(), remote_link_addr);
+        self.counters.update(|s| s.tcp.out_rsts += 1);
+        Ok(())
+    }
+
+    /// Aborts the connection on behalf of the application, as for `SO_LINGER` with a zero
+    /// timeout: the data queued either way is dropped, operations fail with
+    /// [Fail::ConnectionAborted] and a RST goes out.
+    pub fn hard_close(&self) -> Result<(), Fail> {
+        self.fail(Fail::ConnectionAborted {});
+        self.sender.discard_queued();
+        self.abort()
+    }
+
+    /// Closes or aborts the connection on behalf of the idle reaping policy, which its teardown
+    /// summary reports as the reason.
+    pub fn reap(&self, action: ReapAction) -> Result<(), Fail> {
+        self.cold.reaped.set(true);
+        let r = match action {
+            ReapAction::Close => self.close(),
+            ReapAction::Abort => self.abort(),
+        };
+        if r.is_err() {
+            self.cold.reaped.set(false);
+        }
+        r
+    }
+
+    /// Protection against wrapped sequence numbers (PAWS, RFC 7323, section 5): returns whether a
+    /// segment carries no timestamp older than the latest one of the remote. Older ones get
+    /// counted and acknowledged, as they are old duplicates.
+    fn check_timestamp(&self, header: &TcpHeader, now: Instant) -> bool {
+        let (ts, recent) = match (
+            self.ts_clock,
+            header.sender_timestamp(),
+            self.ts_recent.get(),
+        ) {
+            (Some(_), Some(ts), Some(recent)) => (ts, recent),
+            _ => return true,
+        };
+        // The latest timestamp of a connection idle for long enough can't be compared with.
+        let idle = now.saturating_duration_since(self.ts_recent_age.get()) > PAWS_IDLE_TIMEOUT;
+        if header.rst || idle || !timestamp::before(ts, recent) {
+            return true;
+        }
+        self.counters.update(|s| s.tcp.paws_rejected += 1);
+        self.receiver.set_ack_deadline(Some(now));
+        false
+    }
+
+    /// Records the timestamp of an acceptable segment as the latest one of the remote if the
+    /// segment starts at or before the last acknowledgment we sent (RFC 7323, section 4.3).
+    /// Without timestamps agreed on, any timestamp received is recorded as is.
+    fn update_ts_recent(&self, header: &TcpHeader, now: Instant) {
+        let ts = match header.sender_timestamp() {
+            Some(ts) => ts,
+            None => return,
+        };
+        let (last_ack, _) = self.receiver.get_ack_seq_no();
+        if self.ts_clock.is_none() || !seq_lt(last_ack, header.seq_num) {
+            self.ts_recent.set(Some(ts));
+            self.ts_recent_age.set(now);
+        }
+    }
+
+    /// Returns the round-trip time told by the timestamp a segment echoes, if timestamps are
+    /// agreed on.
+    fn timestamp_rtt(&self, header: &TcpHeader, now: Instant) -> Option<Duration> {
+        let clock = self.ts_clock?;
+        clock.elapsed(header.echo_timestamp()?, now)
+    }
+
+    /// Lets auto-tuning grow the receive window on data arriving at `now`, once a round trip
+    /// is known: from the timestamp the segment echoes, or else from our own samples.
+    fn tune_receive_window(&self, header: &TcpHeader, now: Instant) {
+        let rtt = match self.timestamp_rtt(header, now).or_else(|| self.sender.srtt()) {
+            Some(rtt) => rtt,
+            None => return,
+        };
+        if self.receiver.tune_window(rtt, now) {
+            self.counters.update(|s| s.tcp.receive_window_grows += 1);
+        }
+    }
+
+    /// Returns whether a segment carrying `len` bytes is a duplicate ACK (RFC 5681, section 2):
+    /// one acknowledging no new data while some is outstanding, with no data, SYN or FIN of its
+    /// own and the window advertised before. Window updates and data flowing the other way are
+    /// no sign of loss.
+    fn is_duplicate_ack(&self, header: &TcpHeader, len: usize) -> bool {
+        let (snd_una, _) = self.sender.get_base_seq_no();
+        let (snd_nxt, _) = self.sender.get_sent_seq_no();
+        let (snd_wnd, _) = self.sender.get_window_size();
+        let window_size = (header.window_size as u32)
+            .checked_shl(self.sender.get_window_scale() as u32)
+            .unwrap_or(0);
+        len == 0
+            && !header.syn
+            && !header.fin
+            && header.ack_num == snd_una
+            && snd_una != snd_nxt
+            && window_size == snd_wnd
+    }
+
+    /// Header prediction, after the BSD `tcp_input`: returns whether a segment carrying `len`
+    /// bytes is the common case of an established connection, either a pure ACK of new data or
+    /// the next expected data while nothing is in flight and reading isn't shut down, with no
+    /// flags, options or window change to look at. Such a segment needs neither validation nor
+    /// the state machine. The timestamp option, if agreed on, is the one option such a segment may
+    /// carry.
+    fn is_predicted(&self, header: &TcpHeader, len: usize) -> bool {
+        let options_ok = match header.num_options {
+            0 => true,
+            1 => {
+                self.ts_clock.is_some()
+                    && matches!(header.option_list[0], TcpOptions2::Timestamp { .. })
+            }
+            _ => false,
+        };
+        if self.state.get() != State::Established
+            || !header.ack
+            || header.syn
+            || header.fin
+            || header.rst
+            || header.urg
+            || header.ece
+            || header.cwr
+            || !options_ok
+        {
+            return false;
+        }
+        let (rcv_nxt, _) = self.receiver.get_recv_seq_no();
+        let (snd_wnd, _) = self.sender.get_window_size();
+        let window_size = (header.window_size as u32)
+            .checked_shl(self.sender.get_window_scale() as u32)
+            .unwrap_or(0);
+        if header.seq_num != rcv_nxt || window_size != snd_wnd {
+            return false;
+        }
+        let (snd_una, _) = self.sender.get_base_seq_no();
+        let (snd_nxt, _) = self.sender.get_sent_seq_no();
+        if len == 0 {
+            let acked = header.ack_num - snd_una;
+            acked != Wrapping(0) && acked <= snd_nxt - snd_una
+        } else {
+            header.ack_num == snd_una
+                && snd_una == snd_nxt
+                && !self.cold.read_shutdown.get()
+                && !self.receiver.has_out_of_order()
+                && len <= self.receiver.window_size() as usize
+        }
+    }
+
+    /// Processes a segment for which [ControlBlock::is_predicted] holds.
+    fn receive_predicted(&self, header: &TcpHeader, data: RT::Buf, now: Instant) {
+        if data.is_empty() {
+            let rtt = self.timestamp_rtt(header, now);
+            if self.sender.frto_on_ack(header.ack_num, false) {
+                self.counters.update(|s| s.tcp.frto_spurious += 1);
+            }
+            if let Err(e) = self.sender.remote_ack(header.ack_num, false, now, rtt) {
+                warn!("Ignoring remote ack for {:?}: {:?}", header, e);
+            }
+            self.counters.update(|s| s.tcp.predicted_acks += 1);
+            return;
+        }
+        let len = data.len() as u64;
+        match self.receiver.receive_data(header.seq_num, data, now) {
+            Ok(()) => {
+                self.bytes_in.set(self.bytes_in.get() + len);
+                self.counters.update(|s| s.tcp.predicted_data += 1);
+                self.tune_receive_window(header, now);
+            }
+            Err(e) => {
+                warn!("Ignoring remote data for {:?}: {:?}", header, e);
+                self.counters.count_drop(DropReason::OutOfWindow);
+            }
+        }
+    }
+
+    /// Checks the sequence and acknowledgement numbers of a segment carrying `len` bytes against
+    /// the receive and send windows, counting violations. Returns whether the segment should be
+    /// processed, which it always is unless validation is strict or it is a RST off the next
+    /// expected sequence number.
+    fn validate(&self, header: &TcpHeader, len: usize) -> bool {
+        let strict = self.validation.get() == Validation::Strict;
+        let state = self.state.get();
+        let (mut rcv_nxt, _) = self.receiver.get_recv_seq_no();
+        // The FIN of the remote takes up a sequence number that the receiver does not count.
+        if matches!(
+            state,
+            State::PassiveClose
+                | State::CloseWait1
+                | State::CloseWait2
+                | State::LastAck
+                | State::Closing1
+                | State::Closing2
+                | State::FinWait3
+                | State::TimeWait1
+                | State::TimeWait2
+        ) {
+            rcv_nxt += Wrapping(1);
+        }
+
+        // RFC 793, section 3.3: the segment must overlap the receive window.
+        let rcv_wnd = self.receiver.window_size();
+        let offset = (header.seq_num - rcv_nxt).0;
+        let in_window = |offset: u32| offset < rcv_wnd;
+        let acceptable = match (len, rcv_wnd) {
+            (0, 0) => offset == 0,
+            (0, _) => in_window(offset),
+            (_, 0) => false,
+            (_, _) => in_window(offset) || in_window(offset.wrapping_add(len as u32 - 1)),
+        };
+        if !acceptable {
+            self.counters.update(|s| s.tcp.seq_out_of_window += 1);
+            if strict {
+                return false;
+            }
+        }
+
+        // RFC 5961, section 3.2: a RST must be at exactly the next expected sequence number. One
+        // elsewhere in the window gets a challenge ACK, and is discarded either way.
+        if header.rst {
+            if header.seq_num != rcv_nxt {
+                self.counters.update(|s| s.tcp.rst_seq_violations += 1);
+                if acceptable {
+                    self.cold.challenge_ack.set(true);
+                }
+                return false;
+            }
+            return true;
+        }
+
+        // RFC 5961, section 5: the ACK must be of data sent, and not older than the send window.
+        if header.ack && state == State::Established {
+            let (snd_una, _) = self.sender.get_base_seq_no();
+            let (snd_nxt, _) = self.sender.get_sent_seq_no();
+            let (max_snd_wnd, _) = self.sender.get_window_size();
+            let ahead = header.ack_num - snd_una;
+            let behind = snd_una - header.ack_num;
+            if ahead > snd_nxt - snd_una && behind > Wrapping(max_snd_wnd) {
+                if ahead.0 < 1 << 31 {
+                    self.counters.update(|s| s.tcp.ack_unsent += 1);
+                } else {
+                    self.counters.update(|s| s.tcp.ack_too_old += 1);
+                }
+                return !strict;
+            }
+        }
+        true
+    }
+
+    /// Moves the connection to the state `event` leads to, notifying subscribers if it changes.
+    pub fn apply(&self, event: Event) -> Result<(), Fail> {
+        let from = self.state.get();
+        let to = fsm::transition(from, event)?;
+        if to == from {
+            return Ok(());
+        }
+        if to == State::Reset
+            && matches!(
+                from,
+                State::Established | State::CloseWait1 | State::CloseWait2
+            )
+        {
+            self.counters.update(|s| s.tcp.estab_resets += 1);
+        }
+        self.state.set(to);
+        self.cold.subscribers.notify(&StateTransition {
+            local: self.local,
+            remote: self.remote,
+            from,
+            to,
+            trigger: event,
+            at: self.rt.now(),
+        });
+        if !fsm::is_terminal(from) && fsm::is_terminal(to) {
+            self.cold.subscribers.notify_close(&self.close_summary(to));
+        }
         Ok(())
     }
 
+    /// Summarizes the life of this connection, which got torn down into `state`.
+    fn close_summary(&self, state: State) -> CloseSummary {
+        let reason = match state {
+            _ if self.cold.reaped.get() => CloseReason::Idle,
+            _ if matches!(self.failure(), Some(Fail::Timeout {})) => CloseReason::Timeout,
+            _ if matches!(self.failure(), Some(Fail::ConnectionAborted {})) => CloseReason::Aborted,
+            State::Reset => CloseReason::Reset,
+            // Only the side that sent the first FIN waits in TIME_WAIT.
+            State::TimeWait2 => CloseReason::ActiveClose,
+            _ => CloseReason::PassiveClose,
+        };
+        CloseSummary {
+            local: self.local,
+            remote: self.remote,
+            duration: self.rt.now().saturating_duration_since(self.cold.opened_at),
+            bytes_in: self.bytes_in.get(),
+            bytes_out: self.bytes_out.get(),
+            retransmits: self.cold.retransmits.get(),
+            max_rtt: self.sender.max_rtt(),
+            reason,
+        }
+    }
+
+    /// Counts a retransmitted segment, for this connection and the engine.
+    pub fn count_retransmit(&self) {
+        self.cold.retransmits.set(self.cold.retransmits.get() + 1);
+        self.counters.update(|s| s.tcp.retrans_segs += 1);
+        self.sender.on_retransmit();
+    }
+
+    /// Returns whether a segment called for a challenge ACK since the last call.
+    pub fn take_challenge_ack(&self) -> bool {
+        self.cold.challenge_ack.replace(false)
+    }
+
+    /// Acknowledges at once what we received so far, as a challenge to a remote that sent a RST
+    /// or SYN we can't tell from a blind injection (RFC 5961, section 3.2). The ACK goes out
+    /// right away rather than on the next poll of the acknowledger, unless the link address of
+    /// the remote needs resolving.
+    pub fn send_challenge_ack(&self) {
+        self.counters.update(|s| s.tcp.challenge_acks += 1);
+        let remote_link_addr = match self.arp.try_query(self.remote.address()) {
+            Some(addr) => addr,
+            None => {
+                self.receiver.set_ack_deadline(Some(self.rt.now()));
+                return;
+            }
+        };
+        let mut header = self.tcp_header();
+        header.ack = true;
+        header.ack_num = self.receiver.get_recv_seq_no().0;
+        header.seq_num = self.sender.get_sent_seq_no().0;
+        self.emit(header, RT::Buf::empty(), remote_link_addr);
+    }
+
+    /// Counts a zero-window probe.
+    pub fn count_window_probe(&self) {
+        self.counters.update(|s| s.tcp.window_probes += 1);
+    }
+
+    /// Counts a retransmission set off by duplicate ACKs, or by a partial ACK in fast recovery.
+    pub fn count_fast_retransmit(&self) {
+        self.counters.update(|s| s.tcp.fast_retransmits += 1);
+    }
+
+    /// Counts segments retransmitted to fill holes reported through selective acknowledgments,
+    /// past the oldest unacknowledged one.
+    pub fn count_sack_retransmits(&self, num_segments: usize) {
+        self.counters
+            .update(|s| s.tcp.sack_retrans_segs += num_segments as u64);
+    }
+
     /// Fetch a TCP header filling out various values based on our current state.
     pub fn tcp_header(&self) -> TcpHeader {
         let mut header = TcpHeader::new(self.local.port, self.remote.port);
@@ -334,13 +1288,74 @@ impl<RT: Runtime> ControlBlock<RT> {
             if let Some(ack_seq_no) = self.receiver.current_ack() {
                 header.ack_num = ack_seq_no;
                 header.ack = true;
+                header.ece = self.ecn_echo.get();
             }
         }
+        if let Some(clock) = self.ts_clock {
+            header.push_option(TcpOptions2::Timestamp {
+                sender_timestamp: clock.value(self.rt.now()),
+                echo_timestamp: self.ts_recent.get().unwrap_or(0),
+            });
+        }
+        // Data received twice gets reported once, ahead of the blocks held out of order.
+        let dsack = if self.selective_acks {
+            self.receiver.take_dsack()
+        } else {
+            None
+        };
+        if dsack.is_some() || (self.selective_acks && self.receiver.has_out_of_order()) {
+            let max_blocks = if self.ts_clock.is_some() {
+                MAX_SACK_BLOCKS_WITH_TIMESTAMPS
+            } else {
+                MAX_SACK_BLOCKS
+            };
+            let mut blocks = self
+                .receiver
+                .sack_blocks(max_blocks - dsack.is_some() as usize);
+            if let Some(dsack) = dsack {
+                blocks.insert(0, dsack);
+                self.counters.update(|s| s.tcp.dsacks_sent += 1);
+            }
+            let mut sacks = [SelectiveAcknowlegement {
+                begin: Wrapping(0),
+                end: Wrapping(0),
+            }; MAX_SACK_BLOCKS];
+            sacks[..blocks.len()].copy_from_slice(&blocks);
+            header.push_option(TcpOptions2::SelectiveAcknowlegement {
+                num_sacks: blocks.len(),
+                sacks,
+            });
+        }
         header
     }
 
     /// Transmit this message to our connected peer.
     pub fn emit(&self, header: TcpHeader, data: RT::Buf, remote_link_addr: MacAddress) {
+        self.transmit(header, data, remote_link_addr, false)
+    }
+
+    /// Transmits data sent for the first time, which alone is marked ECN-capable, as opposed to
+    /// retransmissions, probes and pure ACKs (RFC 3168, section 6.1.5). The first such segment
+    /// after the congestion window shrank for an ECN echo carries CWR.
+    pub fn emit_new_data(
+        &self,
+        mut header: TcpHeader,
+        data: RT::Buf,
+        remote_link_addr: MacAddress,
+    ) {
+        if self.ecn {
+            header.cwr = self.sender.take_cwr();
+        }
+        self.transmit(header, data, remote_link_addr, self.ecn)
+    }
+
+    fn transmit(
+        &self,
+        header: TcpHeader,
+        data: RT::Buf,
+        remote_link_addr: MacAddress,
+        ect: bool,
+    ) {
         if header.ack {
             let (recv_seq_no, _) = self.receiver.get_recv_seq_no();
             if self.state.get() == State::PassiveClose || self.state.get() == State::FinWait3 {
@@ -352,19 +1367,33 @@ impl<RT: Runtime> ControlBlock<RT> {
             self.receiver.set_ack_seq_no(header.ack_num);
         }
 
-        debug!("Sending {} bytes + {:?}", data.len(), header);
+        datapath!(Debug, "Sending {} bytes + {}", data.len(), header);
         let segment = TcpSegment {
             ethernet2_hdr: Ethernet2Header {
                 dst_addr: remote_link_addr,
                 src_addr: self.rt.local_link_addr(),
                 ether_type: EtherType2::Ipv4,
             },
-            ipv4_hdr: Ipv4Header::new(self.local.addr, self.remote.addr, Ipv4Protocol2::Tcp),
+            ipv4_hdr: Ipv4Header {
+                ecn: if ect { IPV4_ECN_ECT0 } else { IPV4_ECN_NOT_ECT },
+                ..Ipv4Header::new(self.local.addr, self.remote.addr, Ipv4Protocol2::Tcp)
+            },
             tcp_hdr: header,
             data,
-            tx_checksum_offload: self.rt.tcp_options().tx_checksum_offload(),
+            tx_checksum_offload: self.tx_checksum_offload,
+            pseudo_header_sum: Some(self.pseudo_header_sum),
         };
-        self.rt.transmit(segment);
+        match self.loopback.transmit(segment) {
+            Ok(()) => self.counters.update(|s| {
+                s.ip.out_requests += 1;
+                s.tcp.out_segs += 1;
+            }),
+            // Retransmission recovers from the loss.
+            Err(e) => {
+                warn!("Failed to send TCP segment: {:?}", e);
+                self.counters.count_transmit_drop(&e);
+            }
+        }
     }
 
     pub fn remote_mss(&self) -> usize {
@@ -374,4 +1403,88 @@ impl<RT: Runtime> ControlBlock<RT> {
     pub fn current_rto(&self) -> Duration {
         self.sender.current_rto()
     }
+
+    /// Returns the smoothed round-trip time, if any round trip got sampled.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.sender.srtt()
+    }
+
+    pub fn pacing_rate(&self) -> Option<u64> {
+        self.sender.pacing_rate()
+    }
+
+    pub fn get_nodelay(&self) -> (bool, WatchFuture<bool>) {
+        self.sender.get_nodelay()
+    }
+
+    pub fn set_nodelay(&self, value: bool) {
+        self.sender.set_nodelay(value)
+    }
+
+    pub fn set_send_buffer_size(&self, value: usize) {
+        self.sender.set_send_buffer_size(Some(value))
+    }
+
+    /// Shrinks our segments to fit in `next_hop_mtu`, as a router on the way told us with an ICMP
+    /// fragmentation needed message about the segment at `seq_num`, and sends the oldest
+    /// unacknowledged data again since the router dropped it (RFC 1191).
+    pub fn receive_fragmentation_needed(&self, seq_num: SeqNumber, next_hop_mtu: usize) {
+        // Messages must quote data in flight, so that an attacker that can't see the connection
+        // can't shrink its segments at will (RFC 5927, section 5.2).
+        let base_seq_no = self.sender.get_base_seq_no().0;
+        let sent_seq_no = self.sender.get_sent_seq_no().0;
+        if seq_lt(seq_num, base_seq_no) || !seq_gt(sent_seq_no, seq_num) {
+            warn!("Ignoring fragmentation needed message for seq {}", seq_num);
+            return;
+        }
+
+        // Routers predating RFC 1191 leave the MTU out, and no path takes less than the minimum.
+        let mtu = next_hop_mtu.max(MIN_MTU);
+        let mss = (mtu - IPV4_HEADER_SIZE - MIN_TCP_HEADER_SIZE)
+            .saturating_sub(options_size(self.ts_clock));
+        if mss >= self.sender.get_mss() {
+            return;
+        }
+        info!(
+            "Path MTU to {:?} is {}, shrinking MSS to {}",
+            self.remote, mtu, mss
+        );
+        self.sender.set_mss(mss);
+        let probe_interval = self.cold.tcp_options.path_mtu_probe_interval();
+        self.cold
+            .path_mtu_probe_deadline
+            .set(Some(self.rt.now() + probe_interval));
+
+        // The loss isn't a sign of congestion, so the retransmission leaves the congestion window
+        // and the retransmission timer alone.
+        if let Some(remote_link_addr) = self.arp.try_query(self.remote.addr) {
+            for (seq_no, bytes) in self.retransmit_unacked(mss) {
+                let mut header = self.tcp_header();
+                header.seq_num = seq_no;
+                self.emit(header, bytes, remote_link_addr);
+                self.count_retransmit();
+            }
+        }
+    }
+
+    pub fn get_path_mtu_probe_deadline(&self) -> (Option<Instant>, WatchFuture<Option<Instant>>) {
+        self.cold.path_mtu_probe_deadline.watch()
+    }
+
+    /// Goes back to segments of the MSS agreed on, in case the path MTU grew since it shrank
+    /// them. A router still unable to forward them tells us so again (RFC 1191, section 6.3).
+    pub fn probe_path_mtu(&self) {
+        info!("Probing path MTU to {:?}", self.remote);
+        self.sender.set_mss(self.sender.get_max_mss());
+        self.cold.path_mtu_probe_deadline.set(None);
+    }
+}
+
+/// Returns the bytes of options every segment of a connection carries, depending on whether it
+/// agreed on timestamps.
+fn options_size(ts_clock: Option<TimestampClock>) -> usize {
+    match ts_clock {
+        Some(_) => TIMESTAMP_OPTION_SIZE,
+        None => 0,
+    }
 }
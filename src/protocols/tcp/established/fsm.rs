@@ -0,0 +1,217 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! State machine of established connections, kept free of side effects so that it can be model
+//! checked on its own.
+
+use super::ctrlblk::State;
+use crate::fail::Fail;
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Something that moves a connection from one state to another.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Event {
+    /// A segment with these control flags arrived.
+    Segment { rst: bool, fin: bool, ack: bool },
+    /// The application closed the connection.
+    Close,
+    /// Our FIN was sent.
+    FinSent,
+    /// The FIN of the remote peer was acknowledged.
+    FinAcked,
+    /// The connection was torn down on our side, telling the remote with a RST.
+    Abort,
+}
+
+//==============================================================================
+// Standalone Functions
+//==============================================================================
+
+/// Returns the state a connection in `state` moves to on `event`.
+///
+/// Segments never fail: those that do not move the connection, such as retransmitted FINs or
+/// ACKs of data, leave it where it is. Local events fail when they make no sense in `state`.
+pub fn transition(state: State, event: Event) -> Result<State, Fail> {
+    let next = match event {
+        Event::Segment { rst: true, .. } => State::Reset,
+        Event::Segment {
+            fin: true,
+            ack: true,
+            ..
+        } => match state {
+            State::Established => State::PassiveClose,
+            State::FinWait1 | State::Closing1 => State::TimeWait1,
+            State::FinWait2 => State::FinWait3,
+            State::Closing2 | State::LastAck => on_ack(state),
+            s => s,
+        },
+        Event::Segment { fin: true, .. } => match state {
+            State::Established => State::PassiveClose,
+            State::FinWait1 => State::Closing1,
+            State::FinWait2 => State::FinWait3,
+            s => s,
+        },
+        Event::Segment { ack: true, .. } => on_ack(state),
+        Event::Segment { .. } => state,
+        Event::Close => match state {
+            State::Established => State::ActiveClose,
+            State::CloseWait1 => State::CloseWait2,
+            _ => {
+                return Err(Fail::Invalid {
+                    details: "connection not open",
+                })
+            }
+        },
+        Event::FinSent => match state {
+            State::ActiveClose => State::FinWait1,
+            State::CloseWait2 => State::LastAck,
+            _ => {
+                return Err(Fail::Invalid {
+                    details: "no FIN to send",
+                })
+            }
+        },
+        Event::Abort if is_terminal(state) => {
+            return Err(Fail::Invalid {
+                details: "connection not open",
+            })
+        }
+        Event::Abort => State::Reset,
+        Event::FinAcked => match state {
+            State::FinWait3 | State::TimeWait1 => State::TimeWait2,
+            State::Closing1 => State::Closing2,
+            State::PassiveClose => State::CloseWait1,
+            _ => {
+                return Err(Fail::Invalid {
+                    details: "no FIN to acknowledge",
+                })
+            }
+        },
+    };
+    Ok(next)
+}
+
+/// Returns whether a connection in `state` is done exchanging segments.
+pub fn is_terminal(state: State) -> bool {
+    matches!(state, State::TimeWait2 | State::Closed | State::Reset)
+}
+
+/// Returns whether the application may still push data in `state`, not having closed or shut down
+/// writing yet.
+pub fn accepts_data(state: State) -> bool {
+    matches!(
+        state,
+        State::Established | State::PassiveClose | State::CloseWait1
+    )
+}
+
+/// Returns whether data may still arrive in `state`, the remote not having sent its FIN yet.
+pub fn receives_data(state: State) -> bool {
+    matches!(
+        state,
+        State::Established | State::ActiveClose | State::FinWait1 | State::FinWait2
+    )
+}
+
+/// Returns whether data we sent may still be unacknowledged in `state`, our FIN not having been
+/// acknowledged yet.
+pub fn awaits_acks(state: State) -> bool {
+    matches!(
+        state,
+        State::Established
+            | State::ActiveClose
+            | State::FinWait1
+            | State::Closing1
+            | State::PassiveClose
+            | State::CloseWait1
+            | State::CloseWait2
+            | State::LastAck
+    )
+}
+
+/// Handles an ACK, which is taken to acknowledge our FIN if we sent one.
+fn on_ack(state: State) -> State {
+    match state {
+        State::FinWait1 => State::FinWait2,
+        // Our FIN got acknowledged before we acknowledged theirs.
+        State::Closing1 => State::TimeWait1,
+        State::Closing2 => State::TimeWait2,
+        State::LastAck => State::Closed,
+        s => s,
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{transition, Event, State};
+
+    const FIN: Event = Event::Segment {
+        rst: false,
+        fin: true,
+        ack: false,
+    };
+    const ACK: Event = Event::Segment {
+        rst: false,
+        fin: false,
+        ack: true,
+    };
+    const FIN_ACK: Event = Event::Segment {
+        rst: false,
+        fin: true,
+        ack: true,
+    };
+
+    fn run(mut state: State, events: &[Event]) -> State {
+        for event in events {
+            state = transition(state, *event).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn test_active_close() {
+        let events = [Event::Close, Event::FinSent, ACK, FIN, Event::FinAcked];
+        assert_eq!(run(State::Established, &events), State::TimeWait2);
+    }
+
+    #[test]
+    fn test_passive_close() {
+        let events = [FIN, Event::FinAcked, Event::Close, Event::FinSent, ACK];
+        assert_eq!(run(State::Established, &events), State::Closed);
+    }
+
+    #[test]
+    fn test_simultaneous_close() {
+        let events = [Event::Close, Event::FinSent, FIN, Event::FinAcked, ACK];
+        assert_eq!(run(State::Established, &events), State::TimeWait2);
+        let events = [Event::Close, Event::FinSent, FIN, ACK, Event::FinAcked];
+        assert_eq!(run(State::Established, &events), State::TimeWait2);
+        let events = [Event::Close, Event::FinSent, FIN_ACK, Event::FinAcked];
+        assert_eq!(run(State::Established, &events), State::TimeWait2);
+    }
+
+    #[test]
+    fn test_invalid_events() {
+        assert!(transition(State::FinWait1, Event::Close).is_err());
+        assert!(transition(State::Established, Event::FinSent).is_err());
+        assert!(transition(State::Established, Event::FinAcked).is_err());
+        let rst = Event::Segment {
+            rst: true,
+            fin: true,
+            ack: true,
+        };
+        assert_eq!(transition(State::LastAck, rst).unwrap(), State::Reset);
+        assert_eq!(
+            transition(State::FinWait2, Event::Abort).unwrap(),
+            State::Reset
+        );
+        assert!(transition(State::TimeWait2, Event::Abort).is_err());
+    }
+}
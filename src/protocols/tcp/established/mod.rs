@@ -3,6 +3,7 @@
 
 mod background;
 mod ctrlblk;
+pub mod fsm;
 mod receiver;
 mod sender;
 
@@ -14,12 +15,16 @@ use self::background::background;
 use crate::{
     fail::Fail,
     file_table::FileDescriptor,
-    protocols::{ipv4, tcp::segment::TcpHeader},
+    protocols::{
+        ipv4,
+        tcp::{segment::TcpHeader, Options as TcpOptions, Readiness, TcpInfo},
+    },
     runtime::Runtime,
     scheduler::SchedulerHandle,
 };
 use futures::channel::mpsc;
 use std::{
+    net::Shutdown,
     rc::Rc,
     task::{Context, Poll},
     time::Duration,
@@ -27,6 +32,8 @@ use std::{
 
 pub struct EstablishedSocket<RT: Runtime> {
     pub cb: Rc<ControlBlock<RT>>,
+    /// File descriptor the connection is known by to the application.
+    pub fd: FileDescriptor,
     #[allow(unused)]
     background_work: SchedulerHandle,
 }
@@ -42,26 +49,60 @@ impl<RT: Runtime> EstablishedSocket<RT> {
         let handle = cb.rt().spawn(future);
         Self {
             cb: cb.clone(),
+            fd,
             background_work: handle,
         }
     }
 
-    pub fn receive(&self, header: &TcpHeader, data: RT::Buf) {
-        self.cb.receive(header, data)
+    pub fn receive(&self, header: &TcpHeader, data: RT::Buf, ce: bool) {
+        self.cb.receive(header, data, ce);
+        self.cb.notify_ready();
     }
 
     pub fn send(&self, buf: RT::Buf) -> Result<(), Fail> {
         self.cb.send(buf)
     }
 
+    pub fn send_vectored(&self, bufs: &[RT::Buf]) -> Result<(), Fail> {
+        self.cb.send_vectored(bufs)
+    }
+
     pub fn poll_recv(&self, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
         self.cb.poll_recv(ctx)
     }
 
+    pub fn poll_recv_exact(&self, ctx: &mut Context, len: usize) -> Poll<Result<RT::Buf, Fail>> {
+        self.cb.poll_recv_exact(ctx, len)
+    }
+
+    pub fn poll_recv_max(&self, ctx: &mut Context, max_len: usize) -> Poll<Result<RT::Buf, Fail>> {
+        self.cb.poll_recv_max(ctx, max_len)
+    }
+
+    pub fn poll_peek(&self, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
+        self.cb.poll_peek(ctx)
+    }
+
+    pub fn poll_ready(&self, ctx: &mut Context, interest: Readiness) -> Poll<Readiness> {
+        self.cb.poll_ready(ctx, interest)
+    }
+
     pub fn close(&self) -> Result<(), Fail> {
         self.cb.close()
     }
 
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Fail> {
+        self.cb.shutdown(how)
+    }
+
+    pub fn hard_close(&self) -> Result<(), Fail> {
+        self.cb.hard_close()
+    }
+
+    pub fn update_options(&self, options: &TcpOptions<RT>) {
+        self.cb.update_options(options)
+    }
+
     pub fn remote_mss(&self) -> usize {
         self.cb.remote_mss()
     }
@@ -70,6 +111,22 @@ impl<RT: Runtime> EstablishedSocket<RT> {
         self.cb.current_rto()
     }
 
+    pub fn pacing_rate(&self) -> Option<u64> {
+        self.cb.pacing_rate()
+    }
+
+    pub fn info(&self) -> TcpInfo {
+        self.cb.info()
+    }
+
+    pub fn set_nodelay(&self, value: bool) {
+        self.cb.set_nodelay(value)
+    }
+
+    pub fn set_send_buffer_size(&self, value: usize) {
+        self.cb.set_send_buffer_size(value)
+    }
+
     pub fn endpoints(&self) -> (ipv4::Endpoint, ipv4::Endpoint) {
         (self.cb.get_local(), self.cb.get_remote())
     }
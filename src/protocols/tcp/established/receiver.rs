@@ -4,11 +4,11 @@
 use crate::{
     collections::watched::{WatchFuture, WatchedValue},
     fail::Fail,
-    protocols::tcp::SeqNumber,
+    protocols::tcp::{segment::SelectiveAcknowlegement, seq_gt, seq_lt, SeqNumber},
     runtime::Runtime,
 };
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::{BTreeMap, VecDeque},
     convert::TryInto,
     num::Wrapping,
@@ -17,7 +17,22 @@ use std::{
 };
 
 const RECV_QUEUE_SZ: usize = 2048;
-const MAX_OUT_OF_ORDER: usize = 16;
+
+/// Returns the bytes in `ack_frequency` segments of `mss` bytes, saturating rather than wrapping
+/// around for frequencies too large to ever be reached.
+fn ack_threshold(ack_frequency: usize, mss: u32) -> u32 {
+    let ack_frequency: u32 = ack_frequency.try_into().unwrap_or(u32::MAX);
+    ack_frequency.saturating_mul(mss)
+}
+
+/// Receive window auto-tuning: the window grows with the data received over a round trip.
+#[derive(Clone, Copy, Debug)]
+struct WindowTuning {
+    /// Bytes the window grows up to.
+    max_window_size: u32,
+    /// Start of the round trip being measured, and the sequence number received up to then.
+    round: Option<(Instant, SeqNumber)>,
+}
 
 #[derive(Debug)]
 pub struct Receiver<RT: Runtime> {
@@ -40,35 +55,160 @@ pub struct Receiver<RT: Runtime> {
     recv_seq_no: WatchedValue<SeqNumber>,
 
     /// Timeout for delayed ACKs.
-    ack_delay_timeout: Duration,
+    ack_delay_timeout: Cell<Duration>,
+    /// Bytes received unacknowledged from which an ACK goes out at once: a number of full-sized
+    /// segments (RFC 5681, section 4.2).
+    ack_threshold: Cell<u32>,
+    /// Size of a full-sized segment, the MSS advertised to the remote.
+    mss: u32,
 
     ack_deadline: WatchedValue<Option<Instant>>,
 
-    max_window_size: u32,
+    max_window_size: Cell<u32>,
     window_scale: u32,
+    window_tuning: Cell<Option<WindowTuning>>,
 
+    /// Wakers of the pending read and of the pending peek, kept apart so that neither replaces
+    /// the other.
     waker: RefCell<Option<Waker>>,
+    peek_waker: RefCell<Option<Waker>>,
     out_of_order: RefCell<BTreeMap<SeqNumber, RT::Buf>>,
+    /// Sequence number of the segment last held out of order, which selective acknowledgments
+    /// report first.
+    last_out_of_order: Cell<Option<SeqNumber>>,
+    max_out_of_order: Cell<usize>,
+    /// Data last received twice, which the next selective acknowledgment reports first as a
+    /// duplicate (RFC 2883).
+    dsack: Cell<Option<SelectiveAcknowlegement>>,
 }
 
 impl<RT: Runtime> Receiver<RT> {
     pub fn new(
         seq_no: SeqNumber,
         ack_delay_timeout: Duration,
+        ack_frequency: usize,
+        mss: usize,
         max_window_size: u32,
         window_scale: u32,
+        max_out_of_order: usize,
     ) -> Self {
         Self {
             base_seq_no: WatchedValue::new(seq_no),
             recv_queue: RefCell::new(VecDeque::with_capacity(RECV_QUEUE_SZ)),
             ack_seq_no: WatchedValue::new(seq_no),
             recv_seq_no: WatchedValue::new(seq_no),
-            ack_delay_timeout,
+            ack_delay_timeout: Cell::new(ack_delay_timeout),
+            ack_threshold: Cell::new(ack_threshold(ack_frequency, mss as u32)),
+            mss: mss as u32,
             ack_deadline: WatchedValue::new(None),
-            max_window_size,
+            max_window_size: Cell::new(max_window_size),
             window_scale,
+            window_tuning: Cell::new(None),
             waker: RefCell::new(None),
+            peek_waker: RefCell::new(None),
             out_of_order: RefCell::new(BTreeMap::new()),
+            last_out_of_order: Cell::new(None),
+            max_out_of_order: Cell::new(max_out_of_order),
+            dsack: Cell::new(None),
+        }
+    }
+
+    pub fn set_ack_delay_timeout(&self, value: Duration) {
+        self.ack_delay_timeout.set(value)
+    }
+
+    pub fn set_ack_frequency(&self, value: usize) {
+        self.ack_threshold.set(ack_threshold(value, self.mss))
+    }
+
+    pub fn set_max_out_of_order(&self, value: usize) {
+        self.max_out_of_order.set(value)
+    }
+
+    pub fn get_max_window_size(&self) -> u32 {
+        self.max_window_size.get()
+    }
+
+    /// Lets the window grow up to `max_window_size` bytes, or as far as the window scale
+    /// reaches, with [Receiver::tune_window].
+    pub fn set_window_tuning(&self, max_window_size: u32) {
+        let max_window_size = max_window_size.min(0xffff << self.window_scale);
+        self.window_tuning.set(Some(WindowTuning {
+            max_window_size,
+            round: None,
+        }));
+    }
+
+    /// Ends the round trip being measured if it lasted `rtt` by `now`, growing the window to
+    /// twice the data received over it so that the remote isn't held back by the window on the
+    /// next one. Returns whether the window grew.
+    pub fn tune_window(&self, rtt: Duration, now: Instant) -> bool {
+        let mut tuning = match self.window_tuning.get() {
+            Some(tuning) => tuning,
+            None => return false,
+        };
+        let recv_seq_no = self.recv_seq_no.get();
+        let (started_at, start_seq_no) = *tuning.round.get_or_insert((now, recv_seq_no));
+        if now.saturating_duration_since(started_at) < rtt {
+            self.window_tuning.set(Some(tuning));
+            return false;
+        }
+        tuning.round = Some((now, recv_seq_no));
+        self.window_tuning.set(Some(tuning));
+        let Wrapping(received) = recv_seq_no - start_seq_no;
+        let window_size = received.saturating_mul(2).min(tuning.max_window_size);
+        if window_size <= self.max_window_size.get() {
+            return false;
+        }
+        self.max_window_size.set(window_size);
+        true
+    }
+
+    pub fn get_window_scale(&self) -> u32 {
+        self.window_scale
+    }
+
+    /// Returns the base sequence number and the data not yet consumed by the application.
+    pub fn export_queue(&self) -> (SeqNumber, Vec<RT::Buf>) {
+        let queue = self.recv_queue.borrow().iter().cloned().collect();
+        (self.base_seq_no.get(), queue)
+    }
+
+    /// Refills the queue of a freshly created receiver.
+    pub fn import_queue(&self, ack_seq_no: SeqNumber, queue: Vec<RT::Buf>, now: Instant) {
+        let mut recv_seq_no = self.base_seq_no.get();
+        for buf in queue {
+            recv_seq_no += Wrapping(buf.len() as u32);
+            self.recv_queue.borrow_mut().push_back(buf);
+        }
+        self.recv_seq_no.set(recv_seq_no);
+        self.ack_seq_no.set(ack_seq_no);
+        if ack_seq_no != recv_seq_no {
+            self.ack_deadline
+                .set(Some(now + self.ack_delay_timeout.get()));
+        }
+    }
+
+    /// Returns the number of bytes received but not yet consumed by the application.
+    pub fn queued_bytes(&self) -> usize {
+        (self.recv_seq_no.get() - self.base_seq_no.get()).0 as usize
+    }
+
+    /// Drops the data waiting for the application as if it took it, waking up whoever waits for
+    /// some.
+    pub fn discard_queued(&self) {
+        self.recv_queue.borrow_mut().clear();
+        self.base_seq_no.set(self.recv_seq_no.get());
+        self.wake();
+    }
+
+    /// Wakes up whoever waits for data, to read it or to peek at it.
+    fn wake(&self) {
+        if let Some(w) = self.waker.borrow_mut().take() {
+            w.wake()
+        }
+        if let Some(w) = self.peek_waker.borrow_mut().take() {
+            w.wake()
         }
     }
 
@@ -92,13 +232,75 @@ impl<RT: Runtime> Receiver<RT> {
         self.ack_deadline.set(when);
     }
 
+    /// Returns whether segments received ahead of a gap are waiting for it to fill.
+    pub fn has_out_of_order(&self) -> bool {
+        !self.out_of_order.borrow().is_empty()
+    }
+
+    /// Returns up to `max_blocks` blocks of data held out of order, to report them in a
+    /// selective acknowledgment. Segments next to each other make up one block, and the block
+    /// holding the segment received last comes first, as RFC 2018 asks.
+    pub fn sack_blocks(&self, max_blocks: usize) -> Vec<SelectiveAcknowlegement> {
+        // Segments go in the order of their distance from the receive sequence number, which
+        // still holds where the sequence space wraps around.
+        let recv_seq_no = self.recv_seq_no.get();
+        let mut segments: Vec<(SeqNumber, SeqNumber)> = self
+            .out_of_order
+            .borrow()
+            .iter()
+            .map(|(&seq_no, buf)| (seq_no, seq_no + Wrapping(buf.len() as u32)))
+            .collect();
+        segments.sort_by_key(|&(seq_no, _)| (seq_no - recv_seq_no).0);
+        let mut blocks: Vec<SelectiveAcknowlegement> = vec![];
+        for (seq_no, end) in segments {
+            match blocks.last_mut() {
+                Some(last) if !seq_gt(seq_no, last.end) => {
+                    if seq_gt(end, last.end) {
+                        last.end = end;
+                    }
+                }
+                _ => blocks.push(SelectiveAcknowlegement { begin: seq_no, end }),
+            }
+        }
+        if let Some(latest) = self.last_out_of_order.get() {
+            if let Some(i) = blocks
+                .iter()
+                .position(|b| !seq_lt(latest, b.begin) && seq_lt(latest, b.end))
+            {
+                let block = blocks.remove(i);
+                blocks.insert(0, block);
+            }
+        }
+        blocks.truncate(max_blocks);
+        blocks
+    }
+
+    /// Takes the block of data received twice since the last selective acknowledgment, if any.
+    pub fn take_dsack(&self) -> Option<SelectiveAcknowlegement> {
+        self.dsack.take()
+    }
+
+    /// Notes that the data from `begin` to `end` arrived again, which gets acknowledged at once
+    /// for the remote to learn that it retransmitted it needlessly.
+    fn receive_duplicate(&self, begin: SeqNumber, end: SeqNumber, now: Instant) {
+        self.dsack.set(Some(SelectiveAcknowlegement { begin, end }));
+        self.ack_deadline.set(Some(now));
+    }
+
+    /// Returns the number of bytes the remote may send beyond the receive sequence number.
+    pub fn window_size(&self) -> u32 {
+        let Wrapping(bytes_outstanding) = self.recv_seq_no.get() - self.base_seq_no.get();
+        self.max_window_size.get() - bytes_outstanding
+    }
+
     pub fn hdr_window_size(&self) -> u16 {
         let Wrapping(bytes_outstanding) = self.recv_seq_no.get() - self.base_seq_no.get();
-        let window_size = self.max_window_size - bytes_outstanding;
+        let window_size = self.max_window_size.get() - bytes_outstanding;
         let hdr_window_size = (window_size >> self.window_scale)
             .try_into()
             .expect("Window size overflow");
-        debug!(
+        datapath!(
+            Debug,
             "Sending window size update -> {} (hdr {}, scale {})",
             (hdr_window_size as u32) << self.window_scale,
             hdr_window_size,
@@ -124,38 +326,134 @@ impl<RT: Runtime> Receiver<RT> {
     }
 
     pub fn poll_recv(&self, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
+        self.poll_recv_max(ctx, usize::MAX)
+    }
+
+    /// Like [Receiver::poll_recv], but hands out no more than `max_len` bytes, leaving the rest
+    /// of a longer segment for the next read.
+    pub fn poll_recv_max(&self, ctx: &mut Context, max_len: usize) -> Poll<Result<RT::Buf, Fail>> {
         if self.base_seq_no.get() == self.recv_seq_no.get() {
             *self.waker.borrow_mut() = Some(ctx.waker().clone());
             return Poll::Pending;
         }
 
-        let segment = self
-            .recv_queue
-            .borrow_mut()
+        let mut recv_queue = self.recv_queue.borrow_mut();
+        let mut segment = recv_queue
             .pop_front()
             .expect("recv_seq > base_seq without data in queue?");
+        let segment_len = segment.len();
+        if segment_len > max_len {
+            let mut rest = segment.clone();
+            rest.adjust(max_len);
+            segment.trim(segment_len - max_len);
+            recv_queue.push_front(rest);
+        }
         self.base_seq_no
             .modify(|b| b + Wrapping(segment.len() as u32));
 
         Poll::Ready(Ok(segment))
     }
 
+    /// Hands out the head of the receive queue while leaving it there for the next read: the
+    /// segment at its head as is if a full-sized one, or else the bytes queued up to the MSS,
+    /// copied into a new buffer, so that a header split across small segments is seen whole.
+    pub fn poll_peek(&self, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
+        let recv_queue = self.recv_queue.borrow();
+        let head = match recv_queue.front() {
+            Some(head) => head,
+            None => {
+                *self.peek_waker.borrow_mut() = Some(ctx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+        let len = self.queued_bytes().min(self.mss as usize);
+        if head.len() >= len {
+            return Poll::Ready(Ok(head.clone()));
+        }
+        let mut bytes = Vec::with_capacity(len);
+        for segment in recv_queue.iter() {
+            let n = segment.len().min(len - bytes.len());
+            bytes.extend_from_slice(&segment[..n]);
+            if bytes.len() == len {
+                break;
+            }
+        }
+        Poll::Ready(Ok(RT::Buf::from_slice(&bytes)))
+    }
+
+    /// Takes exactly `len` bytes off the receive queue, or whatever is left if `eof` tells that no
+    /// more will arrive. A segment of the right size is handed out as is; otherwise the bytes are
+    /// copied into a new buffer.
+    pub fn poll_recv_exact(
+        &self,
+        ctx: &mut Context,
+        len: usize,
+        eof: bool,
+    ) -> Poll<Result<RT::Buf, Fail>> {
+        let queued = self.queued_bytes();
+        if queued < len && !eof {
+            *self.waker.borrow_mut() = Some(ctx.waker().clone());
+            return Poll::Pending;
+        }
+        let len = len.min(queued);
+
+        let mut recv_queue = self.recv_queue.borrow_mut();
+        let buf = if recv_queue.front().map_or(false, |b| b.len() == len) {
+            recv_queue.pop_front().unwrap()
+        } else {
+            let mut bytes = Vec::with_capacity(len);
+            while bytes.len() < len {
+                let mut segment = recv_queue
+                    .pop_front()
+                    .expect("recv_seq > base_seq without data in queue?");
+                let n = segment.len().min(len - bytes.len());
+                bytes.extend_from_slice(&segment[..n]);
+                if n < segment.len() {
+                    segment.adjust(n);
+                    recv_queue.push_front(segment);
+                }
+            }
+            RT::Buf::from_slice(&bytes)
+        };
+        self.base_seq_no.modify(|b| b + Wrapping(len as u32));
+
+        Poll::Ready(Ok(buf))
+    }
+
     pub fn receive_data(&self, seq_no: SeqNumber, buf: RT::Buf, now: Instant) -> Result<(), Fail> {
         let recv_seq_no = self.recv_seq_no.get();
-        if seq_no > recv_seq_no {
+        // Segments out of order, and those filling a gap, get acknowledged at once so that the
+        // remote learns about the gap early, as RFC 5681 asks.
+        let filling_gap = self.has_out_of_order();
+        if seq_gt(seq_no, recv_seq_no) {
             let mut out_of_order = self.out_of_order.borrow_mut();
             if !out_of_order.contains_key(&seq_no) {
-                while out_of_order.len() > MAX_OUT_OF_ORDER {
-                    let (&key, _) = out_of_order.iter().rev().next().unwrap();
+                // The segment furthest ahead makes room first.
+                while out_of_order.len() > self.max_out_of_order.get() {
+                    let &key = out_of_order
+                        .keys()
+                        .max_by_key(|&&key| (key - recv_seq_no).0)
+                        .unwrap();
                     out_of_order.remove(&key);
                 }
                 out_of_order.insert(seq_no, buf);
+                self.last_out_of_order.set(Some(seq_no));
+                self.ack_deadline.set(Some(now));
                 return Err(Fail::Ignored {
                     details: "Out of order segment (reordered)",
                 });
             }
+            // The block holding the duplicate follows it in the next selective acknowledgment.
+            self.last_out_of_order.set(Some(seq_no));
+            self.receive_duplicate(seq_no, seq_no + Wrapping(buf.len() as u32), now);
+            return Err(Fail::Ignored {
+                details: "Out of order segment (duplicate)",
+            });
         }
-        if seq_no < recv_seq_no {
+        if seq_lt(seq_no, recv_seq_no) {
+            let end = seq_no + Wrapping(buf.len() as u32);
+            let end = if seq_lt(end, recv_seq_no) { end } else { recv_seq_no };
+            self.receive_duplicate(seq_no, end, now);
             return Err(Fail::Ignored {
                 details: "Out of order segment (duplicate)",
             });
@@ -167,7 +465,7 @@ impl<RT: Runtime> Receiver<RT> {
             .iter()
             .map(|b| b.len())
             .sum::<usize>();
-        if unread_bytes + buf.len() > self.max_window_size as usize {
+        if unread_bytes + buf.len() > self.max_window_size.get() as usize {
             return Err(Fail::Ignored {
                 details: "Full receive window",
             });
@@ -175,13 +473,15 @@ impl<RT: Runtime> Receiver<RT> {
 
         self.recv_seq_no.modify(|r| r + Wrapping(buf.len() as u32));
         self.recv_queue.borrow_mut().push_back(buf);
-        if let Some(w) = self.waker.borrow_mut().take() {
-            w.wake()
-        }
+        self.wake();
 
         // TODO: How do we handle when the other side is in PERSIST state here?
-        if self.ack_deadline.get().is_none() {
-            self.ack_deadline.set(Some(now + self.ack_delay_timeout));
+        let Wrapping(unacked) = self.recv_seq_no.get() - self.ack_seq_no.get();
+        if filling_gap || unacked >= self.ack_threshold.get() {
+            self.ack_deadline.set(Some(now));
+        } else if self.ack_deadline.get().is_none() {
+            self.ack_deadline
+                .set(Some(now + self.ack_delay_timeout.get()));
         }
 
         let new_recv_seq_no = self.recv_seq_no.get();
@@ -199,3 +499,42 @@ impl<RT: Runtime> Receiver<RT> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Receiver;
+    use crate::{
+        collections::bytes::Bytes,
+        protocols::tcp::segment::SelectiveAcknowlegement,
+        runtime::RuntimeBuf,
+        test_helpers::TestRuntime,
+    };
+    use std::{
+        num::Wrapping,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_sack_blocks_wrap() {
+        let now = Instant::now();
+        let start = Wrapping(u32::MAX - 99);
+        let receiver: Receiver<TestRuntime> =
+            Receiver::new(start, Duration::from_millis(100), 2, 100, 65535, 0, 16);
+        let segment = || Bytes::from_slice(&[0; 50]);
+
+        // Two segments next to each other on both sides of the wrap, then one past another gap.
+        assert!(receiver.receive_data(start + Wrapping(50), segment(), now).is_err());
+        assert!(receiver.receive_data(start + Wrapping(100), segment(), now).is_err());
+        assert!(receiver.receive_data(start + Wrapping(200), segment(), now).is_err());
+        let block = |begin: u32, end: u32| SelectiveAcknowlegement {
+            begin: start + Wrapping(begin),
+            end: start + Wrapping(end),
+        };
+        assert_eq!(receiver.sack_blocks(4), vec![block(200, 250), block(50, 150)]);
+
+        // Data received again across the wrap gets reported up to the receive sequence number.
+        assert!(receiver.receive_data(start, segment(), now).is_ok());
+        assert!(receiver.receive_data(start, segment(), now).is_err());
+        assert_eq!(receiver.take_dsack(), Some(block(0, 50)));
+    }
+}
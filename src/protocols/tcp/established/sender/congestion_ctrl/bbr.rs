@@ -0,0 +1,564 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! BBR congestion control, version 1 (draft-cardwell-iccrg-bbr-congestion-control-00).
+//!
+//! Rather than reacting to losses, BBR models the path from the delivery rate samples taken on
+//! ACKs: the bottleneck bandwidth is the largest delivery rate over the last rounds, and the
+//! propagation delay the smallest RTT over the last seconds. Data is paced at a gain over the
+//! bottleneck bandwidth, and the amount in flight capped at a gain over their product.
+
+use super::{
+    CongestionControl, FastRetransmitRecovery, LimitedTransmit, Options, RateSample,
+    SlowStartCongestionAvoidance,
+};
+use crate::{
+    collections::watched::{WatchFuture, WatchedValue},
+    protocols::tcp::SeqNumber,
+    runtime::Runtime,
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    convert::TryInto,
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Gain of Startup, the smallest one doubling the delivery rate each round: 2 / ln(2).
+const HIGH_GAIN: f64 = 2.885;
+
+/// Pacing gains ProbeBW cycles through, one phase per propagation delay: probing for more
+/// bandwidth, draining the queue that probing built, then cruising.
+const PACING_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+
+/// Gain over the estimated bandwidth-delay product that bounds the data in flight in ProbeBW.
+const CWND_GAIN: f64 = 2.0;
+
+/// Rounds over which the bottleneck bandwidth is the largest delivery rate.
+const BTL_BW_FILTER_LEN: u64 = 10;
+
+/// Time over which the propagation delay is the smallest RTT.
+const MIN_RTT_FILTER_LEN: Duration = Duration::from_secs(10);
+
+/// Time ProbeRTT holds the data in flight down at the minimum, to measure the propagation delay.
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+
+/// Rounds of the bandwidth growing by less than `FULL_BW_THRESHOLD` after which the pipe is full.
+const FULL_BW_COUNT: u32 = 3;
+const FULL_BW_THRESHOLD: f64 = 1.25;
+
+/// Segments in flight BBR never goes under.
+const MIN_PIPE_SEGMENTS: u32 = 4;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Mode {
+    /// Doubling the sending rate each round until the bandwidth stops growing.
+    Startup,
+    /// Draining the queue Startup built.
+    Drain,
+    /// Cycling the pacing gain to probe for more bandwidth.
+    ProbeBw,
+    /// Briefly emptying the pipe to measure the propagation delay again.
+    ProbeRtt,
+}
+
+#[derive(Debug)]
+pub struct Bbr {
+    mss: u32,
+    initial_cwnd: u32,
+    cwnd: WatchedValue<u32>,
+    /// Rate in bytes per second data is paced at, once any delivery rate is known.
+    pacing_rate: Cell<Option<u64>>,
+    mode: Cell<Mode>,
+
+    /// Delivery rates in bytes per second with the round they were sampled in, decreasing so that
+    /// the first is the bottleneck bandwidth.
+    btl_bw_filter: RefCell<VecDeque<(u64, u64)>>,
+    /// Smallest RTT over the last `MIN_RTT_FILTER_LEN`, and when it was sampled.
+    min_rtt: Cell<Option<(Duration, Instant)>>,
+
+    /// Rounds trips counted so far, a round ending once the data sent at its start gets
+    /// acknowledged.
+    round_count: Cell<u64>,
+    /// Bytes delivered when the current round ends.
+    next_round_delivered: Cell<u64>,
+
+    /// Bottleneck bandwidth when it last grew by `FULL_BW_THRESHOLD`, and the rounds since.
+    full_bw: Cell<u64>,
+    full_bw_count: Cell<u32>,
+    /// Whether Startup found the bottleneck bandwidth.
+    filled_pipe: Cell<bool>,
+
+    /// Phase of ProbeBW in `PACING_GAIN_CYCLE`, and when it started.
+    cycle_index: Cell<usize>,
+    cycle_stamp: Cell<Option<Instant>>,
+
+    /// When ProbeRTT may end, once the data in flight got down to the minimum.
+    probe_rtt_done_stamp: Cell<Option<Instant>>,
+    /// Whether a round went by since the data in flight got down to the minimum.
+    probe_rtt_round_done: Cell<bool>,
+    /// Congestion window to restore after ProbeRTT or a timeout.
+    prior_cwnd: Cell<u32>,
+
+    duplicate_ack_count: Cell<u32>,
+    fast_retransmit_now: WatchedValue<bool>,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [Bbr].
+impl Bbr {
+    const DUP_ACK_THRESHOLD: u32 = 3;
+
+    fn with_mss(mss: u32) -> Self {
+        let initial_cwnd = 10 * mss;
+        Self {
+            mss,
+            initial_cwnd,
+            cwnd: WatchedValue::new(initial_cwnd),
+            pacing_rate: Cell::new(None),
+            mode: Cell::new(Mode::Startup),
+            btl_bw_filter: RefCell::new(VecDeque::new()),
+            min_rtt: Cell::new(None),
+            round_count: Cell::new(0),
+            next_round_delivered: Cell::new(0),
+            full_bw: Cell::new(0),
+            full_bw_count: Cell::new(0),
+            filled_pipe: Cell::new(false),
+            cycle_index: Cell::new(0),
+            cycle_stamp: Cell::new(None),
+            probe_rtt_done_stamp: Cell::new(None),
+            probe_rtt_round_done: Cell::new(false),
+            prior_cwnd: Cell::new(initial_cwnd),
+            duplicate_ack_count: Cell::new(0),
+            fast_retransmit_now: WatchedValue::new(false),
+        }
+    }
+
+    fn btl_bw(&self) -> u64 {
+        self.btl_bw_filter.borrow().front().map_or(0, |&(_, bw)| bw)
+    }
+
+    fn pacing_gain(&self) -> f64 {
+        match self.mode.get() {
+            Mode::Startup => HIGH_GAIN,
+            Mode::Drain => 1.0 / HIGH_GAIN,
+            Mode::ProbeBw => PACING_GAIN_CYCLE[self.cycle_index.get()],
+            Mode::ProbeRtt => 1.0,
+        }
+    }
+
+    fn cwnd_gain(&self) -> f64 {
+        match self.mode.get() {
+            Mode::Startup => HIGH_GAIN,
+            // The window rather than the pacing rate drains the queue when data isn't paced.
+            Mode::Drain | Mode::ProbeRtt => 1.0,
+            Mode::ProbeBw => CWND_GAIN,
+        }
+    }
+
+    fn min_pipe_cwnd(&self) -> u32 {
+        MIN_PIPE_SEGMENTS * self.mss
+    }
+
+    /// Returns `gain` times the estimated bandwidth-delay product, plus room for the segments
+    /// the receiver may hold back before acknowledging them.
+    fn inflight(&self, gain: f64) -> u32 {
+        let min_rtt = match self.min_rtt.get() {
+            Some((rtt, _)) => rtt,
+            None => return self.initial_cwnd,
+        };
+        let bdp = self.btl_bw() as f64 * min_rtt.as_secs_f64();
+        (gain * bdp) as u32 + 3 * self.mss
+    }
+
+    fn update_round(&self, sample: &RateSample) -> bool {
+        if sample.prior_delivered < self.next_round_delivered.get() {
+            return false;
+        }
+        self.next_round_delivered.set(sample.total_delivered);
+        self.round_count.set(self.round_count.get() + 1);
+        true
+    }
+
+    fn update_btl_bw(&self, sample: &RateSample) {
+        let interval = sample.interval.as_micros().max(1) as u64;
+        let bw = sample.delivered * 1_000_000 / interval;
+        let round = self.round_count.get();
+        let mut filter = self.btl_bw_filter.borrow_mut();
+        while filter.back().map_or(false, |&(_, b)| b <= bw) {
+            filter.pop_back();
+        }
+        filter.push_back((round, bw));
+        while filter
+            .front()
+            .map_or(false, |&(r, _)| r + BTL_BW_FILTER_LEN <= round)
+        {
+            filter.pop_front();
+        }
+    }
+
+    fn enter_probe_bw(&self, now: Instant) {
+        self.mode.set(Mode::ProbeBw);
+        // Start cruising rather than probing, as the queue just drained.
+        self.cycle_index.set(2);
+        self.cycle_stamp.set(Some(now));
+    }
+
+    fn check_cycle_phase(&self, sample: &RateSample) {
+        if self.mode.get() != Mode::ProbeBw {
+            return;
+        }
+        let min_rtt = self.min_rtt.get().map_or(Duration::ZERO, |(rtt, _)| rtt);
+        let full_length = self
+            .cycle_stamp
+            .get()
+            .map_or(true, |stamp| sample.now - stamp > min_rtt);
+        let prior_in_flight = sample.in_flight + sample.acked;
+        let gain = self.pacing_gain();
+        let next_phase = if gain > 1.0 {
+            full_length && prior_in_flight >= self.inflight(gain)
+        } else if gain < 1.0 {
+            full_length || prior_in_flight <= self.inflight(1.0)
+        } else {
+            full_length
+        };
+        if next_phase {
+            self.cycle_index
+                .set((self.cycle_index.get() + 1) % PACING_GAIN_CYCLE.len());
+            self.cycle_stamp.set(Some(sample.now));
+        }
+    }
+
+    fn check_full_pipe(&self, round_start: bool) {
+        if self.filled_pipe.get() || !round_start {
+            return;
+        }
+        let bw = self.btl_bw();
+        if bw as f64 >= self.full_bw.get() as f64 * FULL_BW_THRESHOLD {
+            self.full_bw.set(bw);
+            self.full_bw_count.set(0);
+            return;
+        }
+        self.full_bw_count.set(self.full_bw_count.get() + 1);
+        if self.full_bw_count.get() >= FULL_BW_COUNT {
+            self.filled_pipe.set(true);
+        }
+    }
+
+    fn check_drain(&self, sample: &RateSample) {
+        if self.mode.get() == Mode::Startup && self.filled_pipe.get() {
+            self.mode.set(Mode::Drain);
+        }
+        if self.mode.get() == Mode::Drain && sample.in_flight <= self.inflight(1.0) {
+            self.enter_probe_bw(sample.now);
+        }
+    }
+
+    fn update_min_rtt(&self, sample: &RateSample, round_start: bool) {
+        let now = sample.now;
+        let expired = self
+            .min_rtt
+            .get()
+            .map_or(false, |(_, stamp)| now - stamp > MIN_RTT_FILTER_LEN);
+        if expired
+            || self
+                .min_rtt
+                .get()
+                .map_or(true, |(rtt, _)| sample.rtt <= rtt)
+        {
+            self.min_rtt.set(Some((sample.rtt, now)));
+        }
+
+        if expired && self.mode.get() != Mode::ProbeRtt {
+            self.mode.set(Mode::ProbeRtt);
+            self.prior_cwnd.set(self.cwnd.get());
+            self.probe_rtt_done_stamp.set(None);
+        }
+        if self.mode.get() != Mode::ProbeRtt {
+            return;
+        }
+        match self.probe_rtt_done_stamp.get() {
+            None if sample.in_flight <= self.min_pipe_cwnd() => {
+                self.probe_rtt_done_stamp
+                    .set(Some(now + PROBE_RTT_DURATION));
+                self.probe_rtt_round_done.set(false);
+                self.next_round_delivered.set(sample.total_delivered);
+            }
+            None => (),
+            Some(done) => {
+                if round_start {
+                    self.probe_rtt_round_done.set(true);
+                }
+                if self.probe_rtt_round_done.get() && now > done {
+                    if let Some((rtt, _)) = self.min_rtt.get() {
+                        self.min_rtt.set(Some((rtt, now)));
+                    }
+                    self.cwnd.set(self.cwnd.get().max(self.prior_cwnd.get()));
+                    if self.filled_pipe.get() {
+                        self.enter_probe_bw(now);
+                    } else {
+                        self.mode.set(Mode::Startup);
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_pacing_rate(&self) {
+        let rate = (self.pacing_gain() * self.btl_bw() as f64) as u64;
+        if rate == 0 {
+            return;
+        }
+        // Until the pipe is full, the rate only grows.
+        if self.filled_pipe.get() || self.pacing_rate.get().map_or(true, |r| rate > r) {
+            self.pacing_rate.set(Some(rate));
+        }
+    }
+
+    fn set_cwnd(&self, sample: &RateSample) {
+        let target = self.inflight(self.cwnd_gain());
+        let mut cwnd = self.cwnd.get();
+        if self.filled_pipe.get() {
+            cwnd = (cwnd + sample.acked).min(target);
+        } else if cwnd < target || sample.total_delivered < self.initial_cwnd as u64 {
+            cwnd += sample.acked;
+        }
+        cwnd = cwnd.max(self.min_pipe_cwnd());
+        if self.mode.get() == Mode::ProbeRtt {
+            cwnd = cwnd.min(self.min_pipe_cwnd());
+        }
+        self.cwnd.set(cwnd);
+    }
+}
+
+//==============================================================================
+// Trait Implementations
+//==============================================================================
+
+impl<RT: Runtime> CongestionControl<RT> for Bbr {
+    fn new(
+        mss: usize,
+        _seq_no: SeqNumber,
+        _options: Option<Options>,
+    ) -> Box<dyn CongestionControl<RT>> {
+        // RFC 6928 initial window, which Startup grows from.
+        Box::new(Self::with_mss(mss.try_into().unwrap()))
+    }
+}
+
+impl<RT: Runtime> SlowStartCongestionAvoidance<RT> for Bbr {
+    fn get_cwnd(&self) -> u32 {
+        self.cwnd.get()
+    }
+    fn watch_cwnd(&self) -> (u32, WatchFuture<'_, u32>) {
+        self.cwnd.watch()
+    }
+
+    fn on_ack_received(
+        &self,
+        _rto: Duration,
+        base_seq_no: SeqNumber,
+        _sent_seq_no: SeqNumber,
+        ack_seq_no: SeqNumber,
+    ) {
+        // Losses don't shape the model, but duplicate ACKs still trigger fast retransmits.
+        if ack_seq_no != base_seq_no {
+            self.duplicate_ack_count.set(0);
+            return;
+        }
+        let duplicate_ack_count = self.duplicate_ack_count.get() + 1;
+        self.duplicate_ack_count.set(duplicate_ack_count);
+        if duplicate_ack_count == Self::DUP_ACK_THRESHOLD {
+            self.fast_retransmit_now.set(true);
+        }
+    }
+
+    fn on_rto(&self, _base_seq_no: SeqNumber) {
+        // Everything in flight is deemed lost: start over from a single segment, regrowing the
+        // window by the data acknowledged.
+        self.prior_cwnd.set(self.cwnd.get());
+        self.cwnd.set(self.mss);
+    }
+
+    fn on_rate_sample(&self, sample: &RateSample) {
+        let round_start = self.update_round(sample);
+        self.update_btl_bw(sample);
+        self.check_cycle_phase(sample);
+        self.check_full_pipe(round_start);
+        self.check_drain(sample);
+        self.update_min_rtt(sample, round_start);
+        self.set_pacing_rate();
+        self.set_cwnd(sample);
+    }
+
+    fn get_pacing_rate(&self) -> Option<u64> {
+        self.pacing_rate.get()
+    }
+}
+
+impl<RT: Runtime> FastRetransmitRecovery<RT> for Bbr {
+    fn get_duplicate_ack_count(&self) -> u32 {
+        self.duplicate_ack_count.get()
+    }
+
+    fn get_retransmit_now_flag(&self) -> bool {
+        self.fast_retransmit_now.get()
+    }
+    fn watch_retransmit_now_flag(&self) -> (bool, WatchFuture<'_, bool>) {
+        self.fast_retransmit_now.watch()
+    }
+
+    fn on_fast_retransmit(&self) {
+        self.fast_retransmit_now.set_without_notify(false);
+    }
+}
+
+impl<RT: Runtime> LimitedTransmit<RT> for Bbr {}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::super::delivery_rate::DeliveryRate, Bbr, Mode, SlowStartCongestionAvoidance,
+    };
+    use crate::test_helpers::TestRuntime;
+    use std::{
+        collections::VecDeque,
+        num::Wrapping,
+        time::{Duration, Instant},
+    };
+
+    const MSS: u32 = 1000;
+
+    /// Sender using BBR over a path with a bottleneck of `bw` bytes per second and a propagation
+    /// delay of `rtt`, sending whatever the congestion window allows as ACKs come back.
+    struct Simulation {
+        bbr: Bbr,
+        rate: DeliveryRate,
+        bw: u64,
+        rtt: Duration,
+        now: Instant,
+        sent: u32,
+        acked: u32,
+        /// When the bottleneck is done with the data sent so far.
+        bottleneck_free: Instant,
+        /// When each segment in flight gets acknowledged, and its end.
+        acks: VecDeque<(Instant, u32)>,
+    }
+
+    impl Simulation {
+        fn new(bw: u64, rtt: Duration) -> Self {
+            let now = Instant::now();
+            Self {
+                bbr: Bbr::with_mss(MSS),
+                rate: DeliveryRate::new(now),
+                bw,
+                rtt,
+                now,
+                sent: 0,
+                acked: 0,
+                bottleneck_free: now,
+                acks: VecDeque::new(),
+            }
+        }
+
+        fn cc(&self) -> &dyn SlowStartCongestionAvoidance<TestRuntime> {
+            &self.bbr
+        }
+
+        /// Runs the simulation for `duration`, calling `f` after every ACK.
+        fn run<F: FnMut(&Bbr)>(&mut self, duration: Duration, mut f: F) {
+            let end = self.now + duration;
+            loop {
+                while self.sent - self.acked + MSS <= self.cc().get_cwnd() {
+                    self.sent += MSS;
+                    self.rate.on_send(Wrapping(self.sent), self.now);
+                    let transmission = Duration::from_micros(MSS as u64 * 1_000_000 / self.bw);
+                    self.bottleneck_free = self.bottleneck_free.max(self.now) + transmission;
+                    self.acks
+                        .push_back((self.bottleneck_free + self.rtt, self.sent));
+                }
+                let (at, ack) = self.acks[0];
+                if at > end {
+                    self.now = end;
+                    return;
+                }
+                self.acks.pop_front();
+                self.now = at;
+                let acked = ack - self.acked;
+                self.acked = ack;
+                let in_flight = self.sent - ack;
+                if let Some(sample) = self.rate.on_ack(Wrapping(ack), acked, in_flight, at) {
+                    self.cc().on_rate_sample(&sample);
+                }
+                f(&self.bbr);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bbr_startup() {
+        // A 10 MB/s path with a 10 ms propagation delay has a 100 KB bandwidth-delay product.
+        let mut sim = Simulation::new(10_000_000, Duration::from_millis(10));
+        assert_eq!(sim.cc().get_pacing_rate(), None);
+        assert_eq!(sim.bbr.mode.get(), Mode::Startup);
+
+        // Startup finds the bandwidth in a few rounds, then drains the queue it built.
+        let mut modes = vec![Mode::Startup];
+        sim.run(Duration::from_secs(2), |bbr| {
+            if modes.last() != Some(&bbr.mode.get()) {
+                modes.push(bbr.mode.get());
+            }
+        });
+        assert_eq!(modes, vec![Mode::Startup, Mode::Drain, Mode::ProbeBw]);
+
+        let btl_bw = sim.bbr.btl_bw();
+        assert!((9_500_000..=10_500_000).contains(&btl_bw), "{}", btl_bw);
+        let (min_rtt, _) = sim.bbr.min_rtt.get().unwrap();
+        assert!(min_rtt < Duration::from_millis(11), "{:?}", min_rtt);
+        // The window is twice the product, and data is paced around the bandwidth.
+        let cwnd = sim.cc().get_cwnd();
+        assert!((190_000..=215_000).contains(&cwnd), "{}", cwnd);
+        let pacing_rate = sim.cc().get_pacing_rate().unwrap();
+        assert!(
+            (7_000_000..=13_000_000).contains(&pacing_rate),
+            "{}",
+            pacing_rate
+        );
+    }
+
+    #[test]
+    fn test_bbr_probe_rtt() {
+        let mut sim = Simulation::new(10_000_000, Duration::from_millis(10));
+        sim.run(Duration::from_secs(2), |_| ());
+        assert_eq!(sim.bbr.mode.get(), Mode::ProbeBw);
+
+        // Without a new smallest RTT for 10 s, the window drops to the minimum for a while so
+        // that the queue drains and the propagation delay gets measured again.
+        let mut min_cwnd = u32::MAX;
+        let mut probed_rtt = false;
+        sim.run(Duration::from_secs(10), |bbr| {
+            if bbr.mode.get() == Mode::ProbeRtt {
+                probed_rtt = true;
+                min_cwnd = min_cwnd.min(bbr.cwnd.get());
+            }
+        });
+        assert!(probed_rtt);
+        assert_eq!(min_cwnd, 4 * MSS);
+        assert_eq!(sim.bbr.mode.get(), Mode::ProbeBw);
+        let (_, stamp) = sim.bbr.min_rtt.get().unwrap();
+        assert!(sim.now - stamp < Duration::from_secs(10));
+        assert!(sim.cc().get_cwnd() > 4 * MSS);
+    }
+}
@@ -2,7 +2,7 @@
 // Licensed under the MIT license.
 
 use super::{
-    CongestionControl, FastRetransmitRecovery, LimitedTransmit, Options,
+    initial_cwnd, CongestionControl, FastRetransmitRecovery, LimitedTransmit, Options,
     SlowStartCongestionAvoidance,
 };
 use crate::runtime::Runtime;
@@ -52,11 +52,7 @@ impl<RT: Runtime> CongestionControl<RT> for Cubic {
     ) -> Box<dyn CongestionControl<RT>> {
         let mss: u32 = mss.try_into().unwrap();
         // The initial value of cwnd is set according to RFC5681, section 3.1, page 7
-        let initial_cwnd = match mss {
-            0..=1095 => 4 * mss,
-            1096..=2190 => 3 * mss,
-            _ => 2 * mss,
-        };
+        let initial_cwnd = initial_cwnd(mss);
 
         let options: Options = options.unwrap_or_default();
         let fast_convergence = options.get_bool("fast_convergence").unwrap_or(true);
@@ -284,6 +280,9 @@ impl<RT: Runtime> SlowStartCongestionAvoidance<RT> for Cubic {
     fn watch_cwnd(&self) -> (u32, WatchFuture<'_, u32>) {
         self.cwnd.watch()
     }
+    fn get_ssthresh(&self) -> u32 {
+        self.ssthresh.get()
+    }
 
     fn on_cwnd_check_before_send(&self) {
         let long_time_since_send =
@@ -339,6 +338,52 @@ impl<RT: Runtime> SlowStartCongestionAvoidance<RT> for Cubic {
         self.on_rto_ss_ca();
         self.on_rto_fast_recovery(base_seq_no);
     }
+
+    fn on_ecn_echo(&self) {
+        // RFC 8312, section 4.6: an ECN echo is a congestion event like a loss, after which we go
+        // straight back into congestion avoidance. Fast recovery has shrunk cwnd already.
+        if self.in_fast_recovery.get() {
+            return;
+        }
+        let cwnd = self.cwnd.get();
+        if self.fast_convergence {
+            self.fast_convergence();
+        } else {
+            self.w_max.set(cwnd);
+        }
+        let reduced_cwnd = max((cwnd as f32 * Self::BETA_CUBIC) as u32, 2 * self.mss);
+        self.ssthresh.set(reduced_cwnd);
+        self.cwnd.set(reduced_cwnd);
+        self.ca_start.set(Instant::now());
+        self.last_congestion_was_rto.set(false);
+    }
+
+    fn on_undo(&self, cwnd: u32, ssthresh: u32) {
+        // Nothing got lost: leave any recovery with the window as it was before, growing again
+        // from there.
+        self.ssthresh.set(max(self.ssthresh.get(), ssthresh));
+        self.cwnd.set(max(self.cwnd.get(), cwnd));
+        self.ca_start.set(Instant::now());
+        self.last_congestion_was_rto.set(false);
+        self.retransmitted_packets_in_flight.set(0);
+        self.in_fast_recovery.set(false);
+        self.fast_retransmit_now.set_without_notify(false);
+    }
+
+    fn on_cwnd_validation(&self, cwnd: u32, ssthresh: u32) {
+        // RFC 8312, section 5.8: the window shrinks while it goes unused, as for any algorithm.
+        // The cubic function starts a new epoch at the reduced window, or it would grow the
+        // window straight back to where it was.
+        if self.in_fast_recovery.get() {
+            return;
+        }
+        self.ssthresh.set(ssthresh);
+        self.cwnd.set(cwnd);
+        self.w_max.set(cwnd);
+        self.ca_start.set(Instant::now());
+        self.last_congestion_was_rto.set(false);
+        self.limited_transmit_cwnd_increase.set_without_notify(0);
+    }
 }
 
 impl<RT: Runtime> FastRetransmitRecovery<RT> for Cubic {
@@ -2,17 +2,55 @@
 // Licensed under the MIT license.
 
 use crate::{collections::watched::WatchFuture, protocols::tcp::SeqNumber, runtime::Runtime};
-use std::{fmt::Debug, time::Duration};
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant},
+};
 
+mod bbr;
 mod cubic;
+mod new_reno;
 mod none;
 mod options;
 pub use self::{
+    bbr::Bbr,
     cubic::Cubic,
+    new_reno::NewReno,
     none::None,
     options::{OptionValue, Options},
 };
 
+/// Delivery rate sample taken on an ACK of new data, measuring how fast the data sent since the
+/// newly acknowledged data left got delivered (draft-cheng-iccrg-delivery-rate-estimation).
+#[derive(Clone, Copy, Debug)]
+pub struct RateSample {
+    /// When the ACK arrived.
+    pub now: Instant,
+    /// Bytes delivered over `interval`.
+    pub delivered: u64,
+    /// Time over which `delivered` bytes got delivered.
+    pub interval: Duration,
+    /// Time from sending the latest newly acknowledged data to its acknowledgment.
+    pub rtt: Duration,
+    /// Bytes delivered over the connection when the latest newly acknowledged data was sent.
+    pub prior_delivered: u64,
+    /// Bytes delivered over the connection, including those of this ACK.
+    pub total_delivered: u64,
+    /// Bytes this ACK acknowledged.
+    pub acked: u32,
+    /// Bytes in flight once this ACK is processed.
+    pub in_flight: u32,
+}
+
+/// Initial congestion window for segments of `mss` bytes (RFC 5681, section 3.1).
+pub fn initial_cwnd(mss: u32) -> u32 {
+    match mss {
+        0..=1095 => 4 * mss,
+        1096..=2190 => 3 * mss,
+        _ => 2 * mss,
+    }
+}
+
 pub trait SlowStartCongestionAvoidance<RT: Runtime> {
     fn get_cwnd(&self) -> u32 {
         u32::MAX
@@ -20,6 +58,9 @@ pub trait SlowStartCongestionAvoidance<RT: Runtime> {
     fn watch_cwnd(&self) -> (u32, WatchFuture<'_, u32>) {
         (u32::MAX, WatchFuture::Pending)
     }
+    fn get_ssthresh(&self) -> u32 {
+        u32::MAX
+    }
 
     // Called immediately before the cwnd check is performed before data is sent
     fn on_cwnd_check_before_send(&self) {}
@@ -36,8 +77,28 @@ pub trait SlowStartCongestionAvoidance<RT: Runtime> {
     // Called immediately before retransmit after RTO
     fn on_rto(&self, _base_seq_no: SeqNumber) {}
 
+    // Called on an ACK echoing congestion experienced (RFC 3168), at most once per window of data
+    fn on_ecn_echo(&self) {}
+
+    // Called once duplicate SACKs showed every retransmission since the window shrank from `cwnd`
+    // and `ssthresh` for a loss to be needless, to restore them (RFC 2883, section 5)
+    fn on_undo(&self, _cwnd: u32, _ssthresh: u32) {}
+
+    // Called before new data goes out once the window went non-validated for a while, the
+    // connection idle or limited by the application, to shrink it to `cwnd` and raise ssthresh
+    // to `ssthresh` for it to grow back quickly (RFC 7661)
+    fn on_cwnd_validation(&self, _cwnd: u32, _ssthresh: u32) {}
+
     // Called immediately before a segment is sent for the 1st time
     fn on_send(&self, _rto: Duration, _num_sent_bytes: u32) {}
+
+    // Called on every ACK of new data that yields a delivery rate sample
+    fn on_rate_sample(&self, _sample: &RateSample) {}
+
+    // Rate in bytes per second at which data should be sent, if the algorithm paces it
+    fn get_pacing_rate(&self) -> Option<u64> {
+        None
+    }
 }
 
 pub trait FastRetransmitRecovery<RT: Runtime>
@@ -0,0 +1,368 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! NewReno congestion control: slow start and congestion avoidance (RFC 5681), with the fast
+//! recovery of RFC 6582.
+//!
+//! Three duplicate ACKs halve the congestion window and retransmit the first unacknowledged
+//! segment. Each further duplicate ACK means a segment left the network, so it inflates the window
+//! by one segment to keep the ACK clock going. An ACK of some but not all the data outstanding
+//! when the loss was detected reveals another loss, retransmitted right away without leaving
+//! recovery, which only ends once that data got acknowledged in full.
+
+use super::{
+    initial_cwnd, CongestionControl, FastRetransmitRecovery, LimitedTransmit, Options,
+    SlowStartCongestionAvoidance,
+};
+use crate::{
+    collections::watched::{WatchFuture, WatchedValue},
+    protocols::tcp::{seq_gt, SeqNumber},
+    runtime::Runtime,
+};
+use std::{
+    cell::Cell,
+    cmp::{max, min},
+    convert::TryInto,
+    fmt::Debug,
+    num::Wrapping,
+    time::Duration,
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+#[derive(Debug)]
+pub struct NewReno {
+    mss: u32,
+    cwnd: WatchedValue<u32>,
+    /// Size of the congestion window from which congestion avoidance takes over slow start.
+    ssthresh: Cell<u32>,
+    /// Bytes acknowledged in congestion avoidance since the window last grew.
+    bytes_acked: Cell<u32>,
+    /// Bytes in flight as of the latest send or ACK.
+    flight_size: Cell<u32>,
+
+    duplicate_ack_count: Cell<u32>,
+    fast_retransmit_now: WatchedValue<bool>,
+    in_fast_recovery: Cell<bool>,
+    /// Highest sequence number sent when the latest recovery or timeout started. Duplicate ACKs
+    /// not above it are for losses already dealt with.
+    recover: Cell<SeqNumber>,
+
+    limited_transmit_cwnd_increase: WatchedValue<u32>,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [NewReno].
+impl NewReno {
+    const DUP_ACK_THRESHOLD: u32 = 3;
+
+    /// Half the data in flight, but no less than two segments (RFC 5681, equation 4).
+    fn reduced_ssthresh(&self) -> u32 {
+        max(self.flight_size.get() / 2, 2 * self.mss)
+    }
+
+    fn on_dup_ack_received(&self, sent_seq_no: SeqNumber, ack_seq_no: SeqNumber) {
+        let duplicate_ack_count = self.duplicate_ack_count.get() + 1;
+        self.duplicate_ack_count.set(duplicate_ack_count);
+
+        if self.in_fast_recovery.get() {
+            // Another segment left the network.
+            self.cwnd.modify(|c| c + self.mss);
+        } else if duplicate_ack_count < Self::DUP_ACK_THRESHOLD {
+            self.limited_transmit_cwnd_increase
+                .modify(|ltci| ltci + self.mss);
+        } else if duplicate_ack_count == Self::DUP_ACK_THRESHOLD
+            && seq_gt(ack_seq_no, self.recover.get())
+        {
+            let ssthresh = self.reduced_ssthresh();
+            self.ssthresh.set(ssthresh);
+            // The three segments the duplicate ACKs stand for left the network.
+            self.cwnd.set(ssthresh + 3 * self.mss);
+            self.recover.set(sent_seq_no - Wrapping(1));
+            self.in_fast_recovery.set(true);
+            self.limited_transmit_cwnd_increase.set_without_notify(0);
+            self.fast_retransmit_now.set(true);
+        }
+    }
+
+    fn on_ack_received_fast_recovery(
+        &self,
+        base_seq_no: SeqNumber,
+        sent_seq_no: SeqNumber,
+        ack_seq_no: SeqNumber,
+    ) {
+        let Wrapping(bytes_acknowledged) = ack_seq_no - base_seq_no;
+        if seq_gt(ack_seq_no, self.recover.get()) {
+            // Full acknowledgment: deflate the window back and resume congestion avoidance.
+            let Wrapping(flight_size) = sent_seq_no - ack_seq_no;
+            self.cwnd.set(min(
+                self.ssthresh.get(),
+                max(flight_size, self.mss) + self.mss,
+            ));
+            self.bytes_acked.set(0);
+            self.in_fast_recovery.set(false);
+        } else {
+            // Partial acknowledgment: the next segment got lost too. Deflate the window by the
+            // data acknowledged, but let one more segment out for the one that arrived.
+            self.fast_retransmit_now.set(true);
+            let deflated = self.cwnd.get().saturating_sub(bytes_acknowledged);
+            if bytes_acknowledged >= self.mss {
+                self.cwnd.set(deflated + self.mss);
+            } else {
+                self.cwnd.set(deflated);
+            }
+        }
+    }
+
+    fn on_ack_received_ss_ca(&self, base_seq_no: SeqNumber, ack_seq_no: SeqNumber) {
+        let Wrapping(bytes_acknowledged) = ack_seq_no - base_seq_no;
+        let cwnd = self.cwnd.get();
+        if cwnd < self.ssthresh.get() {
+            // Slow start
+            self.cwnd.modify(|c| c + min(bytes_acknowledged, self.mss));
+        } else {
+            // Congestion avoidance: one segment per window acknowledged (RFC 5681, section 3.1).
+            let bytes_acked = self.bytes_acked.get() + bytes_acknowledged;
+            if bytes_acked >= cwnd {
+                self.bytes_acked.set(bytes_acked - cwnd);
+                self.cwnd.modify(|c| c + self.mss);
+            } else {
+                self.bytes_acked.set(bytes_acked);
+            }
+        }
+    }
+}
+
+//==============================================================================
+// Trait Implementations
+//==============================================================================
+
+impl<RT: Runtime> CongestionControl<RT> for NewReno {
+    fn new(
+        mss: usize,
+        seq_no: SeqNumber,
+        _options: Option<Options>,
+    ) -> Box<dyn CongestionControl<RT>> {
+        let mss: u32 = mss.try_into().unwrap();
+        Box::new(Self {
+            mss,
+            cwnd: WatchedValue::new(initial_cwnd(mss)),
+            ssthresh: Cell::new(u32::MAX),
+            bytes_acked: Cell::new(0),
+            flight_size: Cell::new(0),
+            duplicate_ack_count: Cell::new(0),
+            fast_retransmit_now: WatchedValue::new(false),
+            in_fast_recovery: Cell::new(false),
+            recover: Cell::new(seq_no),
+            limited_transmit_cwnd_increase: WatchedValue::new(0),
+        })
+    }
+}
+
+impl<RT: Runtime> SlowStartCongestionAvoidance<RT> for NewReno {
+    fn get_cwnd(&self) -> u32 {
+        self.cwnd.get()
+    }
+    fn watch_cwnd(&self) -> (u32, WatchFuture<'_, u32>) {
+        self.cwnd.watch()
+    }
+    fn get_ssthresh(&self) -> u32 {
+        self.ssthresh.get()
+    }
+
+    fn on_send(&self, _rto: Duration, num_sent_bytes: u32) {
+        self.flight_size
+            .set(max(self.flight_size.get(), num_sent_bytes));
+        self.limited_transmit_cwnd_increase.set_without_notify(
+            self.limited_transmit_cwnd_increase
+                .get()
+                .saturating_sub(self.mss),
+        );
+    }
+
+    fn on_ack_received(
+        &self,
+        _rto: Duration,
+        base_seq_no: SeqNumber,
+        sent_seq_no: SeqNumber,
+        ack_seq_no: SeqNumber,
+    ) {
+        self.flight_size.set((sent_seq_no - ack_seq_no).0);
+        if ack_seq_no == base_seq_no {
+            self.on_dup_ack_received(sent_seq_no, ack_seq_no);
+            return;
+        }
+        self.duplicate_ack_count.set(0);
+        self.limited_transmit_cwnd_increase.set_without_notify(0);
+        if self.in_fast_recovery.get() {
+            self.on_ack_received_fast_recovery(base_seq_no, sent_seq_no, ack_seq_no);
+        } else {
+            self.on_ack_received_ss_ca(base_seq_no, ack_seq_no);
+        }
+    }
+
+    fn on_rto(&self, base_seq_no: SeqNumber) {
+        // RFC 5681, section 3.1: back to slow start from a single segment, and out of any recovery.
+        // Duplicate ACKs for the data sent so far don't start another one (RFC 6582, section 4).
+        self.ssthresh.set(self.reduced_ssthresh());
+        self.cwnd.set(self.mss);
+        self.bytes_acked.set(0);
+        self.duplicate_ack_count.set(0);
+        self.in_fast_recovery.set(false);
+        self.recover
+            .set(base_seq_no + Wrapping(self.flight_size.get()) - Wrapping(1));
+    }
+
+    fn on_ecn_echo(&self) {
+        // RFC 3168, section 6.1.2: shrink the window as for a loss, minus the retransmission. A
+        // recovery under way shrank it already.
+        if self.in_fast_recovery.get() {
+            return;
+        }
+        let ssthresh = self.reduced_ssthresh();
+        self.ssthresh.set(ssthresh);
+        self.cwnd.set(ssthresh);
+        self.bytes_acked.set(0);
+    }
+
+    fn on_undo(&self, cwnd: u32, ssthresh: u32) {
+        // Nothing got lost: leave any recovery with the window as it was before.
+        self.ssthresh.set(max(self.ssthresh.get(), ssthresh));
+        self.cwnd.set(max(self.cwnd.get(), cwnd));
+        self.bytes_acked.set(0);
+        self.in_fast_recovery.set(false);
+        self.fast_retransmit_now.set_without_notify(false);
+    }
+
+    fn on_cwnd_validation(&self, cwnd: u32, ssthresh: u32) {
+        if self.in_fast_recovery.get() {
+            return;
+        }
+        self.ssthresh.set(ssthresh);
+        self.cwnd.set(cwnd);
+        self.bytes_acked.set(0);
+    }
+}
+
+impl<RT: Runtime> FastRetransmitRecovery<RT> for NewReno {
+    fn get_duplicate_ack_count(&self) -> u32 {
+        self.duplicate_ack_count.get()
+    }
+
+    fn get_retransmit_now_flag(&self) -> bool {
+        self.fast_retransmit_now.get()
+    }
+    fn watch_retransmit_now_flag(&self) -> (bool, WatchFuture<'_, bool>) {
+        self.fast_retransmit_now.watch()
+    }
+
+    fn on_fast_retransmit(&self) {
+        self.fast_retransmit_now.set_without_notify(false);
+    }
+
+    fn on_base_seq_no_wraparound(&self) {
+        self.recover.set(Wrapping(0));
+    }
+}
+
+impl<RT: Runtime> LimitedTransmit<RT> for NewReno {
+    fn get_limited_transmit_cwnd_increase(&self) -> u32 {
+        self.limited_transmit_cwnd_increase.get()
+    }
+    fn watch_limited_transmit_cwnd_increase(&self) -> (u32, WatchFuture<'_, u32>) {
+        self.limited_transmit_cwnd_increase.watch()
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{super::CongestionControl, NewReno};
+    use crate::test_helpers::TestRuntime;
+    use std::{num::Wrapping, time::Duration};
+
+    const MSS: u32 = 1000;
+    const RTO: Duration = Duration::from_secs(1);
+
+    /// Congestion control of a connection whose data starts at 0, right after the SYN.
+    fn new_reno() -> Box<dyn CongestionControl<TestRuntime>> {
+        let iss = Wrapping(u32::MAX);
+        <NewReno as CongestionControl<TestRuntime>>::new(MSS as usize, iss, None)
+    }
+
+    #[test]
+    fn test_new_reno_slow_start() {
+        let cc = new_reno();
+        assert_eq!(cc.get_cwnd(), 4 * MSS);
+        for i in 0..4 {
+            cc.on_ack_received(
+                RTO,
+                Wrapping(i * MSS),
+                Wrapping(4 * MSS),
+                Wrapping((i + 1) * MSS),
+            );
+        }
+        assert_eq!(cc.get_cwnd(), 8 * MSS);
+    }
+
+    #[test]
+    fn test_new_reno_fast_recovery() {
+        let cc = new_reno();
+        // Ten segments in flight, the first of which got lost.
+        let sent = Wrapping(10 * MSS);
+        cc.on_ack_received(RTO, Wrapping(0), sent, Wrapping(0));
+        cc.on_ack_received(RTO, Wrapping(0), sent, Wrapping(0));
+        assert!(!cc.get_retransmit_now_flag());
+        assert_eq!(cc.get_limited_transmit_cwnd_increase(), 2 * MSS);
+        cc.on_ack_received(RTO, Wrapping(0), sent, Wrapping(0));
+        assert!(cc.get_retransmit_now_flag());
+        cc.on_fast_retransmit();
+        assert_eq!(cc.get_cwnd(), 5 * MSS + 3 * MSS);
+
+        // Further duplicate ACKs inflate the window.
+        cc.on_ack_received(RTO, Wrapping(0), sent, Wrapping(0));
+        assert_eq!(cc.get_cwnd(), 9 * MSS);
+
+        // A partial ACK retransmits the next hole and deflates the window by the data acknowledged.
+        cc.on_ack_received(RTO, Wrapping(0), sent, Wrapping(3 * MSS));
+        assert!(cc.get_retransmit_now_flag());
+        cc.on_fast_retransmit();
+        assert_eq!(cc.get_cwnd(), 9 * MSS - 3 * MSS + MSS);
+
+        // The full ACK ends recovery with the window at ssthresh.
+        cc.on_ack_received(RTO, Wrapping(3 * MSS), sent, sent);
+        assert!(!cc.get_retransmit_now_flag());
+        assert_eq!(cc.get_cwnd(), 2 * MSS);
+
+        // Below ssthresh, slow start takes over again.
+        cc.on_ack_received(RTO, sent, Wrapping(12 * MSS), Wrapping(12 * MSS));
+        assert_eq!(cc.get_cwnd(), 3 * MSS);
+    }
+
+    #[test]
+    fn test_new_reno_no_recovery_below_recover() {
+        let cc = new_reno();
+        let sent = Wrapping(10 * MSS);
+        for _ in 0..3 {
+            cc.on_ack_received(RTO, Wrapping(0), sent, Wrapping(0));
+        }
+        cc.on_fast_retransmit();
+        cc.on_rto(Wrapping(0));
+        assert_eq!(cc.get_cwnd(), MSS);
+
+        // Duplicate ACKs for data sent before the timeout don't start another recovery.
+        cc.on_ack_received(RTO, Wrapping(0), sent, Wrapping(MSS));
+        for _ in 0..3 {
+            cc.on_ack_received(RTO, Wrapping(MSS), sent, Wrapping(MSS));
+        }
+        assert!(!cc.get_retransmit_now_flag());
+    }
+}
@@ -5,23 +5,156 @@ use super::{
     CongestionControl, FastRetransmitRecovery, LimitedTransmit, Options,
     SlowStartCongestionAvoidance,
 };
-use crate::{protocols::tcp::SeqNumber, runtime::Runtime};
-use std::fmt::Debug;
+use crate::{
+    collections::watched::{WatchFuture, WatchedValue},
+    protocols::tcp::{seq_gt, SeqNumber},
+    runtime::Runtime,
+};
+use std::{cell::Cell, fmt::Debug, num::Wrapping, time::Duration};
 
-// Implementation of congestion control which does nothing.
+// Implementation of congestion control which never limits the sending rate. Losses still get
+// repaired without waiting for the RTO: the third duplicate ACK retransmits the oldest
+// unacknowledged segment, and until the data sent by then is acknowledged, every partial ACK
+// retransmits the next one (RFC 6582).
 #[derive(Debug)]
-pub struct None {}
+pub struct None {
+    duplicate_ack_count: Cell<u32>,
+    fast_retransmit_now: WatchedValue<bool>,
+    in_fast_recovery: Cell<bool>,
+    /// Highest sequence number sent when the latest recovery or timeout started.
+    recover: Cell<SeqNumber>,
+    /// Highest sequence number sent as of the latest ACK.
+    sent_seq_no: Cell<SeqNumber>,
+}
+
+impl None {
+    const DUP_ACK_THRESHOLD: u32 = 3;
+}
 
 impl<RT: Runtime> CongestionControl<RT> for None {
     fn new(
         _mss: usize,
-        _seq_no: SeqNumber,
+        seq_no: SeqNumber,
         _options: Option<Options>,
     ) -> Box<dyn CongestionControl<RT>> {
-        Box::new(Self {})
+        Box::new(Self {
+            duplicate_ack_count: Cell::new(0),
+            fast_retransmit_now: WatchedValue::new(false),
+            in_fast_recovery: Cell::new(false),
+            recover: Cell::new(seq_no),
+            sent_seq_no: Cell::new(seq_no),
+        })
+    }
+}
+
+impl<RT: Runtime> SlowStartCongestionAvoidance<RT> for None {
+    fn on_ack_received(
+        &self,
+        _rto: Duration,
+        base_seq_no: SeqNumber,
+        sent_seq_no: SeqNumber,
+        ack_seq_no: SeqNumber,
+    ) {
+        self.sent_seq_no.set(sent_seq_no);
+        let above_recover = seq_gt(ack_seq_no, self.recover.get());
+        if ack_seq_no == base_seq_no {
+            let duplicate_ack_count = self.duplicate_ack_count.get() + 1;
+            self.duplicate_ack_count.set(duplicate_ack_count);
+            if duplicate_ack_count == Self::DUP_ACK_THRESHOLD
+                && !self.in_fast_recovery.get()
+                && above_recover
+            {
+                self.recover.set(sent_seq_no - Wrapping(1));
+                self.in_fast_recovery.set(true);
+                self.fast_retransmit_now.set(true);
+            }
+            return;
+        }
+        self.duplicate_ack_count.set(0);
+        if self.in_fast_recovery.get() {
+            if above_recover {
+                self.in_fast_recovery.set(false);
+            } else {
+                // Partial acknowledgment: the next segment got lost too.
+                self.fast_retransmit_now.set(true);
+            }
+        }
+    }
+
+    fn on_rto(&self, _base_seq_no: SeqNumber) {
+        // Duplicate ACKs for the data sent so far don't start a recovery (RFC 6582, section 4).
+        self.duplicate_ack_count.set(0);
+        self.in_fast_recovery.set(false);
+        self.recover.set(self.sent_seq_no.get() - Wrapping(1));
+    }
+}
+
+impl<RT: Runtime> FastRetransmitRecovery<RT> for None {
+    fn get_duplicate_ack_count(&self) -> u32 {
+        self.duplicate_ack_count.get()
+    }
+
+    fn get_retransmit_now_flag(&self) -> bool {
+        self.fast_retransmit_now.get()
+    }
+    fn watch_retransmit_now_flag(&self) -> (bool, WatchFuture<'_, bool>) {
+        self.fast_retransmit_now.watch()
+    }
+
+    fn on_fast_retransmit(&self) {
+        self.fast_retransmit_now.set_without_notify(false);
+    }
+
+    fn on_base_seq_no_wraparound(&self) {
+        self.recover.set(Wrapping(0));
     }
 }
 
-impl<RT: Runtime> SlowStartCongestionAvoidance<RT> for None {}
-impl<RT: Runtime> FastRetransmitRecovery<RT> for None {}
 impl<RT: Runtime> LimitedTransmit<RT> for None {}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{super::CongestionControl, None};
+    use crate::test_helpers::TestRuntime;
+    use std::{num::Wrapping, time::Duration};
+
+    const MSS: u32 = 1000;
+    const RTO: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_none_fast_retransmit() {
+        let iss = Wrapping(u32::MAX);
+        let cc = <None as CongestionControl<TestRuntime>>::new(MSS as usize, iss, Option::None);
+        assert_eq!(cc.get_cwnd(), u32::MAX);
+
+        // Ten segments in flight, the first of which got lost.
+        let sent = Wrapping(10 * MSS);
+        cc.on_ack_received(RTO, Wrapping(0), sent, Wrapping(0));
+        cc.on_ack_received(RTO, Wrapping(0), sent, Wrapping(0));
+        assert!(!cc.get_retransmit_now_flag());
+        cc.on_ack_received(RTO, Wrapping(0), sent, Wrapping(0));
+        assert!(cc.get_retransmit_now_flag());
+        cc.on_fast_retransmit();
+
+        // Further duplicate ACKs don't retransmit again, but a partial ACK does.
+        cc.on_ack_received(RTO, Wrapping(0), sent, Wrapping(0));
+        assert!(!cc.get_retransmit_now_flag());
+        cc.on_ack_received(RTO, Wrapping(0), sent, Wrapping(3 * MSS));
+        assert!(cc.get_retransmit_now_flag());
+        cc.on_fast_retransmit();
+
+        // The full ACK ends recovery, and duplicates of it start another.
+        cc.on_ack_received(RTO, Wrapping(3 * MSS), sent, sent);
+        assert!(!cc.get_retransmit_now_flag());
+        let more = Wrapping(20 * MSS);
+        for _ in 0..3 {
+            cc.on_ack_received(RTO, sent, more, sent);
+        }
+        assert!(cc.get_retransmit_now_flag());
+        assert_eq!(cc.get_cwnd(), u32::MAX);
+    }
+}
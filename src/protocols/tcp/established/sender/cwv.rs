@@ -0,0 +1,159 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::tcp::SeqNumber;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// How long a non-validated congestion window is kept for the application to use it again
+/// (RFC 7661, section 4.3).
+pub const NON_VALIDATED_PERIOD: Duration = Duration::from_secs(300);
+
+/// Shortest pipeACK sampling period (RFC 7661, section 4.2).
+const MIN_SAMPLING_PERIOD: Duration = Duration::from_secs(1);
+
+/// Congestion window validation (RFC 7661): the data acknowledged per round trip, pipeACK, tells
+/// whether the window is in use. Less than half of it in use leaves it non-validated, and a
+/// non-validated phase lasting a whole non-validated period shrinks it.
+#[derive(Debug, Default)]
+pub struct CwndValidator {
+    /// Start of the round trip being measured, and the data acknowledged when it started.
+    round: Option<(Instant, SeqNumber)>,
+    /// Data acknowledged per round trip measured, and when, oldest first.
+    samples: VecDeque<(Instant, u32)>,
+    /// Whether any round trip got measured. Until then pipeACK is undefined; afterwards, with
+    /// every sample expired, it is zero.
+    measured: bool,
+    /// When data last got acknowledged.
+    last_ack: Option<Instant>,
+    /// Start of the non-validated phase under way, if any.
+    nvp_start: Option<Instant>,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [CwndValidator].
+impl CwndValidator {
+    /// Records an ACK at `now` of the data up to `ack_seq_no`, taking a pipeACK sample once a
+    /// round trip `rtt` long got measured. No round trip is measured during loss recovery
+    /// (section 4.4), and one cut short by it starts over.
+    pub fn on_ack(&mut self, ack_seq_no: SeqNumber, recovering: bool, rtt: Duration, now: Instant) {
+        self.last_ack = Some(now);
+        match self.round {
+            _ if recovering => self.round = None,
+            Some((start, base)) if now.saturating_duration_since(start) >= rtt => {
+                self.samples.push_back((now, (ack_seq_no - base).0));
+                self.measured = true;
+                self.round = Some((now, ack_seq_no));
+            }
+            Some(_) => (),
+            None => self.round = Some((now, ack_seq_no)),
+        }
+    }
+
+    /// Returns pipeACK at `now`: the largest sample of the sampling period, the longest of three
+    /// round trips `rtt` and a second, if any round trip got measured.
+    pub fn pipe_ack(&mut self, rtt: Duration, now: Instant) -> Option<u32> {
+        let period = (rtt * 3).max(MIN_SAMPLING_PERIOD);
+        while let Some(&(at, _)) = self.samples.front() {
+            if now.saturating_duration_since(at) <= period {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        if !self.measured {
+            return None;
+        }
+        Some(self.samples.iter().map(|&(_, sample)| sample).max().unwrap_or(0))
+    }
+
+    /// Returns how many non-validated periods elapsed for the window `cwnd` since last asked,
+    /// as data goes out at `now`. A window that pipeACK shows at least half in use is
+    /// validated; a phase of an `idle` connection started with the last ACK.
+    pub fn on_send(&mut self, cwnd: u32, idle: bool, rtt: Duration, now: Instant) -> u32 {
+        if self.pipe_ack(rtt, now).map_or(true, |pipe_ack| pipe_ack >= cwnd / 2) {
+            self.nvp_start = None;
+            return 0;
+        }
+        let start = match self.nvp_start {
+            Some(start) => start,
+            None if idle => self.last_ack.unwrap_or(now),
+            None => now,
+        };
+        let elapsed = now.saturating_duration_since(start);
+        let periods = (elapsed.as_secs() / NON_VALIDATED_PERIOD.as_secs()) as u32;
+        self.nvp_start = Some(start + NON_VALIDATED_PERIOD * periods);
+        periods
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{CwndValidator, NON_VALIDATED_PERIOD};
+    use std::{
+        num::Wrapping,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_cwnd_validator() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let rtt = Duration::from_millis(100);
+        let mut validator = CwndValidator::default();
+
+        // Until a round trip got measured, the window is validated.
+        assert_eq!(validator.pipe_ack(rtt, ms(0)), None);
+        assert_eq!(validator.on_send(50000, false, rtt, ms(0)), 0);
+
+        // A round trip acknowledging 20000 bytes validates windows of up to 40000.
+        validator.on_ack(Wrapping(0), false, rtt, ms(0));
+        validator.on_ack(Wrapping(10000), false, rtt, ms(50));
+        validator.on_ack(Wrapping(20000), false, rtt, ms(100));
+        assert_eq!(validator.pipe_ack(rtt, ms(100)), Some(20000));
+        assert_eq!(validator.on_send(40000, false, rtt, ms(100)), 0);
+        assert_eq!(validator.on_send(50000, false, rtt, ms(100)), 0);
+
+        // Samples expire after the sampling period, leaving pipeACK at zero.
+        assert_eq!(validator.pipe_ack(rtt, ms(1100)), Some(20000));
+        assert_eq!(validator.pipe_ack(rtt, ms(1200)), Some(0));
+
+        // The window, non-validated, shrinks once a whole period went by.
+        assert_eq!(validator.on_send(40000, false, rtt, ms(1200) + NON_VALIDATED_PERIOD / 2), 0);
+        assert_eq!(validator.on_send(40000, false, rtt, ms(1200) + NON_VALIDATED_PERIOD), 1);
+    }
+
+    #[test]
+    fn test_cwnd_validator_idle() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let rtt = Duration::from_millis(100);
+        let mut validator = CwndValidator::default();
+
+        validator.on_ack(Wrapping(0), false, rtt, ms(0));
+        validator.on_ack(Wrapping(20000), false, rtt, ms(100));
+
+        // Idle, the phase started with the last ACK, and a long idle spans several periods.
+        assert_eq!(validator.on_send(20000, true, rtt, ms(100) + NON_VALIDATED_PERIOD * 2), 2);
+        assert_eq!(validator.on_send(20000, false, rtt, ms(200) + NON_VALIDATED_PERIOD * 3), 1);
+
+        // Loss recovery starts the round trip measured over.
+        validator.on_ack(Wrapping(20000), false, rtt, ms(200) + NON_VALIDATED_PERIOD * 2);
+        validator.on_ack(Wrapping(30000), true, rtt, ms(300) + NON_VALIDATED_PERIOD * 2);
+        validator.on_ack(Wrapping(40000), false, rtt, ms(350) + NON_VALIDATED_PERIOD * 2);
+        validator.on_ack(Wrapping(45000), false, rtt, ms(450) + NON_VALIDATED_PERIOD * 2);
+        assert_eq!(validator.pipe_ack(rtt, ms(450) + NON_VALIDATED_PERIOD * 2), Some(5000));
+    }
+}
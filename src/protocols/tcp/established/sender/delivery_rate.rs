@@ -0,0 +1,158 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::congestion_ctrl::RateSample;
+use crate::protocols::tcp::{seq_lt, SeqNumber};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// State of the connection when some data was sent, kept until the data gets acknowledged.
+#[derive(Debug)]
+struct SendRecord {
+    /// End of the data.
+    end: SeqNumber,
+    sent_at: Instant,
+    /// Bytes delivered so far, and when the latest of them were.
+    delivered: u64,
+    delivered_at: Instant,
+    /// When the first data in flight at the time was sent.
+    first_sent_at: Instant,
+}
+
+/// Delivery rate estimation (draft-cheng-iccrg-delivery-rate-estimation): every segment sent
+/// records how much data had been delivered by then, so that its acknowledgment tells how much
+/// got delivered since, and over which interval.
+#[derive(Debug)]
+pub struct DeliveryRate {
+    /// Bytes delivered over the connection, and when the latest of them were.
+    delivered: u64,
+    delivered_at: Instant,
+    /// When the first data of the current flight was sent.
+    first_sent_at: Instant,
+    /// Data sent but not yet acknowledged, oldest first.
+    in_flight: VecDeque<SendRecord>,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [DeliveryRate].
+impl DeliveryRate {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            delivered: 0,
+            delivered_at: now,
+            first_sent_at: now,
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    /// Records data up to `end` sent for the first time at `now`.
+    pub fn on_send(&mut self, end: SeqNumber, now: Instant) {
+        if self.in_flight.is_empty() {
+            // The flight starts anew, so the time spent idle doesn't count.
+            self.first_sent_at = now;
+            self.delivered_at = now;
+        }
+        self.in_flight.push_back(SendRecord {
+            end,
+            sent_at: now,
+            delivered: self.delivered,
+            delivered_at: self.delivered_at,
+            first_sent_at: self.first_sent_at,
+        });
+    }
+
+    /// Records the acknowledgment at `now` of `acked` bytes, up to `ack_seq_no`, leaving
+    /// `in_flight` bytes in flight. Returns a sample unless the ACK covers none of the data sent
+    /// in full.
+    pub fn on_ack(
+        &mut self,
+        ack_seq_no: SeqNumber,
+        acked: u32,
+        in_flight: u32,
+        now: Instant,
+    ) -> Option<RateSample> {
+        self.delivered += acked as u64;
+        self.delivered_at = now;
+
+        // The sample is of the data sent last among the data acknowledged.
+        let mut latest = None;
+        while let Some(record) = self.in_flight.front() {
+            if seq_lt(ack_seq_no, record.end) {
+                break;
+            }
+            latest = self.in_flight.pop_front();
+        }
+        let record = latest?;
+        self.first_sent_at = record.sent_at;
+
+        // Data can't be delivered faster than it was sent, so the longer of both phases counts.
+        let send_elapsed = record.sent_at - record.first_sent_at;
+        let ack_elapsed = now - record.delivered_at;
+        let interval = send_elapsed.max(ack_elapsed);
+        if interval == Duration::ZERO {
+            return None;
+        }
+        Some(RateSample {
+            now,
+            delivered: self.delivered - record.delivered,
+            interval,
+            rtt: now - record.sent_at,
+            prior_delivered: record.delivered,
+            total_delivered: self.delivered,
+            acked,
+            in_flight,
+        })
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::DeliveryRate;
+    use std::{
+        num::Wrapping,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_delivery_rate() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut rate = DeliveryRate::new(start);
+
+        // Two segments sent back to back, acknowledged 10 ms later.
+        rate.on_send(Wrapping(1000), ms(0));
+        rate.on_send(Wrapping(2000), ms(0));
+        assert!(rate.on_ack(Wrapping(500), 500, 1500, ms(10)).is_none());
+        let sample = rate.on_ack(Wrapping(1000), 500, 1000, ms(10)).unwrap();
+        assert_eq!(sample.delivered, 1000);
+        assert_eq!(sample.interval, Duration::from_millis(10));
+        assert_eq!(sample.rtt, Duration::from_millis(10));
+        assert_eq!(sample.prior_delivered, 0);
+
+        // The second one measures the time between both ACKs.
+        let sample = rate.on_ack(Wrapping(2000), 1000, 0, ms(12)).unwrap();
+        assert_eq!(sample.delivered, 2000);
+        assert_eq!(sample.interval, Duration::from_millis(12));
+        assert_eq!(sample.total_delivered, 2000);
+
+        // A flight sent after some idle time doesn't count it.
+        rate.on_send(Wrapping(3000), ms(100));
+        let sample = rate.on_ack(Wrapping(3000), 1000, 0, ms(105)).unwrap();
+        assert_eq!(sample.delivered, 1000);
+        assert_eq!(sample.interval, Duration::from_millis(5));
+        assert_eq!(sample.prior_delivered, 2000);
+    }
+}
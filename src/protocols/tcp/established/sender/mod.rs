@@ -2,20 +2,30 @@
 // Licensed under the MIT license.
 
 pub mod congestion_ctrl;
+mod cwv;
+mod delivery_rate;
 mod rto;
+mod rtt_sampler;
+mod scoreboard;
+mod send_buffer;
 
 use super::ControlBlock;
 use crate::{
     collections::watched::{WatchFuture, WatchedValue},
     fail::Fail,
-    protocols::tcp::SeqNumber,
+    protocols::tcp::{seq_gt, seq_lt, SeqNumber},
     runtime::{Runtime, RuntimeBuf},
 };
 use congestion_ctrl as cc;
+use cwv::CwndValidator;
+use delivery_rate::DeliveryRate;
 use rto::RtoCalculator;
+use rtt_sampler::RttSampler;
+use scoreboard::Scoreboard;
+use send_buffer::SendBuffer;
 use std::{
     boxed::Box,
-    cell::RefCell,
+    cell::{Cell, RefCell},
     collections::VecDeque,
     convert::TryInto,
     fmt,
@@ -23,14 +33,40 @@ use std::{
     time::{Duration, Instant},
 };
 
-pub struct UnackedSegment<RT: Runtime> {
-    pub bytes: RT::Buf,
-    // Set to `None` on retransmission to implement Karn's algorithm.
-    pub initial_tx: Option<Instant>,
+/// Pacing rates as a share of the congestion window per smoothed round trip, in percent, in slow
+/// start and in congestion avoidance. Slow start paces faster for the window to keep growing, as
+/// Linux does.
+const PACING_SS_RATIO: u64 = 200;
+const PACING_CA_RATIO: u64 = 120;
+
+/// Shrinking of the congestion window for a loss, which duplicate selective acknowledgments may
+/// show to have been needless (RFC 2883, section 5).
+#[derive(Clone, Copy, Debug)]
+struct Undo {
+    /// Oldest unacknowledged data and next data to send when the window first shrank. Losses
+    /// detected before the latter got acknowledged belong to the same episode.
+    marker: SeqNumber,
+    recover: SeqNumber,
+    /// Congestion window and slow start threshold from before the window shrank.
+    cwnd: u32,
+    ssthresh: u32,
+    /// Segments retransmitted since, not yet reported received twice.
+    retransmits: u32,
 }
 
-/// Hard limit for unsent queue.
-const UNSENT_QUEUE_CUTOFF: usize = 1024;
+/// Forward RTO-recovery under way (RFC 5682), telling from the ACKs after a retransmission timeout
+/// whether the data got delayed rather than lost.
+#[derive(Clone, Copy, Debug)]
+struct Frto {
+    /// Highest sequence number sent when the timer expired.
+    recover: SeqNumber,
+    /// Congestion window and slow start threshold from before the timeout.
+    cwnd: u32,
+    ssthresh: u32,
+    /// End of the new data let out regardless of the congestion window, once the first ACK
+    /// after the timeout acknowledged some of the data outstanding.
+    new_data_limit: Option<SeqNumber>,
+}
 
 pub struct Sender<RT: Runtime> {
     // TODO: Just use Figure 5 from RFC 793 here.
@@ -43,19 +79,70 @@ pub struct Sender<RT: Runtime> {
     //       acknowledged        unacknowledged     ^        unsent
     //
     base_seq_no: WatchedValue<SeqNumber>,
-    unacked_queue: RefCell<VecDeque<UnackedSegment<RT>>>,
+    /// Data from `base_seq_no` to `sent_seq_no`.
+    unacked: RefCell<SendBuffer>,
+    /// Unacknowledged data the remote reported holding, which is not retransmitted on loss.
+    sacked: RefCell<Scoreboard>,
+    /// End of the data past the oldest unacknowledged segment retransmitted to fill holes
+    /// reported by selective acknowledgments, so that each hole is filled once.
+    high_rxt: Cell<SeqNumber>,
+    /// Data timed for an RTT sample, under Karn's algorithm.
+    rtt_sampler: RefCell<RttSampler>,
+    /// Delivery rate estimation, feeding the congestion control.
+    delivery_rate: RefCell<DeliveryRate>,
     sent_seq_no: WatchedValue<SeqNumber>,
+    /// Data pushed but not sent yet.
     unsent_queue: RefCell<VecDeque<RT::Buf>>,
+    /// Data pushed after the unsent queue that small pushes append to rather than queueing one
+    /// buffer each, until a segment gets cut out of it.
+    unsent_tail: RefCell<Vec<u8>>,
     unsent_seq_no: WatchedValue<SeqNumber>,
 
     window_size: WatchedValue<u32>,
     // RFC 1323: Number of bits to shift advertised window, defaults to zero.
     window_scale: u8,
 
-    mss: usize,
+    /// Most data a segment carries: the MSS of the remote, bounded by ours and by the path MTU,
+    /// and less the options every segment carries.
+    mss: Cell<usize>,
+    /// Most data a segment carries regardless of the path MTU, as agreed on during the handshake.
+    max_mss: usize,
+    /// MSS the remote advertised during the handshake.
+    remote_mss: usize,
 
     retransmit_deadline: WatchedValue<Option<Instant>>,
     rto: RefCell<RtoCalculator>,
+    /// Retransmission timeouts since data was last acknowledged.
+    retransmit_timeouts: Cell<usize>,
+    /// Largest round-trip time sampled so far.
+    max_rtt: Cell<Option<Duration>>,
+
+    /// Hard limit for unsent queue.
+    max_unsent_segments: Cell<usize>,
+    /// Bytes unsent or unacknowledged beyond which pushes fail, if bounded.
+    send_buffer_size: Cell<Option<usize>>,
+    /// Whether segments smaller than the MSS go out while data is unacknowledged. Otherwise they
+    /// wait for the data to be acknowledged, or for enough data to fill one (RFC 896).
+    nodelay: WatchedValue<bool>,
+    /// Whether new data goes out spaced at the pacing rate, and when the next segment may.
+    pacing: Cell<bool>,
+    next_send: Cell<Option<Instant>>,
+
+    /// Highest sequence number sent when the congestion window last shrank for an ECN echo.
+    /// Echoes acknowledging no more than it are of the same congestion (RFC 3168, section 6.1.2).
+    ecn_recover: Cell<SeqNumber>,
+    /// Whether the congestion window shrank for an ECN echo since the last new data went out,
+    /// which tells the remote so with CWR.
+    cwr_pending: Cell<bool>,
+    /// Latest shrinking of the congestion window for a loss, while it may still get undone.
+    undo: Cell<Option<Undo>>,
+    /// Whether retransmission timeouts go through forward RTO-recovery, and where it stands.
+    frto: Cell<bool>,
+    frto_state: Cell<Option<Frto>>,
+    /// Whether the congestion window shrinks while it goes unused (RFC 7661), and how much of
+    /// it is in use.
+    cwnd_validation: Cell<bool>,
+    cwnd_validator: RefCell<CwndValidator>,
 
     congestion_ctrl: Box<dyn cc::CongestionControl<RT>>,
 }
@@ -69,6 +156,8 @@ impl<RT: Runtime> fmt::Debug for Sender<RT> {
             .field("window_size", &self.window_size)
             .field("window_scale", &self.window_scale)
             .field("mss", &self.mss)
+            .field("max_mss", &self.max_mss)
+            .field("remote_mss", &self.remote_mss)
             .field("retransmit_deadline", &self.retransmit_deadline)
             .field("rto", &self.rto)
             .finish()
@@ -81,29 +170,72 @@ impl<RT: Runtime> Sender<RT> {
         window_size: u32,
         window_scale: u8,
         mss: usize,
+        remote_mss: usize,
+        rto_bounds: (Duration, Duration),
+        max_unsent_segments: usize,
+        send_buffer_size: Option<usize>,
         cc_constructor: cc::CongestionControlConstructor<RT>,
         congestion_control_options: Option<cc::Options>,
+        nodelay: bool,
+        pacing: bool,
+        frto: bool,
+        cwnd_validation: bool,
+        now: Instant,
     ) -> Self {
         Self {
             base_seq_no: WatchedValue::new(seq_no),
-            unacked_queue: RefCell::new(VecDeque::new()),
+            unacked: RefCell::new(SendBuffer::default()),
+            sacked: RefCell::new(Scoreboard::default()),
+            high_rxt: Cell::new(seq_no),
+            rtt_sampler: RefCell::new(RttSampler::default()),
+            delivery_rate: RefCell::new(DeliveryRate::new(now)),
             sent_seq_no: WatchedValue::new(seq_no),
             unsent_queue: RefCell::new(VecDeque::new()),
+            unsent_tail: RefCell::new(Vec::new()),
             unsent_seq_no: WatchedValue::new(seq_no),
 
             window_size: WatchedValue::new(window_size),
             window_scale,
-            mss,
+            mss: Cell::new(mss),
+            max_mss: mss,
+            remote_mss,
 
             retransmit_deadline: WatchedValue::new(None),
-            rto: RefCell::new(RtoCalculator::new()),
+            rto: RefCell::new(RtoCalculator::new(rto_bounds.0, rto_bounds.1)),
+            retransmit_timeouts: Cell::new(0),
+            max_rtt: Cell::new(None),
+            max_unsent_segments: Cell::new(max_unsent_segments),
+            send_buffer_size: Cell::new(send_buffer_size),
+            nodelay: WatchedValue::new(nodelay),
+            pacing: Cell::new(pacing),
+            next_send: Cell::new(None),
+
+            ecn_recover: Cell::new(seq_no),
+            cwr_pending: Cell::new(false),
+            undo: Cell::new(None),
+            frto: Cell::new(frto),
+            frto_state: Cell::new(None),
+            cwnd_validation: Cell::new(cwnd_validation),
+            cwnd_validator: RefCell::new(CwndValidator::default()),
 
             congestion_ctrl: cc_constructor(mss, seq_no, congestion_control_options),
         }
     }
 
     pub fn get_mss(&self) -> usize {
-        self.mss
+        self.mss.get()
+    }
+
+    pub fn get_max_mss(&self) -> usize {
+        self.max_mss
+    }
+
+    pub fn set_mss(&self, value: usize) {
+        self.mss.set(value)
+    }
+
+    pub fn get_window_scale(&self) -> u8 {
+        self.window_scale
     }
 
     pub fn get_window_size(&self) -> (u32, WatchFuture<u32>) {
@@ -134,12 +266,201 @@ impl<RT: Runtime> Sender<RT> {
         self.retransmit_deadline.set(when);
     }
 
-    pub fn pop_unacked_segment(&self) -> Option<UnackedSegment<RT>> {
-        self.unacked_queue.borrow_mut().pop_front()
+    /// Keeps `bytes`, just sent after the data sent before, until they get acknowledged. Times
+    /// them for an RTT sample unless other data is timed already.
+    pub fn push_unacked(&self, bytes: &[u8], now: Instant) {
+        let mut unacked = self.unacked.borrow_mut();
+        unacked.push(bytes);
+        let end = self.base_seq_no.get() + Wrapping(unacked.len() as u32);
+        self.rtt_sampler.borrow_mut().on_send(end, now);
+        self.delivery_rate.borrow_mut().on_send(end, now);
+    }
+
+    /// Returns unacknowledged data to retransmit, with the sequence number it starts at, in
+    /// segments of up to `max_len` bytes and `max_bytes` in all. The oldest unacknowledged data
+    /// comes first; then the holes before data the remote reported holding, unless retransmitted
+    /// already. Data the remote holds is never part of a segment.
+    pub fn retransmit_unacked(
+        &self,
+        max_len: usize,
+        max_bytes: usize,
+    ) -> Vec<(SeqNumber, RT::Buf)> {
+        let unacked = self.unacked.borrow();
+        let sacked = self.sacked.borrow();
+        let mut segments = vec![];
+        if unacked.is_empty() {
+            return segments;
+        }
+        let base_seq_no = self.base_seq_no.get();
+        let high_rxt = (self.high_rxt.get() - base_seq_no).0 as usize;
+        let mut budget = max_bytes;
+        let mut offset = 0;
+        loop {
+            let (start, end) = sacked.next_hole(offset as u32);
+            let end = match end {
+                Some(end) => end as usize,
+                None if segments.is_empty() => unacked.len(),
+                None => break,
+            };
+            let start = start as usize;
+            let len = end
+                .min(unacked.len())
+                .saturating_sub(start)
+                .min(max_len)
+                .min(budget);
+            if len == 0 {
+                break;
+            }
+            let bytes = match unacked.slices(start, len) {
+                (bytes, []) => RT::Buf::from_slice(bytes),
+                _ => RT::Buf::from_slice(&unacked.to_vec(start, len)),
+            };
+            segments.push((base_seq_no + Wrapping(start as u32), bytes));
+            budget -= len;
+            offset = start + len;
+            if segments.len() == 1 && high_rxt <= unacked.len() {
+                offset = offset.max(high_rxt);
+            }
+        }
+        if segments.len() > 1 {
+            self.high_rxt.set(base_seq_no + Wrapping(offset as u32));
+        }
+        if let Some((seq_no, bytes)) = segments.last() {
+            self.mark_retransmitted(*seq_no + Wrapping(bytes.len() as u32));
+        }
+        segments
+    }
+
+    /// Records that the data up to `end` went out again, so that its ACKs yield no RTT sample
+    /// unless their timestamps do (Karn's algorithm).
+    pub fn mark_retransmitted(&self, end: SeqNumber) {
+        self.rtt_sampler.borrow_mut().on_retransmit(end);
+    }
+
+    /// Records a block of data the remote reported holding in a selective acknowledgment.
+    pub fn remote_sack(&self, begin: SeqNumber, end: SeqNumber) -> Result<(), Fail> {
+        let base_seq_no = self.base_seq_no.get();
+        let Wrapping(outstanding) = self.sent_seq_no.get() - base_seq_no;
+        let Wrapping(begin) = begin - base_seq_no;
+        let Wrapping(end) = end - base_seq_no;
+        if begin >= end || end > outstanding {
+            return Err(Fail::Ignored {
+                details: "SACK block is outside of send window",
+            });
+        }
+        self.sacked.borrow_mut().insert(begin, end);
+        Ok(())
+    }
+
+    /// Forgets what the remote reported holding, which it may discard until it acknowledges it
+    /// cumulatively, so that a retransmission timeout starts over from the oldest data.
+    pub fn clear_sacks(&self) {
+        self.sacked.borrow_mut().clear();
+        self.high_rxt.set(self.base_seq_no.get());
+    }
+
+    /// Notes that the congestion window shrank for a loss from `cwnd` and `ssthresh`, unless an
+    /// earlier loss of the same episode shrank it already.
+    fn on_cwnd_reduction(&self, cwnd: u32, ssthresh: u32) {
+        let base_seq_no = self.base_seq_no.get();
+        if let Some(undo) = self.undo.get() {
+            if seq_gt(undo.recover, base_seq_no) {
+                return;
+            }
+        }
+        self.undo.set(Some(Undo {
+            marker: base_seq_no,
+            recover: self.sent_seq_no.get(),
+            cwnd,
+            ssthresh,
+            retransmits: 0,
+        }));
+    }
+
+    /// Counts a retransmitted segment against the latest shrinking of the congestion window.
+    pub fn on_retransmit(&self) {
+        if let Some(mut undo) = self.undo.get() {
+            undo.retransmits += 1;
+            self.undo.set(Some(undo));
+        }
+    }
+
+    /// Handles a duplicate selective acknowledgment of the data from `begin` to `end`, which the
+    /// remote received twice. Once every segment retransmitted since the congestion window shrank
+    /// got reported so, the loss was not one and the window is restored. Returns whether it was.
+    pub fn remote_dsack(&self, begin: SeqNumber, end: SeqNumber) -> bool {
+        let mut undo = match self.undo.get() {
+            Some(undo) if undo.retransmits > 0 => undo,
+            _ => return false,
+        };
+        let Wrapping(len) = end - begin;
+        if seq_lt(begin, undo.marker) || len == 0 || len >= 1 << 31 {
+            return false;
+        }
+        let mss = self.mss.get() as u32;
+        undo.retransmits = undo.retransmits.saturating_sub((len + mss - 1) / mss);
+        if undo.retransmits > 0 {
+            self.undo.set(Some(undo));
+            return false;
+        }
+        self.undo.set(None);
+        self.congestion_ctrl.on_undo(undo.cwnd, undo.ssthresh);
+        true
+    }
+
+    pub fn set_frto(&self, value: bool) {
+        self.frto.set(value);
+        if !value {
+            self.frto_state.set(None);
+        }
     }
 
-    pub fn push_unacked_segment(&self, segment: UnackedSegment<RT>) {
-        self.unacked_queue.borrow_mut().push_back(segment)
+    /// Takes an ACK of `ack_seq_no` through forward RTO-recovery, if under way, before it gets
+    /// processed (RFC 5682, section 2.1). The first ACK after a timeout acknowledging some but not
+    /// all the data outstanding then lets new data out rather than retransmitting more; a second
+    /// ACK of new data shows that the timeout was spurious, and restores the congestion window.
+    /// A duplicate ACK, or no new data to send, leaves the recovery to the timeout. Returns
+    /// whether the timeout was spurious.
+    pub fn frto_on_ack(&self, ack_seq_no: SeqNumber, duplicate: bool) -> bool {
+        let mut frto = match self.frto_state.get() {
+            Some(frto) => frto,
+            None => return false,
+        };
+        let base_seq_no = self.base_seq_no.get();
+        let sent_seq_no = self.sent_seq_no.get();
+        let Wrapping(bytes_acknowledged) = ack_seq_no - base_seq_no;
+        if bytes_acknowledged > (sent_seq_no - base_seq_no).0
+            || (bytes_acknowledged == 0 && !duplicate)
+        {
+            return false;
+        }
+        self.frto_state.set(None);
+        if bytes_acknowledged == 0 {
+            return false;
+        }
+        if frto.new_data_limit.is_some() {
+            self.congestion_ctrl.on_undo(frto.cwnd, frto.ssthresh);
+            self.undo.set(None);
+            return true;
+        }
+        if seq_lt(ack_seq_no, frto.recover) && self.unsent_len() > 0 {
+            let limit = sent_seq_no + Wrapping(2 * self.mss.get() as u32);
+            frto.new_data_limit = Some(limit);
+            self.frto_state.set(Some(frto));
+        }
+        false
+    }
+
+    /// Returns how much data may be in flight regardless of the congestion window, which forward
+    /// RTO-recovery allows for up to two segments of new data.
+    pub fn frto_window(&self) -> u32 {
+        match self.frto_state.get() {
+            Some(Frto {
+                new_data_limit: Some(limit),
+                ..
+            }) => (limit - self.base_seq_no.get()).0,
+            _ => 0,
+        }
     }
 
     pub fn rto_estimate(&self) -> Duration {
@@ -147,9 +468,177 @@ impl<RT: Runtime> Sender<RT> {
     }
 
     pub fn rto_record_failure(&self) {
+        self.retransmit_timeouts
+            .set(self.retransmit_timeouts.get() + 1);
         self.rto.borrow_mut().record_failure()
     }
 
+    /// Returns how many times in a row the retransmission timer expired without the remote
+    /// acknowledging any data in between.
+    pub fn retransmit_timeouts(&self) -> usize {
+        self.retransmit_timeouts.get()
+    }
+
+    /// Shrinks the congestion window for an ACK of `ack_seq_no` echoing congestion experienced,
+    /// once per window of data. Returns whether it did.
+    pub fn remote_ecn_echo(&self, ack_seq_no: SeqNumber) -> bool {
+        if !seq_gt(ack_seq_no, self.ecn_recover.get()) {
+            return false;
+        }
+        self.congestion_ctrl.on_ecn_echo();
+        // Congestion the network reported is not to be undone.
+        self.undo.set(None);
+        self.ecn_recover.set(self.sent_seq_no.get());
+        self.cwr_pending.set(true);
+        true
+    }
+
+    /// Returns whether the next segment of new data carries CWR, which only the first one after
+    /// the congestion window shrank for an ECN echo does.
+    pub fn take_cwr(&self) -> bool {
+        self.cwr_pending.replace(false)
+    }
+
+    /// Returns the unacknowledged and unsent data, in sequence order.
+    pub fn export_queues(&self) -> (Vec<RT::Buf>, Vec<RT::Buf>) {
+        let unacked = self.unacked.borrow();
+        let unacked = if unacked.is_empty() {
+            vec![]
+        } else {
+            vec![RT::Buf::from_slice(&unacked.to_vec(0, unacked.len()))]
+        };
+        let mut unsent: Vec<_> = self.unsent_queue.borrow().iter().cloned().collect();
+        let tail = self.unsent_tail.borrow();
+        if !tail.is_empty() {
+            unsent.push(RT::Buf::from_slice(&tail));
+        }
+        (unacked, unsent)
+    }
+
+    /// Refills the queues of a freshly created sender. Restored segments are treated as
+    /// retransmissions, so they don't contribute RTT samples.
+    pub fn import_queues(&self, unacked: Vec<RT::Buf>, unsent: Vec<RT::Buf>, now: Instant) {
+        let mut sent_seq_no = self.base_seq_no.get();
+        for bytes in unacked {
+            sent_seq_no += Wrapping(bytes.len() as u32);
+            self.unacked.borrow_mut().push(&bytes);
+        }
+        let mut unsent_seq_no = sent_seq_no;
+        for bytes in unsent {
+            unsent_seq_no += Wrapping(bytes.len() as u32);
+            self.unsent_queue.borrow_mut().push_back(bytes);
+        }
+        self.sent_seq_no.set(sent_seq_no);
+        self.unsent_seq_no.set(unsent_seq_no);
+        if !self.unacked.borrow().is_empty() {
+            let deadline = now + self.rto.borrow().estimate();
+            self.retransmit_deadline.set(Some(deadline));
+        }
+    }
+
+    /// Drops the data waiting to be sent or acknowledged, which will never go out again.
+    pub fn discard_queued(&self) {
+        *self.unacked.borrow_mut() = SendBuffer::default();
+        self.unsent_queue.borrow_mut().clear();
+        self.unsent_tail.borrow_mut().clear();
+        self.retransmit_deadline.set(None);
+    }
+
+    pub fn set_rto_bounds(&self, lbound: Duration, ubound: Duration) {
+        self.rto.borrow_mut().set_bounds(lbound, ubound)
+    }
+
+    pub fn set_max_unsent_segments(&self, value: usize) {
+        self.max_unsent_segments.set(value)
+    }
+
+    pub fn set_send_buffer_size(&self, value: Option<usize>) {
+        self.send_buffer_size.set(value)
+    }
+
+    /// Returns whether a push would be taken: the data unsent or unacknowledged leaves room in the
+    /// send buffer, if bounded, and the unsent queue isn't full. Data waiting on a window the
+    /// remote shut leaves no room either, as more would only pile up behind it.
+    pub fn has_send_room(&self) -> bool {
+        let Wrapping(queued) = self.unsent_seq_no.get() - self.base_seq_no.get();
+        let buffer_room = self
+            .send_buffer_size
+            .get()
+            .map_or(true, |size| (queued as usize) < size);
+        let unsent = self.unsent_len();
+        let queue_room = unsent <= self.max_unsent_segments.get();
+        let window_open = unsent == 0 || self.window_size.get() > 0;
+        buffer_room && queue_room && window_open
+    }
+
+    /// Returns how many bytes a push would take now: none unless [Sender::has_send_room], and
+    /// otherwise what is left of the send buffer, if bounded and not empty.
+    pub fn send_room(&self) -> usize {
+        if !self.has_send_room() {
+            return 0;
+        }
+        let Wrapping(queued) = self.unsent_seq_no.get() - self.base_seq_no.get();
+        match self.send_buffer_size.get() {
+            Some(size) if queued > 0 => size - queued as usize,
+            _ => u32::MAX as usize,
+        }
+    }
+
+    pub fn get_nodelay(&self) -> (bool, WatchFuture<bool>) {
+        self.nodelay.watch()
+    }
+
+    pub fn set_nodelay(&self, value: bool) {
+        self.nodelay.set(value)
+    }
+
+    pub fn set_pacing(&self, value: bool) {
+        self.pacing.set(value);
+        if !value {
+            self.next_send.set(None);
+        }
+    }
+
+    /// Returns when the next segment of new data may go out, if pacing holds it back until then.
+    pub fn pacing_deadline(&self, now: Instant) -> Option<Instant> {
+        self.next_send.get().filter(|&t| t > now)
+    }
+
+    /// Spaces the next segment of new data out from one of `len` bytes sent at `now`. Without a
+    /// rate to pace at, such as before the first round trip got sampled, the next one goes out
+    /// at once.
+    pub fn on_paced_send(&self, len: usize, now: Instant) {
+        if !self.pacing.get() {
+            return;
+        }
+        let next_send = match self.paced_rate() {
+            Some(rate) if rate > 0 => {
+                Some(now + Duration::from_nanos(len as u64 * 1_000_000_000 / rate))
+            }
+            _ => None,
+        };
+        self.next_send.set(next_send);
+    }
+
+    /// Rate in bytes per second that new data goes out at when paced: that of the congestion
+    /// control if it paces data, or else a share of the congestion window per round trip.
+    fn paced_rate(&self) -> Option<u64> {
+        if let Some(rate) = self.congestion_ctrl.get_pacing_rate() {
+            return Some(rate);
+        }
+        let cwnd = self.congestion_ctrl.get_cwnd();
+        let srtt = self.rto.borrow().srtt()?.as_micros() as u64;
+        if cwnd == u32::MAX || srtt == 0 {
+            return None;
+        }
+        let ratio = if cwnd < self.congestion_ctrl.get_ssthresh() / 2 {
+            PACING_SS_RATIO
+        } else {
+            PACING_CA_RATIO
+        };
+        Some(cwnd as u64 * ratio * 10_000 / srtt)
+    }
+
     pub fn send(&self, buf: RT::Buf, cb: &ControlBlock<RT>) -> Result<(), Fail> {
         let buf_len: u32 = buf.len().try_into().map_err(|_| Fail::Ignored {
             details: "Buffer too large",
@@ -160,19 +649,28 @@ impl<RT: Runtime> Sender<RT> {
         let sent_seq = self.sent_seq_no.get();
         let Wrapping(sent_data) = sent_seq - base_seq;
 
+        self.check_send_buffer(buf.len())?;
+
         // Fast path: Try to send the data immediately.
         let in_flight_after_send = sent_data + buf_len;
 
         // Before we get cwnd for the check, we prompt it to shrink it if the connection has been idle
-        self.congestion_ctrl.on_cwnd_check_before_send();
+        let now = cb.rt().now();
+        self.congestion_ctrl_on_cwnd_check_before_send(now);
         let cwnd = self.congestion_ctrl.get_cwnd();
         // The limited transmit algorithm can increase the effective size of cwnd by up to 2MSS
         let effective_cwnd = cwnd + self.congestion_ctrl.get_limited_transmit_cwnd_increase();
 
-        if self.unsent_queue.borrow().len() == 0 {
+        // Nagle's algorithm: a small segment only goes out at once if nothing is in flight.
+        let nagle_allows = self.nodelay.get() || sent_data == 0 || buf.len() >= self.mss.get();
+
+        // Buffers larger than one segment get split up by the background sender.
+        if self.unsent_len() == 0 && buf.len() <= self.mss.get() {
             if win_sz > 0
                 && win_sz >= in_flight_after_send
                 && effective_cwnd >= in_flight_after_send
+                && nagle_allows
+                && self.pacing_deadline(now).is_none()
             {
                 if let Some(remote_link_addr) = cb.arp().try_query(cb.get_remote().address()) {
                     // This hook is primarily intended to record the last time we sent data, so we can later tell if the connection has been idle
@@ -182,39 +680,118 @@ impl<RT: Runtime> Sender<RT> {
 
                     let mut header = cb.tcp_header();
                     header.seq_num = sent_seq;
-                    cb.emit(header, buf.clone(), remote_link_addr);
+                    cb.emit_new_data(header, buf.clone(), remote_link_addr);
 
                     self.unsent_seq_no.modify(|s| s + Wrapping(buf_len));
                     self.sent_seq_no.modify(|s| s + Wrapping(buf_len));
-                    let unacked_segment = UnackedSegment {
-                        bytes: buf,
-                        initial_tx: Some(cb.rt().now()),
-                    };
-                    self.unacked_queue.borrow_mut().push_back(unacked_segment);
+                    self.push_unacked(&buf, now);
+                    self.on_paced_send(buf.len(), now);
                     if self.retransmit_deadline.get().is_none() {
                         let rto = self.rto.borrow().estimate();
-                        self.retransmit_deadline.set(Some(cb.rt().now() + rto));
+                        self.retransmit_deadline.set(Some(now + rto));
                     }
                     return Ok(());
                 }
             }
         }
 
-        // Too fast.
-        if self.unsent_queue.borrow().len() > UNSENT_QUEUE_CUTOFF {
+        // Slow path: Delegating sending the data to background processing.
+        self.queue_unsent(buf)
+    }
+
+    /// Queues `bufs` for sending as if pushed as one, all or none of them. The background sender
+    /// cuts segments out of them, copying only the buffers smaller than a segment together, so
+    /// that an application pushing a header and a payload doesn't have to concatenate them.
+    pub fn send_vectored(&self, bufs: &[RT::Buf]) -> Result<(), Fail> {
+        let len: usize = bufs.iter().map(|b| b.len()).sum();
+        if len > u32::MAX as usize {
+            return Err(Fail::Ignored {
+                details: "Buffer too large",
+            });
+        }
+        self.check_send_buffer(len)?;
+        // Too fast, should every buffer take up an entry of the queue.
+        if self.unsent_len() + bufs.len() > self.max_unsent_segments.get() + 1 {
             return Err(Fail::ResourceBusy {
                 details: "too many packets to send",
             });
         }
+        for buf in bufs.iter().filter(|b| !b.is_empty()) {
+            self.queue_unsent(buf.clone())?;
+        }
+        Ok(())
+    }
 
-        // Slow path: Delegating sending the data to background processing.
-        self.unsent_queue.borrow_mut().push_back(buf);
+    /// Fails unless the send buffer, if bounded, has room for `len` more bytes. A push on an
+    /// empty queue goes through however large, so that it can't wait forever.
+    fn check_send_buffer(&self, len: usize) -> Result<(), Fail> {
+        if let Some(size) = self.send_buffer_size.get() {
+            let Wrapping(queued) = self.unsent_seq_no.get() - self.base_seq_no.get();
+            if queued > 0 && queued as usize + len > size {
+                return Err(Fail::ResourceBusy {
+                    details: "send buffer full",
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues `buf` for the background sender. A buffer smaller than the MSS gets appended to
+    /// the unsent tail if it fits below the MSS there, so that small pushes leave in full-sized
+    /// segments, Nagle's algorithm or not. Their bytes get copied twice: into the tail, and into
+    /// a buffer once the tail is frozen.
+    fn queue_unsent(&self, buf: RT::Buf) -> Result<(), Fail> {
+        let buf_len = buf.len() as u32;
+        let mss = self.mss.get();
+        let small = buf.len() < mss;
+        let tail_len = self.unsent_tail.borrow().len();
+        if !small || tail_len == 0 || tail_len + buf.len() > mss {
+            // Too fast.
+            if self.unsent_len() > self.max_unsent_segments.get() {
+                return Err(Fail::ResourceBusy {
+                    details: "too many packets to send",
+                });
+            }
+            self.freeze_unsent_tail();
+        }
+        if small {
+            self.unsent_tail.borrow_mut().extend_from_slice(&buf);
+        } else {
+            self.unsent_queue.borrow_mut().push_back(buf);
+        }
         self.unsent_seq_no.modify(|s| s + Wrapping(buf_len));
 
         Ok(())
     }
 
-    pub fn remote_ack(&self, ack_seq_no: SeqNumber, now: Instant) -> Result<(), Fail> {
+    /// Returns the number of buffers of unsent data, the unsent tail counting as one.
+    fn unsent_len(&self) -> usize {
+        self.unsent_queue.borrow().len() + usize::from(!self.unsent_tail.borrow().is_empty())
+    }
+
+    /// Moves the unsent tail to the back of the unsent queue, for segments to be cut out of it,
+    /// copying it into a buffer.
+    fn freeze_unsent_tail(&self) {
+        let mut tail = self.unsent_tail.borrow_mut();
+        if !tail.is_empty() {
+            self.unsent_queue
+                .borrow_mut()
+                .push_back(RT::Buf::from_slice(&tail));
+            tail.clear();
+        }
+    }
+
+    /// Processes the cumulative acknowledgment `ack_seq_no`, which the echoed timestamp of the
+    /// segment carrying it timed as `rtt` if it had one. Of the ACKs of no new data, only those
+    /// that are `duplicate` as RFC 5681 defines it reach congestion control, which counts them
+    /// towards a fast retransmission.
+    pub fn remote_ack(
+        &self,
+        ack_seq_no: SeqNumber,
+        duplicate: bool,
+        now: Instant,
+        rtt: Option<Duration>,
+    ) -> Result<(), Fail> {
         let base_seq_no = self.base_seq_no.get();
         let sent_seq_no = self.sent_seq_no.get();
 
@@ -227,13 +804,22 @@ impl<RT: Runtime> Sender<RT> {
             });
         }
 
+        if bytes_acknowledged == Wrapping(0) && !duplicate {
+            return Ok(());
+        }
         let rto: Duration = self.current_rto();
+        let cwnd = self.congestion_ctrl.get_cwnd();
+        let ssthresh = self.congestion_ctrl.get_ssthresh();
         self.congestion_ctrl
             .on_ack_received(rto, base_seq_no, sent_seq_no, ack_seq_no);
+        if self.congestion_ctrl.get_ssthresh() != ssthresh {
+            self.on_cwnd_reduction(cwnd, ssthresh);
+        }
         if bytes_acknowledged == Wrapping(0) {
             return Ok(());
         }
 
+        self.retransmit_timeouts.set(0);
         if ack_seq_no == sent_seq_no {
             // If we've acknowledged all sent data, turn off the retransmit timer.
             self.retransmit_deadline.set(None);
@@ -243,27 +829,42 @@ impl<RT: Runtime> Sender<RT> {
             self.retransmit_deadline.set(Some(deadline));
         }
 
-        // TODO: Do acks need to be on segment boundaries? How does this interact with repacketization?
-        let mut bytes_remaining = bytes_acknowledged.0 as usize;
-        while let Some(segment) = self.unacked_queue.borrow_mut().pop_front() {
-            if segment.bytes.len() > bytes_remaining {
-                // TODO: We need to close the connection in this case.
-                return Err(Fail::Ignored {
-                    details: "ACK isn't on segment boundary",
-                });
-            }
-            bytes_remaining -= segment.bytes.len();
-
-            // Add sample for RTO if not a retransmission
-            // TODO: TCP timestamp support.
-            if let Some(initial_tx) = segment.initial_tx {
-                self.rto.borrow_mut().add_sample(now - initial_tx);
-            }
-            if bytes_remaining == 0 {
-                break;
+        self.unacked
+            .borrow_mut()
+            .trim(bytes_acknowledged.0 as usize);
+        self.sacked.borrow_mut().advance(bytes_acknowledged.0);
+
+        // Add sample for RTO once the timed data is acknowledged, unless the echoed timestamp
+        // of the ACK timed it already.
+        let rtt_sample = self
+            .rtt_sampler
+            .borrow_mut()
+            .on_ack(base_seq_no, ack_seq_no, rtt, now);
+        if let Some(rtt) = rtt_sample {
+            self.rto.borrow_mut().add_sample(rtt);
+            if self.max_rtt.get().map_or(true, |max| rtt > max) {
+                self.max_rtt.set(Some(rtt));
             }
         }
+        let recovering = self
+            .undo
+            .get()
+            .map_or(false, |undo| seq_gt(undo.recover, ack_seq_no));
+        let srtt = self.srtt().unwrap_or(rto);
+        self.cwnd_validator
+            .borrow_mut()
+            .on_ack(ack_seq_no, recovering, srtt, now);
         self.base_seq_no.modify(|b| b + bytes_acknowledged);
+        let in_flight = (sent_seq_no - ack_seq_no).0;
+        let sample = self.delivery_rate.borrow_mut().on_ack(
+            ack_seq_no,
+            bytes_acknowledged.0,
+            in_flight,
+            now,
+        );
+        if let Some(sample) = sample {
+            self.congestion_ctrl.on_rate_sample(&sample);
+        }
         let new_base_seq_no = self.base_seq_no.get();
         if new_base_seq_no < base_seq_no {
             // We've wrapped around, and so we need to do some bookkeeping
@@ -274,6 +875,7 @@ impl<RT: Runtime> Sender<RT> {
     }
 
     pub fn pop_one_unsent_byte(&self) -> Option<RT::Buf> {
+        self.freeze_unsent_tail();
         let mut queue = self.unsent_queue.borrow_mut();
 
         let buf = queue.front_mut()?;
@@ -289,11 +891,29 @@ impl<RT: Runtime> Sender<RT> {
 
     pub fn pop_unsent(&self, max_bytes: usize) -> Option<RT::Buf> {
         // TODO: Use a scatter/gather array to coalesce multiple buffers into a single segment.
+        self.freeze_unsent_tail();
         let mut unsent_queue = self.unsent_queue.borrow_mut();
         let mut buf = unsent_queue.pop_front()?;
         let buf_len = buf.len();
 
-        if buf_len > max_bytes {
+        if buf_len < max_bytes && !unsent_queue.is_empty() {
+            // Coalesce the buffers queued after a small one into one segment.
+            let mut bytes = buf.to_vec();
+            while let Some(mut next) = unsent_queue.pop_front() {
+                let room = max_bytes - bytes.len();
+                if next.len() > room {
+                    bytes.extend_from_slice(&next[..room]);
+                    next.adjust(room);
+                    unsent_queue.push_front(next);
+                    break;
+                }
+                bytes.extend_from_slice(&next);
+                if bytes.len() == max_bytes {
+                    break;
+                }
+            }
+            buf = RT::Buf::from_slice(&bytes);
+        } else if buf_len > max_bytes {
             let mut cloned_buf = buf.clone();
 
             buf.adjust(max_bytes);
@@ -306,8 +926,11 @@ impl<RT: Runtime> Sender<RT> {
     }
 
     pub fn top_size_unsent(&self) -> Option<usize> {
-        let unsent_queue = self.unsent_queue.borrow_mut();
-        Some(unsent_queue.front()?.len())
+        let unsent_queue = self.unsent_queue.borrow();
+        match unsent_queue.front() {
+            Some(buf) => Some(buf.len()),
+            None => Some(self.unsent_tail.borrow().len()).filter(|&len| len > 0),
+        }
     }
 
     pub fn update_remote_window(&self, window_size_hdr: u16) -> Result<(), Fail> {
@@ -318,9 +941,12 @@ impl<RT: Runtime> Sender<RT> {
                 details: "Window size overflow",
             })?;
 
-        debug!(
+        datapath!(
+            Debug,
             "Updating window size -> {} (hdr {}, scale {})",
-            window_size, window_size_hdr, self.window_scale
+            window_size,
+            window_size_hdr,
+            self.window_scale
         );
         self.window_size.set(window_size);
 
@@ -328,13 +954,42 @@ impl<RT: Runtime> Sender<RT> {
     }
 
     pub fn remote_mss(&self) -> usize {
-        self.mss
+        self.remote_mss
+    }
+
+    /// Returns the largest round-trip time sampled so far, if any.
+    pub fn max_rtt(&self) -> Option<Duration> {
+        self.max_rtt.get()
+    }
+
+    /// Returns the rate in bytes per second at which the congestion control paces data, if it
+    /// does.
+    pub fn pacing_rate(&self) -> Option<u64> {
+        self.congestion_ctrl.get_pacing_rate()
     }
 
     pub fn current_rto(&self) -> Duration {
         self.rto.borrow().estimate()
     }
 
+    /// Returns the smoothed round-trip time, if any round trip got sampled.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.rto.borrow().srtt()
+    }
+
+    /// Returns the round-trip time variation, if any round trip got sampled.
+    pub fn rttvar(&self) -> Option<Duration> {
+        self.rto.borrow().rttvar()
+    }
+
+    pub fn cwnd(&self) -> u32 {
+        self.congestion_ctrl.get_cwnd()
+    }
+
+    pub fn ssthresh(&self) -> u32 {
+        self.congestion_ctrl.get_ssthresh()
+    }
+
     pub fn congestion_ctrl_watch_retransmit_now_flag(&self) -> (bool, WatchFuture<bool>) {
         self.congestion_ctrl.watch_retransmit_now_flag()
     }
@@ -344,17 +999,64 @@ impl<RT: Runtime> Sender<RT> {
     }
 
     pub fn congestion_ctrl_on_rto(&self, base_seq_no: Wrapping<u32>) {
-        self.congestion_ctrl.on_rto(base_seq_no)
+        let cwnd = self.congestion_ctrl.get_cwnd();
+        let ssthresh = self.congestion_ctrl.get_ssthresh();
+        // Only the first of a series of timeouts may be spurious (RFC 5682, section 2.1).
+        let frto = if self.frto.get() && self.retransmit_timeouts.get() == 0 {
+            Some(Frto {
+                recover: self.sent_seq_no.get(),
+                cwnd,
+                ssthresh,
+                new_data_limit: None,
+            })
+        } else {
+            None
+        };
+        self.frto_state.set(frto);
+        self.congestion_ctrl.on_rto(base_seq_no);
+        self.on_cwnd_reduction(cwnd, ssthresh);
     }
 
     pub fn congestion_ctrl_on_send(&self, rto: Duration, num_sent_bytes: u32) {
         self.congestion_ctrl.on_send(rto, num_sent_bytes)
     }
 
-    pub fn congestion_ctrl_on_cwnd_check_before_send(&self) {
+    pub fn congestion_ctrl_on_cwnd_check_before_send(&self, now: Instant) {
+        self.validate_cwnd(now);
         self.congestion_ctrl.on_cwnd_check_before_send()
     }
 
+    pub fn set_cwnd_validation(&self, value: bool) {
+        self.cwnd_validation.set(value);
+    }
+
+    /// Shrinks a non-validated congestion window before new data goes out at `now` (RFC 7661,
+    /// section 4.3): for every non-validated period elapsed, it halves, though not below the
+    /// initial window, and the slow start threshold keeps three quarters of it.
+    fn validate_cwnd(&self, now: Instant) {
+        let cwnd = self.congestion_ctrl.get_cwnd();
+        if !self.cwnd_validation.get() || cwnd == u32::MAX {
+            return;
+        }
+        let idle = self.sent_seq_no.get() == self.base_seq_no.get();
+        let rtt = self.srtt().unwrap_or_else(|| self.current_rto());
+        let mut periods = self
+            .cwnd_validator
+            .borrow_mut()
+            .on_send(cwnd, idle, rtt, now);
+        let initial = cc::initial_cwnd(self.mss.get() as u32);
+        let mut validated = cwnd;
+        let mut ssthresh = self.congestion_ctrl.get_ssthresh();
+        while periods > 0 && validated > initial {
+            ssthresh = ssthresh.max(validated / 4 * 3);
+            validated = (validated / 2).max(initial);
+            periods -= 1;
+        }
+        if validated < cwnd {
+            self.congestion_ctrl.on_cwnd_validation(validated, ssthresh);
+        }
+    }
+
     pub fn congestion_ctrl_watch_cwnd(&self) -> (u32, WatchFuture<u32>) {
         self.congestion_ctrl.watch_cwnd()
     }
@@ -1,73 +1,159 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use float_duration::FloatDuration;
-use std::{cmp, time::Duration};
+//! Retransmission timeout of a connection, estimated from round-trip time samples as specified by
+//! RFC 6298.
+
+use std::time::Duration;
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Timeout until the first round-trip time gets sampled (RFC 6298, section 2.1).
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// Granularity of the clock that times round trips and retransmissions. Runtimes tick in
+/// milliseconds.
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// Weight of the round-trip time variation in the timeout (RFC 6298, section 2).
+const K: u32 = 4;
 
-// RFC6298
 #[derive(Debug)]
 pub struct RtoCalculator {
-    srtt: f64,
-    rttvar: f64,
-    rto: f64,
+    /// Smoothed round-trip time, once a round trip got sampled.
+    srtt: Option<Duration>,
+    /// Round-trip time variation.
+    rttvar: Duration,
+    /// Current timeout, doubled for every timeout since the latest sample.
+    rto: Duration,
 
-    received_sample: bool,
+    lbound: Duration,
+    ubound: Duration,
 }
 
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [RtoCalculator].
 impl RtoCalculator {
-    pub fn new() -> Self {
+    pub fn new(lbound: Duration, ubound: Duration) -> Self {
         Self {
-            srtt: 1.0,
-            rttvar: 0.0,
-            rto: 1.0,
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            rto: INITIAL_RTO.max(lbound).min(ubound),
 
-            received_sample: false,
+            lbound,
+            ubound,
         }
     }
 
+    /// Updates the estimate with a round-trip time measured on data that was not retransmitted
+    /// (RFC 6298, section 2), which undoes any backoff.
     pub fn add_sample(&mut self, rtt: Duration) {
-        const ALPHA: f64 = 0.125;
-        const BETA: f64 = 0.25;
-        const GRANULARITY: f64 = 0.001f64;
-
-        let rtt = FloatDuration::from(rtt).as_seconds();
-
-        if !self.received_sample {
-            self.srtt = rtt;
-            self.rttvar = rtt / 2.;
-            self.received_sample = true;
-        } else {
-            self.rttvar = (1.0 - BETA) * self.rttvar + BETA * (self.srtt - rtt).abs();
-            self.srtt = (1.0 - ALPHA) * self.srtt + ALPHA * rtt;
-        }
-
-        let rttvar_x4 = match (4.0 * self.rttvar).partial_cmp(&GRANULARITY) {
-            Some(cmp::Ordering::Less) => GRANULARITY,
-            None => panic!("NaN rttvar: {:?}", self.rttvar),
-            _ => self.rttvar,
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                let delta = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                // RTTVAR <- (1 - beta) * RTTVAR + beta * |SRTT - R'|, with beta = 1/4.
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                // SRTT <- (1 - alpha) * SRTT + alpha * R', with alpha = 1/8.
+                srtt * 7 / 8 + rtt / 8
+            }
         };
-        self.update_rto(self.srtt + rttvar_x4);
+        self.srtt = Some(srtt);
+        self.rto = self.clamp(srtt + CLOCK_GRANULARITY.max(self.rttvar * K));
     }
 
-    fn update_rto(&mut self, new_rto: f64) {
-        const UBOUND_SEC: f64 = 60.0f64;
-        const LBOUND_SEC: f64 = 0.100f64;
-        self.rto = match (
-            new_rto.partial_cmp(&LBOUND_SEC),
-            new_rto.partial_cmp(&UBOUND_SEC),
-        ) {
-            (Some(cmp::Ordering::Less), _) => LBOUND_SEC,
-            (_, Some(cmp::Ordering::Greater)) => UBOUND_SEC,
-            (None, _) | (_, None) => panic!("NaN RTO: {:?}", new_rto),
-            _ => new_rto,
-        };
+    pub fn set_bounds(&mut self, lbound: Duration, ubound: Duration) {
+        self.lbound = lbound;
+        self.ubound = ubound;
+        self.rto = self.clamp(self.rto);
     }
 
+    /// Backs the timeout off after the retransmission timer expired (RFC 6298, section 5.5).
     pub fn record_failure(&mut self) {
-        self.update_rto(self.rto * 2.0);
+        self.rto = self.clamp(self.rto.checked_mul(2).unwrap_or(self.ubound));
     }
 
     pub fn estimate(&self) -> Duration {
-        FloatDuration::seconds(self.rto).to_std().unwrap()
+        self.rto
+    }
+
+    /// Returns the smoothed round-trip time, if any round trip got sampled.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Returns the round-trip time variation, if any round trip got sampled.
+    pub fn rttvar(&self) -> Option<Duration> {
+        self.srtt.map(|_| self.rttvar)
+    }
+
+    fn clamp(&self, rto: Duration) -> Duration {
+        rto.max(self.lbound).min(self.ubound)
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::RtoCalculator;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rto() {
+        let ms = Duration::from_millis;
+        let mut rto = RtoCalculator::new(ms(1), ms(60_000));
+        assert_eq!(rto.estimate(), ms(1000));
+        assert_eq!(rto.srtt(), None);
+
+        // SRTT <- R, RTTVAR <- R/2.
+        rto.add_sample(ms(100));
+        assert_eq!(rto.srtt(), Some(ms(100)));
+        assert_eq!(rto.estimate(), ms(300));
+
+        // RTTVAR <- 3/4 * 50 + 1/4 * 100, SRTT <- 7/8 * 100 + 1/8 * 200.
+        rto.add_sample(ms(200));
+        assert_eq!(rto.srtt(), Some(Duration::from_micros(112_500)));
+        assert_eq!(rto.estimate(), Duration::from_micros(362_500));
+
+        // Timeouts double the estimate until the next sample.
+        rto.record_failure();
+        assert_eq!(rto.estimate(), Duration::from_micros(725_000));
+        rto.record_failure();
+        assert_eq!(rto.estimate(), Duration::from_micros(1_450_000));
+
+        // Once the variation vanishes, the clock granularity still separates the timeout from
+        // the round-trip time.
+        for _ in 0..200 {
+            rto.add_sample(ms(10));
+        }
+        assert_eq!(rto.srtt(), Some(ms(10)));
+        assert_eq!(rto.estimate(), ms(11));
+    }
+
+    #[test]
+    fn test_rto_bounds() {
+        let ms = Duration::from_millis;
+        let mut rto = RtoCalculator::new(ms(200), ms(2000));
+        rto.add_sample(ms(1));
+        assert_eq!(rto.estimate(), ms(200));
+
+        for _ in 0..10 {
+            rto.record_failure();
+        }
+        assert_eq!(rto.estimate(), ms(2000));
+
+        rto.set_bounds(ms(200), ms(500));
+        assert_eq!(rto.estimate(), ms(500));
     }
 }
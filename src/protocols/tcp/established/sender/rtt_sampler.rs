@@ -0,0 +1,155 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::tcp::{seq_gt, seq_lt, SeqNumber};
+use std::time::{Duration, Instant};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Round-trip time sampling under Karn's algorithm (RFC 6298, section 3): one segment at a time
+/// is timed from its sending to its acknowledgment, but an ACK of retransmitted data can't tell
+/// which transmission it is for, so it yields no sample unless the timestamp it echoes does.
+#[derive(Debug, Default)]
+pub struct RttSampler {
+    /// End of the data timed, and when it was sent.
+    timed: Option<(SeqNumber, Instant)>,
+    /// End of the data retransmitted, until acknowledged.
+    retransmitted: Option<SeqNumber>,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [RttSampler].
+impl RttSampler {
+    /// Times the data up to `end`, sent for the first time at `now`, unless other data is timed
+    /// already.
+    pub fn on_send(&mut self, end: SeqNumber, now: Instant) {
+        if self.timed.is_none() {
+            self.timed = Some((end, now));
+        }
+    }
+
+    /// Records that the data up to `end` went out again. The data timed stops being so: its
+    /// ACK may have waited for the retransmission to fill a hole before it.
+    pub fn on_retransmit(&mut self, end: SeqNumber) {
+        self.timed = None;
+        match self.retransmitted {
+            Some(retransmitted) if !seq_lt(retransmitted, end) => (),
+            _ => self.retransmitted = Some(end),
+        }
+    }
+
+    /// Returns the sample an ACK at `now` of the data from `base_seq_no` up to `ack_seq_no`
+    /// yields: the round-trip time `ts_rtt` told by the timestamp it echoes, if any, or else the
+    /// time since the timed data was sent, once acknowledged, unless the ACK is also of
+    /// retransmitted data.
+    pub fn on_ack(
+        &mut self,
+        base_seq_no: SeqNumber,
+        ack_seq_no: SeqNumber,
+        ts_rtt: Option<Duration>,
+        now: Instant,
+    ) -> Option<Duration> {
+        let ambiguous = match self.retransmitted {
+            Some(end) if seq_gt(end, base_seq_no) => {
+                if !seq_lt(ack_seq_no, end) {
+                    self.retransmitted = None;
+                }
+                true
+            }
+            _ => {
+                self.retransmitted = None;
+                false
+            }
+        };
+        let timed_rtt = match self.timed {
+            Some((end, sent_at)) if !seq_lt(ack_seq_no, end) => {
+                self.timed = None;
+                Some(now - sent_at)
+            }
+            _ => None,
+        };
+        ts_rtt.or(if ambiguous { None } else { timed_rtt })
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::RttSampler;
+    use std::{
+        num::Wrapping,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_rtt_sampler() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut sampler = RttSampler::default();
+
+        // One segment at a time is timed, until acknowledged in full.
+        sampler.on_send(Wrapping(100), ms(0));
+        sampler.on_send(Wrapping(200), ms(5));
+        assert_eq!(sampler.on_ack(Wrapping(0), Wrapping(50), None, ms(10)), None);
+        assert_eq!(
+            sampler.on_ack(Wrapping(50), Wrapping(200), None, ms(20)),
+            Some(Duration::from_millis(20))
+        );
+
+        // The next segment sent gets timed, and an echoed timestamp takes precedence.
+        sampler.on_send(Wrapping(300), ms(30));
+        assert_eq!(
+            sampler.on_ack(Wrapping(200), Wrapping(300), Some(Duration::from_millis(8)), ms(40)),
+            Some(Duration::from_millis(8))
+        );
+    }
+
+    #[test]
+    fn test_rtt_sampler_retransmissions() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut sampler = RttSampler::default();
+
+        // The ACK of a retransmission can't tell which transmission it is for.
+        sampler.on_send(Wrapping(100), ms(0));
+        sampler.on_retransmit(Wrapping(100));
+        assert_eq!(sampler.on_ack(Wrapping(0), Wrapping(100), None, ms(1000)), None);
+
+        // Nor can that of data sent after one, which may have waited for it to fill a hole.
+        sampler.on_send(Wrapping(200), ms(1000));
+        sampler.on_send(Wrapping(300), ms(1000));
+        sampler.on_retransmit(Wrapping(200));
+        sampler.on_send(Wrapping(400), ms(1500));
+        assert_eq!(sampler.on_ack(Wrapping(100), Wrapping(400), None, ms(3000)), None);
+
+        // Data retransmitted before still in flight keeps ACKs ambiguous until acknowledged.
+        sampler.on_send(Wrapping(500), ms(3000));
+        sampler.on_retransmit(Wrapping(450));
+        sampler.on_send(Wrapping(600), ms(3100));
+        assert_eq!(sampler.on_ack(Wrapping(400), Wrapping(425), None, ms(3200)), None);
+        assert_eq!(sampler.on_ack(Wrapping(425), Wrapping(600), None, ms(3300)), None);
+
+        // Timestamps tell, retransmission or not.
+        sampler.on_send(Wrapping(700), ms(4000));
+        sampler.on_retransmit(Wrapping(700));
+        assert_eq!(
+            sampler.on_ack(Wrapping(600), Wrapping(700), Some(Duration::from_millis(30)), ms(4500)),
+            Some(Duration::from_millis(30))
+        );
+
+        // Once the retransmitted data is acknowledged, samples resume.
+        sampler.on_send(Wrapping(800), ms(5000));
+        assert_eq!(
+            sampler.on_ack(Wrapping(700), Wrapping(800), None, ms(5040)),
+            Some(Duration::from_millis(40))
+        );
+    }
+}
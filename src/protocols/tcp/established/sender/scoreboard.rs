@@ -0,0 +1,115 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Data beyond the cumulative acknowledgment that the remote reported holding through selective
+/// acknowledgments (RFC 2018). Blocks are kept merged and in sequence order, as offsets from the
+/// oldest unacknowledged byte like those of the send buffer.
+#[derive(Debug, Default)]
+pub struct Scoreboard {
+    blocks: Vec<(u32, u32)>,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [Scoreboard].
+impl Scoreboard {
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Records that the remote holds the bytes from `begin` up to `end`.
+    pub fn insert(&mut self, begin: u32, end: u32) {
+        if begin >= end {
+            return;
+        }
+        let (mut begin, mut end) = (begin, end);
+        // Blocks overlapping or touching the new one get merged into it.
+        let first = self.blocks.partition_point(|&(_, e)| e < begin);
+        let last = self.blocks.partition_point(|&(b, _)| b <= end);
+        if first < last {
+            begin = begin.min(self.blocks[first].0);
+            end = end.max(self.blocks[last - 1].1);
+        }
+        self.blocks.splice(first..last, Some((begin, end)));
+    }
+
+    /// Moves the offsets past `num_bytes` acknowledged cumulatively, dropping the blocks they
+    /// cover.
+    pub fn advance(&mut self, num_bytes: u32) {
+        self.blocks.retain(|&(_, e)| e > num_bytes);
+        for (b, e) in self.blocks.iter_mut() {
+            *b = b.saturating_sub(num_bytes);
+            *e -= num_bytes;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+
+    /// Returns the first offset from `from` on that the remote did not report holding, and where
+    /// the next block the remote holds starts, if any does past it.
+    pub fn next_hole(&self, from: u32) -> (u32, Option<u32>) {
+        let mut start = from;
+        for &(b, e) in &self.blocks {
+            if e <= start {
+                continue;
+            }
+            if b > start {
+                return (start, Some(b));
+            }
+            start = e;
+        }
+        (start, None)
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::Scoreboard;
+
+    #[test]
+    fn test_scoreboard() {
+        let mut scoreboard = Scoreboard::default();
+        assert!(scoreboard.is_empty());
+        assert_eq!(scoreboard.next_hole(0), (0, None));
+
+        scoreboard.insert(200, 300);
+        scoreboard.insert(500, 600);
+        scoreboard.insert(400, 450);
+        assert_eq!(scoreboard.blocks, vec![(200, 300), (400, 450), (500, 600)]);
+
+        // Blocks touching or overlapping others merge with them.
+        scoreboard.insert(450, 500);
+        scoreboard.insert(250, 310);
+        assert_eq!(scoreboard.blocks, vec![(200, 310), (400, 600)]);
+        scoreboard.insert(100, 700);
+        assert_eq!(scoreboard.blocks, vec![(100, 700)]);
+        scoreboard.insert(300, 300);
+        assert_eq!(scoreboard.blocks, vec![(100, 700)]);
+
+        scoreboard.clear();
+        scoreboard.insert(200, 300);
+        scoreboard.insert(400, 600);
+        assert_eq!(scoreboard.next_hole(0), (0, Some(200)));
+        assert_eq!(scoreboard.next_hole(150), (150, Some(200)));
+        assert_eq!(scoreboard.next_hole(200), (300, Some(400)));
+        assert_eq!(scoreboard.next_hole(450), (600, None));
+
+        // Acknowledged blocks go away, the others move down.
+        scoreboard.advance(250);
+        assert_eq!(scoreboard.blocks, vec![(0, 50), (150, 350)]);
+        scoreboard.advance(350);
+        assert!(scoreboard.is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Initial capacity of a buffer, grown by doubling as needed.
+const MIN_CAPACITY: usize = 4096;
+
+/// Data sent but not acknowledged yet, stored contiguously in a ring of bytes. Offsets count from
+/// the oldest unacknowledged byte, so that the sender indexes it by sequence number relative to
+/// its base.
+#[derive(Default)]
+pub struct SendBuffer {
+    buf: Box<[u8]>,
+    /// Position of the oldest byte in `buf`.
+    head: usize,
+    len: usize,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [SendBuffer].
+impl SendBuffer {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Appends `data` after the bytes already in the buffer.
+    pub fn push(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        if self.len + data.len() > self.buf.len() {
+            self.grow(self.len + data.len());
+        }
+        let tail = (self.head + self.len) % self.buf.len();
+        let first = data.len().min(self.buf.len() - tail);
+        self.buf[tail..tail + first].copy_from_slice(&data[..first]);
+        self.buf[..data.len() - first].copy_from_slice(&data[first..]);
+        self.len += data.len();
+    }
+
+    /// Drops the `num_bytes` oldest bytes, once acknowledged.
+    pub fn trim(&mut self, num_bytes: usize) {
+        assert!(num_bytes <= self.len);
+        self.len -= num_bytes;
+        self.head = if self.len == 0 {
+            0
+        } else {
+            (self.head + num_bytes) % self.buf.len()
+        };
+    }
+
+    /// Returns up to `max_len` bytes starting at `offset`, split in two where the ring wraps.
+    pub fn slices(&self, offset: usize, max_len: usize) -> (&[u8], &[u8]) {
+        assert!(offset <= self.len);
+        let len = max_len.min(self.len - offset);
+        if len == 0 {
+            return (&[], &[]);
+        }
+        let start = (self.head + offset) % self.buf.len();
+        let first = len.min(self.buf.len() - start);
+        (&self.buf[start..start + first], &self.buf[..len - first])
+    }
+
+    /// Returns up to `max_len` bytes starting at `offset`, copied out.
+    pub fn to_vec(&self, offset: usize, max_len: usize) -> Vec<u8> {
+        let (first, second) = self.slices(offset, max_len);
+        let mut data = Vec::with_capacity(first.len() + second.len());
+        data.extend_from_slice(first);
+        data.extend_from_slice(second);
+        data
+    }
+
+    /// Reallocates the ring to hold at least `min_capacity` bytes, unwrapping its contents.
+    fn grow(&mut self, min_capacity: usize) {
+        let capacity = min_capacity.next_power_of_two().max(MIN_CAPACITY);
+        let mut buf = vec![0; capacity].into_boxed_slice();
+        let (first, second) = self.slices(0, self.len);
+        buf[..first.len()].copy_from_slice(first);
+        buf[first.len()..self.len].copy_from_slice(second);
+        self.buf = buf;
+        self.head = 0;
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{SendBuffer, MIN_CAPACITY};
+
+    #[test]
+    fn test_send_buffer() {
+        let mut buffer = SendBuffer::default();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.slices(0, 10), (&[][..], &[][..]));
+
+        // Fill the buffer to the end of the ring, then wrap around.
+        let data: Vec<u8> = (0..MIN_CAPACITY).map(|i| i as u8).collect();
+        buffer.push(&data[..MIN_CAPACITY - 2]);
+        buffer.trim(MIN_CAPACITY - 4);
+        buffer.push(&[1, 2, 3, 4]);
+        assert_eq!(buffer.len(), 6);
+        let (first, second) = buffer.slices(1, 4);
+        assert_eq!(first, &[data[MIN_CAPACITY - 3], 1, 2]);
+        assert_eq!(second, &[3]);
+        assert_eq!(buffer.to_vec(2, 100), vec![1, 2, 3, 4]);
+
+        // Growing keeps the data in order.
+        buffer.push(&data);
+        assert_eq!(buffer.len(), MIN_CAPACITY + 6);
+        assert_eq!(buffer.to_vec(0, 6), {
+            let mut v = data[MIN_CAPACITY - 4..MIN_CAPACITY - 2].to_vec();
+            v.extend_from_slice(&[1, 2, 3, 4]);
+            v
+        });
+        assert_eq!(buffer.to_vec(6, MIN_CAPACITY), data);
+
+        buffer.trim(MIN_CAPACITY + 6);
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Notifications of the state transitions, teardown and readiness of established connections.
+
+use super::established::{fsm::Event, State};
+use crate::protocols::ipv4;
+use std::{
+    cell::RefCell,
+    fmt,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// A connection moving from one state to another.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StateTransition {
+    pub local: ipv4::Endpoint,
+    pub remote: ipv4::Endpoint,
+    /// State the connection was in.
+    pub from: State,
+    /// State the connection is now in.
+    pub to: State,
+    /// What caused the transition.
+    pub trigger: Event,
+    /// When the transition happened, as told by the runtime.
+    pub at: Instant,
+}
+
+/// Why a connection was torn down.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    /// We sent the first FIN.
+    ActiveClose,
+    /// The remote sent the first FIN.
+    PassiveClose,
+    /// A RST arrived.
+    Reset,
+    /// The idle reaping policy picked the connection.
+    Idle,
+    /// The remote acknowledged none of the retransmissions the options allow.
+    Timeout,
+    /// The application aborted the connection, sending a RST.
+    Aborted,
+}
+
+/// What happened over the life of a connection, reported once it is torn down.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CloseSummary {
+    pub local: ipv4::Endpoint,
+    pub remote: ipv4::Endpoint,
+    /// Time from establishment to teardown.
+    pub duration: Duration,
+    /// Bytes received from the remote and accepted into the receive window.
+    pub bytes_in: u64,
+    /// Bytes pushed by the application.
+    pub bytes_out: u64,
+    /// Segments retransmitted.
+    pub retransmits: u64,
+    /// Largest round-trip time sampled, if any segment was acknowledged without retransmission.
+    pub max_rtt: Option<Duration>,
+    pub reason: CloseReason,
+}
+
+/// What a connection is ready for, or what the application waits for it to be ready for.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Readiness {
+    /// A pop completes at once, with data, the end of the stream or an error.
+    pub readable: bool,
+    /// A push doesn't fail for a full send buffer.
+    pub writable: bool,
+}
+
+/// Callbacks notified of every state transition and teardown.
+#[derive(Clone, Default)]
+pub struct Subscribers {
+    inner: Rc<RefCell<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    transitions: Vec<Rc<dyn Fn(&StateTransition)>>,
+    closes: Vec<Rc<dyn Fn(&CloseSummary)>>,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [Readiness].
+impl Readiness {
+    pub const READABLE: Self = Self {
+        readable: true,
+        writable: false,
+    };
+    pub const WRITABLE: Self = Self {
+        readable: false,
+        writable: true,
+    };
+    pub const ALL: Self = Self {
+        readable: true,
+        writable: true,
+    };
+
+    /// Returns what both `self` and `other` are.
+    pub fn intersection(self, other: Self) -> Self {
+        Self {
+            readable: self.readable && other.readable,
+            writable: self.writable && other.writable,
+        }
+    }
+
+    pub fn is_empty(self) -> bool {
+        !self.readable && !self.writable
+    }
+}
+
+/// Associate functions for [Subscribers].
+impl Subscribers {
+    /// Registers `f` to be called on every transition from now on.
+    pub fn subscribe<F: Fn(&StateTransition) + 'static>(&self, f: F) {
+        self.inner.borrow_mut().transitions.push(Rc::new(f));
+    }
+
+    /// Registers `f` to be called on every teardown from now on.
+    pub fn subscribe_close<F: Fn(&CloseSummary) + 'static>(&self, f: F) {
+        self.inner.borrow_mut().closes.push(Rc::new(f));
+    }
+
+    /// Calls every subscriber with `transition`.
+    pub fn notify(&self, transition: &StateTransition) {
+        // Subscribers may subscribe others, so do not hold the borrow while calling them.
+        let subscribers = self.inner.borrow().transitions.clone();
+        for f in subscribers.iter() {
+            f(transition);
+        }
+    }
+
+    /// Logs `summary` and calls every teardown subscriber with it.
+    pub fn notify_close(&self, summary: &CloseSummary) {
+        info!("{}", summary);
+        let subscribers = self.inner.borrow().closes.clone();
+        for f in subscribers.iter() {
+            f(summary);
+        }
+    }
+}
+
+//==============================================================================
+// Trait Implementations
+//==============================================================================
+
+/// Formats a summary as a single line of `key=value` pairs.
+impl fmt::Display for CloseSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "tcp_close local={}:{} remote={}:{} duration_ms={} bytes_in={} bytes_out={} \
+             retransmits={} max_rtt_ms=",
+            self.local.addr,
+            self.local.port,
+            self.remote.addr,
+            self.remote.port,
+            self.duration.as_millis(),
+            self.bytes_in,
+            self.bytes_out,
+            self.retransmits,
+        )?;
+        match self.max_rtt {
+            Some(rtt) => write!(f, "{}", rtt.as_millis())?,
+            None => write!(f, "-")?,
+        }
+        let reason = match self.reason {
+            CloseReason::ActiveClose => "active_close",
+            CloseReason::PassiveClose => "passive_close",
+            CloseReason::Reset => "reset",
+            CloseReason::Idle => "idle",
+            CloseReason::Timeout => "timeout",
+            CloseReason::Aborted => "aborted",
+        };
+        write!(f, " reason={}", reason)
+    }
+}
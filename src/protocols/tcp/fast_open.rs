@@ -0,0 +1,175 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! TCP Fast Open (RFC 7413).
+//!
+//! A server grants clients that ask for it a cookie: a SipHash-2-4 MAC of their address under a
+//! 128-bit key of the listener. A client holding a cookie for a server sends it on the SYN of its next
+//! connections along with the first data, which the server acknowledges in the SYN+ACK rather
+//! than a round trip later. Since a SYN carrying data can be replayed by the network, the server
+//! remembers the SYNs whose data it took for a while, and refuses the data of their duplicates.
+
+use crate::protocols::{ipv4, tcp::SeqNumber};
+use siphasher::sip::SipHasher24;
+use std::{
+    collections::HashMap,
+    hash::Hasher,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Length of the cookies a server grants.
+pub const FAST_OPEN_COOKIE_SIZE: usize = 8;
+
+/// How long a server remembers the SYNs whose data it took, which covers the lifetime of a
+/// segment in the network.
+const REPLAY_WINDOW: Duration = Duration::from_secs(60);
+
+/// SYNs a server remembers at once. Once full, data on SYNs is refused until some expire.
+const MAX_REMEMBERED_SYNS: usize = 4096;
+
+/// Servers a client keeps a cookie for.
+const MAX_CACHED_COOKIES: usize = 1024;
+
+/// Server side of TCP Fast Open on a listener.
+pub struct FastOpenGuard {
+    key: [u64; 2],
+    /// SYNs whose data was taken, by remote endpoint and sequence number, and when they came.
+    syns: HashMap<(ipv4::Endpoint, SeqNumber), Instant>,
+}
+
+/// Client side of TCP Fast Open: the cookies granted by servers.
+#[derive(Default)]
+pub struct FastOpenCache {
+    cookies: HashMap<Ipv4Addr, Vec<u8>>,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [FastOpenGuard].
+impl FastOpenGuard {
+    pub fn new(key: [u64; 2]) -> Self {
+        Self {
+            key,
+            syns: HashMap::new(),
+        }
+    }
+
+    /// Returns the cookie of clients at `remote`.
+    pub fn cookie(&self, remote: Ipv4Addr) -> [u8; FAST_OPEN_COOKIE_SIZE] {
+        let mut hash = SipHasher24::new_with_keys(self.key[0], self.key[1]);
+        hash.write_u32(remote.into());
+        hash.finish().to_be_bytes()
+    }
+
+    /// Returns whether `cookie` was granted to clients at `remote`.
+    pub fn check_cookie(&self, remote: Ipv4Addr, cookie: &[u8]) -> bool {
+        cookie[..] == self.cookie(remote)[..]
+    }
+
+    /// Records the SYN of `remote` with sequence number `seq_num`, returning whether its data
+    /// may be taken: not if the same SYN showed up recently, or if too many did.
+    pub fn check_replay(
+        &mut self,
+        remote: ipv4::Endpoint,
+        seq_num: SeqNumber,
+        now: Instant,
+    ) -> bool {
+        if self.syns.len() >= MAX_REMEMBERED_SYNS {
+            self.syns
+                .retain(|_, &mut t| now.saturating_duration_since(t) < REPLAY_WINDOW);
+        }
+        match self.syns.get(&(remote, seq_num)) {
+            Some(&t) if now.saturating_duration_since(t) < REPLAY_WINDOW => false,
+            _ if self.syns.len() >= MAX_REMEMBERED_SYNS => false,
+            _ => {
+                self.syns.insert((remote, seq_num), now);
+                true
+            }
+        }
+    }
+}
+
+/// Associate functions for [FastOpenCache].
+impl FastOpenCache {
+    /// Returns the cookie granted by the server at `remote`, if any.
+    pub fn get(&self, remote: Ipv4Addr) -> Option<&[u8]> {
+        self.cookies.get(&remote).map(|c| &c[..])
+    }
+
+    /// Keeps the cookie granted by the server at `remote`, in place of any earlier one.
+    pub fn insert(&mut self, remote: Ipv4Addr, cookie: &[u8]) {
+        if self.cookies.len() >= MAX_CACHED_COOKIES && !self.cookies.contains_key(&remote) {
+            let evicted = *self.cookies.keys().next().unwrap();
+            self.cookies.remove(&evicted);
+        }
+        self.cookies.insert(remote, cookie.to_vec());
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{FastOpenCache, FastOpenGuard, REPLAY_WINDOW};
+    use crate::{
+        protocols::{ip, ipv4},
+        test_helpers,
+    };
+    use std::{convert::TryFrom, net::Ipv4Addr, num::Wrapping, time::Instant};
+
+    #[test]
+    fn test_fast_open_cookies() {
+        let guard = FastOpenGuard::new([42, 43]);
+        let (alice, carrie) = (test_helpers::ALICE_IPV4, test_helpers::CARRIE_IPV4);
+        let cookie = guard.cookie(alice);
+        assert!(guard.check_cookie(alice, &cookie));
+        assert!(!guard.check_cookie(carrie, &cookie));
+        assert!(!guard.check_cookie(alice, &cookie[..4]));
+        assert!(!FastOpenGuard::new([42, 44]).check_cookie(alice, &cookie));
+
+        // Cookies aren't affine in the address, as CRCs are, so that one can't be made up from
+        // others.
+        let (bob, xor) = (test_helpers::BOB_IPV4, |a: u32, b: u32| a ^ b);
+        let spoofed = Ipv4Addr::from(xor(xor(alice.into(), carrie.into()), bob.into()));
+        let mut made_up = guard.cookie(alice);
+        for (c, (x, y)) in made_up
+            .iter_mut()
+            .zip(guard.cookie(carrie).iter().zip(guard.cookie(bob).iter()))
+        {
+            *c ^= x ^ y;
+        }
+        assert!(!guard.check_cookie(spoofed, &made_up));
+
+        let mut cache = FastOpenCache::default();
+        assert_eq!(cache.get(alice), None);
+        cache.insert(alice, &cookie);
+        assert_eq!(cache.get(alice), Some(&cookie[..]));
+        assert_eq!(cache.get(carrie), None);
+    }
+
+    #[test]
+    fn test_fast_open_replays() {
+        let now = Instant::now();
+        let mut guard = FastOpenGuard::new([42, 43]);
+        let port = |p| ip::Port::try_from(p).unwrap();
+        let remote = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(50000));
+        let other = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(50001));
+
+        assert!(guard.check_replay(remote, Wrapping(1000), now));
+        assert!(!guard.check_replay(remote, Wrapping(1000), now));
+        assert!(guard.check_replay(remote, Wrapping(2000), now));
+        assert!(guard.check_replay(other, Wrapping(1000), now));
+
+        // Duplicates old enough to have left the network are taken again.
+        assert!(guard.check_replay(remote, Wrapping(1000), now + REPLAY_WINDOW));
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Snapshot of the internals of a TCP connection, after the `TCP_INFO` socket option of Linux.
+
+use super::State;
+use crate::protocols::ipv4;
+use std::time::Duration;
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// State of a connection at the time it was asked for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TcpInfo {
+    pub local: ipv4::Endpoint,
+    pub remote: ipv4::Endpoint,
+    pub state: State,
+    /// Maximum segment size we send.
+    pub mss: usize,
+    /// Congestion window, in bytes, or `u32::MAX` if the congestion control sets none.
+    pub cwnd: u32,
+    /// Slow start threshold, in bytes, or `u32::MAX` until the first congestion event.
+    pub ssthresh: u32,
+    /// Window the remote last advertised, in bytes.
+    pub send_window: u32,
+    /// Window we advertise, in bytes.
+    pub receive_window: u32,
+    /// Retransmission timeout, as currently estimated.
+    pub rto: Duration,
+    /// Smoothed round-trip time, once a round trip got sampled.
+    pub srtt: Option<Duration>,
+    /// Round-trip time variation, once a round trip got sampled.
+    pub rttvar: Option<Duration>,
+    /// Segments retransmitted over the life of the connection.
+    pub retransmits: u64,
+    /// Retransmission timeouts in a row without the remote acknowledging data in between.
+    pub retransmit_timeouts: usize,
+    /// Bytes sent but not yet acknowledged.
+    pub bytes_in_flight: usize,
+    /// Bytes pushed but not yet sent.
+    pub unsent_bytes: usize,
+    /// Bytes received but not yet popped.
+    pub recv_queue: usize,
+    /// Bytes received from the remote and accepted into the receive window.
+    pub bytes_in: u64,
+    /// Bytes pushed by the application.
+    pub bytes_out: u64,
+    /// Rate the congestion control paces segments at, in bytes per second, if it paces them.
+    pub pacing_rate: Option<u64>,
+}
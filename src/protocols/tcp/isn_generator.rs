@@ -1,42 +1,99 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
+//! Initial sequence numbers (RFC 6528).
+//!
+//! An ISN is the sum of a clock ticking every 4 microseconds and a SipHash-2-4 of the connection's
+//! endpoints under a 128-bit key drawn from the runtime's randomness. The clock keeps the sequence
+//! numbers of successive incarnations of a connection apart, while the hash keeps off-path
+//! attackers from guessing the ISN of one connection from those of others.
+
 use crate::protocols::{ipv4, tcp::SeqNumber};
-#[allow(unused_imports)]
-use crc::{crc32, Hasher32};
-#[allow(unused_imports)]
-use std::{hash::Hasher, num::Wrapping};
+use siphasher::sip::SipHasher24;
+use std::{hash::Hasher, num::Wrapping, time::Instant};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Microseconds per tick of the ISN clock (RFC 793, section 3.3).
+const CLOCK_TICK_MICROS: u128 = 4;
 
-#[allow(dead_code)]
 pub struct IsnGenerator {
-    nonce: u32,
-    counter: Wrapping<u16>,
+    key: [u64; 2],
+    /// Start of the ISN clock.
+    epoch: Instant,
 }
 
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [IsnGenerator].
 impl IsnGenerator {
-    pub fn new(nonce: u32) -> Self {
-        Self {
-            nonce,
-            counter: Wrapping(0),
-        }
+    pub fn new(key: [u64; 2], now: Instant) -> Self {
+        Self { key, epoch: now }
     }
 
-    #[cfg(test)]
-    pub fn generate(&mut self, _local: &ipv4::Endpoint, _remote: &ipv4::Endpoint) -> SeqNumber {
-        Wrapping(0)
+    /// Returns the ISN of a connection from `local` to `remote` opened at `now`. Tests get 0, for
+    /// the sequence numbers they expect to stay readable.
+    pub fn generate(
+        &self,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+        now: Instant,
+    ) -> SeqNumber {
+        if cfg!(test) {
+            return Wrapping(0);
+        }
+        self.isn(local, remote, now)
     }
 
-    #[cfg(not(test))]
-    pub fn generate(&mut self, local: &ipv4::Endpoint, remote: &ipv4::Endpoint) -> SeqNumber {
-        let mut hash = crc32::Digest::new(crc32::IEEE);
-        hash.write_u32(remote.address().into());
-        hash.write_u16(remote.port().into());
+    fn isn(&self, local: &ipv4::Endpoint, remote: &ipv4::Endpoint, now: Instant) -> SeqNumber {
+        let mut hash = SipHasher24::new_with_keys(self.key[0], self.key[1]);
         hash.write_u32(local.address().into());
         hash.write_u16(local.port().into());
-        hash.write_u32(self.nonce);
-        let hash = hash.sum32();
-        let isn = Wrapping(hash) + Wrapping(u32::from(self.counter.0));
-        self.counter += Wrapping(1);
-        isn
+        hash.write_u32(remote.address().into());
+        hash.write_u16(remote.port().into());
+        let ticks = now.saturating_duration_since(self.epoch).as_micros() / CLOCK_TICK_MICROS;
+        Wrapping(hash.finish() as u32) + Wrapping(ticks as u32)
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::IsnGenerator;
+    use crate::{
+        protocols::{ip, ipv4},
+        test_helpers,
+    };
+    use std::{
+        convert::TryFrom,
+        num::Wrapping,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_isn_generator() {
+        let now = Instant::now();
+        let generator = IsnGenerator::new([42, 43], now);
+        let port = |p| ip::Port::try_from(p).unwrap();
+        let local = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+        let remote = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(50000));
+        let other = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(50001));
+
+        // Connections get ISNs that depend on their endpoints and on the key.
+        let isn = generator.isn(&local, &remote, now);
+        assert_eq!(generator.isn(&local, &remote, now), isn);
+        assert_ne!(generator.isn(&local, &other, now), isn);
+        assert_ne!(IsnGenerator::new([42, 44], now).isn(&local, &remote, now), isn);
+
+        // Later incarnations of a connection start further along, by a tick every 4us.
+        let later = now + Duration::from_millis(1);
+        assert_eq!(generator.isn(&local, &remote, later), isn + Wrapping(250));
     }
 }
@@ -0,0 +1,47 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::ipv4;
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Snapshot of an established TCP connection, detached from any engine so that it can be moved to
+/// another one.
+///
+/// Data the receiver holds out of order is not part of the snapshot; the remote retransmits it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionState {
+    pub local: ipv4::Endpoint,
+    pub remote: ipv4::Endpoint,
+
+    /// First sequence number not yet acknowledged by the remote.
+    pub sender_base_seq_no: u32,
+    /// Segments sent but not yet acknowledged, starting at `sender_base_seq_no`.
+    pub sender_unacked: Vec<Vec<u8>>,
+    /// Data queued but not yet sent, following `sender_unacked`.
+    pub sender_unsent: Vec<Vec<u8>>,
+    pub sender_window_size: u32,
+    pub sender_window_scale: u8,
+    pub sender_mss: usize,
+
+    /// First sequence number not yet consumed by the application.
+    pub receiver_base_seq_no: u32,
+    /// Last acknowledgement number sent to the remote.
+    pub receiver_ack_seq_no: u32,
+    /// Data received but not yet consumed, starting at `receiver_base_seq_no`.
+    pub receiver_queue: Vec<Vec<u8>>,
+    pub receiver_window_size: u32,
+    pub receiver_window_scale: u32,
+
+    /// Whether both sides agreed on selective acknowledgments.
+    pub selective_acks: bool,
+    /// Value of the timestamp clock of the connection, if both sides agreed on timestamps.
+    pub ts_value: Option<u32>,
+    /// Latest timestamp value received from the remote, if it sent any.
+    pub ts_recent: Option<u32>,
+    /// Whether both sides agreed on explicit congestion notification.
+    pub ecn: bool,
+}
@@ -4,12 +4,23 @@
 mod active_open;
 pub mod constants;
 mod established;
+mod events;
+mod fast_open;
+mod info;
 mod isn_generator;
+mod loopback;
+mod migration;
 pub mod operations;
 mod options;
 mod passive_open;
 pub mod peer;
+mod rate_limit;
 pub mod segment;
+mod stream;
+mod syn_flood;
+mod table;
+mod time_wait;
+mod timestamp;
 
 #[cfg(test)]
 mod tests;
@@ -18,4 +29,24 @@ use std::num::Wrapping;
 
 pub type SeqNumber = Wrapping<u32>;
 
-pub use self::{established::cc, options::TcpOptions as Options, peer::Peer};
+/// Returns whether sequence number `a` comes before `b`, which holds for `b` up to half the
+/// sequence space ahead of `a`, as sequence numbers wrap around (RFC 1982).
+pub fn seq_lt(a: SeqNumber, b: SeqNumber) -> bool {
+    ((a - b).0 as i32) < 0
+}
+
+/// Returns whether sequence number `a` comes after `b`, as [seq_lt] tells.
+pub fn seq_gt(a: SeqNumber, b: SeqNumber) -> bool {
+    ((a - b).0 as i32) > 0
+}
+
+pub use self::{
+    established::{cc, fsm, State},
+    events::{CloseReason, CloseSummary, Readiness, StateTransition},
+    info::TcpInfo,
+    migration::ConnectionState,
+    options::{IdleReaping, ReapAction, TcpOptions as Options, Validation},
+    peer::Peer,
+    stream::{TcpListener, TcpStream},
+    table::flow_hash,
+};
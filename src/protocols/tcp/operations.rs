@@ -6,8 +6,10 @@ use crate::{
     fail::Fail,
     file_table::FileDescriptor,
     operations::{OperationResult, ResultFuture},
+    protocols::ipv4,
     runtime::Runtime,
 };
+use futures::Stream;
 use std::{
     cell::RefCell,
     fmt,
@@ -77,8 +79,8 @@ impl<RT: Runtime> TcpOperation<RT> {
 
             Accept(ResultFuture {
                 future,
-                done: Some(Ok(fd)),
-            }) => (future.fd, OperationResult::Accept(fd)),
+                done: Some(Ok((fd, remote))),
+            }) => (future.fd, OperationResult::Accept(fd, remote)),
             Accept(ResultFuture {
                 future,
                 done: Some(Err(e)),
@@ -142,6 +144,8 @@ impl<RT: Runtime> Future for ConnectFuture<RT> {
 pub struct AcceptFuture<RT: Runtime> {
     pub fd: FileDescriptor,
     pub inner: Rc<RefCell<Inner<RT>>>,
+    /// Place in line among the accepts waiting on the listening socket, once this one waits.
+    pub ticket: Option<u64>,
 }
 
 impl<RT: Runtime> fmt::Debug for AcceptFuture<RT> {
@@ -151,14 +155,63 @@ impl<RT: Runtime> fmt::Debug for AcceptFuture<RT> {
 }
 
 impl<RT: Runtime> Future for AcceptFuture<RT> {
-    type Output = Result<FileDescriptor, Fail>;
+    type Output = Result<(FileDescriptor, ipv4::Endpoint), Fail>;
 
     fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
         let self_ = self.get_mut();
         let peer = Peer {
             inner: self_.inner.clone(),
         };
-        peer.poll_accept(self_.fd, context)
+        peer.poll_accept(self_.fd, context, &mut self_.ticket)
+    }
+}
+
+impl<RT: Runtime> Drop for AcceptFuture<RT> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket {
+            Peer {
+                inner: self.inner.clone(),
+            }
+            .cancel_accept(self.fd, ticket);
+        }
+    }
+}
+
+/// Stream of connections accepted on a listening socket, yielding each new socket along with the
+/// remote endpoint it is connected to.
+pub struct IncomingStream<RT: Runtime> {
+    pub fd: FileDescriptor,
+    pub inner: Rc<RefCell<Inner<RT>>>,
+    /// Place in line among the accepts waiting on the listening socket, once this one waits.
+    pub ticket: Option<u64>,
+}
+
+impl<RT: Runtime> fmt::Debug for IncomingStream<RT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IncomingStream({})", self.fd)
+    }
+}
+
+impl<RT: Runtime> Stream for IncomingStream<RT> {
+    type Item = Result<(FileDescriptor, ipv4::Endpoint), Fail>;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<Self::Item>> {
+        let self_ = self.get_mut();
+        let peer = Peer {
+            inner: self_.inner.clone(),
+        };
+        peer.poll_accept(self_.fd, context, &mut self_.ticket).map(Some)
+    }
+}
+
+impl<RT: Runtime> Drop for IncomingStream<RT> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket {
+            Peer {
+                inner: self.inner.clone(),
+            }
+            .cancel_accept(self.fd, ticket);
+        }
     }
 }
 
@@ -207,3 +260,77 @@ impl<RT: Runtime> Future for PopFuture<RT> {
         peer.poll_recv(self_.fd, ctx)
     }
 }
+
+/// Future of [Peer::peek].
+pub struct PeekFuture<RT: Runtime> {
+    pub fd: FileDescriptor,
+    pub inner: Rc<RefCell<Inner<RT>>>,
+}
+
+impl<RT: Runtime> fmt::Debug for PeekFuture<RT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PeekFuture({})", self.fd)
+    }
+}
+
+impl<RT: Runtime> Future for PeekFuture<RT> {
+    type Output = Result<RT::Buf, Fail>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let self_ = self.get_mut();
+        let peer = Peer {
+            inner: self_.inner.clone(),
+        };
+        peer.poll_peek(self_.fd, ctx)
+    }
+}
+
+/// Future of [Peer::pop_exact].
+pub struct PopExactFuture<RT: Runtime> {
+    pub fd: FileDescriptor,
+    pub len: usize,
+    pub inner: Rc<RefCell<Inner<RT>>>,
+}
+
+impl<RT: Runtime> fmt::Debug for PopExactFuture<RT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PopExactFuture({}, {})", self.fd, self.len)
+    }
+}
+
+impl<RT: Runtime> Future for PopExactFuture<RT> {
+    type Output = Result<RT::Buf, Fail>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let self_ = self.get_mut();
+        let peer = Peer {
+            inner: self_.inner.clone(),
+        };
+        peer.poll_recv_exact(self_.fd, self_.len, ctx)
+    }
+}
+
+/// Future of [Peer::pop_max].
+pub struct PopMaxFuture<RT: Runtime> {
+    pub fd: FileDescriptor,
+    pub max_len: usize,
+    pub inner: Rc<RefCell<Inner<RT>>>,
+}
+
+impl<RT: Runtime> fmt::Debug for PopMaxFuture<RT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PopMaxFuture({}, {})", self.fd, self.max_len)
+    }
+}
+
+impl<RT: Runtime> Future for PopMaxFuture<RT> {
+    type Output = Result<RT::Buf, Fail>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        let self_ = self.get_mut();
+        let peer = Peer {
+            inner: self_.inner.clone(),
+        };
+        peer.poll_recv_max(self_.fd, self_.max_len, ctx)
+    }
+}
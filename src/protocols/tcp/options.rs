@@ -2,52 +2,180 @@
 // Licensed under the MIT license.
 
 use crate::{
-    protocols::tcp::{
-        constants::{DEFAULT_MSS, MAX_MSS, MIN_MSS},
-        established::cc::{self, CongestionControl, CongestionControlConstructor},
+    protocols::{
+        ipv4::datagram::IPV4_HEADER_SIZE,
+        tcp::{
+            constants::{DEFAULT_MTU, MAX_MSS, MAX_WINDOW_SCALE, MIN_MSS, MIN_MTU},
+            established::cc::{self, CongestionControl, CongestionControlConstructor},
+            segment::MIN_TCP_HEADER_SIZE,
+        },
     },
-    runtime::Runtime,
+    runtime::{ChecksumOffload, Runtime},
 };
 use std::time::Duration;
 
+/// How strictly segments of established connections are checked against the send and receive
+/// windows. Violations are counted either way, and RSTs not at the next expected sequence number
+/// discarded either way, with a challenge ACK if in the window (RFC 5961, section 3.2).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Validation {
+    /// Process what can be made sense of, ignoring only the offending ACK or data.
+    Permissive,
+    /// Discard segments that fail the checks of RFC 793 and RFC 5961: sequence numbers outside of
+    /// the receive window, and ACKs of data never sent or older than the send window.
+    Strict,
+}
+
+/// What the idle reaping policy does to the connections it picks.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReapAction {
+    /// Close the connection as the application would, sending a FIN once the data pushed so far
+    /// is out.
+    Close,
+    /// Tear the connection down at once, telling the remote with a RST.
+    Abort,
+}
+
+/// Policy tearing down connections idle for a while once the engine holds many of them, so that
+/// forgotten connections don't keep new ones out.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IdleReaping {
+    /// Time without segments received or data pushed after which a connection may be reaped.
+    pub idle_timeout: Duration,
+    /// Number of connections established or being accepted from which idle ones get reaped,
+    /// usually a little under the connection limit.
+    pub high_water: usize,
+    pub action: ReapAction,
+}
+
 /// Options for TCP Stack
 #[derive(Clone, Debug)]
 pub struct TcpOptions<RT: Runtime> {
-    /// Maximum Segment Size
-    advertised_mss: usize,
+    /// Maximum Transmission Unit of the Interface
+    mtu: usize,
+    /// Time After Which Segments Shrunk to the Path MTU Grow Back to Probe for a Larger One
+    path_mtu_probe_interval: Duration,
+    /// Maximum Segment Size, Derived from the MTU if Unset
+    advertised_mss: Option<usize>,
     /// Congestion Control Type
     congestion_ctrl_type: CongestionControlConstructor<RT>,
     /// Options for Congestion Control Algorithm
     congestion_ctrl_options: Option<cc::Options>,
     /// Number of Retries for TCP Handshake Algorithm
     handshake_retries: usize,
-    /// Timeout for TCP Handshake Algorithm
+    /// Timeout for the First SYN of the TCP Handshake, Doubled for Every Retry
     handshake_timeout: Duration,
     /// Window Size
     receive_window_size: u16,
     /// Scaling Factor for Window Size
     window_scale: u8,
+    /// Bytes Buffered by the Receiver, Overriding the Window Size
+    receive_buffer_size: Option<u32>,
+    /// Upper Bound the Receive Window Grows to with the Throughput, Which Is Fixed if Unset
+    receive_window_autotuning: Option<u32>,
+    /// Bytes Queued for Sending and Unacknowledged Beyond Which Pushes Fail
+    send_buffer_size: Option<usize>,
     /// Timeout for Delayed ACKs
     ack_delay_timeout: Duration,
+    /// Full-Sized Segments Received After Which an ACK Goes Out Without Delay
+    ack_frequency: usize,
     /// Offload Checksum to Hardware on Receiving Peer?
     rx_checksum_offload: bool,
     /// Offload Checksum to Hardware on Sending Peer?
     tx_checksum_offload: bool,
+    /// Lower Bound for the Retransmission Timeout
+    rto_lower_bound: Duration,
+    /// Upper Bound for the Retransmission Timeout
+    rto_upper_bound: Duration,
+    /// Retransmissions of Unacknowledged Data After Which a Connection Times Out
+    max_retransmits: usize,
+    /// Upper Bound for the Backlog of Listening Sockets
+    max_backlog: usize,
+    /// Maximum Number of Segments Queued for Sending
+    max_unsent_segments: usize,
+    /// Maximum Number of Out-of-Order Segments Buffered by the Receiver
+    max_out_of_order_segments: usize,
+    /// Handshakes per Second a Listener Starts Before Answering with SYN Cookies
+    syn_flood_listener_rate: usize,
+    /// Handshakes per Second a Source Starts Before its SYNs Get Dropped
+    syn_flood_source_rate: usize,
+    /// Upper Bound for Connections Established or Being Accepted
+    max_connections: Option<usize>,
+    /// Upper Bound for Connections Established or Being Accepted from a Single Address
+    max_connections_per_remote: Option<usize>,
+    /// Strictness of Sequence and Acknowledgement Number Checks
+    validation: Validation,
+    /// RSTs and Challenge ACKs Sent per Second in Reply to Refused Segments
+    error_segment_rate: usize,
+    /// Upper Bound for RSTs and Challenge ACKs Sent in a Burst
+    error_segment_burst: usize,
+    /// Policy for Reaping Idle Connections
+    idle_reaping: Option<IdleReaping>,
+    /// Upper Bound for Connections Kept in TIME_WAIT
+    max_time_wait: usize,
+    /// Time Connections Are Kept in TIME_WAIT (2MSL)
+    time_wait_timeout: Duration,
+    /// Offer Selective Acknowledgments (RFC 2018) During the Handshake?
+    selective_acks: bool,
+    /// Offer Timestamps (RFC 7323) During the Handshake?
+    timestamps: bool,
+    /// Offer Explicit Congestion Notification (RFC 3168) During the Handshake?
+    ecn: bool,
+    /// Exchange Data in the Handshake with TCP Fast Open (RFC 7413)?
+    fast_open: bool,
+    /// Send Small Segments While Data Is Unacknowledged, Disabling Nagle's Algorithm?
+    nodelay: bool,
+    /// Spread Segments Over the Round Trip Rather Than Send the Window at Once?
+    pacing: bool,
+    /// Detect Spurious Retransmission Timeouts with Forward RTO-Recovery (RFC 5682)?
+    frto: bool,
+    /// Shrink the Congestion Window While It Goes Unused (RFC 7661)?
+    cwnd_validation: bool,
 }
 
 impl<RT: Runtime> Default for TcpOptions<RT> {
     fn default() -> Self {
         TcpOptions {
-            advertised_mss: DEFAULT_MSS,
+            mtu: DEFAULT_MTU,
+            path_mtu_probe_interval: Duration::from_secs(10 * 60),
+            advertised_mss: None,
             congestion_ctrl_type: cc::None::new,
             congestion_ctrl_options: None,
             handshake_retries: 5,
             handshake_timeout: Duration::from_secs(3),
             receive_window_size: 0xffff,
             ack_delay_timeout: Duration::from_millis(5),
+            ack_frequency: 2,
             window_scale: 0,
+            receive_buffer_size: None,
+            receive_window_autotuning: None,
+            send_buffer_size: None,
             rx_checksum_offload: false,
             tx_checksum_offload: false,
+            rto_lower_bound: Duration::from_millis(100),
+            rto_upper_bound: Duration::from_secs(60),
+            max_retransmits: 15,
+            max_backlog: 1024,
+            max_unsent_segments: 1024,
+            max_out_of_order_segments: 16,
+            syn_flood_listener_rate: 1024,
+            syn_flood_source_rate: 128,
+            max_connections: None,
+            max_connections_per_remote: None,
+            validation: Validation::Permissive,
+            error_segment_rate: 1000,
+            error_segment_burst: 100,
+            idle_reaping: None,
+            max_time_wait: 4096,
+            time_wait_timeout: Duration::from_secs(60),
+            selective_acks: true,
+            timestamps: true,
+            ecn: false,
+            fast_open: false,
+            nodelay: false,
+            pacing: false,
+            frto: true,
+            cwnd_validation: true,
         }
     }
 }
@@ -92,17 +220,28 @@ impl<RT: Runtime> TcpOptions<RT> {
             options = options.set_ack_delay_timeout(value);
         }
         if let Some(value) = rx_checksum_offload {
-            options.rx_checksum_offload = value;
+            options = options.set_rx_checksum_offload(value);
         }
         if let Some(value) = tx_checksum_offload {
-            options.tx_checksum_offload = value;
+            options = options.set_tx_checksum_offload(value);
         }
 
         options
     }
 
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    pub fn path_mtu_probe_interval(&self) -> Duration {
+        self.path_mtu_probe_interval
+    }
+
+    /// Returns the MSS advertised in our SYNs, which unless set explicitly is what fits in a
+    /// datagram of the MTU next to the IPv4 and TCP headers.
     pub fn advertised_mss(&self) -> usize {
         self.advertised_mss
+            .unwrap_or_else(|| (self.mtu - IPV4_HEADER_SIZE - MIN_TCP_HEADER_SIZE).min(MAX_MSS))
     }
 
     pub fn congestion_ctrl_type(&self) -> CongestionControlConstructor<RT> {
@@ -129,6 +268,34 @@ impl<RT: Runtime> TcpOptions<RT> {
         self.window_scale
     }
 
+    /// Returns the receive window of connections, in bytes, given the window scale they agreed
+    /// on: the receive buffer size if set, or else the window size, scaled unless auto-tuning
+    /// is to grow it.
+    pub fn receive_window(&self, window_scale: u32) -> u32 {
+        match (self.receive_buffer_size, self.receive_window_autotuning) {
+            (Some(size), _) => size.min(0xffff << window_scale),
+            (None, Some(_)) => self.receive_window_size as u32,
+            (None, None) => (self.receive_window_size as u32) << window_scale,
+        }
+    }
+
+    pub fn receive_buffer_size(&self) -> Option<u32> {
+        self.receive_buffer_size
+    }
+
+    /// Returns the bound auto-tuning grows the receive window of connections to, if on. A
+    /// receive buffer size set turns it off, as SO_RCVBUF does.
+    pub fn receive_window_autotuning(&self) -> Option<u32> {
+        match self.receive_buffer_size {
+            Some(_) => None,
+            None => self.receive_window_autotuning,
+        }
+    }
+
+    pub fn send_buffer_size(&self) -> Option<usize> {
+        self.send_buffer_size
+    }
+
     pub fn tx_checksum_offload(&self) -> bool {
         self.tx_checksum_offload
     }
@@ -141,49 +308,369 @@ impl<RT: Runtime> TcpOptions<RT> {
         self.ack_delay_timeout
     }
 
-    fn set_advertised_mss(mut self, value: usize) -> Self {
+    pub fn ack_frequency(&self) -> usize {
+        self.ack_frequency
+    }
+
+    pub fn rto_lower_bound(&self) -> Duration {
+        self.rto_lower_bound
+    }
+
+    pub fn rto_upper_bound(&self) -> Duration {
+        self.rto_upper_bound
+    }
+
+    pub fn max_retransmits(&self) -> usize {
+        self.max_retransmits
+    }
+
+    pub fn max_backlog(&self) -> usize {
+        self.max_backlog
+    }
+
+    pub fn max_unsent_segments(&self) -> usize {
+        self.max_unsent_segments
+    }
+
+    pub fn max_out_of_order_segments(&self) -> usize {
+        self.max_out_of_order_segments
+    }
+
+    pub fn syn_flood_listener_rate(&self) -> usize {
+        self.syn_flood_listener_rate
+    }
+
+    pub fn syn_flood_source_rate(&self) -> usize {
+        self.syn_flood_source_rate
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    pub fn max_connections_per_remote(&self) -> Option<usize> {
+        self.max_connections_per_remote
+    }
+
+    pub fn validation(&self) -> Validation {
+        self.validation
+    }
+
+    pub fn error_segment_rate(&self) -> usize {
+        self.error_segment_rate
+    }
+
+    pub fn error_segment_burst(&self) -> usize {
+        self.error_segment_burst
+    }
+
+    pub fn idle_reaping(&self) -> Option<IdleReaping> {
+        self.idle_reaping
+    }
+
+    pub fn max_time_wait(&self) -> usize {
+        self.max_time_wait
+    }
+
+    pub fn time_wait_timeout(&self) -> Duration {
+        self.time_wait_timeout
+    }
+
+    pub fn selective_acks(&self) -> bool {
+        self.selective_acks
+    }
+
+    pub fn timestamps(&self) -> bool {
+        self.timestamps
+    }
+
+    pub fn ecn(&self) -> bool {
+        self.ecn
+    }
+
+    pub fn fast_open(&self) -> bool {
+        self.fast_open
+    }
+
+    pub fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    pub fn pacing(&self) -> bool {
+        self.pacing
+    }
+
+    pub fn frto(&self) -> bool {
+        self.frto
+    }
+
+    pub fn cwnd_validation(&self) -> bool {
+        self.cwnd_validation
+    }
+
+    pub fn set_advertised_mss(mut self, value: usize) -> Self {
         assert!(value >= MIN_MSS);
         assert!(value <= MAX_MSS);
-        self.advertised_mss = value;
+        self.advertised_mss = Some(value);
+        self
+    }
+
+    pub fn set_mtu(mut self, value: usize) -> Self {
+        assert!(value >= MIN_MTU);
+        self.mtu = value;
         self
     }
 
-    fn set_congestion_ctrl_type(mut self, value: CongestionControlConstructor<RT>) -> Self {
+    /// Sets the time after which a connection whose segments got shrunk to fit the path MTU
+    /// tries segments of the MSS agreed on again, in case the path changed (RFC 1191, section
+    /// 6.3).
+    pub fn set_path_mtu_probe_interval(mut self, value: Duration) -> Self {
+        assert!(value > Duration::new(0, 0));
+        self.path_mtu_probe_interval = value;
+        self
+    }
+
+    pub fn set_congestion_ctrl_type(mut self, value: CongestionControlConstructor<RT>) -> Self {
         self.congestion_ctrl_type = value;
         self
     }
 
-    fn set_congestion_ctrl_options(mut self, value: cc::Options) -> Self {
+    pub fn set_congestion_ctrl_options(mut self, value: cc::Options) -> Self {
         self.congestion_ctrl_options = Some(value);
         self
     }
 
-    fn set_handshake_retries(mut self, value: usize) -> Self {
+    pub fn set_handshake_retries(mut self, value: usize) -> Self {
         assert!(value > 0);
         self.handshake_retries = value;
         self
     }
 
-    fn set_handshake_timeout(mut self, value: Duration) -> Self {
+    pub fn set_handshake_timeout(mut self, value: Duration) -> Self {
         assert!(value > Duration::new(0, 0));
         self.handshake_timeout = value;
         self
     }
 
-    fn set_receive_window_size(mut self, value: u16) -> Self {
+    pub fn set_receive_window_size(mut self, value: u16) -> Self {
         assert!(value > 0);
         self.receive_window_size = value;
         self
     }
 
-    fn set_ack_delay_timeout(mut self, value: Duration) -> Self {
+    pub fn set_ack_delay_timeout(mut self, value: Duration) -> Self {
         assert!(value <= Duration::from_millis(500));
         self.ack_delay_timeout = value;
         self
     }
 
-    fn set_window_scale(mut self, value: u8) -> Self {
+    /// Sets after how many full-sized segments received an ACK goes out at once rather than
+    /// waiting for the ack delay timeout. RFC 5681 asks for at least every second one.
+    pub fn set_ack_frequency(mut self, value: usize) -> Self {
+        assert!(value > 0);
+        self.ack_frequency = value;
+        self
+    }
+
+    pub fn set_window_scale(mut self, value: u8) -> Self {
+        assert!(value <= MAX_WINDOW_SCALE);
         self.window_scale = value;
         self
     }
+
+    /// Offloads the checksums that `offload` says the network device takes care of, in addition
+    /// to those offloaded already.
+    pub fn with_checksum_offload(mut self, offload: ChecksumOffload) -> Self {
+        self.rx_checksum_offload |= offload.rx;
+        self.tx_checksum_offload |= offload.tx;
+        self
+    }
+
+    pub fn set_rx_checksum_offload(mut self, value: bool) -> Self {
+        self.rx_checksum_offload = value;
+        self
+    }
+
+    pub fn set_tx_checksum_offload(mut self, value: bool) -> Self {
+        self.tx_checksum_offload = value;
+        self
+    }
+
+    pub fn set_rto_bounds(mut self, lower: Duration, upper: Duration) -> Self {
+        assert!(lower > Duration::new(0, 0));
+        assert!(lower <= upper);
+        self.rto_lower_bound = lower;
+        self.rto_upper_bound = upper;
+        self
+    }
+
+    /// Sets how many times in a row unacknowledged data gets retransmitted on a timeout, each
+    /// time waiting twice as long, before the connection gives up on the remote and fails with
+    /// [Fail::Timeout](crate::fail::Fail::Timeout).
+    pub fn set_max_retransmits(mut self, value: usize) -> Self {
+        assert!(value > 0);
+        self.max_retransmits = value;
+        self
+    }
+
+    pub fn set_max_backlog(mut self, value: usize) -> Self {
+        assert!(value > 0);
+        self.max_backlog = value;
+        self
+    }
+
+    /// Sets the bytes connections buffer for the application (SO_RCVBUF), which their receive
+    /// window advertises in place of the window size. The window scale grows as needed for the
+    /// window to cover the buffer.
+    pub fn set_receive_buffer_size(mut self, value: u32) -> Self {
+        assert!(value > 0);
+        self.receive_buffer_size = Some(value);
+        while (0xffff << self.window_scale) < value && self.window_scale < MAX_WINDOW_SCALE {
+            self.window_scale += 1;
+        }
+        self
+    }
+
+    /// Lets the receive window of connections start at the window size and grow, once per round
+    /// trip, to twice the data received over the last one, up to `value` bytes. The window scale
+    /// grows as needed for the window to reach it.
+    pub fn set_receive_window_autotuning(mut self, value: u32) -> Self {
+        assert!(value > 0);
+        self.receive_window_autotuning = Some(value);
+        while (0xffff << self.window_scale) < value && self.window_scale < MAX_WINDOW_SCALE {
+            self.window_scale += 1;
+        }
+        self
+    }
+
+    /// Sets the bytes connections hold for sending, unsent or unacknowledged, before pushes fail
+    /// (SO_SNDBUF). A push always goes through on an empty send queue, however large.
+    pub fn set_send_buffer_size(mut self, value: usize) -> Self {
+        assert!(value > 0);
+        self.send_buffer_size = Some(value);
+        self
+    }
+
+    pub fn set_max_unsent_segments(mut self, value: usize) -> Self {
+        assert!(value > 0);
+        self.max_unsent_segments = value;
+        self
+    }
+
+    pub fn set_max_out_of_order_segments(mut self, value: usize) -> Self {
+        self.max_out_of_order_segments = value;
+        self
+    }
+
+    /// Sets the rates of new handshakes, per second, above which a listener answers SYNs with
+    /// SYN cookies and drops a growing share of the SYNs of a single source.
+    pub fn set_syn_flood_rates(mut self, listener: usize, source: usize) -> Self {
+        assert!(listener > 0);
+        assert!(source > 0);
+        self.syn_flood_listener_rate = listener;
+        self.syn_flood_source_rate = source;
+        self
+    }
+
+    /// Caps the number of connections established or being accepted, in total and from a single
+    /// remote address. SYNs beyond either cap are answered with a RST.
+    pub fn set_connection_limits(
+        mut self,
+        total: Option<usize>,
+        per_remote: Option<usize>,
+    ) -> Self {
+        self.max_connections = total;
+        self.max_connections_per_remote = per_remote;
+        self
+    }
+
+    pub fn set_validation(mut self, value: Validation) -> Self {
+        self.validation = value;
+        self
+    }
+
+    /// Sets the rate, per second, and the burst of the RSTs and challenge ACKs sent in reply to
+    /// refused segments, shared by all connections. Replies beyond it are not sent.
+    pub fn set_error_segment_rate(mut self, rate: usize, burst: usize) -> Self {
+        assert!(burst > 0);
+        self.error_segment_rate = rate;
+        self.error_segment_burst = burst;
+        self
+    }
+
+    /// Sets the policy reaping idle connections, or disables reaping with `None`. Connections are
+    /// looked at in the background, at least once a second, oldest activity first, and reaped
+    /// until the count drops under the high water mark.
+    pub fn set_idle_reaping(mut self, value: Option<IdleReaping>) -> Self {
+        self.idle_reaping = value;
+        self
+    }
+
+    /// Sets how many connections are kept in TIME_WAIT, the oldest making room for new ones
+    /// beyond it, and for how long.
+    pub fn set_time_wait(mut self, max: usize, timeout: Duration) -> Self {
+        self.max_time_wait = max;
+        self.time_wait_timeout = timeout;
+        self
+    }
+
+    /// Sets whether the handshake offers selective acknowledgments. They are used on a connection
+    /// only if both sides offer them.
+    pub fn set_selective_acks(mut self, value: bool) -> Self {
+        self.selective_acks = value;
+        self
+    }
+
+    /// Sets whether the handshake offers timestamps, used to measure round-trip times and to
+    /// protect against wrapped sequence numbers on a connection if both sides offer them.
+    pub fn set_timestamps(mut self, value: bool) -> Self {
+        self.timestamps = value;
+        self
+    }
+
+    /// Sets whether the handshake offers explicit congestion notification. On a connection where
+    /// both sides offer it, routers mark data rather than drop it when congested, and the window
+    /// shrinks as it would on a loss.
+    pub fn set_ecn(mut self, value: bool) -> Self {
+        self.ecn = value;
+        self
+    }
+
+    /// Sets whether connections use TCP Fast Open. Listeners then grant cookies and take the
+    /// data of SYNs carrying a valid one, and connecting sockets request a cookie from servers
+    /// they have none for, sending data on the SYN to those they have.
+    pub fn set_fast_open(mut self, value: bool) -> Self {
+        self.fast_open = value;
+        self
+    }
+
+    /// Sets whether connections send segments smaller than the MSS while some of their data is
+    /// unacknowledged, rather than coalesce them until it is (Nagle's algorithm, RFC 896).
+    pub fn set_nodelay(mut self, value: bool) -> Self {
+        self.nodelay = value;
+        self
+    }
+
+    /// Sets whether connections pace new data: segments go out spaced at the rate of the
+    /// congestion control, or else at the congestion window per smoothed round trip, rather
+    /// than in bursts of a window that overflow shallow switch buffers.
+    pub fn set_pacing(mut self, value: bool) -> Self {
+        self.pacing = value;
+        self
+    }
+
+    /// Sets whether connections tell retransmission timeouts of data delayed rather than lost
+    /// from the ACKs that follow, by sending new data rather than retransmitting more, and undo
+    /// the shrinking of the congestion window of those (F-RTO, RFC 5682).
+    pub fn set_frto(mut self, value: bool) -> Self {
+        self.frto = value;
+        self
+    }
+
+    /// Sets whether connections shrink a congestion window that went unused for a while, idle or
+    /// limited by the application, rather than send a stale window at once (RFC 7661).
+    pub fn set_cwnd_validation(mut self, value: bool) -> Self {
+        self.cwnd_validation = value;
+        self
+    }
 }
@@ -1,7 +1,16 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
-use super::{constants::FALLBACK_MSS, established::ControlBlock, isn_generator::IsnGenerator};
+use super::{
+    constants::{FALLBACK_MSS, MAX_WINDOW_SCALE},
+    established::ControlBlock,
+    events::Subscribers,
+    fast_open::{FastOpenGuard, FAST_OPEN_COOKIE_SIZE},
+    isn_generator::IsnGenerator,
+    syn_flood::{SynFloodGuard, SynVerdict},
+    table::ConnectionCount,
+    timestamp::TimestampClock,
+};
 use crate::{
     fail::Fail,
     protocols::{
@@ -10,71 +19,139 @@ use crate::{
         ipv4,
         ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
         tcp::{
+            loopback::Loopback,
             segment::{TcpHeader, TcpOptions2, TcpSegment},
-            SeqNumber,
+            Options as TcpOptions, SeqNumber,
         },
     },
     runtime::Runtime,
     runtime::RuntimeBuf,
     scheduler::SchedulerHandle,
+    stats::{Counters, DropReason},
 };
 use std::collections::{HashMap, HashSet};
 use std::{
     cell::RefCell,
     collections::VecDeque,
-    convert::TryInto,
     future::Future,
     num::Wrapping,
-    rc::Rc,
+    rc::{Rc, Weak},
     task::{Context, Poll, Waker},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-struct InflightAccept {
+struct InflightAccept<RT: Runtime> {
     local_isn: SeqNumber,
     remote_isn: SeqNumber,
-    header_window_size: u16,
     remote_window_scale: Option<u8>,
     mss: usize,
+    /// Whether both sides offered selective acknowledgments.
+    selective_acks: bool,
+    /// Latest timestamp of the remote, if both sides offered timestamps.
+    ts_recent: Option<u32>,
+    /// Whether both sides offered explicit congestion notification.
+    ecn: bool,
+    /// Data of the SYN taken with TCP Fast Open, to be read once the connection is accepted.
+    syn_data: RT::Buf,
 
     #[allow(unused)]
     handle: SchedulerHandle,
 }
 
+/// A segment a listening socket turned away, with whether to answer it with a RST, as segments
+/// other than SYNs that match no handshake are in the LISTEN and SYN-RECEIVED states of RFC 793.
+#[derive(Debug)]
+pub struct Rejection {
+    pub error: Fail,
+    pub reset: bool,
+}
+
+impl Rejection {
+    fn reset(error: Fail) -> Self {
+        Self { error, reset: true }
+    }
+}
+
+impl From<Fail> for Rejection {
+    fn from(error: Fail) -> Self {
+        Self {
+            error,
+            reset: false,
+        }
+    }
+}
+
+/// Accept queue of a listening socket: connections established but not accepted yet.
 struct ReadySockets<RT: Runtime> {
     ready: VecDeque<Result<ControlBlock<RT>, Fail>>,
     endpoints: HashSet<ipv4::Endpoint>,
-    waker: Option<Waker>,
+    /// Accepts waiting for a connection, oldest first, under the ticket each got in line with.
+    waiters: VecDeque<(u64, Waker)>,
+    next_ticket: u64,
 }
 
 impl<RT: Runtime> ReadySockets<RT> {
     fn push_ok(&mut self, cb: ControlBlock<RT>) {
         assert!(self.endpoints.insert(cb.get_remote()));
         self.ready.push_back(Ok(cb));
-        if let Some(w) = self.waker.take() {
-            w.wake()
-        }
+        self.wake_next();
     }
 
-    fn push_err(&mut self, err: Fail) {
-        self.ready.push_back(Err(err));
-        if let Some(w) = self.waker.take() {
-            w.wake()
+    /// Wakes the accept first in line, if a connection awaits it.
+    fn wake_next(&self) {
+        if self.ready.is_empty() {
+            return;
+        }
+        if let Some((_, w)) = self.waiters.front() {
+            w.wake_by_ref()
         }
     }
 
-    fn poll(&mut self, ctx: &mut Context) -> Poll<Result<ControlBlock<RT>, Fail>> {
-        let r = match self.ready.pop_front() {
-            Some(r) => r,
+    /// Hands the oldest connection over to the accept holding `ticket`, unless another accept
+    /// has waited longer. Accepts that have to wait get a ticket, which keeps their place in line
+    /// until they are served or [ReadySockets::cancel] gives it up.
+    fn poll(
+        &mut self,
+        ctx: &mut Context,
+        ticket: &mut Option<u64>,
+    ) -> Poll<Result<ControlBlock<RT>, Fail>> {
+        let first = match (self.waiters.front(), *ticket) {
+            (None, _) => true,
+            (Some(&(t, _)), Some(ticket)) => t == ticket,
+            (Some(_), None) => false,
+        };
+        if first {
+            if let Some(r) = self.ready.pop_front() {
+                if ticket.take().is_some() {
+                    self.waiters.pop_front();
+                }
+                if let Ok(ref cb) = r {
+                    assert!(self.endpoints.remove(&cb.get_remote()));
+                }
+                self.wake_next();
+                return Poll::Ready(r);
+            }
+        }
+        let waker = ctx.waker().clone();
+        match ticket.and_then(|t| self.waiters.iter_mut().find(|(w, _)| *w == t)) {
+            Some(waiter) => waiter.1 = waker,
             None => {
-                self.waker.replace(ctx.waker().clone());
-                return Poll::Pending;
+                let t = self.next_ticket;
+                self.next_ticket += 1;
+                self.waiters.push_back((t, waker));
+                *ticket = Some(t);
             }
-        };
-        if let Ok(ref cb) = r {
-            assert!(self.endpoints.remove(&cb.get_remote()));
         }
-        Poll::Ready(r)
+        Poll::Pending
+    }
+
+    /// Takes the accept holding `ticket` out of line.
+    fn cancel(&mut self, ticket: u64) {
+        let first = matches!(self.waiters.front(), Some(&(t, _)) if t == ticket);
+        self.waiters.retain(|(t, _)| *t != ticket);
+        if first {
+            self.wake_next();
+        }
     }
 
     fn len(&self) -> usize {
@@ -83,86 +160,181 @@ impl<RT: Runtime> ReadySockets<RT> {
 }
 
 pub struct PassiveSocket<RT: Runtime> {
-    inflight: HashMap<ipv4::Endpoint, InflightAccept>,
+    /// Handshakes in progress, which their background tasks take out once they give up on the
+    /// final ACK.
+    inflight: Rc<RefCell<HashMap<ipv4::Endpoint, InflightAccept<RT>>>>,
     ready: Rc<RefCell<ReadySockets<RT>>>,
 
     max_backlog: usize,
     isn_generator: IsnGenerator,
+    syn_guard: SynFloodGuard,
+    fast_open: FastOpenGuard,
 
     local: ipv4::Endpoint,
     rt: RT,
     arp: arp::Peer<RT>,
+    loopback: Loopback<RT>,
+    counters: Counters,
+    subscribers: Subscribers,
+    /// Count of the connections of the peer, which handshakes in progress and connections waiting
+    /// to be accepted are part of.
+    connections: ConnectionCount,
+    tcp_options: TcpOptions<RT>,
+    ts_clock: TimestampClock,
 }
 
 impl<RT: Runtime> PassiveSocket<RT> {
-    pub fn new(local: ipv4::Endpoint, max_backlog: usize, rt: RT, arp: arp::Peer<RT>) -> Self {
+    pub fn new(
+        local: ipv4::Endpoint,
+        max_backlog: usize,
+        rt: RT,
+        arp: arp::Peer<RT>,
+        loopback: Loopback<RT>,
+        counters: Counters,
+        subscribers: Subscribers,
+        connections: ConnectionCount,
+        tcp_options: TcpOptions<RT>,
+        ts_clock: TimestampClock,
+    ) -> Self {
         let ready = ReadySockets {
             ready: VecDeque::new(),
             endpoints: HashSet::new(),
-            waker: None,
+            waiters: VecDeque::new(),
+            next_ticket: 0,
         };
         let ready = Rc::new(RefCell::new(ready));
         let nonce = rt.rng_gen();
+        let syn_guard = SynFloodGuard::new(
+            tcp_options.syn_flood_listener_rate(),
+            tcp_options.syn_flood_source_rate(),
+            rt.rng_gen(),
+            rt.now(),
+        );
+        let fast_open = FastOpenGuard::new(rt.rng_gen());
         Self {
-            inflight: HashMap::new(),
+            inflight: Rc::new(RefCell::new(HashMap::new())),
             ready,
             max_backlog,
-            isn_generator: IsnGenerator::new(nonce),
+            isn_generator: IsnGenerator::new(nonce, rt.now()),
+            syn_guard,
+            fast_open,
             local,
             rt,
             arp,
+            loopback,
+            counters,
+            subscribers,
+            connections,
+            tcp_options,
+            ts_clock,
         }
     }
 
-    pub fn poll_accept(&mut self, ctx: &mut Context) -> Poll<Result<ControlBlock<RT>, Fail>> {
-        self.ready.borrow_mut().poll(ctx)
+    /// Replaces the options used for connections accepted from now on.
+    pub fn set_options(&mut self, tcp_options: TcpOptions<RT>) {
+        self.syn_guard.set_thresholds(
+            tcp_options.syn_flood_listener_rate(),
+            tcp_options.syn_flood_source_rate(),
+        );
+        self.tcp_options = tcp_options;
+    }
+
+    /// Returns the number of connections waiting to be accepted.
+    pub fn ready_len(&self) -> usize {
+        self.ready.borrow().len()
+    }
+
+    /// Returns the remote endpoints of the handshakes in progress and of the connections waiting
+    /// to be accepted.
+    pub fn pending(&self) -> Vec<ipv4::Endpoint> {
+        let ready = self.ready.borrow();
+        let inflight = self.inflight.borrow();
+        inflight
+            .keys()
+            .chain(ready.endpoints.iter())
+            .copied()
+            .collect()
+    }
+
+    /// Takes the oldest connection off the accept queue, serving concurrent accepts in the order
+    /// they started waiting. `ticket` holds the place in line of the accept polling.
+    pub fn poll_accept(
+        &mut self,
+        ctx: &mut Context,
+        ticket: &mut Option<u64>,
+    ) -> Poll<Result<ControlBlock<RT>, Fail>> {
+        self.ready.borrow_mut().poll(ctx, ticket)
+    }
+
+    /// Gives up the place in line of an accept dropped while waiting.
+    pub fn cancel_accept(&mut self, ticket: u64) {
+        self.ready.borrow_mut().cancel(ticket)
     }
 
-    pub fn receive(&mut self, ip_header: &Ipv4Header, header: &TcpHeader) -> Result<(), Fail> {
+    /// Wakes the accepts waiting on a listener about to go away, for them to find it closed, and
+    /// discounts the connections going away with it.
+    pub fn close(&mut self) {
+        for remote in self.pending() {
+            self.connections.remove(&(self.local, remote));
+        }
+        for (_, waker) in self.ready.borrow_mut().waiters.drain(..) {
+            waker.wake();
+        }
+    }
+
+    pub fn receive(
+        &mut self,
+        ip_header: &Ipv4Header,
+        header: &TcpHeader,
+        data: RT::Buf,
+    ) -> Result<(), Rejection> {
         let remote = ipv4::Endpoint::new(ip_header.src_addr, header.src_port);
         if self.ready.borrow().endpoints.contains(&remote) {
             // TODO: What should we do if a packet shows up for a connection that hasn't been
             // `accept`ed yet?
             return Ok(());
         }
-        let inflight_len = self.inflight.len();
+        let inflight_len = self.inflight.borrow().len();
 
         // If the packet is for an inflight connection, route it there.
-        if self.inflight.contains_key(&remote) {
+        if self.inflight.borrow().contains_key(&remote) {
             if !header.ack {
                 return Err(Fail::Malformed {
                     details: "Expected ACK",
-                });
+                }
+                .into());
             }
-            debug!("Received ACK: {:?}", header);
+            debug!("Received ACK: {}", header);
             // TODO: Add entry API.
             let &InflightAccept {
                 local_isn,
                 remote_isn,
-                header_window_size,
                 remote_window_scale,
                 mss,
+                selective_acks,
+                ts_recent,
+                ecn,
                 ..
-            } = self.inflight.get(&remote).unwrap();
+            } = self.inflight.borrow().get(&remote).unwrap();
             if header.ack_num != local_isn + Wrapping(1) {
-                return Err(Fail::Malformed {
+                return Err(Rejection::reset(Fail::Malformed {
                     details: "Invalid SYN+ACK seq num",
-                });
+                }));
+            }
+            // With the accept queue full, drop the ACK and keep the handshake: the SYN+ACK goes
+            // out again, and the ACK answering it gets in once an accept made room.
+            if self.ready.borrow().len() >= self.max_backlog {
+                return Err(Fail::ConnectionRefused {}.into());
             }
 
-            let tcp_options = self.rt.tcp_options();
+            let tcp_options = &self.tcp_options;
             let (local_window_scale, remote_window_scale) = match remote_window_scale {
                 Some(w) => (tcp_options.window_scale() as u32, w),
                 None => (0, 0),
             };
-            let remote_window_size = (header_window_size)
-                .checked_shl(remote_window_scale as u32)
-                .expect("TODO: Window size overflow")
-                .try_into()
-                .expect("TODO: Window size overflow");
-            let local_window_size = (tcp_options.receive_window_size() as u32)
-                .checked_shl(local_window_scale as u32)
-                .expect("TODO: Window size overflow");
+            // Unlike that of the SYN, the window of this ACK is scaled.
+            let remote_window_size = (header.window_size as u32) << remote_window_scale;
+            let local_window_size = tcp_options.receive_window(local_window_scale);
             info!(
                 "Window sizes: local {}, remote {}",
                 local_window_size, remote_window_size
@@ -172,75 +344,288 @@ impl<RT: Runtime> PassiveSocket<RT> {
                 local_window_scale, remote_window_scale
             );
 
-            self.inflight.remove(&remote);
+            // The ACK carries a newer timestamp than the SYN did.
+            let ts_recent = ts_recent.map(|ts| header.sender_timestamp().unwrap_or(ts));
+
+            let syn_data = self.inflight.borrow_mut().remove(&remote).unwrap().syn_data;
             let cb = ControlBlock::new(
                 self.local,
                 remote,
                 self.rt.clone(),
                 self.arp.clone(),
+                self.loopback.clone(),
+                self.counters.clone(),
+                self.subscribers.clone(),
+                tcp_options.clone(),
                 remote_isn + Wrapping(1),
-                self.rt.tcp_options().ack_delay_timeout(),
                 local_window_size,
                 local_window_scale,
                 local_isn + Wrapping(1),
                 remote_window_size,
                 remote_window_scale,
                 mss,
-                tcp_options.congestion_ctrl_type(),
-                tcp_options.congestion_ctrl_options(),
+                selective_acks,
+                ts_recent.map(|_| self.ts_clock),
+                ts_recent,
+                ecn,
             );
+            if !syn_data.is_empty() {
+                cb.receive_syn_data(syn_data);
+            }
             self.ready.borrow_mut().push_ok(cb);
             return Ok(());
         }
 
+        // The final ACK of a handshake answered with a SYN cookie.
+        let now = self.rt.now();
+        if header.ack && !header.syn && !header.rst && self.syn_guard.sent_cookies(now) {
+            return self.receive_cookie_ack(remote, header, now);
+        }
+
         // Otherwise, start a new connection.
         if !header.syn || header.ack || header.rst {
-            return Err(Fail::Malformed {
+            return Err(Rejection::reset(Fail::Malformed {
                 details: "Invalid flags",
-            });
+            }));
         }
-        debug!("Received SYN: {:?}", header);
-        if inflight_len + self.ready.borrow().len() >= self.max_backlog {
-            // TODO: Should we send a RST here?
-            return Err(Fail::ConnectionRefused {});
+        debug!("Received SYN: {}", header);
+        let ready_len = self.ready.borrow().len();
+        if ready_len >= self.max_backlog {
+            // Like a lost SYN, left for the remote to retransmit.
+            return Err(Fail::ConnectionRefused {}.into());
         }
-        let local_isn = self.isn_generator.generate(&self.local, &remote);
-        let remote_isn = header.seq_num;
-        let future = Self::background(
-            local_isn,
-            remote_isn,
-            self.local,
-            remote,
-            self.rt.clone(),
-            self.arp.clone(),
-            self.ready.clone(),
-        );
-        let handle = self.rt.spawn(future);
 
         let mut remote_window_scale = None;
         let mut mss = FALLBACK_MSS;
+        let mut selective_acks = false;
+        let mut ts_recent = None;
+        let mut fast_open_cookie = None;
         for option in header.iter_options() {
             match option {
                 TcpOptions2::WindowScale(w) => {
                     info!("Received window scale: {:?}", w);
-                    remote_window_scale = Some(*w);
+                    // RFC 7323, section 2.3: a larger shift count is taken as the largest one.
+                    remote_window_scale = Some((*w).min(MAX_WINDOW_SCALE));
                 }
                 TcpOptions2::MaximumSegmentSize(m) => {
                     info!("Received advertised MSS: {}", m);
                     mss = *m as usize;
                 }
+                TcpOptions2::SelectiveAcknowlegementPermitted => {
+                    info!("Received SACK permitted");
+                    selective_acks = self.tcp_options.selective_acks();
+                }
+                TcpOptions2::Timestamp {
+                    sender_timestamp, ..
+                } if self.tcp_options.timestamps() => {
+                    info!("Received timestamp: {}", sender_timestamp);
+                    ts_recent = Some(*sender_timestamp);
+                }
+                TcpOptions2::FastOpen { len, cookie } if self.tcp_options.fast_open() => {
+                    info!("Received TCP Fast Open option");
+                    fast_open_cookie = Some(&cookie[..*len]);
+                }
                 _ => continue,
             }
         }
+        // RFC 3168, section 6.1.1: an ECN-setup SYN has both ECE and CWR set.
+        let ecn = header.ece && header.cwr && self.tcp_options.ecn();
+
+        // Stop keeping handshake state once the listener is flooded or its backlog is full of
+        // handshakes.
+        let verdict = self.syn_guard.on_syn(remote.addr, now, self.rt.rng_gen());
+        match verdict {
+            SynVerdict::Drop => {
+                return Err(Fail::ResourceExhausted {
+                    details: "SYN rate of source exceeded",
+                }
+                .into())
+            }
+            SynVerdict::Cookie => return Ok(self.send_cookie(remote, header.seq_num, mss, now)?),
+            SynVerdict::Accept if inflight_len + ready_len >= self.max_backlog => {
+                return Ok(self.send_cookie(remote, header.seq_num, mss, now)?)
+            }
+            SynVerdict::Accept => (),
+        }
+
+        // RFC 7413, section 4.2.2: take the data of a SYN carrying a valid cookie, unless the
+        // SYN may be a replay, and grant a cookie to clients asking for one or holding a stale
+        // one.
+        let mut syn_data = RT::Buf::empty();
+        let mut grant = None;
+        match fast_open_cookie {
+            None => (),
+            Some(cookie) if !self.fast_open.check_cookie(remote.addr, cookie) => {
+                if !cookie.is_empty() {
+                    self.counters.update(|s| s.tcp.fast_open_passive_fails += 1);
+                }
+                grant = Some(self.fast_open.cookie(remote.addr));
+            }
+            Some(_) if data.is_empty() => (),
+            Some(_) => {
+                if self.fast_open.check_replay(remote, header.seq_num, now) {
+                    self.counters.update(|s| s.tcp.fast_open_passive += 1);
+                    syn_data = data;
+                } else {
+                    self.counters.update(|s| s.tcp.fast_open_replays += 1);
+                }
+            }
+        }
+
+        let local_isn = self.isn_generator.generate(&self.local, &remote, now);
+        let remote_isn = header.seq_num;
+        let future = Self::background(
+            local_isn,
+            remote_isn,
+            self.local,
+            remote,
+            self.rt.clone(),
+            self.arp.clone(),
+            self.loopback.clone(),
+            self.counters.clone(),
+            self.connections.clone(),
+            self.tcp_options.clone(),
+            Rc::downgrade(&self.inflight),
+            remote_window_scale.is_some(),
+            selective_acks,
+            ts_recent.map(|ts| (self.ts_clock, ts)),
+            ecn,
+            syn_data.len(),
+            grant,
+        );
+        let handle = self.rt.spawn(future);
+        self.counters.update(|s| s.tcp.passive_opens += 1);
+
         let accept = InflightAccept {
             local_isn,
             remote_isn,
-            header_window_size: header.window_size,
             remote_window_scale,
             mss,
+            selective_acks,
+            ts_recent,
+            ecn,
+            syn_data,
             handle,
         };
-        self.inflight.insert(remote, accept);
+        self.inflight.borrow_mut().insert(remote, accept);
+        self.connections.add((self.local, remote));
+        Ok(())
+    }
+
+    /// Answers a SYN with a SYN+ACK carrying a SYN cookie, keeping no state.
+    fn send_cookie(
+        &mut self,
+        remote: ipv4::Endpoint,
+        remote_isn: SeqNumber,
+        mss: usize,
+        now: Instant,
+    ) -> Result<(), Fail> {
+        let remote_link_addr = match self.arp.try_query(remote.addr) {
+            Some(r) => r,
+            None => {
+                self.counters.count_drop(DropReason::UnresolvedArp);
+                return Ok(());
+            }
+        };
+        let (local_isn, mss) = self
+            .syn_guard
+            .cookie(&self.local, &remote, remote_isn, mss, now);
+        debug!(
+            "Sending SYN cookie {} (MSS {}) to {:?}",
+            local_isn, mss, remote
+        );
+        // A cookie cannot hold the window scale of the remote or whether it offered selective
+        // acknowledgments, timestamps or ECN, so offer none of them.
+        let tcp_hdr = syn_ack_header(
+            &self.local,
+            &remote,
+            local_isn,
+            remote_isn,
+            &self.tcp_options,
+            false,
+            false,
+            None,
+            false,
+        );
+        let segment = TcpSegment {
+            ethernet2_hdr: Ethernet2Header {
+                dst_addr: remote_link_addr,
+                src_addr: self.rt.local_link_addr(),
+                ether_type: EtherType2::Ipv4,
+            },
+            ipv4_hdr: Ipv4Header::new(self.local.addr, remote.addr, Ipv4Protocol2::Tcp),
+            tcp_hdr,
+            data: RT::Buf::empty(),
+            tx_checksum_offload: self.tcp_options.tx_checksum_offload(),
+            pseudo_header_sum: None,
+        };
+        match self.loopback.transmit(segment) {
+            Ok(()) => self.counters.update(|s| {
+                s.ip.out_requests += 1;
+                s.tcp.out_segs += 1;
+                s.tcp.syncookies_sent += 1;
+            }),
+            Err(e) => {
+                warn!("Failed to send SYN cookie: {:?}", e);
+                self.counters.count_transmit_drop(&e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens a connection from the ACK of a SYN+ACK carrying a SYN cookie.
+    fn receive_cookie_ack(
+        &mut self,
+        remote: ipv4::Endpoint,
+        header: &TcpHeader,
+        now: Instant,
+    ) -> Result<(), Rejection> {
+        let mss = match self.syn_guard.check_cookie(
+            &self.local,
+            &remote,
+            header.seq_num,
+            header.ack_num,
+            now,
+        ) {
+            Some(mss) => mss,
+            None => {
+                self.counters.update(|s| s.tcp.syncookies_failed += 1);
+                return Err(Rejection::reset(Fail::Malformed {
+                    details: "Invalid SYN cookie",
+                }));
+            }
+        };
+        if self.ready.borrow().len() >= self.max_backlog {
+            return Err(Fail::ConnectionRefused {}.into());
+        }
+        debug!("Received ACK of SYN cookie: {}", header);
+        self.counters.update(|s| {
+            s.tcp.passive_opens += 1;
+            s.tcp.syncookies_recv += 1;
+        });
+        let cb = ControlBlock::new(
+            self.local,
+            remote,
+            self.rt.clone(),
+            self.arp.clone(),
+            self.loopback.clone(),
+            self.counters.clone(),
+            self.subscribers.clone(),
+            self.tcp_options.clone(),
+            header.seq_num,
+            self.tcp_options.receive_window(0),
+            0,
+            header.ack_num,
+            header.window_size as u32,
+            0,
+            mss,
+            false,
+            None,
+            None,
+            false,
+        );
+        self.ready.borrow_mut().push_ok(cb);
+        self.connections.add((self.local, remote));
         Ok(())
     }
 
@@ -251,36 +636,48 @@ impl<RT: Runtime> PassiveSocket<RT> {
         remote: ipv4::Endpoint,
         rt: RT,
         arp: arp::Peer<RT>,
-        ready: Rc<RefCell<ReadySockets<RT>>>,
+        loopback: Loopback<RT>,
+        counters: Counters,
+        connections: ConnectionCount,
+        tcp_options: TcpOptions<RT>,
+        inflight: Weak<RefCell<HashMap<ipv4::Endpoint, InflightAccept<RT>>>>,
+        window_scale: bool,
+        selective_acks: bool,
+        timestamp: Option<(TimestampClock, u32)>,
+        ecn: bool,
+        syn_data_len: usize,
+        fast_open_cookie: Option<[u8; FAST_OPEN_COOKIE_SIZE]>,
     ) -> impl Future<Output = ()> {
-        let tcp_options = rt.tcp_options();
         let handshake_retries: usize = tcp_options.handshake_retries();
-        let handshake_timeout: Duration = tcp_options.handshake_timeout();
+        let mut handshake_timeout: Duration = tcp_options.handshake_timeout();
 
         async move {
-            for _ in 0..handshake_retries {
+            for attempt in 0..handshake_retries {
                 let remote_link_addr = match arp.query(remote.address()).await {
                     Ok(r) => r,
                     Err(e) => {
                         warn!("ARP query failed: {:?}", e);
+                        counters.count_drop(DropReason::UnresolvedArp);
                         continue;
                     }
                 };
-                let mut tcp_hdr = TcpHeader::new(local.port, remote.port);
-                tcp_hdr.syn = true;
-                tcp_hdr.seq_num = local_isn;
-                tcp_hdr.ack = true;
-                tcp_hdr.ack_num = remote_isn + Wrapping(1);
-                tcp_hdr.window_size = tcp_options.receive_window_size();
-
-                let mss = tcp_options.advertised_mss() as u16;
-                tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(mss));
-                info!("Advertising MSS: {}", mss);
-
-                tcp_hdr.push_option(TcpOptions2::WindowScale(tcp_options.window_scale()));
-                info!("Advertising window scale: {}", tcp_options.window_scale());
-
-                debug!("Sending SYN+ACK: {:?}", tcp_hdr);
+                let mut tcp_hdr = syn_ack_header(
+                    &local,
+                    &remote,
+                    local_isn,
+                    remote_isn,
+                    &tcp_options,
+                    window_scale,
+                    selective_acks,
+                    timestamp.map(|(clock, echo)| (clock.value(rt.now()), echo)),
+                    ecn,
+                );
+                // Data taken from the SYN gets acknowledged along with it.
+                tcp_hdr.ack_num += Wrapping(syn_data_len as u32);
+                if let Some(cookie) = fast_open_cookie {
+                    tcp_hdr.push_option(TcpOptions2::fast_open(&cookie));
+                }
+                debug!("Sending SYN+ACK: {}", tcp_hdr);
                 let segment = TcpSegment {
                     ethernet2_hdr: Ethernet2Header {
                         dst_addr: remote_link_addr,
@@ -291,11 +688,80 @@ impl<RT: Runtime> PassiveSocket<RT> {
                     tcp_hdr,
                     data: RT::Buf::empty(),
                     tx_checksum_offload: tcp_options.tx_checksum_offload(),
+                    pseudo_header_sum: None,
                 };
-                rt.transmit(segment);
+                match loopback.transmit(segment) {
+                    Ok(()) => counters.update(|s| {
+                        s.ip.out_requests += 1;
+                        s.tcp.out_segs += 1;
+                        if attempt > 0 {
+                            s.tcp.retrans_segs += 1;
+                        }
+                    }),
+                    Err(e) => {
+                        warn!("Failed to send SYN+ACK: {:?}", e);
+                        counters.count_transmit_drop(&e);
+                    }
+                }
                 rt.wait(handshake_timeout).await;
+                // As for SYNs, wait twice as long for every SYN+ACK left unanswered.
+                handshake_timeout = (handshake_timeout * 2).min(tcp_options.rto_upper_bound());
+            }
+            // The half-open connection goes away without the application ever seeing it, leaving
+            // its place in the backlog to others. Dropping its own handle only marks this task
+            // for the scheduler to remove once it returns.
+            counters.update(|s| s.tcp.attempt_fails += 1);
+            if let Some(inflight) = inflight.upgrade() {
+                inflight.borrow_mut().remove(&remote);
+                connections.remove(&(local, remote));
             }
-            ready.borrow_mut().push_err(Fail::Timeout {});
         }
     }
 }
+
+/// Builds the SYN+ACK answering a SYN with sequence number `remote_isn`, offering window scaling
+/// if `window_scale` is set, selective acknowledgments if `selective_acks` is, the timestamp
+/// option with the given value and echoed timestamp if `timestamp` is set and explicit congestion
+/// notification if `ecn` is.
+pub(super) fn syn_ack_header<RT: Runtime>(
+    local: &ipv4::Endpoint,
+    remote: &ipv4::Endpoint,
+    local_isn: SeqNumber,
+    remote_isn: SeqNumber,
+    tcp_options: &TcpOptions<RT>,
+    window_scale: bool,
+    selective_acks: bool,
+    timestamp: Option<(u32, u32)>,
+    ecn: bool,
+) -> TcpHeader {
+    let mut tcp_hdr = TcpHeader::new(local.port, remote.port);
+    tcp_hdr.syn = true;
+    tcp_hdr.seq_num = local_isn;
+    tcp_hdr.ack = true;
+    tcp_hdr.ack_num = remote_isn + Wrapping(1);
+    tcp_hdr.window_size = tcp_options.receive_window(0) as u16;
+    // RFC 3168, section 6.1.1: an ECN-setup SYN+ACK has ECE set, but not CWR.
+    tcp_hdr.ece = ecn;
+
+    let mss = tcp_options.advertised_mss() as u16;
+    tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(mss));
+    info!("Advertising MSS: {}", mss);
+
+    if window_scale {
+        tcp_hdr.push_option(TcpOptions2::WindowScale(tcp_options.window_scale()));
+        info!("Advertising window scale: {}", tcp_options.window_scale());
+    }
+
+    if selective_acks {
+        tcp_hdr.push_option(TcpOptions2::SelectiveAcknowlegementPermitted);
+        info!("Advertising SACK permitted");
+    }
+
+    if let Some((sender_timestamp, echo_timestamp)) = timestamp {
+        tcp_hdr.push_option(TcpOptions2::Timestamp {
+            sender_timestamp,
+            echo_timestamp,
+        });
+    }
+    tcp_hdr
+}
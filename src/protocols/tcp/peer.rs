@@ -2,174 +2,551 @@
 // Licensed under the MIT license.
 
 use super::{
-    active_open::ActiveOpenSocket, established::EstablishedSocket, isn_generator::IsnGenerator,
-    passive_open::PassiveSocket,
+    active_open::ActiveOpenSocket,
+    established::{cc::CongestionControlConstructor, fsm, ControlBlock, EstablishedSocket, State},
+    events::{CloseSummary, Readiness, StateTransition, Subscribers},
+    fast_open::FastOpenCache,
+    isn_generator::IsnGenerator,
+    loopback::Loopback,
+    migration::ConnectionState,
+    passive_open::{PassiveSocket, Rejection},
+    rate_limit::TokenBucket,
+    table::{flow_hash, ConnectionCount, ConnectionKey, ConnectionTable},
+    time_wait::{TimeWaitEntry, TimeWaitTable},
+    timestamp::TimestampClock,
 };
 use crate::{
     fail::Fail,
     file_table::{File, FileDescriptor, FileTable},
+    netstat::{SocketInfo, SocketState},
     protocols::{
         arp,
         ethernet2::frame::{EtherType2, Ethernet2Header},
+        ethernet2::MacAddress,
+        icmpv4::FragmentationNeeded,
         ip,
         ip::port::EphemeralPorts,
         ipv4,
-        ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
+        ipv4::datagram::{Ipv4Header, Ipv4Protocol2, IPV4_ECN_CE},
         tcp::{
-            operations::{AcceptFuture, ConnectFuture, ConnectFutureState, PopFuture, PushFuture},
-            segment::{TcpHeader, TcpSegment},
+            operations::{
+                AcceptFuture, ConnectFuture, ConnectFutureState, IncomingStream, PeekFuture,
+                PopExactFuture, PopFuture, PopMaxFuture, PushFuture,
+            },
+            segment::{TcpHeader, TcpOptions2, TcpSegment},
+            seq_gt, Options as TcpOptions, TcpInfo,
         },
+        Protocol,
     },
-    runtime::Runtime,
-    runtime::RuntimeBuf,
+    runtime::{Resource, Runtime, RuntimeBuf},
+    scheduler::SchedulerHandle,
+    stats::{Counters, DropReason},
 };
-use futures::channel::mpsc;
+use byteorder::{ByteOrder, NetworkEndian};
+use futures::{channel::mpsc, future};
 use std::collections::HashMap;
 use std::{
     cell::RefCell,
-    rc::Rc,
+    convert::TryFrom,
+    net::{Ipv4Addr, Shutdown},
+    num::Wrapping,
+    rc::{Rc, Weak},
     task::{Context, Poll},
     time::Duration,
 };
 
+/// Longest time between two passes of the idle reaping policy.
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct Peer<RT: Runtime> {
     pub(super) inner: Rc<RefCell<Inner<RT>>>,
 }
 
+impl<RT: Runtime> Clone for Peer<RT> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
 impl<RT: Runtime> Peer<RT> {
-    pub fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable) -> Self {
+    pub fn new(
+        rt: RT,
+        arp: arp::Peer<RT>,
+        file_table: FileTable,
+        counters: Counters,
+        options: TcpOptions<RT>,
+    ) -> Self {
         let (tx, rx) = mpsc::unbounded();
+        let loopback = Loopback::new(rt.clone());
         let inner = Rc::new(RefCell::new(Inner::new(
             rt.clone(),
             arp,
+            loopback.clone(),
             file_table,
+            counters,
+            options,
             tx,
             rx,
         )));
-        Self { inner }
+        let loopback_work = rt.spawn(Self::receive_loopback(loopback, Rc::downgrade(&inner)));
+        inner.borrow_mut().loopback_work = Some(loopback_work);
+        let peer = Self { inner };
+        peer.schedule_reaping();
+        peer
+    }
+
+    /// Starts applying the idle reaping policy in the background if the options set one, and stops
+    /// otherwise.
+    fn schedule_reaping(&self) {
+        let rt = {
+            let inner = self.inner.borrow();
+            inner.options.idle_reaping().map(|_| inner.rt.clone())
+        };
+        let inner = Rc::downgrade(&self.inner);
+        let reap_work = rt.map(|rt| rt.spawn(Self::reap_idle_connections(rt.clone(), inner)));
+        self.inner.borrow_mut().reap_work = reap_work;
     }
 
-    pub fn socket(&self) -> FileDescriptor {
+    /// Background task applying the idle reaping policy, at least once per [REAP_INTERVAL] and
+    /// once per idle timeout, for as long as the options set a policy.
+    async fn reap_idle_connections(rt: RT, inner: Weak<RefCell<Inner<RT>>>) {
+        loop {
+            let policy = inner
+                .upgrade()
+                .and_then(|inner| inner.borrow().options.idle_reaping());
+            let interval = match policy {
+                Some(policy) => policy.idle_timeout.min(REAP_INTERVAL),
+                None => return,
+            };
+            rt.wait(interval).await;
+            let inner = match inner.upgrade() {
+                Some(inner) => inner,
+                None => return,
+            };
+            inner.borrow_mut().reap_idle();
+        }
+    }
+
+    /// Background task feeding the segments our connections send to each other back to their
+    /// receive path.
+    async fn receive_loopback(loopback: Loopback<RT>, inner: Weak<RefCell<Inner<RT>>>) {
+        loop {
+            let segment = future::poll_fn(|ctx| loopback.poll_pop(ctx)).await;
+            let inner = match inner.upgrade() {
+                Some(inner) => inner,
+                None => return,
+            };
+            let mut inner = inner.borrow_mut();
+            inner.counters.update(|s| s.tcp.in_segs += 1);
+            let TcpSegment {
+                ipv4_hdr,
+                tcp_hdr,
+                data,
+                ..
+            } = segment;
+            if let Err(e) = inner.receive_segment(&ipv4_hdr, &tcp_hdr, data) {
+                warn!("Dropped looped back segment {}: {:?}", tcp_hdr, e);
+            }
+        }
+    }
+
+    pub fn socket(&self) -> Result<FileDescriptor, Fail> {
         let mut inner = self.inner.borrow_mut();
-        let fd = inner.file_table.alloc(File::TcpSocket);
+        inner.check_file_table()?;
+        let fd = inner.file_table.alloc(File::TcpSocket, inner.rt.now());
         assert!(inner
             .sockets
-            .insert(fd, Socket::Inactive { local: None })
+            .insert(
+                fd,
+                Socket::Inactive {
+                    local: None,
+                    reuse_port: false,
+                }
+            )
             .is_none());
-        fd
+        Ok(fd)
     }
 
+    /// Binds the socket to `addr`. A socket that then connects does so from `addr` rather than
+    /// from an ephemeral port, and from the engine's address if that of `addr` is unspecified.
     pub fn bind(&self, fd: FileDescriptor, addr: ipv4::Endpoint) -> Result<(), Fail> {
         let mut inner = self.inner.borrow_mut();
-        if addr.port() >= ip::Port::first_private_port() {
-            return Err(Fail::Malformed {
-                details: "Port number in private port range",
-            });
-        }
         match inner.sockets.get_mut(&fd) {
-            Some(Socket::Inactive { ref mut local }) => {
+            Some(Socket::Inactive { ref mut local, .. }) => {
                 *local = Some(addr);
                 Ok(())
             }
+            None => Err(Fail::BadFileDescriptor {}),
+            _ => Err(Fail::Malformed {
+                details: "Invalid file descriptor",
+            }),
+        }
+    }
+
+    /// Lets the socket listen on a port other sockets with this option already listen on, or
+    /// later will. SYNs for the port are then spread over the listening sockets by [flow_hash].
+    pub fn set_reuse_port(&self, fd: FileDescriptor, enable: bool) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        match inner.sockets.get_mut(&fd) {
+            Some(Socket::Inactive {
+                ref mut reuse_port, ..
+            }) => {
+                *reuse_port = enable;
+                Ok(())
+            }
+            None => Err(Fail::BadFileDescriptor {}),
+            _ => Err(Fail::Malformed {
+                details: "Invalid file descriptor",
+            }),
+        }
+    }
+
+    /// Makes the connections of the socket use `constructor` for their congestion control rather
+    /// than the one of the peer's options.
+    pub fn set_congestion_ctrl(
+        &self,
+        fd: FileDescriptor,
+        constructor: CongestionControlConstructor<RT>,
+    ) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Inactive { .. }) => {
+                inner.socket_options.entry(fd).or_default().congestion_ctrl = Some(constructor);
+                Ok(())
+            }
+            None => Err(Fail::BadFileDescriptor {}),
             _ => Err(Fail::Malformed {
                 details: "Invalid file descriptor",
             }),
         }
     }
 
+    /// Sets whether the connections of the socket send segments smaller than the MSS while some
+    /// of their data is unacknowledged, rather than coalesce them (TCP_NODELAY). Connections
+    /// established already switch at once, and accepted ones inherit the setting of their
+    /// listening socket.
+    pub fn set_nodelay(&self, fd: FileDescriptor, enable: bool) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => {
+                if let Some(s) = inner.established.get(&(*local, *remote)) {
+                    s.set_nodelay(enable);
+                }
+            }
+            None => return Err(Fail::BadFileDescriptor {}),
+            Some(Socket::TimeWait { .. }) | Some(Socket::Failed { .. }) => {
+                return Err(Fail::Malformed {
+                    details: "Invalid file descriptor",
+                })
+            }
+            Some(..) => (),
+        }
+        inner.socket_options.entry(fd).or_default().nodelay = Some(enable);
+        Ok(())
+    }
+
+    /// Sets the bytes the connections of the socket buffer for the application (SO_RCVBUF), which
+    /// their receive window advertises. The window scale goes out on the SYN, so only sockets
+    /// not yet connected or listening take it; accepted connections inherit it.
+    pub fn set_receive_buffer_size(&self, fd: FileDescriptor, size: u32) -> Result<(), Fail> {
+        if size == 0 {
+            return Err(Fail::Invalid {
+                details: "Empty receive buffer",
+            });
+        }
+        let mut inner = self.inner.borrow_mut();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Inactive { .. }) => {
+                inner.socket_options.entry(fd).or_default().receive_buffer_size = Some(size);
+                Ok(())
+            }
+            None => Err(Fail::BadFileDescriptor {}),
+            _ => Err(Fail::Malformed {
+                details: "Invalid file descriptor",
+            }),
+        }
+    }
+
+    /// Sets the bytes the connections of the socket hold for sending, unsent or unacknowledged,
+    /// before pushes fail (SO_SNDBUF). Connections established already take it at once, and
+    /// accepted ones inherit the setting of their listening socket.
+    pub fn set_send_buffer_size(&self, fd: FileDescriptor, size: usize) -> Result<(), Fail> {
+        if size == 0 {
+            return Err(Fail::Invalid {
+                details: "Empty send buffer",
+            });
+        }
+        let mut inner = self.inner.borrow_mut();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => {
+                if let Some(s) = inner.established.get(&(*local, *remote)) {
+                    s.set_send_buffer_size(size);
+                }
+            }
+            None => return Err(Fail::BadFileDescriptor {}),
+            Some(Socket::TimeWait { .. }) | Some(Socket::Failed { .. }) => {
+                return Err(Fail::Malformed {
+                    details: "Invalid file descriptor",
+                })
+            }
+            Some(..) => (),
+        }
+        inner.socket_options.entry(fd).or_default().send_buffer_size = Some(size);
+        Ok(())
+    }
+
     pub fn receive(&self, ip_header: &Ipv4Header, buf: RT::Buf) -> Result<(), Fail> {
         self.inner.borrow_mut().receive(ip_header, buf)
     }
 
+    /// Shrinks the segments of the connection that sent the datagram `msg` is about, if any.
+    pub fn receive_fragmentation_needed(&self, msg: &FragmentationNeeded) {
+        let quote = &msg.quoted_payload;
+        let port = |bytes: &[u8]| ip::Port::try_from(NetworkEndian::read_u16(bytes)).ok();
+        let key = match (port(&quote[0..2]), port(&quote[2..4])) {
+            (Some(local_port), Some(remote_port)) => (
+                ipv4::Endpoint::new(msg.src_addr, local_port),
+                ipv4::Endpoint::new(msg.dst_addr, remote_port),
+            ),
+            _ => return,
+        };
+        let seq_num = Wrapping(NetworkEndian::read_u32(&quote[4..8]));
+        let cb = match self.inner.borrow().established.get(&key) {
+            Some(s) => s.cb.clone(),
+            None => return,
+        };
+        cb.receive_fragmentation_needed(seq_num, msg.next_hop_mtu as usize);
+    }
+
+    /// Starts deferring the pure ACKs of established connections until [Peer::end_batch], so that
+    /// a connection receiving several in a burst of frames only processes the latest.
+    pub fn begin_batch(&self) {
+        self.inner.borrow_mut().batching = true;
+    }
+
+    /// Processes the pure ACKs deferred since [Peer::begin_batch].
+    pub fn end_batch(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.batching = false;
+        inner.collect_failed();
+        for (key, header) in std::mem::take(&mut inner.deferred_acks) {
+            inner.receive_established(key, &header, RT::Buf::empty(), false);
+        }
+    }
+
+    /// Resets a segment from `remote` to `local` that gets refused before reaching any socket,
+    /// such as one rejected by the packet filter.
+    pub fn reject(&self, local: &ipv4::Endpoint, remote: &ipv4::Endpoint) -> Result<(), Fail> {
+        let mut tcp_hdr = TcpHeader::new(local.port, remote.port);
+        tcp_hdr.rst = true;
+        self.inner.borrow_mut().send_rst(local, remote, tcp_hdr)
+    }
+
     pub fn listen(&self, fd: FileDescriptor, backlog: usize) -> Result<(), Fail> {
         let mut inner = self.inner.borrow_mut();
-        let local = match inner.sockets.get_mut(&fd) {
-            Some(Socket::Inactive { local: Some(local) }) => *local,
+        let (local, reuse_port) = match inner.sockets.get_mut(&fd) {
+            Some(Socket::Inactive {
+                local: Some(local),
+                reuse_port,
+            }) => (*local, *reuse_port),
+            None => return Err(Fail::BadFileDescriptor {}),
             _ => {
                 return Err(Fail::Malformed {
                     details: "Invalid file descriptor",
                 })
             }
         };
+        // Private ports are left to the outgoing connections of sockets that aren't bound.
+        if local.port() >= ip::Port::first_private_port() {
+            return Err(Fail::Malformed {
+                details: "Port number in private port range",
+            });
+        }
         // TODO: Should this move to bind?
-        if inner.passive.contains_key(&local) {
-            return Err(Fail::ResourceBusy {
-                details: "Port already in use",
+        if let Some(fds) = inner.listeners.get(&local) {
+            let shared = fds.iter().all(|fd| {
+                matches!(
+                    inner.sockets.get(fd),
+                    Some(Socket::Listening {
+                        reuse_port: true,
+                        ..
+                    })
+                )
             });
+            if !(reuse_port && shared) {
+                return Err(Fail::ResourceBusy {
+                    details: "Port already in use",
+                });
+            }
         }
 
-        let socket = PassiveSocket::new(local, backlog, inner.rt.clone(), inner.arp.clone());
-        assert!(inner.passive.insert(local, socket).is_none());
-        inner.sockets.insert(fd, Socket::Listening { local });
+        // A backlog of zero still lets a connection in, as on Linux.
+        let backlog = backlog.max(1).min(inner.options.max_backlog());
+        let socket = PassiveSocket::new(
+            local,
+            backlog,
+            inner.rt.clone(),
+            inner.arp.clone(),
+            inner.loopback.clone(),
+            inner.counters.clone(),
+            inner.subscribers.clone(),
+            inner.connections.clone(),
+            inner.socket_options(fd),
+            inner.ts_clock,
+        );
+        assert!(inner.passive.insert(fd, socket).is_none());
+        inner.listeners.entry(local).or_default().push(fd);
+        inner
+            .sockets
+            .insert(fd, Socket::Listening { local, reuse_port });
         Ok(())
     }
 
+    /// Polls for a connection to accept on listening socket `fd`, yielding its new socket along
+    /// with the remote endpoint it is connected to. Concurrent accepts are served in the order
+    /// they started waiting, `ticket` holding the place in line of this one.
     pub fn poll_accept(
         &self,
         fd: FileDescriptor,
         ctx: &mut Context,
-    ) -> Poll<Result<FileDescriptor, Fail>> {
+        ticket: &mut Option<u64>,
+    ) -> Poll<Result<(FileDescriptor, ipv4::Endpoint), Fail>> {
         let mut inner_ = self.inner.borrow_mut();
         let inner = &mut *inner_;
 
-        let local = match inner.sockets.get(&fd) {
-            Some(Socket::Listening { local }) => local,
+        match inner.sockets.get(&fd) {
+            Some(Socket::Listening { .. }) => (),
             Some(..) => {
                 return Poll::Ready(Err(Fail::Malformed {
                     details: "Socket not listening",
                 }))
             }
-            None => return Poll::Ready(Err(Fail::Malformed { details: "Bad FD" })),
+            None => return Poll::Ready(Err(Fail::BadFileDescriptor {})),
         };
+        // Leave the connection queued if it cannot get a file descriptor.
+        if let Err(e) = inner.check_file_table() {
+            return Poll::Ready(Err(e));
+        }
         let passive = inner
             .passive
-            .get_mut(local)
-            .expect("sockets/local inconsistency");
-        let cb = match passive.poll_accept(ctx) {
+            .get_mut(&fd)
+            .expect("sockets/passive inconsistency");
+        let cb = match passive.poll_accept(ctx, ticket) {
             Poll::Pending => return Poll::Pending,
             Poll::Ready(Ok(e)) => e,
             Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
         };
-        let fd = inner.file_table.alloc(File::TcpSocket);
+        let nodelay = inner.socket_options.get(&fd).and_then(|o| o.nodelay);
+        let send_buffer_size = inner.socket_options.get(&fd).and_then(|o| o.send_buffer_size);
+        let fd = inner.file_table.alloc(File::TcpSocket, inner.rt.now());
         let established = EstablishedSocket::new(cb, fd, inner.dead_socket_tx.clone());
-        let key = (established.cb.get_local(), established.cb.get_remote());
+        if let Some(nodelay) = nodelay {
+            established.set_nodelay(nodelay);
+        }
+        if let Some(size) = send_buffer_size {
+            established.set_send_buffer_size(size);
+        }
+        let (local, remote) = (established.cb.get_local(), established.cb.get_remote());
 
-        let socket = Socket::Established {
-            local: established.cb.get_local(),
-            remote: established.cb.get_remote(),
-        };
+        let socket = Socket::Established { local, remote };
         assert!(inner.sockets.insert(fd, socket).is_none());
-        assert!(inner.established.insert(key, established).is_none());
+        inner.insert_established((local, remote), established);
+
+        Poll::Ready(Ok((fd, remote)))
+    }
 
-        Poll::Ready(Ok(fd))
+    /// Takes an accept dropped while waiting on listening socket `fd` out of line.
+    pub fn cancel_accept(&self, fd: FileDescriptor, ticket: u64) {
+        // The peer is only busy here if it drops the accept itself, going away with the line.
+        let mut inner = match self.inner.try_borrow_mut() {
+            Ok(inner) => inner,
+            Err(_) => return,
+        };
+        if let Some(passive) = inner.passive.get_mut(&fd) {
+            passive.cancel_accept(ticket);
+        }
     }
 
     pub fn accept(&self, fd: FileDescriptor) -> AcceptFuture<RT> {
         AcceptFuture {
             fd,
             inner: self.inner.clone(),
+            ticket: None,
+        }
+    }
+
+    pub fn incoming(&self, fd: FileDescriptor) -> IncomingStream<RT> {
+        IncomingStream {
+            fd,
+            inner: self.inner.clone(),
+            ticket: None,
         }
     }
 
     pub fn connect(&self, fd: FileDescriptor, remote: ipv4::Endpoint) -> ConnectFuture<RT> {
+        self.connect_with_data(fd, remote, RT::Buf::empty())
+    }
+
+    /// Like [Peer::connect], but sends `buf` as the first data of the connection. With TCP Fast
+    /// Open, the data goes on the SYN if the remote granted a cookie earlier.
+    pub fn connect_with_data(
+        &self,
+        fd: FileDescriptor,
+        remote: ipv4::Endpoint,
+        buf: RT::Buf,
+    ) -> ConnectFuture<RT> {
         let mut inner = self.inner.borrow_mut();
 
         let r = try {
-            match inner.sockets.get_mut(&fd) {
-                Some(Socket::Inactive { .. }) => (),
+            let bound = match inner.sockets.get_mut(&fd) {
+                Some(Socket::Inactive { local, .. }) => *local,
+                None => Err(Fail::BadFileDescriptor {})?,
                 _ => Err(Fail::Malformed {
                     details: "Invalid file descriptor",
                 })?,
-            }
+            };
 
-            // TODO: We need to free these!
-            let local_port = inner.ephemeral_ports.alloc()?;
-            let local = ipv4::Endpoint::new(inner.rt.local_ipv4_addr(), local_port);
+            inner.collect_time_wait();
+            inner.collect_failed();
+            let local_addr = inner.rt.local_ipv4_addr();
+            let now = inner.rt.now();
+            let inner_ = &mut *inner;
+            let (established, connecting, time_wait) =
+                (&inner_.established, &inner_.connecting, &inner_.time_wait);
+            let in_use = |key: &ConnectionKey| {
+                established.contains_key(key)
+                    || connecting.contains_key(key)
+                    || time_wait.contains(key, now)
+            };
+            let local = match bound {
+                // Bound sockets connect from the endpoint they were bound to.
+                Some(mut local) => {
+                    if local.addr.is_unspecified() {
+                        local.addr = local_addr;
+                    }
+                    if local.addr != local_addr {
+                        Err(Fail::AddressNotAvailable {})?
+                    }
+                    if in_use(&(local, remote)) {
+                        Err(Fail::AddressInUse {})?
+                    }
+                    local
+                }
+                None => {
+                    let local_port =
+                        inner_.ephemeral_ports.alloc(local_addr, remote, now, |p| {
+                            in_use(&(ipv4::Endpoint::new(local_addr, p), remote))
+                        })?;
+                    ipv4::Endpoint::new(local_addr, local_port)
+                }
+            };
 
             let socket = Socket::Connecting { local, remote };
             inner.sockets.insert(fd, socket);
 
-            let local_isn = inner.isn_generator.generate(&local, &remote);
+            let local_isn = inner.isn_generator.generate(&local, &remote, inner.rt.now());
             let key = (local, remote);
             let socket = ActiveOpenSocket::new(
                 local_isn,
@@ -177,8 +554,16 @@ impl<RT: Runtime> Peer<RT> {
                 remote,
                 inner.rt.clone(),
                 inner.arp.clone(),
+                inner.loopback.clone(),
+                inner.counters.clone(),
+                inner.subscribers.clone(),
+                inner.socket_options(fd),
+                inner.ts_clock,
+                inner.fast_open_cookies.clone(),
+                buf,
             );
             assert!(inner.connecting.insert(key, socket).is_none());
+            inner.counters.update(|s| s.tcp.active_opens += 1);
             fd
         };
         let state = match r {
@@ -192,32 +577,83 @@ impl<RT: Runtime> Peer<RT> {
         }
     }
 
+    /// Binds the socket to `local` and connects it from there to `remote`.
+    pub fn connect_from(
+        &self,
+        fd: FileDescriptor,
+        local: ipv4::Endpoint,
+        remote: ipv4::Endpoint,
+    ) -> ConnectFuture<RT> {
+        if let Err(e) = self.bind(fd, local) {
+            return ConnectFuture {
+                fd,
+                state: ConnectFutureState::Failed(e),
+                inner: self.inner.clone(),
+            };
+        }
+        self.connect(fd, remote)
+    }
+
     pub fn poll_recv(&self, fd: FileDescriptor, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
         let inner = self.inner.borrow_mut();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(Socket::Connecting { .. }) => {
-                return Poll::Ready(Err(Fail::Malformed {
-                    details: "pool_recv(): socket connecting",
-                }))
-            }
-            Some(Socket::Inactive { .. }) => {
-                return Poll::Ready(Err(Fail::Malformed {
-                    details: "pool_recv(): socket inactive",
-                }))
-            }
-            Some(Socket::Listening { .. }) => {
-                return Poll::Ready(Err(Fail::Malformed {
-                    details: "pool_recv(): socket listening",
-                }))
-            }
-            None => return Poll::Ready(Err(Fail::Malformed { details: "Bad FD" })),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => s.poll_recv(ctx),
-            None => Poll::Ready(Err(Fail::Malformed {
-                details: "Socket not established",
-            })),
+        match inner.established_socket(fd) {
+            Ok(s) => s.poll_recv(ctx),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Like [Peer::poll_recv], but only completes once `len` bytes are available, or the
+    /// connection ends.
+    pub fn poll_recv_exact(
+        &self,
+        fd: FileDescriptor,
+        len: usize,
+        ctx: &mut Context,
+    ) -> Poll<Result<RT::Buf, Fail>> {
+        let inner = self.inner.borrow_mut();
+        match inner.established_socket(fd) {
+            Ok(s) => s.poll_recv_exact(ctx, len),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Like [Peer::poll_recv], but yields no more than `max_len` bytes.
+    pub fn poll_recv_max(
+        &self,
+        fd: FileDescriptor,
+        max_len: usize,
+        ctx: &mut Context,
+    ) -> Poll<Result<RT::Buf, Fail>> {
+        let inner = self.inner.borrow_mut();
+        match inner.established_socket(fd) {
+            Ok(s) => s.poll_recv_max(ctx, max_len),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Like [Peer::poll_recv], but leaves the data for the next read.
+    pub fn poll_peek(&self, fd: FileDescriptor, ctx: &mut Context) -> Poll<Result<RT::Buf, Fail>> {
+        let inner = self.inner.borrow();
+        match inner.established_socket(fd) {
+            Ok(s) => s.poll_peek(ctx),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Completes with what the connection of `fd` is ready for out of `interest`, or else has the
+    /// waker of `ctx` woken once it may be, for applications to learn which of many sockets to
+    /// pop from or push to without an operation pending on each. Only the latest registration of
+    /// a socket gets woken.
+    pub fn poll_ready(
+        &self,
+        fd: FileDescriptor,
+        interest: Readiness,
+        ctx: &mut Context,
+    ) -> Poll<Result<Readiness, Fail>> {
+        let inner = self.inner.borrow();
+        match inner.established_socket(fd) {
+            Ok(s) => s.poll_ready(ctx, interest).map(Ok),
+            Err(e) => Poll::Ready(Err(e)),
         }
     }
 
@@ -240,102 +676,349 @@ impl<RT: Runtime> Peer<RT> {
         }
     }
 
-    fn send(&self, fd: FileDescriptor, buf: RT::Buf) -> Result<(), Fail> {
-        let inner = self.inner.borrow_mut();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            }
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
+    /// Returns a future yielding exactly `len` bytes, or fewer if the connection ends first.
+    pub fn pop_exact(&self, fd: FileDescriptor, len: usize) -> PopExactFuture<RT> {
+        PopExactFuture {
+            fd,
+            len,
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Returns a future yielding the data received next, as [Peer::pop] does, but no more than
+    /// `max_len` bytes of it: the rest of a longer segment is left for the next pop, which suits
+    /// applications reading fixed-size records.
+    pub fn pop_max(&self, fd: FileDescriptor, max_len: usize) -> PopMaxFuture<RT> {
+        PopMaxFuture {
+            fd,
+            max_len,
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Returns a future yielding the data received so far, up to a full-sized segment of it,
+    /// without taking it, so that the next pop returns it again.
+    pub fn peek(&self, fd: FileDescriptor) -> PeekFuture<RT> {
+        PeekFuture {
+            fd,
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Pushes the buffers of `bufs` one after the other as a single write, all or none of them,
+    /// for applications building messages out of pieces such as a header and a payload. Only
+    /// the pieces smaller than a segment get copied, into the segments they share.
+    pub fn pushv(&self, fd: FileDescriptor, bufs: &[RT::Buf]) -> PushFuture<RT> {
+        let err = match self.send_vectored(fd, bufs) {
+            Ok(()) => None,
+            Err(e) => Some(e),
         };
-        match inner.established.get(&key) {
-            Some(ref s) => s.send(buf),
-            None => Err(Fail::Malformed {
-                details: "Socket not established",
-            }),
+        PushFuture {
+            fd,
+            err,
+            _marker: std::marker::PhantomData,
         }
     }
 
-    pub fn close(&self, fd: FileDescriptor) -> Result<(), Fail> {
+    fn send_vectored(&self, fd: FileDescriptor, bufs: &[RT::Buf]) -> Result<(), Fail> {
         let inner = self.inner.borrow_mut();
+        inner.established_socket(fd)?.send_vectored(bufs)
+    }
+
+    /// Returns how many bytes a push on socket `fd` would take now, which is zero while it waits
+    /// for room to send.
+    pub fn send_room(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        let inner = self.inner.borrow();
+        inner.established_socket(fd)?.cb.send_room()
+    }
+
+    fn send(&self, fd: FileDescriptor, buf: RT::Buf) -> Result<(), Fail> {
+        let inner = self.inner.borrow_mut();
+        inner.established_socket(fd)?.send(buf)
+    }
+
+    pub fn close(&self, fd: FileDescriptor) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
         match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => {
+            Some(Socket::Established { .. }) => inner.established_socket(fd)?.close()?,
+            Some(Socket::Connecting { local, remote }) => {
+                // Closing gives opening the connection up, if still under way.
                 let key = (*local, *remote);
-                match inner.established.get(&key) {
-                    Some(ref s) => s.close()?,
-                    None => {
-                        return Err(Fail::Malformed {
-                            details: "Socket not established",
-                        })
-                    }
+                if let Some(mut socket) = inner.connecting.remove(&key) {
+                    socket.abort();
                 }
+                inner.release(fd);
             }
-            Some(..) => {
-                // TODO: Implement close for listening sockets.
-                // unimplemented!();
+            Some(Socket::Failed { .. }) | Some(Socket::Inactive { .. }) => inner.release(fd),
+            Some(Socket::Listening { local, .. }) => {
+                // Handshakes in progress and connections not accepted yet go away with the
+                // listener, and SYNs for its port get reset unless another socket listens on it.
+                let local = *local;
+                if let Some(fds) = inner.listeners.get_mut(&local) {
+                    fds.retain(|&f| f != fd);
+                    if fds.is_empty() {
+                        inner.listeners.remove(&local);
+                    }
+                }
+                if let Some(mut passive) = inner.passive.remove(&fd) {
+                    passive.close();
+                }
+                inner.release(fd);
             }
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
+            Some(Socket::TimeWait { .. }) => (),
+            None => return Err(Fail::BadFileDescriptor {}),
         }
         Ok(())
     }
 
-    pub fn remote_mss(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+    /// Shuts down reading, writing, or both on an established connection. Unlike [Peer::close],
+    /// shutting down writing alone keeps data coming in until the remote closes its side too.
+    pub fn shutdown(&self, fd: FileDescriptor, how: Shutdown) -> Result<(), Fail> {
         let inner = self.inner.borrow();
+        inner.established_socket(fd)?.shutdown(how)
+    }
+
+    /// Aborts a connection at once: unlike [Peer::close], a RST goes out in place of a FIN, the
+    /// data queued either way is dropped, and operations on the socket fail with
+    /// [Fail::ConnectionAborted]. A connection still opening is given up without a RST.
+    pub fn abort(&self, fd: FileDescriptor) -> Result<(), Fail> {
+        let mut inner = self.inner.borrow_mut();
         let key = match inner.sockets.get(&fd) {
             Some(Socket::Established { local, remote }) => (*local, *remote),
+            Some(Socket::Connecting { local, remote }) => {
+                let (local, remote) = (*local, *remote);
+                if let Some(mut socket) = inner.connecting.remove(&(local, remote)) {
+                    socket.abort();
+                }
+                inner.sockets.insert(
+                    fd,
+                    Socket::Failed {
+                        local,
+                        remote,
+                        error: Fail::ConnectionAborted {},
+                    },
+                );
+                return Ok(());
+            }
+            Some(Socket::Failed { .. }) => return Ok(()),
             Some(..) => {
                 return Err(Fail::Malformed {
                     details: "Socket not established",
                 })
             }
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
+            None => return Err(Fail::BadFileDescriptor {}),
         };
-        match inner.established.get(&key) {
-            Some(ref s) => Ok(s.remote_mss()),
-            None => Err(Fail::Malformed {
-                details: "Socket not established",
-            }),
+        if let Some(s) = inner.established.get(&key) {
+            // A connection torn down already has nothing to reset.
+            if let Err(e) = s.hard_close() {
+                debug!("Not sending RST for {:?}: {:?}", key, e);
+            }
+        }
+        inner.tear_down(key, Fail::ConnectionAborted {});
+        Ok(())
+    }
+
+    /// Detaches an established connection from this peer and returns its state. The file
+    /// descriptor is released and no further segments are sent or accepted for the connection.
+    pub fn export_connection(&self, fd: FileDescriptor) -> Result<ConnectionState, Fail> {
+        let mut inner = self.inner.borrow_mut();
+        let socket = inner.established_socket(fd)?;
+        let key = socket.endpoints();
+        let state = socket.cb.export_state()?;
+        inner.remove_established(&key);
+        inner.sockets.remove(&fd);
+        inner.socket_options.remove(&fd);
+        inner.file_table.free(fd);
+        let (local, remote) = key;
+        if local.port.is_private() {
+            let now = inner.rt.now();
+            inner.ephemeral_ports.free(local.port, remote, now);
+        }
+        Ok(state)
+    }
+
+    /// Attaches a connection exported from another peer and returns its new file descriptor.
+    pub fn import_connection(&self, state: ConnectionState) -> Result<FileDescriptor, Fail> {
+        let mut inner = self.inner.borrow_mut();
+        if state.local.addr != inner.rt.local_ipv4_addr() {
+            return Err(Fail::AddressNotAvailable {});
+        }
+        let key = (state.local, state.remote);
+        if inner.established.contains_key(&key) || inner.connecting.contains_key(&key) {
+            return Err(Fail::AddressInUse {});
+        }
+        inner.check_file_table()?;
+        let cb = ControlBlock::import_state(
+            state,
+            inner.rt.clone(),
+            inner.arp.clone(),
+            inner.loopback.clone(),
+            inner.counters.clone(),
+            inner.subscribers.clone(),
+            inner.options.clone(),
+        );
+        let fd = inner.file_table.alloc(File::TcpSocket, inner.rt.now());
+        let established = EstablishedSocket::new(cb, fd, inner.dead_socket_tx.clone());
+        let (local, remote) = key;
+        assert!(inner
+            .sockets
+            .insert(fd, Socket::Established { local, remote })
+            .is_none());
+        inner.insert_established(key, established);
+        Ok(fd)
+    }
+
+    /// Replaces the TCP options of this peer. New connections use `options` as is, while
+    /// established ones only pick up the settings that are safe to change mid-connection.
+    pub fn set_options(&self, options: TcpOptions<RT>) {
+        let mut inner = self.inner.borrow_mut();
+        let options = options.with_checksum_offload(inner.rt.checksum_offload());
+        for socket in inner.established.values() {
+            socket.update_options(&options);
         }
+        inner
+            .time_wait
+            .set_limits(options.max_time_wait(), options.time_wait_timeout());
+        inner.options = options;
+        let fds: Vec<FileDescriptor> = inner.passive.keys().copied().collect();
+        for fd in fds {
+            let options = inner.socket_options(fd);
+            inner.passive.get_mut(&fd).unwrap().set_options(options);
+        }
+        drop(inner);
+        self.schedule_reaping();
+    }
+
+    pub fn options(&self) -> TcpOptions<RT> {
+        self.inner.borrow().options.clone()
+    }
+
+    /// Calls `f` on every state transition of a connection from now on. `f` must not call back
+    /// into this peer.
+    pub fn subscribe<F: Fn(&StateTransition) + 'static>(&self, f: F) {
+        self.inner.borrow().subscribers.subscribe(f)
+    }
+
+    /// Calls `f` with a summary of every connection torn down from now on. Summaries are also
+    /// logged at the `info` level. `f` must not call back into this peer.
+    pub fn subscribe_close<F: Fn(&CloseSummary) + 'static>(&self, f: F) {
+        self.inner.borrow().subscribers.subscribe_close(f)
+    }
+
+    /// Describes every socket of this peer.
+    pub fn sockets(&self) -> Vec<SocketInfo> {
+        let inner = self.inner.borrow();
+        let now = inner.rt.now();
+        inner
+            .sockets
+            .iter()
+            .map(|(&fd, socket)| {
+                let mut rto = None;
+                let mut srtt = None;
+                let (local, remote, state, send_queue, recv_queue) = match socket {
+                    Socket::Inactive { local, .. } => (*local, None, SocketState::Idle, 0, 0),
+                    Socket::Listening { local, .. } => {
+                        let ready = inner.passive.get(&fd).map_or(0, |s| s.ready_len());
+                        (Some(*local), None, SocketState::Listening, 0, ready)
+                    }
+                    Socket::Connecting { local, remote } => {
+                        (Some(*local), Some(*remote), SocketState::Connecting, 0, 0)
+                    }
+                    Socket::Established { local, remote } => {
+                        let cb = &inner.established[&(*local, *remote)].cb;
+                        let (send_queue, recv_queue) = cb.queue_depths();
+                        let state = SocketState::Established(cb.get_state().0);
+                        rto = Some(cb.current_rto());
+                        srtt = cb.srtt();
+                        (Some(*local), Some(*remote), state, send_queue, recv_queue)
+                    }
+                    Socket::TimeWait { local, remote } => {
+                        let state = if inner.time_wait.contains(&(*local, *remote), now) {
+                            State::TimeWait2
+                        } else {
+                            State::Closed
+                        };
+                        let state = SocketState::Established(state);
+                        (Some(*local), Some(*remote), state, 0, 0)
+                    }
+                    Socket::Failed { local, remote, .. } => {
+                        let state = SocketState::Established(State::Reset);
+                        (Some(*local), Some(*remote), state, 0, 0)
+                    }
+                };
+                let opened = inner.file_table.opened(fd).unwrap_or(now);
+                SocketInfo {
+                    fd,
+                    protocol: Protocol::Tcp,
+                    local,
+                    remote,
+                    state,
+                    send_queue,
+                    recv_queue,
+                    age: now.saturating_duration_since(opened),
+                    rto,
+                    srtt,
+                }
+            })
+            .collect()
+    }
+
+    pub fn remote_mss(&self, fd: FileDescriptor) -> Result<usize, Fail> {
+        let inner = self.inner.borrow();
+        Ok(inner.established_socket(fd)?.remote_mss())
     }
 
     pub fn current_rto(&self, fd: FileDescriptor) -> Result<Duration, Fail> {
         let inner = self.inner.borrow();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
-                })
-            }
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => Ok(s.current_rto()),
-            None => Err(Fail::Malformed {
-                details: "Socket not established",
+        Ok(inner.established_socket(fd)?.current_rto())
+    }
+
+    /// Rate the congestion control of the connection paces its segments at, in bytes per second,
+    /// if it paces them at all.
+    pub fn pacing_rate(&self, fd: FileDescriptor) -> Result<Option<u64>, Fail> {
+        let inner = self.inner.borrow();
+        Ok(inner.established_socket(fd)?.pacing_rate())
+    }
+
+    /// Takes a snapshot of the internals of the connection, as for `TCP_INFO`.
+    pub fn info(&self, fd: FileDescriptor) -> Result<TcpInfo, Fail> {
+        let inner = self.inner.borrow();
+        Ok(inner.established_socket(fd)?.info())
+    }
+
+    /// Returns the local endpoint socket `fd` is bound to, explicitly or by connecting, as
+    /// getsockname() does.
+    pub fn local_endpoint(&self, fd: FileDescriptor) -> Result<ipv4::Endpoint, Fail> {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd) {
+            Some(Socket::Inactive { local: None, .. }) => Err(Fail::ResourceNotFound {
+                details: "Socket not bound",
             }),
+            Some(Socket::Inactive { local: Some(local), .. })
+            | Some(Socket::Listening { local, .. })
+            | Some(Socket::Connecting { local, .. })
+            | Some(Socket::Established { local, .. })
+            | Some(Socket::TimeWait { local, .. })
+            | Some(Socket::Failed { local, .. }) => Ok(*local),
+            None => Err(Fail::BadFileDescriptor {}),
         }
     }
 
-    pub fn endpoints(&self, fd: FileDescriptor) -> Result<(ipv4::Endpoint, ipv4::Endpoint), Fail> {
+    /// Returns the remote endpoint socket `fd` connects to, as getpeername() does. Connections
+    /// torn down still tell where they went.
+    pub fn remote_endpoint(&self, fd: FileDescriptor) -> Result<ipv4::Endpoint, Fail> {
         let inner = self.inner.borrow();
-        let key = match inner.sockets.get(&fd) {
-            Some(Socket::Established { local, remote }) => (*local, *remote),
-            Some(..) => {
-                return Err(Fail::Malformed {
-                    details: "Socket not established",
+        match inner.sockets.get(&fd) {
+            Some(Socket::Inactive { .. }) | Some(Socket::Listening { .. }) => {
+                Err(Fail::ResourceNotFound {
+                    details: "Socket not connected",
                 })
             }
-            None => return Err(Fail::Malformed { details: "Bad FD" }),
-        };
-        match inner.established.get(&key) {
-            Some(ref s) => Ok(s.endpoints()),
-            None => Err(Fail::Malformed {
-                details: "Socket not established",
-            }),
+            Some(Socket::Connecting { remote, .. })
+            | Some(Socket::Established { remote, .. })
+            | Some(Socket::TimeWait { remote, .. })
+            | Some(Socket::Failed { remote, .. }) => Ok(*remote),
+            None => Err(Fail::BadFileDescriptor {}),
         }
     }
 }
@@ -343,9 +1026,11 @@ impl<RT: Runtime> Peer<RT> {
 enum Socket {
     Inactive {
         local: Option<ipv4::Endpoint>,
+        reuse_port: bool,
     },
     Listening {
         local: ipv4::Endpoint,
+        reuse_port: bool,
     },
     Connecting {
         local: ipv4::Endpoint,
@@ -355,10 +1040,62 @@ enum Socket {
         local: ipv4::Endpoint,
         remote: ipv4::Endpoint,
     },
+    /// Closed on our side first, and moved to the TIME_WAIT table.
+    TimeWait {
+        local: ipv4::Endpoint,
+        remote: ipv4::Endpoint,
+    },
+    /// Torn down on a failure, such as a RST of the remote, which operations on it return.
+    Failed {
+        local: ipv4::Endpoint,
+        remote: ipv4::Endpoint,
+        error: Fail,
+    },
+}
+
+/// Options set on a single socket. Those left unset follow the options of the peer.
+struct SocketOptions<RT: Runtime> {
+    congestion_ctrl: Option<CongestionControlConstructor<RT>>,
+    nodelay: Option<bool>,
+    receive_buffer_size: Option<u32>,
+    send_buffer_size: Option<usize>,
+}
+
+impl<RT: Runtime> Default for SocketOptions<RT> {
+    fn default() -> Self {
+        Self {
+            congestion_ctrl: None,
+            nodelay: None,
+            receive_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+}
+
+impl<RT: Runtime> SocketOptions<RT> {
+    fn apply(&self, mut options: TcpOptions<RT>) -> TcpOptions<RT> {
+        if let Some(constructor) = self.congestion_ctrl {
+            options = options.set_congestion_ctrl_type(constructor);
+        }
+        if let Some(nodelay) = self.nodelay {
+            options = options.set_nodelay(nodelay);
+        }
+        if let Some(size) = self.receive_buffer_size {
+            options = options.set_receive_buffer_size(size);
+        }
+        if let Some(size) = self.send_buffer_size {
+            options = options.set_send_buffer_size(size);
+        }
+        options
+    }
 }
 
 pub struct Inner<RT: Runtime> {
     isn_generator: IsnGenerator,
+    /// Clock behind the timestamps sent on the connections of this peer.
+    ts_clock: TimestampClock,
+    /// TCP Fast Open cookies granted by remotes.
+    fast_open_cookies: Rc<RefCell<FastOpenCache>>,
 
     file_table: FileTable,
     ephemeral_ports: EphemeralPorts,
@@ -366,76 +1103,523 @@ pub struct Inner<RT: Runtime> {
     // FD -> local port
     sockets: HashMap<FileDescriptor, Socket>,
 
-    passive: HashMap<ipv4::Endpoint, PassiveSocket<RT>>,
+    /// Options set on individual sockets, overriding `options` for their connections.
+    socket_options: HashMap<FileDescriptor, SocketOptions<RT>>,
+
+    passive: HashMap<FileDescriptor, PassiveSocket<RT>>,
+    /// Listening sockets of each local endpoint, in the order they started listening.
+    listeners: HashMap<ipv4::Endpoint, Vec<FileDescriptor>>,
     connecting: HashMap<(ipv4::Endpoint, ipv4::Endpoint), ActiveOpenSocket<RT>>,
-    established: HashMap<(ipv4::Endpoint, ipv4::Endpoint), EstablishedSocket<RT>>,
+    established: ConnectionTable<EstablishedSocket<RT>>,
+    time_wait: TimeWaitTable,
+    /// Established connections that entered TIME_WAIT since the table was last updated.
+    entered_time_wait: Rc<RefCell<Vec<ConnectionKey>>>,
+    /// Established connections torn down since, on a failure the receive path did not see.
+    entered_reset: Rc<RefCell<Vec<ConnectionKey>>>,
 
     rt: RT,
     arp: arp::Peer<RT>,
+    loopback: Loopback<RT>,
+    /// Background task receiving the segments in `loopback`, set once the peer is created.
+    #[allow(unused)]
+    loopback_work: Option<SchedulerHandle>,
+    /// Background task reaping idle connections, set while the options call for it.
+    #[allow(unused)]
+    reap_work: Option<SchedulerHandle>,
+    /// Connections established or being accepted, counted against the connection limits.
+    connections: ConnectionCount,
+    counters: Counters,
+    subscribers: Subscribers,
+    options: TcpOptions<RT>,
+    /// Budget of RSTs and challenge ACKs, shared by all connections.
+    error_segments: TokenBucket,
+    /// Whether a burst of frames is being received, during which pure ACKs are deferred.
+    batching: bool,
+    /// Latest pure ACK of each connection received during the current burst, in arrival order.
+    deferred_acks: Vec<(ConnectionKey, TcpHeader)>,
 
     dead_socket_tx: mpsc::UnboundedSender<FileDescriptor>,
 }
 
 impl<RT: Runtime> Inner<RT> {
+    /// Fails if no file descriptor can be allocated.
+    fn check_file_table(&self) -> Result<(), Fail> {
+        if self.rt.exhausted(Resource::FileDescriptor) {
+            return Err(Fail::TooManyOpenedFiles {
+                details: "file table exhausted",
+            });
+        }
+        Ok(())
+    }
+
+    /// Options of the connections of socket `fd`.
+    fn socket_options(&self, fd: FileDescriptor) -> TcpOptions<RT> {
+        match self.socket_options.get(&fd) {
+            Some(socket_options) => socket_options.apply(self.options.clone()),
+            None => self.options.clone(),
+        }
+    }
+
+    /// Returns the connection of established socket `fd`. Sockets whose connection was torn down
+    /// fail with the error that tore it down.
+    fn established_socket(&self, fd: FileDescriptor) -> Result<&EstablishedSocket<RT>, Fail> {
+        let not_established = || Fail::Malformed {
+            details: "Socket not established",
+        };
+        match self.sockets.get(&fd) {
+            Some(Socket::Established { local, remote }) => self
+                .established
+                .get(&(*local, *remote))
+                .ok_or_else(not_established),
+            Some(Socket::Failed { error, .. }) => Err(error.clone()),
+            Some(..) => Err(not_established()),
+            None => Err(Fail::BadFileDescriptor {}),
+        }
+    }
+
     fn new(
         rt: RT,
         arp: arp::Peer<RT>,
+        loopback: Loopback<RT>,
         file_table: FileTable,
+        counters: Counters,
+        options: TcpOptions<RT>,
         dead_socket_tx: mpsc::UnboundedSender<FileDescriptor>,
         _dead_socket_rx: mpsc::UnboundedReceiver<FileDescriptor>,
     ) -> Self {
+        let error_segments = TokenBucket::new(options.error_segment_burst(), rt.now());
+        let established = ConnectionTable::with_capacity(options.max_connections().unwrap_or(0));
+        let time_wait = TimeWaitTable::new(options.max_time_wait(), options.time_wait_timeout());
+        let subscribers = Subscribers::default();
+        let entered_time_wait = Rc::new(RefCell::new(Vec::new()));
+        let entered_reset = Rc::new(RefCell::new(Vec::new()));
+        let (entered, reset) = (entered_time_wait.clone(), entered_reset.clone());
+        subscribers.subscribe(move |t| match t.to {
+            State::TimeWait2 => entered.borrow_mut().push((t.local, t.remote)),
+            State::Reset => reset.borrow_mut().push((t.local, t.remote)),
+            _ => (),
+        });
+        // Connections no longer count once they close or fail, though they stay in the table
+        // until they are collected.
+        let connections = ConnectionCount::default();
+        let count = connections.clone();
+        subscribers.subscribe(move |t| {
+            if fsm::is_terminal(t.to) && !fsm::is_terminal(t.from) {
+                count.remove(&(t.local, t.remote));
+            }
+        });
         Self {
-            isn_generator: IsnGenerator::new(rt.rng_gen()),
+            isn_generator: IsnGenerator::new(rt.rng_gen(), rt.now()),
+            ts_clock: TimestampClock::new(rt.now()),
+            fast_open_cookies: Rc::new(RefCell::new(FastOpenCache::default())),
             file_table,
             ephemeral_ports: EphemeralPorts::new(&rt),
             sockets: HashMap::new(),
+            socket_options: HashMap::new(),
             passive: HashMap::new(),
+            listeners: HashMap::new(),
             connecting: HashMap::new(),
-            established: HashMap::new(),
+            established,
+            time_wait,
+            entered_time_wait,
+            entered_reset,
             rt,
             arp,
+            loopback,
+            loopback_work: None,
+            reap_work: None,
+            connections,
+            counters,
+            subscribers,
+            error_segments,
+            batching: false,
+            deferred_acks: Vec::new(),
+            options,
             dead_socket_tx,
         }
     }
 
     fn receive(&mut self, ip_hdr: &Ipv4Header, buf: RT::Buf) -> Result<(), Fail> {
-        let tcp_options = self.rt.tcp_options();
-        let (tcp_hdr, data) = TcpHeader::parse(ip_hdr, buf, tcp_options.rx_checksum_offload())?;
-        debug!("TCP received {:?}", tcp_hdr);
+        self.counters.update(|s| s.tcp.in_segs += 1);
+        let (tcp_hdr, data) =
+            match TcpHeader::parse(ip_hdr, buf, self.options.rx_checksum_offload()) {
+                Ok(r) => r,
+                Err(e) => {
+                    self.counters.update(|s| {
+                        s.tcp.in_errs += 1;
+                        if let Fail::Malformed {
+                            details: "TCP checksum mismatch",
+                        } = e
+                        {
+                            s.tcp.in_csum_errors += 1;
+                        }
+                    });
+                    self.counters.count_parse_drop(&e);
+                    return Err(e);
+                }
+            };
+        datapath!(Debug, "TCP received {}", tcp_hdr);
+        self.receive_segment(ip_hdr, &tcp_hdr, data)
+    }
+
+    /// Routes a parsed segment to the socket it belongs to.
+    fn receive_segment(
+        &mut self,
+        ip_hdr: &Ipv4Header,
+        tcp_hdr: &TcpHeader,
+        data: RT::Buf,
+    ) -> Result<(), Fail> {
         let local = ipv4::Endpoint::new(ip_hdr.dst_addr, tcp_hdr.dst_port);
         let remote = ipv4::Endpoint::new(ip_hdr.src_addr, tcp_hdr.src_port);
 
         if remote.addr.is_broadcast() || remote.addr.is_multicast() || remote.addr.is_unspecified()
         {
+            self.counters.update(|s| s.tcp.in_errs += 1);
+            self.counters.count_drop(DropReason::Malformed);
             return Err(Fail::Malformed {
                 details: "Invalid address type",
             });
         }
         let key = (local, remote);
+        self.collect_time_wait();
+        self.collect_failed();
 
-        if let Some(s) = self.established.get(&key) {
-            debug!("Routing to established connection: {:?}", key);
-            s.receive(&tcp_hdr, data);
+        if self.established.contains_key(&key) {
+            let pure_ack = tcp_hdr.ack
+                && !tcp_hdr.syn
+                && !tcp_hdr.fin
+                && !tcp_hdr.rst
+                && !tcp_hdr.urg
+                && data.is_empty();
+            // Pure ACKs aren't ECN-capable, so deferring them loses no congestion mark.
+            if self.batching && pure_ack {
+                self.defer_ack(key, tcp_hdr);
+                return Ok(());
+            }
+            self.flush_deferred_ack(&key);
+            datapath!(Debug, "Routing to established connection: {:?}", key);
+            self.receive_established(key, tcp_hdr, data, ip_hdr.ecn == IPV4_ECN_CE);
             return Ok(());
         }
         if let Some(s) = self.connecting.get_mut(&key) {
-            debug!("Routing to connecting connection: {:?}", key);
-            s.receive(&tcp_hdr);
+            datapath!(Debug, "Routing to connecting connection: {:?}", key);
+            s.receive(tcp_hdr);
             return Ok(());
         }
         let (local, _) = key;
-        if let Some(s) = self.passive.get_mut(&local) {
-            debug!("Routing to passive connection: {:?}", local);
-            return s.receive(ip_hdr, &tcp_hdr);
+        let syn = tcp_hdr.syn && !tcp_hdr.ack && !tcp_hdr.rst;
+        let now = self.rt.now();
+        if let Some(&entry) = self.time_wait.get(&key, now) {
+            if tcp_hdr.fin && !tcp_hdr.syn && !tcp_hdr.rst {
+                // Our last ACK was lost: acknowledge the FIN again and wait another 2MSL.
+                datapath!(
+                    Debug,
+                    "Acknowledging retransmitted FIN for {:?} in TIME_WAIT",
+                    key
+                );
+                self.time_wait.restart(&key, now);
+                self.send_time_wait_ack(&entry, tcp_hdr, &local, &remote)?;
+                return Ok(());
+            }
+            if !syn || !entry.allows_syn(tcp_hdr.seq_num, tcp_hdr.sender_timestamp()) {
+                // Segments of the old incarnation, or SYNs that could be mistaken for them.
+                datapath!(Debug, "Ignoring {} for {:?} in TIME_WAIT", tcp_hdr, key);
+                return Ok(());
+            }
+            debug!("Recycling {:?} out of TIME_WAIT", key);
+            self.time_wait.remove(&key);
+            self.counters.update(|s| s.tcp.time_wait_recycled += 1);
+        }
+        if self.listeners.contains_key(&local) && syn && self.over_connection_limit(remote.addr) {
+            debug!("Connection limit reached, sending RST to {:?}", remote);
+            self.counters.update(|s| s.tcp.limit_rejects += 1);
+            self.reset(&local, &remote, tcp_hdr, data.len())?;
+            return Ok(());
+        }
+        if let Some(fd) = self.listener(&local, &remote) {
+            datapath!(Debug, "Routing to passive connection: {:?}", local);
+            let s = self
+                .passive
+                .get_mut(&fd)
+                .expect("listeners/passive inconsistency");
+            let r = s.receive(ip_hdr, tcp_hdr, data.clone());
+            match r {
+                Err(Rejection { reset: true, .. }) => {
+                    self.counters.count_drop(DropReason::Malformed);
+                    debug!("Sending RST for {:?}, {:?}", local, remote);
+                    self.reset(&local, &remote, tcp_hdr, data.len())?;
+                }
+                Err(Rejection {
+                    error: Fail::ConnectionRefused {},
+                    ..
+                }) => self.counters.count_drop(DropReason::QueueFull),
+                Err(Rejection {
+                    error: Fail::ResourceExhausted { .. },
+                    ..
+                }) => self.counters.count_drop(DropReason::SynFlood),
+                Err(_) => self.counters.count_drop(DropReason::Malformed),
+                Ok(()) => (),
+            }
+            return r.map_err(|r| r.error);
         }
 
         // The packet isn't for an open port; send a RST segment.
         debug!("Sending RST for {:?}, {:?}", local, remote);
-        self.send_rst(&local, &remote)?;
+        self.counters.count_drop(DropReason::NoListener);
+        self.reset(&local, &remote, tcp_hdr, data.len())?;
         Ok(())
     }
 
-    fn send_rst(&mut self, local: &ipv4::Endpoint, remote: &ipv4::Endpoint) -> Result<(), Fail> {
+    /// Releases the file descriptor of a socket with no connection left, opening or open.
+    fn release(&mut self, fd: FileDescriptor) {
+        self.sockets.remove(&fd);
+        self.socket_options.remove(&fd);
+        self.file_table.free(fd);
+    }
+
+    /// Adds established connection `key` to the table, counting it unless it closed already.
+    fn insert_established(&mut self, key: ConnectionKey, socket: EstablishedSocket<RT>) {
+        if !fsm::is_terminal(socket.cb.get_state().0) {
+            self.connections.add(key);
+        }
+        assert!(self.established.insert(key, socket).is_none());
+    }
+
+    /// Takes established connection `key` out of the table and the connection count.
+    fn remove_established(&mut self, key: &ConnectionKey) -> Option<EstablishedSocket<RT>> {
+        self.connections.remove(key);
+        self.established.remove(key)
+    }
+
+    /// Releases the connection for `key`, leaving its socket to fail operations with `error`.
+    fn tear_down(&mut self, key: ConnectionKey, error: Fail) {
+        let socket = match self.remove_established(&key) {
+            Some(s) => s,
+            None => return,
+        };
+        debug!("Tearing {:?} down: {:?}", key, error);
+        let (local, remote) = key;
+        self.sockets.insert(
+            socket.fd,
+            Socket::Failed {
+                local,
+                remote,
+                error,
+            },
+        );
+    }
+
+    /// Releases the connections that failed in the background, such as on a retransmission
+    /// timeout, leaving their sockets to fail operations.
+    fn collect_failed(&mut self) {
+        if self.entered_reset.borrow().is_empty() {
+            return;
+        }
+        let keys: Vec<ConnectionKey> = self.entered_reset.borrow_mut().drain(..).collect();
+        for key in keys {
+            let failure = match self.established.get(&key) {
+                Some(s) => s.cb.failure(),
+                None => continue,
+            };
+            if let Some(e) = failure {
+                self.tear_down(key, e);
+            }
+        }
+    }
+
+    /// Moves the connections that entered TIME_WAIT to the TIME_WAIT table, releasing everything
+    /// but what telling their segments from those of a new incarnation takes.
+    fn collect_time_wait(&mut self) {
+        if self.entered_time_wait.borrow().is_empty() {
+            return;
+        }
+        let keys: Vec<ConnectionKey> = self.entered_time_wait.borrow_mut().drain(..).collect();
+        let now = self.rt.now();
+        for key in keys {
+            match self.established.get(&key) {
+                Some(s) if s.cb.get_state().0 == State::TimeWait2 => (),
+                _ => continue,
+            }
+            let socket = self.remove_established(&key).unwrap();
+            let (recv_seq_no, _) = socket.cb.get_last_recv_seq_no();
+            let (sent_seq_no, _) = socket.cb.get_sent_seq_no();
+            let entry = TimeWaitEntry {
+                since: now,
+                // The FIN of the remote takes up a sequence number that the receiver does not
+                // count.
+                rcv_nxt: recv_seq_no + Wrapping(1),
+                // Our FIN goes out at the sent sequence number without moving it.
+                snd_nxt: sent_seq_no + Wrapping(1),
+                window_size: socket.cb.tcp_header().window_size,
+                ts_recent: socket.cb.ts_recent(),
+                ts_clock: socket.cb.ts_clock(),
+            };
+            let (local, remote) = key;
+            self.sockets
+                .insert(socket.fd, Socket::TimeWait { local, remote });
+            let evicted = self.time_wait.insert(key, entry) as u64;
+            if evicted > 0 {
+                self.counters.update(|s| s.tcp.time_wait_evicted += evicted);
+            }
+        }
+    }
+
+    /// Returns the listening socket that handles connections from `remote` to `local`, picked by
+    /// [flow_hash] if several listen on `local`.
+    fn listener(&self, local: &ipv4::Endpoint, remote: &ipv4::Endpoint) -> Option<FileDescriptor> {
+        let fds = self.listeners.get(local)?;
+        Some(fds[flow_hash(local, remote) as usize % fds.len()])
+    }
+
+    /// Passes a segment to established connection `key`, unless it failed already. A connection
+    /// failing on the segment is torn down, and a challenge ACK it calls for goes out at once.
+    fn receive_established(
+        &mut self,
+        key: ConnectionKey,
+        header: &TcpHeader,
+        data: RT::Buf,
+        ce: bool,
+    ) {
+        let s = match self.established.get(&key) {
+            Some(s) => s,
+            None => return,
+        };
+        if s.cb.failure().is_none() {
+            s.receive(header, data, ce);
+        }
+        let challenge_ack = s.cb.take_challenge_ack();
+        if let Some(e) = s.cb.failure() {
+            self.tear_down(key, e);
+        } else if challenge_ack && self.take_error_segment() {
+            self.established[&key].cb.send_challenge_ack();
+        }
+    }
+
+    /// Holds a pure ACK received during a burst, superseding the one held for the same connection
+    /// if it acknowledges more and the held one reports no SACK or DSACK blocks, which the newer
+    /// one may not repeat. Otherwise the held ACK gets processed first, as congestion control
+    /// counts duplicate ACKs and the scoreboard takes every block reported.
+    fn defer_ack(&mut self, key: ConnectionKey, header: &TcpHeader) {
+        match self.deferred_acks.iter().position(|(k, _)| *k == key) {
+            Some(i)
+                if seq_gt(header.ack_num, self.deferred_acks[i].1.ack_num)
+                    && self.deferred_acks[i].1.selective_acks().is_empty() =>
+            {
+                self.deferred_acks[i].1 = header.clone();
+                self.counters.update(|s| s.tcp.coalesced_acks += 1);
+            }
+            Some(_) => {
+                self.flush_deferred_ack(&key);
+                self.deferred_acks.push((key, header.clone()));
+            }
+            None => self.deferred_acks.push((key, header.clone())),
+        }
+    }
+
+    /// Processes the pure ACK held for `key`, if any.
+    fn flush_deferred_ack(&mut self, key: &ConnectionKey) {
+        if let Some(i) = self.deferred_acks.iter().position(|(k, _)| k == key) {
+            let (_, header) = self.deferred_acks.remove(i);
+            self.receive_established(*key, &header, RT::Buf::empty(), false);
+        }
+    }
+
+    /// Returns whether accepting another connection from `remote` would exceed a connection limit.
+    /// Connections count from their SYN until they close or are torn down.
+    fn over_connection_limit(&self, remote: Ipv4Addr) -> bool {
+        let max_total = self.options.max_connections();
+        let max_per_remote = self.options.max_connections_per_remote();
+        max_total.map_or(false, |m| self.connections.total() >= m)
+            || max_per_remote.map_or(false, |m| self.connections.from_remote(remote) >= m)
+    }
+
+    /// Applies the idle reaping policy: once the connections established or being accepted reach
+    /// its high water mark, tears down those idle for longer than its timeout, oldest activity
+    /// first, until the count drops under the mark.
+    fn reap_idle(&mut self) {
+        let policy = match self.options.idle_reaping() {
+            Some(p) => p,
+            None => return,
+        };
+        let mut total = self.connections.total();
+        if total < policy.high_water {
+            return;
+        }
+        let now = self.rt.now();
+        let mut idle: Vec<_> = self
+            .established
+            .values()
+            .filter(|s| !fsm::is_terminal(s.cb.get_state().0))
+            .filter(|s| now.saturating_duration_since(s.cb.last_activity()) >= policy.idle_timeout)
+            .collect();
+        idle.sort_by_key(|s| s.cb.last_activity());
+        for socket in idle {
+            if total < policy.high_water {
+                break;
+            }
+            match socket.cb.reap(policy.action) {
+                Ok(()) => {
+                    info!("Reaped idle connection {:?}", socket.endpoints());
+                    self.counters.update(|s| s.tcp.idle_reaped += 1);
+                    total -= 1;
+                }
+                // Connections already closing on our side can't be closed again.
+                Err(e) => debug!("Not reaping {:?}: {:?}", socket.endpoints(), e),
+            }
+        }
+    }
+
+    /// Takes a token for a RST or challenge ACK, counting the segment as throttled if there is
+    /// none.
+    fn take_error_segment(&mut self) -> bool {
+        let (rate, burst) = (
+            self.options.error_segment_rate(),
+            self.options.error_segment_burst(),
+        );
+        if self.error_segments.try_take(rate, burst, self.rt.now()) {
+            return true;
+        }
+        self.counters
+            .update(|s| s.tcp.error_segments_throttled += 1);
+        false
+    }
+
+    /// Answers a segment that belongs to no connection with a RST, as in RFC 793, section 3.4:
+    /// the RST takes its sequence number from the ACK of the segment, or else acknowledges the
+    /// segment. A RST is never answered.
+    fn reset(
+        &mut self,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+        header: &TcpHeader,
+        data_len: usize,
+    ) -> Result<(), Fail> {
+        if header.rst {
+            return Ok(());
+        }
+        let mut tcp_hdr = TcpHeader::new(local.port, remote.port);
+        tcp_hdr.rst = true;
+        if header.ack {
+            tcp_hdr.seq_num = header.ack_num;
+        } else {
+            // SYN and FIN take up a sequence number each.
+            let len = data_len as u32 + header.syn as u32 + header.fin as u32;
+            tcp_hdr.ack = true;
+            tcp_hdr.ack_num = header.seq_num + Wrapping(len);
+        }
+        self.send_rst(local, remote, tcp_hdr)
+    }
+
+    fn send_rst(
+        &mut self,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+        tcp_hdr: TcpHeader,
+    ) -> Result<(), Fail> {
+        if !self.take_error_segment() {
+            debug!("Not sending RST to {:?}, over the rate limit", remote);
+            return Ok(());
+        }
         // TODO: Make this work pending on ARP resolution if needed.
         let remote_link_addr = self
             .arp
@@ -443,10 +1627,48 @@ impl<RT: Runtime> Inner<RT> {
             .ok_or(Fail::ResourceNotFound {
                 details: "RST destination not in ARP cache",
             })?;
+        self.transmit_control(local, remote, remote_link_addr, tcp_hdr)?;
+        self.counters.update(|s| s.tcp.out_rsts += 1);
+        Ok(())
+    }
+
+    /// Acknowledges again the FIN that closed the connection of `entry`, in TIME_WAIT.
+    fn send_time_wait_ack(
+        &mut self,
+        entry: &TimeWaitEntry,
+        header: &TcpHeader,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+    ) -> Result<(), Fail> {
+        let remote_link_addr = self
+            .arp
+            .try_query(remote.addr)
+            .ok_or(Fail::ResourceNotFound {
+                details: "ACK destination not in ARP cache",
+            })?;
 
         let mut tcp_hdr = TcpHeader::new(local.port, remote.port);
-        tcp_hdr.rst = true;
+        tcp_hdr.seq_num = entry.snd_nxt;
+        tcp_hdr.ack = true;
+        tcp_hdr.ack_num = entry.rcv_nxt;
+        tcp_hdr.window_size = entry.window_size;
+        if let Some(clock) = entry.ts_clock {
+            tcp_hdr.push_option(TcpOptions2::Timestamp {
+                sender_timestamp: clock.value(self.rt.now()),
+                echo_timestamp: header.sender_timestamp().or(entry.ts_recent).unwrap_or(0),
+            });
+        }
+        self.transmit_control(local, remote, remote_link_addr, tcp_hdr)
+    }
 
+    /// Sends a segment without payload that no connection keeps track of.
+    fn transmit_control(
+        &mut self,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+        remote_link_addr: MacAddress,
+        tcp_hdr: TcpHeader,
+    ) -> Result<(), Fail> {
         let segment = TcpSegment {
             ethernet2_hdr: Ethernet2Header {
                 dst_addr: remote_link_addr,
@@ -456,10 +1678,17 @@ impl<RT: Runtime> Inner<RT> {
             ipv4_hdr: Ipv4Header::new(local.addr, remote.addr, Ipv4Protocol2::Tcp),
             tcp_hdr,
             data: RT::Buf::empty(),
-            tx_checksum_offload: self.rt.tcp_options().tx_checksum_offload(),
+            tx_checksum_offload: self.options.tx_checksum_offload(),
+            pseudo_header_sum: None,
         };
-        self.rt.transmit(segment);
-
+        self.loopback.transmit(segment).map_err(|e| {
+            self.counters.count_transmit_drop(&e);
+            e
+        })?;
+        self.counters.update(|s| {
+            s.ip.out_requests += 1;
+            s.tcp.out_segs += 1;
+        });
         Ok(())
     }
 
@@ -470,12 +1699,13 @@ impl<RT: Runtime> Inner<RT> {
     ) -> Poll<Result<(), Fail>> {
         let key = match self.sockets.get(&fd) {
             Some(Socket::Connecting { local, remote }) => (*local, *remote),
+            Some(Socket::Failed { error, .. }) => return Poll::Ready(Err(error.clone())),
             Some(..) => {
                 return Poll::Ready(Err(Fail::Malformed {
                     details: "Socket not connecting",
                 }))
             }
-            None => return Poll::Ready(Err(Fail::Malformed { details: "Bad FD" })),
+            None => return Poll::Ready(Err(Fail::BadFileDescriptor {})),
         };
 
         let result = {
@@ -494,9 +1724,30 @@ impl<RT: Runtime> Inner<RT> {
         };
         self.connecting.remove(&key);
 
-        let cb = result?;
+        let cb = match result {
+            Ok(cb) => cb,
+            Err(e) => {
+                let (local, remote) = key;
+                let error = e.clone();
+                self.sockets.insert(
+                    fd,
+                    Socket::Failed {
+                        local,
+                        remote,
+                        error,
+                    },
+                );
+                return Poll::Ready(Err(e));
+            }
+        };
         let socket = EstablishedSocket::new(cb, fd, self.dead_socket_tx.clone());
-        assert!(self.established.insert(key, socket).is_none());
+        if let Some(nodelay) = self.socket_options.get(&fd).and_then(|o| o.nodelay) {
+            socket.set_nodelay(nodelay);
+        }
+        if let Some(size) = self.socket_options.get(&fd).and_then(|o| o.send_buffer_size) {
+            socket.set_send_buffer_size(size);
+        }
+        self.insert_established(key, socket);
         let (local, remote) = key;
         self.sockets
             .insert(fd, Socket::Established { local, remote });
@@ -0,0 +1,75 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Rate limiting of the segments the engine sends in reply to segments it refuses, such as RSTs,
+//! so that a port scan or a misbehaving peer cannot use it to reflect or amplify traffic.
+
+use std::time::Instant;
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// A token bucket, refilled at a rate given on every use so that it follows option changes.
+#[derive(Clone, Copy, Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    /// Time at which the bucket held `tokens`.
+    updated: Instant,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [TokenBucket].
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(burst: usize, now: Instant) -> Self {
+        Self {
+            tokens: burst as f64,
+            updated: now,
+        }
+    }
+
+    /// Takes a token from the bucket, refilled with `rate` tokens per second up to `burst`, and
+    /// returns whether there was one.
+    pub fn try_take(&mut self, rate: usize, burst: usize, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(burst as f64);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::TokenBucket;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2, now);
+        assert!(bucket.try_take(10, 2, now));
+        assert!(bucket.try_take(10, 2, now));
+        assert!(!bucket.try_take(10, 2, now));
+
+        // One token comes back every 100ms, and the bucket holds no more than the burst.
+        let later = now + Duration::from_millis(100);
+        assert!(bucket.try_take(10, 2, later));
+        assert!(!bucket.try_take(10, 2, later));
+        let much_later = later + Duration::from_secs(10);
+        assert!(bucket.try_take(10, 2, much_later));
+        assert!(bucket.try_take(10, 2, much_later));
+        assert!(!bucket.try_take(10, 2, much_later));
+    }
+}
@@ -5,7 +5,7 @@ use crate::{
     protocols::{
         ethernet2::frame::Ethernet2Header,
         ip,
-        ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
+        ipv4::datagram::{Ipv4Header, Ipv4Protocol2, IPV4_DONT_FRAGMENT},
         tcp::SeqNumber,
     },
     runtime::PacketBuf,
@@ -14,13 +14,18 @@ use crate::{
 use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt};
 use std::{
     convert::{TryFrom, TryInto},
-    io::Cursor,
+    fmt,
+    io::{Cursor, Read},
+    net::Ipv4Addr,
     num::Wrapping,
 };
 
 pub const MIN_TCP_HEADER_SIZE: usize = 20;
 pub const MAX_TCP_HEADER_SIZE: usize = 60;
 pub const MAX_TCP_OPTIONS: usize = 5;
+/// Bounds on the length of a TCP Fast Open cookie (RFC 7413, section 4.1.1).
+pub const MIN_FAST_OPEN_COOKIE_SIZE: usize = 4;
+pub const MAX_FAST_OPEN_COOKIE_SIZE: usize = 16;
 
 pub struct TcpSegment<T: RuntimeBuf> {
     pub ethernet2_hdr: Ethernet2Header,
@@ -29,6 +34,8 @@ pub struct TcpSegment<T: RuntimeBuf> {
     pub data: T,
 
     pub tx_checksum_offload: bool,
+    /// Partial checksum of the pseudo-header from [pseudo_header_sum], if the sender cached it.
+    pub pseudo_header_sum: Option<u32>,
 }
 
 impl<T: RuntimeBuf> PacketBuf<T> for TcpSegment<T> {
@@ -52,16 +59,25 @@ impl<T: RuntimeBuf> PacketBuf<T> for TcpSegment<T> {
             .serialize(&mut buf[cur_pos..(cur_pos + eth_hdr_size)]);
         cur_pos += eth_hdr_size;
 
+        // Segments never get fragmented on the way, as we discover the path MTU instead.
+        let ipv4_hdr = Ipv4Header {
+            flags: self.ipv4_hdr.flags | IPV4_DONT_FRAGMENT,
+            ..self.ipv4_hdr
+        };
         let ipv4_payload_len = tcp_hdr_size + self.data.len();
-        self.ipv4_hdr.serialize(
+        ipv4_hdr.serialize(
             &mut buf[cur_pos..(cur_pos + ipv4_hdr_size)],
             ipv4_payload_len,
+            self.tx_checksum_offload,
         );
         cur_pos += ipv4_hdr_size;
 
-        self.tcp_hdr.serialize(
+        let pseudo_header_sum = self
+            .pseudo_header_sum
+            .unwrap_or_else(|| pseudo_header_sum(self.ipv4_hdr.src_addr, self.ipv4_hdr.dst_addr));
+        self.tcp_hdr.serialize_with_sum(
             &mut buf[cur_pos..(cur_pos + tcp_hdr_size)],
-            &self.ipv4_hdr,
+            pseudo_header_sum,
             &self.data[..],
             self.tx_checksum_offload,
         );
@@ -72,13 +88,15 @@ impl<T: RuntimeBuf> PacketBuf<T> for TcpSegment<T> {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SelectiveAcknowlegement {
     pub begin: SeqNumber,
     pub end: SeqNumber,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TcpOptions2 {
     NoOperation,
     MaximumSegmentSize(u16),
@@ -92,6 +110,11 @@ pub enum TcpOptions2 {
         sender_timestamp: u32,
         echo_timestamp: u32,
     },
+    /// TCP Fast Open cookie, or a request for one if empty.
+    FastOpen {
+        len: usize,
+        cookie: [u8; MAX_FAST_OPEN_COOKIE_SIZE],
+    },
 }
 
 impl TcpOptions2 {
@@ -104,6 +127,18 @@ impl TcpOptions2 {
             SelectiveAcknowlegementPermitted => 2,
             SelectiveAcknowlegement { num_sacks, .. } => 2 + 8 * num_sacks,
             Timestamp { .. } => 10,
+            FastOpen { len, .. } => 2 + len,
+        }
+    }
+
+    /// Returns the TCP Fast Open option carrying `cookie`, which requests one if empty.
+    pub fn fast_open(cookie: &[u8]) -> Self {
+        assert!(cookie.len() <= MAX_FAST_OPEN_COOKIE_SIZE);
+        let mut option = [0; MAX_FAST_OPEN_COOKIE_SIZE];
+        option[..cookie.len()].copy_from_slice(cookie);
+        TcpOptions2::FastOpen {
+            len: cookie.len(),
+            cookie: option,
         }
     }
 
@@ -156,11 +191,18 @@ impl TcpOptions2 {
                 NetworkEndian::write_u32(&mut buf[6..10], *echo_timestamp);
                 10
             }
+            FastOpen { len, cookie } => {
+                buf[0] = 34;
+                buf[1] = 2 + *len as u8;
+                buf[2..(2 + len)].copy_from_slice(&cookie[..*len]);
+                2 + len
+            }
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TcpHeader {
     pub src_port: ip::Port,
     pub dst_port: ip::Port,
@@ -348,6 +390,21 @@ impl TcpHeader {
                             echo_timestamp,
                         }
                     }
+                    34 => {
+                        let option_length = option_rdr.read_u8()? as usize;
+                        let len = option_length.wrapping_sub(2);
+                        if len != 0
+                            && !(MIN_FAST_OPEN_COOKIE_SIZE..=MAX_FAST_OPEN_COOKIE_SIZE)
+                                .contains(&len)
+                        {
+                            return Err(Fail::Malformed {
+                                details: "Invalid TCP Fast Open cookie size",
+                            });
+                        }
+                        let mut cookie = [0; MAX_FAST_OPEN_COOKIE_SIZE];
+                        option_rdr.read_exact(&mut cookie[..len])?;
+                        TcpOptions2::FastOpen { len, cookie }
+                    }
                     _ => {
                         return Err(Fail::Malformed {
                             details: "Invalid TCP option",
@@ -394,6 +451,19 @@ impl TcpHeader {
         ipv4_hdr: &Ipv4Header,
         data: &[u8],
         tx_checksum_offload: bool,
+    ) {
+        let pseudo_header_sum = pseudo_header_sum(ipv4_hdr.src_addr, ipv4_hdr.dst_addr);
+        self.serialize_with_sum(buf, pseudo_header_sum, data, tx_checksum_offload)
+    }
+
+    /// Serializes the header like [TcpHeader::serialize], starting the checksum from
+    /// `pseudo_header_sum` as returned by [pseudo_header_sum] for the addresses of the datagram.
+    pub fn serialize_with_sum(
+        &self,
+        buf: &mut [u8],
+        pseudo_header_sum: u32,
+        data: &[u8],
+        tx_checksum_offload: bool,
     ) {
         let fixed_buf: &mut [u8; MIN_TCP_HEADER_SIZE] =
             (&mut buf[..MIN_TCP_HEADER_SIZE]).try_into().unwrap();
@@ -455,7 +525,7 @@ impl TcpHeader {
 
         // Alright, we've fully filled out the header, time to compute the checksum.
         if !tx_checksum_offload {
-            let checksum = tcp_checksum(ipv4_hdr, &buf[..], data);
+            let checksum = tcp_checksum_with_sum(pseudo_header_sum, &buf[..], data);
             NetworkEndian::write_u16(&mut buf[16..18], checksum);
         } else {
             NetworkEndian::write_u16(&mut buf[16..18], 0u16);
@@ -480,30 +550,80 @@ impl TcpHeader {
         (0..self.num_options).map(move |i| &self.option_list[i])
     }
 
+    /// Returns the value of the timestamp option, if the segment carries one.
+    pub fn sender_timestamp(&self) -> Option<u32> {
+        self.iter_options().find_map(|o| match o {
+            TcpOptions2::Timestamp {
+                sender_timestamp, ..
+            } => Some(*sender_timestamp),
+            _ => None,
+        })
+    }
+
+    /// Returns the timestamp echoed by the timestamp option, if the segment carries one.
+    pub fn echo_timestamp(&self) -> Option<u32> {
+        self.iter_options().find_map(|o| match o {
+            TcpOptions2::Timestamp { echo_timestamp, .. } => Some(*echo_timestamp),
+            _ => None,
+        })
+    }
+
+    /// Returns the blocks of the selective acknowledgment option, if the segment carries one.
+    pub fn selective_acks(&self) -> &[SelectiveAcknowlegement] {
+        self.iter_options()
+            .find_map(|o| match o {
+                TcpOptions2::SelectiveAcknowlegement { num_sacks, sacks } => {
+                    Some(&sacks[..*num_sacks])
+                }
+                _ => None,
+            })
+            .unwrap_or(&[])
+    }
+
+    /// Returns the cookie of the TCP Fast Open option, empty for a cookie request, if the segment
+    /// carries one.
+    pub fn fast_open_cookie(&self) -> Option<&[u8]> {
+        self.iter_options().find_map(|o| match o {
+            TcpOptions2::FastOpen { len, cookie } => Some(&cookie[..*len]),
+            _ => None,
+        })
+    }
+
     pub fn push_option(&mut self, option: TcpOptions2) {
         self.option_list[self.num_options] = option;
         self.num_options += 1;
     }
 }
 
-fn tcp_checksum(ipv4_header: &Ipv4Header, header: &[u8], data: &[u8]) -> u16 {
+/// Returns the partial checksum of the parts of the "pseudo-IP" header that stay the same over
+/// the life of a connection, to be completed with the segment length and contents.
+pub fn pseudo_header_sum(src_addr: Ipv4Addr, dst_addr: Ipv4Addr) -> u32 {
     let mut state = 0xffffu32;
 
-    // First, fold in a "pseudo-IP" header of...
     // 1) Source address (4 bytes)
-    let src_octets = ipv4_header.src_addr.octets();
+    let src_octets = src_addr.octets();
     state += NetworkEndian::read_u16(&src_octets[0..2]) as u32;
     state += NetworkEndian::read_u16(&src_octets[2..4]) as u32;
 
     // 2) Destination address (4 bytes)
-    let dst_octets = ipv4_header.dst_addr.octets();
+    let dst_octets = dst_addr.octets();
     state += NetworkEndian::read_u16(&dst_octets[0..2]) as u32;
     state += NetworkEndian::read_u16(&dst_octets[2..4]) as u32;
 
     // 3) 1 byte of zeros and TCP protocol number (1 byte)
     state += NetworkEndian::read_u16(&[0, Ipv4Protocol2::Tcp as u8]) as u32;
+    state
+}
 
-    // 4) TCP segment length (2 bytes)
+fn tcp_checksum(ipv4_header: &Ipv4Header, header: &[u8], data: &[u8]) -> u16 {
+    let pseudo_header_sum = pseudo_header_sum(ipv4_header.src_addr, ipv4_header.dst_addr);
+    tcp_checksum_with_sum(pseudo_header_sum, header, data)
+}
+
+fn tcp_checksum_with_sum(pseudo_header_sum: u32, header: &[u8], data: &[u8]) -> u16 {
+    // First, fold in the "pseudo-IP" header, of which only the TCP segment length (2 bytes) is
+    // left.
+    let mut state = pseudo_header_sum;
     state += (header.len() + data.len()) as u32;
 
     let fixed_header: &[u8; MIN_TCP_HEADER_SIZE] =
@@ -564,3 +684,105 @@ fn tcp_checksum(ipv4_header: &Ipv4Header, header: &[u8], data: &[u8]) -> u16 {
     }
     !state as u16
 }
+
+impl TcpHeader {
+    /// Formats everything but the ports, e.g. `Flags [S.], seq 0, ack 1, win 65535`.
+    fn fmt_details(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Flags are listed in the same order as tcpdump does.
+        let flags = [
+            (self.fin, 'F'),
+            (self.syn, 'S'),
+            (self.rst, 'R'),
+            (self.psh, 'P'),
+            (self.ack, '.'),
+            (self.urg, 'U'),
+            (self.ece, 'E'),
+            (self.cwr, 'W'),
+        ];
+        let flags: String = flags
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, c)| c)
+            .collect();
+        if flags.is_empty() {
+            write!(f, "Flags [none]")?;
+        } else {
+            write!(f, "Flags [{}]", flags)?;
+        }
+        write!(f, ", seq {}", self.seq_num)?;
+        if self.ack {
+            write!(f, ", ack {}", self.ack_num)?;
+        }
+        write!(f, ", win {}", self.window_size)?;
+        if self.urg {
+            write!(f, ", urg {}", self.urgent_pointer)?;
+        }
+        if self.num_options > 0 {
+            write!(f, ", options [")?;
+            for (i, option) in self.iter_options().enumerate() {
+                if i > 0 {
+                    write!(f, ",")?;
+                }
+                write!(f, "{}", option)?;
+            }
+            write!(f, "]")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TcpOptions2 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use TcpOptions2::*;
+        match self {
+            NoOperation => write!(f, "nop"),
+            MaximumSegmentSize(mss) => write!(f, "mss {}", mss),
+            WindowScale(scale) => write!(f, "wscale {}", scale),
+            SelectiveAcknowlegementPermitted => write!(f, "sackOK"),
+            SelectiveAcknowlegement { num_sacks, sacks } => {
+                write!(f, "sack {}", num_sacks)?;
+                for sack in &sacks[..*num_sacks] {
+                    write!(f, " {{{}:{}}}", sack.begin, sack.end)?;
+                }
+                Ok(())
+            }
+            Timestamp {
+                sender_timestamp,
+                echo_timestamp,
+            } => write!(f, "TS val {} ecr {}", sender_timestamp, echo_timestamp),
+            FastOpen { len: 0, .. } => write!(f, "tfo cookiereq"),
+            FastOpen { len, cookie } => {
+                write!(f, "tfo cookie ")?;
+                for byte in &cookie[..*len] {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Formats the header as `443 > 5555: Flags [S.], seq 0, ack 1, win 65535`.
+impl fmt::Display for TcpHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} > {}: ", self.src_port, self.dst_port)?;
+        self.fmt_details(f)
+    }
+}
+
+/// Formats the segment the way tcpdump does, e.g.
+/// `IP 10.0.0.1.443 > 10.0.0.2.5555: Flags [S.], seq 0, ack 1, win 65535, length 0`.
+impl<T: RuntimeBuf> fmt::Display for TcpSegment<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "IP {}.{} > {}.{}: ",
+            self.ipv4_hdr.src_addr,
+            self.tcp_hdr.src_port,
+            self.ipv4_hdr.dst_addr,
+            self.tcp_hdr.dst_port
+        )?;
+        self.tcp_hdr.fmt_details(f)?;
+        write!(f, ", length {}", self.data.len())
+    }
+}
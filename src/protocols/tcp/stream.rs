@@ -0,0 +1,246 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Owned TCP socket handles in the spirit of `std::net::{TcpListener, TcpStream}`.
+//!
+//! Each handle pairs a file descriptor with a handle to the TCP peer that created it and closes
+//! the descriptor when dropped.
+
+use super::{
+    events::Readiness,
+    operations::{IncomingStream, PopFuture, PushFuture},
+    peer::Peer,
+};
+use crate::{
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::ipv4,
+    runtime::{Runtime, RuntimeBuf},
+};
+use futures::io::{AsyncRead, AsyncWrite};
+use std::{
+    cmp, fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// A TCP socket listening for incoming connections.
+pub struct TcpListener<RT: Runtime> {
+    fd: FileDescriptor,
+    peer: Peer<RT>,
+}
+
+/// An established TCP connection.
+pub struct TcpStream<RT: Runtime> {
+    fd: FileDescriptor,
+    peer: Peer<RT>,
+    /// Received data not yet consumed through [AsyncRead].
+    unread: Option<RT::Buf>,
+    closed: bool,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+impl<RT: Runtime> TcpListener<RT> {
+    /// Creates a TCP socket bound to `local` and starts listening on it.
+    pub fn bind(peer: &Peer<RT>, local: ipv4::Endpoint, backlog: usize) -> Result<Self, Fail> {
+        let listener = Self {
+            fd: peer.socket()?,
+            peer: peer.clone(),
+        };
+        listener.peer.bind(listener.fd, local)?;
+        listener.peer.listen(listener.fd, backlog)?;
+        Ok(listener)
+    }
+
+    /// Waits for the next incoming connection.
+    pub fn accept(&self) -> impl Future<Output = Result<TcpStream<RT>, Fail>> {
+        let peer = self.peer.clone();
+        let future = self.peer.accept(self.fd);
+        async move {
+            let (fd, _) = future.await?;
+            Ok(TcpStream::new(fd, peer))
+        }
+    }
+
+    /// Returns a stream of incoming connections, each paired with its remote endpoint.
+    pub fn incoming(&self) -> IncomingStream<RT> {
+        self.peer.incoming(self.fd)
+    }
+
+    /// Returns the underlying file descriptor.
+    pub fn fd(&self) -> FileDescriptor {
+        self.fd
+    }
+}
+
+impl<RT: Runtime> TcpStream<RT> {
+    fn new(fd: FileDescriptor, peer: Peer<RT>) -> Self {
+        Self {
+            fd,
+            peer,
+            unread: None,
+            closed: false,
+        }
+    }
+
+    /// Opens a TCP connection to `remote`. The socket gets closed should connecting fail, or the
+    /// future be dropped first.
+    pub fn connect(
+        peer: &Peer<RT>,
+        remote: ipv4::Endpoint,
+    ) -> impl Future<Output = Result<Self, Fail>> {
+        let connect = peer
+            .socket()
+            .map(|fd| (Self::new(fd, peer.clone()), peer.connect(fd, remote)));
+        async move {
+            let (stream, future) = connect?;
+            future.await?;
+            Ok(stream)
+        }
+    }
+
+    /// Enqueues `buf` for transmission.
+    pub fn send(&self, buf: RT::Buf) -> PushFuture<RT> {
+        self.peer.push(self.fd, buf)
+    }
+
+    /// Waits for the next chunk of received data.
+    pub fn recv(&self) -> PopFuture<RT> {
+        self.peer.pop(self.fd)
+    }
+
+    /// Returns the local endpoint of this connection.
+    pub fn local_addr(&self) -> Result<ipv4::Endpoint, Fail> {
+        self.peer.local_endpoint(self.fd)
+    }
+
+    /// Returns the remote endpoint of this connection.
+    pub fn peer_addr(&self) -> Result<ipv4::Endpoint, Fail> {
+        self.peer.remote_endpoint(self.fd)
+    }
+
+    /// Returns the underlying file descriptor.
+    pub fn fd(&self) -> FileDescriptor {
+        self.fd
+    }
+}
+
+//==============================================================================
+// Trait Implementations
+//==============================================================================
+
+impl<RT: Runtime> fmt::Debug for TcpListener<RT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TcpListener({})", self.fd)
+    }
+}
+
+impl<RT: Runtime> fmt::Debug for TcpStream<RT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TcpStream({})", self.fd)
+    }
+}
+
+impl<RT: Runtime> Drop for TcpListener<RT> {
+    fn drop(&mut self) {
+        if let Err(e) = self.peer.close(self.fd) {
+            warn!("Failed to close listener {}: {:?}", self.fd, e);
+        }
+    }
+}
+
+impl<RT: Runtime> AsyncRead for TcpStream<RT> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let self_ = self.get_mut();
+        let mut unread = match self_.unread.take() {
+            Some(unread) => unread,
+            None => match self_.peer.poll_recv(self_.fd, ctx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(received)) => received,
+                // Reads end with ResourceNotFound once the remote sent its FIN or reading got
+                // shut down, and with the error that tore the connection down otherwise.
+                Poll::Ready(Err(Fail::ResourceNotFound { .. })) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+            },
+        };
+        let nbytes = cmp::min(buf.len(), unread.len());
+        buf[..nbytes].copy_from_slice(&unread[..nbytes]);
+        unread.adjust(nbytes);
+        if !unread.is_empty() {
+            self_.unread = Some(unread);
+        }
+        Poll::Ready(Ok(nbytes))
+    }
+}
+
+impl<RT: Runtime> AsyncWrite for TcpStream<RT> {
+    /// Pushes as much of `buf` as the send buffer takes, copying it once. While it is full, the
+    /// waker of `ctx` is registered through [Peer::poll_ready] to be woken once it has room,
+    /// which replaces any registration the application made for the same socket.
+    fn poll_write(self: Pin<&mut Self>, ctx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let room = match self.peer.send_room(self.fd) {
+            Ok(0) => {
+                return match self.peer.poll_ready(self.fd, Readiness::WRITABLE, ctx) {
+                    Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
+                    Poll::Ready(Ok(_)) => {
+                        ctx.waker().wake_by_ref();
+                        Poll::Pending
+                    }
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+            Ok(room) => room,
+            Err(e) => return Poll::Ready(Err(e.into())),
+        };
+        let len = buf.len().min(room);
+        let mut push_future = self.peer.push(self.fd, RT::Buf::from_slice(&buf[..len]));
+        match Future::poll(Pin::new(&mut push_future), ctx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(len)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _ctx: &mut Context) -> Poll<io::Result<()>> {
+        // Pushed data is handed to the sender immediately; there is nothing to flush.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _ctx: &mut Context) -> Poll<io::Result<()>> {
+        let self_ = self.get_mut();
+        if !self_.closed {
+            self_.closed = true;
+            if let Err(e) = self_.peer.close(self_.fd) {
+                return Poll::Ready(Err(e.into()));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<RT: Runtime> Drop for TcpStream<RT> {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        if let Err(e) = self.peer.close(self.fd) {
+            warn!("Failed to close stream {}: {:?}", self.fd, e);
+        }
+    }
+}
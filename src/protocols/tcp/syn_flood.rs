@@ -0,0 +1,330 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Protection of listeners against SYN floods.
+//!
+//! A listener tracks how fast handshakes are started, in total and per source address. Above the
+//! listener threshold it stops keeping state for new handshakes and answers with a SYN cookie: an
+//! ISN that encodes the MSS and a hash of the connection, checked when the final ACK arrives.
+//! Above the source threshold, SYNs of that source are dropped with a probability that grows with
+//! its rate, so a single flooding host cannot crowd out the others.
+
+use crate::protocols::{ipv4, tcp::SeqNumber};
+use crc::{crc32, Hasher32};
+use std::{
+    collections::HashMap,
+    hash::Hasher,
+    net::Ipv4Addr,
+    num::Wrapping,
+    time::{Duration, Instant},
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Window over which rates are measured.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Sources tracked at once. SYNs from other sources only count towards the listener rate.
+const MAX_TRACKED_SOURCES: usize = 4096;
+
+/// Period of the cookie counter. A cookie is valid for one to two periods.
+const COOKIE_PERIOD: Duration = Duration::from_secs(64);
+
+/// MSS values a cookie can encode, in 3 bits.
+const COOKIE_MSS: [u16; 8] = [536, 1200, 1360, 1400, 1440, 1460, 4312, 8960];
+
+/// What to do with a SYN.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SynVerdict {
+    /// Start a handshake, keeping its state.
+    Accept,
+    /// Answer with a SYN cookie and keep no state.
+    Cookie,
+    /// Drop the SYN.
+    Drop,
+}
+
+/// Events counted over the current window, with the count of the previous one to smooth the
+/// estimate across window boundaries.
+#[derive(Clone, Copy, Debug)]
+struct Rate {
+    window_start: Instant,
+    current: usize,
+    previous: usize,
+}
+
+/// Rates of the handshakes started on a listener.
+pub struct SynFloodGuard {
+    listener: Rate,
+    sources: HashMap<Ipv4Addr, Rate>,
+    listener_threshold: usize,
+    source_threshold: usize,
+    secret: u32,
+    /// Start of the cookie counter.
+    epoch: Instant,
+    /// When a cookie was last sent, if ever.
+    last_cookie: Option<Instant>,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [Rate].
+impl Rate {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            current: 0,
+            previous: 0,
+        }
+    }
+
+    /// Moves the window forward to include `now`.
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        if elapsed < 2 * RATE_WINDOW {
+            self.previous = self.current;
+            self.window_start += RATE_WINDOW;
+        } else {
+            self.previous = 0;
+            self.window_start = now;
+        }
+        self.current = 0;
+    }
+
+    /// Returns the number of events over the last window, weighting the previous window by how
+    /// much of it is still covered.
+    fn get(&self, now: Instant) -> usize {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        let (current, previous, elapsed) = if elapsed < RATE_WINDOW {
+            (self.current, self.previous, elapsed)
+        } else if elapsed < 2 * RATE_WINDOW {
+            (0, self.current, elapsed - RATE_WINDOW)
+        } else {
+            return 0;
+        };
+        let remaining = (RATE_WINDOW - elapsed).as_micros() as usize;
+        current + previous * remaining / RATE_WINDOW.as_micros() as usize
+    }
+
+    fn is_idle(&self) -> bool {
+        self.current == 0 && self.previous == 0
+    }
+}
+
+/// Associate functions for [SynFloodGuard].
+impl SynFloodGuard {
+    pub fn new(
+        listener_threshold: usize,
+        source_threshold: usize,
+        secret: u32,
+        now: Instant,
+    ) -> Self {
+        Self {
+            listener: Rate::new(now),
+            sources: HashMap::new(),
+            listener_threshold,
+            source_threshold,
+            secret,
+            epoch: now,
+            last_cookie: None,
+        }
+    }
+
+    /// Replaces the thresholds, in handshakes per second.
+    pub fn set_thresholds(&mut self, listener_threshold: usize, source_threshold: usize) {
+        self.listener_threshold = listener_threshold;
+        self.source_threshold = source_threshold;
+    }
+
+    /// Counts a SYN from `source` and decides what to do with it. `random` is a uniformly
+    /// distributed value used to pick which SYNs of a flooding source get dropped.
+    pub fn on_syn(&mut self, source: Ipv4Addr, now: Instant, random: u32) -> SynVerdict {
+        let window_start = self.listener.window_start;
+        self.listener.roll(now);
+        if self.listener.window_start != window_start {
+            self.sources.retain(|_, r| {
+                r.roll(now);
+                !r.is_idle()
+            });
+        }
+        self.listener.current += 1;
+
+        if self.sources.len() < MAX_TRACKED_SOURCES || self.sources.contains_key(&source) {
+            let rate = self.sources.entry(source).or_insert_with(|| Rate::new(now));
+            rate.roll(now);
+            rate.current += 1;
+            // Drop with probability (rate - threshold) / rate.
+            let rate = rate.get(now);
+            if rate > self.source_threshold && (random as usize % rate) >= self.source_threshold {
+                return SynVerdict::Drop;
+            }
+        }
+
+        if self.listener.get(now) > self.listener_threshold {
+            SynVerdict::Cookie
+        } else {
+            SynVerdict::Accept
+        }
+    }
+
+    /// Returns the SYN cookie to use as ISN in reply to a SYN with sequence number `remote_isn`
+    /// and MSS option `mss`, and the MSS actually encoded.
+    pub fn cookie(
+        &mut self,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+        remote_isn: SeqNumber,
+        mss: usize,
+        now: Instant,
+    ) -> (SeqNumber, usize) {
+        self.last_cookie = Some(now);
+        let index = COOKIE_MSS
+            .iter()
+            .rposition(|&m| m as usize <= mss)
+            .unwrap_or(0);
+        let counter = self.counter(now);
+        let hash = self.hash(local, remote, remote_isn, counter);
+        let cookie = ((counter & 0x1f) << 27) | ((index as u32) << 24) | (hash & 0x00ff_ffff);
+        (Wrapping(cookie), COOKIE_MSS[index] as usize)
+    }
+
+    /// Checks the SYN cookie acknowledged by an ACK with sequence number `seq_num` and
+    /// acknowledgement number `ack_num`, returning the MSS it encodes if valid.
+    pub fn check_cookie(
+        &self,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+        seq_num: SeqNumber,
+        ack_num: SeqNumber,
+        now: Instant,
+    ) -> Option<usize> {
+        if !self.sent_cookies(now) {
+            return None;
+        }
+        let cookie = (ack_num - Wrapping(1)).0;
+        let remote_isn = seq_num - Wrapping(1);
+        let counter = self.counter(now);
+        [counter, counter.wrapping_sub(1)]
+            .iter()
+            .find(|&&c| {
+                cookie >> 27 == c & 0x1f
+                    && cookie & 0x00ff_ffff == self.hash(local, remote, remote_isn, c) & 0x00ff_ffff
+            })
+            .map(|_| COOKIE_MSS[((cookie >> 24) & 0x7) as usize] as usize)
+    }
+
+    /// Returns whether cookies sent until `now` may still be valid.
+    pub fn sent_cookies(&self, now: Instant) -> bool {
+        self.last_cookie.map_or(false, |t| {
+            now.saturating_duration_since(t) < 2 * COOKIE_PERIOD
+        })
+    }
+
+    fn counter(&self, now: Instant) -> u32 {
+        (now.saturating_duration_since(self.epoch).as_secs() / COOKIE_PERIOD.as_secs()) as u32
+    }
+
+    fn hash(
+        &self,
+        local: &ipv4::Endpoint,
+        remote: &ipv4::Endpoint,
+        remote_isn: SeqNumber,
+        counter: u32,
+    ) -> u32 {
+        let mut hash = crc32::Digest::new(crc32::IEEE);
+        hash.write_u32(remote.address().into());
+        hash.write_u16(remote.port().into());
+        hash.write_u32(local.address().into());
+        hash.write_u16(local.port().into());
+        hash.write_u32(remote_isn.0);
+        hash.write_u32(counter);
+        hash.write_u32(self.secret);
+        hash.sum32()
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{SynFloodGuard, SynVerdict, COOKIE_PERIOD};
+    use crate::{
+        protocols::{ip, ipv4},
+        test_helpers,
+    };
+    use std::{
+        convert::TryFrom,
+        num::Wrapping,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_syn_verdicts() {
+        let now = Instant::now();
+        let mut guard = SynFloodGuard::new(4, 2, 0, now);
+        let (alice, carrie) = (test_helpers::ALICE_IPV4, test_helpers::CARRIE_IPV4);
+
+        assert_eq!(guard.on_syn(alice, now, 0), SynVerdict::Accept);
+        assert_eq!(guard.on_syn(alice, now, 0), SynVerdict::Accept);
+        // Alice is over her threshold, so one in three of her SYNs is dropped.
+        assert_eq!(guard.on_syn(alice, now, 2), SynVerdict::Drop);
+        assert_eq!(guard.on_syn(carrie, now, 0), SynVerdict::Accept);
+        assert_eq!(guard.on_syn(carrie, now, 0), SynVerdict::Cookie);
+        assert_eq!(guard.listener.get(now), 5);
+
+        // Rates decay once the flood stops.
+        let later = now + Duration::from_secs(2);
+        assert_eq!(guard.listener.get(later), 0);
+        assert_eq!(guard.on_syn(alice, later, 2), SynVerdict::Accept);
+    }
+
+    #[test]
+    fn test_syn_cookies() {
+        let now = Instant::now();
+        let mut guard = SynFloodGuard::new(1, 1, 42, now);
+        let port = |p| ip::Port::try_from(p).unwrap();
+        let local = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port(80));
+        let remote = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(50000));
+        let remote_isn = Wrapping(1000);
+
+        // No cookie was sent yet.
+        assert_eq!(
+            guard.check_cookie(&local, &remote, remote_isn + Wrapping(1), Wrapping(1), now),
+            None
+        );
+
+        let (isn, mss) = guard.cookie(&local, &remote, remote_isn, 1500, now);
+        assert_eq!(mss, 1460);
+        let (seq_num, ack_num) = (remote_isn + Wrapping(1), isn + Wrapping(1));
+        assert_eq!(
+            guard.check_cookie(&local, &remote, seq_num, ack_num, now),
+            Some(1460)
+        );
+        let later = now + COOKIE_PERIOD;
+        assert_eq!(
+            guard.check_cookie(&local, &remote, seq_num, ack_num, later),
+            Some(1460)
+        );
+
+        // Cookies expire, and do not validate for another connection.
+        let expired = now + 2 * COOKIE_PERIOD;
+        assert_eq!(
+            guard.check_cookie(&local, &remote, seq_num, ack_num, expired),
+            None
+        );
+        let other = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, port(50001));
+        assert_eq!(
+            guard.check_cookie(&local, &other, seq_num, ack_num, now),
+            None
+        );
+    }
+}
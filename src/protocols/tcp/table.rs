@@ -0,0 +1,233 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::protocols::ipv4;
+use slab::Slab;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    net::Ipv4Addr,
+    ops::Index,
+    rc::Rc,
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Local and remote endpoints of a connection.
+pub type ConnectionKey = (ipv4::Endpoint, ipv4::Endpoint);
+
+/// Per-connection state kept in a slab owned by the peer, so that the entries the demultiplexer
+/// looks up sit next to each other and slots freed by torn down connections are reused instead of
+/// going back to the allocator. An index maps the endpoints of a connection to its slot.
+pub struct ConnectionTable<S> {
+    slab: Slab<(ConnectionKey, S)>,
+    index: HashMap<ConnectionKey, usize>,
+}
+
+/// Running count of the connections established or being accepted, in total and per remote
+/// address, which the connection limits are checked against. Counting a connection twice or
+/// discounting one that is not counted has no effect, so that every place a connection may come
+/// or go can report it. Clones share the count.
+#[derive(Clone, Default)]
+pub struct ConnectionCount {
+    inner: Rc<RefCell<CountInner>>,
+}
+
+#[derive(Default)]
+struct CountInner {
+    keys: HashSet<ConnectionKey>,
+    per_remote: HashMap<Ipv4Addr, usize>,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [ConnectionTable].
+impl<S> ConnectionTable<S> {
+    /// Creates a table with room for `capacity` connections before it has to grow.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            slab: Slab::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
+        }
+    }
+
+    pub fn contains_key(&self, key: &ConnectionKey) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn get(&self, key: &ConnectionKey) -> Option<&S> {
+        self.index.get(key).map(|&slot| &self.slab[slot].1)
+    }
+
+    /// Stores `value` for `key`, returning the value it replaces if any.
+    pub fn insert(&mut self, key: ConnectionKey, value: S) -> Option<S> {
+        if let Some(&slot) = self.index.get(&key) {
+            return Some(std::mem::replace(&mut self.slab[slot].1, value));
+        }
+        let slot = self.slab.insert((key, value));
+        self.index.insert(key, slot);
+        None
+    }
+
+    pub fn remove(&mut self, key: &ConnectionKey) -> Option<S> {
+        let slot = self.index.remove(key)?;
+        Some(self.slab.remove(slot).1)
+    }
+
+    /// Iterates over the connections in slot order.
+    pub fn iter(&self) -> impl Iterator<Item = (&ConnectionKey, &S)> {
+        self.slab.iter().map(|(_, (key, value))| (key, value))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &S> {
+        self.slab.iter().map(|(_, (_, value))| value)
+    }
+}
+
+/// Associate functions for [ConnectionCount].
+impl ConnectionCount {
+    pub fn add(&self, key: ConnectionKey) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.keys.insert(key) {
+            *inner.per_remote.entry(key.1.addr).or_default() += 1;
+        }
+    }
+
+    pub fn remove(&self, key: &ConnectionKey) {
+        let mut inner = self.inner.borrow_mut();
+        if !inner.keys.remove(key) {
+            return;
+        }
+        let count = inner.per_remote.get_mut(&key.1.addr).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            inner.per_remote.remove(&key.1.addr);
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.inner.borrow().keys.len()
+    }
+
+    pub fn from_remote(&self, addr: Ipv4Addr) -> usize {
+        self.inner.borrow().per_remote.get(&addr).copied().unwrap_or(0)
+    }
+}
+
+//==============================================================================
+// Trait Implementations
+//==============================================================================
+
+impl<S> Index<&ConnectionKey> for ConnectionTable<S> {
+    type Output = S;
+
+    fn index(&self, key: &ConnectionKey) -> &S {
+        self.get(key).expect("no connection for key")
+    }
+}
+
+//==============================================================================
+// Standalone Functions
+//==============================================================================
+
+/// Hashes the endpoints of a connection with FNV-1a. The result only depends on the endpoints, so
+/// engines sharing a port across shards steer a given flow to the same listener index.
+pub fn flow_hash(local: &ipv4::Endpoint, remote: &ipv4::Endpoint) -> u32 {
+    const OFFSET_BASIS: u32 = 0x811c_9dc5;
+    const PRIME: u32 = 0x0100_0193;
+
+    let (local_port, remote_port): (u16, u16) = (local.port().into(), remote.port().into());
+    let mut bytes = [0u8; 12];
+    bytes[0..4].copy_from_slice(&local.address().octets());
+    bytes[4..6].copy_from_slice(&local_port.to_be_bytes());
+    bytes[6..10].copy_from_slice(&remote.address().octets());
+    bytes[10..12].copy_from_slice(&remote_port.to_be_bytes());
+    bytes.iter().fold(OFFSET_BASIS, |hash, &b| {
+        (hash ^ b as u32).wrapping_mul(PRIME)
+    })
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{flow_hash, ConnectionCount, ConnectionTable};
+    use crate::{
+        protocols::{ip, ipv4},
+        test_helpers,
+    };
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_connection_table() {
+        let endpoint = |addr, port| ipv4::Endpoint::new(addr, ip::Port::try_from(port).unwrap());
+        let local = endpoint(test_helpers::ALICE_IPV4, 80);
+        let (a, b) = (
+            (local, endpoint(test_helpers::BOB_IPV4, 50000)),
+            (local, endpoint(test_helpers::CARRIE_IPV4, 50000)),
+        );
+
+        let mut table = ConnectionTable::with_capacity(2);
+        assert!(table.insert(a, 1).is_none());
+        assert!(table.insert(b, 2).is_none());
+        assert_eq!(table.insert(a, 3), Some(1));
+        assert_eq!(table[&a], 3);
+        assert_eq!(table.iter().collect::<Vec<_>>(), vec![(&a, &3), (&b, &2)]);
+
+        // Freed slots are reused.
+        assert_eq!(table.remove(&a), Some(3));
+        assert!(!table.contains_key(&a));
+        assert!(table.remove(&a).is_none());
+        table.insert(a, 4);
+        assert_eq!(table.values().collect::<Vec<_>>(), vec![&4, &2]);
+        assert_eq!(table.get(&b), Some(&2));
+    }
+
+    #[test]
+    fn test_connection_count() {
+        let endpoint = |addr, port| ipv4::Endpoint::new(addr, ip::Port::try_from(port).unwrap());
+        let local = endpoint(test_helpers::ALICE_IPV4, 80);
+        let (a, b, c) = (
+            (local, endpoint(test_helpers::BOB_IPV4, 50000)),
+            (local, endpoint(test_helpers::BOB_IPV4, 50001)),
+            (local, endpoint(test_helpers::CARRIE_IPV4, 50000)),
+        );
+
+        let count = ConnectionCount::default();
+        count.add(a);
+        count.add(b);
+        count.add(c);
+        count.add(a);
+        assert_eq!(count.total(), 3);
+        assert_eq!(count.from_remote(test_helpers::BOB_IPV4), 2);
+
+        // Clones share the count, and connections are discounted once.
+        let clone = count.clone();
+        clone.remove(&a);
+        clone.remove(&a);
+        assert_eq!(count.total(), 2);
+        assert_eq!(count.from_remote(test_helpers::BOB_IPV4), 1);
+        count.remove(&c);
+        assert_eq!(count.from_remote(test_helpers::CARRIE_IPV4), 0);
+    }
+
+    #[test]
+    fn test_flow_hash() {
+        let endpoint = |addr, port| ipv4::Endpoint::new(addr, ip::Port::try_from(port).unwrap());
+        let local = endpoint(test_helpers::ALICE_IPV4, 80);
+        let remote = endpoint(test_helpers::BOB_IPV4, 50000);
+
+        assert_eq!(flow_hash(&local, &remote), flow_hash(&local, &remote));
+        assert_ne!(flow_hash(&local, &remote), flow_hash(&remote, &local));
+        assert_ne!(
+            flow_hash(&local, &remote),
+            flow_hash(&local, &endpoint(test_helpers::BOB_IPV4, 50001))
+        );
+    }
+}
@@ -4,33 +4,47 @@
 use crate::{
     collections::bytes::{Bytes, BytesMut},
     engine::Engine,
+    fail::Fail,
     file_table::FileDescriptor,
+    netstat::SocketState,
     protocols::{
         ip::{self},
         ipv4::{self},
         tcp::{
+            self,
+            fsm::Event,
             operations::PushFuture,
             tests::{
                 check_packet_data, check_packet_pure_ack,
                 setup::{advance_clock, connection_setup},
+                WakeCount,
             },
+            CloseReason, CloseSummary, State, StateTransition,
         },
+        Protocol,
     },
     runtime::Runtime,
-    test_helpers::{self, TestRuntime},
+    test_helpers::{self, ClockSkew, LinkModel, TestRuntime},
 };
 use futures::task::noop_waker_ref;
 use must_let::must_let;
 use rand;
 use std::{
+    cell::RefCell,
     collections::VecDeque,
     convert::TryFrom,
     future::Future,
+    net::Shutdown,
     num::Wrapping,
     ops::Add,
     pin::Pin,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 //=============================================================================
@@ -284,6 +298,43 @@ fn connection_hangup(
 
 //=============================================================================
 
+/// Connects two sockets of `engine` to each other over loopback, returning the listening socket,
+/// the accepted connection and the client connection.
+fn loopback_connection(
+    engine: &mut Engine<TestRuntime>,
+) -> (FileDescriptor, FileDescriptor, FileDescriptor) {
+    loopback_connection_with(engine, |_, _| ())
+}
+
+/// Connects like [loopback_connection], letting `configure` set the client socket up before it
+/// connects.
+fn loopback_connection_with<F: FnOnce(&mut Engine<TestRuntime>, FileDescriptor)>(
+    engine: &mut Engine<TestRuntime>,
+    configure: F,
+) -> (FileDescriptor, FileDescriptor, FileDescriptor) {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd: FileDescriptor = engine.tcp_socket().unwrap();
+    engine.tcp_bind(listen_fd, listen_addr).unwrap();
+    engine.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = engine.tcp_accept(listen_fd);
+    let client_fd: FileDescriptor = engine.tcp_socket().unwrap();
+    configure(engine, client_fd);
+    let mut connect_future = engine.tcp_connect(client_fd, listen_addr);
+
+    // Each poll of the scheduler carries the handshake one segment further.
+    for _ in 0..8 {
+        engine.rt().poll_scheduler();
+    }
+    must_let!(let Poll::Ready(Ok((server_fd, _))) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    (listen_fd, server_fd, client_fd)
+}
+
+//=============================================================================
+
 /// Tests one way communication. This should force the receiving peer to send
 /// pure ACKs to the sender.
 #[test]
@@ -332,6 +383,58 @@ pub fn test_send_recv_loop() {
 
 //=============================================================================
 
+/// Tests that one way traffic takes the header prediction fast path, for data on the receiving
+/// side and for pure ACKs on the sending side.
+#[test]
+pub fn test_header_prediction() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers.
+    let mut server: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let mut client: Engine<TestRuntime> = test_helpers::new_alice2(now);
+    let window_scale: u8 = client.rt().tcp_options().window_scale();
+    let max_window_size: u32 = (client.rt().tcp_options().receive_window_size() as u32)
+        .checked_shl(window_scale as u32)
+        .unwrap();
+
+    let (server_fd, client_fd): (FileDescriptor, FileDescriptor) = connection_setup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        listen_port,
+        listen_addr,
+    );
+
+    let bufsize: u32 = 64;
+    let buf: Bytes = cook_buffer(bufsize as usize, None);
+    let rounds: u32 = 8;
+    for i in 0..rounds {
+        send_recv(
+            &mut ctx,
+            &mut now,
+            &mut server,
+            &mut client,
+            server_fd,
+            client_fd,
+            max_window_size as u16,
+            Wrapping(1 + i * bufsize),
+            buf.clone(),
+        );
+    }
+
+    assert_eq!(server.stats().tcp.predicted_data, rounds as u64);
+    assert_eq!(client.stats().tcp.predicted_acks, rounds as u64);
+    assert_eq!(client.stats().tcp.predicted_data, 0);
+}
+
+//=============================================================================
+
 #[test]
 pub fn test_send_recv_round_loop() {
     let mut ctx = Context::from_waker(noop_waker_ref());
@@ -499,3 +602,1264 @@ fn test_connect_disconnect() {
         client_fd,
     );
 }
+
+//=============================================================================
+
+/// Tests a connection between two sockets of the same engine, whose segments never leave it.
+#[test]
+fn test_loopback() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listen_fd: FileDescriptor = engine.tcp_socket().unwrap();
+    engine.tcp_bind(listen_fd, listen_addr).unwrap();
+    engine.tcp_listen(listen_fd, 1).unwrap();
+    let mut accept_future = engine.tcp_accept(listen_fd);
+    let client_fd: FileDescriptor = engine.tcp_socket().unwrap();
+    let mut connect_future = engine.tcp_connect(client_fd, listen_addr);
+
+    // Each poll of the scheduler carries the handshake one segment further.
+    for _ in 0..8 {
+        engine.rt().poll_scheduler();
+    }
+    must_let!(let Poll::Ready(Ok((server_fd, remote))) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    // The accept tells where the connection comes from.
+    assert_eq!(remote, engine.tcp_info(client_fd).unwrap().local);
+    assert_eq!(engine.tcp_local_endpoint(client_fd), Ok(remote));
+    assert_eq!(engine.tcp_remote_endpoint(client_fd), Ok(listen_addr));
+    assert_eq!(engine.tcp_local_endpoint(server_fd), Ok(listen_addr));
+    assert_eq!(engine.tcp_remote_endpoint(server_fd), Ok(remote));
+    assert_eq!(engine.tcp_local_endpoint(listen_fd), Ok(listen_addr));
+    must_let!(let Err(Fail::ResourceNotFound { .. }) = engine.tcp_remote_endpoint(listen_fd));
+
+    let buf: Bytes = cook_buffer(64, None);
+    let mut push_future = engine.tcp_push(client_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    for _ in 0..8 {
+        engine.rt().poll_scheduler();
+    }
+    let mut pop_future = engine.tcp_pop(server_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&received[..], &buf[..]);
+
+    assert!(engine.rt().pop_frame_unchecked().is_none());
+    assert!(engine.stats().tcp.in_segs >= 4);
+}
+
+//=============================================================================
+
+/// Tests that exact pops gather bytes across segments and split them as needed.
+#[test]
+fn test_pop_exact() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let (_, server_fd, client_fd) = loopback_connection(&mut engine);
+
+    let mut expected: Vec<u8> = Vec::new();
+    let mut pop_future = engine.tcp_pop_exact(server_fd, 100);
+    for stamp in 0..3 {
+        let buf: Bytes = cook_buffer(64, Some(stamp));
+        expected.extend_from_slice(&buf[..]);
+        let mut push_future = engine.tcp_push(client_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        for _ in 0..8 {
+            engine.rt().poll_scheduler();
+        }
+        // The first segment alone is not enough.
+        if stamp == 0 {
+            must_let!(let Poll::Pending = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+        }
+    }
+
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&received[..], &expected[..100]);
+    let mut pop_future = engine.tcp_pop_exact(server_fd, 92);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&received[..], &expected[100..]);
+    let mut pop_future = engine.tcp_pop_exact(server_fd, 1);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+
+    // Lengths that could never fit in the receive window fail right away.
+    let mut pop_future = engine.tcp_pop_exact(server_fd, 1 << 20);
+    must_let!(let Poll::Ready(Err(Fail::Invalid { .. })) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+}
+
+//=============================================================================
+
+/// Tests that bounded pops split a segment, leaving the rest for the next pop.
+#[test]
+fn test_pop_max() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let (_, server_fd, client_fd) = loopback_connection(&mut engine);
+
+    let mut pop_future = engine.tcp_pop_max(server_fd, 30);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    let buf: Bytes = cook_buffer(100, Some(1));
+    let mut push_future = engine.tcp_push(client_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    for _ in 0..8 {
+        engine.rt().poll_scheduler();
+    }
+
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&received[..], &buf[..30]);
+    let mut pop_future = engine.tcp_pop_max(server_fd, 1000);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&received[..], &buf[30..]);
+    let mut pop_future = engine.tcp_pop_max(server_fd, 1000);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+
+    let mut pop_future = engine.tcp_pop_max(server_fd, 0);
+    must_let!(let Poll::Ready(Err(Fail::Invalid { .. })) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+}
+
+//=============================================================================
+
+/// Tests that vectored pushes send their buffers as one write, with no segment of its own for a
+/// small header.
+#[test]
+fn test_pushv() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let (_, server_fd, client_fd) = loopback_connection(&mut engine);
+
+    let header: Bytes = cook_buffer(8, Some(1));
+    let payload: Bytes = cook_buffer(3000, Some(2));
+    let mut expected: Vec<u8> = header.to_vec();
+    expected.extend_from_slice(&payload[..]);
+    let mut push_future = engine.tcp_pushv(client_fd, &[header.clone(), payload]);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    for _ in 0..8 {
+        engine.rt().poll_scheduler();
+    }
+
+    // The header shares its segment with the start of the payload.
+    let mut pop_future = engine.tcp_pop(server_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert!(received.len() > header.len());
+    assert_eq!(&received[..], &expected[..received.len()]);
+    let mut pop_future = engine.tcp_pop_exact(server_fd, expected.len() - received.len());
+    must_let!(let Poll::Ready(Ok(rest)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&rest[..], &expected[received.len()..]);
+    assert_eq!(engine.tcp_info(client_fd).unwrap().bytes_out, expected.len() as u64);
+}
+
+//=============================================================================
+
+/// Tests that peeks return the data across segments without taking it off the receive queue, and
+/// wait for it alongside pops.
+#[test]
+fn test_peek() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let (_, server_fd, client_fd) = loopback_connection(&mut engine);
+
+    let mut peek_future = engine.tcp_peek(server_fd);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut peek_future), &mut ctx));
+
+    let mut expected: Vec<u8> = Vec::new();
+    for stamp in 0..2 {
+        let buf: Bytes = cook_buffer(64, Some(stamp));
+        expected.extend_from_slice(&buf[..]);
+        let mut push_future = engine.tcp_push(client_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        for _ in 0..8 {
+            engine.rt().poll_scheduler();
+        }
+    }
+
+    // Peeking twice sees the same bytes, which the pops that follow still get.
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut peek_future), &mut ctx));
+    assert_eq!(&received[..], &expected[..]);
+    let mut peek_future = engine.tcp_peek(server_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut peek_future), &mut ctx));
+    assert_eq!(&received[..], &expected[..]);
+    let mut pop_future = engine.tcp_pop_exact(server_fd, expected.len());
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&received[..], &expected[..]);
+
+    let mut peek_future = engine.tcp_peek(server_fd);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut peek_future), &mut ctx));
+
+    // A peek and a pop waiting together both get woken once data arrives.
+    let peek_wakes = Arc::new(WakeCount(AtomicUsize::new(0)));
+    let peek_waker = futures::task::waker(peek_wakes.clone());
+    let mut peek_ctx = Context::from_waker(&peek_waker);
+    let mut peek_future = engine.tcp_peek(server_fd);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut peek_future), &mut peek_ctx));
+    let pop_wakes = Arc::new(WakeCount(AtomicUsize::new(0)));
+    let pop_waker = futures::task::waker(pop_wakes.clone());
+    let mut pop_ctx = Context::from_waker(&pop_waker);
+    let mut pop_future = engine.tcp_pop(server_fd);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut pop_future), &mut pop_ctx));
+
+    // Peeks copy no more than a full-sized segment out of smaller ones.
+    let mss = engine.tcp_info(server_fd).unwrap().mss;
+    let mut expected: Vec<u8> = Vec::new();
+    for (size, stamp) in [(100, 2), (mss, 3)].iter() {
+        let buf: Bytes = cook_buffer(*size, Some(*stamp));
+        expected.extend_from_slice(&buf[..]);
+        let mut push_future = engine.tcp_push(client_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        for _ in 0..8 {
+            engine.rt().poll_scheduler();
+        }
+    }
+    assert_eq!(peek_wakes.0.load(Ordering::SeqCst), 1);
+    assert_eq!(pop_wakes.0.load(Ordering::SeqCst), 1);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut peek_future), &mut ctx));
+    assert_eq!(&received[..], &expected[..mss]);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&received[..], &expected[..100]);
+}
+
+//=============================================================================
+
+/// Tests that a socket can use a congestion control other than the one of the peer's options.
+#[test]
+fn test_congestion_ctrl_per_socket() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let (_, server_fd, client_fd) = loopback_connection_with(&mut engine, |engine, client_fd| {
+        engine
+            .tcp_set_congestion_ctrl(client_fd, tcp::cc::Bbr::new)
+            .unwrap();
+    });
+
+    // Only connections that haven't started yet can switch.
+    must_let!(let Err(Fail::Malformed { .. }) = engine.tcp_set_congestion_ctrl(client_fd, tcp::cc::Cubic::new));
+
+    // Once some data got delivered, the client paces while the server doesn't.
+    let buf: Bytes = cook_buffer(64, None);
+    let mut push_future = engine.tcp_push(client_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    for _ in 0..8 {
+        now += Duration::from_millis(10);
+        engine.rt().advance_clock(now);
+        engine.rt().poll_scheduler();
+    }
+    assert!(engine.tcp_pacing_rate(client_fd).unwrap().unwrap() > 0);
+    assert_eq!(engine.tcp_pacing_rate(server_fd).unwrap(), None);
+}
+
+//=============================================================================
+
+/// Tests that the receive buffer of a socket bounds the window its connection advertises, and that
+/// the send buffer of a socket bounds the data queued on its connection.
+#[test]
+fn test_buffer_sizes_per_socket() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let (_, server_fd, client_fd) = loopback_connection_with(&mut engine, |engine, client_fd| {
+        engine.tcp_set_receive_buffer_size(client_fd, 1000).unwrap();
+    });
+
+    // The window scale went out already, so the receive buffer can't change anymore.
+    must_let!(let Err(Fail::Malformed { .. }) = engine.tcp_set_receive_buffer_size(client_fd, 2000));
+
+    // A push on an empty queue goes through however large, but the next one doesn't fit.
+    engine.tcp_set_send_buffer_size(server_fd, 1000).unwrap();
+    let mut push_future = engine.tcp_push(server_fd, cook_buffer(1500, None));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    let mut push_future = engine.tcp_push(server_fd, cook_buffer(1, None));
+    must_let!(let Poll::Ready(Err(Fail::ResourceBusy { .. })) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    for _ in 0..8 {
+        engine.rt().poll_scheduler();
+    }
+
+    // The server sends no more than the receive buffer of the client takes.
+    let mut received = 0;
+    loop {
+        let mut pop_future = engine.tcp_pop(client_fd);
+        match Future::poll(Pin::new(&mut pop_future), &mut ctx) {
+            Poll::Ready(Ok(buf)) => received += buf.len(),
+            Poll::Ready(Err(e)) => panic!("pop failed: {:?}", e),
+            Poll::Pending => break,
+        }
+    }
+    assert_eq!(received, 1000);
+}
+
+//=============================================================================
+
+/// Tests that readiness registrations wake the application once data arrives and once the send
+/// buffer frees up.
+#[test]
+fn test_readiness() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let (_, server_fd, client_fd) = loopback_connection(&mut engine);
+
+    // A fresh connection can be written to but has nothing to read.
+    let wakes = Arc::new(WakeCount(AtomicUsize::new(0)));
+    let waker = futures::task::waker(wakes.clone());
+    let mut ready_ctx = Context::from_waker(&waker);
+    must_let!(let Poll::Ready(Ok(ready)) = engine.tcp_poll_ready(server_fd, tcp::Readiness::ALL, &mut ready_ctx));
+    assert_eq!(ready, tcp::Readiness::WRITABLE);
+    must_let!(let Poll::Pending = engine.tcp_poll_ready(server_fd, tcp::Readiness::READABLE, &mut ready_ctx));
+
+    // Data arriving wakes the registration up.
+    let mut push_future = engine.tcp_push(client_fd, cook_buffer(64, None));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    for _ in 0..8 {
+        engine.rt().poll_scheduler();
+    }
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+    must_let!(let Poll::Ready(Ok(ready)) = engine.tcp_poll_ready(server_fd, tcp::Readiness::READABLE, &mut ready_ctx));
+    assert_eq!(ready, tcp::Readiness::READABLE);
+    let mut pop_future = engine.tcp_pop(server_fd);
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    must_let!(let Poll::Pending = engine.tcp_poll_ready(server_fd, tcp::Readiness::READABLE, &mut ready_ctx));
+
+    // A full send buffer isn't writable until the remote acknowledges enough of it.
+    engine.tcp_set_send_buffer_size(server_fd, 1000).unwrap();
+    let mut push_future = engine.tcp_push(server_fd, cook_buffer(1500, None));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    must_let!(let Poll::Pending = engine.tcp_poll_ready(server_fd, tcp::Readiness::WRITABLE, &mut ready_ctx));
+    for _ in 0..20 {
+        now += Duration::from_millis(10);
+        engine.rt().advance_clock(now);
+        engine.rt().poll_scheduler();
+    }
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 2);
+    must_let!(let Poll::Ready(Ok(ready)) = engine.tcp_poll_ready(server_fd, tcp::Readiness::WRITABLE, &mut ready_ctx));
+    assert_eq!(ready, tcp::Readiness::WRITABLE);
+}
+
+/// Tests that a connection whose unsent queue is full isn't writable, so that an application
+/// pushing on readiness doesn't get pushes refused, until the queue drains.
+#[test]
+fn test_readiness_unsent_queue() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let options = engine.tcp_options().set_max_unsent_segments(2);
+    engine.set_tcp_options(options);
+    let (_, server_fd, client_fd) = loopback_connection(&mut engine);
+    let mss = engine.tcp_info(client_fd).unwrap().mss;
+
+    // Pushes larger than a segment wait in the unsent queue for the background sender.
+    let wakes = Arc::new(WakeCount(AtomicUsize::new(0)));
+    let waker = futures::task::waker(wakes.clone());
+    let mut ready_ctx = Context::from_waker(&waker);
+    for _ in 0..3 {
+        must_let!(let Poll::Ready(Ok(ready)) = engine.tcp_poll_ready(client_fd, tcp::Readiness::WRITABLE, &mut ready_ctx));
+        assert_eq!(ready, tcp::Readiness::WRITABLE);
+        let mut push_future = engine.tcp_push(client_fd, cook_buffer(2 * mss, None));
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    }
+    must_let!(let Poll::Pending = engine.tcp_poll_ready(client_fd, tcp::Readiness::WRITABLE, &mut ready_ctx));
+    let mut push_future = engine.tcp_push(client_fd, cook_buffer(2 * mss, None));
+    must_let!(let Poll::Ready(Err(Fail::ResourceBusy { .. })) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+
+    // The segments going out make room again.
+    for _ in 0..20 {
+        now += Duration::from_millis(10);
+        engine.rt().advance_clock(now);
+        engine.rt().poll_scheduler();
+    }
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+    must_let!(let Poll::Ready(Ok(ready)) = engine.tcp_poll_ready(client_fd, tcp::Readiness::WRITABLE, &mut ready_ctx));
+    assert_eq!(ready, tcp::Readiness::WRITABLE);
+    let mut pop_future = engine.tcp_pop_exact(server_fd, 6 * mss);
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+}
+
+//=============================================================================
+
+/// Tests that an ACK frequency too large to ever be reached doesn't overflow the number of bytes
+/// received that sends an ACK at once.
+#[test]
+fn test_ack_frequency_unreachable() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let options = engine.tcp_options().set_ack_frequency(usize::MAX);
+    engine.set_tcp_options(options);
+    let (_, server_fd, client_fd) = loopback_connection(&mut engine);
+    let mss = engine.tcp_info(client_fd).unwrap().mss;
+
+    let mut push_future = engine.tcp_push(client_fd, cook_buffer(4 * mss, None));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    for _ in 0..20 {
+        now += Duration::from_millis(10);
+        engine.rt().advance_clock(now);
+        engine.rt().poll_scheduler();
+    }
+    let mut pop_future = engine.tcp_pop_exact(server_fd, 4 * mss);
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+}
+
+//=============================================================================
+
+/// Tests that connection info reflects the congestion control, round trips, and queues of a
+/// connection.
+#[test]
+fn test_tcp_info() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let (listen_fd, server_fd, client_fd) =
+        loopback_connection_with(&mut engine, |engine, client_fd| {
+            engine
+                .tcp_set_congestion_ctrl(client_fd, tcp::cc::NewReno::new)
+                .unwrap();
+        });
+    must_let!(let Err(Fail::Malformed { .. }) = engine.tcp_info(listen_fd));
+
+    let buf: Bytes = cook_buffer(64, None);
+    let mut push_future = engine.tcp_push(client_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    let info = engine.tcp_info(client_fd).unwrap();
+    assert_eq!(info.state, State::Established);
+    assert_eq!(info.remote, listen_addr);
+    assert_eq!(info.bytes_in_flight, 64);
+    assert_eq!(info.bytes_out, 64);
+    assert_eq!(info.srtt, None);
+
+    for _ in 0..8 {
+        now += Duration::from_millis(10);
+        engine.rt().advance_clock(now);
+        engine.rt().poll_scheduler();
+    }
+
+    // The data got acknowledged, but waits for the server to pop it.
+    let info = engine.tcp_info(client_fd).unwrap();
+    assert_eq!(info.bytes_in_flight, 0);
+    assert!(info.cwnd < u32::MAX);
+    assert_eq!(info.ssthresh, u32::MAX);
+    assert!(info.srtt.is_some());
+    assert!(info.rttvar.is_some());
+    assert_eq!(info.retransmits, 0);
+    let info = engine.tcp_info(server_fd).unwrap();
+    assert_eq!(info.recv_queue, 64);
+    assert_eq!(info.bytes_in, 64);
+    assert_eq!(info.cwnd, u32::MAX);
+}
+
+//=============================================================================
+
+/// Tests that turning Nagle's algorithm on for a socket holds small pushes back until the data in
+/// flight is acknowledged, and then sends them in one segment.
+#[test]
+fn test_nodelay() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let (_, server_fd, client_fd) = loopback_connection(&mut engine);
+    engine.tcp_set_nodelay(client_fd, false).unwrap();
+
+    // Only the first push goes out, the others waiting for its delayed ACK.
+    let out_segs: u64 = engine.stats().tcp.out_segs;
+    let mut expected: Vec<u8> = Vec::new();
+    for stamp in 0..3 {
+        let buf: Bytes = cook_buffer(64, Some(stamp));
+        expected.extend_from_slice(&buf[..]);
+        let mut push_future = engine.tcp_push(client_fd, buf);
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    }
+    for _ in 0..8 {
+        engine.rt().poll_scheduler();
+    }
+    assert_eq!(engine.stats().tcp.out_segs, out_segs + 1);
+
+    // The ACK releases them in a single segment.
+    now += Duration::from_millis(10);
+    engine.rt().advance_clock(now);
+    for _ in 0..8 {
+        engine.rt().poll_scheduler();
+    }
+    assert_eq!(engine.stats().tcp.out_segs, out_segs + 3);
+    let mut pop_future = engine.tcp_pop_exact(server_fd, expected.len());
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&received[..], &expected[..]);
+}
+
+//=============================================================================
+
+/// Tests that paced connections spread the segments of a large push over time rather than send
+/// them all at once.
+#[test]
+fn test_pacing() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let options = engine.tcp_options().set_pacing(true);
+    engine.set_tcp_options(options);
+    let (_, server_fd, client_fd) = loopback_connection_with(&mut engine, |engine, client_fd| {
+        engine
+            .tcp_set_congestion_ctrl(client_fd, tcp::cc::NewReno::new)
+            .unwrap();
+    });
+    let mss = engine.tcp_info(client_fd).unwrap().mss;
+
+    // Without a round trip sampled yet, there is no rate to pace at.
+    let mut push_future = engine.tcp_push(client_fd, cook_buffer(mss, None));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    for _ in 0..8 {
+        now += Duration::from_millis(10);
+        engine.rt().advance_clock(now);
+        engine.rt().poll_scheduler();
+    }
+    assert!(engine.tcp_info(client_fd).unwrap().srtt.is_some());
+
+    // Only the first segment goes out until time passes.
+    let out_segs: u64 = engine.stats().tcp.out_segs;
+    let mut push_future = engine.tcp_push(client_fd, cook_buffer(3 * mss, None));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    for _ in 0..8 {
+        engine.rt().poll_scheduler();
+    }
+    assert_eq!(engine.stats().tcp.out_segs, out_segs + 1);
+
+    for _ in 0..8 {
+        now += Duration::from_millis(10);
+        engine.rt().advance_clock(now);
+        engine.rt().poll_scheduler();
+    }
+    assert_eq!(engine.tcp_info(server_fd).unwrap().bytes_in, 4 * mss as u64);
+}
+
+//=============================================================================
+
+/// Tests that a connection whose client shut down writing still carries the response of the
+/// server, and that shutting down reading ends pops even as data keeps arriving.
+#[test]
+fn test_half_close() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let (listen_fd, server_fd, client_fd) = loopback_connection(&mut engine);
+
+    // The client sends its request and shuts down writing.
+    let request: Bytes = cook_buffer(64, Some(1));
+    let mut push_future = engine.tcp_push(client_fd, request.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    engine.tcp_shutdown(client_fd, Shutdown::Write).unwrap();
+    let mut push_future = engine.tcp_push(client_fd, cook_buffer(64, None));
+    must_let!(let Poll::Ready(Err(Fail::Ignored { .. })) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    for _ in 0..8 {
+        now += Duration::from_millis(10);
+        engine.rt().advance_clock(now);
+        engine.rt().poll_scheduler();
+    }
+
+    // The server reads the request up to the FIN, then responds.
+    let mut pop_future = engine.tcp_pop(server_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&received[..], &request[..]);
+    let mut pop_future = engine.tcp_pop(server_fd);
+    must_let!(let Poll::Ready(Err(Fail::ResourceNotFound { .. })) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    let response: Bytes = cook_buffer(64, Some(2));
+    let mut push_future = engine.tcp_push(server_fd, response.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    for _ in 0..8 {
+        now += Duration::from_millis(10);
+        engine.rt().advance_clock(now);
+        engine.rt().poll_scheduler();
+    }
+    let mut pop_future = engine.tcp_pop(client_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&received[..], &response[..]);
+
+    // Once the client shuts down reading, what the server sends is acknowledged but dropped.
+    engine.tcp_shutdown(client_fd, Shutdown::Read).unwrap();
+    let mut push_future = engine.tcp_push(server_fd, cook_buffer(64, Some(3)));
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    let retrans_segs: u64 = engine.stats().tcp.retrans_segs;
+    for _ in 0..8 {
+        now += Duration::from_millis(100);
+        engine.rt().advance_clock(now);
+        engine.rt().poll_scheduler();
+    }
+    assert_eq!(engine.stats().tcp.retrans_segs, retrans_segs);
+    let mut pop_future = engine.tcp_pop(client_fd);
+    must_let!(let Poll::Ready(Err(Fail::ResourceNotFound { .. })) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+
+    // Shutting down needs an established connection.
+    must_let!(let Err(Fail::Malformed { .. }) = engine.tcp_shutdown(listen_fd, Shutdown::Both));
+}
+
+//=============================================================================
+
+/// Tests that listening sockets sharing a port each get the connections their flow hash picks.
+#[test]
+fn test_reuse_port() {
+    let now = Instant::now();
+    let mut engine: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let mut listen_fds: Vec<FileDescriptor> = Vec::new();
+    for _ in 0..2 {
+        let fd: FileDescriptor = engine.tcp_socket().unwrap();
+        engine.tcp_set_reuse_port(fd, true).unwrap();
+        engine.tcp_bind(fd, listen_addr).unwrap();
+        engine.tcp_listen(fd, 16).unwrap();
+        listen_fds.push(fd);
+    }
+
+    // Sockets without the option can't join the group.
+    let fd: FileDescriptor = engine.tcp_socket().unwrap();
+    engine.tcp_bind(fd, listen_addr).unwrap();
+    must_let!(let Err(Fail::ResourceBusy { .. }) = engine.tcp_listen(fd, 16));
+
+    let mut connect_futures = Vec::new();
+    for _ in 0..8 {
+        let client_fd: FileDescriptor = engine.tcp_socket().unwrap();
+        connect_futures.push(engine.tcp_connect(client_fd, listen_addr));
+    }
+    for _ in 0..8 {
+        engine.rt().poll_scheduler();
+    }
+
+    let connections = engine.connections();
+    let mut expected: Vec<usize> = vec![0; listen_fds.len()];
+    for info in connections.iter() {
+        if matches!(info.state, SocketState::Established(_)) && info.remote == Some(listen_addr) {
+            let hash = tcp::flow_hash(&listen_addr, &info.local.unwrap());
+            expected[hash as usize % listen_fds.len()] += 1;
+        }
+    }
+    assert_eq!(expected.iter().sum::<usize>(), 8);
+    for (fd, count) in listen_fds.iter().zip(expected) {
+        let info = connections.iter().find(|info| info.fd == *fd).unwrap();
+        assert_eq!(info.recv_queue, count);
+    }
+}
+
+//=============================================================================
+
+/// Tests that subscribers see every state transition of a connection, in order.
+#[test]
+fn test_state_transitions() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers.
+    let mut server: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let mut client: Engine<TestRuntime> = test_helpers::new_alice2(now);
+    let server_log: Rc<RefCell<Vec<StateTransition>>> = Rc::new(RefCell::new(Vec::new()));
+    let client_log: Rc<RefCell<Vec<StateTransition>>> = Rc::new(RefCell::new(Vec::new()));
+    let log = server_log.clone();
+    server.tcp_subscribe(move |t| log.borrow_mut().push(*t));
+    let log = client_log.clone();
+    client.tcp_subscribe(move |t| log.borrow_mut().push(*t));
+
+    let (server_fd, client_fd): (FileDescriptor, FileDescriptor) = connection_setup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        listen_port,
+        listen_addr,
+    );
+    assert!(server_log.borrow().is_empty());
+    assert!(client_log.borrow().is_empty());
+
+    connection_hangup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        server_fd,
+        client_fd,
+    );
+
+    let client_log = client_log.borrow();
+    let states: Vec<(State, State)> = client_log.iter().map(|t| (t.from, t.to)).collect();
+    assert_eq!(
+        states,
+        vec![
+            (State::Established, State::ActiveClose),
+            (State::ActiveClose, State::FinWait1),
+            (State::FinWait1, State::FinWait2),
+            (State::FinWait2, State::FinWait3),
+            (State::FinWait3, State::TimeWait2),
+        ]
+    );
+    assert_eq!(client_log[0].trigger, Event::Close);
+    assert_eq!(client_log[1].trigger, Event::FinSent);
+    assert_eq!(client_log[4].trigger, Event::FinAcked);
+    assert!(client_log.iter().all(|t| t.remote == listen_addr));
+    assert!(client_log.windows(2).all(|w| w[0].at <= w[1].at));
+
+    let server_log = server_log.borrow();
+    let states: Vec<(State, State)> = server_log.iter().map(|t| (t.from, t.to)).collect();
+    assert_eq!(
+        states,
+        vec![
+            (State::Established, State::PassiveClose),
+            (State::PassiveClose, State::CloseWait1),
+            (State::CloseWait1, State::CloseWait2),
+            (State::CloseWait2, State::LastAck),
+            (State::LastAck, State::Closed),
+        ]
+    );
+    assert!(server_log.iter().all(|t| t.local == listen_addr));
+    assert!(server_log.windows(2).all(|w| w[0].at <= w[1].at));
+}
+
+//=============================================================================
+
+/// Tests that each side reports a single summary of a connection when it is torn down.
+#[test]
+fn test_close_summaries() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers.
+    let mut server: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let mut client: Engine<TestRuntime> = test_helpers::new_alice2(now);
+    let window_size: u16 = client.rt().tcp_options().receive_window_size();
+    let server_log: Rc<RefCell<Vec<CloseSummary>>> = Rc::new(RefCell::new(Vec::new()));
+    let client_log: Rc<RefCell<Vec<CloseSummary>>> = Rc::new(RefCell::new(Vec::new()));
+    let log = server_log.clone();
+    server.tcp_subscribe_close(move |s| log.borrow_mut().push(*s));
+    let log = client_log.clone();
+    client.tcp_subscribe_close(move |s| log.borrow_mut().push(*s));
+
+    let (server_fd, client_fd): (FileDescriptor, FileDescriptor) = connection_setup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        listen_port,
+        listen_addr,
+    );
+
+    let bufsize: usize = 64;
+    send_recv(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        server_fd,
+        client_fd,
+        window_size,
+        Wrapping(1),
+        cook_buffer(bufsize, None),
+    );
+    assert!(server_log.borrow().is_empty());
+    assert!(client_log.borrow().is_empty());
+
+    connection_hangup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        server_fd,
+        client_fd,
+    );
+
+    let client_log = client_log.borrow();
+    assert_eq!(client_log.len(), 1);
+    assert_eq!(client_log[0].reason, CloseReason::ActiveClose);
+    assert_eq!(client_log[0].remote, listen_addr);
+    assert_eq!(client_log[0].bytes_out, bufsize as u64);
+    assert_eq!(client_log[0].bytes_in, 0);
+    assert_eq!(client_log[0].retransmits, 0);
+
+    let server_log = server_log.borrow();
+    assert_eq!(server_log.len(), 1);
+    assert_eq!(server_log[0].reason, CloseReason::PassiveClose);
+    assert_eq!(server_log[0].local, listen_addr);
+    assert_eq!(server_log[0].bytes_in, bufsize as u64);
+    assert_eq!(server_log[0].bytes_out, 0);
+    assert!(server_log[0].to_string().ends_with("reason=passive_close"));
+}
+
+//=============================================================================
+
+/// Tests that the connection table reports endpoints, states and queue depths.
+#[test]
+fn test_connections() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers.
+    let mut server: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let mut client: Engine<TestRuntime> = test_helpers::new_alice2(now);
+
+    let (server_fd, client_fd): (FileDescriptor, FileDescriptor) = connection_setup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        listen_port,
+        listen_addr,
+    );
+
+    // Push data that the server receives but does not pop.
+    let mut push_future: PushFuture<TestRuntime> =
+        client.tcp_push(client_fd, cook_buffer(32, None));
+    let bytes: Bytes = client.rt().pop_frame();
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    server.receive(bytes).unwrap();
+
+    let connections = client.connections();
+    assert_eq!(connections.len(), 1);
+    let connection = connections[0];
+    assert_eq!(connection.fd, client_fd);
+    assert_eq!(connection.protocol, Protocol::Tcp);
+    assert_eq!(connection.remote, Some(listen_addr));
+    assert_eq!(
+        connection.state,
+        SocketState::Established(State::Established)
+    );
+    assert_eq!(connection.send_queue, 32);
+    assert_eq!(connection.recv_queue, 0);
+    assert!(connection.age > Duration::from_secs(0));
+
+    let connections = server.connections();
+    assert_eq!(connections.len(), 2);
+    let listening = connections
+        .iter()
+        .find(|c| c.state == SocketState::Listening)
+        .unwrap();
+    assert_eq!(listening.local, Some(listen_addr));
+    assert_eq!(listening.remote, None);
+    assert_eq!(listening.recv_queue, 0);
+    let connection = connections.iter().find(|c| c.fd == server_fd).unwrap();
+    assert_eq!(connection.local, Some(listen_addr));
+    assert_eq!(connection.send_queue, 0);
+    assert_eq!(connection.recv_queue, 32);
+}
+
+//=============================================================================
+
+/// Tests that an established connection keeps working after being moved to another engine.
+#[test]
+fn test_connection_migration() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers.
+    let mut server: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let mut client: Engine<TestRuntime> = test_helpers::new_alice2(now);
+
+    let (server_fd, client_fd): (FileDescriptor, FileDescriptor) = connection_setup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        listen_port,
+        listen_addr,
+    );
+
+    // Leave some data in the receive queue of the server.
+    let buf: Bytes = cook_buffer(32, None);
+    let mut push_future: PushFuture<TestRuntime> = client.tcp_push(client_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    server.receive(client.rt().pop_frame()).unwrap();
+
+    // Move the connection to a fresh engine.
+    let state = server.tcp_export_connection(server_fd).unwrap();
+    assert_eq!(state.receiver_queue, vec![buf.to_vec()]);
+    assert!(state.ts_value.is_some());
+    let mut new_server: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let new_server_fd: FileDescriptor = new_server.tcp_import_connection(state).unwrap();
+
+    // Data queued on the old engine is available on the new one.
+    let mut pop_future = new_server.tcp_pop(new_server_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, buf);
+
+    // The new engine can send data over the migrated connection.
+    let mut push_future: PushFuture<TestRuntime> = new_server.tcp_push(new_server_fd, buf.clone());
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    client.receive(new_server.rt().pop_frame()).unwrap();
+    let mut pop_future = client.tcp_pop(client_fd);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(received, buf);
+}
+
+//=============================================================================
+
+/// Tests delivering a burst of segments in a single call. The receiver should acknowledge all of
+/// them at once.
+#[test]
+pub fn test_receive_batch() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers.
+    let mut server: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let mut client: Engine<TestRuntime> = test_helpers::new_alice2(now);
+    let window_scale: u8 = client.rt().tcp_options().window_scale();
+    let max_window_size: u32 = (client.rt().tcp_options().receive_window_size() as u32)
+        .checked_shl(window_scale as u32)
+        .unwrap();
+
+    let (server_fd, client_fd): (FileDescriptor, FileDescriptor) = connection_setup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        listen_port,
+        listen_addr,
+    );
+
+    // Client sends a burst of segments, with a garbage frame in the middle of it.
+    let nsegments: u32 = 3;
+    let bufsize: u32 = 64;
+    let buf: Bytes = cook_buffer(bufsize as usize, None);
+    let mut frames: Vec<Bytes> = Vec::new();
+    for i in 0..nsegments {
+        let mut push_future: PushFuture<TestRuntime> = client.tcp_push(client_fd, buf.clone());
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        frames.push(client.rt().pop_frame());
+        if i == 0 {
+            frames.push(cook_buffer(8, None));
+        }
+    }
+
+    // Server receives the whole burst at once.
+    assert_eq!(server.receive_batch(frames), nsegments as usize);
+    for _ in 0..nsegments {
+        let mut pop_future = server.tcp_pop(server_fd);
+        must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    }
+
+    // A single ACK covers the burst.
+    advance_clock(Some(&mut server), Some(&mut client), &mut now);
+    server.rt().poll_scheduler();
+    check_packet_pure_ack(
+        server.rt().pop_frame(),
+        server.rt().local_link_addr(),
+        client.rt().local_link_addr(),
+        server.rt().local_ipv4_addr(),
+        client.rt().local_ipv4_addr(),
+        max_window_size as u16,
+        Wrapping(1 + nsegments * bufsize),
+    );
+    assert!(server.rt().pop_frame_unchecked().is_none());
+}
+
+//=============================================================================
+
+/// Tests that pure ACKs of the same connection received in a burst are processed as the latest.
+#[test]
+pub fn test_receive_batch_acks() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers.
+    let mut server: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let mut client: Engine<TestRuntime> = test_helpers::new_alice2(now);
+
+    let (server_fd, client_fd): (FileDescriptor, FileDescriptor) = connection_setup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        listen_port,
+        listen_addr,
+    );
+
+    // Server sends segments that the client acknowledges one by one. Only the clock of the client
+    // moves, so that the server doesn't retransmit.
+    let nsegments: usize = 3;
+    let buf: Bytes = cook_buffer(64, None);
+    let mut acks: Vec<Bytes> = Vec::new();
+    for _ in 0..nsegments {
+        let mut push_future: PushFuture<TestRuntime> = server.tcp_push(server_fd, buf.clone());
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+        client.receive(server.rt().pop_frame()).unwrap();
+        let mut pop_future = client.tcp_pop(client_fd);
+        must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+        now += Duration::from_secs(1);
+        client.rt().advance_clock(now);
+        client.rt().poll_scheduler();
+        acks.push(client.rt().pop_frame());
+    }
+
+    // Server receives the ACKs at once and only processes the last one, which covers all data.
+    assert_eq!(server.receive_batch(acks), nsegments);
+    assert_eq!(server.stats().tcp.coalesced_acks, nsegments as u64 - 1);
+    let socket = server
+        .connections()
+        .into_iter()
+        .find(|s| s.fd == server_fd)
+        .unwrap();
+    assert_eq!(socket.send_queue, 0);
+}
+
+//=============================================================================
+
+/// Tests that a segment lost on the link is retransmitted once the RTO expires.
+#[test]
+pub fn test_retransmit_on_loss() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers.
+    let mut server: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let mut client: Engine<TestRuntime> = test_helpers::new_alice2(now);
+    let window_scale: u8 = client.rt().tcp_options().window_scale();
+    let max_window_size: u32 = (client.rt().tcp_options().receive_window_size() as u32)
+        .checked_shl(window_scale as u32)
+        .unwrap();
+
+    let (server_fd, client_fd): (FileDescriptor, FileDescriptor) = connection_setup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        listen_port,
+        listen_addr,
+    );
+
+    // The link drops the first transmission.
+    client.rt().set_link_model(LinkModel {
+        drop_probability: 1.0,
+        ..Default::default()
+    });
+    let bufsize: usize = 64;
+    let buf: Bytes = cook_buffer(bufsize, None);
+    let mut push_future: PushFuture<TestRuntime> = client.tcp_push(client_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    assert!(client.rt().pop_frame_unchecked().is_none());
+    client.rt().set_link_model(LinkModel::default());
+
+    // Wait for the retransmission.
+    let mut retransmission = None;
+    for _ in 0..16 {
+        advance_clock(Some(&mut server), Some(&mut client), &mut now);
+        client.rt().poll_scheduler();
+        retransmission = client.rt().pop_frame_unchecked();
+        if retransmission.is_some() {
+            break;
+        }
+    }
+    let bytes: Bytes = retransmission.expect("segment was not retransmitted");
+    let nbytes: usize = check_packet_data(
+        bytes.clone(),
+        client.rt().local_link_addr(),
+        server.rt().local_link_addr(),
+        client.rt().local_ipv4_addr(),
+        server.rt().local_ipv4_addr(),
+        max_window_size as u16,
+        Wrapping(1),
+        None,
+    );
+    assert_eq!(nbytes, bufsize);
+    recv_data(&mut ctx, &mut server, &mut client, server_fd, bytes);
+}
+
+//=============================================================================
+
+/// Tests that frames are held on the link for the configured latency.
+#[test]
+pub fn test_link_latency() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers.
+    let mut server: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let mut client: Engine<TestRuntime> = test_helpers::new_alice2(now);
+
+    let (server_fd, client_fd): (FileDescriptor, FileDescriptor) = connection_setup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        listen_port,
+        listen_addr,
+    );
+
+    let latency: Duration = Duration::from_millis(50);
+    let jitter: Duration = Duration::from_millis(10);
+    client.rt().set_link_model(LinkModel {
+        latency,
+        jitter,
+        ..Default::default()
+    });
+    let buf: Bytes = cook_buffer(64, None);
+    let mut push_future: PushFuture<TestRuntime> = client.tcp_push(client_fd, buf);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+
+    // The segment is still in flight.
+    let due: Instant = client.rt().next_delivery().unwrap();
+    assert!(due >= now + latency && due <= now + latency + jitter);
+    assert!(client.rt().pop_frame_unchecked().is_none());
+
+    // The segment arrives once the clock reaches its delivery time.
+    now = due;
+    client.rt().advance_clock(now);
+    server.rt().advance_clock(now);
+    let bytes: Bytes = client.rt().pop_frame();
+    recv_data(&mut ctx, &mut server, &mut client, server_fd, bytes);
+}
+
+//=============================================================================
+
+/// Tests that a bandwidth-limited link spaces out frames and tail drops the ones that do not fit
+/// in its queue.
+#[test]
+pub fn test_link_bandwidth() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers.
+    let mut server: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let mut client: Engine<TestRuntime> = test_helpers::new_alice2(now);
+
+    let (_, client_fd): (FileDescriptor, FileDescriptor) = connection_setup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        listen_port,
+        listen_addr,
+    );
+
+    client.rt().set_link_model(LinkModel {
+        rate: 10_000,
+        queue_limit: 2,
+        ..Default::default()
+    });
+    let buf: Bytes = cook_buffer(64, None);
+    for _ in 0..4 {
+        let mut push_future: PushFuture<TestRuntime> = client.tcp_push(client_fd, buf.clone());
+        must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut push_future), &mut ctx));
+    }
+
+    // Frames come out one transmission time apart.
+    let first: Instant = client.rt().next_delivery().unwrap();
+    let transmission_time: Duration = first - now;
+    assert!(transmission_time > Duration::ZERO);
+    assert!(client.rt().pop_frame_unchecked().is_none());
+    client.rt().advance_clock(first);
+    client.rt().pop_frame();
+
+    let second: Instant = client.rt().next_delivery().unwrap();
+    assert_eq!(second - first, transmission_time);
+    client.rt().advance_clock(second);
+    client.rt().pop_frame();
+
+    // The remaining frames were dropped.
+    assert!(client.rt().next_delivery().is_none());
+}
+
+//=============================================================================
+
+/// Tests that peers whose clocks are offset and drift apart still exchange data and close the
+/// connection.
+#[test]
+pub fn test_clock_skew() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let start: Instant = Instant::now();
+    let mut now: Instant = start;
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers, the client running ahead and fast, the server slow.
+    let mut server: Engine<TestRuntime> = test_helpers::new_bob2(now);
+    let mut client: Engine<TestRuntime> = test_helpers::new_alice2(now);
+    let offset: Duration = Duration::from_secs(3600);
+    client.rt().set_clock_skew(ClockSkew {
+        offset,
+        drift_ppm: 100_000,
+    });
+    server.rt().set_clock_skew(ClockSkew {
+        offset: Duration::ZERO,
+        drift_ppm: -50_000,
+    });
+    assert_eq!(client.rt().now(), start + offset);
+    let window_scale: u8 = client.rt().tcp_options().window_scale();
+    let max_window_size: u32 = (client.rt().tcp_options().receive_window_size() as u32)
+        .checked_shl(window_scale as u32)
+        .unwrap();
+
+    let (server_fd, client_fd): (FileDescriptor, FileDescriptor) = connection_setup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        listen_port,
+        listen_addr,
+    );
+
+    let bufsize: u32 = 64;
+    let buf: Bytes = cook_buffer(bufsize as usize, None);
+    for i in 0..4 {
+        send_recv(
+            &mut ctx,
+            &mut now,
+            &mut server,
+            &mut client,
+            server_fd,
+            client_fd,
+            max_window_size as u16,
+            Wrapping(1 + i * bufsize),
+            buf.clone(),
+        );
+    }
+    connection_hangup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        server_fd,
+        client_fd,
+    );
+
+    // Clocks drifted apart by the configured rates.
+    let elapsed: Duration = now - start;
+    assert_eq!(client.rt().now(), now + offset + elapsed / 10);
+    assert_eq!(server.rt().now(), now - elapsed / 20);
+}
@@ -2,9 +2,19 @@
 // Licensed under the MIT license.
 
 pub mod established;
+pub mod model;
+pub mod script;
 pub mod setup;
+pub mod stream;
 
-use std::{net::Ipv4Addr, num::Wrapping};
+use std::{
+    net::Ipv4Addr,
+    num::Wrapping,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use crate::{
     collections::bytes::Bytes,
@@ -14,6 +24,18 @@ use crate::{
         tcp::segment::TcpHeader,
     },
 };
+use futures::task::ArcWake;
+
+//=============================================================================
+
+/// Counts the times it gets woken.
+pub struct WakeCount(pub AtomicUsize);
+
+impl ArcWake for WakeCount {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
 
 //=============================================================================
 
@@ -67,9 +89,107 @@ pub fn check_packet_pure_ack(
     assert_eq!(ipv4_header.src_addr, ipv4_src_addr);
     assert_eq!(ipv4_header.dst_addr, ipv4_dst_addr);
     let (tcp_header, tcp_payload) = TcpHeader::parse(&ipv4_header, ipv4_payload, false).unwrap();
-    assert_eq!(tcp_payload.len(), 0);
-    assert_eq!(tcp_header.window_size, window_size);
-    assert_eq!(tcp_header.seq_num, Wrapping(0));
-    assert_eq!(tcp_header.ack, true);
-    assert_eq!(tcp_header.ack_num, ack_num);
+    assert_eq!(tcp_payload.len(), 0, "{}", tcp_header);
+    assert_eq!(tcp_header.window_size, window_size, "{}", tcp_header);
+    assert_eq!(tcp_header.seq_num, Wrapping(0), "{}", tcp_header);
+    assert_eq!(tcp_header.ack, true, "{}", tcp_header);
+    assert_eq!(tcp_header.ack_num, ack_num, "{}", tcp_header);
+}
+
+//=============================================================================
+
+#[test]
+fn test_segment_display() {
+    use crate::{
+        protocols::{
+            ip,
+            ipv4::Ipv4Protocol2,
+            tcp::segment::{TcpOptions2, TcpSegment},
+        },
+        runtime::RuntimeBuf,
+    };
+    use std::convert::TryFrom;
+
+    let mut tcp_hdr = TcpHeader::new(
+        ip::Port::try_from(443).unwrap(),
+        ip::Port::try_from(5555).unwrap(),
+    );
+    tcp_hdr.syn = true;
+    tcp_hdr.ack = true;
+    tcp_hdr.ack_num = Wrapping(1);
+    tcp_hdr.window_size = 65535;
+    tcp_hdr.push_option(TcpOptions2::MaximumSegmentSize(1460));
+    tcp_hdr.push_option(TcpOptions2::WindowScale(7));
+    assert_eq!(
+        tcp_hdr.to_string(),
+        "443 > 5555: Flags [S.], seq 0, ack 1, win 65535, options [mss 1460,wscale 7]"
+    );
+
+    let segment = TcpSegment {
+        ethernet2_hdr: Ethernet2Header::new(MacAddress::nil(), MacAddress::nil(), EtherType2::Ipv4),
+        ipv4_hdr: Ipv4Header::new(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Protocol2::Tcp,
+        ),
+        tcp_hdr,
+        data: Bytes::from_slice(&[0; 4]),
+        tx_checksum_offload: false,
+        pseudo_header_sum: None,
+    };
+    assert_eq!(
+        segment.to_string(),
+        "IP 10.0.0.1.443 > 10.0.0.2.5555: Flags [S.], seq 0, ack 1, win 65535, \
+         options [mss 1460,wscale 7], length 4"
+    );
+}
+
+//=============================================================================
+
+#[test]
+fn test_pseudo_header_sum() {
+    use crate::{
+        protocols::{
+            ip,
+            ipv4::Ipv4Protocol2,
+            tcp::segment::{self, TcpSegment},
+        },
+        runtime::{PacketBuf, RuntimeBuf},
+    };
+    use std::convert::TryFrom;
+
+    let (src_addr, dst_addr) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+    let serialize = |pseudo_header_sum| {
+        let mut tcp_hdr = TcpHeader::new(
+            ip::Port::try_from(443).unwrap(),
+            ip::Port::try_from(5555).unwrap(),
+        );
+        tcp_hdr.ack = true;
+        tcp_hdr.seq_num = Wrapping(1);
+        tcp_hdr.window_size = 65535;
+        let segment = TcpSegment {
+            ethernet2_hdr: Ethernet2Header::new(
+                MacAddress::nil(),
+                MacAddress::nil(),
+                EtherType2::Ipv4,
+            ),
+            ipv4_hdr: Ipv4Header::new(src_addr, dst_addr, Ipv4Protocol2::Tcp),
+            tcp_hdr,
+            data: Bytes::from_slice(&[1, 2, 3]),
+            tx_checksum_offload: false,
+            pseudo_header_sum,
+        };
+        let mut buf = vec![0; segment.header_size()];
+        segment.write_header(&mut buf);
+        buf.extend_from_slice(&[1, 2, 3]);
+        buf
+    };
+
+    // A cached sum gives the same checksum as one computed for each segment.
+    let frame = serialize(Some(segment::pseudo_header_sum(src_addr, dst_addr)));
+    assert_eq!(frame, serialize(None));
+    let (_, eth2_payload) = Ethernet2Header::parse(Bytes::from_slice(&frame)).unwrap();
+    let (ipv4_header, ipv4_payload) = Ipv4Header::parse(eth2_payload).unwrap();
+    let (_, tcp_payload) = TcpHeader::parse(&ipv4_header, ipv4_payload, false).unwrap();
+    assert_eq!(&tcp_payload[..], &[1, 2, 3]);
 }
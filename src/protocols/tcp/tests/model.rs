@@ -0,0 +1,241 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Model checks connection teardown: two peers exchange data and close the connection while
+//! segments, acknowledgement timers and user calls interleave in every possible order.
+
+use crate::protocols::tcp::established::{
+    fsm::{self, Event},
+    State,
+};
+use stateright::{Checker, Model, Property};
+use std::collections::VecDeque;
+
+//=============================================================================
+
+/// A segment in flight.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Segment {
+    fin: bool,
+    ack: bool,
+    /// Sequence number of the data segment carried, if any.
+    data: Option<u8>,
+}
+
+/// One end of the connection.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Peer {
+    state: State,
+    /// Data segments not yet pushed.
+    unsent: u8,
+    next_seq: u8,
+    /// Data segments received so far.
+    delivered: u8,
+    /// Whether received data awaits an acknowledgement.
+    pending_ack: bool,
+    fin_received: bool,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Teardown {
+    peers: [Peer; 2],
+    /// Segments in flight towards each peer, in order.
+    links: [VecDeque<Segment>; 2],
+    out_of_order: bool,
+    lost_fin: bool,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Action {
+    /// The segment at the head of the link arrives at the peer.
+    Deliver(usize),
+    /// The peer sends a data segment.
+    Push(usize),
+    /// The application closes the peer, once all of its data has been pushed. The FIN is
+    /// actually sent by the closer on the next poll, which the model folds into the call.
+    Close(usize),
+    /// The acknowledger fires and the peer acknowledges received data.
+    AckTimer(usize),
+    /// The closer acknowledges the FIN of the other peer.
+    AckFin(usize),
+}
+
+/// Both peers start established with `data` segments to send each.
+struct TeardownModel {
+    data: u8,
+}
+
+//=============================================================================
+
+impl Teardown {
+    /// Sends `segment` from peer `i` to the other one.
+    fn send(&mut self, i: usize, segment: Segment) {
+        self.links[1 - i].push_back(segment);
+    }
+
+    fn deliver(&mut self, i: usize) {
+        let segment = match self.links[i].pop_front() {
+            Some(s) => s,
+            None => return,
+        };
+        let peer = &mut self.peers[i];
+        if fsm::is_terminal(peer.state) {
+            return;
+        }
+        if let Some(seq) = segment.data {
+            if seq != peer.delivered || peer.fin_received {
+                self.out_of_order = true;
+            }
+            peer.delivered += 1;
+            peer.pending_ack = true;
+        }
+        let event = Event::Segment {
+            rst: false,
+            fin: segment.fin,
+            ack: segment.ack,
+        };
+        let next = fsm::transition(peer.state, event).unwrap();
+        if segment.fin && !peer.fin_received {
+            if next == peer.state {
+                self.lost_fin = true;
+            }
+            peer.fin_received = true;
+        }
+        peer.state = next;
+    }
+}
+
+//=============================================================================
+
+impl Model for TeardownModel {
+    type State = Teardown;
+    type Action = Action;
+
+    fn init_states(&self) -> Vec<Teardown> {
+        let peer = Peer {
+            state: State::Established,
+            unsent: self.data,
+            next_seq: 0,
+            delivered: 0,
+            pending_ack: false,
+            fin_received: false,
+        };
+        vec![Teardown {
+            peers: [peer.clone(), peer],
+            links: [VecDeque::new(), VecDeque::new()],
+            out_of_order: false,
+            lost_fin: false,
+        }]
+    }
+
+    fn actions(&self, state: &Teardown, actions: &mut Vec<Action>) {
+        for i in 0..2 {
+            let peer = &state.peers[i];
+            if !state.links[i].is_empty() {
+                actions.push(Action::Deliver(i));
+            }
+            if fsm::is_terminal(peer.state) {
+                continue;
+            }
+            if peer.state == State::Established && peer.unsent > 0 {
+                actions.push(Action::Push(i));
+            }
+            if peer.unsent == 0 && fsm::transition(peer.state, Event::Close).is_ok() {
+                actions.push(Action::Close(i));
+            }
+            if peer.pending_ack {
+                actions.push(Action::AckTimer(i));
+            } else if fsm::transition(peer.state, Event::FinAcked).is_ok() {
+                actions.push(Action::AckFin(i));
+            }
+        }
+    }
+
+    fn next_state(&self, state: &Teardown, action: Action) -> Option<Teardown> {
+        let mut next = state.clone();
+        match action {
+            Action::Deliver(i) => next.deliver(i),
+            Action::Push(i) => {
+                let peer = &mut next.peers[i];
+                let segment = Segment {
+                    fin: false,
+                    ack: peer.pending_ack,
+                    data: Some(peer.next_seq),
+                };
+                peer.unsent -= 1;
+                peer.next_seq += 1;
+                peer.pending_ack = false;
+                next.send(i, segment);
+            }
+            Action::Close(i) => {
+                let peer = &mut next.peers[i];
+                let closing = fsm::transition(peer.state, Event::Close).ok()?;
+                peer.state = fsm::transition(closing, Event::FinSent).ok()?;
+                let segment = Segment {
+                    fin: true,
+                    ack: peer.pending_ack,
+                    data: None,
+                };
+                peer.pending_ack = false;
+                next.send(i, segment);
+            }
+            Action::AckTimer(i) => {
+                next.peers[i].pending_ack = false;
+                let segment = Segment {
+                    fin: false,
+                    ack: true,
+                    data: None,
+                };
+                next.send(i, segment);
+            }
+            Action::AckFin(i) => {
+                let peer = &mut next.peers[i];
+                peer.state = fsm::transition(peer.state, Event::FinAcked).ok()?;
+                let segment = Segment {
+                    fin: false,
+                    ack: true,
+                    data: None,
+                };
+                next.send(i, segment);
+            }
+        }
+        Some(next)
+    }
+
+    fn properties(&self) -> Vec<Property<Self>> {
+        vec![
+            Property::always("no FIN lost", |_, state: &Teardown| !state.lost_fin),
+            Property::always("data delivered in order", |_, state: &Teardown| {
+                !state.out_of_order
+            }),
+            Property::eventually("both sides finish", |_, state: &Teardown| {
+                state.peers.iter().all(|p| fsm::is_terminal(p.state))
+            }),
+            Property::sometimes("all data delivered", |model, state: &Teardown| {
+                state.peers.iter().all(|p| p.delivered == model.data)
+            }),
+        ]
+    }
+}
+
+//=============================================================================
+
+#[test]
+fn test_model_close() {
+    TeardownModel { data: 0 }
+        .checker()
+        .spawn_bfs()
+        .join()
+        .assert_properties();
+}
+
+#[test]
+fn test_model_close_with_data() {
+    for data in 1..=2 {
+        TeardownModel { data }
+            .checker()
+            .spawn_bfs()
+            .join()
+            .assert_properties();
+    }
+}
@@ -0,0 +1,1008 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Interpreter for packetdrill-style scripts that drive a single TCP socket of a test engine.
+//!
+//! Each line starts with a time in seconds, either absolute (`0.100`) or relative to the previous
+//! line (`+0.1`), followed by one of:
+//!
+//! - `< FLAGS SEQ:END(LEN) [ack N] [win N] [<OPTIONS>]` injects a segment from the remote peer;
+//! - `> FLAGS [SEQ:END(LEN)] [ack N] [win N] [<OPTIONS>]` expects the engine to have emitted
+//!   such a segment; fields left out are not checked;
+//! - `< icmp mtu N seq SEQ` injects an ICMP fragmentation needed message from a router, about
+//!   the segment the engine sent at `SEQ`, with `N` as the MTU of the next hop;
+//! - a socket call: `socket`, `bind PORT`, `listen BACKLOG`, `connect PORT [LEN]`, which sends
+//!   `LEN` bytes as the first data of the connection, `accept`, `read`, `write LEN`,
+//!   `shutdown read|write|both`, `close`, or `abort`;
+//! - `await CALL [LEN | error]`, which checks that the oldest pending `accept`, `connect`,
+//!   `read`, or `write` has completed, successfully (with `LEN` bytes, for reads) or not.
+//!
+//! Flags use the tcpdump letters `S`, `F`, `R`, `P`, `.` (ACK), `U`, `E`, and `W`, or `-` for none.
+//! They may follow the ECN field of the IP header in brackets, as in packetdrill: `[noecn]`,
+//! `[ect0]`, `[ect1]`, or `[ce]`. Injected segments are not ECN-capable unless it says otherwise.
+//! Options are comma separated and follow the tcpdump notation as well, e.g.
+//! `<mss 1460,wscale 7,sackOK>`, `<sack 101:201 301:401>` or `<tfo cookie 0102030405060708>`.
+//! Everything after a `#` is a comment.
+//!
+//! The engine under test is bob and the remote peer is alice. Once `accept` completes, the
+//! accepted connection becomes the socket that later calls act on. Every segment the engine emits
+//! must be matched by an expectation before the script ends.
+
+use crate::{
+    collections::bytes::{Bytes, BytesMut},
+    fail::Fail,
+    file_table::FileDescriptor,
+    netstat::SocketState,
+    protocols::{
+        ethernet2::{EtherType2, Ethernet2Header},
+        icmpv4::{Icmpv4Header, Icmpv4Message, Icmpv4Type2, UNREACHABLE_FRAGMENTATION_NEEDED},
+        ip,
+        ipv4::{
+            self,
+            datagram::{
+                IPV4_DONT_FRAGMENT, IPV4_ECN_CE, IPV4_ECN_ECT0, IPV4_ECN_ECT1, IPV4_ECN_NOT_ECT,
+                IPV4_HEADER_SIZE,
+            },
+            Ipv4Header, Ipv4Protocol2,
+        },
+        tcp::{
+            cc,
+            operations::{AcceptFuture, ConnectFuture, PopFuture, PushFuture},
+            segment::{
+                SelectiveAcknowlegement, TcpHeader, TcpOptions2, TcpSegment,
+                MAX_FAST_OPEN_COOKIE_SIZE,
+            },
+            State, Validation,
+        },
+    },
+    runtime::{PacketBuf, Runtime, RuntimeBuf},
+    test_helpers::{self, TestEngine},
+};
+use byteorder::{ByteOrder, NetworkEndian};
+use futures::task::noop_waker_ref;
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    future::Future,
+    net::Shutdown,
+    num::Wrapping,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Port of the remote peer, unless the script connects elsewhere.
+const REMOTE_PORT: u16 = 5555;
+
+/// A segment described by a script line.
+struct SegmentSpec {
+    /// ECN field of the IP header.
+    ecn: Option<u8>,
+    flags: String,
+    /// Sequence number and payload length.
+    seq: Option<(u32, usize)>,
+    ack: Option<u32>,
+    window: Option<u16>,
+    options: Option<Vec<TcpOptions2>>,
+}
+
+enum Pending {
+    Accept(AcceptFuture<test_helpers::TestRuntime>),
+    Connect(ConnectFuture<test_helpers::TestRuntime>),
+    Read(PopFuture<test_helpers::TestRuntime>),
+    Write(PushFuture<test_helpers::TestRuntime>),
+}
+
+/// State of a running script.
+struct Interpreter {
+    engine: TestEngine,
+    start: Instant,
+    fd: Option<FileDescriptor>,
+    local_port: Option<ip::Port>,
+    remote_port: ip::Port,
+    pending: Vec<Pending>,
+    emitted: VecDeque<Bytes>,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+impl Pending {
+    fn name(&self) -> &'static str {
+        match self {
+            Pending::Accept(..) => "accept",
+            Pending::Connect(..) => "connect",
+            Pending::Read(..) => "read",
+            Pending::Write(..) => "write",
+        }
+    }
+}
+
+impl Interpreter {
+    fn new() -> Self {
+        let start = Instant::now();
+        Self {
+            engine: test_helpers::new_bob2(start),
+            start,
+            fd: None,
+            local_port: None,
+            remote_port: ip::Port::try_from(REMOTE_PORT).unwrap(),
+            pending: Vec::new(),
+            emitted: VecDeque::new(),
+        }
+    }
+
+    /// Runs the background work of the engine and collects the segments it emitted.
+    fn run_engine(&mut self) {
+        self.engine.rt().poll_scheduler();
+        while let Some(frame) = self.engine.rt().pop_frame_unchecked() {
+            self.emitted.push_back(frame);
+        }
+    }
+
+    fn execute(&mut self, command: &str) -> Result<(), String> {
+        let mut words = command.split_whitespace();
+        let verb = words.next().ok_or("missing command")?;
+        let rest: Vec<&str> = words.collect();
+        match verb {
+            "<" if rest.first() == Some(&"icmp") => self.inject_icmp(&rest[1..])?,
+            "<" => self.inject(&parse_segment(&rest.join(" "))?)?,
+            ">" => self.expect(&parse_segment(&rest.join(" "))?)?,
+            "await" => self.await_call(&rest)?,
+            _ => self.call(verb, &rest)?,
+        }
+        self.run_engine();
+        Ok(())
+    }
+
+    fn fd(&self) -> Result<FileDescriptor, String> {
+        self.fd.ok_or_else(|| "no socket".to_string())
+    }
+
+    fn call(&mut self, verb: &str, args: &[&str]) -> Result<(), String> {
+        let arg = |i: usize| -> Result<usize, String> {
+            let word = args
+                .get(i)
+                .ok_or_else(|| format!("{} needs an argument", verb))?;
+            word.parse()
+                .map_err(|_| format!("invalid argument {:?}", word))
+        };
+        match verb {
+            "socket" => {
+                let fd = self
+                    .engine
+                    .tcp_socket()
+                    .map_err(|e| format!("socket failed: {:?}", e))?;
+                self.fd = Some(fd);
+            }
+            "bind" => {
+                let fd = self.fd()?;
+                let port = port(arg(0)?)?;
+                let endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, port);
+                self.engine
+                    .tcp_bind(fd, endpoint)
+                    .map_err(|e| format!("bind failed: {:?}", e))?;
+                self.local_port = Some(port);
+            }
+            "listen" => {
+                let fd = self.fd()?;
+                self.engine
+                    .tcp_listen(fd, arg(0)?)
+                    .map_err(|e| format!("listen failed: {:?}", e))?
+            }
+            "connect" => {
+                self.remote_port = port(arg(0)?)?;
+                let remote = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, self.remote_port);
+                let fd = self.fd()?;
+                let future = match args.get(1) {
+                    Some(_) => self
+                        .engine
+                        .tcp_connect_with_data(fd, remote, payload(arg(1)?)),
+                    None => self.engine.tcp_connect(fd, remote),
+                };
+                self.pending.push(Pending::Connect(future));
+            }
+            "accept" => {
+                let fd = self.fd()?;
+                let future = self.engine.tcp_accept(fd);
+                self.pending.push(Pending::Accept(future));
+            }
+            "read" => {
+                let fd = self.fd()?;
+                let future = self.engine.tcp_pop(fd);
+                self.pending.push(Pending::Read(future));
+            }
+            "write" => {
+                let fd = self.fd()?;
+                let future = self.engine.tcp_push(fd, payload(arg(0)?));
+                self.pending.push(Pending::Write(future));
+            }
+            "shutdown" => {
+                let how = match args.first() {
+                    Some(&"read") => Shutdown::Read,
+                    Some(&"write") => Shutdown::Write,
+                    Some(&"both") => Shutdown::Both,
+                    _ => return Err("shutdown needs read, write, or both".to_string()),
+                };
+                let fd = self.fd()?;
+                self.engine
+                    .tcp_shutdown(fd, how)
+                    .map_err(|e| format!("shutdown failed: {:?}", e))?
+            }
+            "close" => {
+                let fd = self.fd()?;
+                self.engine
+                    .tcp_close(fd)
+                    .map_err(|e| format!("close failed: {:?}", e))?
+            }
+            "abort" => {
+                let fd = self.fd()?;
+                self.engine
+                    .tcp_abort(fd)
+                    .map_err(|e| format!("abort failed: {:?}", e))?
+            }
+            _ => return Err(format!("unknown command {:?}", verb)),
+        }
+        Ok(())
+    }
+
+    fn await_call(&mut self, args: &[&str]) -> Result<(), String> {
+        let name = *args.first().ok_or("await needs a call")?;
+        let expect_error = args.get(1) == Some(&"error");
+        let i = self
+            .pending
+            .iter()
+            .position(|p| p.name() == name)
+            .ok_or_else(|| format!("no pending {}", name))?;
+        let mut ctx = Context::from_waker(noop_waker_ref());
+        let result: Poll<Result<Option<usize>, _>> = match &mut self.pending[i] {
+            Pending::Accept(f) => match Future::poll(Pin::new(f), &mut ctx) {
+                Poll::Ready(Ok((fd, _))) => {
+                    self.fd = Some(fd);
+                    Poll::Ready(Ok(None))
+                }
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            },
+            Pending::Connect(f) => Future::poll(Pin::new(f), &mut ctx).map_ok(|()| None),
+            Pending::Read(f) => Future::poll(Pin::new(f), &mut ctx).map_ok(|buf| Some(buf.len())),
+            Pending::Write(f) => Future::poll(Pin::new(f), &mut ctx).map_ok(|()| None),
+        };
+        let result = match result {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Err(format!("{} has not completed", name)),
+        };
+        self.pending.remove(i);
+        match (result, expect_error) {
+            (Ok(len), false) => {
+                if let (Some(len), Some(expected)) = (len, args.get(1)) {
+                    if len.to_string() != *expected {
+                        return Err(format!("read {} bytes instead of {}", len, expected));
+                    }
+                }
+                Ok(())
+            }
+            (Err(_), true) => Ok(()),
+            (Ok(_), true) => Err(format!("{} succeeded", name)),
+            (Err(e), false) => Err(format!("{} failed: {:?}", name, e)),
+        }
+    }
+
+    /// Injects a segment from the remote peer.
+    fn inject(&mut self, spec: &SegmentSpec) -> Result<(), String> {
+        let local_port = self.local_port.ok_or("local port unknown")?;
+        let mut tcp_hdr = TcpHeader::new(self.remote_port, local_port);
+        set_flags(&mut tcp_hdr, &spec.flags)?;
+        let (seq, len) = spec.seq.ok_or("injected segments need a sequence number")?;
+        tcp_hdr.seq_num = Wrapping(seq);
+        if let Some(ack) = spec.ack {
+            tcp_hdr.ack_num = Wrapping(ack);
+        }
+        tcp_hdr.window_size = spec.window.unwrap_or(u16::MAX);
+        for option in spec.options.iter().flatten() {
+            tcp_hdr.push_option(*option);
+        }
+        let segment = TcpSegment {
+            ethernet2_hdr: Ethernet2Header::new(
+                test_helpers::BOB_MAC,
+                test_helpers::ALICE_MAC,
+                EtherType2::Ipv4,
+            ),
+            ipv4_hdr: Ipv4Header {
+                ecn: spec.ecn.unwrap_or(IPV4_ECN_NOT_ECT),
+                ..Ipv4Header::new(
+                    test_helpers::ALICE_IPV4,
+                    test_helpers::BOB_IPV4,
+                    Ipv4Protocol2::Tcp,
+                )
+            },
+            tcp_hdr,
+            data: payload(len),
+            tx_checksum_offload: false,
+            pseudo_header_sum: None,
+        };
+        self.receive(segment);
+        Ok(())
+    }
+
+    /// Injects an ICMP fragmentation needed message about a segment the engine sent.
+    fn inject_icmp(&mut self, args: &[&str]) -> Result<(), String> {
+        let invalid = || format!("invalid ICMP message {:?}", args.join(" "));
+        let (next_hop_mtu, seq) = match args {
+            ["mtu", mtu, "seq", seq] => (
+                mtu.parse().map_err(|_| invalid())?,
+                seq.parse().map_err(|_| invalid())?,
+            ),
+            _ => return Err(invalid()),
+        };
+        let local_port = self.local_port.ok_or("local port unknown")?;
+
+        // The dropped datagram, quoted up to the sequence number of the segment.
+        let mut quote = vec![0u8; IPV4_HEADER_SIZE + 8];
+        Ipv4Header::new(
+            test_helpers::BOB_IPV4,
+            test_helpers::ALICE_IPV4,
+            Ipv4Protocol2::Tcp,
+        )
+        .serialize(&mut quote[..IPV4_HEADER_SIZE], 8, false);
+        NetworkEndian::write_u16(&mut quote[20..22], local_port.into());
+        NetworkEndian::write_u16(&mut quote[22..24], self.remote_port.into());
+        NetworkEndian::write_u32(&mut quote[24..28], seq);
+
+        let message = Icmpv4Message::with_data(
+            Ethernet2Header::new(
+                test_helpers::BOB_MAC,
+                test_helpers::ALICE_MAC,
+                EtherType2::Ipv4,
+            ),
+            Ipv4Header::new(
+                test_helpers::ALICE_IPV4,
+                test_helpers::BOB_IPV4,
+                Ipv4Protocol2::Icmpv4,
+            ),
+            Icmpv4Header::new(
+                Icmpv4Type2::DestinationUnreachable { next_hop_mtu },
+                UNREACHABLE_FRAGMENTATION_NEEDED,
+            ),
+            quote,
+        );
+        self.receive(message);
+        Ok(())
+    }
+
+    /// Serializes `packet` and hands it over to the engine.
+    fn receive<P: PacketBuf<Bytes>>(&mut self, packet: P) {
+        let header_size = packet.header_size();
+        let mut buf = BytesMut::zeroed(header_size + packet.body_size()).unwrap();
+        packet.write_header(&mut buf[..header_size]);
+        if let Some(body) = packet.take_body() {
+            buf[header_size..].copy_from_slice(&body[..]);
+        }
+        if let Err(e) = self.engine.receive(buf.freeze()) {
+            debug!("Engine rejected injected frame: {:?}", e);
+        }
+    }
+
+    /// Checks the oldest segment emitted by the engine against `spec`.
+    fn expect(&mut self, spec: &SegmentSpec) -> Result<(), String> {
+        let frame = self
+            .emitted
+            .pop_front()
+            .ok_or("expected a segment, none was sent")?;
+        let (_, payload) = Ethernet2Header::parse(frame).map_err(|e| format!("{:?}", e))?;
+        let (ipv4_hdr, payload) = Ipv4Header::parse(payload).map_err(|e| format!("{:?}", e))?;
+        if ipv4_hdr.flags & IPV4_DONT_FRAGMENT == 0 {
+            return Err("segment sent without the don't fragment flag".to_string());
+        }
+        let (tcp_hdr, data) =
+            TcpHeader::parse(&ipv4_hdr, payload, false).map_err(|e| format!("{:?}", e))?;
+        let mismatch = |what: &str| Err(format!("{} mismatch, got {}", what, tcp_hdr));
+
+        if let Some(ecn) = spec.ecn {
+            if ipv4_hdr.ecn != ecn {
+                return mismatch("ECN");
+            }
+        }
+        let mut expected_flags = TcpHeader::new(tcp_hdr.src_port, tcp_hdr.dst_port);
+        set_flags(&mut expected_flags, &spec.flags)?;
+        if flags(&expected_flags) != flags(&tcp_hdr) {
+            return mismatch("flags");
+        }
+        if let Some((seq, len)) = spec.seq {
+            if tcp_hdr.seq_num != Wrapping(seq) || data.len() != len {
+                return mismatch("sequence");
+            }
+        }
+        if let Some(ack) = spec.ack {
+            if tcp_hdr.ack_num != Wrapping(ack) {
+                return mismatch("ack");
+            }
+        }
+        if let Some(window) = spec.window {
+            if tcp_hdr.window_size != window {
+                return mismatch("window");
+            }
+        }
+        if let Some(options) = &spec.options {
+            if !tcp_hdr.iter_options().eq(options.iter()) {
+                return mismatch("options");
+            }
+        }
+        if tcp_hdr.syn {
+            self.local_port = Some(tcp_hdr.src_port);
+        }
+        Ok(())
+    }
+}
+
+//==============================================================================
+// Standalone Functions
+//==============================================================================
+
+/// Runs `script` against a fresh engine, panicking with the offending line on failure.
+pub fn run(script: &str) {
+    run_with(script, |_| ());
+}
+
+/// Runs `script` against an engine first set up by `configure`, and returns the engine.
+pub fn run_with<F: FnOnce(&mut TestEngine)>(script: &str, configure: F) -> TestEngine {
+    let mut interpreter = Interpreter::new();
+    configure(&mut interpreter.engine);
+    let mut now = Duration::ZERO;
+    for (i, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let result = (|| {
+            let (time, command) = line.split_at(line.find(char::is_whitespace).unwrap_or(0));
+            now = match time.strip_prefix('+') {
+                Some(delta) => now + parse_time(delta)?,
+                None => parse_time(time)?,
+            };
+            let when = interpreter.start + now;
+            if when > interpreter.engine.rt().now() {
+                interpreter.engine.rt().advance_clock(when);
+            }
+            interpreter.run_engine();
+            interpreter.execute(command)
+        })();
+        if let Err(e) = result {
+            panic!("line {}: {:?}: {}", i + 1, line, e);
+        }
+    }
+    if let Some(frame) = interpreter.emitted.pop_front() {
+        panic!("unexpected segment at the end of the script: {:?}", frame);
+    }
+    interpreter.engine
+}
+
+/// Parses a time in seconds without going through floating point, so that timers set by the
+/// engine line up exactly with the times written in scripts.
+fn parse_time(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid time {:?}", s);
+    let (secs, fraction) = match s.find('.') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, ""),
+    };
+    if fraction.len() > 6 {
+        return Err(invalid());
+    }
+    let secs: u64 = secs.parse().map_err(|_| invalid())?;
+    let micros: u64 = match fraction {
+        "" => 0,
+        f => format!("{:0<6}", f).parse().map_err(|_| invalid())?,
+    };
+    Ok(Duration::from_secs(secs) + Duration::from_micros(micros))
+}
+
+fn parse_segment(s: &str) -> Result<SegmentSpec, String> {
+    let (s, options) = match s.find('<') {
+        Some(i) => {
+            let end = s.rfind('>').ok_or("unterminated options")?;
+            (&s[..i], Some(parse_options(&s[i + 1..end])?))
+        }
+        None => (s, None),
+    };
+    let mut words = s.split_whitespace().peekable();
+    let ecn = match words.next_if(|w| w.starts_with('[')) {
+        Some("[noecn]") => Some(IPV4_ECN_NOT_ECT),
+        Some("[ect0]") => Some(IPV4_ECN_ECT0),
+        Some("[ect1]") => Some(IPV4_ECN_ECT1),
+        Some("[ce]") => Some(IPV4_ECN_CE),
+        Some(word) => return Err(format!("invalid ECN field {:?}", word)),
+        None => None,
+    };
+    let flags = words.next().ok_or("missing flags")?.to_string();
+    let mut spec = SegmentSpec {
+        ecn,
+        flags,
+        seq: None,
+        ack: None,
+        window: None,
+        options,
+    };
+    if let Some(word) = words.peek().copied().filter(|w| w.contains(':')) {
+        // START:END(LEN)
+        let invalid = || format!("invalid sequence {:?}", word);
+        let (start, rest) = word.split_at(word.find(':').unwrap());
+        let open = rest.find('(').ok_or_else(invalid)?;
+        let len = rest[open + 1..].trim_end_matches(')');
+        spec.seq = Some((
+            start.parse().map_err(|_| invalid())?,
+            len.parse().map_err(|_| invalid())?,
+        ));
+        words.next();
+    }
+    while let Some(word) = words.next() {
+        let value = words
+            .next()
+            .ok_or_else(|| format!("{} needs a value", word))?;
+        let invalid = || format!("invalid {} {:?}", word, value);
+        match word {
+            "ack" => spec.ack = Some(value.parse().map_err(|_| invalid())?),
+            "win" => spec.window = Some(value.parse().map_err(|_| invalid())?),
+            _ => return Err(format!("unknown field {:?}", word)),
+        }
+    }
+    Ok(spec)
+}
+
+fn parse_options(s: &str) -> Result<Vec<TcpOptions2>, String> {
+    let mut options = Vec::new();
+    for option in s.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+        let words: Vec<&str> = option.split_whitespace().collect();
+        let invalid = || format!("invalid option {:?}", option);
+        let number = |i: usize| -> Result<u32, String> {
+            words
+                .get(i)
+                .ok_or_else(invalid)?
+                .parse()
+                .map_err(|_| invalid())
+        };
+        options.push(match words[0] {
+            "nop" => TcpOptions2::NoOperation,
+            "mss" => TcpOptions2::MaximumSegmentSize(number(1)? as u16),
+            "wscale" => TcpOptions2::WindowScale(number(1)? as u8),
+            "sackOK" => TcpOptions2::SelectiveAcknowlegementPermitted,
+            "sack" if (2..=5).contains(&words.len()) => {
+                let mut sacks = [SelectiveAcknowlegement {
+                    begin: Wrapping(0),
+                    end: Wrapping(0),
+                }; 4];
+                for (sack, block) in sacks.iter_mut().zip(&words[1..]) {
+                    let (begin, end) = block.split_at(block.find(':').ok_or_else(invalid)?);
+                    sack.begin = Wrapping(begin.parse().map_err(|_| invalid())?);
+                    sack.end = Wrapping(end[1..].parse().map_err(|_| invalid())?);
+                }
+                TcpOptions2::SelectiveAcknowlegement {
+                    num_sacks: words.len() - 1,
+                    sacks,
+                }
+            }
+            "TS" if words.get(1) == Some(&"val") && words.get(3) == Some(&"ecr") => {
+                TcpOptions2::Timestamp {
+                    sender_timestamp: number(2)?,
+                    echo_timestamp: number(4)?,
+                }
+            }
+            "tfo" if words.get(1) == Some(&"cookiereq") => TcpOptions2::fast_open(&[]),
+            "tfo" if words.get(1) == Some(&"cookie") => {
+                let hex = words.get(2).ok_or_else(invalid)?;
+                let cookie = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+                    .collect::<Option<Vec<u8>>>()
+                    .filter(|c| c.len() <= MAX_FAST_OPEN_COOKIE_SIZE)
+                    .ok_or_else(invalid)?;
+                TcpOptions2::fast_open(&cookie)
+            }
+            _ => return Err(invalid()),
+        });
+    }
+    Ok(options)
+}
+
+fn set_flags(header: &mut TcpHeader, flags: &str) -> Result<(), String> {
+    for flag in flags.chars() {
+        match flag {
+            'F' => header.fin = true,
+            'S' => header.syn = true,
+            'R' => header.rst = true,
+            'P' => header.psh = true,
+            '.' => header.ack = true,
+            'U' => header.urg = true,
+            'E' => header.ece = true,
+            'W' => header.cwr = true,
+            '-' => (),
+            _ => return Err(format!("unknown flag {:?}", flag)),
+        }
+    }
+    Ok(())
+}
+
+fn flags(header: &TcpHeader) -> [bool; 8] {
+    [
+        header.fin, header.syn, header.rst, header.psh, header.ack, header.urg, header.ece,
+        header.cwr,
+    ]
+}
+
+fn port(n: usize) -> Result<ip::Port, String> {
+    u16::try_from(n)
+        .ok()
+        .and_then(|n| ip::Port::try_from(n).ok())
+        .ok_or_else(|| format!("invalid port {}", n))
+}
+
+fn payload(len: usize) -> Bytes {
+    let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+    Bytes::from_slice(&data)
+}
+
+//==============================================================================
+// Scripts
+//==============================================================================
+
+#[test]
+fn script_passive_open() {
+    run(include_str!("scripts/passive_open.pkt"));
+}
+
+#[test]
+fn script_active_open() {
+    run(include_str!("scripts/active_open.pkt"));
+}
+
+#[test]
+fn script_syn_retransmit() {
+    let engine = run_with(include_str!("scripts/syn_retransmit.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_handshake_timeout(Duration::from_secs(1));
+        engine.set_tcp_options(options);
+    });
+    assert_eq!(engine.stats().tcp.retrans_segs, 1);
+}
+
+#[test]
+fn script_syn_ack_retransmit() {
+    let engine = run_with(include_str!("scripts/syn_ack_retransmit.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_handshake_timeout(Duration::from_secs(1));
+        engine.set_tcp_options(options);
+    });
+    assert_eq!(engine.stats().tcp.retrans_segs, 2);
+}
+
+#[test]
+fn script_syn_ack_timeout() {
+    let engine = run_with(include_str!("scripts/syn_ack_timeout.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_handshake_retries(2)
+            .set_handshake_timeout(Duration::from_secs(1));
+        engine.set_tcp_options(options);
+    });
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.retrans_segs, 1);
+    assert_eq!(stats.tcp.attempt_fails, 1);
+}
+
+#[test]
+fn script_connect_timeout() {
+    let engine = run_with(include_str!("scripts/connect_timeout.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_handshake_retries(3)
+            .set_handshake_timeout(Duration::from_secs(1));
+        engine.set_tcp_options(options);
+    });
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.retrans_segs, 2);
+    assert_eq!(stats.tcp.attempt_fails, 1);
+}
+
+#[test]
+fn script_simultaneous_open() {
+    let engine = run_with(include_str!("scripts/simultaneous_open.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_handshake_timeout(Duration::from_secs(1));
+        engine.set_tcp_options(options);
+    });
+    assert_eq!(engine.stats().tcp.retrans_segs, 1);
+}
+
+#[test]
+fn script_strict_validation() {
+    let engine = run_with(include_str!("scripts/strict_validation.pkt"), |engine| {
+        let options = engine.tcp_options().set_validation(Validation::Strict);
+        engine.set_tcp_options(options);
+    });
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.rst_seq_violations, 1);
+    assert_eq!(stats.tcp.seq_out_of_window, 1);
+    assert_eq!(stats.tcp.ack_unsent, 1);
+    assert_eq!(stats.drops.out_of_window, 3);
+}
+
+#[test]
+fn script_challenge_ack() {
+    let engine = run_with(include_str!("scripts/challenge_ack.pkt"), |engine| {
+        let options = engine.tcp_options().set_error_segment_rate(0, 2);
+        engine.set_tcp_options(options);
+    });
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.challenge_acks, 2);
+    assert_eq!(stats.tcp.error_segments_throttled, 1);
+    assert_eq!(stats.tcp.rst_seq_violations, 3);
+}
+
+#[test]
+fn script_connection_reset() {
+    let mut engine = run_with(include_str!("scripts/connection_reset.pkt"), |_| ());
+    assert_eq!(engine.stats().tcp.estab_resets, 1);
+    let info = engine.connections().pop().unwrap();
+    assert_eq!(info.state, SocketState::Established(State::Reset));
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut pop_future = engine.tcp_pop(info.fd);
+    assert!(matches!(
+        Future::poll(Pin::new(&mut pop_future), &mut ctx),
+        Poll::Ready(Err(Fail::ConnectionReset {}))
+    ));
+}
+
+#[test]
+fn script_abort() {
+    let mut engine = run_with(include_str!("scripts/abort.pkt"), |_| ());
+    assert_eq!(engine.stats().tcp.out_rsts, 1);
+    let info = engine.connections().pop().unwrap();
+    assert_eq!(info.state, SocketState::Established(State::Reset));
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut pop_future = engine.tcp_pop(info.fd);
+    assert!(matches!(
+        Future::poll(Pin::new(&mut pop_future), &mut ctx),
+        Poll::Ready(Err(Fail::ConnectionAborted {}))
+    ));
+}
+
+#[test]
+fn script_reset() {
+    let engine = run_with(include_str!("scripts/reset.pkt"), |_| ());
+    assert_eq!(engine.stats().tcp.out_rsts, 3);
+}
+
+#[test]
+fn script_time_wait_recycle() {
+    let engine = run_with(include_str!("scripts/time_wait_recycle.pkt"), |_| ());
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.time_wait_recycled, 1);
+    assert_eq!(stats.tcp.out_rsts, 0);
+}
+
+#[test]
+fn script_time_wait() {
+    let engine = run_with(include_str!("scripts/time_wait.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_time_wait(16, Duration::from_secs(1));
+        engine.set_tcp_options(options);
+    });
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.time_wait_recycled, 0);
+    assert_eq!(stats.tcp.out_rsts, 0);
+}
+
+#[test]
+fn script_window_scale() {
+    run_with(include_str!("scripts/window_scale.pkt"), |engine| {
+        let options = engine.tcp_options().set_window_scale(7);
+        engine.set_tcp_options(options);
+    });
+}
+
+#[test]
+fn script_receive_window_autotuning() {
+    let engine = run_with(include_str!("scripts/receive_window_autotuning.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_receive_window_size(4000)
+            .set_receive_window_autotuning(1_000_000);
+        engine.set_tcp_options(options);
+    });
+    assert_eq!(engine.stats().tcp.receive_window_grows, 1);
+}
+
+#[test]
+fn script_selective_acks() {
+    let engine = run_with(include_str!("scripts/selective_acks.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_congestion_ctrl_type(cc::Cubic::new);
+        engine.set_tcp_options(options);
+    });
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.retrans_segs, 2);
+    assert_eq!(stats.tcp.sack_retrans_segs, 1);
+}
+
+#[test]
+fn script_dsack() {
+    let engine = run_with(include_str!("scripts/dsack.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_congestion_ctrl_type(cc::NewReno::new);
+        engine.set_tcp_options(options);
+    });
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.dsacks_sent, 2);
+    assert_eq!(stats.tcp.dsacks_received, 1);
+    assert_eq!(stats.tcp.dsack_undos, 1);
+    assert_eq!(stats.tcp.retrans_segs, 1);
+}
+
+#[test]
+fn script_zero_window() {
+    let engine = run_with(include_str!("scripts/zero_window.pkt"), |_| ());
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.window_probes, 2);
+    assert_eq!(stats.tcp.retrans_segs, 0);
+}
+
+#[test]
+fn script_delayed_ack() {
+    run(include_str!("scripts/delayed_ack.pkt"));
+}
+
+#[test]
+fn script_shutdown_read() {
+    run(include_str!("scripts/shutdown_read.pkt"));
+}
+
+#[test]
+fn script_mss() {
+    run_with(include_str!("scripts/mss.pkt"), |engine| {
+        let options = engine.tcp_options().set_mtu(1200);
+        engine.set_tcp_options(options);
+    });
+}
+
+#[test]
+fn script_path_mtu() {
+    let engine = run_with(include_str!("scripts/path_mtu.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_path_mtu_probe_interval(Duration::from_secs(2));
+        engine.set_tcp_options(options);
+    });
+    let stats = engine.stats();
+    assert_eq!(stats.icmp.in_dest_unreachs, 2);
+    assert_eq!(stats.tcp.retrans_segs, 2);
+}
+
+#[test]
+fn script_nagle() {
+    run_with(include_str!("scripts/nagle.pkt"), |engine| {
+        let options = engine.tcp_options().set_nodelay(false);
+        engine.set_tcp_options(options);
+    });
+}
+
+#[test]
+fn script_coalescing() {
+    run_with(include_str!("scripts/coalescing.pkt"), |engine| {
+        let options = engine.tcp_options().set_nodelay(true);
+        engine.set_tcp_options(options);
+    });
+}
+
+#[test]
+fn script_new_reno() {
+    let engine = run_with(include_str!("scripts/new_reno.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_congestion_ctrl_type(cc::NewReno::new);
+        engine.set_tcp_options(options);
+    });
+    assert_eq!(engine.stats().tcp.retrans_segs, 2);
+}
+
+#[test]
+fn script_ecn() {
+    let engine = run_with(include_str!("scripts/ecn.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_ecn(true)
+            .set_congestion_ctrl_type(cc::NewReno::new);
+        engine.set_tcp_options(options);
+    });
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.ecn_ce_marks, 1);
+    assert_eq!(stats.tcp.ecn_cwnd_reductions, 1);
+}
+
+#[test]
+fn script_ecn_not_negotiated() {
+    run_with(include_str!("scripts/ecn_not_negotiated.pkt"), |engine| {
+        let options = engine.tcp_options().set_ecn(true);
+        engine.set_tcp_options(options);
+    });
+}
+
+#[test]
+fn script_fast_open() {
+    let engine = run_with(include_str!("scripts/fast_open.pkt"), |engine| {
+        let options = engine.tcp_options().set_fast_open(true);
+        engine.set_tcp_options(options);
+    });
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.fast_open_cookie_reqs, 1);
+    assert_eq!(stats.tcp.fast_open_active, 1);
+    assert_eq!(stats.tcp.fast_open_active_fails, 1);
+}
+
+#[test]
+fn script_fast_retransmit() {
+    let engine = run_with(include_str!("scripts/fast_retransmit.pkt"), |_| ());
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.dup_acks, 3);
+    assert_eq!(stats.tcp.fast_retransmits, 2);
+    assert_eq!(stats.tcp.retrans_segs, 2);
+}
+
+#[test]
+fn script_frto() {
+    let engine = run_with(include_str!("scripts/frto.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_congestion_ctrl_type(cc::NewReno::new);
+        engine.set_tcp_options(options);
+    });
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.retrans_segs, 1);
+    assert_eq!(stats.tcp.frto_spurious, 1);
+}
+
+#[test]
+fn script_cwnd_validation() {
+    let engine = run_with(include_str!("scripts/cwnd_validation.pkt"), |engine| {
+        let options = engine
+            .tcp_options()
+            .set_congestion_ctrl_type(cc::NewReno::new);
+        engine.set_tcp_options(options);
+    });
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.retrans_segs, 0);
+}
+
+#[test]
+fn script_retransmit_timeout() {
+    let mut engine = run_with(include_str!("scripts/retransmit_timeout.pkt"), |engine| {
+        let options = engine.tcp_options().set_max_retransmits(2);
+        engine.set_tcp_options(options);
+    });
+    let stats = engine.stats();
+    assert_eq!(stats.tcp.retrans_segs, 2);
+    assert_eq!(stats.tcp.timeout_aborts, 1);
+    let info = engine.connections().pop().unwrap();
+    assert_eq!(info.state, SocketState::Established(State::Reset));
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut pop_future = engine.tcp_pop(info.fd);
+    assert!(matches!(
+        Future::poll(Pin::new(&mut pop_future), &mut ctx),
+        Poll::Ready(Err(Fail::Timeout {}))
+    ));
+}
+
+#[test]
+fn script_timestamps() {
+    let engine = run_with(include_str!("scripts/timestamps.pkt"), |_| ());
+    assert_eq!(engine.stats().tcp.paws_rejected, 1);
+}
@@ -3,7 +3,7 @@
 
 use crate::{
     collections::bytes::{Bytes, BytesMut},
-    engine::Engine,
+    engine::{Engine, EngineBuilder},
     fail::Fail,
     file_table::FileDescriptor,
     protocols::{
@@ -12,20 +12,23 @@ use crate::{
         ipv4::{self, Ipv4Header},
         tcp::{
             operations::{AcceptFuture, ConnectFuture},
-            segment::{TcpHeader, TcpSegment},
+            segment::{TcpHeader, TcpOptions2, TcpSegment},
+            CloseReason, IdleReaping, ReapAction,
         },
     },
-    runtime::{PacketBuf, Runtime, RuntimeBuf},
+    runtime::{PacketBuf, Resource, Runtime, RuntimeBuf},
     test_helpers::{self, TestRuntime},
 };
 use futures::task::noop_waker_ref;
 use must_let::must_let;
 use std::{
+    cell::RefCell,
     convert::TryFrom,
     future::Future,
     net::Ipv4Addr,
     num::Wrapping,
     pin::Pin,
+    rc::Rc,
     task::{Context, Poll},
     time::{Duration, Instant},
 };
@@ -45,7 +48,7 @@ fn test_connection_timeout() {
     // Setup client.
     let mut client = test_helpers::new_alice2(now);
     let nretries: usize = client.rt().tcp_options().handshake_retries();
-    let timeout: Duration = client.rt().tcp_options().handshake_timeout();
+    let mut timeout: Duration = client.rt().tcp_options().handshake_timeout();
 
     // T(0) -> T(1)
     advance_clock(None, Some(&mut client), &mut now);
@@ -64,14 +67,73 @@ fn test_connection_timeout() {
         listen_port,
     );
 
+    // Every SYN waits twice as long as the one before.
     for _ in 0..nretries {
         for _ in 0..timeout.as_secs() {
             advance_clock(None, Some(&mut client), &mut now);
         }
         client.rt().poll_scheduler();
+        timeout *= 2;
+    }
+
+    must_let!(let Poll::Ready(Err(Fail::Timeout{})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+}
+
+//=============================================================================
+
+/// Tests that TCP options handed to [EngineBuilder] take precedence over those of the runtime.
+#[test]
+fn test_connection_timeout_engine_builder_options() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup client with fewer handshake retries than the runtime default.
+    let rt = TestRuntime::new(
+        "alice",
+        now,
+        test_helpers::ALICE_MAC,
+        test_helpers::ALICE_IPV4,
+    );
+    let mut arp_options = rt.arp_options();
+    arp_options
+        .initial_values
+        .insert(test_helpers::BOB_IPV4, test_helpers::BOB_MAC);
+    let nretries: usize = 2;
+    assert!(nretries < rt.tcp_options().handshake_retries());
+    let tcp_options = rt.tcp_options().set_handshake_retries(nretries);
+    let mut timeout: Duration = tcp_options.handshake_timeout();
+    let mut client = EngineBuilder::new(rt)
+        .arp_options(arp_options)
+        .tcp_options(tcp_options)
+        .build()
+        .unwrap();
+
+    // Client: SYN_SENT state at T(0).
+    let (_, mut connect_future, _): (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) =
+        connection_setup_listen_syn_sent(&mut client, listen_addr);
+
+    for i in 1..=nretries {
+        for _ in 0..timeout.as_secs() {
+            advance_clock(None, Some(&mut client), &mut now);
+        }
+        client.rt().poll_scheduler();
+        if i < nretries {
+            // SYN retransmission.
+            client.rt().pop_frame();
+        }
+        timeout *= 2;
     }
 
+    assert!(client.rt().pop_frame_unchecked().is_none());
     must_let!(let Poll::Ready(Err(Fail::Timeout{})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    let stats = client.stats();
+    assert_eq!(stats.tcp.active_opens, 1);
+    assert_eq!(stats.tcp.attempt_fails, 1);
+    assert_eq!(stats.tcp.out_segs, nretries as u64);
 }
 
 //=============================================================================
@@ -127,6 +189,7 @@ fn test_refuse_connection_early_rst() {
         },
         data: Bytes::empty(),
         tx_checksum_offload: false,
+        pseudo_header_sum: None,
     };
 
     // Serialize segment.
@@ -192,6 +255,7 @@ fn test_refuse_connection_early_ack() {
         },
         data: Bytes::empty(),
         tx_checksum_offload: false,
+        pseudo_header_sum: None,
     };
 
     // Serialize segment.
@@ -267,6 +331,7 @@ fn test_refuse_connection_missing_syn() {
         },
         data: Bytes::empty(),
         tx_checksum_offload: false,
+        pseudo_header_sum: None,
     };
 
     // Serialize segment.
@@ -312,7 +377,7 @@ fn connection_setup_listen_syn_sent(
     listen_addr: ipv4::Endpoint,
 ) -> (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) {
     // Issue CONNECT operation.
-    let client_fd: FileDescriptor = client.tcp_socket();
+    let client_fd: FileDescriptor = client.tcp_socket().unwrap();
     let connect_future: ConnectFuture<TestRuntime> = client.tcp_connect(client_fd, listen_addr);
 
     // SYN_SENT state.
@@ -328,7 +393,7 @@ fn connection_setup_closed_listen(
     listen_addr: ipv4::Endpoint,
 ) -> AcceptFuture<TestRuntime> {
     // Issue ACCEPT operation.
-    let socket_fd: u32 = server.tcp_socket();
+    let socket_fd: u32 = server.tcp_socket().unwrap();
     server.tcp_bind(socket_fd, listen_addr).unwrap();
     server.tcp_listen(socket_fd, 1).unwrap();
     let accept_future: AcceptFuture<TestRuntime> = server.tcp_accept(socket_fd);
@@ -518,7 +583,7 @@ pub fn connection_setup(
     // Server: ESTABLISHED at T(4).
     connection_setup_sync_rcvd_established(server, bytes);
 
-    must_let!(let Poll::Ready(Ok(server_fd)) = Future::poll(Pin::new(&mut accept_future), ctx));
+    must_let!(let Poll::Ready(Ok((server_fd, _))) = Future::poll(Pin::new(&mut accept_future), ctx));
     must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), ctx));
 
     (server_fd, client_fd)
@@ -546,4 +611,520 @@ fn test_good_connect() {
         listen_port,
         listen_addr,
     );
+
+    // SYN and ACK went out of the client, SYN+ACK out of the server.
+    let client_stats = client.stats();
+    assert_eq!(client_stats.tcp.active_opens, 1);
+    assert_eq!(client_stats.tcp.passive_opens, 0);
+    assert_eq!(client_stats.tcp.in_segs, 1);
+    assert_eq!(client_stats.tcp.out_segs, 2);
+    let server_stats = server.stats();
+    assert_eq!(server_stats.tcp.active_opens, 0);
+    assert_eq!(server_stats.tcp.passive_opens, 1);
+    assert_eq!(server_stats.tcp.in_segs, 2);
+    assert_eq!(server_stats.tcp.out_segs, 1);
+    assert_eq!(server_stats.ip.in_delivers, 2);
+}
+
+/// Tests that a connection waits in the accept queue while the file table is exhausted.
+#[test]
+fn test_accept_file_table_exhausted() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers.
+    let mut server = test_helpers::new_bob2(now);
+    let mut client = test_helpers::new_alice2(now);
+    let socket_fd: FileDescriptor = server.tcp_socket().unwrap();
+    server.tcp_bind(socket_fd, listen_addr).unwrap();
+    server.tcp_listen(socket_fd, 1).unwrap();
+    let mut accept_future: AcceptFuture<TestRuntime> = server.tcp_accept(socket_fd);
+    let (_, mut connect_future, mut bytes): (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) =
+        connection_setup_listen_syn_sent(&mut client, listen_addr);
+    advance_clock(Some(&mut server), Some(&mut client), &mut now);
+    bytes = connection_setup_listen_syn_rcvd(&mut server, bytes);
+    advance_clock(Some(&mut server), Some(&mut client), &mut now);
+    bytes = connection_setup_syn_sent_established(&mut client, bytes);
+    advance_clock(Some(&mut server), Some(&mut client), &mut now);
+    connection_setup_sync_rcvd_established(&mut server, bytes);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // Accepting fails while no file descriptor is left.
+    server
+        .rt()
+        .inject_exhaustion(Resource::FileDescriptor, 0, 1);
+    must_let!(let Poll::Ready(Err(err)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+    assert_eq!(
+        err,
+        Fail::TooManyOpenedFiles {
+            details: "file table exhausted",
+        }
+    );
+
+    // The connection is still queued.
+    let mut accept_future: AcceptFuture<TestRuntime> = server.tcp_accept(socket_fd);
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+}
+
+/// Tests that pending accepts are served in the order they started waiting.
+#[test]
+fn test_accept_in_order() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers, with two accepts waiting on the listener.
+    let mut server = test_helpers::new_bob2(now);
+    let mut client = test_helpers::new_alice2(now);
+    let socket_fd: FileDescriptor = server.tcp_socket().unwrap();
+    server.tcp_bind(socket_fd, listen_addr).unwrap();
+    server.tcp_listen(socket_fd, 4).unwrap();
+    let mut first_accept: AcceptFuture<TestRuntime> = server.tcp_accept(socket_fd);
+    let mut second_accept: AcceptFuture<TestRuntime> = server.tcp_accept(socket_fd);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut first_accept), &mut ctx));
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut second_accept), &mut ctx));
+
+    // Establish one connection.
+    let (_, mut connect_future, mut bytes): (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) =
+        connection_setup_listen_syn_sent(&mut client, listen_addr);
+    advance_clock(Some(&mut server), Some(&mut client), &mut now);
+    bytes = connection_setup_listen_syn_rcvd(&mut server, bytes);
+    advance_clock(Some(&mut server), Some(&mut client), &mut now);
+    bytes = connection_setup_syn_sent_established(&mut client, bytes);
+    advance_clock(Some(&mut server), Some(&mut client), &mut now);
+    connection_setup_sync_rcvd_established(&mut server, bytes);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // It goes to the accept that waited first, however they get polled.
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut second_accept), &mut ctx));
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut first_accept), &mut ctx));
+
+    // A dropped accept gives up its place in line.
+    drop(second_accept);
+    let (_, mut connect_future, mut bytes): (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) =
+        connection_setup_listen_syn_sent(&mut client, listen_addr);
+    advance_clock(Some(&mut server), Some(&mut client), &mut now);
+    bytes = connection_setup_listen_syn_rcvd(&mut server, bytes);
+    advance_clock(Some(&mut server), Some(&mut client), &mut now);
+    bytes = connection_setup_syn_sent_established(&mut client, bytes);
+    advance_clock(Some(&mut server), Some(&mut client), &mut now);
+    connection_setup_sync_rcvd_established(&mut server, bytes);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    let mut third_accept: AcceptFuture<TestRuntime> = server.tcp_accept(socket_fd);
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut third_accept), &mut ctx));
+}
+
+/// Tests that a SYN that does not fit in the transmit queue gets retransmitted.
+#[test]
+fn test_syn_transmit_queue_full() {
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup client.
+    let mut client = test_helpers::new_alice2(now);
+    let timeout: Duration = client.rt().tcp_options().handshake_timeout();
+    client.rt().inject_exhaustion(Resource::TransmitQueue, 0, 1);
+
+    // The first SYN is lost.
+    let client_fd: FileDescriptor = client.tcp_socket().unwrap();
+    let _connect_future: ConnectFuture<TestRuntime> = client.tcp_connect(client_fd, listen_addr);
+    client.rt().poll_scheduler();
+    assert!(client.rt().pop_frame_unchecked().is_none());
+
+    // It is sent again once the handshake times out.
+    now += timeout;
+    client.rt().advance_clock(now);
+    client.rt().poll_scheduler();
+    check_packet_pure_syn(
+        client.rt().pop_frame(),
+        test_helpers::ALICE_MAC,
+        test_helpers::BOB_MAC,
+        test_helpers::ALICE_IPV4,
+        test_helpers::BOB_IPV4,
+        listen_port,
+    );
+}
+
+/// Tests that a handshake giving up on the final ACK goes away without another segment showing up.
+#[test]
+fn test_syn_ack_timeout_reaped() {
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers, with a server that sends the SYN+ACK twice.
+    let mut server = test_helpers::new_bob2(now);
+    let mut client = test_helpers::new_alice2(now);
+    let options = server
+        .tcp_options()
+        .set_handshake_retries(2)
+        .set_handshake_timeout(Duration::from_secs(1));
+    server.set_tcp_options(options);
+    let _accept_future: AcceptFuture<TestRuntime> =
+        connection_setup_closed_listen(&mut server, listen_addr);
+    let tasks = server.scheduler_stats().tasks;
+
+    // The client never answers the SYN+ACK.
+    let (_, _, bytes): (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) =
+        connection_setup_listen_syn_sent(&mut client, listen_addr);
+    connection_setup_listen_syn_rcvd(&mut server, bytes);
+    assert_eq!(server.scheduler_stats().tasks, tasks + 1);
+    advance_clock(Some(&mut server), None, &mut now);
+    server.rt().poll_scheduler();
+    server.rt().pop_frame();
+
+    // Once the retries run out, the handshake and its task are gone.
+    advance_clock(Some(&mut server), None, &mut now);
+    advance_clock(Some(&mut server), None, &mut now);
+    server.rt().poll_scheduler();
+    server.rt().poll_scheduler();
+    assert!(server.rt().pop_frame_unchecked().is_none());
+    assert_eq!(server.stats().tcp.attempt_fails, 1);
+    assert_eq!(server.scheduler_stats().tasks, tasks);
+}
+
+/// Tests that a flooded listener answers with SYN cookies and still accepts their handshakes.
+#[test]
+fn test_syn_cookies() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers, with a server that starts one handshake per second at most.
+    let mut server = test_helpers::new_bob2(now);
+    let mut client = test_helpers::new_alice2(now);
+    let options = server.tcp_options().set_syn_flood_rates(1, 128);
+    server.set_tcp_options(options);
+    let socket_fd: FileDescriptor = server.tcp_socket().unwrap();
+    server.tcp_bind(socket_fd, listen_addr).unwrap();
+    server.tcp_listen(socket_fd, 4).unwrap();
+    let mut accept_future: AcceptFuture<TestRuntime> = server.tcp_accept(socket_fd);
+
+    // The first SYN is answered as usual.
+    let (_, _, mut bytes): (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) =
+        connection_setup_listen_syn_sent(&mut client, listen_addr);
+    bytes = connection_setup_listen_syn_rcvd(&mut server, bytes);
+    check_packet_syn_ack(
+        bytes,
+        test_helpers::BOB_MAC,
+        test_helpers::ALICE_MAC,
+        test_helpers::BOB_IPV4,
+        test_helpers::ALICE_IPV4,
+        listen_port,
+    );
+
+    // The second one gets a SYN cookie, without window scaling.
+    let (_, mut connect_future, mut bytes): (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) =
+        connection_setup_listen_syn_sent(&mut client, listen_addr);
+    server.receive(bytes).unwrap();
+    bytes = server.rt().pop_frame();
+    let (_, _, tcp_header): (Ethernet2Header, Ipv4Header, TcpHeader) =
+        extract_headers(bytes.clone());
+    assert!(tcp_header.syn && tcp_header.ack);
+    assert_ne!(tcp_header.seq_num, Wrapping(0));
+    assert!(!tcp_header
+        .iter_options()
+        .any(|o| matches!(o, TcpOptions2::WindowScale(_))));
+
+    // Its handshake completes all the same.
+    advance_clock(Some(&mut server), Some(&mut client), &mut now);
+    bytes = connection_setup_syn_sent_established(&mut client, bytes);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    connection_setup_sync_rcvd_established(&mut server, bytes);
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+
+    let stats = server.stats();
+    assert_eq!(stats.tcp.syncookies_sent, 1);
+    assert_eq!(stats.tcp.syncookies_recv, 1);
+    assert_eq!(stats.tcp.syncookies_failed, 0);
+    assert_eq!(stats.tcp.passive_opens, 2);
+}
+
+/// Tests that a client sends data on the SYN once the server granted it a TCP Fast Open cookie,
+/// and that the server hands the data over along with the connection.
+#[test]
+fn test_fast_open() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers, both with TCP Fast Open.
+    let mut server = test_helpers::new_bob2(now);
+    let mut client = test_helpers::new_alice2(now);
+    let options = server.tcp_options().set_fast_open(true);
+    server.set_tcp_options(options);
+    let options = client.tcp_options().set_fast_open(true);
+    client.set_tcp_options(options);
+    let socket_fd: FileDescriptor = server.tcp_socket().unwrap();
+    server.tcp_bind(socket_fd, listen_addr).unwrap();
+    server.tcp_listen(socket_fd, 4).unwrap();
+
+    // The first connection asks for a cookie, which the SYN+ACK grants.
+    let mut accept_future: AcceptFuture<TestRuntime> = server.tcp_accept(socket_fd);
+    let (_, mut connect_future, mut bytes): (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) =
+        connection_setup_listen_syn_sent(&mut client, listen_addr);
+    let (_, _, tcp_header): (Ethernet2Header, Ipv4Header, TcpHeader) =
+        extract_headers(bytes.clone());
+    assert_eq!(tcp_header.fast_open_cookie(), Some(&[][..]));
+    bytes = connection_setup_listen_syn_rcvd(&mut server, bytes);
+    let (_, _, tcp_header): (Ethernet2Header, Ipv4Header, TcpHeader) =
+        extract_headers(bytes.clone());
+    let cookie: Vec<u8> = tcp_header.fast_open_cookie().unwrap().to_vec();
+    assert_eq!(cookie.len(), 8);
+    bytes = connection_setup_syn_sent_established(&mut client, bytes);
+    connection_setup_sync_rcvd_established(&mut server, bytes);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+
+    // The next one sends its data on the SYN, which the SYN+ACK acknowledges.
+    let mut accept_future: AcceptFuture<TestRuntime> = server.tcp_accept(socket_fd);
+    let client_fd: FileDescriptor = client.tcp_socket().unwrap();
+    let data: Bytes = Bytes::from_slice(&[0x5a; 100]);
+    let mut connect_future: ConnectFuture<TestRuntime> =
+        client.tcp_connect_with_data(client_fd, listen_addr, data);
+    client.rt().poll_scheduler();
+    bytes = client.rt().pop_frame();
+    let (_, _, tcp_header): (Ethernet2Header, Ipv4Header, TcpHeader) =
+        extract_headers(bytes.clone());
+    assert_eq!(tcp_header.fast_open_cookie(), Some(&cookie[..]));
+    bytes = connection_setup_listen_syn_rcvd(&mut server, bytes);
+    let (_, _, tcp_header): (Ethernet2Header, Ipv4Header, TcpHeader) =
+        extract_headers(bytes.clone());
+    assert_eq!(tcp_header.ack_num, Wrapping(101));
+    bytes = connection_setup_syn_sent_established(&mut client, bytes);
+    connection_setup_sync_rcvd_established(&mut server, bytes);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    must_let!(let Poll::Ready(Ok((server_fd, _))) = Future::poll(Pin::new(&mut accept_future), &mut ctx));
+
+    // The data is there to read without further segments.
+    assert!(client.rt().pop_frame_unchecked().is_none());
+    let mut pop_future = server.tcp_pop(server_fd);
+    must_let!(let Poll::Ready(Ok(buf)) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(&buf[..], &[0x5a; 100][..]);
+
+    let client_stats = client.stats();
+    assert_eq!(client_stats.tcp.fast_open_cookie_reqs, 1);
+    assert_eq!(client_stats.tcp.fast_open_active, 1);
+    assert_eq!(client_stats.tcp.fast_open_active_fails, 0);
+    let server_stats = server.stats();
+    assert_eq!(server_stats.tcp.fast_open_passive, 1);
+    assert_eq!(server_stats.tcp.fast_open_passive_fails, 0);
+}
+
+/// Tests that SYNs beyond the connection limit of a remote are answered with a RST.
+#[test]
+fn test_connection_limit_per_remote() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers, with a server that accepts one connection per remote.
+    let mut server = test_helpers::new_bob2(now);
+    let mut client = test_helpers::new_alice2(now);
+    let options = server.tcp_options().set_connection_limits(None, Some(1));
+    server.set_tcp_options(options);
+    connection_setup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        listen_port,
+        listen_addr,
+    );
+
+    // A second connection from the same remote is refused.
+    let (_, _, bytes): (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) =
+        connection_setup_listen_syn_sent(&mut client, listen_addr);
+    server.receive(bytes).unwrap();
+    let (_, _, tcp_header): (Ethernet2Header, Ipv4Header, TcpHeader) =
+        extract_headers(server.rt().pop_frame());
+    assert!(tcp_header.rst);
+
+    let stats = server.stats();
+    assert_eq!(stats.tcp.limit_rejects, 1);
+    assert_eq!(stats.tcp.out_rsts, 1);
+    assert_eq!(stats.tcp.passive_opens, 1);
+}
+
+/// Tests that sockets bound before connecting send their SYN from the bound endpoint.
+#[test]
+fn test_bind_before_connect() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+    let local_port: ip::Port = ip::Port::try_from(1234).unwrap();
+    let local_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, local_port);
+
+    let mut client = test_helpers::new_alice2(now);
+    let fd: FileDescriptor = client.tcp_socket().unwrap();
+    client.tcp_bind(fd, local_addr).unwrap();
+    let mut connect_future = client.tcp_connect(fd, listen_addr);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    client.rt().poll_scheduler();
+    let (_, ipv4_header, tcp_header): (Ethernet2Header, Ipv4Header, TcpHeader) =
+        extract_headers(client.rt().pop_frame());
+    assert!(tcp_header.syn);
+    assert_eq!(ipv4_header.src_addr, test_helpers::ALICE_IPV4);
+    assert_eq!(tcp_header.src_port, local_port);
+
+    // The same endpoints can't be used twice.
+    let fd: FileDescriptor = client.tcp_socket().unwrap();
+    let mut connect_future = client.tcp_connect_from(fd, local_addr, listen_addr);
+    must_let!(let Poll::Ready(Err(Fail::AddressInUse {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // Nor can addresses the engine doesn't have.
+    let fd: FileDescriptor = client.tcp_socket().unwrap();
+    let other_addr = ipv4::Endpoint::new(test_helpers::CARRIE_IPV4, local_port);
+    let mut connect_future = client.tcp_connect_from(fd, other_addr, listen_addr);
+    must_let!(let Poll::Ready(Err(Fail::AddressNotAvailable {})) = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+
+    // Sockets bound to the unspecified address connect from the engine's, and may pick a port in
+    // the private range, which only listening sockets are kept out of.
+    let private_port: ip::Port = ip::Port::try_from(50000).unwrap();
+    let wildcard_addr = ipv4::Endpoint::new(Ipv4Addr::UNSPECIFIED, private_port);
+    let fd: FileDescriptor = client.tcp_socket().unwrap();
+    let mut connect_future = client.tcp_connect_from(fd, wildcard_addr, listen_addr);
+    must_let!(let Poll::Pending = Future::poll(Pin::new(&mut connect_future), &mut ctx));
+    client.rt().poll_scheduler();
+    let (_, ipv4_header, tcp_header): (Ethernet2Header, Ipv4Header, TcpHeader) =
+        extract_headers(client.rt().pop_frame());
+    assert!(tcp_header.syn);
+    assert_eq!(ipv4_header.src_addr, test_helpers::ALICE_IPV4);
+    assert_eq!(tcp_header.src_port, private_port);
+
+    let fd: FileDescriptor = client.tcp_socket().unwrap();
+    let private_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, private_port);
+    client.tcp_bind(fd, private_addr).unwrap();
+    must_let!(let Err(Fail::Malformed { .. }) = client.tcp_listen(fd, 1));
+}
+
+/// Tests that idle connections make room for new ones once the high water mark is reached.
+#[test]
+fn test_idle_reaping() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let listen_port: ip::Port = ip::Port::try_from(80).unwrap();
+    let listen_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    // Setup peers, with a server that holds a single connection and aborts it after 5s idle.
+    let mut server = test_helpers::new_bob2(now);
+    let mut client = test_helpers::new_alice2(now);
+    let options = server
+        .tcp_options()
+        .set_connection_limits(Some(1), None)
+        .set_idle_reaping(Some(IdleReaping {
+            idle_timeout: Duration::from_secs(5),
+            high_water: 1,
+            action: ReapAction::Abort,
+        }));
+    server.set_tcp_options(options);
+    let reasons: Rc<RefCell<Vec<CloseReason>>> = Rc::new(RefCell::new(Vec::new()));
+    let log = reasons.clone();
+    server.tcp_subscribe_close(move |s| log.borrow_mut().push(s.reason));
+    connection_setup(
+        &mut ctx,
+        &mut now,
+        &mut server,
+        &mut client,
+        listen_port,
+        listen_addr,
+    );
+
+    // Not idle for long enough yet: the limit applies.
+    let (_, _, bytes): (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) =
+        connection_setup_listen_syn_sent(&mut client, listen_addr);
+    server.receive(bytes).unwrap();
+    let (_, _, tcp_header): (Ethernet2Header, Ipv4Header, TcpHeader) =
+        extract_headers(server.rt().pop_frame());
+    assert!(tcp_header.rst);
+    assert_eq!(server.stats().tcp.limit_rejects, 1);
+
+    // The idle connection gets aborted in the background, making room for a new one.
+    for _ in 0..5 {
+        advance_clock(Some(&mut server), Some(&mut client), &mut now);
+    }
+    server.rt().poll_scheduler();
+    let (_, _, tcp_header): (Ethernet2Header, Ipv4Header, TcpHeader) =
+        extract_headers(server.rt().pop_frame());
+    assert!(tcp_header.rst);
+
+    let (_, _, bytes): (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) =
+        connection_setup_listen_syn_sent(&mut client, listen_addr);
+    server.receive(bytes).unwrap();
+    let (_, _, tcp_header): (Ethernet2Header, Ipv4Header, TcpHeader) =
+        extract_headers(server.rt().pop_frame());
+    assert!(tcp_header.syn && tcp_header.ack);
+
+    assert_eq!(&*reasons.borrow(), &[CloseReason::Idle]);
+    let stats = server.stats();
+    assert_eq!(stats.tcp.idle_reaped, 1);
+    assert_eq!(stats.tcp.limit_rejects, 1);
+}
+
+/// Tests that RSTs to a port scan are rate limited.
+#[test]
+fn test_rst_rate_limit() {
+    let mut now = Instant::now();
+
+    // Connection parameters
+    let closed_port: ip::Port = ip::Port::try_from(81).unwrap();
+    let closed_addr: ipv4::Endpoint = ipv4::Endpoint::new(test_helpers::BOB_IPV4, closed_port);
+
+    // Setup peers, with a server that sends one RST per second, in bursts of two.
+    let mut server = test_helpers::new_bob2(now);
+    let mut client = test_helpers::new_alice2(now);
+    let options = server.tcp_options().set_error_segment_rate(1, 2);
+    server.set_tcp_options(options);
+
+    // Only the first two SYNs to the closed port get a RST.
+    for _ in 0..3 {
+        let (_, _, bytes): (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) =
+            connection_setup_listen_syn_sent(&mut client, closed_addr);
+        server.receive(bytes).unwrap();
+    }
+    for _ in 0..2 {
+        let (_, _, tcp_header): (Ethernet2Header, Ipv4Header, TcpHeader) =
+            extract_headers(server.rt().pop_frame());
+        assert!(tcp_header.rst);
+    }
+    assert!(server.rt().pop_frame_unchecked().is_none());
+
+    // The budget refills over time.
+    advance_clock(Some(&mut server), Some(&mut client), &mut now);
+    let (_, _, bytes): (FileDescriptor, ConnectFuture<TestRuntime>, Bytes) =
+        connection_setup_listen_syn_sent(&mut client, closed_addr);
+    server.receive(bytes).unwrap();
+    let (_, _, tcp_header): (Ethernet2Header, Ipv4Header, TcpHeader) =
+        extract_headers(server.rt().pop_frame());
+    assert!(tcp_header.rst);
+
+    let stats = server.stats();
+    assert_eq!(stats.tcp.out_rsts, 3);
+    assert_eq!(stats.tcp.error_segments_throttled, 1);
 }
@@ -0,0 +1,268 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use crate::{
+    collections::bytes::{Bytes, BytesMut},
+    engine::Engine,
+    fail::Fail,
+    protocols::{
+        ip, ipv4,
+        tcp::{tests::WakeCount, TcpListener, TcpStream},
+    },
+    runtime::Runtime,
+    test_helpers::{self, TestRuntime},
+};
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    pin_mut,
+    task::noop_waker_ref,
+};
+use must_let::must_let;
+use std::{
+    convert::TryFrom,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+//=============================================================================
+
+/// Cooks a buffer.
+fn cook_buffer(size: usize) -> Bytes {
+    let mut buf = BytesMut::zeroed(size).unwrap();
+    for i in 0..size {
+        buf[i] = i as u8;
+    }
+    buf.freeze()
+}
+
+/// Connects a [TcpStream] to a [TcpListener].
+fn connect_streams(
+    ctx: &mut Context,
+    now: Instant,
+) -> (
+    Engine<TestRuntime>,
+    Engine<TestRuntime>,
+    TcpStream<TestRuntime>,
+    TcpStream<TestRuntime>,
+) {
+    let mut client = test_helpers::new_alice2(now);
+    let mut server = test_helpers::new_bob2(now);
+
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listener = TcpListener::bind(&server.tcp_peer(), listen_addr, 1).unwrap();
+    let accept_future = listener.accept();
+    pin_mut!(accept_future);
+    let connect_future = TcpStream::connect(&client.tcp_peer(), listen_addr);
+    pin_mut!(connect_future);
+
+    // SYN, SYN+ACK, ACK.
+    client.rt().poll_scheduler();
+    server.receive(client.rt().pop_frame()).unwrap();
+    server.rt().poll_scheduler();
+    client.receive(server.rt().pop_frame()).unwrap();
+    client.rt().poll_scheduler();
+    server.receive(client.rt().pop_frame()).unwrap();
+    server.rt().poll_scheduler();
+
+    must_let!(let Poll::Ready(Ok(client_stream)) = Future::poll(connect_future.as_mut(), ctx));
+    must_let!(let Poll::Ready(Ok(server_stream)) = Future::poll(accept_future.as_mut(), ctx));
+    assert_eq!(client_stream.peer_addr().unwrap(), listen_addr);
+    assert_eq!(
+        server_stream.peer_addr().unwrap(),
+        client_stream.local_addr().unwrap()
+    );
+
+    (client, server, client_stream, server_stream)
+}
+
+//=============================================================================
+
+/// Exchanges data over a [TcpStream].
+#[test]
+fn test_stream_send_recv() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let (client, mut server, client_stream, server_stream) =
+        connect_streams(&mut ctx, Instant::now());
+    let buf = cook_buffer(32);
+
+    let push_future = client_stream.send(buf.clone());
+    pin_mut!(push_future);
+    must_let!(let Poll::Ready(Ok(())) = Future::poll(push_future, &mut ctx));
+    server.receive(client.rt().pop_frame()).unwrap();
+
+    let pop_future = server_stream.recv();
+    pin_mut!(pop_future);
+    must_let!(let Poll::Ready(Ok(received)) = Future::poll(pop_future, &mut ctx));
+    assert_eq!(received, buf);
+}
+
+//=============================================================================
+
+/// Fails to connect a [TcpStream] to a port nobody listens on, which closes its socket.
+#[test]
+fn test_stream_connect_refused() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut client = test_helpers::new_alice2(now);
+    let mut server = test_helpers::new_bob2(now);
+    let remote_port = ip::Port::try_from(80).unwrap();
+    let remote = ipv4::Endpoint::new(test_helpers::BOB_IPV4, remote_port);
+
+    let connect_future = TcpStream::connect(&client.tcp_peer(), remote);
+    pin_mut!(connect_future);
+    assert_eq!(client.connections().len(), 1);
+
+    // SYN, RST.
+    client.rt().poll_scheduler();
+    server.receive(client.rt().pop_frame()).unwrap();
+    client.receive(server.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::ConnectionRefused {})) = Future::poll(connect_future.as_mut(), &mut ctx));
+    assert!(client.connections().is_empty());
+}
+
+/// Drops a [TcpListener], which frees its socket and port: SYNs for the port get reset until
+/// another listener binds it.
+#[test]
+fn test_stream_listener_drop() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+    let mut client = test_helpers::new_alice2(now);
+    let mut server = test_helpers::new_bob2(now);
+    let listen_port = ip::Port::try_from(80).unwrap();
+    let listen_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, listen_port);
+
+    let listener = TcpListener::bind(&server.tcp_peer(), listen_addr, 1).unwrap();
+    let accept_future = listener.accept();
+    pin_mut!(accept_future);
+    must_let!(let Poll::Pending = Future::poll(accept_future.as_mut(), &mut ctx));
+    drop(listener);
+    assert!(server.connections().is_empty());
+    must_let!(let Poll::Ready(Err(_)) = Future::poll(accept_future.as_mut(), &mut ctx));
+
+    // SYN, RST.
+    let connect_future = TcpStream::connect(&client.tcp_peer(), listen_addr);
+    pin_mut!(connect_future);
+    client.rt().poll_scheduler();
+    server.receive(client.rt().pop_frame()).unwrap();
+    client.receive(server.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Err(Fail::ConnectionRefused {})) = Future::poll(connect_future.as_mut(), &mut ctx));
+
+    // The port is free to listen on again.
+    let _listener = TcpListener::bind(&server.tcp_peer(), listen_addr, 1).unwrap();
+    let connect_future = TcpStream::connect(&client.tcp_peer(), listen_addr);
+    pin_mut!(connect_future);
+    client.rt().poll_scheduler();
+    server.receive(client.rt().pop_frame()).unwrap();
+    server.rt().poll_scheduler();
+    client.receive(server.rt().pop_frame()).unwrap();
+    client.rt().poll_scheduler();
+    must_let!(let Poll::Ready(Ok(_)) = Future::poll(connect_future.as_mut(), &mut ctx));
+}
+
+//=============================================================================
+
+/// Exchanges data over a [TcpStream] through the [AsyncRead] and [AsyncWrite] traits, reading it
+/// back in chunks smaller than the segment that carried it.
+#[test]
+fn test_stream_async_read_write() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let (client, mut server, mut client_stream, mut server_stream) =
+        connect_streams(&mut ctx, Instant::now());
+    let buf = cook_buffer(32);
+
+    must_let!(let Poll::Ready(Ok(32)) = Pin::new(&mut client_stream).poll_write(&mut ctx, &buf[..]));
+    server.receive(client.rt().pop_frame()).unwrap();
+
+    let mut received = [0u8; 20];
+    must_let!(let Poll::Ready(Ok(20)) = Pin::new(&mut server_stream).poll_read(&mut ctx, &mut received));
+    assert_eq!(&received[..], &buf[..20]);
+    must_let!(let Poll::Ready(Ok(12)) = Pin::new(&mut server_stream).poll_read(&mut ctx, &mut received));
+    assert_eq!(&received[..12], &buf[20..]);
+    assert!(Pin::new(&mut server_stream)
+        .poll_read(&mut ctx, &mut received)
+        .is_pending());
+}
+
+//=============================================================================
+
+/// Writes to a [TcpStream] with a full unsent queue through [AsyncWrite], which waits for the
+/// queue to make room rather than fail.
+#[test]
+fn test_stream_async_write_full() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let (mut client, _server, mut client_stream, _server_stream) =
+        connect_streams(&mut ctx, Instant::now());
+    let options = client.tcp_options().set_max_unsent_segments(1);
+    client.set_tcp_options(options);
+    let buf = cook_buffer(4096);
+
+    // Writes larger than a segment wait in the unsent queue until it fills up.
+    for _ in 0..2 {
+        must_let!(let Poll::Ready(Ok(4096)) = Pin::new(&mut client_stream).poll_write(&mut ctx, &buf[..]));
+    }
+    let wakes = Arc::new(WakeCount(AtomicUsize::new(0)));
+    let waker = futures::task::waker(wakes.clone());
+    let mut write_ctx = Context::from_waker(&waker);
+    assert!(Pin::new(&mut client_stream)
+        .poll_write(&mut write_ctx, &buf[..])
+        .is_pending());
+
+    // The background sender sending segments makes room, waking the writer.
+    client.rt().poll_scheduler();
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+    must_let!(let Poll::Ready(Ok(4096)) = Pin::new(&mut client_stream).poll_write(&mut write_ctx, &buf[..]));
+}
+
+/// Writes to a [TcpStream] with little room left in its send buffer through [AsyncWrite], which
+/// takes as much as fits.
+#[test]
+fn test_stream_async_write_partial() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let (mut client, _server, mut client_stream, _server_stream) =
+        connect_streams(&mut ctx, Instant::now());
+    client
+        .tcp_set_send_buffer_size(client_stream.fd(), 6000)
+        .unwrap();
+    let buf = cook_buffer(4096);
+
+    must_let!(let Poll::Ready(Ok(4096)) = Pin::new(&mut client_stream).poll_write(&mut ctx, &buf[..]));
+    must_let!(let Poll::Ready(Ok(1904)) = Pin::new(&mut client_stream).poll_write(&mut ctx, &buf[..]));
+    assert!(Pin::new(&mut client_stream)
+        .poll_write(&mut ctx, &buf[..])
+        .is_pending());
+}
+
+//=============================================================================
+
+/// Reads the end of a [TcpStream] through [AsyncRead] once the remote closes it, but an error
+/// once it resets it.
+#[test]
+fn test_stream_async_read_eof() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let (mut client, mut server, mut client_stream, mut server_stream) =
+        connect_streams(&mut ctx, Instant::now());
+    let mut received = [0u8; 20];
+
+    // A FIN ends the stream.
+    must_let!(let Poll::Ready(Ok(())) = Pin::new(&mut client_stream).poll_close(&mut ctx));
+    client.rt().poll_scheduler();
+    server.receive(client.rt().pop_frame()).unwrap();
+    must_let!(let Poll::Ready(Ok(0)) = Pin::new(&mut server_stream).poll_read(&mut ctx, &mut received));
+
+    // A RST fails it.
+    server.tcp_abort(server_stream.fd()).unwrap();
+    while let Some(frame) = server.rt().pop_frame_unchecked() {
+        client.receive(frame).unwrap();
+    }
+    must_let!(let Poll::Ready(Err(e)) = Pin::new(&mut client_stream).poll_read(&mut ctx, &mut received));
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+}
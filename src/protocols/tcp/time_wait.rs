@@ -0,0 +1,246 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Connections we closed first, kept around after teardown so that stray segments of the old
+//! incarnation are not taken for a new connection on the same endpoints. The table only keeps
+//! what telling incarnations apart takes, is bounded, and lets a SYN reuse the endpoints of an
+//! entry when it can't be confused with the old incarnation, as in RFC 6191. A retransmitted FIN
+//! of the remote, meaning our last ACK was lost, is acknowledged again and restarts the timeout.
+
+use super::{
+    seq_gt,
+    table::ConnectionKey,
+    timestamp::{self, TimestampClock},
+    SeqNumber,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// What remains of a connection in TIME_WAIT.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimeWaitEntry {
+    /// When the connection entered TIME_WAIT.
+    pub since: Instant,
+    /// Sequence number following the FIN of the remote.
+    pub rcv_nxt: SeqNumber,
+    /// Sequence number following our FIN.
+    pub snd_nxt: SeqNumber,
+    /// Window advertised in our last ACK.
+    pub window_size: u16,
+    /// Latest timestamp value received from the remote, if it sent any.
+    pub ts_recent: Option<u32>,
+    /// Clock of our timestamps, if the connection used them.
+    pub ts_clock: Option<TimestampClock>,
+}
+
+/// Connections in TIME_WAIT, expiring after a timeout and evicted oldest first beyond a capacity.
+pub struct TimeWaitTable {
+    entries: HashMap<ConnectionKey, TimeWaitEntry>,
+    /// Keys in the order they were inserted, with the time they were. Keys removed or inserted
+    /// again are left behind and skipped when they come up.
+    order: VecDeque<(ConnectionKey, Instant)>,
+    capacity: usize,
+    timeout: Duration,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [TimeWaitEntry].
+impl TimeWaitEntry {
+    /// Returns whether a SYN with sequence number `seq_num` and timestamp value `timestamp` may
+    /// start a new connection on the endpoints of this entry. With timestamps on both
+    /// incarnations, the SYN must carry a later one; otherwise it must start past the sequence
+    /// space of the old incarnation.
+    pub fn allows_syn(&self, seq_num: SeqNumber, timestamp: Option<u32>) -> bool {
+        match (self.ts_recent, timestamp) {
+            (Some(recent), Some(ts)) => timestamp::before(recent, ts),
+            _ => seq_gt(seq_num, self.rcv_nxt),
+        }
+    }
+}
+
+/// Associate functions for [TimeWaitTable].
+impl TimeWaitTable {
+    pub fn new(capacity: usize, timeout: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            timeout,
+        }
+    }
+
+    /// Changes the capacity and timeout, which apply to entries as they are next looked at.
+    pub fn set_limits(&mut self, capacity: usize, timeout: Duration) {
+        self.capacity = capacity;
+        self.timeout = timeout;
+    }
+
+    /// Returns the entry for `key` unless it expired by `now`.
+    pub fn get(&mut self, key: &ConnectionKey, now: Instant) -> Option<&TimeWaitEntry> {
+        self.expire(now);
+        self.entries.get(key)
+    }
+
+    /// Returns whether there is an entry for `key` that did not expire by `now`.
+    pub fn contains(&self, key: &ConnectionKey, now: Instant) -> bool {
+        self.entries.get(key).map_or(false, |e| {
+            now.saturating_duration_since(e.since) < self.timeout
+        })
+    }
+
+    /// Restarts the timeout of the entry for `key` at `now`, as when the remote retransmits its
+    /// FIN. Returns whether there was an entry.
+    pub fn restart(&mut self, key: &ConnectionKey, now: Instant) -> bool {
+        match self.get(key, now) {
+            Some(&entry) => {
+                self.insert(
+                    *key,
+                    TimeWaitEntry {
+                        since: now,
+                        ..entry
+                    },
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, key: &ConnectionKey) -> Option<TimeWaitEntry> {
+        self.entries.remove(key)
+    }
+
+    /// Adds `entry` for `key`, evicting the oldest entries if the table is full. Returns how many
+    /// were evicted.
+    pub fn insert(&mut self, key: ConnectionKey, entry: TimeWaitEntry) -> usize {
+        self.expire(entry.since);
+        self.entries.remove(&key);
+        let mut evicted = 0;
+        while self.entries.len() >= self.capacity {
+            match self.pop_oldest() {
+                Some(_) => evicted += 1,
+                None => return evicted + 1,
+            }
+        }
+        self.entries.insert(key, entry);
+        self.order.push_back((key, entry.since));
+        evicted
+    }
+
+    /// Drops the entries older than the timeout.
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(key, since)) = self.order.front() {
+            if self.entries.get(&key).map(|e| e.since) != Some(since) {
+                // Left behind by a key removed or inserted again.
+                self.order.pop_front();
+                continue;
+            }
+            if now.saturating_duration_since(since) < self.timeout {
+                break;
+            }
+            self.pop_oldest();
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<ConnectionKey> {
+        while let Some((key, since)) = self.order.pop_front() {
+            if self.entries.get(&key).map(|e| e.since) == Some(since) {
+                self.entries.remove(&key);
+                return Some(key);
+            }
+        }
+        None
+    }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{TimeWaitEntry, TimeWaitTable};
+    use crate::{
+        protocols::{ip, ipv4},
+        test_helpers,
+    };
+    use std::{
+        convert::TryFrom,
+        num::Wrapping,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_time_wait_table() {
+        let endpoint = |addr, port| ipv4::Endpoint::new(addr, ip::Port::try_from(port).unwrap());
+        let local = endpoint(test_helpers::ALICE_IPV4, 80);
+        let key = |port| (local, endpoint(test_helpers::BOB_IPV4, port));
+        let now = Instant::now();
+        let entry = |secs| TimeWaitEntry {
+            since: now + Duration::from_secs(secs),
+            rcv_nxt: Wrapping(1000),
+            snd_nxt: Wrapping(1),
+            window_size: 0xffff,
+            ts_recent: None,
+            ts_clock: None,
+        };
+
+        let mut table = TimeWaitTable::new(2, Duration::from_secs(60));
+        assert_eq!(table.insert(key(1), entry(0)), 0);
+        assert_eq!(table.insert(key(2), entry(1)), 0);
+        // Full: the oldest entry makes room.
+        assert_eq!(table.insert(key(3), entry(2)), 1);
+        assert!(table.get(&key(1), now).is_none());
+        assert!(table.get(&key(2), now).is_some());
+        assert!(table.contains(&key(3), now));
+
+        // Entries expire after the timeout.
+        assert!(table.get(&key(2), now + Duration::from_secs(61)).is_none());
+        assert!(table.get(&key(3), now + Duration::from_secs(61)).is_some());
+        assert!(table.get(&key(3), now + Duration::from_secs(62)).is_none());
+
+        // A retransmitted FIN restarts the timeout.
+        assert_eq!(table.insert(key(4), entry(0)), 0);
+        assert!(table.restart(&key(4), now + Duration::from_secs(50)));
+        assert!(table.get(&key(4), now + Duration::from_secs(70)).is_some());
+        assert!(table.contains(&key(4), now + Duration::from_secs(109)));
+        assert!(table.get(&key(4), now + Duration::from_secs(110)).is_none());
+        assert!(!table.restart(&key(4), now + Duration::from_secs(110)));
+
+        // Nothing is kept without capacity.
+        table.set_limits(0, Duration::from_secs(60));
+        assert_eq!(table.insert(key(4), entry(70)), 1);
+        assert!(table.get(&key(4), now + Duration::from_secs(70)).is_none());
+    }
+
+    #[test]
+    fn test_time_wait_allows_syn() {
+        let mut entry = TimeWaitEntry {
+            since: Instant::now(),
+            rcv_nxt: Wrapping(1000),
+            snd_nxt: Wrapping(1),
+            window_size: 0xffff,
+            ts_recent: None,
+            ts_clock: None,
+        };
+        assert!(entry.allows_syn(Wrapping(1001), None));
+        assert!(!entry.allows_syn(Wrapping(1000), None));
+        assert!(!entry.allows_syn(Wrapping(10), Some(7)));
+
+        // Timestamps take precedence over sequence numbers when both sides have them.
+        entry.ts_recent = Some(u32::MAX);
+        assert!(entry.allows_syn(Wrapping(10), Some(3)));
+        assert!(!entry.allows_syn(Wrapping(2000), Some(u32::MAX)));
+        assert!(!entry.allows_syn(Wrapping(2000), Some(u32::MAX - 1)));
+        assert!(entry.allows_syn(Wrapping(2000), None));
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Clock behind the values of the timestamp option (RFC 7323), which the remote echoes back so
+//! that round-trip times can be measured on every acknowledgment and old duplicate segments told
+//! apart from new ones (PAWS).
+
+use std::time::{Duration, Instant};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Time after which the latest timestamp of an idle connection no longer tells old segments
+/// apart, as the clock of the remote may have wrapped around in the meantime (RFC 7323, section
+/// 5.5).
+pub const PAWS_IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+
+/// Bytes the timestamp option takes in a header, including the padding aligning it.
+pub const TIMESTAMP_OPTION_SIZE: usize = 12;
+
+/// Clock ticking once a millisecond, from a value chosen when it starts.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimestampClock {
+    start: Instant,
+    offset: u32,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [TimestampClock].
+impl TimestampClock {
+    /// Returns a clock reading zero at `start`.
+    pub fn new(start: Instant) -> Self {
+        Self::resume(0, start)
+    }
+
+    /// Returns a clock reading `value` at `now`, to carry on with the clock of a connection moved
+    /// from elsewhere.
+    pub fn resume(value: u32, now: Instant) -> Self {
+        Self {
+            start: now,
+            offset: value,
+        }
+    }
+
+    /// Returns the value of the clock at `now`.
+    pub fn value(&self, now: Instant) -> u32 {
+        let millis = now.saturating_duration_since(self.start).as_millis() as u32;
+        self.offset.wrapping_add(millis)
+    }
+
+    /// Returns the time elapsed since the clock read `value`, unless `value` is yet to come.
+    pub fn elapsed(&self, value: u32, now: Instant) -> Option<Duration> {
+        let millis = self.value(now).wrapping_sub(value);
+        if (millis as i32) < 0 {
+            return None;
+        }
+        Some(Duration::from_millis(millis as u64))
+    }
+}
+
+//==============================================================================
+// Standalone Functions
+//==============================================================================
+
+/// Returns whether timestamp `a` comes before timestamp `b`, modulo 2^32.
+pub fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::{before, TimestampClock};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_timestamp_clock() {
+        let start = Instant::now();
+        let clock = TimestampClock::new(start);
+        assert_eq!(clock.value(start), 0);
+        assert_eq!(clock.value(start + Duration::from_micros(2500)), 2);
+        assert_eq!(
+            clock.elapsed(1, start + Duration::from_millis(11)),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(clock.elapsed(12, start + Duration::from_millis(11)), None);
+
+        // A resumed clock wraps around.
+        let clock = TimestampClock::resume(u32::MAX, start);
+        assert_eq!(clock.value(start + Duration::from_millis(2)), 1);
+        assert_eq!(
+            clock.elapsed(u32::MAX - 1, start + Duration::from_millis(2)),
+            Some(Duration::from_millis(3))
+        );
+    }
+
+    #[test]
+    fn test_timestamp_before() {
+        assert!(before(1, 2));
+        assert!(!before(2, 2));
+        assert!(!before(3, 2));
+        assert!(before(u32::MAX, 0));
+        assert!(!before(0, u32::MAX));
+    }
+}
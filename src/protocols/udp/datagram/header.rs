@@ -12,7 +12,10 @@ use crate::{
 
 use byteorder::{ByteOrder, NetworkEndian};
 
-use std::convert::{TryFrom, TryInto};
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+};
 
 //==============================================================================
 // Constants & Structures
@@ -30,7 +33,8 @@ const UDP_HEADER_SIZE: usize = 8;
 /// - TODO: write unit test for checksum computation
 /// - TODO: write unit test for parsing/serializing
 ///
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UdpHeader {
     /// Port used on sender side (optional).
     src_port: Option<ip::Port>,
@@ -199,3 +203,15 @@ impl UdpHeader {
         !state as u16
     }
 }
+
+//==============================================================================
+// Trait Implementations
+//==============================================================================
+
+/// Formats the header as `5555 > 53: UDP`. A missing source port is shown as 0.
+impl fmt::Display for UdpHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let src_port: u16 = self.src_port.map(|p| p.into()).unwrap_or(0);
+        write!(f, "{} > {}: UDP", src_port, self.dst_port)
+    }
+}
@@ -8,6 +8,7 @@ use crate::{
     runtime::PacketBuf,
     runtime::RuntimeBuf,
 };
+use std::fmt;
 
 pub use header::UdpHeader;
 
@@ -30,7 +31,7 @@ pub struct UdpDatagram<T: RuntimeBuf> {
     udp_hdr: UdpHeader,
     /// Payload
     data: T,
-    /// Disable checksum?
+    /// Leave the IPv4 and UDP checksums to the network device?
     no_checksum: bool,
 }
 
@@ -91,6 +92,7 @@ impl<T: RuntimeBuf> PacketBuf<T> for UdpDatagram<T> {
         self.ipv4_hdr.serialize(
             &mut buf[cur_pos..(cur_pos + ipv4_hdr_size)],
             ipv4_payload_len,
+            self.no_checksum,
         );
         cur_pos += ipv4_hdr_size;
 
@@ -108,3 +110,19 @@ impl<T: RuntimeBuf> PacketBuf<T> for UdpDatagram<T> {
         Some(self.data)
     }
 }
+
+/// Formats the datagram the way tcpdump does, e.g. `IP 10.0.0.1.5555 > 10.0.0.2.53: UDP, length 32`.
+impl<T: RuntimeBuf> fmt::Display for UdpDatagram<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let src_port: u16 = self.udp_hdr.src_port().map(|p| p.into()).unwrap_or(0);
+        write!(
+            f,
+            "IP {}.{} > {}.{}: UDP, length {}",
+            self.ipv4_hdr.src_addr,
+            src_port,
+            self.ipv4_hdr.dst_addr,
+            self.udp_hdr.dest_port(),
+            self.data.len()
+        )
+    }
+}
@@ -3,7 +3,7 @@
 
 use crate::protocols::ipv4;
 
-use std::{collections::VecDeque, task::Waker};
+use std::{collections::VecDeque, ops::Deref, task::Waker};
 
 pub struct Listener<T> {
     buf: VecDeque<(Option<ipv4::Endpoint>, T)>,
@@ -42,6 +42,14 @@ impl<T> Listener<T> {
     }
 }
 
+/// Associate functions for [Listener] of byte buffers.
+impl<T: Deref<Target = [u8]>> Listener<T> {
+    /// Returns the number of bytes waiting to be popped.
+    pub fn queued_bytes(&self) -> usize {
+        self.buf.iter().map(|(_, data)| data.len()).sum()
+    }
+}
+
 //==============================================================================
 // Trait Implementations
 //==============================================================================
@@ -1,6 +1,8 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
+use crate::runtime::ChecksumOffload;
+
 //==============================================================================
 // Constants & Structures
 //==============================================================================
@@ -37,6 +39,14 @@ impl UdpOptions {
     pub fn tx_checksum(&self) -> bool {
         self.tx_checksum
     }
+
+    /// Offloads the checksums that `offload` says the network device takes care of, in addition
+    /// to those offloaded already.
+    pub fn with_checksum_offload(mut self, offload: ChecksumOffload) -> Self {
+        self.rx_checksum |= offload.rx;
+        self.tx_checksum |= offload.tx;
+        self
+    }
 }
 
 //==============================================================================
@@ -5,20 +5,24 @@ use super::{
     datagram::{UdpDatagram, UdpHeader},
     listener::Listener,
     operations::PopFuture,
+    options::UdpOptions,
     socket::Socket,
 };
 
 use crate::{
     fail::Fail,
     file_table::{File, FileDescriptor, FileTable},
+    netstat::{SocketInfo, SocketState},
     protocols::{
         arp,
         ethernet2::frame::{EtherType2, Ethernet2Header},
         ipv4,
         ipv4::datagram::{Ipv4Header, Ipv4Protocol2},
+        Protocol,
     },
-    runtime::Runtime,
+    runtime::{Resource, Runtime},
     scheduler::SchedulerHandle,
+    stats::{Counters, DropReason},
 };
 use futures::{channel::mpsc, stream::StreamExt};
 use std::{cell::RefCell, collections::HashMap, rc::Rc};
@@ -45,6 +49,8 @@ struct UdpPeerInner<RT: Runtime> {
     rt: RT,
     arp: arp::Peer<RT>,
     file_table: FileTable,
+    counters: Counters,
+    options: UdpOptions,
 
     sockets: HashMap<FileDescriptor, Socket>,
     bound: HashMap<ipv4::Endpoint, Rc<RefCell<Listener<RT::Buf>>>>,
@@ -69,6 +75,8 @@ impl<RT: Runtime> UdpPeerInner<RT> {
         rt: RT,
         arp: arp::Peer<RT>,
         file_table: FileTable,
+        counters: Counters,
+        options: UdpOptions,
         tx: OutgoingSender<RT::Buf>,
         handle: SchedulerHandle,
     ) -> Self {
@@ -76,6 +84,8 @@ impl<RT: Runtime> UdpPeerInner<RT> {
             rt,
             arp,
             file_table,
+            counters,
+            options,
             sockets: HashMap::new(),
             bound: HashMap::new(),
             outgoing: tx,
@@ -94,7 +104,7 @@ impl<RT: Runtime> UdpPeerInner<RT> {
         // operation to the async path.
         if let Some(link_addr) = self.arp.try_query(remote.addr) {
             let udp_header = UdpHeader::new(local.map(|l| l.port), remote.port);
-            debug!("UDP send {:?}", udp_header);
+            datapath!(Debug, "UDP send {:?}", udp_header);
             let datagram = UdpDatagram::new(
                 Ethernet2Header {
                     dst_addr: link_addr,
@@ -104,9 +114,13 @@ impl<RT: Runtime> UdpPeerInner<RT> {
                 Ipv4Header::new(self.rt.local_ipv4_addr(), remote.addr, Ipv4Protocol2::Udp),
                 udp_header,
                 buf,
-                self.rt.udp_options().tx_checksum(),
+                self.options.tx_checksum(),
             );
-            self.rt.transmit(datagram);
+            self.rt.try_transmit(datagram).map_err(|e| {
+                self.counters.count_transmit_drop(&e);
+                e
+            })?;
+            count_out_datagram(&self.counters);
         } else {
             self.outgoing.unbounded_send((local, remote, buf)).unwrap();
         }
@@ -117,20 +131,41 @@ impl<RT: Runtime> UdpPeerInner<RT> {
 /// Associate functions for [UdpPeer].
 impl<RT: Runtime> UdpPeer<RT> {
     /// Creates a Udp peer.
-    pub fn new(rt: RT, arp: arp::Peer<RT>, file_table: FileTable) -> Self {
+    pub fn new(
+        rt: RT,
+        arp: arp::Peer<RT>,
+        file_table: FileTable,
+        counters: Counters,
+        options: UdpOptions,
+    ) -> Self {
         let (tx, rx) = mpsc::unbounded();
-        let future = Self::background(rt.clone(), arp.clone(), rx);
+        let future = Self::background(
+            rt.clone(),
+            arp.clone(),
+            counters.clone(),
+            options.tx_checksum(),
+            rx,
+        );
         let handle = rt.spawn(future);
-        let inner = UdpPeerInner::new(rt, arp, file_table, tx, handle);
+        let inner = UdpPeerInner::new(rt, arp, file_table, counters, options, tx, handle);
         Self {
             inner: Rc::new(RefCell::new(inner)),
         }
     }
 
-    async fn background(rt: RT, arp: arp::Peer<RT>, mut rx: OutgoingReceiver<RT::Buf>) {
+    async fn background(
+        rt: RT,
+        arp: arp::Peer<RT>,
+        counters: Counters,
+        tx_checksum: bool,
+        mut rx: OutgoingReceiver<RT::Buf>,
+    ) {
         while let Some((local, remote, buf)) = rx.next().await {
             let r: Result<_, Fail> = try {
-                let link_addr = arp.query(remote.addr).await?;
+                let link_addr = arp.query(remote.addr).await.map_err(|e| {
+                    counters.count_drop(DropReason::UnresolvedArp);
+                    e
+                })?;
                 let datagram = UdpDatagram::new(
                     Ethernet2Header {
                         dst_addr: link_addr,
@@ -140,9 +175,13 @@ impl<RT: Runtime> UdpPeer<RT> {
                     Ipv4Header::new(rt.local_ipv4_addr(), remote.addr, Ipv4Protocol2::Udp),
                     UdpHeader::new(local.map(|l| l.port), remote.port),
                     buf,
-                    rt.udp_options().tx_checksum(),
+                    tx_checksum,
                 );
-                rt.transmit(datagram);
+                rt.try_transmit(datagram).map_err(|e| {
+                    counters.count_transmit_drop(&e);
+                    e
+                })?;
+                count_out_datagram(&counters);
             };
             if let Err(e) = r {
                 warn!("Failed to send UDP message: {:?}", e);
@@ -167,7 +206,12 @@ impl<RT: Runtime> UdpPeer<RT> {
         timer!("udp::socket");
 
         let mut inner = self.inner.borrow_mut();
-        let fd = inner.file_table.alloc(File::UdpSocket);
+        if inner.rt.exhausted(Resource::FileDescriptor) {
+            return Err(Fail::TooManyOpenedFiles {
+                details: "file table exhausted",
+            });
+        }
+        let fd = inner.file_table.alloc(File::UdpSocket, inner.rt.now());
         let socket = Socket::default();
         if inner.sockets.insert(fd, socket).is_some() {
             return Err(Fail::TooManyOpenedFiles {
@@ -252,18 +296,41 @@ impl<RT: Runtime> UdpPeer<RT> {
         #[cfg(feature = "profiler")]
         timer!("udp::receive");
 
-        let mut inner = self.inner.borrow_mut();
-        let (hdr, data) = UdpHeader::parse(ipv4_header, buf, inner.rt.udp_options().rx_checksum())?;
-        debug!("UDP received {:?}", hdr);
+        let inner = self.inner.borrow();
+        let (hdr, data) = match UdpHeader::parse(ipv4_header, buf, inner.options.rx_checksum()) {
+            Ok(r) => r,
+            Err(e) => {
+                inner.counters.update(|s| {
+                    s.udp.in_errors += 1;
+                    if let Fail::Malformed {
+                        details: "UDP checksum mismatch",
+                    } = e
+                    {
+                        s.udp.in_csum_errors += 1;
+                    }
+                });
+                inner.counters.count_parse_drop(&e);
+                return Err(e);
+            }
+        };
+        datapath!(Debug, "UDP received {:?}", hdr);
         let local = ipv4::Endpoint::new(ipv4_header.dst_addr, hdr.dest_port());
         let remote = hdr
             .src_port()
             .map(|p| ipv4::Endpoint::new(ipv4_header.src_addr, p));
 
         // TODO: Send ICMPv4 error in this condition.
-        let listener = inner.bound.get_mut(&local).ok_or(Fail::Malformed {
-            details: "Port not bound",
-        })?;
+        let listener = match inner.bound.get(&local) {
+            Some(l) => l,
+            None => {
+                inner.counters.update(|s| s.udp.no_ports += 1);
+                inner.counters.count_drop(DropReason::NoListener);
+                return Err(Fail::Malformed {
+                    details: "Port not bound",
+                });
+            }
+        };
+        inner.counters.update(|s| s.udp.in_datagrams += 1);
 
         // Consume data and wakeup receiver.
         let mut l = listener.borrow_mut();
@@ -322,4 +389,70 @@ impl<RT: Runtime> UdpPeer<RT> {
 
         PopFuture::new(fd, listener)
     }
+
+    /// Returns the local endpoint socket `fd` is bound to, as getsockname() does.
+    pub fn local_endpoint(&self, fd: FileDescriptor) -> Result<ipv4::Endpoint, Fail> {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd) {
+            Some(s) => s.local().ok_or(Fail::ResourceNotFound {
+                details: "Socket not bound",
+            }),
+            None => Err(Fail::BadFileDescriptor {}),
+        }
+    }
+
+    /// Returns the remote endpoint socket `fd` sends to by default, as getpeername() does.
+    pub fn remote_endpoint(&self, fd: FileDescriptor) -> Result<ipv4::Endpoint, Fail> {
+        let inner = self.inner.borrow();
+        match inner.sockets.get(&fd) {
+            Some(s) => s.remote().ok_or(Fail::ResourceNotFound {
+                details: "Socket not connected",
+            }),
+            None => Err(Fail::BadFileDescriptor {}),
+        }
+    }
+
+    /// Describes every socket of this peer.
+    pub fn sockets(&self) -> Vec<SocketInfo> {
+        let inner = self.inner.borrow();
+        let now = inner.rt.now();
+        inner
+            .sockets
+            .iter()
+            .map(|(&fd, socket)| {
+                let (state, recv_queue) = match socket.local() {
+                    Some(local) => {
+                        let listener = inner.bound.get(&local).unwrap();
+                        (SocketState::Bound, listener.borrow().queued_bytes())
+                    }
+                    None => (SocketState::Idle, 0),
+                };
+                let opened = inner.file_table.opened(fd).unwrap_or(now);
+                SocketInfo {
+                    fd,
+                    protocol: Protocol::Udp,
+                    local: socket.local(),
+                    remote: socket.remote(),
+                    state,
+                    send_queue: 0,
+                    recv_queue,
+                    age: now.saturating_duration_since(opened),
+                    rto: None,
+                    srtt: None,
+                }
+            })
+            .collect()
+    }
+}
+
+//==============================================================================
+// Standalone Functions
+//==============================================================================
+
+/// Counts a datagram handed over to the runtime.
+fn count_out_datagram(counters: &Counters) {
+    counters.update(|s| {
+        s.ip.out_requests += 1;
+        s.udp.out_datagrams += 1;
+    });
 }
@@ -3,17 +3,30 @@
 
 use crate::{
     collections::bytes::BytesMut,
+    engine::Engine,
     fail::Fail,
     file_table::FileDescriptor,
-    protocols::{ip, ipv4},
-    test_helpers,
+    filter::{Rule, Verdict},
+    protocols::{
+        ethernet2::Ethernet2Header,
+        icmpv4::{self, Icmpv4Header},
+        ip,
+        ipv4::{self, Ipv4Header, Ipv4Protocol2},
+        Protocol,
+    },
+    runtime::{ChecksumOffload, Resource},
+    stats::DropReason,
+    tap::Direction,
+    test_helpers, trace,
 };
 use futures::task::{noop_waker_ref, Context};
 use must_let::must_let;
 use std::{
+    cell::RefCell,
     convert::TryFrom,
     future::Future,
     pin::Pin,
+    rc::Rc,
     task::Poll,
     time::{Duration, Instant},
 };
@@ -31,7 +44,10 @@ fn udp_bind_close() {
     let alice_port = ip::Port::try_from(80).unwrap();
     let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, alice_port);
     let alice_fd: FileDescriptor = alice.udp_socket().unwrap();
+    must_let!(let Err(Fail::ResourceNotFound { .. }) = alice.udp_local_endpoint(alice_fd));
     alice.udp_bind(alice_fd, alice_addr).unwrap();
+    assert_eq!(alice.udp_local_endpoint(alice_fd), Ok(alice_addr));
+    must_let!(let Err(Fail::ResourceNotFound { .. }) = alice.udp_remote_endpoint(alice_fd));
 
     // Setup Bob.
     let mut bob = test_helpers::new_bob2(now);
@@ -53,94 +69,207 @@ fn udp_bind_close() {
 
 #[test]
 fn udp_push_pop() {
-    let mut ctx = Context::from_waker(noop_waker_ref());
-    let mut now = Instant::now();
+    let now = Instant::now();
 
-    // Setup Alice.
+    // Setup peers.
     let mut alice = test_helpers::new_alice2(now);
-    let alice_port = ip::Port::try_from(80).unwrap();
-    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, alice_port);
-    let alice_fd: FileDescriptor = alice.udp_socket().unwrap();
-    alice.udp_bind(alice_fd, alice_addr).unwrap();
+    let (alice_fd, alice_addr) = test_helpers::udp_socket_setup(&mut alice, 80);
+    let mut bob = test_helpers::new_bob2(now);
+    let (bob_fd, bob_addr) = test_helpers::udp_socket_setup(&mut bob, 80);
 
-    // Setup Bob.
+    // Send data to Bob.
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    test_helpers::udp_send_recv_round(
+        &mut alice, alice_fd, alice_addr, &mut bob, bob_fd, bob_addr, buf,
+    );
+
+    // Close peers.
+    alice.close(alice_fd).unwrap();
+    bob.close(bob_fd).unwrap();
+}
+
+/// Tests that datagrams are counted on both ends.
+#[test]
+fn udp_stats() {
+    let now = Instant::now();
+
+    // Setup peers.
+    let mut alice = test_helpers::new_alice2(now);
+    let (alice_fd, alice_addr) = test_helpers::udp_socket_setup(&mut alice, 80);
     let mut bob = test_helpers::new_bob2(now);
-    let bob_port = ip::Port::try_from(80).unwrap();
-    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, bob_port);
-    let bob_fd: FileDescriptor = bob.udp_socket().unwrap();
-    bob.udp_bind(bob_fd, bob_addr).unwrap();
+    let (bob_fd, bob_addr) = test_helpers::udp_socket_setup(&mut bob, 80);
 
     // Send data to Bob.
     let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
-    alice.udp_pushto(alice_fd, buf.clone(), bob_addr).unwrap();
-    alice.rt().poll_scheduler();
+    test_helpers::udp_send_recv_round(
+        &mut alice,
+        alice_fd,
+        alice_addr,
+        &mut bob,
+        bob_fd,
+        bob_addr,
+        buf.clone(),
+    );
 
-    now += Duration::from_micros(1);
+    // Send data to a port Bob is not bound to.
+    let unbound_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(81).unwrap());
+    let bytes = test_helpers::udp_send(
+        &mut alice,
+        alice_fd,
+        alice_addr,
+        unbound_addr,
+        test_helpers::BOB_MAC,
+        buf,
+    );
+    must_let!(let Err(Fail::Malformed { details: "Port not bound" }) = bob.receive(bytes));
+
+    let alice_stats = alice.stats();
+    assert_eq!(alice_stats.udp.out_datagrams, 2);
+    assert_eq!(alice_stats.ip.out_requests, 2);
+    let bob_stats = bob.stats();
+    assert_eq!(bob_stats.ip.in_receives, 2);
+    assert_eq!(bob_stats.ip.in_delivers, 2);
+    assert_eq!(bob_stats.udp.in_datagrams, 1);
+    assert_eq!(bob_stats.udp.no_ports, 1);
+    assert_eq!(bob_stats.drops.get(DropReason::NoListener), 1);
+    assert_eq!(bob_stats.drops.total(), 1);
+
+    // Alice polled her scheduler once per datagram, running her background tasks the first time.
+    let scheduler_stats = alice.scheduler_stats();
+    assert_eq!(scheduler_stats.ticks, 2);
+    assert!(scheduler_stats.polls > 0);
+    assert!(alice.timer_stats().is_some());
 
-    // Receive data from Alice.
-    bob.receive(alice.rt().pop_frame()).unwrap();
-    let mut pop_future = bob.udp_pop(bob_fd);
-    must_let!(let Poll::Ready(Ok((Some(remote_addr), received_buf))) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
-    assert_eq!(remote_addr, alice_addr);
-    assert_eq!(received_buf, buf);
+    // Close peers.
+    alice.close(alice_fd).unwrap();
+    bob.close(bob_fd).unwrap();
+}
+
+/// Tests that taps see frames on both ends, with their direction.
+#[test]
+fn udp_tap() {
+    let now = Instant::now();
+
+    // Setup peers.
+    let mut alice = test_helpers::new_alice2(now);
+    let (alice_fd, alice_addr) = test_helpers::udp_socket_setup(&mut alice, 80);
+    let mut bob = test_helpers::new_bob2(now);
+    let (bob_fd, bob_addr) = test_helpers::udp_socket_setup(&mut bob, 80);
+
+    let alice_frames: Rc<RefCell<Vec<(Direction, Instant, Vec<u8>)>>> = Rc::default();
+    let frames = alice_frames.clone();
+    alice
+        .tap(move |f| {
+            frames
+                .borrow_mut()
+                .push((f.direction, f.timestamp, f.data.to_vec()))
+        })
+        .unwrap();
+    let bob_frames: Rc<RefCell<Vec<(Direction, Instant, Vec<u8>)>>> = Rc::default();
+    let frames = bob_frames.clone();
+    bob.tap(move |f| {
+        frames
+            .borrow_mut()
+            .push((f.direction, f.timestamp, f.data.to_vec()))
+    })
+    .unwrap();
+
+    // Send data to Bob.
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    test_helpers::udp_send_recv_round(
+        &mut alice, alice_fd, alice_addr, &mut bob, bob_fd, bob_addr, buf,
+    );
+
+    let alice_frames = alice_frames.borrow();
+    let bob_frames = bob_frames.borrow();
+    assert_eq!(alice_frames.len(), 1);
+    assert_eq!(bob_frames.len(), 1);
+    assert_eq!(alice_frames[0].0, Direction::Transmit);
+    assert_eq!(bob_frames[0].0, Direction::Receive);
+    assert_eq!(alice_frames[0].1, now);
+    assert_eq!(alice_frames[0].2, bob_frames[0].2);
 
     // Close peers.
     alice.close(alice_fd).unwrap();
     bob.close(bob_fd).unwrap();
 }
 
+/// Tests that traced frames are summarized and matched to their socket.
+#[test]
+fn udp_trace() {
+    let now = Instant::now();
+
+    // Setup peers.
+    let mut alice = test_helpers::new_alice2(now);
+    let (alice_fd, alice_addr) = test_helpers::udp_socket_setup(&mut alice, 80);
+    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+
+    // Unbound sockets cannot be traced.
+    let unbound_fd = alice.udp_socket().unwrap();
+    must_let!(let Err(Fail::Malformed { details: "Socket not bound" }) = alice.trace(unbound_fd, true));
+    alice.trace(alice_fd, true).unwrap();
+
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let bytes = test_helpers::udp_send(
+        &mut alice,
+        alice_fd,
+        alice_addr,
+        bob_addr,
+        test_helpers::BOB_MAC,
+        buf,
+    );
+    let summary = trace::summarize(&bytes).unwrap();
+    assert_eq!(summary.protocol, Protocol::Udp);
+    assert_eq!(summary.src, alice_addr);
+    assert_eq!(summary.dst, bob_addr);
+    assert_eq!(summary.detail, "len=32");
+
+    let filter = trace::TraceFilter {
+        protocol: Protocol::Udp,
+        local: alice_addr,
+        remote: None,
+    };
+    assert!(filter.matches(&summary));
+    let other = trace::TraceFilter {
+        local: bob_addr,
+        remote: Some(ipv4::Endpoint::new(
+            test_helpers::CARRIE_IPV4,
+            alice_addr.port,
+        )),
+        ..filter
+    };
+    assert!(!other.matches(&summary));
+
+    alice.trace(alice_fd, false).unwrap();
+    alice.close(alice_fd).unwrap();
+    alice.close(unbound_fd).unwrap();
+}
+
 //==============================================================================
 // Ping Pong
 //==============================================================================
 
 #[test]
 fn udp_ping_pong() {
-    let mut ctx = Context::from_waker(noop_waker_ref());
-    let mut now = Instant::now();
+    let now = Instant::now();
 
-    // Setup Alice.
+    // Setup peers.
     let mut alice = test_helpers::new_alice2(now);
-    let alice_port = ip::Port::try_from(80).unwrap();
-    let alice_addr = ipv4::Endpoint::new(test_helpers::ALICE_IPV4, alice_port);
-    let alice_fd: FileDescriptor = alice.udp_socket().unwrap();
-    alice.udp_bind(alice_fd, alice_addr).unwrap();
-
-    // Setup Bob.
+    let (alice_fd, alice_addr) = test_helpers::udp_socket_setup(&mut alice, 80);
     let mut bob = test_helpers::new_bob2(now);
-    let bob_port = ip::Port::try_from(80).unwrap();
-    let bob_addr = ipv4::Endpoint::new(test_helpers::BOB_IPV4, bob_port);
-    let bob_fd: FileDescriptor = bob.udp_socket().unwrap();
-    bob.udp_bind(bob_fd, bob_addr).unwrap();
+    let (bob_fd, bob_addr) = test_helpers::udp_socket_setup(&mut bob, 80);
 
     // Send data to Bob.
     let buf_a = BytesMut::from(&vec![0x5a; 32][..]).freeze();
-    alice.udp_pushto(alice_fd, buf_a.clone(), bob_addr).unwrap();
-    alice.rt().poll_scheduler();
-
-    now += Duration::from_micros(1);
-
-    // Receive data from Alice.
-    bob.receive(alice.rt().pop_frame()).unwrap();
-    let mut pop_future = bob.udp_pop(bob_fd);
-    must_let!(let Poll::Ready(Ok((Some(remote_addr), received_buf_a))) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
-    assert_eq!(remote_addr, alice_addr);
-    assert_eq!(received_buf_a, buf_a);
-
-    now += Duration::from_micros(1);
+    test_helpers::udp_send_recv_round(
+        &mut alice, alice_fd, alice_addr, &mut bob, bob_fd, bob_addr, buf_a,
+    );
 
     // Send data to Alice.
-    let buf_b = BytesMut::from(&vec![0x5a; 32][..]).freeze();
-    bob.udp_pushto(bob_fd, buf_b.clone(), alice_addr).unwrap();
-    bob.rt().poll_scheduler();
-
-    now += Duration::from_micros(1);
-
-    // Receive data from Bob.
-    alice.receive(bob.rt().pop_frame()).unwrap();
-    let mut pop_future = alice.udp_pop(alice_fd);
-    must_let!(let Poll::Ready(Ok((Some(remote_addr), received_buf_b))) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
-    assert_eq!(remote_addr, bob_addr);
-    assert_eq!(received_buf_b, buf_b);
+    let buf_b = BytesMut::from(&vec![0xa5; 32][..]).freeze();
+    test_helpers::udp_send_recv_round(
+        &mut bob, bob_fd, bob_addr, &mut alice, alice_fd, alice_addr, buf_b,
+    );
 
     // Close peers.
     alice.close(alice_fd).unwrap();
@@ -453,3 +582,210 @@ fn udp_push_bad_file_descriptor() {
     alice.close(alice_fd).unwrap();
     bob.close(bob_fd).unwrap();
 }
+
+//==============================================================================
+// Resource Exhaustion
+//==============================================================================
+
+#[test]
+fn udp_socket_file_table_exhausted() {
+    let now = Instant::now();
+
+    // Setup Alice with no file descriptor left.
+    let mut alice = test_helpers::new_alice2(now);
+    alice
+        .rt()
+        .inject_exhaustion(Resource::FileDescriptor, 0, usize::MAX);
+    must_let!(let Err(err) = alice.udp_socket());
+    assert_eq!(
+        err,
+        Fail::TooManyOpenedFiles {
+            details: "file table exhausted",
+        }
+    );
+
+    // Sockets can be opened again once descriptors are released.
+    alice.rt().clear_exhaustion();
+    let alice_fd: FileDescriptor = alice.udp_socket().unwrap();
+    alice.close(alice_fd).unwrap();
+}
+
+#[test]
+fn udp_pushto_transmit_queue_full() {
+    let now = Instant::now();
+
+    // Setup peers.
+    let mut alice = test_helpers::new_alice2(now);
+    let (alice_fd, alice_addr) = test_helpers::udp_socket_setup(&mut alice, 80);
+    let mut bob = test_helpers::new_bob2(now);
+    let (bob_fd, bob_addr) = test_helpers::udp_socket_setup(&mut bob, 80);
+
+    // The first datagram does not fit in the transmit queue.
+    alice.rt().inject_exhaustion(Resource::TransmitQueue, 0, 1);
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    must_let!(let Err(err) = alice.udp_pushto(alice_fd, buf.clone(), bob_addr));
+    assert_eq!(
+        err,
+        Fail::ResourceBusy {
+            details: "transmit queue full",
+        }
+    );
+    alice.rt().poll_scheduler();
+    assert!(alice.rt().pop_frame_unchecked().is_none());
+
+    // The next one goes through.
+    test_helpers::udp_send_recv_round(
+        &mut alice, alice_fd, alice_addr, &mut bob, bob_fd, bob_addr, buf,
+    );
+
+    // Close peers.
+    alice.close(alice_fd).unwrap();
+    bob.close(bob_fd).unwrap();
+}
+
+#[test]
+fn udp_receive_buffer_exhausted() {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    let now = Instant::now();
+
+    // Setup peers.
+    let mut alice = test_helpers::new_alice2(now);
+    let (alice_fd, alice_addr) = test_helpers::udp_socket_setup(&mut alice, 80);
+    let mut bob = test_helpers::new_bob2(now);
+    let (bob_fd, bob_addr) = test_helpers::udp_socket_setup(&mut bob, 80);
+
+    // Bob has no buffer for the first datagram, which gets dropped.
+    bob.rt().inject_exhaustion(Resource::Buffer, 0, 1);
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    let bytes = test_helpers::udp_send(
+        &mut alice,
+        alice_fd,
+        alice_addr,
+        bob_addr,
+        test_helpers::BOB_MAC,
+        buf.clone(),
+    );
+    must_let!(let Err(err) = bob.receive(bytes.clone()));
+    assert_eq!(
+        err,
+        Fail::ResourceExhausted {
+            details: "no buffer for received frame",
+        }
+    );
+    let mut pop_future = bob.udp_pop(bob_fd);
+    assert!(Future::poll(Pin::new(&mut pop_future), &mut ctx).is_pending());
+
+    // A retransmission of the datagram is delivered.
+    bob.receive(bytes).unwrap();
+    must_let!(let Poll::Ready(Ok((Some(remote_addr), received_buf))) = Future::poll(Pin::new(&mut pop_future), &mut ctx));
+    assert_eq!(remote_addr, alice_addr);
+    assert_eq!(received_buf, buf);
+
+    // Close peers.
+    alice.close(alice_fd).unwrap();
+    bob.close(bob_fd).unwrap();
+}
+
+/// Tests that the packet filter drops and rejects datagrams in both directions.
+#[test]
+fn udp_packet_filter() {
+    let now = Instant::now();
+
+    // Setup peers.
+    let mut alice = test_helpers::new_alice2(now);
+    let (alice_fd, alice_addr) = test_helpers::udp_socket_setup(&mut alice, 80);
+    let mut bob = test_helpers::new_bob2(now);
+    let (bob_fd, bob_addr) = test_helpers::udp_socket_setup(&mut bob, 80);
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+
+    // Bob drops datagrams from Alice to his port 80.
+    let filter = bob.packet_filter().unwrap();
+    filter.push(
+        Rule::new(Verdict::Drop)
+            .direction(Direction::Receive)
+            .protocol(Protocol::Udp)
+            .src(test_helpers::ALICE_IPV4, 32)
+            .dst_port(80..=80),
+    );
+    let bytes = test_helpers::udp_send(
+        &mut alice,
+        alice_fd,
+        alice_addr,
+        bob_addr,
+        test_helpers::BOB_MAC,
+        buf.clone(),
+    );
+    must_let!(let Err(Fail::Ignored { .. }) = bob.receive(bytes));
+    assert_eq!(filter.hits(), vec![1]);
+    assert_eq!(bob.stats().udp.in_datagrams, 0);
+    assert_eq!(bob.stats().drops.get(DropReason::Filtered), 1);
+
+    // Then rejects datagrams from Alice's subnet, answering with a destination unreachable.
+    filter.clear();
+    filter.push(Rule::new(Verdict::Reject).src(test_helpers::ALICE_IPV4, 24));
+    let bytes = test_helpers::udp_send(
+        &mut alice,
+        alice_fd,
+        alice_addr,
+        bob_addr,
+        test_helpers::BOB_MAC,
+        buf.clone(),
+    );
+    must_let!(let Err(Fail::Ignored { .. }) = bob.receive(bytes.clone()));
+    let (_, ipv4_payload) = Ethernet2Header::parse(bob.rt().pop_frame()).unwrap();
+    let (ipv4_hdr, icmpv4_payload) = Ipv4Header::parse(ipv4_payload).unwrap();
+    assert_eq!(ipv4_hdr.protocol, Ipv4Protocol2::Icmpv4);
+    assert_eq!(ipv4_hdr.dst_addr, test_helpers::ALICE_IPV4);
+    let (icmpv4_hdr, quote) = Icmpv4Header::parse(icmpv4_payload).unwrap();
+    assert_eq!(icmpv4_hdr.code, icmpv4::UNREACHABLE_ADMIN_PROHIBITED);
+    assert_eq!(&quote[..], &bytes[14..14 + 20 + 8]);
+    assert_eq!(bob.stats().drops.get(DropReason::Filtered), 2);
+
+    // Alice refuses to send to Bob.
+    alice.packet_filter().unwrap().push(
+        Rule::new(Verdict::Reject)
+            .direction(Direction::Transmit)
+            .dst(test_helpers::BOB_IPV4, 32),
+    );
+    must_let!(let Err(Fail::ConnectionRefused {}) = alice.udp_pushto(alice_fd, buf, bob_addr));
+    assert_eq!(alice.stats().drops.get(DropReason::Filtered), 1);
+
+    // Close peers.
+    alice.close(alice_fd).unwrap();
+    bob.close(bob_fd).unwrap();
+}
+
+/// Tests that checksums offloaded to the network device are skipped in software.
+#[test]
+fn udp_checksum_offload() {
+    let now = Instant::now();
+    let offload = ChecksumOffload { rx: true, tx: true };
+
+    // Setup peers, with devices that take care of checksums.
+    let alice_rt = test_helpers::alice_runtime(now);
+    alice_rt.set_checksum_offload(offload);
+    let mut alice = Engine::new(alice_rt).unwrap();
+    let (alice_fd, alice_addr) = test_helpers::udp_socket_setup(&mut alice, 80);
+    let bob_rt = test_helpers::bob_runtime(now);
+    bob_rt.set_checksum_offload(offload);
+    let mut bob = Engine::new(bob_rt).unwrap();
+    let (bob_fd, bob_addr) = test_helpers::udp_socket_setup(&mut bob, 80);
+    assert!(bob.tcp_options().rx_checksum_offload());
+
+    // Alice leaves the IPv4 and UDP checksums blank.
+    let buf = BytesMut::from(&vec![0x5a; 32][..]).freeze();
+    alice.udp_pushto(alice_fd, buf.clone(), bob_addr).unwrap();
+    alice.rt().poll_scheduler();
+    let bytes = alice.rt().pop_frame();
+    assert_eq!(&bytes[24..26], &[0, 0]);
+    assert_eq!(&bytes[40..42], &[0, 0]);
+
+    // Bob trusts his device, while a peer without offload does not.
+    let mut plain_bob = test_helpers::new_bob2(now);
+    must_let!(let Err(Fail::Malformed { details: "Invalid IPv4 checksum" }) = plain_bob.receive(bytes.clone()));
+    test_helpers::udp_recv(&mut bob, bob_fd, alice_addr, bytes, &buf);
+
+    // Close peers.
+    alice.close(alice_fd).unwrap();
+    bob.close(bob_fd).unwrap();
+}
@@ -0,0 +1,435 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Traces of everything that drives a [LibOS](crate::libos::LibOS): inbound frames, clock
+//! advances, scheduler polls, and API calls.
+//!
+//! A trace recorded with [LibOS::start_recording](crate::libos::LibOS::start_recording) is replayed
+//! with [LibOS::replay](crate::libos::LibOS::replay), which reproduces the exact execution of the
+//! engine, provided that the replaying runtime starts from the same state as the recording one
+//! (addresses, options, and random number generator seed).
+
+use crate::{
+    fail::Fail,
+    file_table::FileDescriptor,
+    protocols::{ip, ipv4::Endpoint},
+};
+use byteorder::{ByteOrder, LittleEndian};
+use std::{convert::TryFrom, fs, net::Ipv4Addr, path::Path, time::Duration};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+const TRACE_MAGIC: &[u8; 4] = b"CNTR";
+const TRACE_VERSION: u32 = 1;
+
+const TAG_POLL: u8 = 0;
+const TAG_RECEIVE: u8 = 1;
+const TAG_ADVANCE_CLOCK: u8 = 2;
+const TAG_SOCKET: u8 = 3;
+const TAG_BIND: u8 = 4;
+const TAG_LISTEN: u8 = 5;
+const TAG_ACCEPT: u8 = 6;
+const TAG_CONNECT: u8 = 7;
+const TAG_CLOSE: u8 = 8;
+const TAG_PUSH: u8 = 9;
+const TAG_PUSHTO: u8 = 10;
+const TAG_POP: u8 = 11;
+const TAG_DROP_QTOKEN: u8 = 12;
+const TAG_TAKE: u8 = 13;
+
+/// Something that drove the engine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The scheduler polled its ready tasks, this many times in a row.
+    Poll(u32),
+    /// A batch of frames was received.
+    Receive(Vec<Vec<u8>>),
+    /// The clock was advanced, to this long after the start of the recording.
+    AdvanceClock(Duration),
+    Socket {
+        domain: i32,
+        socket_type: i32,
+    },
+    Bind {
+        fd: FileDescriptor,
+        local: Endpoint,
+    },
+    Listen {
+        fd: FileDescriptor,
+        backlog: usize,
+    },
+    Accept {
+        fd: FileDescriptor,
+    },
+    Connect {
+        fd: FileDescriptor,
+        remote: Endpoint,
+    },
+    Close {
+        fd: FileDescriptor,
+    },
+    Push {
+        fd: FileDescriptor,
+        data: Vec<u8>,
+    },
+    PushTo {
+        fd: FileDescriptor,
+        data: Vec<u8>,
+        to: Endpoint,
+    },
+    Pop {
+        fd: FileDescriptor,
+    },
+    DropQToken {
+        qt: u64,
+    },
+    /// The result of a completed operation was taken out of the scheduler.
+    Take {
+        qt: u64,
+    },
+}
+
+/// A recorded sequence of events.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub events: Vec<Event>,
+}
+
+/// Cursor over an encoded trace.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+impl Trace {
+    /// Appends `event`, merging consecutive polls.
+    pub fn push(&mut self, event: Event) {
+        if let (Some(Event::Poll(count)), Event::Poll(more)) = (self.events.last_mut(), &event) {
+            if let Some(sum) = count.checked_add(*more) {
+                *count = sum;
+                return;
+            }
+        }
+        self.events.push(event);
+    }
+
+    /// Encodes the trace in a compact binary format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = TRACE_MAGIC.to_vec();
+        put_u32(&mut buf, TRACE_VERSION);
+        for event in &self.events {
+            match event {
+                Event::Poll(count) => {
+                    buf.push(TAG_POLL);
+                    put_u32(&mut buf, *count);
+                }
+                Event::Receive(frames) => {
+                    buf.push(TAG_RECEIVE);
+                    put_u32(&mut buf, frames.len() as u32);
+                    for frame in frames {
+                        put_bytes(&mut buf, frame);
+                    }
+                }
+                Event::AdvanceClock(offset) => {
+                    buf.push(TAG_ADVANCE_CLOCK);
+                    put_u64(&mut buf, offset.as_secs());
+                    put_u32(&mut buf, offset.subsec_nanos());
+                }
+                Event::Socket {
+                    domain,
+                    socket_type,
+                } => {
+                    buf.push(TAG_SOCKET);
+                    put_u32(&mut buf, *domain as u32);
+                    put_u32(&mut buf, *socket_type as u32);
+                }
+                Event::Bind { fd, local } => {
+                    buf.push(TAG_BIND);
+                    put_u32(&mut buf, *fd);
+                    put_endpoint(&mut buf, local);
+                }
+                Event::Listen { fd, backlog } => {
+                    buf.push(TAG_LISTEN);
+                    put_u32(&mut buf, *fd);
+                    put_u64(&mut buf, *backlog as u64);
+                }
+                Event::Accept { fd } => {
+                    buf.push(TAG_ACCEPT);
+                    put_u32(&mut buf, *fd);
+                }
+                Event::Connect { fd, remote } => {
+                    buf.push(TAG_CONNECT);
+                    put_u32(&mut buf, *fd);
+                    put_endpoint(&mut buf, remote);
+                }
+                Event::Close { fd } => {
+                    buf.push(TAG_CLOSE);
+                    put_u32(&mut buf, *fd);
+                }
+                Event::Push { fd, data } => {
+                    buf.push(TAG_PUSH);
+                    put_u32(&mut buf, *fd);
+                    put_bytes(&mut buf, data);
+                }
+                Event::PushTo { fd, data, to } => {
+                    buf.push(TAG_PUSHTO);
+                    put_u32(&mut buf, *fd);
+                    put_bytes(&mut buf, data);
+                    put_endpoint(&mut buf, to);
+                }
+                Event::Pop { fd } => {
+                    buf.push(TAG_POP);
+                    put_u32(&mut buf, *fd);
+                }
+                Event::DropQToken { qt } => {
+                    buf.push(TAG_DROP_QTOKEN);
+                    put_u64(&mut buf, *qt);
+                }
+                Event::Take { qt } => {
+                    buf.push(TAG_TAKE);
+                    put_u64(&mut buf, *qt);
+                }
+            }
+        }
+        buf
+    }
+
+    /// Decodes a trace produced by [Trace::encode].
+    pub fn decode(buf: &[u8]) -> Result<Self, Fail> {
+        let mut reader = Reader { buf };
+        if reader.take(4)? != TRACE_MAGIC {
+            return Err(malformed("not a trace"));
+        }
+        if reader.u32()? != TRACE_VERSION {
+            return Err(Fail::Unsupported {
+                details: "trace version",
+            });
+        }
+        let mut events = Vec::new();
+        while !reader.buf.is_empty() {
+            let event = match reader.u8()? {
+                TAG_POLL => Event::Poll(reader.u32()?),
+                TAG_RECEIVE => {
+                    let count = reader.u32()?;
+                    let mut frames = Vec::new();
+                    for _ in 0..count {
+                        frames.push(reader.bytes()?);
+                    }
+                    Event::Receive(frames)
+                }
+                TAG_ADVANCE_CLOCK => {
+                    let secs = reader.u64()?;
+                    let nanos = reader.u32()?;
+                    if nanos >= 1_000_000_000 {
+                        return Err(malformed("clock offset"));
+                    }
+                    Event::AdvanceClock(Duration::new(secs, nanos))
+                }
+                TAG_SOCKET => Event::Socket {
+                    domain: reader.u32()? as i32,
+                    socket_type: reader.u32()? as i32,
+                },
+                TAG_BIND => Event::Bind {
+                    fd: reader.u32()?,
+                    local: reader.endpoint()?,
+                },
+                TAG_LISTEN => Event::Listen {
+                    fd: reader.u32()?,
+                    backlog: reader.u64()? as usize,
+                },
+                TAG_ACCEPT => Event::Accept { fd: reader.u32()? },
+                TAG_CONNECT => Event::Connect {
+                    fd: reader.u32()?,
+                    remote: reader.endpoint()?,
+                },
+                TAG_CLOSE => Event::Close { fd: reader.u32()? },
+                TAG_PUSH => Event::Push {
+                    fd: reader.u32()?,
+                    data: reader.bytes()?,
+                },
+                TAG_PUSHTO => Event::PushTo {
+                    fd: reader.u32()?,
+                    data: reader.bytes()?,
+                    to: reader.endpoint()?,
+                },
+                TAG_POP => Event::Pop { fd: reader.u32()? },
+                TAG_DROP_QTOKEN => Event::DropQToken { qt: reader.u64()? },
+                TAG_TAKE => Event::Take { qt: reader.u64()? },
+                _ => return Err(malformed("unknown trace event")),
+            };
+            events.push(event);
+        }
+        Ok(Self { events })
+    }
+
+    pub fn write_file(&self, path: &Path) -> Result<(), Fail> {
+        fs::write(path, self.encode()).map_err(|e| Fail::from(e).context("writing trace file"))
+    }
+
+    pub fn read_file(path: &Path) -> Result<Self, Fail> {
+        let contents = fs::read(path).map_err(|e| Fail::from(e).context("reading trace file"))?;
+        Self::decode(&contents)
+    }
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Fail> {
+        if self.buf.len() < len {
+            return Err(malformed("truncated trace"));
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Fail> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Fail> {
+        Ok(LittleEndian::read_u16(self.take(2)?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Fail> {
+        Ok(LittleEndian::read_u32(self.take(4)?))
+    }
+
+    fn u64(&mut self) -> Result<u64, Fail> {
+        Ok(LittleEndian::read_u64(self.take(8)?))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, Fail> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn endpoint(&mut self) -> Result<Endpoint, Fail> {
+        let addr = Ipv4Addr::from(self.u32()?);
+        let port = ip::Port::try_from(self.u16()?)?;
+        Ok(Endpoint::new(addr, port))
+    }
+}
+
+//==============================================================================
+// Standalone Functions
+//==============================================================================
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    put_u32(buf, data.len() as u32);
+    buf.extend_from_slice(data);
+}
+
+fn put_endpoint(buf: &mut Vec<u8>, endpoint: &Endpoint) {
+    put_u32(buf, u32::from(endpoint.address()));
+    let port: u16 = endpoint.port().into();
+    buf.extend_from_slice(&port.to_le_bytes());
+}
+
+fn malformed(details: &'static str) -> Fail {
+    Fail::Malformed { details }
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::{Event, Trace};
+    use crate::{
+        collections::bytes::Bytes,
+        libos::LibOS,
+        protocols::{ip, ipv4::Endpoint},
+        runtime::{Runtime, RuntimeBuf},
+        test_helpers::{self, TestRuntime},
+    };
+    use std::{
+        convert::TryFrom,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_trace_encoding() {
+        let endpoint = Endpoint::new(test_helpers::BOB_IPV4, ip::Port::try_from(80).unwrap());
+        let mut trace = Trace::default();
+        trace.push(Event::Socket {
+            domain: libc::AF_INET,
+            socket_type: libc::SOCK_STREAM,
+        });
+        trace.push(Event::Poll(1));
+        trace.push(Event::Poll(2));
+        trace.push(Event::Connect {
+            fd: 3,
+            remote: endpoint,
+        });
+        trace.push(Event::Receive(vec![vec![1, 2, 3], vec![]]));
+        trace.push(Event::AdvanceClock(Duration::new(1, 500)));
+        trace.push(Event::PushTo {
+            fd: 3,
+            data: vec![0x5a; 32],
+            to: endpoint,
+        });
+        trace.push(Event::Take { qt: 7 });
+        assert_eq!(trace.events[1], Event::Poll(3));
+
+        let encoded = trace.encode();
+        assert_eq!(Trace::decode(&encoded).unwrap(), trace);
+        assert!(Trace::decode(&encoded[..encoded.len() - 1]).is_err());
+        assert!(Trace::decode(b"pcap").is_err());
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let now = Instant::now();
+        let new_alice = || {
+            let rt = TestRuntime::new(
+                "alice",
+                now,
+                test_helpers::ALICE_MAC,
+                test_helpers::ALICE_IPV4,
+            );
+            LibOS::new(rt).unwrap()
+        };
+        let mut alice = new_alice();
+        let mut bob = test_helpers::new_bob(now);
+        let port = ip::Port::try_from(80).unwrap();
+        let alice_addr = Endpoint::new(test_helpers::ALICE_IPV4, port);
+        let bob_addr = Endpoint::new(test_helpers::BOB_IPV4, port);
+
+        // Send a datagram, which first resolves Bob's address.
+        alice.start_recording();
+        let fd = alice.socket(libc::AF_INET, libc::SOCK_DGRAM, 0).unwrap();
+        alice.bind(fd, alice_addr).unwrap();
+        let qt = alice
+            .pushto2(fd, Bytes::from_slice(b"hello"), bob_addr)
+            .unwrap();
+        alice.poll_bg_work();
+        let request = alice.rt().pop_frame();
+        bob.receive(request.clone()).unwrap();
+        alice.rt().push_frame(bob.rt().pop_frame());
+        alice.wait(qt);
+        let datagram = alice.rt().pop_frame();
+        let trace = alice.stop_recording().unwrap();
+        let trace = Trace::decode(&trace.encode()).unwrap();
+
+        // A fresh engine replaying the trace behaves identically.
+        let mut replica = new_alice();
+        replica.replay(&trace, now).unwrap();
+        assert_eq!(replica.rt().pop_frame(), request);
+        assert_eq!(replica.rt().pop_frame(), datagram);
+        assert!(replica.rt().pop_frame_unchecked().is_none());
+    }
+}
@@ -1,9 +1,14 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
+#[cfg(not(target_arch = "wasm32"))]
+use crate::interop::dmtr_sgarray_t;
 use crate::{
-    interop::dmtr_sgarray_t,
+    fail::Fail,
+    filter::{PacketFilter, Verdict},
     protocols::{arp, ethernet2::MacAddress, tcp, udp},
     scheduler::{Operation, Scheduler, SchedulerHandle},
+    tap::{Direction, PacketTap},
+    timer::TimerStats,
 };
 use arrayvec::ArrayVec;
 use rand::distributions::{Distribution, Standard};
@@ -17,6 +22,27 @@ use std::{
 
 pub const RECEIVE_BATCH_SIZE: usize = 4;
 
+/// Resources of a runtime that may run out.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Resource {
+    /// Buffers holding frames and application data.
+    Buffer,
+    /// Entries of the file table.
+    FileDescriptor,
+    /// Slots of the transmit queue.
+    TransmitQueue,
+}
+
+/// Checksums the network device of a runtime takes care of.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ChecksumOffload {
+    /// IPv4, TCP and UDP checksums of received frames were verified, and frames that failed
+    /// verification were discarded before reaching the engine.
+    pub rx: bool,
+    /// IPv4, TCP and UDP checksums of transmitted frames are inserted by the device.
+    pub tx: bool,
+}
+
 pub trait RuntimeBuf: Clone + Debug + Deref<Target = [u8]> + Sized + Unpin {
     fn empty() -> Self;
 
@@ -40,15 +66,66 @@ pub trait Runtime: Clone + Unpin + 'static {
     type Buf: RuntimeBuf;
     type WaitFuture: Future<Output = ()>;
 
+    // Scatter-gather arrays back the C-compatible LibOS interface, which is not available when
+    // targeting WebAssembly.
+    #[cfg(not(target_arch = "wasm32"))]
     #[allow(clippy::wrong_self_convention)]
     fn into_sgarray(&self, buf: Self::Buf) -> dmtr_sgarray_t;
+    #[cfg(not(target_arch = "wasm32"))]
     fn alloc_sgarray(&self, size: usize) -> dmtr_sgarray_t;
+    #[cfg(not(target_arch = "wasm32"))]
     fn free_sgarray(&self, sga: dmtr_sgarray_t);
+    #[cfg(not(target_arch = "wasm32"))]
     fn clone_sgarray(&self, sga: &dmtr_sgarray_t) -> Self::Buf;
 
     fn advance_clock(&self, now: Instant);
     fn transmit(&self, pkt: impl PacketBuf<Self::Buf>);
+    /// Transmits `pkt`, unless the transmit queue is full or the packet filter rejects it.
+    fn try_transmit(&self, pkt: impl PacketBuf<Self::Buf>) -> Result<(), Fail> {
+        if self.exhausted(Resource::TransmitQueue) {
+            return Err(Fail::ResourceBusy {
+                details: "transmit queue full",
+            });
+        }
+        if let Some(filter) = self.packet_filter().filter(|f| !f.accepts_all()) {
+            let mut header = vec![0; pkt.header_size()];
+            pkt.write_header(&mut header);
+            match filter.evaluate(Direction::Transmit, &header) {
+                Verdict::Accept => (),
+                Verdict::Drop => return Ok(()),
+                Verdict::Reject => return Err(Fail::ConnectionRefused {}),
+            }
+        }
+        self.transmit(pkt);
+        Ok(())
+    }
     fn receive(&self) -> ArrayVec<Self::Buf, RECEIVE_BATCH_SIZE>;
+    /// Called with every frame handed to the engine, before it gets parsed.
+    fn on_receive(&self, _frame: &Self::Buf) {}
+    /// Returns the tap of this runtime, if it has one. The engine notifies it of received frames,
+    /// while the runtime notifies it of the frames it transmits once they are serialized.
+    fn packet_tap(&self) -> Option<PacketTap> {
+        None
+    }
+    /// Returns the packet filter of this runtime, if it has one. The engine evaluates it on
+    /// received frames and [Runtime::try_transmit] on transmitted ones.
+    fn packet_filter(&self) -> Option<PacketFilter> {
+        None
+    }
+    /// Returns the checksums the network device verifies and inserts, which the engine then skips
+    /// in software on top of those its options offload.
+    fn checksum_offload(&self) -> ChecksumOffload {
+        ChecksumOffload::default()
+    }
+    /// Returns whether `resource` is currently exhausted. The engine checks this before using a
+    /// resource and fails the operation instead.
+    fn exhausted(&self, _resource: Resource) -> bool {
+        false
+    }
+    /// Returns the counters of the timer behind [Runtime::wait], if the runtime keeps them.
+    fn timer_stats(&self) -> Option<TimerStats> {
+        None
+    }
 
     fn local_link_addr(&self) -> MacAddress;
     fn local_ipv4_addr(&self) -> Ipv4Addr;
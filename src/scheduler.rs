@@ -105,6 +105,25 @@ impl Drop for SchedulerHandle {
     }
 }
 
+/// Snapshot of the work held by a [Scheduler] and counters of how it was polled.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SchedulerStats {
+    /// Tasks inserted and not yet removed, whether pending or completed.
+    pub tasks: usize,
+    /// Tasks notified and waiting to be polled on the next tick.
+    pub runnable: usize,
+    /// Calls to [Scheduler::poll].
+    pub ticks: u64,
+    /// Ticks that found no task to poll.
+    pub wasted_ticks: u64,
+    /// Tasks polled, over all ticks.
+    pub polls: u64,
+    /// Polls that left the task pending.
+    pub pending_polls: u64,
+    /// Tasks polled on the last tick.
+    pub last_tick_polls: u64,
+}
+
 /// The scheduler
 /// runs on a single thread multiplexing between all available work.
 pub struct Scheduler<F: Future<Output = ()> + Unpin> {
@@ -132,6 +151,8 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
             slab: PinSlab::new(),
             pages: vec![],
             root_waker: SharedWaker::new(),
+            tasks: 0,
+            stats: SchedulerStats::default(),
         };
         Self {
             inner: Rc::new(RefCell::new(inner)),
@@ -145,9 +166,25 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
         let (page, subpage_ix) = inner.page(key);
         assert!(!page.was_dropped(subpage_ix));
         page.clear(subpage_ix);
+        inner.tasks -= 1;
         inner.slab.remove_unpin(key as usize).unwrap()
     }
 
+    /// Returns a snapshot of the work held by the scheduler.
+    pub fn stats(&self) -> SchedulerStats {
+        let inner = self.inner.borrow();
+        let runnable = inner
+            .pages
+            .iter()
+            .map(|page| page.count_notified() as usize)
+            .sum();
+        SchedulerStats {
+            tasks: inner.tasks,
+            runnable,
+            ..inner.stats
+        }
+    }
+
     /// Given the raw `key` representing this future return a proper handle.
     pub fn from_raw_handle(&self, key: u64) -> Option<SchedulerHandle> {
         let inner = self.inner.borrow();
@@ -177,6 +214,8 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
     pub fn poll(&self) {
         let mut inner = self.inner.borrow_mut();
         // inner.root_waker.register(ctx.waker());
+        let mut polls = 0;
+        let mut pending_polls = 0;
 
         // TODO rewrite this loop to use high-level iterators instead of indexes.
         // Iterate through all our pages finding the tasks that are ready to be polled again
@@ -205,9 +244,10 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
                         let poll_result = { Future::poll(pinned_ref, &mut sub_ctx) };
                         inner = self.inner.borrow_mut();
 
+                        polls += 1;
                         match poll_result {
                             Poll::Ready(()) => inner.pages[page_ix].mark_completed(subpage_ix),
-                            Poll::Pending => (),
+                            Poll::Pending => pending_polls += 1,
                         }
                     }
                 }
@@ -218,10 +258,20 @@ impl<F: Future<Output = ()> + Unpin> Scheduler<F> {
                         let ix = page_ix * WAKER_PAGE_SIZE + subpage_ix;
                         inner.slab.remove(ix);
                         inner.pages[page_ix].clear(subpage_ix);
+                        inner.tasks -= 1;
                     }
                 }
             }
         }
+
+        let stats = &mut inner.stats;
+        stats.ticks += 1;
+        if polls == 0 {
+            stats.wasted_ticks += 1;
+        }
+        stats.polls += polls;
+        stats.pending_polls += pending_polls;
+        stats.last_tick_polls = polls;
     }
 }
 
@@ -233,6 +283,10 @@ struct Inner<F: Future<Output = ()> + Unpin> {
     /// The statuses are arranged in pages.
     pages: Vec<WakerPageRef>,
     root_waker: SharedWaker,
+    /// Number of futures in the slab.
+    tasks: usize,
+    /// Polling counters, reported by [Scheduler::stats].
+    stats: SchedulerStats,
 }
 
 impl<F: Future<Output = ()> + Unpin> Inner<F> {
@@ -250,6 +304,7 @@ impl<F: Future<Output = ()> + Unpin> Inner<F> {
     /// key is used to index into the slab for accessing the future.
     fn insert(&mut self, future: F) -> u64 {
         let key = self.slab.insert(future);
+        self.tasks += 1;
 
         // Add a new page to hold this future's status if the current page is filled.
         while key >= self.pages.len() * WAKER_PAGE_SIZE {
@@ -0,0 +1,321 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Counters modeled after the IP, ICMP, TCP and UDP groups of the standard MIB (RFC 4293, RFC
+//! 4022 and RFC 4113). Fields are named after the corresponding MIB objects.
+
+use crate::fail::Fail;
+use std::{cell::RefCell, rc::Rc};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// IP group.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IpStats {
+    /// Datagrams received, including those received in error.
+    pub in_receives: u64,
+    /// Datagrams discarded due to errors in their header.
+    pub in_hdr_errors: u64,
+    /// Datagrams discarded because they were not addressed to us.
+    pub in_addr_errors: u64,
+    /// Datagrams discarded because of an unknown or unsupported protocol.
+    pub in_unknown_protos: u64,
+    /// Datagrams handed over to ICMP, TCP or UDP.
+    pub in_delivers: u64,
+    /// Datagrams handed over to the runtime for transmission.
+    pub out_requests: u64,
+    /// Fragments discarded, as reassembly is not supported.
+    pub reasm_fails: u64,
+}
+
+/// ICMP group.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IcmpStats {
+    /// Messages received, including those received in error.
+    pub in_msgs: u64,
+    /// Messages received with errors, such as a bad checksum.
+    pub in_errors: u64,
+    pub in_echos: u64,
+    pub in_echo_reps: u64,
+    pub in_dest_unreachs: u64,
+    /// Messages handed over to the runtime for transmission.
+    pub out_msgs: u64,
+    /// Messages not sent due to errors, such as a full transmit queue.
+    pub out_errors: u64,
+    pub out_echos: u64,
+    pub out_echo_reps: u64,
+}
+
+/// TCP group.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TcpStats {
+    /// Connections opened with `connect`.
+    pub active_opens: u64,
+    /// Connections opened by a SYN on a listening socket.
+    pub passive_opens: u64,
+    /// Connection attempts that were refused or timed out.
+    pub attempt_fails: u64,
+    /// Established connections reset by the remote peer.
+    pub estab_resets: u64,
+    /// Segments received, including those received in error.
+    pub in_segs: u64,
+    /// Segments handed over to the runtime for transmission.
+    pub out_segs: u64,
+    /// Segments retransmitted.
+    pub retrans_segs: u64,
+    /// Segments discarded due to errors, such as a bad checksum.
+    pub in_errs: u64,
+    /// Segments sent with the RST flag.
+    pub out_rsts: u64,
+    /// Segments discarded because of a bad checksum.
+    pub in_csum_errors: u64,
+    /// SYN+ACKs sent with a SYN cookie instead of keeping the handshake state.
+    pub syncookies_sent: u64,
+    /// Connections opened by an ACK carrying a valid SYN cookie.
+    pub syncookies_recv: u64,
+    /// ACKs whose SYN cookie did not validate.
+    pub syncookies_failed: u64,
+    /// SYNs answered with a RST because a connection limit was reached.
+    pub limit_rejects: u64,
+    /// Segments starting outside of the receive window.
+    pub seq_out_of_window: u64,
+    /// RSTs not at the next expected sequence number.
+    pub rst_seq_violations: u64,
+    /// ACKs of data not sent yet.
+    pub ack_unsent: u64,
+    /// ACKs of data older than the send window.
+    pub ack_too_old: u64,
+    /// RSTs and challenge ACKs not sent because of the error segment rate limit.
+    pub error_segments_throttled: u64,
+    /// Challenge ACKs sent in reply to RSTs and SYNs that may be blind injections (RFC 5961).
+    pub challenge_acks: u64,
+    /// Pure ACKs handled by header prediction.
+    pub predicted_acks: u64,
+    /// In-order data segments handled by header prediction.
+    pub predicted_data: u64,
+    /// Pure ACKs superseded by a later one for the same connection in a receive batch.
+    pub coalesced_acks: u64,
+    /// Connections torn down by the idle reaping policy.
+    pub idle_reaped: u64,
+    /// Connections dropped from TIME_WAIT early to make room for newer ones.
+    pub time_wait_evicted: u64,
+    /// SYNs starting a connection on the endpoints of one still in TIME_WAIT.
+    pub time_wait_recycled: u64,
+    /// Segments retransmitted to fill holes reported by selective acknowledgments, past the
+    /// oldest unacknowledged one.
+    pub sack_retrans_segs: u64,
+    /// Segments dropped for carrying a timestamp older than the latest one of the remote (PAWS).
+    pub paws_rejected: u64,
+    /// Probes of a zero window the remote advertised, retransmissions included.
+    pub window_probes: u64,
+    /// Duplicate ACKs received, as RFC 5681 defines them.
+    pub dup_acks: u64,
+    /// Retransmissions triggered by duplicate ACKs, or by partial ACKs in fast recovery, rather
+    /// than by the retransmission timer.
+    pub fast_retransmits: u64,
+    /// Connections torn down after their data went unacknowledged through every retransmission
+    /// allowed.
+    pub timeout_aborts: u64,
+    /// Segments received marked with congestion experienced by a router on the way.
+    pub ecn_ce_marks: u64,
+    /// Reductions of the congestion window for ACKs echoing congestion experienced.
+    pub ecn_cwnd_reductions: u64,
+    /// Connection attempts asking for a TCP Fast Open cookie.
+    pub fast_open_cookie_reqs: u64,
+    /// Connections whose data on the SYN the remote acknowledged.
+    pub fast_open_active: u64,
+    /// Connections whose data on the SYN the remote did not acknowledge, to be sent again.
+    pub fast_open_active_fails: u64,
+    /// SYNs whose data was taken for carrying a valid TCP Fast Open cookie.
+    pub fast_open_passive: u64,
+    /// SYNs carrying a TCP Fast Open cookie that did not validate.
+    pub fast_open_passive_fails: u64,
+    /// SYNs with a valid cookie whose data was refused as a possible replay.
+    pub fast_open_replays: u64,
+    /// Duplicate selective acknowledgments sent to report data received twice (RFC 2883).
+    pub dsacks_sent: u64,
+    /// Duplicate selective acknowledgments received.
+    pub dsacks_received: u64,
+    /// Reductions of the congestion window undone once duplicate selective acknowledgments
+    /// showed every retransmission behind them to be spurious.
+    pub dsack_undos: u64,
+    /// Retransmission timeouts that forward RTO-recovery found spurious, whose shrinking of the
+    /// congestion window got undone.
+    pub frto_spurious: u64,
+    /// Times auto-tuning grew the receive window of a connection to keep up with its throughput.
+    pub receive_window_grows: u64,
+}
+
+/// UDP group.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UdpStats {
+    /// Datagrams delivered to a socket.
+    pub in_datagrams: u64,
+    /// Datagrams discarded because no socket was bound to their port.
+    pub no_ports: u64,
+    /// Datagrams discarded due to errors, such as a bad checksum.
+    pub in_errors: u64,
+    /// Datagrams handed over to the runtime for transmission.
+    pub out_datagrams: u64,
+    /// Datagrams discarded because of a bad checksum.
+    pub in_csum_errors: u64,
+}
+
+/// Why a packet was discarded.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DropReason {
+    /// Headers failed to parse or made no sense.
+    Malformed,
+    /// A checksum did not match.
+    BadChecksum,
+    /// Addressed to another host.
+    NotForUs,
+    /// Uses a protocol or feature we do not support, such as IP fragmentation.
+    Unsupported,
+    /// No socket was bound to the destination port.
+    NoListener,
+    /// Data outside of the receive window, either already received or beyond what fits.
+    OutOfWindow,
+    /// The link address of the destination could not be resolved.
+    UnresolvedArp,
+    /// Rejected by a packet filter.
+    Filtered,
+    /// A transmit queue or listen backlog was full.
+    QueueFull,
+    /// The runtime ran out of buffers.
+    NoBuffer,
+    /// A SYN shed because its source opened handshakes too fast.
+    SynFlood,
+}
+
+/// Packets discarded, by reason.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DropStats {
+    pub malformed: u64,
+    pub bad_checksum: u64,
+    pub not_for_us: u64,
+    pub unsupported: u64,
+    pub no_listener: u64,
+    pub out_of_window: u64,
+    pub unresolved_arp: u64,
+    pub filtered: u64,
+    pub queue_full: u64,
+    pub no_buffer: u64,
+    pub syn_flood: u64,
+}
+
+/// Snapshot of the counters of an engine.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Stats {
+    pub ip: IpStats,
+    pub icmp: IcmpStats,
+    pub tcp: TcpStats,
+    pub udp: UdpStats,
+    pub drops: DropStats,
+}
+
+/// Counters shared by the protocols of an engine.
+#[derive(Clone, Default)]
+pub struct Counters {
+    inner: Rc<RefCell<Stats>>,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [DropStats].
+impl DropStats {
+    /// Returns the number of packets discarded for `reason`.
+    pub fn get(&self, reason: DropReason) -> u64 {
+        match reason {
+            DropReason::Malformed => self.malformed,
+            DropReason::BadChecksum => self.bad_checksum,
+            DropReason::NotForUs => self.not_for_us,
+            DropReason::Unsupported => self.unsupported,
+            DropReason::NoListener => self.no_listener,
+            DropReason::OutOfWindow => self.out_of_window,
+            DropReason::UnresolvedArp => self.unresolved_arp,
+            DropReason::Filtered => self.filtered,
+            DropReason::QueueFull => self.queue_full,
+            DropReason::NoBuffer => self.no_buffer,
+            DropReason::SynFlood => self.syn_flood,
+        }
+    }
+
+    /// Returns the total number of packets discarded.
+    pub fn total(&self) -> u64 {
+        self.malformed
+            + self.bad_checksum
+            + self.not_for_us
+            + self.unsupported
+            + self.no_listener
+            + self.out_of_window
+            + self.unresolved_arp
+            + self.filtered
+            + self.queue_full
+            + self.no_buffer
+            + self.syn_flood
+    }
+
+    fn count(&mut self, reason: DropReason) {
+        let counter = match reason {
+            DropReason::Malformed => &mut self.malformed,
+            DropReason::BadChecksum => &mut self.bad_checksum,
+            DropReason::NotForUs => &mut self.not_for_us,
+            DropReason::Unsupported => &mut self.unsupported,
+            DropReason::NoListener => &mut self.no_listener,
+            DropReason::OutOfWindow => &mut self.out_of_window,
+            DropReason::UnresolvedArp => &mut self.unresolved_arp,
+            DropReason::Filtered => &mut self.filtered,
+            DropReason::QueueFull => &mut self.queue_full,
+            DropReason::NoBuffer => &mut self.no_buffer,
+            DropReason::SynFlood => &mut self.syn_flood,
+        };
+        *counter += 1;
+    }
+}
+
+/// Associate functions for [Counters].
+impl Counters {
+    /// Returns the current value of every counter.
+    pub fn get(&self) -> Stats {
+        *self.inner.borrow()
+    }
+
+    /// Updates counters with `f`.
+    pub fn update<F: FnOnce(&mut Stats)>(&self, f: F) {
+        f(&mut self.inner.borrow_mut())
+    }
+
+    /// Counts a packet discarded for `reason`.
+    pub fn count_drop(&self, reason: DropReason) {
+        self.inner.borrow_mut().drops.count(reason)
+    }
+
+    /// Counts a packet discarded because it failed to parse with `e`.
+    pub fn count_parse_drop(&self, e: &Fail) {
+        self.count_drop(match e {
+            Fail::Unsupported { .. } => DropReason::Unsupported,
+            Fail::Malformed { details } if details.contains("checksum") => DropReason::BadChecksum,
+            _ => DropReason::Malformed,
+        })
+    }
+
+    /// Counts a packet the runtime failed to transmit with `e`.
+    pub fn count_transmit_drop(&self, e: &Fail) {
+        self.count_drop(match e {
+            Fail::ConnectionRefused {} => DropReason::Filtered,
+            _ => DropReason::QueueFull,
+        })
+    }
+}
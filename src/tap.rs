@@ -0,0 +1,60 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Taps that see a copy of every frame an engine transmits and receives, for in-process
+//! sniffers, invariant checkers and live captures.
+
+use std::{cell::RefCell, rc::Rc, time::Instant};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Which way a frame went.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Direction {
+    Transmit,
+    Receive,
+}
+
+/// A frame seen by a tap.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TappedFrame<'a> {
+    pub direction: Direction,
+    /// When the frame went through, as told by the runtime.
+    pub timestamp: Instant,
+    /// The whole frame, starting with its Ethernet header.
+    pub data: &'a [u8],
+}
+
+/// Callbacks notified of every frame.
+#[derive(Clone, Default)]
+pub struct PacketTap {
+    inner: Rc<RefCell<Vec<Rc<dyn Fn(&TappedFrame)>>>>,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [PacketTap].
+impl PacketTap {
+    /// Registers `f` to be called on every frame from now on.
+    pub fn subscribe<F: Fn(&TappedFrame) + 'static>(&self, f: F) {
+        self.inner.borrow_mut().push(Rc::new(f));
+    }
+
+    /// Calls every subscriber with `data`.
+    pub fn notify(&self, direction: Direction, timestamp: Instant, data: &[u8]) {
+        // Subscribers may subscribe others, so do not hold the borrow while calling them.
+        let subscribers = self.inner.borrow().clone();
+        let frame = TappedFrame {
+            direction,
+            timestamp,
+            data,
+        };
+        for f in subscribers.iter() {
+            f(&frame);
+        }
+    }
+}
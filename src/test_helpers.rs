@@ -1,18 +1,31 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
+#[cfg(not(target_arch = "wasm32"))]
 use crate::interop::{dmtr_sgarray_t, dmtr_sgaseg_t};
 use crate::{
     collections::bytes::{Bytes, BytesMut},
     engine::Engine,
+    fail::Fail,
+    file_table::FileDescriptor,
+    filter::PacketFilter,
     logging,
-    protocols::{arp, ethernet2::MacAddress, tcp, udp},
-    runtime::{PacketBuf, Runtime, RECEIVE_BATCH_SIZE},
+    pcap::PcapWriter,
+    protocols::{
+        arp,
+        ethernet2::{EtherType2, Ethernet2Header, MacAddress},
+        ip,
+        ipv4::{self, Ipv4Header, Ipv4Protocol2},
+        tcp,
+        udp::{self, datagram::UdpHeader},
+    },
+    runtime::{ChecksumOffload, PacketBuf, Resource, Runtime, RECEIVE_BATCH_SIZE},
     scheduler::{Operation, Scheduler, SchedulerHandle},
-    timer::{Timer, TimerRc},
+    tap::{Direction, PacketTap},
+    timer::{Timer, TimerRc, TimerStats},
 };
 use arrayvec::ArrayVec;
-use futures::FutureExt;
+use futures::{task::noop_waker_ref, FutureExt};
 use rand::{
     distributions::{Distribution, Standard},
     rngs::SmallRng,
@@ -20,17 +33,24 @@ use rand::{
     Rng, SeedableRng,
 };
 use std::collections::HashMap;
-use std::mem;
-use std::ptr;
-use std::slice;
 use std::{
     cell::RefCell,
+    cmp,
     collections::VecDeque,
+    convert::TryFrom,
+    env,
+    fs::File,
     future::Future,
     net::Ipv4Addr,
+    path::{Path, PathBuf},
+    pin::Pin,
     rc::Rc,
+    task::{Context, Poll},
+    thread,
     time::{Duration, Instant},
 };
+#[cfg(not(target_arch = "wasm32"))]
+use std::{mem, ptr, slice};
 
 pub const RECEIVE_WINDOW_SIZE: usize = 1024;
 pub const ALICE_MAC: MacAddress = MacAddress::new([0x12, 0x23, 0x45, 0x67, 0x89, 0xab]);
@@ -40,12 +60,77 @@ pub const BOB_IPV4: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 2);
 pub const CARRIE_MAC: MacAddress = MacAddress::new([0xef, 0xcd, 0xab, 0x89, 0x67, 0x45]);
 pub const CARRIE_IPV4: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 3);
 
+/// Environment variable naming a directory in which every [TestRuntime] records its traffic.
+pub const CAPTURE_DIR_VAR: &str = "CATNIP_TEST_CAPTURE_DIR";
+
 pub type TestEngine = Engine<TestRuntime>;
 
+/// Impairments applied to the frames a [TestRuntime] transmits.
+///
+/// Decisions are drawn from a generator seeded with `seed`, independently of the runtime's own
+/// generator, so a given model and traffic pattern always produce the same outcome.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LinkModel {
+    pub seed: u64,
+    /// Probability that a frame is dropped.
+    pub drop_probability: f64,
+    /// Probability that a frame is delivered twice.
+    pub duplicate_probability: f64,
+    /// Probability that a frame is held back and overtaken by later frames.
+    pub reorder_probability: f64,
+    /// Maximum number of later frames that may overtake a held back frame.
+    pub reorder_window: usize,
+    /// Time a frame spends on the link before it can be popped.
+    pub latency: Duration,
+    /// Upper bound of an extra delay, drawn uniformly for each frame. Frames may overtake each
+    /// other as a result.
+    pub jitter: Duration,
+    /// Rate (in bytes per second) at which the token bucket in front of the link refills. Zero
+    /// leaves the bandwidth unlimited.
+    pub rate: u64,
+    /// Capacity of the token bucket (in bytes), i.e. the largest burst sent at full speed.
+    pub burst: usize,
+    /// Number of frames that may wait for tokens before further ones are tail dropped. Zero
+    /// leaves the queue unbounded.
+    pub queue_limit: usize,
+}
+
+/// Skew of the clock of a [TestRuntime] against the reference time tests advance it to.
+///
+/// The local clock reads `offset` ahead of the reference when the runtime is created, and drifts
+/// by `drift_ppm` parts per million of the reference time elapsed since then. Times reported by
+/// the runtime, such as [TestRuntime::next_delivery], are local.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClockSkew {
+    pub offset: Duration,
+    /// Positive values run the clock fast, negative ones slow.
+    pub drift_ppm: i64,
+}
+
+/// State of the token bucket that limits the bandwidth of a link.
+struct TokenBucket {
+    tokens: f64,
+    /// Time at which the bucket held `tokens`.
+    updated: Instant,
+    /// Times at which the frames waiting for tokens are released onto the link.
+    queue: VecDeque<Instant>,
+}
+
+/// Exhaustion of a resource injected into a [TestRuntime].
+#[derive(Clone, Copy, Debug)]
+struct Exhaustion {
+    /// Number of checks that still find the resource available.
+    skip: usize,
+    /// Number of checks that find the resource exhausted after those.
+    count: usize,
+}
+
 #[derive(Clone)]
 pub struct TestRuntime {
     inner: Rc<RefCell<Inner>>,
     scheduler: Scheduler<Operation<TestRuntime>>,
+    tap: PacketTap,
+    filter: PacketFilter,
 }
 
 impl TestRuntime {
@@ -64,7 +149,8 @@ impl TestRuntime {
             false,
         );
 
-        let tcp_options = tcp::Options::<Self>::default();
+        // Most tests expect each push to go out at once, so Nagle's algorithm is opt-in here.
+        let tcp_options = tcp::Options::<Self>::default().set_nodelay(true);
 
         let inner = Inner {
             name,
@@ -76,19 +162,113 @@ impl TestRuntime {
             ipv4_addr,
             tcp_options,
             arp_options,
+            link_model: LinkModel::default(),
+            link_rng: SmallRng::seed_from_u64(0),
+            held: Vec::new(),
+            bucket: TokenBucket {
+                tokens: 0.0,
+                updated: now,
+                queue: VecDeque::new(),
+            },
+            capture: None,
+            epoch: now,
+            exhaustion: HashMap::new(),
+            skew: ClockSkew::default(),
+            checksum_offload: ChecksumOffload::default(),
+            reference: now,
         };
-        Self {
+        let rt = Self {
             inner: Rc::new(RefCell::new(inner)),
             scheduler: Scheduler::new(),
+            tap: PacketTap::default(),
+            filter: PacketFilter::default(),
+        };
+        if let Some(dir) = env::var_os(CAPTURE_DIR_VAR) {
+            let path = capture_path(Path::new(&dir), name);
+            if let Err(e) = rt.start_capture(&path) {
+                warn!("Failed to capture traffic to {:?}: {:?}", path, e);
+            }
         }
+        rt
+    }
+
+    /// Makes the runtime advertise `offload` to engines created from now on.
+    pub fn set_checksum_offload(&self, offload: ChecksumOffload) {
+        self.inner.borrow_mut().checksum_offload = offload;
+    }
+
+    /// Records every frame transmitted and received from now on into a pcap file at `path`.
+    /// Timestamps count from the time the runtime was created, starting at the Unix epoch.
+    pub fn start_capture(&self, path: &Path) -> Result<(), Fail> {
+        let file =
+            File::create(path).map_err(|e| Fail::from(e).context("creating capture file"))?;
+        self.inner.borrow_mut().capture = Some(PcapWriter::new(file)?);
+        Ok(())
+    }
+
+    /// Makes `resource` look exhausted to the engine for `count` checks, after `skip` checks
+    /// found it available. Use `usize::MAX` as `count` to exhaust it until [clear_exhaustion] is
+    /// called.
+    ///
+    /// [clear_exhaustion]: Self::clear_exhaustion
+    pub fn inject_exhaustion(&self, resource: Resource, skip: usize, count: usize) {
+        self.inner
+            .borrow_mut()
+            .exhaustion
+            .insert(resource, Exhaustion { skip, count });
+    }
+
+    /// Makes every resource available again.
+    pub fn clear_exhaustion(&self) {
+        self.inner.borrow_mut().exhaustion.clear();
+    }
+
+    /// Skews the local clock by `skew` from now on. The clock never goes backwards, so a smaller
+    /// skew than the current one only takes effect once the reference time catches up.
+    pub fn set_clock_skew(&self, skew: ClockSkew) {
+        assert!(
+            skew.drift_ppm > -1_000_000,
+            "invalid drift {}",
+            skew.drift_ppm
+        );
+        let mut inner = self.inner.borrow_mut();
+        inner.skew = skew;
+        let reference = inner.reference;
+        inner.advance_local_clock(reference);
     }
 
     pub fn pop_frame(&self) -> Bytes {
-        self.inner.borrow_mut().outgoing.pop_front().unwrap()
+        self.inner.borrow_mut().pop_outgoing().unwrap()
     }
 
     pub fn pop_frame_unchecked(&self) -> Option<Bytes> {
-        self.inner.borrow_mut().outgoing.pop_front()
+        self.inner.borrow_mut().pop_outgoing()
+    }
+
+    /// Returns the time at which the next frame in flight gets delivered.
+    pub fn next_delivery(&self) -> Option<Instant> {
+        let inner = self.inner.borrow();
+        let held = inner.held.iter().map(|(_, due, _)| *due);
+        inner.outgoing.iter().map(|(due, _)| *due).chain(held).min()
+    }
+
+    /// Applies `model` to frames transmitted from now on.
+    pub fn set_link_model(&self, model: LinkModel) {
+        for p in &[
+            model.drop_probability,
+            model.duplicate_probability,
+            model.reorder_probability,
+        ] {
+            assert!((0.0..=1.0).contains(p), "invalid probability {}", p);
+        }
+        let mut inner = self.inner.borrow_mut();
+        inner.link_rng = SmallRng::seed_from_u64(model.seed);
+        inner.bucket = TokenBucket {
+            tokens: model.burst as f64,
+            updated: inner.timer.0.now(),
+            queue: VecDeque::new(),
+        };
+        inner.link_model = model;
     }
 
     pub fn push_frame(&self, buf: Bytes) {
@@ -102,23 +282,176 @@ impl TestRuntime {
 }
 
 struct Inner {
-    #[allow(unused)]
     name: &'static str,
     timer: TimerRc,
     rng: SmallRng,
     incoming: VecDeque<Bytes>,
-    outgoing: VecDeque<Bytes>,
+    /// Transmitted frames, ordered by the time they get delivered.
+    outgoing: VecDeque<(Instant, Bytes)>,
 
     link_addr: MacAddress,
     ipv4_addr: Ipv4Addr,
     tcp_options: tcp::Options<TestRuntime>,
     arp_options: arp::Options,
+
+    link_model: LinkModel,
+    link_rng: SmallRng,
+    /// Frames held back for reordering, with the number of frames still to overtake them.
+    held: Vec<(usize, Instant, Bytes)>,
+    bucket: TokenBucket,
+
+    capture: Option<PcapWriter<File>>,
+    /// Time mapped onto the Unix epoch in captures.
+    epoch: Instant,
+    skew: ClockSkew,
+    checksum_offload: ChecksumOffload,
+    /// Reference time the runtime was last advanced to.
+    reference: Instant,
+
+    exhaustion: HashMap<Resource, Exhaustion>,
+}
+
+impl Inner {
+    fn capture_frame(&mut self, frame: &[u8]) {
+        let timestamp = self.timer.0.now() - self.epoch;
+        if let Some(capture) = self.capture.as_mut() {
+            if let Err(e) = capture.write_frame(timestamp, frame) {
+                warn!("Stopped capturing traffic of {}: {:?}", self.name, e);
+                self.capture = None;
+            }
+        }
+    }
+
+    /// Hands a transmitted frame over to the link.
+    fn transmit_frame(&mut self, frame: Bytes) {
+        let model = self.link_model;
+        if self.link_rng.gen_bool(model.drop_probability) {
+            return;
+        }
+        let copies = if self.link_rng.gen_bool(model.duplicate_probability) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut due = match self.shape(frame.len()) {
+                Some(release) => release + model.latency,
+                None => continue,
+            };
+            if model.jitter > Duration::ZERO {
+                due += self.link_rng.gen_range(Duration::ZERO..=model.jitter);
+            }
+            if model.reorder_window > 0 && self.link_rng.gen_bool(model.reorder_probability) {
+                let overtakers = self.link_rng.gen_range(1..=model.reorder_window);
+                self.held.push((overtakers, due, frame.clone()));
+            } else {
+                self.enqueue_outgoing(due, frame.clone());
+            }
+        }
+    }
+
+    /// Runs a frame of `len` bytes through the token bucket. Returns the time at which the frame
+    /// is released onto the link, or `None` if it is tail dropped.
+    fn shape(&mut self, len: usize) -> Option<Instant> {
+        let now = self.timer.0.now();
+        let model = self.link_model;
+        if model.rate == 0 {
+            return Some(now);
+        }
+        let bucket = &mut self.bucket;
+        while bucket
+            .queue
+            .front()
+            .map_or(false, |release| *release <= now)
+        {
+            bucket.queue.pop_front();
+        }
+        if model.queue_limit > 0 && bucket.queue.len() >= model.queue_limit {
+            return None;
+        }
+
+        // Frames leave in order, so this one starts waiting once the previous one is released.
+        let start = cmp::max(now, bucket.updated);
+        let refill = (start - bucket.updated).as_secs_f64() * model.rate as f64;
+        let tokens = (bucket.tokens + refill).min(model.burst as f64);
+        let release = if tokens >= len as f64 {
+            bucket.tokens = tokens - len as f64;
+            start
+        } else {
+            bucket.tokens = 0.0;
+            start + Duration::from_secs_f64((len as f64 - tokens) / model.rate as f64)
+        };
+        bucket.updated = release;
+        if release > now {
+            bucket.queue.push_back(release);
+        }
+        Some(release)
+    }
+
+    fn enqueue_outgoing(&mut self, due: Instant, frame: Bytes) {
+        self.insert_outgoing(due, frame);
+        let mut i = 0;
+        while i < self.held.len() {
+            self.held[i].0 -= 1;
+            if self.held[i].0 == 0 {
+                let (_, due, frame) = self.held.remove(i);
+                self.insert_outgoing(due, frame);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    fn insert_outgoing(&mut self, due: Instant, frame: Bytes) {
+        let i = self
+            .outgoing
+            .iter()
+            .position(|(d, _)| *d > due)
+            .unwrap_or_else(|| self.outgoing.len());
+        self.outgoing.insert(i, (due, frame));
+    }
+
+    /// Returns the local time at `reference` time.
+    fn local_time(&self, reference: Instant) -> Instant {
+        let elapsed = (reference - self.epoch).as_nanos();
+        let drift = elapsed * self.skew.drift_ppm.unsigned_abs() as u128 / 1_000_000;
+        let drift = Duration::from_nanos(drift as u64);
+        let local = reference + self.skew.offset;
+        if self.skew.drift_ppm < 0 {
+            local - drift
+        } else {
+            local + drift
+        }
+    }
+
+    fn advance_local_clock(&mut self, reference: Instant) {
+        self.reference = reference;
+        let local = cmp::max(self.local_time(reference), self.timer.0.now());
+        self.timer.0.advance_clock(local);
+    }
+
+    /// Pops the next frame whose delivery time has come.
+    fn pop_outgoing(&mut self) -> Option<Bytes> {
+        // Once the link goes idle, frames still held back are delivered.
+        if self.outgoing.is_empty() {
+            for (_, due, frame) in std::mem::take(&mut self.held) {
+                self.insert_outgoing(due, frame);
+            }
+        }
+        match self.outgoing.front() {
+            Some((due, _)) if *due <= self.timer.0.now() => {
+                self.outgoing.pop_front().map(|(_, frame)| frame)
+            }
+            _ => None,
+        }
+    }
 }
 
 impl Runtime for TestRuntime {
     type Buf = Bytes;
     type WaitFuture = crate::timer::WaitFuture<TimerRc>;
 
+    #[cfg(not(target_arch = "wasm32"))]
     fn into_sgarray(&self, buf: Bytes) -> dmtr_sgarray_t {
         let buf_copy: Box<[u8]> = (&buf[..]).into();
         let ptr = Box::into_raw(buf_copy);
@@ -134,6 +467,7 @@ impl Runtime for TestRuntime {
         }
     }
 
+    #[cfg(not(target_arch = "wasm32"))]
     fn alloc_sgarray(&self, size: usize) -> dmtr_sgarray_t {
         let allocation: Box<[u8]> = unsafe { Box::new_uninit_slice(size).assume_init() };
         let ptr = Box::into_raw(allocation);
@@ -149,6 +483,7 @@ impl Runtime for TestRuntime {
         }
     }
 
+    #[cfg(not(target_arch = "wasm32"))]
     fn free_sgarray(&self, sga: dmtr_sgarray_t) {
         assert_eq!(sga.sga_numsegs, 1);
         let sgaseg = sga.sga_segs[0];
@@ -161,6 +496,7 @@ impl Runtime for TestRuntime {
         drop(allocation);
     }
 
+    #[cfg(not(target_arch = "wasm32"))]
     fn clone_sgarray(&self, sga: &dmtr_sgarray_t) -> Bytes {
         let mut len = 0;
         for i in 0..sga.sga_numsegs as usize {
@@ -188,7 +524,11 @@ impl Runtime for TestRuntime {
         if let Some(body) = pkt.take_body() {
             buf[header_size..].copy_from_slice(&body[..]);
         }
-        self.inner.borrow_mut().outgoing.push_back(buf.freeze());
+        let frame = buf.freeze();
+        self.tap.notify(Direction::Transmit, self.now(), &frame);
+        let mut inner = self.inner.borrow_mut();
+        inner.capture_frame(&frame);
+        inner.transmit_frame(frame);
     }
 
     fn receive(&self) -> ArrayVec<Bytes, RECEIVE_BATCH_SIZE> {
@@ -199,6 +539,43 @@ impl Runtime for TestRuntime {
         out
     }
 
+    fn on_receive(&self, frame: &Bytes) {
+        self.inner.borrow_mut().capture_frame(frame);
+    }
+
+    fn packet_tap(&self) -> Option<PacketTap> {
+        Some(self.tap.clone())
+    }
+
+    fn packet_filter(&self) -> Option<PacketFilter> {
+        Some(self.filter.clone())
+    }
+
+    fn checksum_offload(&self) -> ChecksumOffload {
+        self.inner.borrow().checksum_offload
+    }
+
+    fn timer_stats(&self) -> Option<TimerStats> {
+        Some(self.inner.borrow().timer.0.stats())
+    }
+
+    fn exhausted(&self, resource: Resource) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        let exhaustion = match inner.exhaustion.get_mut(&resource) {
+            Some(exhaustion) => exhaustion,
+            None => return false,
+        };
+        if exhaustion.skip > 0 {
+            exhaustion.skip -= 1;
+            return false;
+        }
+        if exhaustion.count > 0 {
+            exhaustion.count -= 1;
+            return true;
+        }
+        false
+    }
+
     fn scheduler(&self) -> &Scheduler<Operation<Self>> {
         &self.scheduler
     }
@@ -224,7 +601,7 @@ impl Runtime for TestRuntime {
     }
 
     fn advance_clock(&self, now: Instant) {
-        self.inner.borrow_mut().timer.0.advance_clock(now);
+        self.inner.borrow_mut().advance_local_clock(now);
     }
 
     fn wait(&self, duration: Duration) -> Self::WaitFuture {
@@ -264,6 +641,16 @@ impl Runtime for TestRuntime {
     }
 }
 
+/// Returns the path of the capture of runtime `name` in the test running on this thread.
+fn capture_path(dir: &Path, name: &str) -> PathBuf {
+    // The test harness names each thread after the test it runs.
+    let test = thread::current()
+        .name()
+        .unwrap_or("unnamed")
+        .replace("::", ".");
+    dir.join(format!("{}.{}.pcap", test, name))
+}
+
 pub fn new_alice(now: Instant) -> Engine<TestRuntime> {
     let rt = TestRuntime::new("alice", now, ALICE_MAC, ALICE_IPV4);
     Engine::new(rt).unwrap()
@@ -275,26 +662,234 @@ pub fn new_bob(now: Instant) -> Engine<TestRuntime> {
 }
 
 pub fn new_alice2(now: Instant) -> Engine<TestRuntime> {
+    Engine::new(alice_runtime(now)).unwrap()
+}
+
+pub fn new_bob2(now: Instant) -> Engine<TestRuntime> {
+    Engine::new(bob_runtime(now)).unwrap()
+}
+
+/// Returns the runtime of [new_alice2], to adjust before creating an engine from it.
+pub fn alice_runtime(now: Instant) -> TestRuntime {
     let rt = TestRuntime::new("alice", now, ALICE_MAC, ALICE_IPV4);
     {
         let arp_options: &mut _ = &mut rt.inner.borrow_mut().arp_options;
         arp_options.initial_values.insert(ALICE_IPV4, ALICE_MAC);
         arp_options.initial_values.insert(BOB_IPV4, BOB_MAC);
     }
-    Engine::new(rt).unwrap()
+    rt
 }
 
-pub fn new_bob2(now: Instant) -> Engine<TestRuntime> {
+/// Returns the runtime of [new_bob2], to adjust before creating an engine from it.
+pub fn bob_runtime(now: Instant) -> TestRuntime {
     let rt = TestRuntime::new("bob", now, BOB_MAC, BOB_IPV4);
     {
         let arp_options: &mut _ = &mut rt.inner.borrow_mut().arp_options;
         arp_options.initial_values.insert(BOB_IPV4, BOB_MAC);
         arp_options.initial_values.insert(ALICE_IPV4, ALICE_MAC);
     }
-    Engine::new(rt).unwrap()
+    rt
 }
 
 pub fn new_carrie(now: Instant) -> Engine<TestRuntime> {
     let rt = TestRuntime::new("carrie", now, CARRIE_MAC, CARRIE_IPV4);
     Engine::new(rt).unwrap()
 }
+
+/// A learning Ethernet switch connecting any number of test engines.
+///
+/// Frames are only moved when [VirtualSwitch::forward] is called, so tests keep full control over
+/// the order of events. Frames to broadcast, multicast, and not yet learned addresses are flooded
+/// to every port except the one they came in on.
+pub struct VirtualSwitch {
+    ports: Vec<TestEngine>,
+    mac_table: HashMap<MacAddress, usize>,
+}
+
+impl VirtualSwitch {
+    pub fn new() -> Self {
+        Self {
+            ports: Vec::new(),
+            mac_table: HashMap::new(),
+        }
+    }
+
+    /// Plugs `engine` into a new port and returns the port number.
+    pub fn attach(&mut self, engine: TestEngine) -> usize {
+        self.ports.push(engine);
+        self.ports.len() - 1
+    }
+
+    pub fn engine(&mut self, port: usize) -> &mut TestEngine {
+        &mut self.ports[port]
+    }
+
+    pub fn num_ports(&self) -> usize {
+        self.ports.len()
+    }
+
+    /// Returns the port on which `link_addr` was last seen.
+    pub fn lookup(&self, link_addr: MacAddress) -> Option<usize> {
+        self.mac_table.get(&link_addr).copied()
+    }
+
+    /// Advances the clock of every attached engine and runs their background work.
+    pub fn advance_clock(&mut self, now: Instant) {
+        for engine in &self.ports {
+            engine.rt().advance_clock(now);
+            engine.rt().poll_scheduler();
+        }
+    }
+
+    /// Moves every frame ready for delivery to its destination ports. Returns the number of frames
+    /// taken from the engines.
+    pub fn forward(&mut self) -> usize {
+        let mut frames = Vec::new();
+        for (port, engine) in self.ports.iter().enumerate() {
+            while let Some(frame) = engine.rt().pop_frame_unchecked() {
+                frames.push((port, frame));
+            }
+        }
+        let count = frames.len();
+        for (in_port, frame) in frames {
+            let header = match Ethernet2Header::parse(frame.clone()) {
+                Ok((header, _)) => header,
+                Err(e) => {
+                    warn!("Switch dropped malformed frame: {:?}", e);
+                    continue;
+                }
+            };
+            if header.src_addr.is_unicast() {
+                self.mac_table.insert(header.src_addr, in_port);
+            }
+            let out_port = match header.dst_addr.is_unicast() {
+                true => self.lookup(header.dst_addr),
+                false => None,
+            };
+            for port in 0..self.ports.len() {
+                if port == in_port || out_port.map_or(false, |out_port| out_port != port) {
+                    continue;
+                }
+                if let Err(e) = self.ports[port].receive(frame.clone()) {
+                    debug!("Port {} dropped {}: {:?}", port, header, e);
+                }
+            }
+        }
+        count
+    }
+}
+
+impl Default for VirtualSwitch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//==============================================================================
+// UDP
+//==============================================================================
+
+/// Opens a UDP socket on `engine` and binds it to `port` of the engine's address.
+pub fn udp_socket_setup(
+    engine: &mut Engine<TestRuntime>,
+    port: u16,
+) -> (FileDescriptor, ipv4::Endpoint) {
+    let port = ip::Port::try_from(port).unwrap();
+    let addr = ipv4::Endpoint::new(engine.rt().local_ipv4_addr(), port);
+    let fd: FileDescriptor = engine.udp_socket().unwrap();
+    engine.udp_bind(fd, addr).unwrap();
+    (fd, addr)
+}
+
+/// Sends `buf` from `sender_fd` to `receiver_addr` and returns the frame put on the wire, after
+/// checking it with [check_udp_packet].
+pub fn udp_send(
+    sender: &mut Engine<TestRuntime>,
+    sender_fd: FileDescriptor,
+    sender_addr: ipv4::Endpoint,
+    receiver_addr: ipv4::Endpoint,
+    receiver_mac: MacAddress,
+    buf: Bytes,
+) -> Bytes {
+    sender
+        .udp_pushto(sender_fd, buf.clone(), receiver_addr)
+        .unwrap();
+    sender.rt().poll_scheduler();
+    let bytes = sender.rt().pop_frame();
+    check_udp_packet(
+        bytes.clone(),
+        sender.rt().local_link_addr(),
+        receiver_mac,
+        sender_addr,
+        receiver_addr,
+        &buf,
+    );
+    bytes
+}
+
+/// Delivers `bytes` to `receiver` and checks that popping `receiver_fd` yields `expected` from
+/// `sender_addr`.
+pub fn udp_recv(
+    receiver: &mut Engine<TestRuntime>,
+    receiver_fd: FileDescriptor,
+    sender_addr: ipv4::Endpoint,
+    bytes: Bytes,
+    expected: &Bytes,
+) {
+    let mut ctx = Context::from_waker(noop_waker_ref());
+    receiver.receive(bytes).unwrap();
+    let mut pop_future = receiver.udp_pop(receiver_fd);
+    match Future::poll(Pin::new(&mut pop_future), &mut ctx) {
+        Poll::Ready(Ok((remote_addr, received))) => {
+            assert_eq!(remote_addr, Some(sender_addr));
+            assert_eq!(&received, expected);
+        }
+        Poll::Ready(Err(e)) => panic!("UDP pop failed: {:?}", e),
+        Poll::Pending => panic!("UDP pop did not complete"),
+    }
+}
+
+/// Sends `buf` from one bound UDP socket to another and checks that it arrives intact.
+pub fn udp_send_recv_round(
+    sender: &mut Engine<TestRuntime>,
+    sender_fd: FileDescriptor,
+    sender_addr: ipv4::Endpoint,
+    receiver: &mut Engine<TestRuntime>,
+    receiver_fd: FileDescriptor,
+    receiver_addr: ipv4::Endpoint,
+    buf: Bytes,
+) {
+    let receiver_mac = receiver.rt().local_link_addr();
+    let bytes = udp_send(
+        sender,
+        sender_fd,
+        sender_addr,
+        receiver_addr,
+        receiver_mac,
+        buf.clone(),
+    );
+    udp_recv(receiver, receiver_fd, sender_addr, bytes, &buf);
+}
+
+/// Checks for a UDP datagram carrying `payload`.
+pub fn check_udp_packet(
+    bytes: Bytes,
+    eth2_src_addr: MacAddress,
+    eth2_dst_addr: MacAddress,
+    src_addr: ipv4::Endpoint,
+    dst_addr: ipv4::Endpoint,
+    payload: &[u8],
+) {
+    let (eth2_header, eth2_payload) = Ethernet2Header::parse(bytes).unwrap();
+    assert_eq!(eth2_header.src_addr, eth2_src_addr);
+    assert_eq!(eth2_header.dst_addr, eth2_dst_addr);
+    assert_eq!(eth2_header.ether_type, EtherType2::Ipv4);
+    let (ipv4_header, ipv4_payload) = Ipv4Header::parse(eth2_payload).unwrap();
+    assert_eq!(ipv4_header.protocol, Ipv4Protocol2::Udp);
+    assert_eq!(ipv4_header.src_addr, src_addr.address());
+    assert_eq!(ipv4_header.dst_addr, dst_addr.address());
+    let (udp_header, udp_payload) = UdpHeader::parse(&ipv4_header, ipv4_payload, false).unwrap();
+    assert_eq!(udp_header.src_port(), Some(src_addr.port()));
+    assert_eq!(udp_header.dest_port(), dst_addr.port());
+    assert_eq!(&udp_payload[..], payload);
+}
@@ -73,6 +73,16 @@ impl Ord for TimerQueueEntry {
 struct TimerInner {
     now: Instant,
     heap: PairingHeap<TimerQueueEntry>,
+    stats: TimerStats,
+}
+
+/// Counters of a [Timer].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TimerStats {
+    /// Timers registered and waiting to expire.
+    pub pending: usize,
+    /// Timers that expired while registered, over the life of the timer.
+    pub expired: u64,
 }
 
 pub struct Timer<P: TimerPtr> {
@@ -85,6 +95,7 @@ impl<P: TimerPtr> Timer<P> {
         let inner = TimerInner {
             now,
             heap: PairingHeap::new(),
+            stats: TimerStats::default(),
         };
         Self {
             inner: RefCell::new(inner),
@@ -108,6 +119,8 @@ impl<P: TimerPtr> Timer<P> {
                     task.wake();
                 }
                 inner.heap.remove(entry);
+                inner.stats.pending -= 1;
+                inner.stats.expired += 1;
             }
         }
         inner.now = now;
@@ -117,6 +130,10 @@ impl<P: TimerPtr> Timer<P> {
         self.inner.borrow().now
     }
 
+    pub fn stats(&self) -> TimerStats {
+        self.inner.borrow().stats
+    }
+
     pub fn wait(&self, ptr: P, timeout: Duration) -> WaitFuture<P> {
         self.wait_until(ptr, self.now() + timeout)
     }
@@ -168,6 +185,7 @@ impl<P: TimerPtr> Future for WaitFuture<P> {
                         unsafe {
                             inner.heap.insert(wait_node);
                         }
+                        inner.stats.pending += 1;
                         Poll::Pending
                     }
                 }
@@ -204,13 +222,9 @@ impl<P: TimerPtr> Drop for WaitFuture<P> {
         // Otherwise the timer would access invalid memory.
         if let Some(ptr) = &self.ptr {
             if let PollState::Registered = self.wait_node.state {
-                unsafe {
-                    ptr.timer()
-                        .inner
-                        .borrow_mut()
-                        .heap
-                        .remove(&mut self.wait_node)
-                };
+                let mut inner = ptr.timer().inner.borrow_mut();
+                unsafe { inner.heap.remove(&mut self.wait_node) };
+                inner.stats.pending -= 1;
                 self.wait_node.state = PollState::Unregistered;
             }
         }
@@ -262,10 +276,14 @@ mod tests {
 
         assert!(Future::poll(Pin::new(&mut wait_future1), &mut ctx).is_pending());
         assert!(Future::poll(Pin::new(&mut wait_future2), &mut ctx).is_ready());
+        assert_eq!(timer.stats().pending, 1);
+        assert_eq!(timer.stats().expired, 1);
 
         now += Duration::from_millis(750);
         timer.advance_clock(now);
 
         assert!(Future::poll(Pin::new(&mut wait_future1), &mut ctx).is_ready());
+        assert_eq!(timer.stats().pending, 0);
+        assert_eq!(timer.stats().expired, 2);
     }
 }
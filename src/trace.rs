@@ -0,0 +1,248 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Logging of a header summary and a bounded hexdump of every frame of selected sockets, to debug
+//! interop problems without an external capture. Frames are seen through the
+//! [PacketTap](crate::tap::PacketTap) of the
+//! runtime.
+
+use crate::{
+    file_table::FileDescriptor,
+    protocols::{ip, ipv4, Protocol},
+    tap::{Direction, TappedFrame},
+};
+use byteorder::{BigEndian, ByteOrder};
+use std::{
+    cell::RefCell, collections::HashMap, convert::TryFrom, fmt::Write, net::Ipv4Addr, rc::Rc,
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// Bytes of each frame dumped, starting with its Ethernet header.
+pub const MAX_HEXDUMP_LEN: usize = 128;
+
+const ETHERNET2_HEADER_SIZE: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const IPV4_PROTOCOL_TCP: u8 = 6;
+const IPV4_PROTOCOL_UDP: u8 = 17;
+
+/// Frames of a traced socket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TraceFilter {
+    pub protocol: Protocol,
+    pub local: ipv4::Endpoint,
+    /// Remote of a connected socket. Frames from any remote match if unset.
+    pub remote: Option<ipv4::Endpoint>,
+}
+
+/// Addresses and headers of a TCP or UDP frame.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FrameSummary {
+    pub protocol: Protocol,
+    pub src: ipv4::Endpoint,
+    pub dst: ipv4::Endpoint,
+    /// Flags of a TCP segment, zero for UDP.
+    pub tcp_flags: u8,
+    /// Remaining header fields, formatted for humans.
+    pub detail: String,
+}
+
+/// Sockets being traced, shared with the tap subscriber that logs their frames.
+#[derive(Clone, Default)]
+pub struct Tracer {
+    inner: Rc<RefCell<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    sockets: HashMap<FileDescriptor, TraceFilter>,
+    /// Whether the tracer subscribed to the packet tap already.
+    subscribed: bool,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+/// Associate functions for [TraceFilter].
+impl TraceFilter {
+    /// Returns whether `summary` describes a frame to or from the traced socket.
+    pub fn matches(&self, summary: &FrameSummary) -> bool {
+        let is_local = |e: &ipv4::Endpoint| {
+            e.port == self.local.port
+                && (self.local.addr.is_unspecified() || e.addr == self.local.addr)
+        };
+        let is_remote = |e: &ipv4::Endpoint| self.remote.map_or(true, |r| *e == r);
+        summary.protocol == self.protocol
+            && ((is_local(&summary.src) && is_remote(&summary.dst))
+                || (is_local(&summary.dst) && is_remote(&summary.src)))
+    }
+}
+
+/// Associate functions for [Tracer].
+impl Tracer {
+    /// Starts tracing the frames of `fd` that match `filter`.
+    pub fn trace(&self, fd: FileDescriptor, filter: TraceFilter) {
+        self.inner.borrow_mut().sockets.insert(fd, filter);
+    }
+
+    /// Stops tracing `fd`.
+    pub fn untrace(&self, fd: FileDescriptor) {
+        self.inner.borrow_mut().sockets.remove(&fd);
+    }
+
+    /// Returns whether `fd` is being traced.
+    pub fn is_traced(&self, fd: FileDescriptor) -> bool {
+        self.inner.borrow().sockets.contains_key(&fd)
+    }
+
+    /// Marks the tracer as subscribed to a packet tap, returning whether it was already.
+    pub fn set_subscribed(&self) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        let subscribed = inner.subscribed;
+        inner.subscribed = true;
+        subscribed
+    }
+
+    /// Logs `frame` if it belongs to a traced socket.
+    pub fn on_frame(&self, frame: &TappedFrame) {
+        let inner = self.inner.borrow();
+        if inner.sockets.is_empty() {
+            return;
+        }
+        let summary = match summarize(frame.data) {
+            Some(s) => s,
+            None => return,
+        };
+        for (fd, filter) in inner.sockets.iter() {
+            if filter.matches(&summary) {
+                let direction = match frame.direction {
+                    Direction::Transmit => "TX",
+                    Direction::Receive => "RX",
+                };
+                info!(
+                    "fd {} {} {:?} {} -> {} {}\n{}",
+                    fd,
+                    direction,
+                    summary.protocol,
+                    format_endpoint(&summary.src),
+                    format_endpoint(&summary.dst),
+                    summary.detail,
+                    hexdump(frame.data, MAX_HEXDUMP_LEN)
+                );
+                return;
+            }
+        }
+    }
+}
+
+//==============================================================================
+// Standalone Functions
+//==============================================================================
+
+/// Parses the headers of a TCP or UDP over IPv4 frame, without validating checksums.
+pub fn summarize(data: &[u8]) -> Option<FrameSummary> {
+    if data.len() < ETHERNET2_HEADER_SIZE || BigEndian::read_u16(&data[12..14]) != ETHERTYPE_IPV4 {
+        return None;
+    }
+    let ip_hdr = &data[ETHERNET2_HEADER_SIZE..];
+    if ip_hdr.len() < 20 {
+        return None;
+    }
+    let ihl = (ip_hdr[0] & 0xf) as usize * 4;
+    if ihl < 20 || ip_hdr.len() < ihl {
+        return None;
+    }
+    let total_len = BigEndian::read_u16(&ip_hdr[2..4]) as usize;
+    let src_addr = Ipv4Addr::new(ip_hdr[12], ip_hdr[13], ip_hdr[14], ip_hdr[15]);
+    let dst_addr = Ipv4Addr::new(ip_hdr[16], ip_hdr[17], ip_hdr[18], ip_hdr[19]);
+    let l4 = &ip_hdr[ihl..];
+    if l4.len() < 8 {
+        return None;
+    }
+    let src_port = ip::Port::try_from(BigEndian::read_u16(&l4[0..2])).ok()?;
+    let dst_port = ip::Port::try_from(BigEndian::read_u16(&l4[2..4])).ok()?;
+
+    let (protocol, tcp_flags, detail) = match ip_hdr[9] {
+        IPV4_PROTOCOL_TCP => {
+            if l4.len() < 20 {
+                return None;
+            }
+            let data_offset = (l4[12] >> 4) as usize * 4;
+            let flags = l4[13];
+            let names = ["FIN", "SYN", "RST", "PSH", "ACK", "URG", "ECE", "CWR"];
+            let set: Vec<&str> = names
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| flags & (1 << i) != 0)
+                .map(|(_, n)| *n)
+                .collect();
+            let detail = format!(
+                "[{}] seq={} ack={} win={} len={}",
+                set.join(" "),
+                BigEndian::read_u32(&l4[4..8]),
+                BigEndian::read_u32(&l4[8..12]),
+                BigEndian::read_u16(&l4[14..16]),
+                total_len.saturating_sub(ihl + data_offset)
+            );
+            (Protocol::Tcp, flags, detail)
+        }
+        IPV4_PROTOCOL_UDP => {
+            let len = BigEndian::read_u16(&l4[4..6]) as usize;
+            (Protocol::Udp, 0, format!("len={}", len.saturating_sub(8)))
+        }
+        _ => return None,
+    };
+    Some(FrameSummary {
+        protocol,
+        src: ipv4::Endpoint::new(src_addr, src_port),
+        dst: ipv4::Endpoint::new(dst_addr, dst_port),
+        tcp_flags,
+        detail,
+    })
+}
+
+/// Formats up to `max_len` bytes of `data` as offset-prefixed lines of 16 bytes each.
+pub fn hexdump(data: &[u8], max_len: usize) -> String {
+    let mut out = String::new();
+    let shown = &data[..data.len().min(max_len)];
+    for (i, line) in shown.chunks(16).enumerate() {
+        write!(out, "{:04x}:", i * 16).unwrap();
+        for byte in line {
+            write!(out, " {:02x}", byte).unwrap();
+        }
+        out.push('\n');
+    }
+    if data.len() > shown.len() {
+        writeln!(out, "... {} more bytes", data.len() - shown.len()).unwrap();
+    }
+    out
+}
+
+fn format_endpoint(e: &ipv4::Endpoint) -> String {
+    format!("{}:{}", e.addr, e.port)
+}
+
+//==============================================================================
+// Unit Tests
+//==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::hexdump;
+
+    #[test]
+    fn test_hexdump() {
+        let data: Vec<u8> = (0..40).collect();
+        let dump = hexdump(&data, 20);
+        assert_eq!(
+            dump,
+            "0000: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n\
+             0010: 10 11 12 13\n\
+             ... 20 more bytes\n"
+        );
+        assert_eq!(hexdump(&data[..2], 20), "0000: 00 01\n");
+    }
+}
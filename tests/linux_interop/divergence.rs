@@ -0,0 +1,160 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use super::tap::Direction;
+
+use catnip::{
+    collections::bytes::Bytes,
+    protocols::{
+        ethernet2::{EtherType2, Ethernet2Header},
+        ipv4::{Ipv4Header, Ipv4Protocol2},
+        tcp::segment::{TcpHeader, TcpOptions2},
+    },
+};
+
+use std::{collections::HashMap, num::Wrapping};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// What one side of a connection told the other.
+#[derive(Default)]
+struct Side {
+    /// Options carried by the SYN, if seen.
+    syn_options: Option<Vec<TcpOptions2>>,
+    /// Highest sequence number the side may be sent, as last advertised.
+    right_edge: Option<Wrapping<u32>>,
+}
+
+#[derive(Default)]
+struct Connection {
+    engine: Side,
+    linux: Side,
+}
+
+//==============================================================================
+// Standalone Functions
+//==============================================================================
+
+/// Inspects the TCP segments of `trace` and describes any behavior in which the engine and the
+/// kernel disagree: resets, options answered without being offered, data sent beyond the
+/// advertised window, and segments the engine cannot parse.
+pub fn find(trace: &[(Direction, Bytes)]) -> Vec<String> {
+    let mut divergences = Vec::new();
+    let mut connections: HashMap<(u16, u16), Connection> = HashMap::new();
+    for (direction, frame) in trace {
+        let (header, payload) = match parse(frame.clone()) {
+            Some(Ok(segment)) => segment,
+            Some(Err(e)) => {
+                divergences.push(format!("{:?} segment failed to parse: {}", direction, e));
+                continue;
+            }
+            None => continue,
+        };
+        let who = match direction {
+            Direction::Tx => "engine",
+            Direction::Rx => "Linux",
+        };
+        let key: (u16, u16) = match direction {
+            Direction::Tx => (header.src_port.into(), header.dst_port.into()),
+            Direction::Rx => (header.dst_port.into(), header.src_port.into()),
+        };
+        let connection = connections.entry(key).or_default();
+        let (sender, receiver) = match direction {
+            Direction::Tx => (&mut connection.engine, &mut connection.linux),
+            Direction::Rx => (&mut connection.linux, &mut connection.engine),
+        };
+
+        if header.rst {
+            divergences.push(format!("{} sent a reset: {}", who, header));
+        }
+        if header.syn {
+            let options: Vec<TcpOptions2> = header.iter_options().copied().collect();
+            if header.ack {
+                let offered = receiver.syn_options.as_deref().unwrap_or(&[]);
+                for option in &options {
+                    let answered_unoffered = match option {
+                        TcpOptions2::MaximumSegmentSize(..) | TcpOptions2::NoOperation => false,
+                        _ => !offered
+                            .iter()
+                            .any(|o| std::mem::discriminant(o) == std::mem::discriminant(option)),
+                    };
+                    if answered_unoffered {
+                        divergences.push(format!(
+                            "{} answered with option {} that was not offered: {}",
+                            who, option, header
+                        ));
+                    }
+                }
+            }
+            if !options
+                .iter()
+                .any(|o| matches!(o, TcpOptions2::MaximumSegmentSize(..)))
+            {
+                divergences.push(format!("{} sent a SYN without MSS: {}", who, header));
+            }
+            sender.syn_options = Some(options);
+        }
+
+        // Data (and FIN) must fit in the window the receiver advertised last.
+        let len = payload.len() as u32 + header.syn as u32 + header.fin as u32;
+        if len > 0 && !header.syn {
+            if let Some(right_edge) = receiver.right_edge {
+                let end = header.seq_num + Wrapping(len);
+                if (end - right_edge).0 as i32 > 0 {
+                    divergences.push(format!(
+                        "{} sent beyond the advertised window (right edge {}): {}, length {}",
+                        who,
+                        right_edge,
+                        header,
+                        payload.len()
+                    ));
+                }
+            }
+        }
+        if header.ack {
+            let shift = match header.syn {
+                // Windows of SYN segments are never scaled.
+                true => 0,
+                false => window_shift(sender, receiver),
+            };
+            let window = (header.window_size as u32) << shift;
+            sender.right_edge = Some(header.ack_num + Wrapping(window));
+        }
+    }
+    divergences
+}
+
+/// Returns the window scale `side` applies to the windows it advertises.
+fn window_shift(side: &Side, peer: &Side) -> u8 {
+    let scale = |side: &Side| {
+        side.syn_options.as_ref().and_then(|options| {
+            options.iter().find_map(|o| match o {
+                TcpOptions2::WindowScale(shift) => Some(*shift),
+                _ => None,
+            })
+        })
+    };
+    match (scale(side), scale(peer)) {
+        (Some(shift), Some(_)) => shift.min(14),
+        _ => 0,
+    }
+}
+
+/// Parses a TCP segment out of an Ethernet frame. Returns `None` for frames that do not carry
+/// TCP.
+fn parse(frame: Bytes) -> Option<Result<(TcpHeader, Bytes), String>> {
+    let (eth, payload) = Ethernet2Header::parse(frame).ok()?;
+    if eth.ether_type != EtherType2::Ipv4 {
+        return None;
+    }
+    let (ipv4, payload) = match Ipv4Header::parse(payload) {
+        Ok(datagram) => datagram,
+        Err(e) => return Some(Err(format!("{:?}", e))),
+    };
+    if ipv4.protocol != Ipv4Protocol2::Tcp {
+        return None;
+    }
+    Some(TcpHeader::parse(&ipv4, payload, false).map_err(|e| format!("{:?}", e)))
+}
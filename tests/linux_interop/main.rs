@@ -0,0 +1,283 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+//! Runs the engine against the Linux TCP/UDP stack.
+//!
+//! Each test creates a network namespace holding a TAP device. The kernel side of the device is
+//! configured inside the namespace, and the engine exchanges raw frames with it through the
+//! device. Kernel sockets are opened from threads that joined the namespace. Besides checking the
+//! outcome of each scenario, the tests inspect the frames exchanged and fail on behavioral
+//! divergences between the two stacks.
+//!
+//! These tests need `CAP_NET_ADMIN` and the `ip` tool, so they are ignored by default:
+//!
+//! ```text
+//! sudo -E cargo test --test linux_interop -- --ignored --test-threads 1
+//! ```
+
+#![cfg(target_os = "linux")]
+
+#[macro_use]
+extern crate log;
+
+mod divergence;
+mod netns;
+mod tap;
+
+use catnip::{
+    collections::bytes::Bytes,
+    file_table::FileDescriptor,
+    interop::{dmtr_opcode_t, dmtr_qresult_t},
+    libos::{LibOS, QToken},
+    protocols::{ethernet2::MacAddress, ip, ipv4},
+    runtime::{Runtime, RuntimeBuf},
+};
+
+use netns::Namespace;
+use tap::{TapDevice, TapRuntime};
+
+use std::{
+    convert::TryFrom,
+    io::{Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpListener, TcpStream, UdpSocket},
+    panic::{self, AssertUnwindSafe},
+    slice,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+const TAP_NAME: &str = "catnip0";
+const LINUX_IPV4: Ipv4Addr = Ipv4Addr::new(10, 11, 0, 1);
+const ENGINE_IPV4: Ipv4Addr = Ipv4Addr::new(10, 11, 0, 2);
+const ENGINE_MAC: MacAddress = MacAddress::new([0x02, 0x00, 0x00, 0x00, 0x00, 0x02]);
+const PORT: u16 = 5000;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Message exchanged by the scenarios; larger than a segment so that it gets split.
+fn message() -> Vec<u8> {
+    (0..4096u32).map(|i| i as u8).collect()
+}
+
+/// An engine attached to the kernel of a dedicated network namespace.
+struct Harness {
+    libos: LibOS<TapRuntime>,
+    ns: Namespace,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+impl Harness {
+    fn new(name: &str) -> Self {
+        let ns = Namespace::create(&format!("catnip-{}", name)).unwrap();
+        let device = ns
+            .spawn(|| TapDevice::open(TAP_NAME))
+            .join()
+            .unwrap()
+            .unwrap();
+        let addr = format!("{}/24", LINUX_IPV4);
+        ns.exec(&["ip", "addr", "add", &addr, "dev", TAP_NAME])
+            .unwrap();
+        ns.exec(&["ip", "link", "set", TAP_NAME, "up"]).unwrap();
+        // Keep IPv6 chatter off the link.
+        let sysctl = format!("net.ipv6.conf.{}.disable_ipv6=1", TAP_NAME);
+        let _ = ns.exec(&["sysctl", "-qw", &sysctl]);
+
+        let rt = TapRuntime::new(device, ENGINE_MAC, ENGINE_IPV4);
+        let libos = LibOS::new(rt).unwrap();
+        Self { libos, ns }
+    }
+
+    /// Polls the engine until `qt` completes.
+    fn wait(&mut self, qt: QToken) -> dmtr_qresult_t {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            if let Some(qr) = self.libos.poll(qt) {
+                return qr;
+            }
+            assert!(Instant::now() < deadline, "operation timed out");
+        }
+    }
+
+    /// Runs `f` inside the namespace, on a kernel-facing thread.
+    fn spawn<F: FnOnce() + Send + 'static>(&self, f: F) -> mpsc::Receiver<thread::Result<()>> {
+        let (done_tx, done_rx) = mpsc::channel();
+        self.ns.spawn(move || {
+            let _ = done_tx.send(panic::catch_unwind(AssertUnwindSafe(f)));
+        });
+        done_rx
+    }
+
+    /// Keeps the engine running until a thread started with [Harness::spawn] finishes, and
+    /// propagates its panics.
+    fn run_until_done(&mut self, done: mpsc::Receiver<thread::Result<()>>) {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            match done.try_recv() {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => panic::resume_unwind(e),
+                Err(_) => (),
+            }
+            self.libos.poll_bg_work();
+            assert!(Instant::now() < deadline, "scenario timed out");
+        }
+    }
+
+    /// Receives until `len` bytes arrived on `fd`.
+    fn recv_exact(&mut self, fd: FileDescriptor, len: usize) -> Vec<u8> {
+        let mut received = Vec::new();
+        while received.len() < len {
+            let qt = self.libos.pop(fd).unwrap();
+            let qr = self.wait(qt);
+            assert_eq!(qr.qr_opcode, dmtr_opcode_t::DMTR_OPC_POP);
+            let sga = unsafe { qr.qr_value.sga };
+            for seg in &sga.sga_segs[..sga.sga_numsegs as usize] {
+                received.extend_from_slice(unsafe {
+                    slice::from_raw_parts(seg.sgaseg_buf as *const u8, seg.sgaseg_len as usize)
+                });
+            }
+            self.libos.rt().free_sgarray(sga);
+        }
+        received
+    }
+
+    fn send(&mut self, fd: FileDescriptor, data: &[u8]) {
+        let qt = self.libos.push2(fd, Bytes::from_slice(data)).unwrap();
+        assert_eq!(self.wait(qt).qr_opcode, dmtr_opcode_t::DMTR_OPC_PUSH);
+    }
+
+    /// Fails the test if the engine and the kernel diverged.
+    fn check_divergences(&self) {
+        let divergences = divergence::find(&self.libos.rt().trace());
+        assert!(
+            divergences.is_empty(),
+            "Divergences from Linux:\n{}",
+            divergences.join("\n")
+        );
+    }
+}
+
+fn endpoint(addr: Ipv4Addr, port: u16) -> ipv4::Endpoint {
+    ipv4::Endpoint::new(addr, ip::Port::try_from(port).unwrap())
+}
+
+//==============================================================================
+// TCP
+//==============================================================================
+
+/// The engine connects to a Linux listener, sends a message, reads the echo back, and closes
+/// first.
+#[test]
+#[ignore]
+fn tcp_engine_to_linux() {
+    let mut harness = Harness::new("tcp-engine-to-linux");
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let server = harness.spawn(move || {
+        let listener = TcpListener::bind(SocketAddrV4::new(LINUX_IPV4, PORT)).unwrap();
+        ready_tx.send(()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut buf = vec![0u8; message().len()];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+        // The engine closes first, so the next read sees the end of the stream.
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    });
+    ready_rx.recv_timeout(TIMEOUT).unwrap();
+
+    let fd = harness
+        .libos
+        .socket(libc::AF_INET, libc::SOCK_STREAM, 0)
+        .unwrap();
+    let qt = harness
+        .libos
+        .connect(fd, endpoint(LINUX_IPV4, PORT))
+        .unwrap();
+    assert_eq!(harness.wait(qt).qr_opcode, dmtr_opcode_t::DMTR_OPC_CONNECT);
+    harness.send(fd, &message());
+    assert_eq!(harness.recv_exact(fd, message().len()), message());
+    harness.libos.close(fd).unwrap();
+
+    harness.run_until_done(server);
+    harness.check_divergences();
+}
+
+/// A Linux client connects to the engine, sends a message, reads the echo back, and closes
+/// first.
+#[test]
+#[ignore]
+fn tcp_linux_to_engine() {
+    let mut harness = Harness::new("tcp-linux-to-engine");
+    let fd = harness
+        .libos
+        .socket(libc::AF_INET, libc::SOCK_STREAM, 0)
+        .unwrap();
+    harness.libos.bind(fd, endpoint(ENGINE_IPV4, PORT)).unwrap();
+    harness.libos.listen(fd, 8).unwrap();
+    let accept_qt = harness.libos.accept(fd).unwrap();
+
+    let client = harness.spawn(move || {
+        let mut stream = TcpStream::connect(SocketAddrV4::new(ENGINE_IPV4, PORT)).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        stream.write_all(&message()).unwrap();
+        let mut buf = vec![0u8; message().len()];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, message());
+        stream.shutdown(Shutdown::Write).unwrap();
+    });
+
+    let qr = harness.wait(accept_qt);
+    assert_eq!(qr.qr_opcode, dmtr_opcode_t::DMTR_OPC_ACCEPT);
+    let qd = unsafe { qr.qr_value.ares.qd } as FileDescriptor;
+    let received = harness.recv_exact(qd, message().len());
+    harness.send(qd, &received);
+
+    harness.run_until_done(client);
+    harness.libos.close(qd).unwrap();
+    harness.libos.close(fd).unwrap();
+    harness.check_divergences();
+}
+
+//==============================================================================
+// UDP
+//==============================================================================
+
+/// The engine and a Linux socket exchange datagrams in both directions.
+#[test]
+#[ignore]
+fn udp_echo() {
+    let mut harness = Harness::new("udp-echo");
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let server = harness.spawn(move || {
+        let socket = UdpSocket::bind(SocketAddrV4::new(LINUX_IPV4, PORT)).unwrap();
+        socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+        ready_tx.send(()).unwrap();
+        let mut buf = [0u8; 1500];
+        let (len, from) = socket.recv_from(&mut buf).unwrap();
+        socket.send_to(&buf[..len], from).unwrap();
+    });
+    ready_rx.recv_timeout(TIMEOUT).unwrap();
+
+    let fd = harness
+        .libos
+        .socket(libc::AF_INET, libc::SOCK_DGRAM, 0)
+        .unwrap();
+    harness.libos.bind(fd, endpoint(ENGINE_IPV4, PORT)).unwrap();
+    let datagram = &message()[..1024];
+    let qt = harness
+        .libos
+        .pushto2(fd, Bytes::from_slice(datagram), endpoint(LINUX_IPV4, PORT))
+        .unwrap();
+    assert_eq!(harness.wait(qt).qr_opcode, dmtr_opcode_t::DMTR_OPC_PUSH);
+    assert_eq!(harness.recv_exact(fd, datagram.len()), datagram);
+
+    harness.run_until_done(server);
+    harness.libos.close(fd).unwrap();
+    harness.check_divergences();
+}
@@ -0,0 +1,84 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use std::{
+    fs::File,
+    io,
+    os::unix::io::AsRawFd,
+    process::Command,
+    thread::{self, JoinHandle},
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+/// A network namespace, deleted on drop.
+pub struct Namespace {
+    name: String,
+    file: File,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+impl Namespace {
+    /// Creates the network namespace `name` with `ip netns`.
+    pub fn create(name: &str) -> io::Result<Self> {
+        // Leftovers of an aborted run would make the creation fail.
+        let _ = Command::new("ip").args(&["netns", "del", name]).status();
+        run(Command::new("ip").args(&["netns", "add", name]))?;
+        let file = File::open(format!("/var/run/netns/{}", name))?;
+        Ok(Self {
+            name: name.to_string(),
+            file,
+        })
+    }
+
+    /// Runs a command inside the namespace.
+    pub fn exec(&self, args: &[&str]) -> io::Result<()> {
+        run(Command::new("ip")
+            .args(&["netns", "exec", &self.name])
+            .args(args))
+    }
+
+    /// Runs `f` on a new thread that has joined the namespace. Sockets and devices created by `f`
+    /// belong to the namespace.
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let fd = self.file.as_raw_fd();
+        thread::spawn(move || {
+            if unsafe { libc::setns(fd, libc::CLONE_NEWNET) } != 0 {
+                panic!("setns failed: {}", io::Error::last_os_error());
+            }
+            f()
+        })
+    }
+}
+
+fn run(command: &mut Command) -> io::Result<()> {
+    let status = command.status()?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{:?} exited with {}", command, status),
+        ));
+    }
+    Ok(())
+}
+
+//==============================================================================
+// Trait Implementations
+//==============================================================================
+
+impl Drop for Namespace {
+    fn drop(&mut self) {
+        let _ = Command::new("ip")
+            .args(&["netns", "del", &self.name])
+            .status();
+    }
+}
@@ -0,0 +1,338 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT license.
+
+use arrayvec::ArrayVec;
+
+use catnip::{
+    collections::bytes::{Bytes, BytesMut},
+    interop::{dmtr_sgarray_t, dmtr_sgaseg_t},
+    protocols::{arp, ethernet2::MacAddress, tcp, udp},
+    runtime::{PacketBuf, Runtime, RuntimeBuf, RECEIVE_BATCH_SIZE},
+    scheduler::{Operation, Scheduler, SchedulerHandle},
+    timer::{Timer, TimerRc},
+};
+
+use futures::FutureExt;
+
+use rand::{
+    distributions::{Distribution, Standard},
+    rngs::SmallRng,
+    seq::SliceRandom,
+    Rng, SeedableRng,
+};
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::CString,
+    future::Future,
+    io, mem,
+    net::Ipv4Addr,
+    os::unix::io::RawFd,
+    ptr,
+    rc::Rc,
+    slice,
+    time::{Duration, Instant},
+};
+
+//==============================================================================
+// Constants & Structures
+//==============================================================================
+
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const MAX_FRAME_SIZE: usize = 9216;
+
+/// Direction of a frame, as seen from the engine.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// Sent by the engine.
+    Tx,
+    /// Sent by the kernel.
+    Rx,
+}
+
+/// Request passed to `TUNSETIFF`.
+#[repr(C)]
+struct IfReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// File descriptor of a TAP device, closed on drop.
+pub struct TapDevice {
+    fd: RawFd,
+}
+
+/// A runtime that exchanges frames with the kernel through a TAP device and keeps a trace of
+/// them.
+#[derive(Clone)]
+pub struct TapRuntime {
+    inner: Rc<RefCell<Inner>>,
+    scheduler: Scheduler<Operation<TapRuntime>>,
+}
+
+struct Inner {
+    timer: TimerRc,
+    rng: SmallRng,
+    device: TapDevice,
+    trace: Vec<(Direction, Bytes)>,
+
+    link_addr: MacAddress,
+    ipv4_addr: Ipv4Addr,
+    tcp_options: tcp::Options<TapRuntime>,
+    arp_options: arp::Options,
+}
+
+//==============================================================================
+// Associate Functions
+//==============================================================================
+
+impl TapDevice {
+    /// Creates the TAP device `name` in the network namespace of the calling thread.
+    pub fn open(name: &str) -> io::Result<Self> {
+        let path = CString::new("/dev/net/tun").unwrap();
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let device = Self { fd };
+        let mut req = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: IFF_TAP | IFF_NO_PI,
+            _pad: [0; 22],
+        };
+        for (dst, src) in req.name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        if unsafe { libc::ioctl(device.fd, TUNSETIFF, &mut req as *mut IfReq) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(device)
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Option<usize> {
+        let n = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            assert_eq!(
+                e.kind(),
+                io::ErrorKind::WouldBlock,
+                "TAP read failed: {}",
+                e
+            );
+            return None;
+        }
+        Some(n as usize)
+    }
+
+    fn write(&self, buf: &[u8]) {
+        let n = unsafe { libc::write(self.fd, buf.as_ptr() as *const _, buf.len()) };
+        if n < 0 {
+            warn!("TAP write failed: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+impl TapRuntime {
+    pub fn new(device: TapDevice, link_addr: MacAddress, ipv4_addr: Ipv4Addr) -> Self {
+        let arp_options = arp::Options::new(
+            Duration::from_secs(600),
+            Duration::from_secs(1),
+            2,
+            HashMap::new(),
+            false,
+        );
+        let inner = Inner {
+            timer: TimerRc(Rc::new(Timer::new(Instant::now()))),
+            rng: SmallRng::from_seed([0; 32]),
+            device,
+            trace: Vec::new(),
+            link_addr,
+            ipv4_addr,
+            tcp_options: tcp::Options::default(),
+            arp_options,
+        };
+        Self {
+            inner: Rc::new(RefCell::new(inner)),
+            scheduler: Scheduler::new(),
+        }
+    }
+
+    /// Returns every frame exchanged so far.
+    pub fn trace(&self) -> Vec<(Direction, Bytes)> {
+        self.inner.borrow().trace.clone()
+    }
+}
+
+//==============================================================================
+// Trait Implementations
+//==============================================================================
+
+impl Drop for TapDevice {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl Runtime for TapRuntime {
+    type WaitFuture = catnip::timer::WaitFuture<TimerRc>;
+    type Buf = Bytes;
+
+    fn into_sgarray(&self, buf: Bytes) -> dmtr_sgarray_t {
+        let buf_copy: Box<[u8]> = (&buf[..]).into();
+        let ptr = Box::into_raw(buf_copy);
+        let sgaseg = dmtr_sgaseg_t {
+            sgaseg_buf: ptr as *mut _,
+            sgaseg_len: buf.len() as u32,
+        };
+        dmtr_sgarray_t {
+            sga_buf: ptr::null_mut(),
+            sga_numsegs: 1,
+            sga_segs: [sgaseg],
+            sga_addr: unsafe { mem::zeroed() },
+        }
+    }
+
+    fn alloc_sgarray(&self, size: usize) -> dmtr_sgarray_t {
+        let allocation: Box<[u8]> = vec![0u8; size].into_boxed_slice();
+        let ptr = Box::into_raw(allocation);
+        let sgaseg = dmtr_sgaseg_t {
+            sgaseg_buf: ptr as *mut _,
+            sgaseg_len: size as u32,
+        };
+        dmtr_sgarray_t {
+            sga_buf: ptr::null_mut(),
+            sga_numsegs: 1,
+            sga_segs: [sgaseg],
+            sga_addr: unsafe { mem::zeroed() },
+        }
+    }
+
+    fn free_sgarray(&self, sga: dmtr_sgarray_t) {
+        assert_eq!(sga.sga_numsegs, 1);
+        let seg = &sga.sga_segs[0];
+        let allocation: Box<[u8]> = unsafe {
+            Box::from_raw(slice::from_raw_parts_mut(
+                seg.sgaseg_buf as *mut _,
+                seg.sgaseg_len as usize,
+            ))
+        };
+        drop(allocation);
+    }
+
+    fn clone_sgarray(&self, sga: &dmtr_sgarray_t) -> Bytes {
+        let mut len = 0;
+        for i in 0..sga.sga_numsegs as usize {
+            len += sga.sga_segs[i].sgaseg_len;
+        }
+        let mut buf = BytesMut::zeroed(len as usize).unwrap();
+        let mut pos = 0;
+        for i in 0..sga.sga_numsegs as usize {
+            let seg = &sga.sga_segs[i];
+            let seg_slice = unsafe {
+                slice::from_raw_parts(seg.sgaseg_buf as *mut u8, seg.sgaseg_len as usize)
+            };
+            buf[pos..(pos + seg_slice.len())].copy_from_slice(seg_slice);
+            pos += seg_slice.len();
+        }
+        buf.freeze()
+    }
+
+    fn transmit(&self, pkt: impl PacketBuf<Bytes>) {
+        let header_size = pkt.header_size();
+        let body_size = pkt.body_size();
+
+        let mut buf = BytesMut::zeroed(header_size + body_size).unwrap();
+        pkt.write_header(&mut buf[..header_size]);
+        if let Some(body) = pkt.take_body() {
+            buf[header_size..].copy_from_slice(&body[..]);
+        }
+        let frame = buf.freeze();
+        let mut inner = self.inner.borrow_mut();
+        inner.device.write(&frame);
+        inner.trace.push((Direction::Tx, frame));
+    }
+
+    fn receive(&self) -> ArrayVec<Bytes, RECEIVE_BATCH_SIZE> {
+        let mut out = ArrayVec::new();
+        let mut inner = self.inner.borrow_mut();
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        while !out.is_full() {
+            let len = match inner.device.read(&mut buf) {
+                Some(len) => len,
+                None => break,
+            };
+            let frame = Bytes::from_slice(&buf[..len]);
+            inner.trace.push((Direction::Rx, frame.clone()));
+            out.push(frame);
+        }
+        out
+    }
+
+    fn scheduler(&self) -> &Scheduler<Operation<Self>> {
+        &self.scheduler
+    }
+
+    fn local_link_addr(&self) -> MacAddress {
+        self.inner.borrow().link_addr
+    }
+
+    fn local_ipv4_addr(&self) -> Ipv4Addr {
+        self.inner.borrow().ipv4_addr
+    }
+
+    fn tcp_options(&self) -> tcp::Options<Self> {
+        self.inner.borrow().tcp_options.clone()
+    }
+
+    fn udp_options(&self) -> udp::Options {
+        udp::Options::default()
+    }
+
+    fn arp_options(&self) -> arp::Options {
+        self.inner.borrow().arp_options.clone()
+    }
+
+    fn advance_clock(&self, now: Instant) {
+        self.inner.borrow_mut().timer.0.advance_clock(now);
+    }
+
+    fn wait(&self, duration: Duration) -> Self::WaitFuture {
+        let inner = self.inner.borrow_mut();
+        let now = inner.timer.0.now();
+        inner
+            .timer
+            .0
+            .wait_until(inner.timer.clone(), now + duration)
+    }
+
+    fn wait_until(&self, when: Instant) -> Self::WaitFuture {
+        let inner = self.inner.borrow_mut();
+        inner.timer.0.wait_until(inner.timer.clone(), when)
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.borrow().timer.0.now()
+    }
+
+    fn rng_gen<T>(&self) -> T
+    where
+        Standard: Distribution<T>,
+    {
+        let mut inner = self.inner.borrow_mut();
+        inner.rng.gen()
+    }
+
+    fn rng_shuffle<T>(&self, slice: &mut [T]) {
+        let mut inner = self.inner.borrow_mut();
+        slice.shuffle(&mut inner.rng);
+    }
+
+    fn spawn<F: Future<Output = ()> + 'static>(&self, future: F) -> SchedulerHandle {
+        self.scheduler
+            .insert(Operation::Background(future.boxed_local()))
+    }
+}
@@ -1,6 +1,7 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT license.
 
+#![cfg(not(target_arch = "wasm32"))]
 #![feature(new_uninit)]
 #![feature(const_panic, const_alloc_layout)]
 #![feature(const_mut_refs, const_type_name)]